@@ -0,0 +1,663 @@
+//! An asynchronous interface to collections, enabled by the `async` feature.
+//!
+//! The MongoDB driver is synchronous, so the operations of an
+//! [`AsyncCollection`](struct.AsyncCollection.html) are executed on a
+//! [`BlockingPool`](struct.BlockingPool.html) of background threads, and
+//! the returned futures complete when the operation has finished. Tasks
+//! awaiting them don't block the threads of the executor. Since nothing
+//! here depends on a particular executor, the futures and streams can be
+//! awaited on `tokio`, `async-std` or any other runtime.
+//!
+//! The results of `find_many()` and `aggregate()` are yielded by an
+//! [`AsyncCursor`](struct.AsyncCursor.html), which implements `Stream`.
+//! An open cursor occupies a thread of the pool until it is exhausted or
+//! dropped, so the pool should have more threads than the number of cursors
+//! expected to be open at the same time.
+//!
+//! An [`AsyncLoader`](struct.AsyncLoader.html) batches lookups by ID: the
+//! IDs of all `load()` futures created before the first of them is polled
+//! are fetched by a single query, e.g. when the futures of many GraphQL
+//! resolvers are joined.
+
+use std::thread;
+use std::panic::{ self, AssertUnwindSafe };
+use std::borrow::Borrow;
+use std::pin::Pin;
+use std::hash::Hash;
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError, mpsc };
+use std::task::{ Context, Poll, Waker };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use futures::{ Future, Stream, SinkExt };
+use futures::channel::{ oneshot, mpsc as channel };
+use futures::executor::block_on;
+use crate::{
+    coll::{ Collection, InsertStreamProgress, UpdateOneResult, UpsertOneResult, UpdateManyResult },
+    cursor::{ self, Cursor },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    error::{ Error, ErrorKind, ErrorKind::Canceled, ErrorExt, Result },
+};
+
+/// The number of items an `AsyncCursor` reads ahead of its consumer; the
+/// size of the first batch returned by the server by default.
+const BUFFERED_ITEMS: usize = 101;
+
+/// A job run by a thread of a `BlockingPool`.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed-size pool of threads executing blocking database operations.
+/// Cloning the pool is cheap; the clones share the same threads, which
+/// exit once every clone has been dropped.
+#[derive(Clone)]
+pub struct BlockingPool {
+    /// Sends jobs to the worker threads.
+    sender: Arc<Mutex<mpsc::Sender<Job>>>,
+    /// The number of worker threads.
+    num_threads: usize,
+}
+
+impl BlockingPool {
+    /// Starts a pool with `size` worker threads (at least one).
+    pub fn new(size: usize) -> Self {
+        let num_threads = size.max(1);
+        let (sender, rx) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(rx));
+
+        for _ in 0..num_threads {
+            let shared = receiver.clone();
+
+            thread::spawn(move || loop {
+                let next = match shared.lock() {
+                    Ok(guard) => guard.recv(),
+                    Err(_) => return,
+                };
+
+                match next {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        BlockingPool {
+            sender: Arc::new(Mutex::new(sender)),
+            num_threads,
+        }
+    }
+
+    /// Returns the number of worker threads.
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    /// Runs `f` on a worker thread, returning a future of its result. If `f`
+    /// panics, the future resolves to a `Canceled` error, and the worker
+    /// thread goes on with the next job.
+    pub fn run<F, R>(&self, f: F) -> Pending<R>
+        where F: FnOnce() -> Result<R> + Send + 'static,
+              R: Send + 'static,
+    {
+        let (tx, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // If the future has been dropped, nobody is interested in the result.
+            tx.send(catch_panic(f)).ok();
+        });
+
+        // The workers only exit once the sender is dropped, and jobs never
+        // panic while holding the lock, so this shouldn't fail. If it does,
+        // the future resolves to a `Canceled` error, since the job is dropped.
+        if let Ok(sender) = self.sender.lock() {
+            sender.send(job).ok();
+        }
+
+        Pending { receiver }
+    }
+}
+
+impl Debug for BlockingPool {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("BlockingPool")
+            .field("num_threads", &self.num_threads)
+            .finish()
+    }
+}
+
+/// Runs `f`, turning a panic into a `Canceled` error, so that a panicking
+/// job neither kills its worker thread nor leaves its caller guessing.
+fn catch_panic<F, R>(f: F) -> Result<R>
+    where F: FnOnce() -> Result<R>
+{
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let cause = payload
+            .downcast_ref::<&str>()
+            .map(|message| String::from(*message))
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown cause"));
+
+        Err(Error::new(Canceled, format!("background operation panicked: {}", cause)))
+    })
+}
+
+/// The future result of an operation running on a `BlockingPool`.
+#[derive(Debug)]
+pub struct Pending<R> {
+    /// Receives the result once the operation has finished.
+    receiver: oneshot::Receiver<Result<R>>,
+}
+
+impl<R> Future for Pending<R> {
+    type Output = Result<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver).poll(cx).map(|result| result.unwrap_or_else(
+            |_| Err(Error::new(Canceled, "background operation was abandoned"))
+        ))
+    }
+}
+
+/// A stream of the results of a query or an aggregation.
+#[derive(Debug)]
+pub struct AsyncCursor<T> {
+    /// Receives the items read by the background thread.
+    receiver: channel::Receiver<Result<T>>,
+}
+
+impl<T: cursor::Item + Send + 'static> AsyncCursor<T> {
+    /// Opens a cursor on a thread of the pool using `open`, then sends its
+    /// items through a channel holding at most `BUFFERED_ITEMS` items.
+    fn spawn<F>(pool: &BlockingPool, open: F) -> Self
+        where F: FnOnce() -> Result<Cursor<T>> + Send + 'static
+    {
+        let (mut sender, receiver) = channel::channel(BUFFERED_ITEMS);
+
+        pool.run(move || {
+            let outcome = catch_panic(|| open().map(|cursor| for item in cursor {
+                // Stop reading once the stream has been dropped.
+                if block_on(sender.send(item)).is_err() {
+                    break;
+                }
+            }));
+
+            // Report a panic too, instead of just ending the stream.
+            if let Err(error) = outcome {
+                block_on(sender.send(Err(error))).ok();
+            }
+
+            Ok(())
+        });
+
+        AsyncCursor { receiver }
+    }
+}
+
+impl<T> Stream for AsyncCursor<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// A collection whose operations return futures, executed on a
+/// `BlockingPool`. Cloning it is cheap.
+pub struct AsyncCollection<T: Doc> {
+    /// The synchronous collection.
+    inner: Arc<Collection<T>>,
+    /// The threads executing the operations.
+    pool: BlockingPool,
+}
+
+impl<T> AsyncCollection<T> where T: Doc + Send + Sync + 'static {
+    /// Creates an asynchronous collection, executing the operations of
+    /// `collection` on the threads of `pool`.
+    pub fn new(collection: Collection<T>, pool: BlockingPool) -> Self {
+        AsyncCollection {
+            inner: Arc::new(collection),
+            pool,
+        }
+    }
+
+    /// Returns the synchronous collection.
+    pub fn sync(&self) -> &Collection<T> {
+        &self.inner
+    }
+
+    /// Returns the number of documents matching the query criteria.
+    pub fn count<Q>(&self, query: Q) -> Pending<usize>
+        where Q: Count<T> + Send + 'static
+    {
+        self.run(move |coll| coll.count(query))
+    }
+
+    /// Returns the number of documents in the collection, based on the
+    /// metadata of the collection.
+    pub fn estimated_count(&self) -> Pending<usize> {
+        self.run(|coll| coll.estimated_count())
+    }
+
+    /// Retrieves a single document satisfying the query, if one exists.
+    pub fn find_one<Q>(&self, query: Q) -> Pending<Option<Q::Output>>
+        where Q: Query<T> + Send + 'static,
+              Q::Output: Send + 'static,
+    {
+        self.run(move |coll| coll.find_one(query))
+    }
+
+    /// Retrieves all documents satisfying the query.
+    pub fn find_many<Q>(&self, query: Q) -> AsyncCursor<Q::Output>
+        where Q: Query<T> + Send + 'static,
+              Q::Output: cursor::Item + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        AsyncCursor::spawn(&self.pool, move || inner.find_many(query))
+    }
+
+    /// Runs an aggregation pipeline.
+    pub fn aggregate<P>(&self, pipeline: P) -> AsyncCursor<P::Output>
+        where P: Pipeline<T> + Send + 'static,
+              P::Output: cursor::Item + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        AsyncCursor::spawn(&self.pool, move || inner.aggregate(pipeline))
+    }
+
+    /// Inserts a single document.
+    pub fn insert_one(&self, entity: T) -> Pending<Uid<T>> {
+        self.run(move |coll| coll.insert_one(&entity))
+    }
+
+    /// Inserts many documents. Returns the IDs of the inserted documents,
+    /// keyed by their index in `entities`.
+    pub fn insert_many(&self, entities: Vec<T>) -> Pending<BTreeMap<u64, Uid<T>>>
+        where T::Id: Clone + Debug + Send
+    {
+        self.run(move |coll| coll.insert_many(entities))
+    }
+
+    /// Inserts the documents yielded by `entities` in batches, calling
+    /// `progress` after each batch. See `Collection::insert_stream()`.
+    pub fn insert_stream<I, F>(&self, entities: I, progress: F) -> Pending<InsertStreamProgress>
+        where I: IntoIterator + Send + 'static,
+              I::Item: Borrow<T>,
+              F: FnMut(&InsertStreamProgress) + Send + 'static,
+    {
+        self.run(move |coll| coll.insert_stream(entities, progress))
+    }
+
+    /// Updates a single document.
+    pub fn update_one<U>(&self, update: U) -> Pending<UpdateOneResult>
+        where U: Update<T> + Send + 'static
+    {
+        self.run(move |coll| coll.update_one(update))
+    }
+
+    /// Upserts a single document.
+    pub fn upsert_one<U>(&self, upsert: U) -> Pending<UpsertOneResult<Uid<T>>>
+        where U: Upsert<T> + Send + 'static
+    {
+        self.run(move |coll| coll.upsert_one(upsert))
+    }
+
+    /// Updates multiple documents.
+    pub fn update_many<U>(&self, update: U) -> Pending<UpdateManyResult>
+        where U: Update<T> + Send + 'static
+    {
+        self.run(move |coll| coll.update_many(update))
+    }
+
+    /// Deletes one document. Returns `true` if one was found and deleted.
+    pub fn delete_one<Q>(&self, query: Q) -> Pending<bool>
+        where Q: Delete<T> + Send + 'static
+    {
+        self.run(move |coll| coll.delete_one(query))
+    }
+
+    /// Deletes many documents. Returns the number of deleted documents.
+    pub fn delete_many<Q>(&self, query: Q) -> Pending<usize>
+        where Q: Delete<T> + Send + 'static
+    {
+        self.run(move |coll| coll.delete_many(query))
+    }
+
+    /// Finds a single document based on query criteria and updates it.
+    pub fn find_one_and_update<U>(&self, update: U) -> Pending<Option<U::Output>>
+        where U: FindAndUpdate<T> + Send + 'static,
+              U::Output: Send + 'static,
+    {
+        self.run(move |coll| coll.find_one_and_update(update))
+    }
+
+    /// Returns a loader which batches and deduplicates lookups by ID, and
+    /// caches the loaded documents.
+    pub fn loader(&self) -> AsyncLoader<T>
+        where T: Clone,
+              T::Id: Hash + Clone + Debug + Send,
+    {
+        AsyncLoader::new(self.clone())
+    }
+
+    /// Runs an arbitrary operation of the synchronous collection on the pool.
+    pub fn run<F, R>(&self, f: F) -> Pending<R>
+        where F: FnOnce(&Collection<T>) -> Result<R> + Send + 'static,
+              R: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        self.pool.run(move || f(&inner))
+    }
+}
+
+impl<T: Doc> Clone for AsyncCollection<T> {
+    fn clone(&self) -> Self {
+        AsyncCollection {
+            inner: self.inner.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<T: Doc> Debug for AsyncCollection<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "AsyncCollection<{}>", T::NAME)
+    }
+}
+
+/// Batches lookups by ID into single queries executed on a `BlockingPool`,
+/// and caches the results. Cloning it is cheap; the clones share the cache.
+pub struct AsyncLoader<T: Doc> {
+    /// The collection the documents are loaded from.
+    collection: AsyncCollection<T>,
+    /// The cached documents and the queued and fetched IDs.
+    state: Arc<Mutex<AsyncLoaderState<T>>>,
+}
+
+/// The mutable state of an `AsyncLoader`.
+struct AsyncLoaderState<T: Doc> {
+    /// The documents loaded so far, `None` if there is no document with
+    /// the given ID.
+    cache: HashMap<Uid<T>, Option<T>>,
+    /// The kind and message of the error of the failed lookups.
+    failed: HashMap<Uid<T>, (ErrorKind, String)>,
+    /// The requested IDs which haven't been fetched yet.
+    pending: HashSet<Uid<T>>,
+    /// The IDs currently being fetched.
+    in_flight: HashSet<Uid<T>>,
+    /// The tasks waiting for the IDs in flight.
+    wakers: Vec<Waker>,
+}
+
+impl<T> AsyncLoader<T>
+    where T: Doc + Clone + Send + Sync + 'static,
+          T::Id: Hash + Clone + Debug + Send,
+{
+    /// Creates a loader with an empty cache.
+    pub fn new(collection: AsyncCollection<T>) -> Self {
+        AsyncLoader {
+            collection,
+            state: Arc::new(Mutex::new(AsyncLoaderState {
+                cache: HashMap::new(),
+                failed: HashMap::new(),
+                pending: HashSet::new(),
+                in_flight: HashSet::new(),
+                wakers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns the document with the given ID, or `None` if there is none.
+    /// Unless it is already cached, the ID is queued, and fetched together
+    /// with every other queued ID once one of the returned futures is polled.
+    /// Failed lookups are cached as well, until they are cleared.
+    pub fn load(&self, id: Uid<T>) -> LoadFuture<T> {
+        {
+            let mut state = lock_loader_state(&self.state);
+
+            if !state.cache.contains_key(&id)
+                && !state.failed.contains_key(&id)
+                && !state.in_flight.contains(&id) {
+                state.pending.insert(id.clone());
+            }
+        }
+
+        LoadFuture {
+            collection: self.collection.clone(),
+            state: self.state.clone(),
+            id,
+            batch: None,
+        }
+    }
+
+    /// Caches an already loaded document, so that it isn't fetched again.
+    pub fn prime(&self, entity: T) {
+        if let Some(id) = entity.id().cloned() {
+            let mut state = lock_loader_state(&self.state);
+            state.pending.remove(&id);
+            state.failed.remove(&id);
+            state.cache.insert(id, Some(entity));
+        }
+    }
+
+    /// Removes the document or error with the given ID from the cache, so
+    /// that it is fetched again when requested.
+    pub fn clear(&self, id: &Uid<T>) {
+        let mut state = lock_loader_state(&self.state);
+        state.cache.remove(id);
+        state.failed.remove(id);
+    }
+
+    /// Empties the cache.
+    pub fn clear_all(&self) {
+        let mut state = lock_loader_state(&self.state);
+        state.cache.clear();
+        state.failed.clear();
+    }
+}
+
+impl<T: Doc> Clone for AsyncLoader<T> {
+    fn clone(&self) -> Self {
+        AsyncLoader {
+            collection: self.collection.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Doc> Debug for AsyncLoader<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "AsyncLoader<{}>", T::NAME)
+    }
+}
+
+/// The future result of `AsyncLoader::load()`.
+pub struct LoadFuture<T: Doc> {
+    /// The collection the documents are loaded from.
+    collection: AsyncCollection<T>,
+    /// The state shared with the loader and its other futures.
+    state: Arc<Mutex<AsyncLoaderState<T>>>,
+    /// The requested ID.
+    id: Uid<T>,
+    /// The query dispatched by this future, if any.
+    batch: Option<Batch<T>>,
+}
+
+/// A query of an `AsyncLoader`, dispatched by one of its futures.
+struct Batch<T: Doc> {
+    /// The IDs being fetched.
+    ids: Vec<Uid<T>>,
+    /// The result of the query.
+    result: Pending<HashMap<Uid<T>, T>>,
+    /// Queues the IDs again if the query is abandoned. `Drop` can't
+    /// require `T::Id: Hash`, so this is instantiated by `poll()`.
+    abandon: fn(&Mutex<AsyncLoaderState<T>>, Vec<Uid<T>>),
+}
+
+// The fields are never pinned structurally.
+impl<T: Doc> Unpin for LoadFuture<T> {}
+
+impl<T> Future for LoadFuture<T>
+    where T: Doc + Clone + Send + Sync + 'static,
+          T::Id: Hash + Clone + Debug + Send,
+{
+    type Output = Result<Option<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if let Some(ref mut batch) = this.batch {
+            let result = match Pin::new(&mut batch.result).poll(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if let Some(finished) = this.batch.take() {
+                complete_batch(&this.state, finished.ids, result);
+            }
+        }
+
+        let mut state = lock_loader_state(&this.state);
+
+        if let Some(entity) = state.cache.get(&this.id) {
+            return Poll::Ready(Ok(entity.clone()));
+        }
+
+        if let Some(&(kind, ref message)) = state.failed.get(&this.id) {
+            return Poll::Ready(Err(Error::new(kind, message.clone())));
+        }
+
+        if state.in_flight.contains(&this.id) {
+            state.wakers.push(cx.waker().clone());
+        } else {
+            // The ID may have been cleared while being fetched, so queue it again.
+            state.pending.insert(this.id.clone());
+
+            let ids: Vec<_> = state.pending.drain().collect();
+            let query_ids = ids.clone();
+
+            state.in_flight.extend(ids.iter().cloned());
+            this.batch = Some(Batch {
+                ids,
+                result: this.collection.run(move |coll| coll.find_by_ids(&query_ids)),
+                abandon: abandon_batch,
+            });
+
+            // Poll again in order to register with the query.
+            cx.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T: Doc> Drop for LoadFuture<T> {
+    fn drop(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            (batch.abandon)(&self.state, batch.ids);
+        }
+    }
+}
+
+impl<T: Doc> Debug for LoadFuture<T> where T::Id: Debug {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("LoadFuture")
+            .field("collection", &T::NAME)
+            .field("id", &self.id)
+            .field("dispatched", &self.batch.is_some())
+            .finish()
+    }
+}
+
+/// Caches the result of a batched query of an `AsyncLoader`, and wakes the
+/// futures waiting for it.
+fn complete_batch<T>(
+    shared: &Mutex<AsyncLoaderState<T>>,
+    ids: Vec<Uid<T>>,
+    result: Result<HashMap<Uid<T>, T>>,
+) where
+    T: Doc,
+    T::Id: Hash,
+{
+    let mut state = lock_loader_state(shared);
+
+    for id in &ids {
+        state.in_flight.remove(id);
+    }
+
+    match result {
+        Ok(mut found) => for id in ids {
+            let entity = found.remove(&id);
+            state.cache.insert(id, entity);
+        },
+        Err(error) => {
+            let message = error.to_string();
+
+            for id in ids {
+                state.failed.insert(id, (error.kind(), message.clone()));
+            }
+        }
+    }
+
+    for waker in state.wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+/// Queues the IDs of an abandoned batched query of an `AsyncLoader` again,
+/// and wakes the futures waiting for them, so that they don't hang.
+fn abandon_batch<T>(shared: &Mutex<AsyncLoaderState<T>>, ids: Vec<Uid<T>>)
+    where T: Doc,
+          T::Id: Hash,
+{
+    let mut state = lock_loader_state(shared);
+
+    for id in ids {
+        state.in_flight.remove(&id);
+        state.pending.insert(id);
+    }
+
+    for waker in state.wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+/// Locks the state of an `AsyncLoader`. It is consistent at all times, so
+/// poisoning is ignored.
+fn lock_loader_state<T: Doc>(state: &Mutex<AsyncLoaderState<T>>) -> MutexGuard<'_, AsyncLoaderState<T>> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use crate::error::{ Error, ErrorKind, ErrorExt };
+    use super::BlockingPool;
+
+    #[test]
+    fn pool_runs_jobs() {
+        let pool = BlockingPool::new(2);
+        let results: Vec<_> = (0..4_i32).map(|i| pool.run(move || Ok(i * i))).collect();
+        let squares: Vec<_> = results.into_iter().map(|result| block_on(result).unwrap()).collect();
+
+        assert_eq!(squares, [0, 1, 4, 9]);
+
+        let error = block_on(pool.run(|| -> Result<(), Error> {
+            Err(Error::new(ErrorKind::MongoDbError, "oops"))
+        })).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::MongoDbError);
+    }
+
+    #[test]
+    fn panicking_job_fails_its_future_only() {
+        let pool = BlockingPool::new(1);
+        let error = block_on(pool.run(|| -> Result<(), Error> {
+            panic!("job exploded")
+        })).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::Canceled);
+        assert!(error.to_string().contains("job exploded"));
+
+        // the only worker thread is still alive
+        assert_eq!(block_on(pool.run(|| Ok(42))).unwrap(), 42);
+    }
+}
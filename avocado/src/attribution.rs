@@ -0,0 +1,238 @@
+//! Tagging operations for attributing database load to application features.
+//!
+//! Wrapping an operation into an [`Attributed`](struct.Attributed.html)
+//! adds the tag, e.g. the name of the team or the endpoint issuing the
+//! operation, to its filter as a `$comment`. The comment shows up in the
+//! server's profiler output, in `currentOp` and in the slow query log, so
+//! the load can be broken down by tag without changing the query itself.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! use avocado::attribution::Attributed;
+//!
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! struct Order {
+//!     _id: Uid<Order>,
+//!     status: String,
+//! }
+//!
+//! # fn main() {
+//! let query = Attributed::new("checkout/list-orders", doc!{ "status": "open" });
+//!
+//! assert_eq!(Query::<Order>::filter(&query), doc!{
+//!     "status": "open",
+//!     "$comment": "checkout/list-orders",
+//! });
+//! # }
+//! ```
+
+use bson::{ Bson, Document };
+use mongodb::common::WriteConcern;
+use mongodb::coll::options::{
+    FindOptions,
+    CountOptions,
+    DistinctOptions,
+    AggregateOptions,
+    FindOneAndUpdateOptions,
+};
+use crate::{
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
+    doc::Doc,
+    ops::*,
+    error::Result,
+};
+
+/// Wraps an operation so that it carries an attribution tag.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Attributed<O> {
+    /// The tag, e.g. `"billing"` or `"GET /orders"`.
+    pub tag: String,
+    /// The wrapped operation.
+    pub op: O,
+}
+
+impl<O> Attributed<O> {
+    /// Tags the operation `op` with `tag`.
+    pub fn new<S: Into<String>>(tag: S, op: O) -> Self {
+        Attributed { tag: tag.into(), op }
+    }
+
+    /// Adds the tag to a filter as its `$comment`, replacing any existing one.
+    fn tag_filter(&self, mut filter: Document) -> Document {
+        filter.insert("$comment", self.tag.clone());
+        filter
+    }
+}
+
+impl<T: Doc, Q: Count<T>> Count<T> for Attributed<Q> {
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn options(&self) -> CountOptions {
+        self.op.options()
+    }
+}
+
+impl<T: Doc, Q: Distinct<T>> Distinct<T> for Attributed<Q> {
+    type Output = Q::Output;
+
+    const FIELD: &'static str = Q::FIELD;
+
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn transform(raw: Bson) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> DistinctOptions {
+        self.op.options()
+    }
+}
+
+/// Pipelines are tagged by a leading `$match` stage containing only the
+/// `$comment`, which matches every document.
+impl<T: Doc, P: Pipeline<T>> Pipeline<T> for Attributed<P> {
+    type Output = P::Output;
+
+    fn stages(&self) -> Vec<Document> {
+        let mut stages = vec![doc!{ "$match": self.tag_filter(Document::new()) }];
+        stages.extend(self.op.stages());
+        stages
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        P::transform(raw)
+    }
+
+    fn options(&self) -> AggregateOptions {
+        self.op.options()
+    }
+}
+
+impl<T: Doc, Q: Query<T>> Query<T> for Attributed<Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.op.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.op.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.op.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<T: Doc, U: Update<T>> Update<T> for Attributed<U> {
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn update(&self) -> Document {
+        self.op.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.op.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<T: Doc, U: Upsert<T>> Upsert<T> for Attributed<U> {
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn upsert(&self) -> Document {
+        self.op.upsert()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.op.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<T: Doc, Q: Delete<T>> Delete<T> for Attributed<Q> {
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for Attributed<U> {
+    type Output = U::Output;
+
+    fn filter(&self) -> Document {
+        self.tag_filter(self.op.filter())
+    }
+
+    fn update(&self) -> Document {
+        self.op.update()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        U::transform(raw)
+    }
+
+    fn options(&self) -> FindOneAndUpdateOptions {
+        self.op.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.op.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.op.sort()
+    }
+}
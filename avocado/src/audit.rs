@@ -0,0 +1,740 @@
+//! Opt-in audit logging of write operations.
+//!
+//! A [`Log`](struct.Log.html) records who changed what and when
+//! into a dedicated collection (by default, `_audit`). Writes are recorded
+//! when performed through an [`Audited`](struct.Audited.html) wrapper around
+//! a typed `Collection`, which computes the change set of every written
+//! document using the [`diff`](../diff/index.html) module.
+//!
+//! Document types marked with `#[avocado(audited)]` have their own audit log
+//! collection, named after their collection with an `_audit` suffix, which
+//! `Log::for_doc()` writes to. The actor performing the writes, along
+//! with any other details worth recording, e.g. the ID of the request, is
+//! described by a [`Context`](struct.Context.html):
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::audit::{ Log, Context, Audited };
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! #[avocado(audited)]
+//! struct Account {
+//!     _id: Uid<Account>,
+//!     owner: String,
+//!     balance: i64,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let accounts: Collection<Account> = db.existing_collection();
+//! let log = Log::for_doc::<Account, _>(&db).expect("`Account` is audited");
+//! let context = Context::new("alice").with("request_id", "4f1e");
+//! let audited = Audited::with_context(&accounts, &log, context);
+//!
+//! // Recorded in `Account_audit`, with the request ID and the fields of
+//! // the new document.
+//! audited.insert_one(&Account { _id: Uid::new_oid()?, owner: "bob".into(), balance: 100 })?;
+//!
+//! // Each deleted document is recorded separately.
+//! audited.delete_many(doc!{ "owner": "bob" })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use chrono::Utc;
+use bson::{ Bson, Document, UtcDateTime };
+use mongodb::db::ThreadedDatabase;
+use mongodb::common::WriteConcern;
+use mongodb::coll::options::FindOptions;
+use crate::{
+    coll::{ Collection, UpdateOneResult, UpdateManyResult, UpsertOneResult },
+    doc::Doc,
+    uid::Uid,
+    ops::{ Query, Update, Delete },
+    options,
+    update::ArrayFilters,
+    diff::{ self, Change, ChangeSet },
+    bsn::serialize_document,
+    error::{ Error, ErrorKind::MissingId, Result, ResultExt },
+};
+
+/// The default name of the collection audit entries are written to.
+pub const AUDIT_COLLECTION_NAME: &str = "_audit";
+
+/// The suffix of the name of the audit log collection of an audited
+/// document type; see `Doc::audit_collection()`.
+pub const AUDIT_COLLECTION_SUFFIX: &str = "_audit";
+
+/// The value that replaces redacted fields in audit entries.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The kind of a recorded write operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Operation {
+    /// A new document was inserted.
+    Insert,
+    /// An existing document was replaced or updated.
+    Update,
+    /// A document was deleted.
+    Delete,
+}
+
+/// A single entry in the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The caller-supplied identity of whoever performed the write.
+    pub principal: String,
+    /// The name of the collection that was written to.
+    pub collection: String,
+    /// The `_id` of the written document.
+    pub document_id: Bson,
+    /// The kind of the write operation.
+    pub operation: Operation,
+    /// The changed fields, with sensitive values redacted.
+    pub changes: ChangeSet,
+    /// When the write happened.
+    pub timestamp: UtcDateTime,
+    /// The attributes of the `Context` of the write, if any.
+    #[serde(default, skip_serializing_if = "Document::is_empty")]
+    pub context: Document,
+}
+
+/// Who performs audited writes, and under what circumstances.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    /// The identity of the actor, e.g. a user name or a service account.
+    /// It is recorded as the `principal` of the audit entries.
+    pub actor: String,
+    /// Further details recorded with every entry, e.g. the ID of the request
+    /// or the address of the client.
+    pub attributes: Document,
+}
+
+impl Context {
+    /// Creates a context for writes performed by `actor`, with no attributes.
+    pub fn new<S: Into<String>>(actor: S) -> Self {
+        Context {
+            actor: actor.into(),
+            attributes: Document::new(),
+        }
+    }
+
+    /// Builder-style method for adding an attribute.
+    pub fn with<K, V>(mut self, key: K, value: V) -> Self
+        where K: Into<String>,
+              V: Into<Bson>,
+    {
+        self.attributes.insert(key, value);
+        self
+    }
+}
+
+/// An audit log, backed by a MongoDB collection.
+pub struct Log {
+    /// The collection audit entries are written to.
+    inner: mongodb::coll::Collection,
+    /// Dotted paths of fields whose values are never recorded.
+    redactions: Vec<String>,
+}
+
+impl Log {
+    /// Creates an audit log writing to the `_audit` collection
+    /// of the given database.
+    pub fn new<D: ThreadedDatabase>(db: &D) -> Self {
+        Self::with_collection_name(db, AUDIT_COLLECTION_NAME)
+    }
+
+    /// Creates an audit log writing to the named collection
+    /// of the given database.
+    pub fn with_collection_name<D: ThreadedDatabase>(db: &D, name: &str) -> Self {
+        Log {
+            inner: db.collection(name),
+            redactions: Vec::new(),
+        }
+    }
+
+    /// Builder-style method for adding a redaction rule. The value of the
+    /// field at the given dotted path (and of everything nested within it)
+    /// will be replaced by `REDACTED` in every recorded change set.
+    pub fn redact<S: Into<String>>(mut self, path: S) -> Self {
+        self.redactions.push(path.into());
+        self
+    }
+
+    /// Creates the audit log of the document type `T`, which writes to the
+    /// collection named by `T::audit_collection()`, e.g. `User_audit`, and
+    /// redacts the fields in `T::encrypted_fields()`. Returns `None` if `T`
+    /// isn't audited.
+    pub fn for_doc<T: Doc, D: ThreadedDatabase>(db: &D) -> Option<Self> {
+        T::audit_collection().map(|name| {
+            T::encrypted_fields()
+                .iter()
+                .fold(Self::with_collection_name(db, name), |log, field| log.redact(field.name))
+        })
+    }
+
+    /// Applies the redaction rules and writes an entry to the audit log.
+    pub fn record(&self, mut entry: Entry) -> Result<()> {
+        entry.changes = redact_changes(&self.redactions, entry.changes);
+
+        let doc = serialize_document(&entry)?;
+        let message = || format!("can't record audit entry for {}", entry.collection);
+
+        self.inner
+            .insert_one(doc, None)
+            .chain(&message)
+            .and_then(|result| match result.write_exception {
+                Some(error) => Err(Error::with_cause(message(), error)),
+                None => Ok(()),
+            })
+    }
+}
+
+impl Debug for Log {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Log")
+            .field("collection", &self.inner.namespace)
+            .field("redactions", &self.redactions)
+            .finish()
+    }
+}
+
+/// Replaces the values of fields subject to any of the redaction `rules`
+/// in a change set.
+fn redact_changes(rules: &[String], changes: ChangeSet) -> ChangeSet {
+    changes
+        .into_iter()
+        .map(|change| match change {
+            Change::Added { path, mut value } => {
+                redact_value(rules, &path, &mut value);
+                Change::Added { path, value }
+            }
+            Change::Removed { path, mut value } => {
+                redact_value(rules, &path, &mut value);
+                Change::Removed { path, value }
+            }
+            Change::Modified { path, mut before, mut after } => {
+                redact_value(rules, &path, &mut before);
+                redact_value(rules, &path, &mut after);
+                Change::Modified { path, before, after }
+            }
+        })
+        .collect()
+}
+
+/// Redacts the value found at `path`, or the parts of it that are
+/// subject to any of the redaction `rules`.
+fn redact_value(rules: &[String], path: &str, value: &mut Bson) {
+    for rule in rules {
+        if is_path_prefix(rule, path) {
+            *value = Bson::from(REDACTED);
+            return;
+        } else if is_path_prefix(path, rule) {
+            redact_relative(value, &rule[path.len() + 1..]);
+        }
+    }
+}
+
+/// Returns `true` if `prefix` is equal to `path` or it is a proper
+/// prefix thereof in terms of whole dotted path segments.
+fn is_path_prefix(prefix: &str, path: &str) -> bool {
+    path.starts_with(prefix) && (
+        path.len() == prefix.len() || path[prefix.len()..].starts_with('.')
+    )
+}
+
+/// Redacts the field at the dotted path `rel`, relative to `value`.
+fn redact_relative(value: &mut Bson, rel: &str) {
+    if let Bson::Document(ref mut doc) = *value {
+        let (head, tail) = match rel.find('.') {
+            Some(i) => (&rel[..i], Some(&rel[i + 1..])),
+            None => (rel, None),
+        };
+
+        if let Some(inner) = doc.get_mut(head) {
+            match tail {
+                Some(rest) => redact_relative(inner, rest),
+                None => *inner = Bson::from(REDACTED),
+            }
+        }
+    }
+}
+
+/// A view of a typed collection that records every write performed through
+/// it in an audit log, on behalf of the actor of a `Context`.
+///
+/// Writes and the recording of the corresponding audit entries are **not**
+/// atomic. If the write succeeds but the audit entry can't be recorded, the
+/// method returns an error nevertheless.
+#[derive(Debug)]
+pub struct Audited<'a, T: Doc> {
+    /// The collection being written to.
+    collection: &'a Collection<T>,
+    /// The audit log.
+    log: &'a Log,
+    /// Who performs the writes.
+    context: Context,
+}
+
+impl<'a, T: Doc> Audited<'a, T> {
+    /// Wraps the collection so that writes are recorded in `log`, on behalf
+    /// of `principal`.
+    pub fn new(collection: &'a Collection<T>, log: &'a Log, principal: &str) -> Self {
+        Self::with_context(collection, log, Context::new(principal))
+    }
+
+    /// Wraps the collection so that writes are recorded in `log`, along with
+    /// the actor and the attributes of `context`.
+    pub fn with_context(collection: &'a Collection<T>, log: &'a Log, context: Context) -> Self {
+        Audited { collection, log, context }
+    }
+
+    /// Returns the underlying collection, e.g. for performing queries.
+    pub fn collection(&self) -> &'a Collection<T> {
+        self.collection
+    }
+
+    /// Inserts a single document and records its fields as added.
+    pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
+        let id = self.collection.insert_one(entity)?;
+        let id_bson = bson::to_bson(&id)?;
+        let mut doc = serialize_document(entity)?;
+
+        doc.insert("_id", id_bson.clone());
+        self.record(id_bson, Operation::Insert, &Document::new(), &doc)?;
+
+        Ok(id)
+    }
+
+    /// Inserts many documents, and records the fields of each as added.
+    pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
+        where I: IntoIterator,
+              I::Item: Borrow<T>,
+              T::Id: Clone + Debug,
+              T: 'static,
+    {
+        let inserted: Vec<_> = entities.into_iter().collect();
+        let ids = self.collection.insert_many(inserted.iter().map(Borrow::<T>::borrow))?;
+
+        // on success, there is exactly one ID for each entity, in order
+        for (entity, id) in inserted.iter().map(Borrow::<T>::borrow).zip(ids.values()) {
+            let id_bson = bson::to_bson(id)?;
+            let mut doc = serialize_document(entity)?;
+
+            doc.insert("_id", id_bson.clone());
+            self.record(id_bson, Operation::Insert, &Document::new(), &doc)?;
+        }
+
+        Ok(ids)
+    }
+
+    /// Replaces a document based on its identity, and records the changes.
+    pub fn replace_entity(&self, entity: &T) -> Result<UpdateOneResult> where T: Debug {
+        let id = entity_id(entity)?;
+        let previous = self.find_raw(&id)?;
+        let result = self.collection.replace_entity(entity)?;
+
+        if let Some(before) = previous {
+            if result.matched {
+                let after = serialize_document(entity)?;
+                self.record(id, Operation::Update, &before, &after)?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Upserts a document based on its identity, and records the changes.
+    pub fn upsert_entity(&self, entity: &T) -> Result<UpsertOneResult<Uid<T>>> where T: Debug {
+        let id = entity_id(entity)?;
+        let previous = self.find_raw(&id)?;
+        let result = self.collection.upsert_entity(entity)?;
+        let after = serialize_document(entity)?;
+
+        match previous {
+            Some(before) => self.record(id, Operation::Update, &before, &after)?,
+            None => self.record(id, Operation::Insert, &Document::new(), &after)?,
+        }
+
+        Ok(result)
+    }
+
+    /// Updates a single document, and records the changes.
+    ///
+    /// The document is read before the update, which is then restricted to
+    /// that very document, so that the recorded changes belong to the
+    /// modified document even if the filter matches several ones. It's read
+    /// again after the update, so the recorded change set may include the
+    /// effects of concurrent writes as well.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        let before = match self.collection.find_one(RawQuery(update.filter()))? {
+            Some(before) => before,
+            None => return self.collection.update_one(&update),
+        };
+        let id = before.get("_id").cloned().ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in {} document", T::NAME))
+        )?;
+        let result = self.collection.update_one(ById { id: id.clone(), op: &update })?;
+
+        if result.modified {
+            let after = self.find_raw(&id)?.unwrap_or_default();
+            self.record(id, Operation::Update, &before, &after)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Updates all documents matching the filter of `update`, and records
+    /// the changes of each modified document.
+    ///
+    /// The documents are read before and after the update, so the recorded
+    /// change sets may include the effects of concurrent writes as well.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        let before = self.find_many_raw(update.filter())?;
+        let result = self.collection.update_many(&update)?;
+
+        if result.num_modified > 0 {
+            let ids = document_ids::<T>(&before)?;
+            let after = self.find_many_raw(doc!{ "_id": { "$in": ids } })?;
+
+            for old in &before {
+                let old_id = old.get("_id");
+                let current = after.iter().find(|doc| doc.get("_id") == old_id);
+
+                if let (Some(id), Some(new)) = (old_id, current) {
+                    if old != new {
+                        self.record(id.clone(), Operation::Update, old, new)?;
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Deletes a document, and records its fields as removed. Like in
+    /// `update_one()`, the deletion is restricted to the document read
+    /// beforehand, so the record belongs to the deleted document.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        let before = match self.collection.find_one(RawQuery(query.filter()))? {
+            Some(before) => before,
+            None => return self.collection.delete_one(&query),
+        };
+        let id = before.get("_id").cloned().ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in {} document", T::NAME))
+        )?;
+        let deleted = self.collection.delete_one(ById { id: id.clone(), op: &query })?;
+
+        if deleted {
+            self.record(id, Operation::Delete, &before, &Document::new())?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Deletes all documents matching the query, and records the fields of
+    /// each deleted document as removed.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        let before = self.find_many_raw(query.filter())?;
+        let num_deleted = self.collection.delete_many(&query)?;
+
+        if num_deleted > 0 {
+            let ids = document_ids::<T>(&before)?;
+            let remaining = self.find_many_raw(doc!{ "_id": { "$in": ids } })?;
+
+            for old in &before {
+                let old_id = old.get("_id");
+
+                if let (Some(id), false) = (old_id, remaining.iter().any(|doc| doc.get("_id") == old_id)) {
+                    self.record(id.clone(), Operation::Delete, old, &Document::new())?;
+                }
+            }
+        }
+
+        Ok(num_deleted)
+    }
+
+    /// Deletes a document based on its identity, and records its fields
+    /// as removed.
+    pub fn delete_entity(&self, entity: &T) -> Result<bool> where T: Debug {
+        let id = entity_id(entity)?;
+        let previous = self.find_raw(&id)?;
+        let deleted = self.collection.delete_entity(entity)?;
+
+        if let Some(before) = previous {
+            if deleted {
+                self.record(id, Operation::Delete, &before, &Document::new())?;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Retrieves the raw document with the given ID.
+    fn find_raw(&self, id: &Bson) -> Result<Option<Document>> {
+        self.collection.find_one(RawQuery(doc!{ "_id": id.clone() }))
+    }
+
+    /// Retrieves the raw documents matching a filter.
+    fn find_many_raw(&self, filter: Document) -> Result<Vec<Document>> {
+        self.collection.find_many(RawQuery(filter))?.collect()
+    }
+
+    /// Computes the change set and records an audit entry.
+    fn record(
+        &self,
+        document_id: Bson,
+        operation: Operation,
+        before: &Document,
+        after: &Document,
+    ) -> Result<()> {
+        self.log.record(Entry {
+            principal: self.context.actor.clone(),
+            collection: T::NAME.into(),
+            document_id,
+            operation,
+            changes: redact_encrypted::<T>(diff::documents(before, after)),
+            timestamp: UtcDateTime(Utc::now()),
+            context: self.context.attributes.clone(),
+        })
+    }
+}
+
+/// Redacts the fields of `T` which are encrypted on the client side. The
+/// documents read back are decrypted and the written ones aren't encrypted
+/// yet, so their values would otherwise be recorded in plaintext, whichever
+/// audit log they are written to.
+fn redact_encrypted<T: Doc>(changes: ChangeSet) -> ChangeSet {
+    let rules: Vec<_> = T::encrypted_fields().iter().map(|field| field.name.to_owned()).collect();
+    redact_changes(&rules, changes)
+}
+
+/// Returns the `_id` of an entity as raw BSON.
+fn entity_id<T: Doc>(entity: &T) -> Result<Bson> {
+    let id = entity.id().ok_or_else(
+        || Error::new(MissingId, format!("No `_id` in entity of type {}", T::NAME))
+    )?;
+    bson::to_bson(id).map_err(From::from)
+}
+
+/// Returns the `_id`s of raw documents.
+fn document_ids<T: Doc>(docs: &[Document]) -> Result<Vec<Bson>> {
+    docs.iter()
+        .map(|doc| doc.get("_id").cloned().ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in {} document", T::NAME))
+        ))
+        .collect()
+}
+
+/// A query returning raw, untransformed documents, ignoring the default
+/// query options of the document type (e.g. projections).
+#[derive(Debug, Clone)]
+struct RawQuery(Document);
+
+impl<T: Doc> Query<T> for RawQuery {
+    type Output = Document;
+
+    fn filter(&self) -> Document {
+        self.0.clone()
+    }
+
+    fn options(&self) -> FindOptions {
+        FindOptions::default()
+    }
+}
+
+/// An update or a deletion restricted to the single document with the
+/// given `_id`, among those matching the filter of the operation.
+#[derive(Debug)]
+struct ById<'a, O: 'a> {
+    /// The `_id` of the document.
+    id: Bson,
+    /// The restricted operation.
+    op: &'a O,
+}
+
+impl<'a, O: 'a> ById<'a, O> {
+    /// Adds the `_id` to a filter, without discarding its other conditions.
+    fn restrict(&self, filter: Document) -> Document {
+        doc!{ "$and": [filter, { "_id": self.id.clone() }] }
+    }
+}
+
+impl<'a, T: Doc, U: Update<T>> Update<T> for ById<'a, U> {
+    fn filter(&self) -> Document {
+        self.restrict(self.op.filter())
+    }
+
+    fn update(&self) -> Document {
+        self.op.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.op.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<'a, T: Doc, Q: Delete<T>> Delete<T> for ById<'a, Q> {
+    fn filter(&self) -> Document {
+        self.restrict(self.op.filter())
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use bson::oid::ObjectId;
+    use crate::{
+        doc::Doc,
+        uid::Uid,
+        diff::{ Change, ChangeSet },
+        encrypt::{ EncryptedField, EncryptionMode },
+    };
+    use super::{ REDACTED, is_path_prefix, redact_relative, redact_changes, redact_encrypted };
+
+    /// A document type with an encrypted field.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Patient {
+        /// The unique ID of the patient.
+        #[serde(rename = "_id")]
+        id: Uid<Patient>,
+        /// The diagnosis, which is encrypted.
+        diagnosis: String,
+    }
+
+    impl Doc for Patient {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Patient";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+
+        fn encrypted_fields() -> &'static [EncryptedField] {
+            &[EncryptedField { name: "diagnosis", mode: EncryptionMode::Randomized }]
+        }
+    }
+
+    #[test]
+    fn path_prefixes() {
+        assert!(is_path_prefix("password", "password"));
+        assert!(is_path_prefix("credentials", "credentials.password"));
+        assert!(!is_path_prefix("credentials.password", "credentials"));
+        assert!(!is_path_prefix("pass", "password"));
+    }
+
+    #[test]
+    fn redact_nested_value() {
+        let mut value = bson!({
+            "user": "alice",
+            "secret": { "key": "hunter2", "hint": "animal" },
+        });
+        redact_relative(&mut value, "secret.key");
+        redact_relative(&mut value, "missing.field");
+
+        assert_eq!(value, bson!({
+            "user": "alice",
+            "secret": { "key": REDACTED, "hint": "animal" },
+        }));
+    }
+
+    #[test]
+    fn redacted_change_set() {
+        let rules = vec![String::from("password"), String::from("profile.ssn")];
+        let changes: ChangeSet = vec![
+            Change::Added {
+                path: String::from("password"),
+                value: Bson::from("hunter2"),
+            },
+            Change::Added {
+                path: String::from("profile"),
+                value: bson!({ "ssn": "123-45-6789", "age": 42 }),
+            },
+            Change::Modified {
+                path: String::from("name"),
+                before: Bson::from("Alice"),
+                after: Bson::from("Alicia"),
+            },
+        ].into_iter().collect();
+
+        let redacted: Vec<_> = redact_changes(&rules, changes).into_iter().collect();
+
+        assert_eq!(redacted, vec![
+            Change::Added {
+                path: String::from("password"),
+                value: Bson::from(REDACTED),
+            },
+            Change::Added {
+                path: String::from("profile"),
+                value: bson!({ "ssn": REDACTED, "age": 42 }),
+            },
+            Change::Modified {
+                path: String::from("name"),
+                before: Bson::from("Alice"),
+                after: Bson::from("Alicia"),
+            },
+        ]);
+    }
+
+    #[test]
+    fn encrypted_fields_redacted() {
+        let changes: ChangeSet = vec![
+            Change::Added {
+                path: String::from("diagnosis"),
+                value: Bson::from("flu"),
+            },
+            Change::Modified {
+                path: String::from("name"),
+                before: Bson::from("Bob"),
+                after: Bson::from("Robert"),
+            },
+        ].into_iter().collect();
+
+        let redacted: Vec<_> = redact_encrypted::<Patient>(changes).into_iter().collect();
+
+        assert_eq!(redacted, vec![
+            Change::Added {
+                path: String::from("diagnosis"),
+                value: Bson::from(REDACTED),
+            },
+            Change::Modified {
+                path: String::from("name"),
+                before: Bson::from("Bob"),
+                after: Bson::from("Robert"),
+            },
+        ]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Buffering many small writes into few bulk writes.
+//!
+//! High-throughput producers, e.g. telemetry ingestion, often perform
+//! thousands of single-document writes per second. A
+//! [`BatchedWriter`](struct.BatchedWriter.html) collects such inserts and
+//! updates in memory, and sends them to the server as a single bulk write
+//! once enough of them have accumulated, or once the oldest one has waited
+//! long enough. Whatever is still buffered is flushed when the writer is
+//! dropped; call `flush()` explicitly in order to observe errors, though.
+//!
+//! The time threshold is checked whenever a write is buffered, since the
+//! writer doesn't spawn a background thread. An idle producer should call
+//! `flush_if_due()` periodically so that buffered writes don't linger.
+//!
+//! A writer created with `with_cancellation()` stops sending writes once
+//! its [`CancellationToken`](../cancel/struct.CancellationToken.html) is
+//! canceled or its deadline passes: the buffered writes are discarded, and
+//! flushing fails with a `Canceled` error. A bulk write already being sent
+//! is completed, though.
+
+use std::mem;
+use std::time::{ Duration, Instant };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use mongodb::coll::options::WriteModel;
+use crate::{
+    coll::{ Collection, check_entity_document },
+    doc::Doc,
+    ops::{ Update, Upsert },
+    cancel::CancellationToken,
+    utils::int_to_usize_with_msg,
+    error::Result,
+};
+
+/// Thresholds which trigger sending the buffered writes to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Options {
+    /// Flush as soon as this many writes are buffered. Default: 1000.
+    pub max_size: usize,
+    /// Flush as soon as the oldest buffered write is this old. Default: 1 second.
+    pub max_delay: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_size: 1000,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The outcome of sending a batch of buffered writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FlushResult {
+    /// The number of writes sent.
+    pub num_writes: usize,
+    /// The number of inserted documents.
+    pub num_inserted: usize,
+    /// The number of documents matched by updates and upserts.
+    pub num_matched: usize,
+    /// The number of documents modified by updates and upserts.
+    pub num_modified: usize,
+    /// The number of documents inserted by upserts.
+    pub num_upserted: usize,
+}
+
+/// Buffers inserts, updates and upserts, and sends them in bulk.
+pub struct BatchedWriter<'a, T: Doc> {
+    /// The collection the writes are sent to.
+    collection: &'a Collection<T>,
+    /// The flush thresholds.
+    options: Options,
+    /// The buffered writes, in order.
+    writes: Vec<WriteModel>,
+    /// The time the oldest buffered write was buffered at.
+    oldest: Option<Instant>,
+    /// The token stopping the writes when canceled, if any.
+    token: Option<CancellationToken>,
+}
+
+impl<'a, T: Doc> BatchedWriter<'a, T> {
+    /// Creates a writer with an empty buffer.
+    pub fn new(collection: &'a Collection<T>, options: Options) -> Self {
+        BatchedWriter {
+            collection,
+            options,
+            writes: Vec::with_capacity(options.max_size),
+            oldest: None,
+            token: None,
+        }
+    }
+
+    /// Makes the writer stop sending writes once `token` is canceled or its
+    /// deadline passes.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Returns the number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns `true` if no writes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Buffers the insertion of a document. Fails immediately, without
+    /// buffering, if the document is bigger than `T::bson_size_limit()`.
+    ///
+    /// Returns the result of the flush if this write triggered one.
+    pub fn insert(&mut self, entity: &T) -> Result<Option<FlushResult>> {
+        let document = T::SERDE_PROFILE.serialize(entity)?;
+        check_entity_document::<T>(&document)?;
+        self.push(WriteModel::InsertOne { document })
+    }
+
+    /// Buffers an update of a single document.
+    ///
+    /// Returns the result of the flush if this write triggered one.
+    pub fn update<U: Update<T>>(&mut self, update: U) -> Result<Option<FlushResult>> {
+        self.push(WriteModel::UpdateOne {
+            filter: update.filter(),
+            update: update.update(),
+            upsert: Some(false),
+        })
+    }
+
+    /// Buffers an upsert of a single document.
+    ///
+    /// Returns the result of the flush if this write triggered one.
+    pub fn upsert<U: Upsert<T>>(&mut self, upsert: U) -> Result<Option<FlushResult>> {
+        self.push(WriteModel::UpdateOne {
+            filter: upsert.filter(),
+            update: upsert.upsert(),
+            upsert: Some(true),
+        })
+    }
+
+    /// Sends the buffered writes if the oldest one has been waiting for at
+    /// least `max_delay`. Returns the result of the flush if there was one.
+    pub fn flush_if_due(&mut self) -> Result<Option<FlushResult>> {
+        let due = self.oldest.map_or(false, |oldest| oldest.elapsed() >= self.options.max_delay);
+
+        if due {
+            self.flush().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Sends all buffered writes in a single, ordered bulk write.
+    ///
+    /// The buffer is emptied even if the bulk write fails, since some of the
+    /// writes may already have been applied, and repeating those could
+    /// introduce duplicates.
+    pub fn flush(&mut self) -> Result<FlushResult> {
+        self.oldest = None;
+
+        if self.writes.is_empty() {
+            return Ok(FlushResult::default());
+        }
+        if let Some(ref token) = self.token {
+            if let Err(error) = token.check() {
+                self.writes.clear();
+                return Err(error);
+            }
+        }
+
+        let writes = mem::replace(&mut self.writes, Vec::with_capacity(self.options.max_size));
+        let num_writes = writes.len();
+        let result = self.collection.bulk_write(writes)?;
+
+        Ok(FlushResult {
+            num_writes,
+            num_inserted: int_to_usize_with_msg(result.inserted_count, "# of inserted documents")?,
+            num_matched: int_to_usize_with_msg(result.matched_count, "# of matched documents")?,
+            num_modified: int_to_usize_with_msg(result.modified_count, "# of modified documents")?,
+            num_upserted: int_to_usize_with_msg(result.upserted_count, "# of upserted documents")?,
+        })
+    }
+
+    /// Buffers a write, then flushes if any of the thresholds is reached.
+    fn push(&mut self, write: WriteModel) -> Result<Option<FlushResult>> {
+        self.writes.push(write);
+        self.oldest.get_or_insert_with(Instant::now);
+
+        if self.writes.len() >= self.options.max_size {
+            self.flush().map(Some)
+        } else {
+            self.flush_if_due()
+        }
+    }
+}
+
+impl<'a, T: Doc> Debug for BatchedWriter<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("BatchedWriter")
+            .field("collection", &self.collection)
+            .field("options", &self.options)
+            .field("num_buffered", &self.writes.len())
+            .field("oldest", &self.oldest)
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+/// Flushes the remaining writes. Errors are ignored, because they can't be
+/// reported from `drop()`; call `flush()` beforehand in order to handle them.
+impl<'a, T: Doc> Drop for BatchedWriter<'a, T> {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
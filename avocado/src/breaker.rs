@@ -0,0 +1,386 @@
+//! A circuit breaker for shedding load when the database is degraded.
+//!
+//! A [`Breaker`](struct.Breaker.html) keeps track of the
+//! outcome of the most recent operations executed through it. When the
+//! ratio of failures exceeds a threshold, the breaker _opens_, and further
+//! operations fail immediately with `ErrorKind::CircuitOpen`, without
+//! contacting the server. After a cool-down period, the breaker becomes
+//! _half-open_ and lets a single probe operation through: if it succeeds,
+//! the breaker closes again; otherwise it re-opens for another period.
+//!
+//! The breaker is cheap to clone, and clones share their state, so a single
+//! breaker can protect every collection of a client.
+//!
+//! ```
+//! # extern crate avocado;
+//! #
+//! # use avocado::breaker::{ Breaker, Config };
+//! # use avocado::prelude::*;
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! let breaker = Breaker::new(Config::default());
+//!
+//! // `coll.count(doc!{})` or any other fallible operation goes here
+//! let count = breaker.call(|| Ok(42))?;
+//! assert_eq!(count, 42);
+//!
+//! // a fallback is invoked when the operation fails or is rejected
+//! let count = breaker.call_or_else(
+//!     || Err(AvocadoError::new(AvocadoErrorKind::MongoDbError, "timeout")),
+//!     |_| Ok(0),
+//! )?;
+//! assert_eq!(count, 0);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use crate::error::{ Error, ErrorExt, ErrorKind, Result };
+
+/// Parameters governing when a circuit breaker opens and closes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    /// The ratio of failed operations, between 0 and 1, at or above which
+    /// the breaker opens.
+    pub failure_rate: f64,
+    /// The number of most recent operations the failure rate is computed
+    /// over. The breaker never opens before this many have been recorded.
+    pub window: usize,
+    /// How long the breaker stays open before letting a probe through.
+    pub open_duration: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            failure_rate: 0.5,
+            window: 20,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The observable state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    /// Operations are executed and their outcomes recorded.
+    Closed,
+    /// Operations are rejected without being executed.
+    Open,
+    /// A single probe operation is allowed to decide whether to close.
+    HalfOpen,
+}
+
+/// Shields the database from load while it is failing. See the
+/// [module-level documentation](index.html) for details.
+#[derive(Clone)]
+pub struct Breaker {
+    /// The configuration, fixed upon creation.
+    config: Config,
+    /// The mutable state, shared among clones.
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// The mutable state of a circuit breaker.
+#[derive(Debug)]
+struct Inner {
+    /// The current state.
+    state: Phase,
+    /// Outcomes of the most recent operations; `true` means failure.
+    outcomes: VecDeque<bool>,
+}
+
+/// The internal state of a circuit breaker, with bookkeeping information.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// Operations are allowed.
+    Closed,
+    /// Operations are rejected until the given instant.
+    Open {
+        /// When to let the next probe through.
+        until: Instant,
+    },
+    /// A probe operation is in flight.
+    HalfOpen,
+}
+
+impl Breaker {
+    /// Creates a closed circuit breaker with the given configuration.
+    pub fn new(config: Config) -> Self {
+        Breaker {
+            config,
+            inner: Arc::new(Mutex::new(Inner {
+                state: Phase::Closed,
+                outcomes: VecDeque::with_capacity(config.window),
+            })),
+        }
+    }
+
+    /// Returns the configuration of this breaker.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the current state of the breaker.
+    pub fn state(&self) -> State {
+        match self.lock().state {
+            Phase::Closed => State::Closed,
+            Phase::Open { until } => if Instant::now() < until {
+                State::Open
+            } else {
+                State::HalfOpen
+            },
+            Phase::HalfOpen => State::HalfOpen,
+        }
+    }
+
+    /// Executes `op` unless the breaker is open, and records its outcome.
+    /// Only errors of kind `MongoDbError`, i.e. those signalling connection,
+    /// server or timeout problems, count as failures; e.g. a duplicate key
+    /// or a deserialization error doesn't mean that the cluster is degraded.
+    ///
+    /// If the breaker is open, `op` is not called at all and an error of
+    /// kind `CircuitOpen` is returned instead. If `op` panics, that counts
+    /// as a failure, so a panicking probe re-opens a half-open breaker.
+    pub fn call<F, R>(&self, op: F) -> Result<R>
+        where F: FnOnce() -> Result<R>
+    {
+        let attempt = self.acquire()?;
+        let result = op();
+        let failed = match result {
+            Ok(_) => false,
+            Err(ref error) => error.kind() == ErrorKind::MongoDbError,
+        };
+
+        attempt.finish(failed);
+
+        result
+    }
+
+    /// Like `call()`, but invokes `fallback` with the error if the breaker
+    /// is open or the operation fails, e.g. for serving stale cached data.
+    pub fn call_or_else<F, G, R>(&self, op: F, fallback: G) -> Result<R>
+        where F: FnOnce() -> Result<R>,
+              G: FnOnce(Error) -> Result<R>,
+    {
+        self.call(op).or_else(fallback)
+    }
+
+    /// Closes the breaker and forgets all recorded outcomes.
+    pub fn reset(&self) {
+        let mut inner = self.lock();
+        inner.state = Phase::Closed;
+        inner.outcomes.clear();
+    }
+
+    /// Checks whether an operation may be executed, and transitions from
+    /// the open to the half-open state if the cool-down period is over.
+    /// The returned attempt records a failure unless it's finished.
+    fn acquire(&self) -> Result<Attempt<'_>> {
+        let mut inner = self.lock();
+
+        match inner.state {
+            Phase::Closed => Ok(Attempt { breaker: self, finished: false }),
+            Phase::Open { until } if Instant::now() >= until => {
+                inner.state = Phase::HalfOpen;
+                Ok(Attempt { breaker: self, finished: false })
+            }
+            Phase::Open { .. } | Phase::HalfOpen => Err(Error::new(
+                ErrorKind::CircuitOpen,
+                "circuit breaker is open; operation rejected",
+            )),
+        }
+    }
+
+    /// Records the outcome of an operation and updates the state.
+    fn record(&self, failed: bool) {
+        let mut inner = self.lock();
+
+        match inner.state {
+            Phase::HalfOpen => if failed {
+                inner.state = Phase::Open {
+                    until: Instant::now() + self.config.open_duration
+                };
+            } else {
+                inner.state = Phase::Closed;
+                inner.outcomes.clear();
+            },
+            Phase::Closed => {
+                if inner.outcomes.len() >= self.config.window {
+                    inner.outcomes.pop_front();
+                }
+                inner.outcomes.push_back(failed);
+
+                if self.should_open(&inner.outcomes) {
+                    inner.state = Phase::Open {
+                        until: Instant::now() + self.config.open_duration
+                    };
+                    inner.outcomes.clear();
+                }
+            }
+            // Another operation already opened the breaker while this one
+            // was in flight; its outcome is no longer relevant.
+            Phase::Open { .. } => {}
+        }
+    }
+
+    /// Returns `true` if the recorded outcomes warrant opening the breaker.
+    #[allow(clippy::cast_precision_loss)]
+    fn should_open(&self, outcomes: &VecDeque<bool>) -> bool {
+        if outcomes.is_empty() || outcomes.len() < self.config.window {
+            return false;
+        }
+
+        let failures = outcomes.iter().filter(|&&failed| failed).count();
+
+        failures as f64 / outcomes.len() as f64 >= self.config.failure_rate
+    }
+
+    /// Locks the shared state. A panic in another thread can't leave the
+    /// state inconsistent, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An operation admitted by a circuit breaker. If it's dropped without
+/// being finished, i.e. because the operation panicked, a failure is
+/// recorded, so that a half-open breaker doesn't wait for its probe forever.
+struct Attempt<'a> {
+    /// The breaker which admitted the operation.
+    breaker: &'a Breaker,
+    /// Whether the outcome has already been recorded.
+    finished: bool,
+}
+
+impl<'a> Attempt<'a> {
+    /// Records the outcome of the operation.
+    fn finish(mut self, failed: bool) {
+        self.finished = true;
+        self.breaker.record(failed);
+    }
+}
+
+impl<'a> Drop for Attempt<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.record(true);
+        }
+    }
+}
+
+impl<'a> Debug for Attempt<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Attempt")
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl Debug for Breaker {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Breaker")
+            .field("config", &self.config)
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::panic::{ self, AssertUnwindSafe };
+    use crate::error::{ Error, ErrorExt, ErrorKind, Result };
+    use super::{ Breaker, Config, State };
+
+    /// Creates a breaker opening at 2 failures out of 4 operations.
+    fn breaker(open_duration: Duration) -> Breaker {
+        Breaker::new(Config {
+            failure_rate: 0.5,
+            window: 4,
+            open_duration,
+        })
+    }
+
+    /// An operation failing with a server error.
+    fn fail() -> Result<()> {
+        Err(Error::new(ErrorKind::MongoDbError, "connection refused"))
+    }
+
+    #[test]
+    fn opens_after_failure_rate_exceeded() {
+        let breaker = breaker(Duration::from_secs(3600));
+
+        breaker.call(|| Ok(())).unwrap();
+        breaker.call(fail).unwrap_err();
+        breaker.call(|| Ok(())).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+
+        breaker.call(fail).unwrap_err();
+        assert_eq!(breaker.state(), State::Open);
+
+        let mut called = false;
+        let error = breaker.call(|| { called = true; Ok(()) }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::CircuitOpen);
+        assert!(!called);
+
+        let value = breaker.call_or_else(|| Ok(1), |_| Ok(2)).unwrap();
+        assert_eq!(value, 2);
+
+        breaker.reset();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn non_server_errors_are_not_failures() {
+        let breaker = breaker(Duration::from_secs(3600));
+
+        for _ in 0..8 {
+            breaker.call(|| -> Result<()> {
+                Err(Error::new(ErrorKind::BsonDecoding, "bad document"))
+            }).unwrap_err();
+        }
+
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let breaker = breaker(Duration::from_secs(0));
+
+        for _ in 0..4 {
+            breaker.call(fail).unwrap_err();
+        }
+        assert_eq!(breaker.state(), State::HalfOpen);
+
+        // failed probe re-opens the breaker
+        breaker.call(fail).unwrap_err();
+        assert_eq!(breaker.state(), State::HalfOpen);
+
+        // successful probe closes it
+        breaker.call(|| Ok(())).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn panicking_probe_reopens() {
+        let breaker = breaker(Duration::from_secs(0));
+
+        for _ in 0..4 {
+            breaker.call(fail).unwrap_err();
+        }
+
+        let probe = breaker.clone();
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            probe.call(|| -> Result<()> { panic!("probe panicked") })
+        }));
+        assert!(outcome.is_err());
+
+        // the breaker isn't stuck in the half-open state
+        breaker.call(|| Ok(())).unwrap();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+}
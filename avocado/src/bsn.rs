@@ -1,8 +1,13 @@
 //! BSON serialization and deserialization helpers.
 
-use std::borrow::Borrow;
+use std::io::{ self, Read, Write };
+use std::cmp::Reverse;
+use std::f64;
 use serde_json::Value;
 use bson::{ Bson, Document, ValueAccessError };
+use bson::oid::ObjectId;
+use bson::spec::BinarySubtype;
+use chrono::{ DateTime, Datelike, SecondsFormat, TimeZone, Utc };
 use serde::Serialize;
 use crate::error::{ Error, ErrorKind, Result };
 
@@ -98,22 +103,378 @@ pub fn serialize_document<T: Serialize>(value: &T) -> Result<Document> {
         .and_then(BsonExt::try_into_doc)
 }
 
-/// Creates an array of `Document`s from an iterator over serializable values.
-pub fn serialize_documents<T, I>(values: I) -> Result<Vec<Document>>
-    where T: Serialize,
-          I: IntoIterator,
-          I::Item: Borrow<T>,
-{
-    values
+/// Converts MongoDB Extended JSON, in its canonical or relaxed form, to BSON.
+///
+/// The single-key wrapper objects `$oid`, `$date` (an ISO-8601 string,
+/// milliseconds since the epoch, or `{ "$numberLong": "..." }`),
+/// `$numberLong`, `$numberInt`, `$numberDouble`, `$regularExpression`,
+/// `$timestamp`, `$binary`, `$symbol` and `$code` (optionally with `$scope`)
+/// are turned into the corresponding BSON values. `$numberDecimal` is an
+/// error, since the `bson` crate has no `Decimal128` type. Unlike `Bson::from_extended_document()`,
+/// a malformed wrapper, e.g. an `$oid` that isn't 24 hex digits, is an error
+/// instead of a panic. Other objects, including query operator expressions
+/// such as `{ "$regex": "^a", "$options": "i" }`, are kept as documents.
+pub fn from_extended_json(value: Value) -> Result<Bson> {
+    match value {
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Ok(Bson::I64(i)),
+            (None, Some(x)) if n.is_f64() => Ok(Bson::FloatingPoint(x)),
+            _ => Err(Error::new(
+                ErrorKind::BsonNumberRepr,
+                format!("Value `{}` can't be represented in BSON", n)
+            )),
+        },
+        Value::Array(items) => items
+            .into_iter()
+            .map(from_extended_json)
+            .collect::<Result<Vec<_>>>()
+            .map(Bson::Array),
+        Value::Object(fields) => {
+            if fields.len() == 1 {
+                let wrapped = fields.iter().next().and_then(|(k, v)| wrapped_value(k, v));
+
+                if let Some(result) = wrapped {
+                    return result;
+                }
+            }
+
+            if fields.len() == 2 {
+                if let (Some(&Value::String(ref code)), Some(raw_scope)) = (fields.get("$code"), fields.get("$scope")) {
+                    let scope = from_extended_json(raw_scope.clone())?.try_into_doc()?;
+                    return Ok(Bson::JavaScriptCodeWithScope(code.clone(), scope));
+                }
+            }
+
+            fields
+                .into_iter()
+                .map(|(k, v)| from_extended_json(v).map(|converted| (k, converted)))
+                .collect::<Result<Document>>()
+                .map(Bson::Document)
+        }
+        other => Ok(other.into()),
+    }
+}
+
+/// Converts the value of a single-key Extended JSON wrapper object.
+/// Returns `None` if the key isn't a known wrapper.
+fn wrapped_value(key: &str, value: &Value) -> Option<Result<Bson>> {
+    let malformed = || Error::new(
+        ErrorKind::JsonTranscoding,
+        format!("malformed Extended JSON `{}` value: {}", key, value)
+    );
+    let result = match (key, value) {
+        ("$oid", &Value::String(ref hex)) => {
+            ObjectId::with_string(hex).map(Bson::ObjectId).map_err(Into::into)
+        }
+        ("$date", _) => extended_date(value).map(Bson::UtcDatetime).ok_or_else(malformed),
+        ("$numberLong", &Value::String(ref digits)) => {
+            digits.parse().map(Bson::I64).map_err(|_| malformed())
+        }
+        ("$numberInt", &Value::String(ref digits)) => {
+            digits.parse().map(Bson::I32).map_err(|_| malformed())
+        }
+        ("$numberDouble", &Value::String(ref repr)) => {
+            extended_double(repr).map(Bson::FloatingPoint).ok_or_else(malformed)
+        }
+        ("$numberDecimal", _) => Err(Error::new(
+            ErrorKind::JsonTranscoding,
+            "`$numberDecimal` is not supported by the `bson` crate"
+        )),
+        ("$regularExpression", &Value::Object(ref regex)) => {
+            match (regex.get("pattern"), regex.get("options")) {
+                (Some(&Value::String(ref pattern)), Some(&Value::String(ref options))) => {
+                    Ok(Bson::RegExp(pattern.clone(), options.clone()))
+                }
+                _ => Err(malformed()),
+            }
+        }
+        ("$timestamp", &Value::Object(ref timestamp)) => {
+            let max = i64::from(u32::max_value());
+            let part = |name: &str| timestamp
+                .get(name)
+                .and_then(Value::as_i64)
+                .filter(|&n| n >= 0 && n <= max);
+
+            match (part("t"), part("i")) {
+                (Some(seconds), Some(ordinal)) => Ok(Bson::TimeStamp((seconds << 32) | ordinal)),
+                _ => Err(malformed()),
+            }
+        }
+        ("$binary", &Value::Object(ref binary)) => {
+            match (binary.get("base64"), binary.get("subType")) {
+                (Some(&Value::String(ref data)), Some(&Value::String(ref subtype))) => {
+                    match (base64::decode(data), u8::from_str_radix(subtype, 16)) {
+                        (Ok(bytes), Ok(code)) => Ok(Bson::Binary(BinarySubtype::from(code), bytes)),
+                        _ => Err(malformed()),
+                    }
+                }
+                _ => Err(malformed()),
+            }
+        }
+        ("$symbol", &Value::String(ref symbol)) => Ok(Bson::Symbol(symbol.clone())),
+        ("$code", &Value::String(ref code)) => Ok(Bson::JavaScriptCode(code.clone())),
+        ("$oid", _) | ("$numberLong", _) | ("$numberInt", _) | ("$numberDouble", _)
+            | ("$regularExpression", _) | ("$timestamp", _) | ("$binary", _)
+            | ("$symbol", _) | ("$code", _) => Err(malformed()),
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// Parses the value of a `$date` wrapper.
+fn extended_date(value: &Value) -> Option<DateTime<Utc>> {
+    match *value {
+        Value::String(ref repr) => {
+            DateTime::parse_from_rfc3339(repr).ok().map(|date| date.with_timezone(&Utc))
+        }
+        Value::Number(ref millis) => millis.as_i64().map(|ms| Utc.timestamp_millis(ms)),
+        Value::Object(ref long) if long.len() == 1 => match long.get("$numberLong") {
+            Some(&Value::String(ref digits)) => {
+                digits.parse().ok().map(|ms| Utc.timestamp_millis(ms))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parses the value of a `$numberDouble` wrapper, including the special
+/// values, which have no JSON number representation.
+fn extended_double(repr: &str) -> Option<f64> {
+    match repr {
+        "Infinity" => Some(f64::INFINITY),
+        "-Infinity" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        _ => repr.parse().ok(),
+    }
+}
+
+/// The flavors of MongoDB Extended JSON (v2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExtendedJsonMode {
+    /// Preserves the exact BSON type of every value, e.g. integers are
+    /// written as `{ "$numberInt": "42" }`. This is what `mongoexport
+    /// --jsonFormat=canonical` produces.
+    Canonical,
+    /// Writes numbers as plain JSON numbers and dates as ISO-8601 strings,
+    /// at the cost of losing the distinction between numeric types. This is
+    /// the default format of `mongoexport`.
+    Relaxed,
+}
+
+/// Converts BSON to MongoDB Extended JSON in the specified mode. The
+/// result can be converted back using [`from_extended_json()`](fn.from_extended_json.html).
+pub fn to_extended_json(value: &Bson, mode: ExtendedJsonMode) -> Value {
+    let relaxed = mode == ExtendedJsonMode::Relaxed;
+
+    match *value {
+        Bson::FloatingPoint(x) if relaxed && x.is_finite() => json!(x),
+        Bson::FloatingPoint(x) => {
+            let repr = if x.is_nan() {
+                String::from("NaN")
+            } else if x.is_infinite() {
+                String::from(if x > 0.0 { "Infinity" } else { "-Infinity" })
+            } else {
+                format!("{:?}", x)
+            };
+            json!({ "$numberDouble": repr })
+        }
+        Bson::I32(n) if relaxed => json!(n),
+        Bson::I32(n) => json!({ "$numberInt": n.to_string() }),
+        Bson::I64(n) if relaxed => json!(n),
+        Bson::I64(n) => json!({ "$numberLong": n.to_string() }),
+        Bson::String(ref string) => json!(string),
+        Bson::Boolean(b) => json!(b),
+        Bson::Null => Value::Null,
+        Bson::Array(ref items) => Value::Array(
+            items.iter().map(|item| to_extended_json(item, mode)).collect()
+        ),
+        Bson::Document(ref doc) => Value::Object(
+            doc.iter().map(|(k, v)| (k.clone(), to_extended_json(v, mode))).collect()
+        ),
+        Bson::ObjectId(ref oid) => json!({ "$oid": oid.to_hex() }),
+        Bson::UtcDatetime(ref date) if relaxed && date.year() >= 1970 && date.year() <= 9999 => {
+            json!({ "$date": date.to_rfc3339_opts(SecondsFormat::Millis, true) })
+        }
+        Bson::UtcDatetime(ref date) => {
+            json!({ "$date": { "$numberLong": date.timestamp_millis().to_string() } })
+        }
+        Bson::RegExp(ref pattern, ref options) => json!({
+            "$regularExpression": { "pattern": pattern, "options": options }
+        }),
+        Bson::TimeStamp(ts) => json!({
+            "$timestamp": { "t": (ts >> 32) & 0xFFFF_FFFF, "i": ts & 0xFFFF_FFFF }
+        }),
+        Bson::Binary(subtype, ref bytes) => json!({
+            "$binary": {
+                "base64": base64::encode(bytes),
+                "subType": format!("{:02x}", u8::from(subtype)),
+            }
+        }),
+        Bson::Symbol(ref symbol) => json!({ "$symbol": symbol }),
+        Bson::JavaScriptCode(ref code) => json!({ "$code": code }),
+        Bson::JavaScriptCodeWithScope(ref code, ref scope) => json!({
+            "$code": code,
+            "$scope": to_extended_json(&Bson::Document(scope.clone()), mode),
+        }),
+    }
+}
+
+/// Returns the size of the document when encoded as BSON, in bytes.
+pub fn document_size(doc: &Document) -> Result<usize> {
+    let mut buf = Vec::new();
+    bson::encode_document(&mut buf, doc)?;
+    Ok(buf.len())
+}
+
+/// Returns the encoded size of each top-level field of the document,
+/// in bytes, sorted in descending order of size.
+pub fn field_sizes(doc: &Document) -> Result<Vec<(String, usize)>> {
+    // An empty document takes 5 bytes: a 4-byte length and a trailing NUL.
+    let empty_size = document_size(&Document::new())?;
+    let mut sizes = doc
+        .iter()
+        .map(|(key, value)| {
+            let mut field = Document::new();
+            field.insert(key.clone(), value.clone());
+            document_size(&field).map(|size| (key.clone(), size - empty_size))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    sizes.sort_by_key(|&(_, size)| Reverse(size));
+
+    Ok(sizes)
+}
+
+/// Ensures that the document isn't bigger than `limit` bytes when encoded.
+/// If it is, the error message lists the biggest top-level fields as well.
+pub fn check_document_size(doc: &Document, limit: usize, name: &str) -> Result<()> {
+    /// The number of top-level fields reported in the error message.
+    const NUM_REPORTED_FIELDS: usize = 5;
+
+    let size = document_size(doc)?;
+
+    if size <= limit {
+        return Ok(());
+    }
+
+    let fields: Vec<_> = field_sizes(doc)?
         .into_iter()
-        .map(|val| serialize_document(val.borrow()))
-        .collect()
+        .take(NUM_REPORTED_FIELDS)
+        .map(|(key, field_size)| format!("`{}`: {} bytes", key, field_size))
+        .collect();
+
+    Err(Error::new(
+        ErrorKind::DocumentTooLarge,
+        format!("{} document is {} bytes, exceeding the limit of {} bytes; largest fields: {}",
+                name, size, limit, fields.join(", "))
+    ))
+}
+
+/// Ensures that no field name of the document, at any depth, contains a `.`
+/// or starts with a `$`, which servers before MongoDB 5.0 reject, and which
+/// can't be queried unambiguously anyway. The `$ref`, `$id` and `$db` fields
+/// of DBRefs are allowed. The error message names the first offending path.
+pub fn check_field_names(doc: &Document, name: &str) -> Result<()> {
+    match invalid_field_path(doc, "") {
+        None => Ok(()),
+        Some(path) => Err(Error::new(
+            ErrorKind::InvalidFieldName,
+            format!("{} document has invalid field name at `{}`: field names must not \
+                     contain `.` or start with `$`", name, path)
+        )),
+    }
+}
+
+/// Returns the path of the first field of `doc` whose name is invalid, if
+/// any, searching embedded documents and arrays as well.
+fn invalid_field_path(doc: &Document, prefix: &str) -> Option<String> {
+    /// The fields of DBRefs, which are the only valid names starting with `$`.
+    const DBREF_FIELDS: &[&str] = &["$ref", "$id", "$db"];
+
+    for (key, value) in doc {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        if key.contains('.') || (key.starts_with('$') && !DBREF_FIELDS.contains(&key.as_str())) {
+            return Some(path);
+        }
+        if let Some(invalid) = invalid_value_path(value, &path) {
+            return Some(invalid);
+        }
+    }
+
+    None
+}
+
+/// Returns the path of the first invalid field name within `value`, if it
+/// is a document or an array.
+fn invalid_value_path(value: &Bson, path: &str) -> Option<String> {
+    match *value {
+        Bson::Document(ref doc) => invalid_field_path(doc, path),
+        Bson::Array(ref items) => items.iter().enumerate().find_map(
+            |(index, item)| invalid_value_path(item, &format!("{}.{}", path, index))
+        ),
+        _ => None,
+    }
+}
+
+/// Ensures that the document has no top-level fields other than `known`.
+/// If it does, the error message lists all of the unknown ones.
+pub fn check_known_fields(doc: &Document, known: &[&str], name: &str) -> Result<()> {
+    let unknown: Vec<_> = doc
+        .keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| format!("`{}`", key))
+        .collect();
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::UnknownField,
+            format!("{} document contains unknown fields: {}", name, unknown.join(", "))
+        ))
+    }
+}
+
+/// Appends the document to a stream of BSON documents, as found in the
+/// `.bson` files produced by `mongodump`. Since every BSON document starts
+/// with its length, no additional framing is needed.
+pub fn write_document<W: Write>(writer: &mut W, doc: &Document) -> Result<()> {
+    bson::encode_document(writer, doc).map_err(Into::into)
+}
+
+/// Reads the next document from a stream of BSON documents. Returns `None`
+/// if the stream ended cleanly, i.e. exactly at a document boundary.
+pub fn read_document<R: Read>(reader: &mut R) -> Result<Option<Document>> {
+    let mut length = [0_u8; 4];
+    let mut n_read = 0;
+
+    while n_read < length.len() {
+        match reader.read(&mut length[n_read..]) {
+            Ok(0) if n_read == 0 => return Ok(None),
+            Ok(0) => return Err(Error::new(
+                ErrorKind::BsonDecoding, "BSON stream ends in the middle of a document"
+            )),
+            Ok(n) => n_read += n,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let mut document = io::Cursor::new(length).chain(reader);
+    bson::decode_document(&mut document).map(Some).map_err(Into::into)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{ u64, i64, i128 };
-    use crate::error::Result;
+    use std::{ u64, i64, i128, f64 };
+    use crate::error::{ ErrorExt, Result };
     use crate::prelude::*;
     use super::*;
 
@@ -141,6 +502,81 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn extended_json() -> Result<()> {
+        use chrono::{ TimeZone, Utc };
+
+        let value = json!({
+            "id": { "$oid": "5c7e5b1f0d4c8a2b3c4d5e6f" },
+            "at": { "$date": 1551398400000_i64 },
+            "n": { "$numberInt": "7" },
+            "big": { "$numberLong": "-9007199254740993" },
+            "nan": { "$numberDouble": "NaN" },
+            "re": { "$regularExpression": { "pattern": "^a", "options": "i" } },
+            "ts": { "$timestamp": { "t": 1, "i": 2 } },
+            "list": [1, 2.5, null, { "$oid": "5c7e5b1f0d4c8a2b3c4d5e6f" }],
+            "op": { "$regex": "^a", "$options": "i" },
+        });
+        let doc = from_extended_json(value)?.try_into_doc()?;
+
+        assert_eq!(doc.get_object_id("id")?.to_hex(), "5c7e5b1f0d4c8a2b3c4d5e6f");
+        assert_eq!(doc.get_utc_datetime("at")?, &Utc.ymd(2019, 3, 1).and_hms(0, 0, 0));
+        assert_eq!(doc.get_i32("n")?, 7);
+        assert_eq!(doc.get_i64("big")?, -9_007_199_254_740_993);
+        assert!(doc.get_f64("nan")?.is_nan());
+        assert_eq!(doc.get("re"), Some(&Bson::RegExp("^a".into(), "i".into())));
+        assert_eq!(doc.get("ts"), Some(&Bson::TimeStamp((1 << 32) | 2)));
+        assert_eq!(doc.get_array("list")?[..3], [Bson::I64(1), Bson::FloatingPoint(2.5), Bson::Null]);
+        assert_eq!(doc.get_document("op")?, &doc!{ "$regex": "^a", "$options": "i" });
+
+        assert!(from_extended_json(json!({ "$oid": "xyz" })).is_err());
+        assert!(from_extended_json(json!({ "$date": "yesterday" })).is_err());
+        assert!(from_extended_json(json!({ "$numberLong": 42 })).is_err());
+        assert!(from_extended_json(json!({ "$timestamp": { "t": -1, "i": 0 } })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_json_round_trip() -> Result<()> {
+        use chrono::{ TimeZone, Utc };
+        use bson::spec::BinarySubtype;
+
+        let doc = doc!{
+            "id": ObjectId::with_string("5c7e5b1f0d4c8a2b3c4d5e6f")?,
+            "n": 7,
+            "big": 9_007_199_254_740_993_i64,
+            "x": 1.5,
+            "inf": f64::INFINITY,
+            "at": Utc.ymd(2019, 3, 1).and_hms_milli(12, 30, 0, 250),
+            "bin": (BinarySubtype::Generic, vec![1_u8, 2, 3]),
+            "re": Bson::RegExp("^a".into(), "i".into()),
+            "js": Bson::JavaScriptCodeWithScope("x + y".into(), doc!{ "y": 1 }),
+            "nested": [{ "ts": Bson::TimeStamp((5 << 32) | 6) }],
+        };
+        let value = Bson::Document(doc.clone());
+
+        let canonical = to_extended_json(&value, ExtendedJsonMode::Canonical);
+        assert_eq!(canonical["n"], json!({ "$numberInt": "7" }));
+        assert_eq!(canonical["x"], json!({ "$numberDouble": "1.5" }));
+        assert_eq!(canonical["at"], json!({ "$date": { "$numberLong": "1551443400250" } }));
+        assert_eq!(canonical["bin"], json!({ "$binary": { "base64": "AQID", "subType": "00" } }));
+        assert_eq!(from_extended_json(canonical)?, value);
+
+        let relaxed = to_extended_json(&value, ExtendedJsonMode::Relaxed);
+        assert_eq!(relaxed["n"], json!(7));
+        assert_eq!(relaxed["inf"], json!({ "$numberDouble": "Infinity" }));
+        assert_eq!(relaxed["at"], json!({ "$date": "2019-03-01T12:30:00.250Z" }));
+        assert_eq!(relaxed["nested"][0]["ts"], json!({ "$timestamp": { "t": 5, "i": 6 } }));
+
+        // Relaxed mode doesn't preserve the width of integers
+        let relaxed_doc = from_extended_json(relaxed)?.try_into_doc()?;
+        assert_eq!(relaxed_doc.get("n"), Some(&Bson::I64(7)));
+        assert_eq!(relaxed_doc.get("at"), doc.get("at"));
+
+        Ok(())
+    }
+
     #[test]
     fn bson_ext_try_into_doc() -> Result<()> {
         let doc = bson!({ "foo": "bar", "qux": 3.14 });
@@ -203,23 +639,106 @@ mod tests {
     }
 
     #[test]
-    fn serialize_many_documents() -> Result<()> {
-        #[derive(Serialize)]
-        struct Number { value: u64 };
+    fn document_and_field_sizes() -> Result<()> {
+        let doc = doc!{
+            "small": 1_i32,
+            "big": "0123456789",
+        };
+
+        assert_eq!(document_size(&Document::new())?, 5);
+        // 5 bytes of overhead + (1 + 6 + 4) for `small` + (1 + 4 + 4 + 11) for `big`
+        assert_eq!(document_size(&doc)?, 36);
+        assert_eq!(field_sizes(&doc)?, vec![
+            (String::from("big"), 20),
+            (String::from("small"), 11),
+        ]);
 
-        let good = Number { value: i64::MAX as u64 };
-        let bad = Number { value: i64::MAX as u64 + 1 };
+        Ok(())
+    }
 
-        assert_eq!(serialize_documents::<Number, _>(vec![&good, &good])?,
-                   vec![
-                       doc!{ "value": i64::MAX },
-                       doc!{ "value": i64::MAX },
-                   ]);
+    #[test]
+    fn known_fields() {
+        let doc = doc!{ "_id": 1, "name": "foo", "legacy": true, "extra": null };
 
-        assert!(serialize_documents::<Number, _>(vec![&good, &bad])
-                .unwrap_err()
-                .to_string()
-                .contains("can't be represented in BSON"));
+        assert!(check_known_fields(&doc, &["_id", "name", "legacy", "extra"], "Foo").is_ok());
+        assert!(check_known_fields(&doc!{}, &[], "Foo").is_ok());
+
+        let error = check_known_fields(&doc, &["_id", "name"], "Foo").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnknownField);
+        assert!(error.to_string().contains("unknown fields: `legacy`, `extra`"));
+    }
+
+    #[test]
+    fn document_stream() -> Result<()> {
+        let docs = vec![
+            doc!{ "_id": 1, "name": "foo" },
+            doc!{},
+            doc!{ "_id": 2, "nested": { "list": [1, 2, 3] } },
+        ];
+        let mut buf = Vec::new();
+
+        for doc in &docs {
+            write_document(&mut buf, doc)?;
+        }
+
+        let mut reader = buf.as_slice();
+        let mut read = Vec::new();
+
+        while let Some(doc) = read_document(&mut reader)? {
+            read.push(doc);
+        }
+
+        assert_eq!(read, docs);
+
+        // A truncated stream is an error, not a clean end
+        let mut truncated = &buf[..buf.len() - 1];
+        assert!(read_document(&mut truncated)?.is_some());
+        assert!(read_document(&mut truncated)?.is_some());
+        assert!(read_document(&mut truncated).is_err());
+
+        let mut torn_length = &buf[..2];
+        assert!(read_document(&mut torn_length).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_field_names() {
+        let valid = doc!{
+            "name": "x",
+            "owner": { "$ref": "User", "$id": 1, "$db": "app" },
+            "tags": [{ "label": "a" }],
+        };
+
+        assert!(check_field_names(&valid, "Sample").is_ok());
+
+        let dotted = doc!{ "items": [{ "ok": 1 }, { "price.usd": 2 }] };
+        let error = check_field_names(&dotted, "Sample").unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidFieldName);
+        assert!(error.to_string().contains("Sample document has invalid field name at `items.1.price.usd`"));
+
+        let dollar = doc!{ "meta": { "$where": "true" } };
+        let message = check_field_names(&dollar, "Sample").unwrap_err().to_string();
+
+        assert!(message.contains("at `meta.$where`"));
+    }
+
+    #[test]
+    fn check_size_limit() -> Result<()> {
+        let doc = doc!{
+            "small": 1_i32,
+            "big": "0123456789",
+        };
+
+        check_document_size(&doc, 36, "Sample")?;
+
+        let error = check_document_size(&doc, 35, "Sample").unwrap_err();
+        let message = error.to_string();
+
+        assert_eq!(error.kind(), ErrorKind::DocumentTooLarge);
+        assert!(message.contains("Sample document is 36 bytes"));
+        assert!(message.contains("`big`: 20 bytes, `small`: 11 bytes"));
 
         Ok(())
     }
@@ -0,0 +1,404 @@
+//! Using a collection as a cache of expensive-to-compute values.
+//!
+//! A [`Cache`](struct.Cache.html) stores entities under a key, which becomes
+//! their `_id`, together with an expiration date. Expired entries are
+//! removed by the server, through a TTL index on the expiration date field,
+//! which must be declared on the document type with an `expire_after_secs`
+//! (or `expire_after_seconds`) of 0, meaning that each document expires at
+//! the date stored in it:
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use bson::UtcDateTime;
+//! # use avocado::prelude::*;
+//! # use avocado::cache::Cache;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! #[id_type = "String"]
+//! #[index(keys(expires_at = "ascending"), expire_after_secs = 0)]
+//! struct ExchangeRate {
+//!     _id: Uid<ExchangeRate>,
+//!     rate: f64,
+//!     expires_at: Option<UtcDateTime>,
+//! }
+//!
+//! # fn fetch_rate(_currency: &str) -> f64 { 1.0 }
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let collection: Collection<ExchangeRate> = db.existing_collection();
+//! collection.create_indexes()?;
+//!
+//! let cache = Cache::new(collection, "expires_at");
+//! let key = Uid::from_raw(String::from("EUR/HUF"));
+//! let ttl = Duration::from_secs(15 * 60);
+//! let rate = cache.get_or_compute(key, ttl, || Ok(ExchangeRate {
+//!     _id: Uid::from_raw(String::new()),
+//!     rate: fetch_rate("HUF"),
+//!     expires_at: None,
+//! }))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server deletes expired documents only periodically, about once a
+//! minute, so lookups additionally ignore the entries whose expiration date
+//! has passed. Concurrent computations of the same missing entry are not
+//! coordinated: each of them stores its result, and the last one wins.
+//!
+//! A [`Queries`](struct.Queries.html), on the other hand, keeps the
+//! results of `find_one()` and `find_many()` queries in memory, keyed by the
+//! serialized filter and options, for absorbing hot read paths. Cached
+//! results expire after a TTL, and all of them are discarded as soon as a
+//! write command on the collection is sent through the same client, be it
+//! by Avocado or by the driver (it is noticed by a command listener; see the
+//! [`monitor`](../monitor/index.html) module). Writes by other clients or
+//! processes are only reflected after the TTL.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use avocado::prelude::*;
+//! # use avocado::cache::Queries;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct Product {
+//!     _id: Uid<Product>,
+//!     name: String,
+//!     featured: bool,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let products: Collection<Product> = db.existing_collection();
+//! let cache = Queries::new(products, Duration::from_secs(30))?;
+//!
+//! let featured = cache.find_many(doc!{ "featured": true })?; // queries the server
+//! let featured = cache.find_many(doc!{ "featured": true })?; // served from memory
+//! # Ok(())
+//! # }
+//! ```
+
+use std::any::{ Any, type_name };
+use std::time::{ Duration, Instant };
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::collections::HashMap;
+use chrono::Utc;
+use bson::{ Bson, Document };
+use crate::{
+    coll::{ Collection, find_command },
+    doc::Doc,
+    uid::Uid,
+    ops::{ Query, Upsert },
+    monitor::{ self, CommandListener, CommandStarted, CommandSucceeded, CommandFailed, ListenerHandle },
+    error::{ Error, ErrorKind, Result },
+};
+
+/// The commands which modify the documents of a collection, and thus
+/// invalidate the results held by a `Queries`.
+const WRITE_COMMANDS: &[&str] = &[
+    "insert", "update", "delete", "findAndModify", "findandmodify", "drop", "renameCollection",
+];
+
+/// A cache of `T` entities, keyed by their `_id`, stored in a collection
+/// with a TTL index.
+#[derive(Debug)]
+pub struct Cache<T: Doc> {
+    /// The collection holding the entries.
+    collection: Collection<T>,
+    /// The name of the field storing the expiration date of an entry.
+    expiry_field: &'static str,
+}
+
+impl<T: Doc> Cache<T> {
+    /// Creates a cache storing its entries in `collection`, with their
+    /// expiration date in the `expiry_field` field, as serialized.
+    pub fn new(collection: Collection<T>, expiry_field: &'static str) -> Self {
+        Cache { collection, expiry_field }
+    }
+
+    /// Returns the underlying collection.
+    pub fn collection(&self) -> &Collection<T> {
+        &self.collection
+    }
+
+    /// Returns the entry stored under `key`, unless it's missing or expired.
+    pub fn get(&self, key: &Uid<T>) -> Result<Option<T>> {
+        let mut filter = doc!{ "_id": bson::to_bson(key)? };
+        filter.insert(self.expiry_field, doc!{ "$gt": Utc::now() });
+        self.collection.find_one(filter)
+    }
+
+    /// Stores `value` under `key`, replacing any previous entry, so that it
+    /// expires after `ttl`. The `_id` and the expiration date field of the
+    /// stored value are overwritten. Returns the value with `key` as its `_id`.
+    pub fn put(&self, key: Uid<T>, mut value: T, ttl: Duration) -> Result<T> {
+        let lifetime = chrono::Duration::from_std(ttl).map_err(|_| Error::new(
+            ErrorKind::IntConversionOverflow,
+            format!("cache TTL of {:?} is out of range", ttl)
+        ))?;
+        let id = bson::to_bson(&key)?;
+
+        value.set_id(key);
+
+        let mut fields = T::SERDE_PROFILE.serialize(&value)?;
+        fields.remove("_id");
+        fields.insert(self.expiry_field, Utc::now() + lifetime);
+
+        self.collection.upsert_one(Entry { id, fields })?;
+
+        Ok(value)
+    }
+
+    /// Returns the entry stored under `key`. If it's missing or expired,
+    /// computes it by calling `compute`, then stores it, so that it expires
+    /// after `ttl`. Errors returned by `compute` are propagated, and nothing
+    /// is stored in that case.
+    pub fn get_or_compute<F>(&self, key: Uid<T>, ttl: Duration, compute: F) -> Result<T>
+        where F: FnOnce() -> Result<T>
+    {
+        match self.get(&key)? {
+            Some(value) => Ok(value),
+            None => self.put(key, compute()?, ttl),
+        }
+    }
+
+    /// Removes the entry stored under `key`. Returns `true` if there was one,
+    /// including an expired one not yet deleted by the server.
+    pub fn invalidate(&self, key: &Uid<T>) -> Result<bool> {
+        self.collection.delete_one(doc!{ "_id": bson::to_bson(key)? })
+    }
+}
+
+/// Stores the fields of a cache entry under its key.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// The key of the entry.
+    id: Bson,
+    /// The fields of the entry, except for `_id`.
+    fields: Document,
+}
+
+impl<T: Doc> Upsert<T> for Entry {
+    fn filter(&self) -> Document {
+        doc!{ "_id": self.id.clone() }
+    }
+
+    fn upsert(&self) -> Document {
+        doc!{ "$set": self.fields.clone() }
+    }
+}
+
+/// An in-memory cache of the results of queries on a collection, which are
+/// discarded after a TTL, or when the collection is written to.
+#[derive(Debug)]
+pub struct Queries<T: Doc> {
+    /// The collection whose query results are cached.
+    collection: Collection<T>,
+    /// How long a result stays valid.
+    ttl: Duration,
+    /// The cached results, shared with the invalidating command listener.
+    state: Arc<Mutex<QueryCacheState>>,
+    /// The command listener invalidating the results upon writes.
+    listener: ListenerHandle,
+}
+
+impl<T: Doc> Queries<T> {
+    /// Creates an empty cache of the queries on `collection`, whose results
+    /// are valid for `ttl`, and registers a command listener for noticing
+    /// the writes to the collection.
+    pub fn new(collection: Collection<T>, ttl: Duration) -> Result<Self> {
+        let state = Arc::new(Mutex::new(QueryCacheState::default()));
+        let listener = collection.add_command_listener(Invalidator { state: state.clone() })?;
+
+        Ok(Queries { collection, ttl, state, listener })
+    }
+
+    /// Returns the underlying collection, which doesn't cache.
+    pub fn collection(&self) -> &Collection<T> {
+        &self.collection
+    }
+
+    /// Like `Collection::find_one()`, but returns the cached result of the
+    /// same query, if there is a valid one.
+    pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>>
+        where Q::Output: Clone + Send + Sync + 'static
+    {
+        let key = cache_key("find_one", &query);
+        self.cached(key, || self.collection.find_one(query))
+    }
+
+    /// Like `Collection::find_many()`, but collects the results, and returns
+    /// the cached results of the same query, if there are valid ones.
+    pub fn find_many<Q: Query<T>>(&self, query: Q) -> Result<Vec<Q::Output>>
+        where Q::Output: Clone + Send + Sync + 'static
+    {
+        let key = cache_key("find_many", &query);
+        self.cached(key, || self.collection.find_many(query)?.collect())
+    }
+
+    /// Discards all cached results, e.g. after the collection was modified
+    /// by another client.
+    pub fn invalidate(&self) {
+        lock_state(&self.state).invalidate();
+    }
+
+    /// Returns the valid cached value under `key`, or the result of `fetch`,
+    /// which is cached unless the collection was written to in the meantime.
+    fn cached<V, F>(&self, key: String, fetch: F) -> Result<V>
+        where V: Clone + Send + Sync + 'static,
+              F: FnOnce() -> Result<V>,
+    {
+        let generation = {
+            let mut state = lock_state(&self.state);
+
+            if let Some(entry) = state.entries.get(&key) {
+                if entry.expires_at > Instant::now() {
+                    if let Some(value) = entry.value.downcast_ref::<V>() {
+                        return Ok(value.clone());
+                    }
+                }
+            }
+
+            state.entries.remove(&key);
+            state.generation
+        };
+        let value = fetch()?;
+        let mut state = lock_state(&self.state);
+
+        // The result of a query running concurrently with a write may or may
+        // not reflect the write, so it isn't cached.
+        if state.generation == generation {
+            let now = Instant::now();
+
+            state.entries.retain(|_, entry| entry.expires_at > now);
+            state.entries.insert(key, CachedResult {
+                expires_at: now + self.ttl,
+                value: Arc::new(value.clone()),
+            });
+        }
+
+        Ok(value)
+    }
+}
+
+impl<T: Doc> Drop for Queries<T> {
+    fn drop(&mut self) {
+        monitor::remove_listener(self.listener);
+    }
+}
+
+/// The results held by a `Queries`.
+#[derive(Debug, Default)]
+struct QueryCacheState {
+    /// The cached results, keyed by the operation and the serialized query.
+    entries: HashMap<String, CachedResult>,
+    /// Incremented upon every invalidation.
+    generation: u64,
+}
+
+impl QueryCacheState {
+    /// Discards all cached results.
+    fn invalidate(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+    }
+}
+
+/// A cached query result.
+#[derive(Debug, Clone)]
+struct CachedResult {
+    /// The instant after which the result is no longer valid.
+    expires_at: Instant,
+    /// The result, of the type returned by the query.
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+/// Invalidates the results of a `Queries` when a write command on its
+/// collection is started, and again when it completes.
+#[derive(Debug)]
+struct Invalidator {
+    /// The state of the cache.
+    state: Arc<Mutex<QueryCacheState>>,
+}
+
+impl Invalidator {
+    /// Invalidates the results if the command writes to the collection.
+    fn notice(&self, command_name: &str) {
+        if WRITE_COMMANDS.contains(&command_name) {
+            lock_state(&self.state).invalidate();
+        }
+    }
+}
+
+impl CommandListener for Invalidator {
+    fn started(&self, event: &CommandStarted) {
+        self.notice(&event.command_name);
+    }
+
+    fn succeeded(&self, event: &CommandSucceeded) {
+        self.notice(&event.command_name);
+    }
+
+    fn failed(&self, event: &CommandFailed) {
+        self.notice(&event.command_name);
+    }
+}
+
+/// Locks the state of a `Queries`. The state is consistent at all times,
+/// so poisoning is ignored.
+fn lock_state(state: &Mutex<QueryCacheState>) -> MutexGuard<'_, QueryCacheState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the key of the results of a query: the operation, the type of
+/// the query (which determines the type of the results), and the `find`
+/// command built from the filter and the options.
+fn cache_key<T: Doc, Q: Query<T>>(operation: &str, query: &Q) -> String {
+    let command = find_command(T::NAME.into(), query.filter(), query.options(), &query.command_options());
+    format!("{} {} {}", operation, type_name::<Q>(), Bson::Document(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+    use crate::monitor::{ CommandListener, CommandStarted };
+    use super::{ Invalidator, QueryCacheState };
+
+    #[test]
+    fn writes_invalidate() {
+        let state = Arc::new(Mutex::new(QueryCacheState::default()));
+        let invalidator = Invalidator { state: state.clone() };
+        let event = |command_name: &str| CommandStarted {
+            command_name: command_name.into(),
+            database: String::from("db"),
+            collection: Some(String::from("coll")),
+            filter: None,
+            size: 0,
+            request_id: 1,
+        };
+
+        invalidator.started(&event("find"));
+        assert_eq!(state.lock().unwrap().generation, 0);
+
+        invalidator.started(&event("update"));
+        invalidator.started(&event("findAndModify"));
+        assert_eq!(state.lock().unwrap().generation, 2);
+    }
+}
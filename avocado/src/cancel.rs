@@ -0,0 +1,420 @@
+//! Deadlines and cancellation of long-running operations.
+//!
+//! A [`CancellationToken`](struct.CancellationToken.html) is passed to
+//! operations which may run for a long time, such as
+//! `Collection::find_many_cancellable()`, `Collection::aggregate_cancellable()`
+//! and a `BatchedWriter` created with `with_cancellation()`. It limits the
+//! operations in two ways:
+//!
+//! * If the token has a deadline, the remaining time is sent to the server
+//!   as `maxTimeMS`, so the server stops working on the operation by itself
+//!   once the deadline passes.
+//! * When the token is canceled, e.g. by another thread handling a client
+//!   disconnect, the server-side cursors of the operations using it are
+//!   killed with `killCursors`, and the commands still running on the server
+//!   are interrupted with `killOp`. The operations then fail with a
+//!   `Canceled` error instead of leaving abandoned work on the server.
+//!
+//! The commands are recognized by a unique comment, so killing them requires
+//! a server version and privileges which allow `$currentOp` and `killOp` on
+//! one's own operations.
+//!
+//! ```
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use avocado::cancel::CancellationToken;
+//! # use avocado::error::{ ErrorExt, ErrorKind };
+//! #
+//! # fn main() -> avocado::error::Result<()> {
+//! let token = CancellationToken::with_timeout(Duration::from_secs(30));
+//! let handle = token.clone();
+//!
+//! assert!(!token.is_canceled());
+//! assert!(token.max_time_ms(None).map_or(false, |ms| ms <= 30_000));
+//!
+//! // e.g. on another thread
+//! handle.cancel()?;
+//!
+//! assert!(token.is_canceled());
+//! assert_eq!(token.check().unwrap_err().kind(), ErrorKind::Canceled);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::process;
+use std::convert::TryFrom;
+use std::sync::{ Arc, Mutex, MutexGuard, PoisonError };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use bson::{ Bson, Document };
+use mongodb::db::{ Database, ThreadedDatabase };
+use mongodb::{ CommandType, ThreadedClient };
+use crate::error::{ Error, ErrorKind::Canceled, Result };
+
+/// The number of tokens created by this process, used for making their
+/// comments unique.
+static NUM_TOKENS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets a deadline for, and allows canceling, long-running operations.
+/// Clones share their state, so canceling any of them cancels all.
+#[derive(Clone)]
+pub struct CancellationToken {
+    /// The state shared by the clones.
+    state: Arc<TokenState>,
+}
+
+/// The shared state of a `CancellationToken`.
+struct TokenState {
+    /// The comment identifying the commands issued with the token.
+    comment: String,
+    /// The point in time after which the operations are abandoned, if any.
+    deadline: Option<Instant>,
+    /// Whether `cancel()` was called.
+    canceled: AtomicBool,
+    /// The operations currently running with the token.
+    active: Mutex<ActiveOps>,
+}
+
+/// The operations currently running with a token.
+#[derive(Default)]
+struct ActiveOps {
+    /// The identifier of the next registered operation.
+    next_id: usize,
+    /// The registered operations.
+    ops: Vec<ActiveOp>,
+}
+
+/// An operation running with a token.
+struct ActiveOp {
+    /// Identifies the registration.
+    id: usize,
+    /// The database the operation runs in.
+    db: Database,
+    /// The name of the collection the operation reads.
+    collection: String,
+    /// The ID of the server-side cursor of the operation, or 0 if it has none.
+    cursor_id: i64,
+}
+
+impl CancellationToken {
+    /// Creates a token without a deadline.
+    pub fn new() -> Self {
+        Self::with_optional_deadline(None)
+    }
+
+    /// Creates a token whose operations are abandoned at `deadline`.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self::with_optional_deadline(Some(deadline))
+    }
+
+    /// Creates a token whose operations are abandoned after `timeout`.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Creates a token with a unique comment.
+    fn with_optional_deadline(deadline: Option<Instant>) -> Self {
+        let serial = NUM_TOKENS.fetch_add(1, Ordering::SeqCst);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let comment = format!("avocado-cancel:{}:{}:{}", process::id(), nanos, serial);
+
+        CancellationToken {
+            state: Arc::new(TokenState {
+                comment,
+                deadline,
+                canceled: AtomicBool::new(false),
+                active: Mutex::new(ActiveOps::default()),
+            }),
+        }
+    }
+
+    /// Returns the deadline, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.deadline
+    }
+
+    /// Returns the time left until the deadline, if any. Zero if it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.state.deadline.map(|deadline| {
+            let now = Instant::now();
+
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        })
+    }
+
+    /// Returns the comment attached to the commands issued with the token.
+    pub fn comment(&self) -> &str {
+        &self.state.comment
+    }
+
+    /// Returns `true` if the token was canceled or its deadline has passed.
+    pub fn is_canceled(&self) -> bool {
+        self.state.canceled.load(Ordering::SeqCst)
+            || self.remaining() == Some(Duration::from_secs(0))
+    }
+
+    /// Returns a `Canceled` error if the token was canceled or its deadline
+    /// has passed.
+    pub fn check(&self) -> Result<()> {
+        if self.state.canceled.load(Ordering::SeqCst) {
+            Err(Error::new(Canceled, "operation canceled"))
+        } else if self.remaining() == Some(Duration::from_secs(0)) {
+            Err(Error::new(Canceled, "deadline exceeded"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the `maxTimeMS` of an operation: the smaller one of the
+    /// explicitly specified limit and the time left until the deadline,
+    /// which is rounded up to whole milliseconds.
+    pub fn max_time_ms(&self, explicit: Option<i64>) -> Option<i64> {
+        let remaining = self.remaining().map(|remaining| {
+            let millis = remaining.as_secs()
+                .saturating_mul(1000)
+                .saturating_add(u64::from((remaining.subsec_nanos() + 999_999) / 1_000_000));
+
+            i64::try_from(millis.max(1)).unwrap_or_else(|_| i64::max_value())
+        });
+
+        match (explicit, remaining) {
+            (Some(limit), Some(left)) => Some(limit.min(left)),
+            (limit, left) => limit.or(left),
+        }
+    }
+
+    /// Cancels the operations using the token: kills their server-side
+    /// cursors and interrupts their running commands. Operations started
+    /// afterwards fail immediately. Returns the first error encountered
+    /// while killing the operations; the token is canceled regardless.
+    pub fn cancel(&self) -> Result<()> {
+        self.state.canceled.store(true, Ordering::SeqCst);
+
+        let ops: Vec<_> = self.lock_active()
+            .ops
+            .iter()
+            .map(|op| (op.db.clone(), op.collection.clone(), op.cursor_id))
+            .collect();
+        let mut result = Ok(());
+
+        for &(ref db, ref collection, cursor_id) in &ops {
+            if cursor_id != 0 {
+                result = result.and(kill_cursor(db, collection, cursor_id));
+            }
+        }
+
+        if let Some(&(ref db, _, _)) = ops.first() {
+            result = result.and(self.kill_commands(db));
+        }
+
+        result
+    }
+
+    /// Interrupts the commands carrying the comment of the token, using the
+    /// client of `db`.
+    fn kill_commands(&self, db: &Database) -> Result<()> {
+        let admin = db.client.db("admin");
+        let comment = self.comment();
+        let command = doc!{
+            "aggregate": 1,
+            "pipeline": [
+                { "$currentOp": {} },
+                {
+                    "$match": {
+                        "$or": [
+                            { "command.comment": comment },
+                            { "cursor.originatingCommand.comment": comment },
+                        ]
+                    }
+                },
+                { "$project": { "opid": 1 } },
+            ],
+            "cursor": {},
+        };
+        let mut reply = admin.command(command, CommandType::Suppressed, None)?;
+        let batch = match reply.remove("cursor") {
+            Some(Bson::Document(mut cursor)) => match cursor.remove("firstBatch") {
+                Some(Bson::Array(batch)) => batch,
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+
+        for item in batch {
+            if let Bson::Document(mut op) = item {
+                if let Some(opid) = op.remove("opid") {
+                    admin.command(doc!{ "killOp": 1, "op": opid }, CommandType::Suppressed, None)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prepares a `find` or `aggregate` command to run with the token: sets
+    /// its comment, and limits its `maxTimeMS` to the time left.
+    pub(crate) fn attach(&self, command: &mut Document) {
+        let explicit = match command.get("maxTimeMS") {
+            Some(&Bson::I64(ms)) => Some(ms),
+            Some(&Bson::I32(ms)) => Some(i64::from(ms)),
+            _ => None,
+        };
+
+        command.insert("comment", self.comment());
+
+        if let Some(max_time_ms) = self.max_time_ms(explicit) {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+    }
+
+    /// Records that an operation is running in `db` with the token, until
+    /// the returned guard is dropped.
+    pub(crate) fn register(&self, db: &Database, collection: &str) -> ActiveGuard<'_> {
+        let mut active = self.lock_active();
+        let id = active.next_id;
+
+        active.next_id += 1;
+        active.ops.push(ActiveOp {
+            id,
+            db: db.clone(),
+            collection: collection.into(),
+            cursor_id: 0,
+        });
+
+        ActiveGuard { token: self, id }
+    }
+
+    /// Converts the error of a failed operation into a `Canceled` error if
+    /// it was caused by canceling the token.
+    pub(crate) fn interrupted(&self, error: Error) -> Error {
+        if self.is_canceled() {
+            Error::new(Canceled, format!("operation canceled: {}", error))
+        } else {
+            error
+        }
+    }
+
+    /// Locks the registered operations. They are consistent at all times,
+    /// so poisoning is ignored.
+    fn lock_active(&self) -> MutexGuard<'_, ActiveOps> {
+        self.state.active.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for CancellationToken {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("CancellationToken")
+            .field("comment", &self.state.comment)
+            .field("deadline", &self.state.deadline)
+            .field("canceled", &self.state.canceled.load(Ordering::SeqCst))
+            .field("num_active", &self.lock_active().ops.len())
+            .finish()
+    }
+}
+
+/// Keeps an operation registered with a token while it's running.
+pub(crate) struct ActiveGuard<'a> {
+    /// The token the operation is registered with.
+    token: &'a CancellationToken,
+    /// Identifies the registration.
+    id: usize,
+}
+
+impl<'a> ActiveGuard<'a> {
+    /// Records the ID of the server-side cursor of the operation, so that
+    /// it is killed if the token is canceled.
+    pub(crate) fn set_cursor(&self, cursor_id: i64) {
+        let mut active = self.token.lock_active();
+
+        if let Some(op) = active.ops.iter_mut().find(|op| op.id == self.id) {
+            op.cursor_id = cursor_id;
+        }
+    }
+
+    /// Kills the server-side cursor of the operation, which is abandoned
+    /// before the cursor was exhausted.
+    pub(crate) fn kill_cursor(&self) -> Result<()> {
+        let cursor = self.token
+            .lock_active()
+            .ops
+            .iter()
+            .find(|op| op.id == self.id && op.cursor_id != 0)
+            .map(|op| (op.db.clone(), op.collection.clone(), op.cursor_id));
+
+        match cursor {
+            Some((db, collection, cursor_id)) => kill_cursor(&db, &collection, cursor_id),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Drop for ActiveGuard<'a> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.token.lock_active().ops.retain(|op| op.id != id);
+    }
+}
+
+/// Kills a server-side cursor.
+fn kill_cursor(db: &Database, collection: &str, cursor_id: i64) -> Result<()> {
+    let command = doc!{
+        "killCursors": collection,
+        "cursors": [cursor_id],
+    };
+
+    db.command(command, CommandType::Suppressed, None)
+        .map(drop)
+        .map_err(From::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, Instant };
+    use crate::error::{ ErrorExt, ErrorKind };
+    use super::CancellationToken;
+
+    #[test]
+    fn deadline_limits_max_time() {
+        let token = CancellationToken::with_timeout(Duration::from_secs(10));
+        let max_time_ms = token.max_time_ms(None).unwrap_or(0);
+
+        assert!(max_time_ms > 9_000 && max_time_ms <= 10_000);
+        assert_eq!(token.max_time_ms(Some(250)), Some(250));
+        assert!(token.max_time_ms(Some(60_000)).unwrap_or(0) <= 10_000);
+        assert!(token.check().is_ok());
+
+        let unlimited = CancellationToken::new();
+        assert_eq!(unlimited.max_time_ms(None), None);
+        assert_eq!(unlimited.max_time_ms(Some(250)), Some(250));
+        assert_ne!(unlimited.comment(), token.comment());
+    }
+
+    #[test]
+    fn canceled_and_expired_tokens() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        clone.cancel().unwrap();
+        assert!(token.is_canceled());
+        assert_eq!(token.check().unwrap_err().kind(), ErrorKind::Canceled);
+
+        let expired = CancellationToken::with_deadline(Instant::now());
+        assert!(expired.is_canceled());
+        assert_eq!(expired.check().unwrap_err().kind(), ErrorKind::Canceled);
+        assert_eq!(expired.max_time_ms(None), Some(1));
+    }
+}
@@ -0,0 +1,459 @@
+//! A supervised consumer of MongoDB change streams.
+//!
+//! A [`Consumer`](struct.Consumer.html) opens a
+//! change stream on a collection (using the `$changeStream` aggregation
+//! stage) and passes every event to a user-supplied handler. It keeps the
+//! stream alive across failovers and network errors: the stream is reopened
+//! with exponential backoff, resuming after the last successfully handled
+//! event. Since the resume token is only advanced once the handler returns
+//! successfully, events are delivered **at least once**; handlers should
+//! therefore be idempotent.
+//!
+//! If the stream is invalidated (e.g. because the collection was dropped or
+//! renamed), it can't be resumed, so the consumer starts a fresh stream.
+//!
+//! The current lag of the consumer, i.e. the difference between the wall
+//! clock and the cluster time of the last handled event, along with other
+//! counters, is available through [`ConsumerMetrics`](struct.ConsumerMetrics.html),
+//! which can be shared with e.g. a monitoring thread.
+//!
+//! For simply iterating over the events of a change stream, without
+//! supervision, use `Collection::watch()`, which also deserializes the
+//! changed documents into the document type of the collection.
+
+use std::thread;
+use std::marker::PhantomData;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use serde::Deserialize;
+use bson::{ Bson, Document };
+use mongodb::coll::options::AggregateOptions;
+use crate::{
+    coll::Collection,
+    doc::Doc,
+    ops::Pipeline,
+    error::{ Error, Result },
+};
+
+/// A single event of a change stream. The type parameter `D` is the type of
+/// the changed document; it's a raw `Document` unless the stream is opened
+/// by `Collection::watch()`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEvent<D = Document> {
+    /// The resume token of the event.
+    #[serde(rename = "_id")]
+    pub resume_token: Document,
+    /// The kind of the change, e.g. `insert`, `update` or `invalidate`.
+    pub operation_type: String,
+    /// The cluster time of the change. The upper 32 bits are the number of
+    /// seconds since the Unix epoch.
+    pub cluster_time: Option<Bson>,
+    /// The `_id` (and shard key) of the changed document.
+    pub document_key: Option<Document>,
+    /// The changed document. Always present for inserts and replacements;
+    /// for updates, only if the full document was requested, and the
+    /// document still exists when it is looked up.
+    pub full_document: Option<D>,
+    /// The changed and removed fields of an update.
+    pub update_description: Option<UpdateDescription>,
+}
+
+impl<D> ChangeEvent<D> {
+    /// Returns `true` if this event invalidates the stream.
+    pub fn is_invalidate(&self) -> bool {
+        self.operation_type == "invalidate"
+    }
+
+    /// Returns the cluster time of the event, as a duration since the epoch.
+    /// The precision of the cluster time is one second.
+    #[allow(clippy::cast_sign_loss)]
+    pub fn cluster_time(&self) -> Option<Duration> {
+        match self.cluster_time {
+            Some(Bson::TimeStamp(ts)) => Some(Duration::from_secs((ts as u64) >> 32)),
+            _ => None,
+        }
+    }
+}
+
+/// The changes made by an update.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateDescription {
+    /// The new values of the changed fields, keyed by dotted paths.
+    pub updated_fields: Document,
+    /// The dotted paths of the removed fields.
+    pub removed_fields: Vec<String>,
+}
+
+/// The options of a change stream opened by `Collection::watch()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchOptions {
+    /// Whether to look up the current version of updated documents,
+    /// i.e. `fullDocument: "updateLookup"`.
+    pub full_document: bool,
+    /// The resume token after which events should be delivered, e.g. one
+    /// persisted from a previously handled `ChangeEvent`.
+    pub resume_after: Option<Document>,
+    /// Aggregation stages, e.g. a `$match` on `operationType`, applied to
+    /// the events on the server side. They must leave the shape of the
+    /// events intact.
+    pub pipeline: Vec<Document>,
+}
+
+/// What the consumer should do after an event has been handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    /// Keep consuming events.
+    Continue,
+    /// Stop consuming events and return from `Consumer::run()`.
+    Stop,
+}
+
+/// A snapshot of the state of a consumer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerStats {
+    /// The difference between the wall clock and the cluster time of the
+    /// last handled event, at the time it was handled.
+    pub lag: Option<Duration>,
+    /// The number of successfully handled events.
+    pub events_handled: u64,
+    /// The number of times the stream had to be reopened after an error.
+    pub restarts: u64,
+    /// The number of times the stream was invalidated.
+    pub invalidations: u64,
+    /// The resume token of the last successfully handled event.
+    pub resume_token: Option<Document>,
+}
+
+/// Shared, continuously updated metrics of a consumer.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerMetrics {
+    /// The current state.
+    inner: Arc<Mutex<ConsumerStats>>,
+}
+
+impl ConsumerMetrics {
+    /// Returns a snapshot of the current state.
+    pub fn stats(&self) -> ConsumerStats {
+        self.lock().clone()
+    }
+
+    /// Returns the current lag of the consumer.
+    pub fn lag(&self) -> Option<Duration> {
+        self.lock().lag
+    }
+
+    /// Locks the shared state, ignoring poisoning: the stats are plain
+    /// counters, which can't be left in an inconsistent state.
+    fn lock(&self) -> MutexGuard<'_, ConsumerStats> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Consumes the change stream of a collection, surviving failovers.
+pub struct Consumer<'a, T: Doc> {
+    /// The watched collection.
+    collection: &'a Collection<T>,
+    /// Additional stages filtering or transforming the events.
+    pipeline: Vec<Document>,
+    /// Whether to look up the current version of updated documents.
+    full_document: bool,
+    /// The delay before the first attempt to reopen a failed stream.
+    initial_backoff: Duration,
+    /// The maximal delay between attempts to reopen a failed stream.
+    max_backoff: Duration,
+    /// The maximal number of consecutive failed attempts, if limited.
+    max_retries: Option<u32>,
+    /// The delay before reopening a stream which the server closed normally.
+    idle_delay: Duration,
+    /// Lag and counters, shared with observers.
+    metrics: ConsumerMetrics,
+}
+
+impl<'a, T: Doc> Consumer<'a, T> {
+    /// Creates a consumer of all changes of the collection, with default
+    /// settings: backoff starting at 100 milliseconds, doubling up to 30
+    /// seconds, retried indefinitely.
+    pub fn new(collection: &'a Collection<T>) -> Self {
+        Consumer {
+            collection,
+            pipeline: Vec::new(),
+            full_document: false,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_retries: None,
+            idle_delay: Duration::from_millis(100),
+            metrics: ConsumerMetrics::default(),
+        }
+    }
+
+    /// Sets aggregation stages, e.g. a `$match` on `operationType`,
+    /// applied to the events on the server side.
+    pub fn pipeline(mut self, stages: Vec<Document>) -> Self {
+        self.pipeline = stages;
+        self
+    }
+
+    /// Requests the current version of updated documents in `full_document`.
+    pub fn full_document(mut self, full_document: bool) -> Self {
+        self.full_document = full_document;
+        self
+    }
+
+    /// Sets the initial and maximal delays between attempts to reopen
+    /// a failed stream.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Gives up after the given number of consecutive failed attempts to
+    /// (re)open the stream, returning the last error from `run()`.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Resumes consumption after the event with the given resume token,
+    /// e.g. one persisted from `ConsumerStats::resume_token` by a previous
+    /// run of the process.
+    pub fn resume_after(self, token: Document) -> Self {
+        self.metrics.lock().resume_token = Some(token);
+        self
+    }
+
+    /// Returns the shared metrics of this consumer.
+    pub fn metrics(&self) -> ConsumerMetrics {
+        self.metrics.clone()
+    }
+
+    /// Consumes events until the handler returns `Control::Stop` or an
+    /// error. Errors of the stream itself are retried with backoff.
+    ///
+    /// If the handler fails, its error is returned and the event is not
+    /// acknowledged, so it will be delivered again by the next `run()`.
+    pub fn run<F>(&self, mut handler: F) -> Result<()>
+        where F: FnMut(ChangeEvent) -> Result<Control>
+    {
+        let mut backoff = self.initial_backoff;
+        let mut failures = 0;
+
+        loop {
+            match self.consume(&mut handler) {
+                Ok(Some(Control::Stop)) => return Ok(()),
+                Ok(Some(Control::Continue)) | Ok(None) => {
+                    // The stream ended normally or was invalidated.
+                    backoff = self.initial_backoff;
+                    failures = 0;
+                    thread::sleep(self.idle_delay);
+                }
+                Err(StreamError::Handler(error)) => return Err(error),
+                Err(StreamError::Stream(error, made_progress)) => {
+                    if made_progress {
+                        backoff = self.initial_backoff;
+                        failures = 0;
+                    }
+
+                    failures += 1;
+
+                    if self.max_retries.map_or(false, |max| failures > max) {
+                        return Err(error);
+                    }
+
+                    self.metrics.lock().restarts += 1;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+            }
+        }
+    }
+
+    /// Opens the stream and handles events until it ends. Returns the
+    /// control value of the last handler invocation, if any.
+    fn consume<F>(&self, handler: &mut F) -> StdResult<Option<Control>, StreamError>
+        where F: FnMut(ChangeEvent) -> Result<Control>
+    {
+        let resume_token = self.metrics.lock().resume_token.clone();
+        let stream = ChangeStream::<T, ChangeEvent>::new(
+            resume_token,
+            self.full_document,
+            &self.pipeline,
+        );
+        let cursor = self.collection
+            .aggregate(stream)
+            .map_err(|error| StreamError::Stream(error, false))?;
+        let mut last = None;
+
+        for item in cursor {
+            let event = item.map_err(|error| StreamError::Stream(error, last.is_some()))?;
+            let token = event.resume_token.clone();
+            let invalidate = event.is_invalidate();
+            let lag = event.cluster_time().and_then(|time| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .and_then(|now| now.checked_sub(time))
+            });
+            let control = handler(event).map_err(StreamError::Handler)?;
+
+            {
+                let mut stats = self.metrics.lock();
+                stats.events_handled += 1;
+                stats.lag = lag.or(stats.lag);
+
+                if invalidate {
+                    // An invalidated stream can't be resumed, start afresh.
+                    stats.invalidations += 1;
+                    stats.resume_token = None;
+                } else {
+                    stats.resume_token = Some(token);
+                }
+            }
+
+            last = Some(control);
+
+            if invalidate || control == Control::Stop {
+                break;
+            }
+        }
+
+        Ok(last)
+    }
+}
+
+impl<'a, T: Doc> Debug for Consumer<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Consumer")
+            .field("collection", &self.collection)
+            .field("pipeline", &self.pipeline)
+            .field("full_document", &self.full_document)
+            .field("metrics", &self.metrics.stats())
+            .finish()
+    }
+}
+
+/// Shorthand for results with an error type other than `avocado::Error`.
+type StdResult<T, E> = std::result::Result<T, E>;
+
+/// The reason why consuming a stream was interrupted.
+#[derive(Debug)]
+enum StreamError {
+    /// The user-supplied handler failed; this is not retried.
+    Handler(Error),
+    /// The stream itself failed, and whether any event had been handled
+    /// before the failure.
+    Stream(Error, bool),
+}
+
+/// The aggregation pipeline opening a change stream, yielding events of
+/// type `E`.
+pub(crate) struct ChangeStream<'p, T, E> {
+    /// The resume token after which events should be delivered.
+    resume_after: Option<Document>,
+    /// Whether to look up the current version of updated documents.
+    full_document: bool,
+    /// The user-specified stages following `$changeStream`.
+    pipeline: &'p [Document],
+    /// The type of the watched documents and of the events.
+    _marker: PhantomData<fn() -> (T, E)>,
+}
+
+impl<'p, T, E> ChangeStream<'p, T, E> {
+    /// Creates the pipeline of a change stream.
+    pub(crate) fn new(
+        resume_after: Option<Document>,
+        full_document: bool,
+        pipeline: &'p [Document],
+    ) -> Self {
+        ChangeStream { resume_after, full_document, pipeline, _marker: PhantomData }
+    }
+}
+
+impl<'p, T, E> Debug for ChangeStream<'p, T, E> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("ChangeStream")
+            .field("resume_after", &self.resume_after)
+            .field("full_document", &self.full_document)
+            .field("pipeline", &self.pipeline)
+            .finish()
+    }
+}
+
+impl<'p, T, E> Pipeline<T> for ChangeStream<'p, T, E>
+    where T: Doc,
+          E: for<'a> Deserialize<'a>,
+{
+    type Output = E;
+
+    fn stages(&self) -> Vec<Document> {
+        let mut spec = Document::new();
+
+        if let Some(ref token) = self.resume_after {
+            spec.insert("resumeAfter", token.clone());
+        }
+        if self.full_document {
+            spec.insert("fullDocument", "updateLookup");
+        }
+
+        let mut stages = vec![doc!{ "$changeStream": spec }];
+        stages.extend(self.pipeline.iter().cloned());
+        stages
+    }
+
+    fn options(&self) -> AggregateOptions {
+        Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use bson::{ Bson, from_bson };
+    use super::{ ChangeEvent, UpdateDescription };
+
+    #[test]
+    fn event_cluster_time() {
+        let event: ChangeEvent = ChangeEvent {
+            resume_token: doc!{ "_data": "token" },
+            operation_type: String::from("insert"),
+            cluster_time: Some(Bson::TimeStamp((1_500_000_000 << 32) | 7)),
+            document_key: Some(doc!{ "_id": 1 }),
+            full_document: None,
+            update_description: None,
+        };
+
+        assert!(!event.is_invalidate());
+        assert_eq!(event.cluster_time(), Some(Duration::from_secs(1_500_000_000)));
+    }
+
+    #[test]
+    fn typed_event() {
+        #[derive(Debug, Clone, PartialEq, Deserialize)]
+        struct Item {
+            _id: i32,
+            name: String,
+        }
+
+        let raw = bson!({
+            "_id": { "_data": "token" },
+            "operationType": "update",
+            "documentKey": { "_id": 1 },
+            "fullDocument": { "_id": 1, "name": "pen" },
+            "updateDescription": {
+                "updatedFields": { "name": "pen" },
+                "removedFields": ["color"],
+            },
+        });
+        let event: ChangeEvent<Item> = from_bson(raw).expect("can't deserialize event");
+
+        assert_eq!(event.full_document, Some(Item { _id: 1, name: String::from("pen") }));
+        assert_eq!(event.update_description, Some(UpdateDescription {
+            updated_fields: doc!{ "name": "pen" },
+            removed_fields: vec![String::from("color")],
+        }));
+        assert_eq!(event.cluster_time(), None);
+    }
+}
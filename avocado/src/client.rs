@@ -0,0 +1,330 @@
+//! Connecting to a MongoDB deployment.
+//!
+//! [`connect()`](fn.connect.html) connects using only a connection string,
+//! while [`Options`](struct.Options.html) additionally configures
+//! timeouts, the default read preference and write concern, TLS and
+//! credentials, so that the setup of the underlying driver needn't be
+//! spelled out before a `DatabaseExt` can be used:
+//!
+//! ```no_run
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::client::Options;
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! let client = Options::new("mongodb://db0.example.com,db1.example.com/?replicaSet=rs0")
+//!     .server_selection_timeout_ms(5000)
+//!     .credentials("app", "s3cr3t", "admin")
+//!     .connect()?;
+//!
+//! let db = client.db("app");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The user name and password in the connection string, if any, are used
+//! unless credentials are given explicitly. They are verified against the
+//! database named by the `authSource` option, or else the database in the
+//! path of the connection string, or else `admin`.
+//!
+//! TLS requires the `tls` feature, which enables the `ssl` feature of the
+//! driver. The driver always uses its own connection pool size and connect
+//! timeout, so these can't be configured.
+
+use mongodb::{ Client, ThreadedClient, CommandType };
+use mongodb::connstring::{ self, ConnectionString };
+use mongodb::common::{ ReadPreference, WriteConcern };
+use mongodb::db::ThreadedDatabase;
+#[cfg(feature = "tls")]
+use mongodb::stream::StreamConnector;
+#[cfg(not(feature = "tls"))]
+use crate::error::{ Error, ErrorKind::MongoDbError };
+use crate::error::{ Result, ResultExt };
+
+/// Connects to the deployment described by a `mongodb://` connection
+/// string, authenticating with the credentials it contains, if any.
+pub fn connect(uri: &str) -> Result<Client> {
+    Options::new(uri).connect()
+}
+
+/// The credentials used for authenticating a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// The name of the user.
+    pub user: String,
+    /// The password of the user.
+    pub password: String,
+    /// The database in which the user is defined.
+    pub source: String,
+}
+
+/// The TLS configuration of a client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// The PEM file of the certificate authority used for verifying the
+    /// certificate of the server.
+    pub ca_file: Option<String>,
+    /// The PEM files of the client certificate and of its private key, if
+    /// the server requires clients to present a certificate.
+    pub client_cert: Option<(String, String)>,
+    /// Whether the certificate of the server is verified. Disabling this
+    /// is insecure, only do it for testing.
+    pub verify_peer: bool,
+}
+
+impl TlsOptions {
+    /// Verifies the certificate of the server against the given certificate
+    /// authority.
+    pub fn with_ca_file<S: Into<String>>(ca_file: S) -> Self {
+        TlsOptions {
+            ca_file: Some(ca_file.into()),
+            client_cert: None,
+            verify_peer: true,
+        }
+    }
+
+    /// Presents the given client certificate and private key to the server.
+    pub fn client_cert<C, K>(mut self, cert_file: C, key_file: K) -> Self
+        where C: Into<String>,
+              K: Into<String>,
+    {
+        self.client_cert = Some((cert_file.into(), key_file.into()));
+        self
+    }
+}
+
+/// Builder for a connection to a MongoDB deployment.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The `mongodb://` connection string.
+    uri: String,
+    /// How long to wait for a suitable server before failing an operation.
+    server_selection_timeout_ms: Option<i64>,
+    /// How often the state of the servers is checked.
+    heartbeat_frequency_ms: Option<u32>,
+    /// The size of the latency window for selecting among suitable servers.
+    local_threshold_ms: Option<i64>,
+    /// The read preference of operations which don't specify one.
+    read_preference: Option<ReadPreference>,
+    /// The write concern of operations which don't specify one.
+    write_concern: Option<WriteConcern>,
+    /// The TLS configuration; `None` connects without TLS.
+    tls: Option<TlsOptions>,
+    /// The credentials, if not given in the connection string.
+    credentials: Option<Credentials>,
+}
+
+impl Options {
+    /// Starts configuring a connection to the deployment described by a
+    /// `mongodb://` connection string.
+    pub fn new<S: Into<String>>(uri: S) -> Self {
+        Options {
+            uri: uri.into(),
+            server_selection_timeout_ms: None,
+            heartbeat_frequency_ms: None,
+            local_threshold_ms: None,
+            read_preference: None,
+            write_concern: None,
+            tls: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets how long an operation waits for a suitable server.
+    pub fn server_selection_timeout_ms(mut self, timeout_ms: i64) -> Self {
+        self.server_selection_timeout_ms = Some(timeout_ms);
+        self
+    }
+
+    /// Sets how often the state of the servers is checked.
+    pub fn heartbeat_frequency_ms(mut self, frequency_ms: u32) -> Self {
+        self.heartbeat_frequency_ms = Some(frequency_ms);
+        self
+    }
+
+    /// Sets the size of the latency window for selecting among suitable
+    /// servers.
+    pub fn local_threshold_ms(mut self, threshold_ms: i64) -> Self {
+        self.local_threshold_ms = Some(threshold_ms);
+        self
+    }
+
+    /// Sets the default read preference.
+    pub fn read_preference(mut self, read_preference: ReadPreference) -> Self {
+        self.read_preference = Some(read_preference);
+        self
+    }
+
+    /// Sets the default write concern.
+    pub fn write_concern(mut self, write_concern: WriteConcern) -> Self {
+        self.write_concern = Some(write_concern);
+        self
+    }
+
+    /// Connects using TLS.
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticates as `user`, defined in the database `source`.
+    pub fn credentials<U, P, S>(mut self, user: U, password: P, source: S) -> Self
+        where U: Into<String>,
+              P: Into<String>,
+              S: Into<String>,
+    {
+        self.credentials = Some(Credentials {
+            user: user.into(),
+            password: password.into(),
+            source: source.into(),
+        });
+        self
+    }
+
+    /// Parses the connection string, connects and authenticates.
+    pub fn connect(self) -> Result<Client> {
+        // The connection string may contain a password, so it's not quoted.
+        let message = || String::from("can't connect to MongoDB");
+        let config = connstring::parse(&self.uri).chain(&message)?;
+        let auth = self.credentials.clone().or_else(|| uri_credentials(&config));
+        let client = Client::with_config(config, Some(self.driver_options()?), None)
+            .chain(&message)?;
+
+        if let Some(credentials) = auth {
+            client
+                .db(&credentials.source)
+                .auth(&credentials.user, &credentials.password)
+                .chain(|| format!("can't authenticate as {}", credentials.user))?;
+        }
+
+        // Fail early if no server is reachable.
+        client
+            .db("admin")
+            .command(doc!{ "ping": 1 }, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        Ok(client)
+    }
+
+    /// Converts the options to those of the driver.
+    fn driver_options(&self) -> Result<mongodb::ClientOptions> {
+        let mut options = mongodb::ClientOptions::new();
+
+        if let Some(timeout_ms) = self.server_selection_timeout_ms {
+            options.server_selection_timeout_ms = timeout_ms;
+        }
+        if let Some(frequency_ms) = self.heartbeat_frequency_ms {
+            options.heartbeat_frequency_ms = frequency_ms;
+        }
+        if let Some(threshold_ms) = self.local_threshold_ms {
+            options.local_threshold_ms = threshold_ms;
+        }
+
+        options.read_preference = self.read_preference.clone();
+        options.write_concern = self.write_concern.clone();
+
+        if let Some(ref tls) = self.tls {
+            options.stream_connector = stream_connector(tls)?;
+        }
+
+        Ok(options)
+    }
+}
+
+/// Returns the credentials embedded in a connection string, if any.
+fn uri_credentials(config: &ConnectionString) -> Option<Credentials> {
+    let user = config.user.clone()?;
+    let password = config.password.clone().unwrap_or_default();
+    let source = config.options
+        .as_ref()
+        .and_then(|options| options.get("authSource").cloned())
+        .or_else(|| {
+            let uri = config.string.as_ref()?;
+            uri_database(uri).map(Into::into)
+        })
+        .filter(|source| !source.is_empty())
+        .unwrap_or_else(|| String::from("admin"));
+
+    Some(Credentials { user, password, source })
+}
+
+/// Returns the database named in the path of a connection string, if any.
+/// The driver's parsed `database` can't be used for this, because it's
+/// `"test"` when the connection string has no path.
+fn uri_database(uri: &str) -> Option<&str> {
+    let address = uri.splitn(2, "://").nth(1)?;
+    let path = match address.rfind(".sock") {
+        Some(index) => address[index + ".sock".len()..].trim_start_matches('/'),
+        None => address.splitn(2, '/').nth(1)?,
+    };
+    let database = path.split(|c| c == '?' || c == '.').next()?;
+
+    if database.is_empty() {
+        None
+    } else {
+        Some(database)
+    }
+}
+
+/// Creates the TLS stream connector of the driver.
+#[cfg(feature = "tls")]
+fn stream_connector(tls: &TlsOptions) -> Result<StreamConnector> {
+    let ca_file = tls.ca_file.as_ref().map(String::as_str);
+
+    Ok(match tls.client_cert {
+        Some((ref cert_file, ref key_file)) => StreamConnector::with_ssl(
+            ca_file, cert_file, key_file, tls.verify_peer
+        ),
+        None => StreamConnector::with_unauthenticated_ssl(ca_file, tls.verify_peer),
+    })
+}
+
+/// Without the `tls` feature, the driver can't connect using TLS.
+#[cfg(not(feature = "tls"))]
+fn stream_connector(_: &TlsOptions) -> Result<mongodb::stream::StreamConnector> {
+    Err(Error::new(MongoDbError, "TLS requires the `tls` feature of avocado"))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::connstring;
+    use super::{ Credentials, uri_credentials, uri_database };
+
+    #[test]
+    fn credentials_from_uri() {
+        let parse = |uri| uri_credentials(&connstring::parse(uri).unwrap());
+
+        assert_eq!(parse("mongodb://localhost/"), None);
+        assert_eq!(parse("mongodb://app:pw@localhost/sales"), Some(Credentials {
+            user: "app".into(),
+            password: "pw".into(),
+            source: "sales".into(),
+        }));
+        assert_eq!(parse("mongodb://app:pw@localhost/sales?authSource=users"), Some(Credentials {
+            user: "app".into(),
+            password: "pw".into(),
+            source: "users".into(),
+        }));
+        assert_eq!(parse("mongodb://app:pw@localhost/"), Some(Credentials {
+            user: "app".into(),
+            password: "pw".into(),
+            source: "admin".into(),
+        }));
+        assert_eq!(parse("mongodb://app:pw@localhost"), Some(Credentials {
+            user: "app".into(),
+            password: "pw".into(),
+            source: "admin".into(),
+        }));
+    }
+
+    #[test]
+    fn database_from_uri() {
+        assert_eq!(uri_database("mongodb://localhost"), None);
+        assert_eq!(uri_database("mongodb://localhost/"), None);
+        assert_eq!(uri_database("mongodb://localhost/?w=1"), None);
+        assert_eq!(uri_database("mongodb://a:b@h1,h2/sales?w=1"), Some("sales"));
+        assert_eq!(uri_database("mongodb://localhost/sales.orders"), Some("sales"));
+        assert_eq!(uri_database("mongodb:///tmp/mongodb-27017.sock/sales"), Some("sales"));
+    }
+}
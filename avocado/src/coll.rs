@@ -1,34 +1,84 @@
 //! A MongoDB collection of a single homogeneous type.
 
+use std::io::{ Read, Write };
 use std::borrow::Borrow;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::any::TypeId;
 use std::cmp::Ordering;
 use std::iter::FromIterator;
-use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::collections::{ BTreeMap, HashMap };
 use std::result::Result as StdResult;
 use std::hash::{ Hash, Hasher };
 use std::fmt::{ Debug, Formatter, Result as FmtResult };
 use serde::Deserialize;
-use bson::{ Bson, Document, from_bson };
+use serde::de::DeserializeOwned;
+use chrono::Utc;
+use bson::{ Bson, Document, oid::ObjectId, from_bson };
 use mongodb::coll::options::{
+    IndexModel,
+    IndexOptions,
+    FindOptions,
+    CursorType,
     UpdateOptions,
     FindOneAndDeleteOptions,
     FindOneAndUpdateOptions,
     ReturnDocument,
+    WriteModel,
 };
-use mongodb::coll::results::UpdateResult;
+use mongodb::coll::results::{ UpdateResult, BulkWriteResult };
+use mongodb::coll::error::{ WriteException, BulkWriteException };
+use mongodb::db::{ Database, ThreadedDatabase };
+use mongodb::common::{ ReadMode, ReadPreference, WriteConcern };
+use mongodb::{ CommandType, ThreadedClient };
 use typemap::Key;
 use crate::{
-    cursor::Cursor,
-    doc::Doc,
+    pipeline::{ self, Accumulator },
+    db::DatabaseExt,
+    update::ArrayFilters,
+    options,
+    projection::AsView,
+    cursor::{ self, Cursor },
+    scope::{ ScopedCollection, and_filters },
+    variant::{ self, Subtype },
+    retry::{ self, RetryingCollection },
+    monitor::{ self, CommandListener, ListenerHandle },
+    batch::{ self, BatchedWriter },
+    cancel::CancellationToken,
+    loader::Loader,
+    consistency::ReadYourWrites,
+    scan::{ self, SAMPLES_PER_PARTITION, partitions_from_sample },
+    paginate::Paginator,
+    encrypt::{ encrypt_fields, decrypt_fields, encrypt_update },
+    shard::check_targeted,
+    transaction::{ Transaction, ReadSession },
+    change_stream::{ ChangeStream, ChangeEvent, WatchOptions },
+    index_sync::{ self, IndexDiff, index_name },
+    diff,
+    explain,
+    geo::{ self, Point },
+    text,
+    literal::ExplainVerbosity,
+    doc::{ Doc, MAX_DOCUMENT_SIZE },
     uid::Uid,
+    relation::Ref,
     ops::*,
     bsn::*,
     utils::*,
-    error::{ Error, ErrorKind::{ MissingId, BsonDecoding }, Result, ResultExt },
+    error::{
+        Error, ErrorKind, ErrorKind::{ MissingId, MissingDocumentField, BsonDecoding, MongoDbError },
+        UniqueViolation, ServerInfo, DUPLICATE_KEY_ERROR_CODE, Result, ResultExt,
+    },
 };
 
+/// The name of the field storing the idempotency key of documents inserted
+/// via `Collection::insert_one_idempotent()`.
+pub const IDEMPOTENCY_KEY_FIELD: &str = "_idempotency_key";
+
+/// The name of the unique index on `IDEMPOTENCY_KEY_FIELD`.
+pub const IDEMPOTENCY_INDEX_NAME: &str = "_idempotency_key_unique";
+
 /// A statically-typed (homogeneous) `MongoDB` collection.
 pub struct Collection<T: Doc> {
     /// The backing `MongoDB` collection.
@@ -52,17 +102,214 @@ impl<T: Doc> Collection<T> {
         }
     }
 
+    /// Brings the indexes of the collection in line with `T::indexes()`:
+    /// creates the missing ones and, if `options.drop_stale` is set, drops
+    /// the undeclared ones and recreates the changed ones. Returns the
+    /// differences found, which are left alone if `options.dry_run` is set.
+    /// See the [`index_sync`](../index_sync/index.html) module for details.
+    pub fn sync_indexes(&self, options: index_sync::Options) -> Result<IndexDiff> {
+        let message = || format!("can't synchronize indexes of {}", T::NAME);
+        let existing = self.list_indexes_typed()?;
+        let diff = IndexDiff::between(&T::indexes(), &existing);
+
+        if options.dry_run {
+            return Ok(diff);
+        }
+
+        let mut created = diff.missing.clone();
+
+        if options.drop_stale {
+            for name in diff.stale.iter().cloned().chain(diff.changed.iter().map(index_name)) {
+                self.inner.drop_index_string(name).chain(&message)?;
+            }
+
+            created.extend(diff.changed.iter().cloned());
+        }
+
+        if !created.is_empty() {
+            self.inner.create_indexes(created).chain(&message)?;
+        }
+
+        Ok(diff)
+    }
+
+    /// Returns a handle which restricts every read and write to the
+    /// documents matching `scope`, e.g. those of a single tenant, or
+    /// those which have not been soft-deleted. Inserted documents receive
+    /// the plain field values of the scope automatically.
+    pub fn scoped(&self, scope: Document) -> ScopedCollection<'_, T> {
+        ScopedCollection::new(self, scope)
+    }
+
+    /// Returns a handle which restricts every read and write to the
+    /// documents of a single variant of a tagged enum, and reads and inserts
+    /// them as the type of that variant. See the
+    /// [`variant`](../variant/index.html) module.
+    pub fn of_variant<V: Subtype<T>>(&self) -> variant::View<'_, T, V> {
+        variant::View::new(self)
+    }
+
+    /// Registers a listener notified of the commands operating on this
+    /// collection. See the [`monitor`](../monitor/index.html) module.
+    pub fn add_command_listener<L>(&self, listener: L) -> Result<ListenerHandle>
+        where L: CommandListener + 'static
+    {
+        monitor::add_listener(&self.inner.db.client, Some(T::NAME), Arc::new(listener))
+    }
+
+    /// Returns a handle whose writes are retried according to `policy`
+    /// when they fail because of network errors or primary elections.
+    /// See the [`retry`](../retry/index.html) module for caveats.
+    pub fn retrying(&self, policy: retry::Policy) -> RetryingCollection<'_, T> {
+        RetryingCollection::new(self, policy)
+    }
+
+    /// Creates the unique index on `IDEMPOTENCY_KEY_FIELD` required by
+    /// `insert_one_idempotent()`. The index is sparse, so documents inserted
+    /// without an idempotency key are not affected by it.
+    pub fn create_idempotency_index(&self) -> Result<()> {
+        let index = IndexModel {
+            keys: doc!{ IDEMPOTENCY_KEY_FIELD: 1 },
+            options: IndexOptions {
+                name: Some(String::from(IDEMPOTENCY_INDEX_NAME)),
+                unique: Some(true),
+                sparse: Some(true),
+                ..Default::default()
+            },
+        };
+
+        self.inner
+            .create_indexes(vec![index])
+            .map(drop)
+            .chain(|| format!("can't create idempotency index on {}", T::NAME))
+    }
+
+    /// Returns the indexes which currently exist on the collection,
+    /// including the `_id` index, e.g. for comparing them with `T::indexes()`.
+    ///
+    /// Numeric key orders are normalized to 32-bit integers, as used by
+    /// `IndexType`. Options which `IndexOptions` can't express, such as the
+    /// partial filter expression, are returned alongside the `IndexModel`.
+    pub fn list_indexes_typed(&self) -> Result<Vec<ExistingIndex>> {
+        let message = || format!("error in {}::list_indexes_typed()", T::NAME);
+
+        self.inner
+            .list_indexes()
+            .chain(&message)?
+            .map(|result| result.chain(&message).and_then(ExistingIndex::from_document))
+            .collect()
+    }
+
+    /// Shards the collection by `T::shard_key()`, using the `shardCollection`
+    /// admin command. Sharding must already be enabled for the database.
+    /// Returns a `MissingDocumentField` error if `T` declares no shard key.
+    pub fn shard_collection(&self) -> Result<()> {
+        let message = || format!("can't shard collection {}", T::NAME);
+        let shard_key = T::shard_key().ok_or_else(|| Error::new(
+            MissingDocumentField,
+            format!("{} declares no shard key", T::NAME)
+        ))?;
+        let command = doc!{
+            "shardCollection": format!("{}.{}", self.inner.db.name, self.inner.name()),
+            "key": shard_key.keys,
+        };
+
+        self.inner
+            .db
+            .client
+            .db("admin")
+            .command(command, CommandType::Suppressed, None)
+            .map(drop)
+            .chain(&message)
+    }
+
     /// Deletes the collection.
     pub fn drop(&self) -> Result<()> {
         self.inner.drop().map_err(Into::into)
     }
 
     /// Returns the number of documents matching the query criteria.
+    ///
+    /// Like the `countDocuments()` method of other drivers, this counts the
+    /// matching documents using an aggregation, instead of the deprecated
+    /// `count` command, which may return inaccurate results on sharded
+    /// clusters and after an unclean shutdown. The `skip`, `limit`, `hint`,
+    /// `max_time_ms` and `read_preference` options are respected.
     pub fn count<Q: Count<T>>(&self, query: Q) -> Result<usize> {
-        self.inner
-            .count(query.filter().into(), query.options().into())
-            .chain(|| format!("error in {}::count({:#?})", T::NAME, query))
-            .and_then(|n| int_to_usize_with_msg(n, "# of counted documents"))
+        let message = || format!("error in {}::count({:#?})", T::NAME, query);
+        let options = query.options().with_default_max_time();
+        let mut pipeline = vec![
+            Bson::from(doc!{ "$match": live::<T>(renamed::<T>(query.filter())) }),
+        ];
+
+        // A limit of 0 means no limit, but the `$limit` stage rejects it.
+        if let Some(skip) = options.skip.filter(|&skip| skip > 0) {
+            pipeline.push(doc!{ "$skip": skip }.into());
+        }
+        if let Some(limit) = options.limit.filter(|&limit| limit > 0) {
+            pipeline.push(doc!{ "$limit": limit }.into());
+        }
+
+        pipeline.push(doc!{ "$group": { "_id": 1, "n": { "$sum": 1_i64 } } }.into());
+
+        let mut command = doc!{
+            "aggregate": self.inner.name(),
+            "pipeline": pipeline,
+            "cursor": {},
+        };
+
+        if let Some(hint) = options.hint_doc {
+            command.insert("hint", hint);
+        } else if let Some(hint) = options.hint {
+            command.insert("hint", hint);
+        }
+        if let Some(max_time_ms) = options.max_time_ms {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+
+        let reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, options.read_preference)
+            .chain(&message)?;
+        let batch = reply
+            .get_document("cursor")
+            .and_then(|cursor| cursor.get_array("firstBatch"))
+            .chain(&message)?;
+
+        // No group is output if no documents match.
+        let n = match batch.first() {
+            Some(&Bson::Document(ref group)) => group.get_i64("n").chain(&message)?,
+            _ => 0,
+        };
+
+        int_to_usize_with_msg(n, "# of counted documents")
+    }
+
+    /// Returns the number of documents in the collection, based on the
+    /// metadata of the collection, which is fast, but may be inaccurate on
+    /// sharded clusters and after an unclean shutdown. Soft-deleted
+    /// documents are counted as well. Only the `max_time_ms` and
+    /// `read_preference` options of `T::count_options()` are used.
+    pub fn estimated_count(&self) -> Result<usize> {
+        let message = || format!("error in {}::estimated_count()", T::NAME);
+        let options = T::count_options().with_default_max_time();
+        let mut command = doc!{ "count": self.inner.name() };
+
+        if let Some(max_time_ms) = options.max_time_ms {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+
+        let reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, options.read_preference)
+            .chain(&message)?;
+        let n = match reply.get("n") {
+            Some(&Bson::I32(n)) => i64::from(n),
+            Some(&Bson::I64(n)) => n,
+            _ => return Err(Error::new(MissingDocumentField, message() + ": missing `n`")),
+        };
+
+        int_to_usize_with_msg(n, "# of documents")
     }
 
     /// Returns the distinct values of a certain field.
@@ -70,52 +317,1105 @@ impl<T: Doc> Collection<T> {
         where Q: Distinct<T>,
               C: FromIterator<Q::Output>,
     {
+        let field = T::field_naming().rename_path(Q::FIELD);
+
         self.inner
-            .distinct(Q::FIELD, query.filter().into(), query.options().into())
+            .distinct(&field, live::<T>(renamed::<T>(query.filter())).into(), query.options().with_default_max_time().into())
             .chain(|| format!("error in {}::distinct({:#?})", T::NAME, query))
             .and_then(|values| {
                 values
                     .into_iter()
-                    .map(|b| from_bson(Q::transform(b)?).chain(|| format!(
+                    .map(|b| T::SERDE_PROFILE.deserialize(Q::transform(b)?).chain(|| format!(
                         "can't deserialize {}::{}", T::NAME, Q::FIELD
                     )))
                     .collect()
             })
     }
 
+    /// Counts the documents matching `filter`, grouped by `key`, using a
+    /// `$group` aggregation stage. `key` is either the name of a field (the
+    /// leading `$` is optional) or an arbitrary aggregation expression, e.g.
+    /// `doc!{ "$dateToString": { "format": "%Y-%m-%d", "date": "$created" } }`.
+    ///
+    /// The groups are returned in descending order of their count; groups
+    /// with equal counts are ordered by key. Documents lacking the field are
+    /// counted under a `null` key, so `K` may need to be an `Option`. The
+    /// name of the field and the filter are translated by `T::field_naming()`.
+    pub fn count_by<K, B>(&self, key: B, filter: Document) -> Result<Vec<(K, i64)>>
+        where K: for<'a> Deserialize<'a>,
+              B: Into<Bson>,
+    {
+        let group_key = match key.into() {
+            Bson::String(ref field) if !field.starts_with("$$") => {
+                let path = field.trim_start_matches('$');
+                Bson::String(format!("${}", T::field_naming().rename_path(path)))
+            }
+            expr => expr,
+        };
+        let pipeline = vec![
+            doc!{ "$match": live::<T>(renamed::<T>(filter)) },
+            doc!{ "$group": { "_id": group_key, "count": { "$sum": 1_i64 } } },
+            doc!{ "$sort": { "count": -1, "_id": 1 } },
+        ];
+
+        self.inner
+            .aggregate(pipeline.clone(), T::aggregate_options().with_default_max_time().into())
+            .chain(|| format!("error in {}::count_by({:#?})", T::NAME, pipeline))?
+            .map(|result| {
+                let doc = result.chain(|| format!("error in {}::count_by()", T::NAME))?;
+                let group: GroupCount<K> = from_bson(doc.into())
+                    .chain(|| format!("can't deserialize {}::count_by() group", T::NAME))?;
+                Ok((group.key, group.count))
+            })
+            .collect()
+    }
+
+    /// Returns the query plan chosen by the server for the query, i.e. the
+    /// reply of the `explain` command with `queryPlanner` verbosity.
+    pub fn explain<Q: Query<T>>(&self, query: Q) -> Result<Document> {
+        let find = find_command(
+            self.inner.name(),
+            live::<T>(renamed::<T>(query.filter())),
+            query_options::<T, Q>(&query),
+            &query.command_options(),
+        );
+        let command = doc!{
+            "explain": find,
+            "verbosity": "queryPlanner",
+        };
+
+        self.inner
+            .db
+            .command(command, CommandType::Suppressed, None)
+            .chain(|| format!("error in {}::explain({:#?})", T::NAME, query))
+    }
+
+    /// Explains the query at the given verbosity. With `ExecutionStats` or
+    /// `AllPlansExecution`, the query is actually executed, so that e.g. the
+    /// number of examined index keys and documents can be checked in tests.
+    pub fn explain_with<Q: Query<T>>(&self, query: Q, verbosity: ExplainVerbosity)
+        -> Result<explain::Report>
+    {
+        let message = || format!("error in {}::explain_with({:#?})", T::NAME, query);
+        let find = find_command(
+            self.inner.name(),
+            live::<T>(renamed::<T>(query.filter())),
+            query_options::<T, Q>(&query),
+            &query.command_options(),
+        );
+        let command = doc!{
+            "explain": find,
+            "verbosity": verbosity,
+        };
+
+        let reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        explain::Report::from_reply(reply).chain(&message)
+    }
+
+    /// Explains the initial, query-like part of an aggregation pipeline,
+    /// i.e. how the documents entering the pipeline are retrieved, at the
+    /// given verbosity.
+    pub fn explain_pipeline<P: Pipeline<T>>(&self, pipeline: P, verbosity: ExplainVerbosity)
+        -> Result<explain::Report>
+    {
+        let message = || format!("error in {}::explain_pipeline({:#?})", T::NAME, pipeline);
+        let stages: Vec<Bson> = live_stages::<T>(pipeline.stages())
+            .into_iter()
+            .map(Bson::Document)
+            .collect();
+        let command = doc!{
+            "explain": {
+                "aggregate": self.inner.name(),
+                "pipeline": stages,
+                "cursor": {},
+            },
+            "verbosity": verbosity,
+        };
+
+        let reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        explain::Report::from_reply(reply).chain(&message)
+    }
+
+    /// Checks the integrity of the collection's data and indexes using the
+    /// `validate` command. A `full` validation is more thorough, but much
+    /// slower, and it blocks all reads and writes of the collection.
+    ///
+    /// Validation errors are reported in the returned result, with `valid`
+    /// set to `false`; only a failure to run the command is an `Err`.
+    pub fn validate(&self, full: bool) -> Result<ValidationResult> {
+        let message = || format!("error in {}::validate(full: {})", T::NAME, full);
+        let command = doc!{
+            "validate": self.inner.name(),
+            "full": full,
+        };
+        let reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        from_bson(reply.into()).chain(&message)
+    }
+
+    /// Runs the database command `name` on this collection, with the given
+    /// additional arguments, e.g. `run_command_as("collMod", doc!{ ... })`,
+    /// and deserializes its reply as `R`. See `DatabaseExt::run_command_as()`.
+    pub fn run_command_as<R: DeserializeOwned>(&self, name: &str, arguments: Document) -> Result<R> {
+        let mut command = doc!{ name: self.inner.name() };
+
+        for (key, value) in arguments {
+            command.insert(key, value);
+        }
+
+        self.inner
+            .db
+            .run_command_as(command)
+            .chain(|| format!("error in {}::run_command_as(`{}`)", T::NAME, name))
+    }
+
+    /// Copies the documents matching `options.filter` into a collection of
+    /// another (or the same) database, e.g. for refreshing an environment or
+    /// migrating a tenant. The documents are copied verbatim, in batches of
+    /// `options.batch_size`; `progress` is called after each batch.
+    ///
+    /// The target collection is named `T::NAME` unless `options.target_name`
+    /// is set. Existing documents of the target collection are kept, so
+    /// copying a document with an `_id` already present there fails.
+    pub fn copy_to<F>(
+        &self,
+        target: &Database,
+        options: CopyOptions,
+        mut progress: F,
+    ) -> Result<CopyProgress>
+        where F: FnMut(&CopyProgress)
+    {
+        let name = options.target_name.as_ref().map_or(T::NAME, String::as_str);
+        let message = || format!("error in {}::copy_to({}.{})", T::NAME, target.name, name);
+        let target_coll: Collection<T> = target.collection(name).into();
+        let batch_size = options.batch_size.max(1);
+
+        if options.create_indexes {
+            target_coll.create_indexes().chain(&message)?;
+        }
+
+        let mut cursor = self.inner
+            .find(Some(options.filter.clone()), None)
+            .chain(&message)?;
+        let mut state = CopyProgress::default();
+
+        loop {
+            let batch = cursor
+                .by_ref()
+                .take(batch_size)
+                .collect::<StdResult<Vec<_>, _>>()
+                .chain(&message)?;
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let n_docs = batch.len();
+            let result = target_coll.inner
+                .insert_many(batch, None)
+                .chain(&message)?;
+
+            if let Some(error) = result.bulk_write_exception {
+                return Err(bulk_write_error::<T>(message(), error));
+            }
+
+            state.documents += n_docs;
+            state.batches += 1;
+            progress(&state);
+        }
+
+        Ok(state)
+    }
+
+    /// Inserts the documents yielded by `entities`, e.g. for a bulk import
+    /// too big to be held in memory at once. The documents are sent in
+    /// batches of at most `MAX_INSERT_BATCH_LEN` documents and
+    /// `MAX_DOCUMENT_SIZE` bytes, the limits of a single `insert` command;
+    /// `progress` is called after each batch.
+    ///
+    /// A failing batch doesn't stop the import: its error is recorded in
+    /// `InsertStreamProgress::errors`, and the next batch is sent anyway.
+    /// Documents that can't be serialized, or that are bigger than
+    /// `T::bson_size_limit()`, are skipped and recorded as errors as well.
+    pub fn insert_stream<I, F>(&self, entities: I, mut progress: F) -> Result<InsertStreamProgress>
+        where I: IntoIterator,
+              I::Item: Borrow<T>,
+              F: FnMut(&InsertStreamProgress),
+    {
+        let limit = T::bson_size_limit();
+        let mut state = InsertStreamProgress::default();
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        let mut pending = entities.into_iter().peekable();
+
+        while let Some(entity) = pending.next() {
+            let serialized = serialize_entity(entity.borrow()).and_then(|mut doc| {
+                generate_id::<T>(&mut doc)?;
+                stamp_inserted::<T>(&mut doc);
+                let size = document_size(&doc)?;
+
+                if size > limit {
+                    check_document_size(&doc, limit, T::NAME)?;
+                }
+                if T::check_field_names() {
+                    check_field_names(&doc, T::NAME)?;
+                }
+
+                Ok((doc, size))
+            });
+
+            match serialized {
+                Ok((doc, size)) => {
+                    if batch_bytes + size > MAX_DOCUMENT_SIZE {
+                        self.insert_stream_batch(&mut batch, &mut state, &mut progress);
+                        batch_bytes = 0;
+                    }
+
+                    batch.push(doc);
+                    batch_bytes += size;
+                }
+                Err(error) => state.errors.push((state.batches, error)),
+            }
+
+            if batch.len() >= MAX_INSERT_BATCH_LEN || (pending.peek().is_none() && !batch.is_empty()) {
+                self.insert_stream_batch(&mut batch, &mut state, &mut progress);
+                batch_bytes = 0;
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Sends a batch of `insert_stream()`, recording its outcome in `state`.
+    fn insert_stream_batch<F>(
+        &self,
+        batch: &mut Vec<Document>,
+        state: &mut InsertStreamProgress,
+        progress: &mut F,
+    )
+        where F: FnMut(&InsertStreamProgress)
+    {
+        let batch_index = state.batches;
+        let message = || format!("error in {}::insert_stream(), batch #{}", T::NAME, batch_index);
+        let n_docs = batch.len();
+        let result = self.inner
+            .insert_many(batch.split_off(0), T::insert_options().into())
+            .chain(&message);
+
+        match result {
+            Ok(reply) => match reply.bulk_write_exception {
+                // Inserts are ordered by default, so the documents after the
+                // first failing one may not have been inserted either.
+                Some(error) => {
+                    state.documents += reply.inserted_ids.map_or(0, |ids| ids.len());
+                    state.errors.push((batch_index, bulk_write_error::<T>(message(), error)));
+                }
+                None => state.documents += n_docs,
+            },
+            Err(error) => state.errors.push((batch_index, error)),
+        }
+
+        state.batches += 1;
+        progress(state);
+    }
+
+    /// Writes the documents matching `filter` to `writer` as a stream of
+    /// BSON documents, in the format of the `.bson` files of `mongodump`.
+    /// The documents are written verbatim, without deserializing them into
+    /// `T`. Returns the number of documents written.
+    pub fn dump<W: Write>(&self, writer: W, filter: Document) -> Result<usize> {
+        self.dump_with_progress(writer, filter, |_| {})
+    }
+
+    /// Like `dump()`, but calls `progress` with the number of documents
+    /// written so far after every 1000 documents, and once at the end.
+    pub fn dump_with_progress<W, F>(&self, mut writer: W, filter: Document, mut progress: F)
+        -> Result<usize>
+        where W: Write,
+              F: FnMut(usize),
+    {
+        /// The number of documents written between calls to `progress`.
+        const PROGRESS_INTERVAL: usize = 1000;
+
+        let message = || format!("error in {}::dump()", T::NAME);
+        let cursor = self.inner.find(Some(filter), None).chain(&message)?;
+        let mut n_docs = 0;
+
+        for doc in cursor {
+            write_document(&mut writer, &doc.chain(&message)?).chain(&message)?;
+            n_docs += 1;
+
+            if n_docs % PROGRESS_INTERVAL == 0 {
+                progress(n_docs);
+            }
+        }
+
+        writer.flush().chain(&message)?;
+
+        if n_docs % PROGRESS_INTERVAL != 0 {
+            progress(n_docs);
+        }
+
+        Ok(n_docs)
+    }
+
+    /// Reads a stream of BSON documents, as written by `dump()` or
+    /// `mongodump`, and stores them in this collection. Documents whose
+    /// `_id` already exists are handled according to `policy`.
+    pub fn restore<R: Read>(&self, reader: R, policy: RestorePolicy) -> Result<RestoreResult> {
+        self.restore_with_progress(reader, policy, |_| {})
+    }
+
+    /// Like `restore()`, but calls `progress` with the documents stored so
+    /// far after every 1000 documents, and once at the end.
+    pub fn restore_with_progress<R, F>(&self, mut reader: R, policy: RestorePolicy, progress: F)
+        -> Result<RestoreResult>
+        where R: Read,
+              F: FnMut(&RestoreResult),
+    {
+        let message = || format!("error in {}::restore({:?})", T::NAME, policy);
+        self.store_documents(|| read_document(&mut reader), policy, progress, message)
+    }
+
+    /// Writes the documents matching `filter` to `writer` as MongoDB
+    /// Extended JSON, one document per line, like `mongoexport` does.
+    /// The documents are written verbatim, without deserializing them into
+    /// `T`. Returns the number of documents written.
+    pub fn export_json<W: Write>(&self, mut writer: W, filter: Document, mode: ExtendedJsonMode)
+        -> Result<usize>
+    {
+        let message = || format!("error in {}::export_json({:?})", T::NAME, mode);
+        let cursor = self.inner.find(Some(filter), None).chain(&message)?;
+        let mut n_docs = 0;
+
+        for doc in cursor {
+            let json = to_extended_json(&Bson::Document(doc.chain(&message)?), mode);
+            serde_json::to_writer(&mut writer, &json).chain(&message)?;
+            writer.write_all(b"\n").chain(&message)?;
+            n_docs += 1;
+        }
+
+        writer.flush().chain(&message)?;
+
+        Ok(n_docs)
+    }
+
+    /// Reads documents in MongoDB Extended JSON, as written by
+    /// `export_json()` or `mongoexport`, and stores them in this collection.
+    /// The documents may be separated by any whitespace, e.g. one per line.
+    /// Documents whose `_id` already exists are handled according to `policy`.
+    pub fn import_json<R: Read>(&self, reader: R, policy: RestorePolicy) -> Result<RestoreResult> {
+        let message = || format!("error in {}::import_json({:?})", T::NAME, policy);
+        let mut values = serde_json::Deserializer::from_reader(reader).into_iter::<serde_json::Value>();
+        let next = || match values.next() {
+            Some(value) => from_extended_json(value?)?.try_into_doc().map(Some),
+            None => Ok(None),
+        };
+
+        self.store_documents(next, policy, |_| {}, message)
+    }
+
+    /// Stores the documents returned by `next` until it returns `None`,
+    /// handling existing `_id`s according to `policy`, and calling
+    /// `progress` after every `BATCH_SIZE` documents and at the end. Used by
+    /// `restore()` and `import_json()`.
+    fn store_documents<N, P, M>(&self, mut next: N, policy: RestorePolicy, mut progress: P, message: M)
+        -> Result<RestoreResult>
+        where N: FnMut() -> Result<Option<Document>>,
+              P: FnMut(&RestoreResult),
+              M: Fn() -> String,
+    {
+        /// The number of documents inserted at once with `RestorePolicy::Insert`,
+        /// and the number of documents stored between calls to `progress`.
+        const BATCH_SIZE: usize = 1000;
+
+        let mut result = RestoreResult::default();
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            let next_doc = next().chain(&message)?;
+
+            match policy {
+                RestorePolicy::Insert => {
+                    let done = next_doc.is_none();
+
+                    batch.extend(next_doc);
+
+                    if batch.len() >= BATCH_SIZE || (done && !batch.is_empty()) {
+                        let n_docs = batch.len();
+                        let reply = self.inner
+                            .insert_many(batch.split_off(0), T::insert_options().into())
+                            .chain(&message)?;
+
+                        if let Some(error) = reply.bulk_write_exception {
+                            return Err(bulk_write_error::<T>(message(), error));
+                        }
+
+                        result.inserted += n_docs;
+                        progress(&result);
+                    }
+
+                    if done {
+                        break;
+                    }
+                }
+                RestorePolicy::Replace => {
+                    let doc = match next_doc {
+                        Some(doc) => doc,
+                        None => {
+                            if (result.inserted + result.replaced) % BATCH_SIZE != 0 {
+                                progress(&result);
+                            }
+                            break;
+                        }
+                    };
+                    let id = doc.get("_id").cloned().ok_or_else(
+                        || Error::new(MissingId, format!("{}: document without `_id`", message()))
+                    )?;
+                    let options = UpdateOptions {
+                        upsert: Some(true),
+                        write_concern: T::update_options().into(),
+                    };
+                    let reply = self.inner
+                        .replace_one(doc!{ "_id": id }, doc, options.into())
+                        .chain(&message)?;
+
+                    if let Some(error) = reply.write_exception {
+                        return Err(write_error::<T>(message(), error));
+                    }
+
+                    if reply.upserted_id.is_some() {
+                        result.inserted += 1;
+                    } else {
+                        result.replaced += 1;
+                    }
+
+                    if (result.inserted + result.replaced) % BATCH_SIZE == 0 {
+                        progress(&result);
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
     /// Runs an aggregation pipeline.
-    pub fn aggregate<P: Pipeline<T>>(&self, pipeline: P) -> Result<Cursor<P::Output>> {
+    pub fn aggregate<P>(&self, pipeline: P) -> Result<Cursor<P::Output>>
+        where P: Pipeline<T>,
+              P::Output: cursor::Item,
+    {
         self.inner
-            .aggregate(pipeline.stages(), pipeline.options().into())
+            .aggregate(live_stages::<T>(pipeline.stages()), pipeline.options().with_default_max_time().into())
             .chain(|| format!("error in {}::aggregate({:#?})", T::NAME, pipeline))
-            .map(|crs| Cursor::from_cursor_and_transform(crs, P::transform))
+            .map(|crs| Cursor::from_cursor_and_transform(crs, P::transform).with_profile(T::SERDE_PROFILE))
+    }
+
+    /// Runs an aggregation pipeline built using the typed
+    /// [`pipeline`](../pipeline/index.html) builder.
+    pub fn aggregate_pipeline<O>(&self, pipeline: pipeline::Pipeline<T, O>) -> Result<Cursor<O>>
+        where O: cursor::Item
+    {
+        self.aggregate(pipeline)
+    }
+
+    /// Searches the text index of the collection for `search`, in `$text`
+    /// syntax, e.g. `coffee "fair trade" -decaf`. Returns the matching
+    /// documents, most relevant first, along with their text score.
+    pub fn text_search<S>(&self, search: S, options: text::SearchOptions) -> Result<Cursor<(T, f64)>>
+        where S: Into<String>,
+              T: cursor::Item,
+    {
+        self.aggregate(text::Search::with_options(search, options).scored())
+    }
+
+    /// Returns the documents nearest to `point`, nearest first, along with
+    /// their distance from it, using a `$geoNear` aggregation. Unless
+    /// `options.key` names the field to use, the collection must have exactly
+    /// one geospatial index, e.g. declared by `#[avocado(geo_index)]`.
+    pub fn geo_near(&self, point: Point, options: geo::NearOptions) -> Result<Cursor<geo::NearResult<T>>>
+        where T: cursor::Item
+    {
+        self.aggregate(geo::Near::new(point, options))
+    }
+
+    /// Groups the documents by the expression `key`, e.g. `"$customer"`, and
+    /// computes a single value of each group using `accumulator`, e.g. the
+    /// number of documents or the sum of a field. Returns the key and the
+    /// value of each group, in ascending order of the key.
+    pub fn group_by<K, V, E>(&self, key: E, accumulator: Accumulator) -> Result<Vec<(K, V)>>
+        where K: cursor::Item,
+              V: cursor::Item,
+              E: Into<Bson>,
+    {
+        /// A group, as returned by the `$group` stage.
+        #[derive(Deserialize)]
+        struct Group<K, V> {
+            /// The key of the group.
+            #[serde(rename = "_id")]
+            key: K,
+            /// The accumulated value.
+            value: V,
+        }
+
+        let id = key.into();
+        let message = || format!("error in {}::group_by({}, {:?})", T::NAME, id, accumulator);
+        let pipeline = pipeline::Pipeline::<T>::new()
+            .group(id.clone(), doc!{ "value": accumulator.clone() })
+            .sort(doc!{ "_id": 1 })
+            .output::<Group<K, V>>();
+
+        self.aggregate_pipeline(pipeline)
+            .chain(&message)?
+            .map(|result| result.map(|group| (group.key, group.value)))
+            .collect::<Result<_>>()
+            .chain(&message)
+    }
+
+    /// Runs an aggregation pipeline which writes its output documents into
+    /// the collection of `U`, and returns that collection.
+    ///
+    /// If the last stage of the pipeline is an `$out` or `$merge` stage,
+    /// e.g. one appended by `pipeline::Pipeline::out()` or `merge_into()`,
+    /// it must target the collection of `U`. Otherwise, an `$out` stage
+    /// replacing the collection of `U` is appended to the pipeline.
+    pub fn aggregate_into<U, P>(&self, pipeline: P) -> Result<Collection<U>>
+        where U: Doc,
+              P: Pipeline<T, Output = U>,
+    {
+        let message = || format!("error in {}::aggregate_into::<{}>({:#?})", T::NAME, U::NAME, pipeline);
+        let mut stages = live_stages::<T>(pipeline.stages());
+        let target = stages.last().and_then(|stage| {
+            stage.get_str("$out")
+                .or_else(|_| stage.get_document("$merge").and_then(|merge| merge.get_str("into")))
+                .ok()
+                .map(ToOwned::to_owned)
+        });
+
+        match target {
+            Some(ref name) if name == U::NAME => {}
+            Some(name) => return Err(Error::new(
+                ErrorKind::InvalidPipeline,
+                format!("{}: output written to {}, not {}", message(), name, U::NAME)
+            )),
+            None => stages.push(doc!{ "$out": U::NAME }),
+        }
+
+        let options = pipeline.options().with_default_max_time();
+        let cursor = self.inner.aggregate(stages, options.into()).chain(&message)?;
+
+        // The output stage yields no documents, but exhausting the cursor
+        // surfaces any error reported after the first batch.
+        for result in cursor {
+            result.chain(&message)?;
+        }
+
+        Ok(self.inner.db.collection(U::NAME).into())
+    }
+
+    /// Opens a change stream on the collection, yielding an event for each
+    /// change of its documents, with the changed documents deserialized as
+    /// `T`. Iterating the cursor blocks until the next event arrives.
+    ///
+    /// Unlike a [`change_stream::Consumer`](../change_stream/struct.Consumer.html),
+    /// the stream isn't reopened on errors; resume it manually by passing
+    /// the resume token of the last handled event in `options.resume_after`.
+    pub fn watch(&self, options: WatchOptions) -> Result<Cursor<ChangeEvent<T>>>
+        where T: cursor::Item
+    {
+        let stream = ChangeStream::<T, ChangeEvent<T>>::new(
+            options.resume_after,
+            options.full_document,
+            &options.pipeline,
+        );
+
+        self.aggregate(stream)
     }
 
     /// Retrieves a single document satisfying the query, if one exists.
     pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        let options = query_options::<T, Q>(&query).with_default_max_time();
+        let command_options = query.command_options();
+        let raw = if command_options.is_empty() {
+            self.inner.find_one(filter.into(), options.into()).map_err(From::from)
+        } else {
+            self.find_command_cursor(filter, options, &command_options, true)
+                .and_then(|mut cursor| cursor.next().map_or(
+                    Ok(None),
+                    |doc| doc.map(Some).map_err(From::from),
+                ))
+        };
+
         // This uses `impl Deserialize for Option<T> where T: Deserialize`
         // and the fact that in MongoDB, top-level documents are always
         // `Document`s and never `Null`.
-        self.inner
-            .find_one(query.filter().into(), query.options().into())
+        raw
             .chain(|| format!("error in {}::find_one({:#?})", T::NAME, query))
             .and_then(|opt| opt.map_or(Ok(None), |doc| {
-                let transformed = Q::transform(doc)?;
-                from_bson(transformed).map_err(From::from)
+                let transformed = strict_transform::<T, Q>(doc)?;
+                T::SERDE_PROFILE.deserialize(transformed)
             }))
     }
 
     /// Retrieves all documents satisfying the query.
-    pub fn find_many<Q: Query<T>>(&self, query: Q) -> Result<Cursor<Q::Output>> {
-        self.inner
-            .find(query.filter().into(), query.options().into())
+    pub fn find_many<Q>(&self, query: Q) -> Result<Cursor<Q::Output>>
+        where Q: Query<T>,
+              Q::Output: cursor::Item,
+    {
+        self.find_many_internal(live::<T>(renamed::<T>(query.filter())), &query)
             .chain(|| format!("error in {}::find_many({:#?})", T::NAME, query))
-            .map(|crs| Cursor::from_cursor_and_transform(crs, Q::transform))
+    }
+
+    /// Retrieves all documents satisfying the query as the lighter view type
+    /// `V`, fetching only the fields of `V` rather than entire documents,
+    /// unless the query specifies a projection of its own.
+    /// See `projection::Projection::of()`.
+    pub fn find_as<V, Q>(&self, query: Q) -> Result<Cursor<V>>
+        where V: cursor::Item + Debug,
+              Q: Query<T>,
+    {
+        self.find_many(AsView::new(query))
+    }
+
+    /// Runs the query with the given (already renamed) filter.
+    fn find_many_internal<Q>(&self, filter: Document, query: &Q) -> Result<Cursor<Q::Output>>
+        where Q: Query<T>,
+              Q::Output: cursor::Item,
+    {
+        let options = query_options::<T, Q>(query).with_default_max_time();
+        let command_options = query.command_options();
+        let cursor = if command_options.is_empty() {
+            self.inner.find(filter.into(), options.into())?
+        } else {
+            self.find_command_cursor(filter, options, &command_options, false)?
+        };
+
+        Ok(Cursor::from_cursor_and_transform(cursor, strict_transform::<T, Q>).with_profile(T::SERDE_PROFILE))
+    }
+
+    /// Runs a `find` command with command options, which the driver can't
+    /// send. If `single` is set, at most one document is returned.
+    fn find_command_cursor(
+        &self,
+        filter: Document,
+        mut options: FindOptions,
+        command_options: &options::Extra,
+        single: bool,
+    ) -> Result<mongodb::cursor::Cursor> {
+        if single {
+            options.limit = Some(1);
+        }
+
+        let read_preference = options.read_preference.clone().unwrap_or_else(
+            || ReadPreference::new(ReadMode::Primary, None)
+        );
+        let mut command = find_command(self.inner.name(), filter, options, command_options);
+
+        if single {
+            command.insert("singleBatch", true);
+        }
+
+        self.inner.db
+            .command_cursor(command, CommandType::Find, read_preference)
+            .map_err(From::from)
+    }
+
+    /// Loads the documents referred to by `refs` using a single `$in` query.
+    /// The result contains one item per reference, in the same order, which
+    /// is `None` if the reference is dangling.
+    pub fn populate<'a, I>(&self, refs: I) -> Result<Vec<Option<T>>>
+        where I: IntoIterator<Item = &'a Ref<T>>,
+              T: cursor::Item + Clone + 'a,
+              T::Id: Hash + Clone + Debug,
+    {
+        let all_refs: Vec<_> = refs.into_iter().collect();
+        let ids: Vec<_> = all_refs.iter().map(|reference| reference.id().clone()).collect();
+        let found = self.find_by_ids(&ids)?;
+
+        Ok(all_refs.iter().map(|reference| found.get(reference.id()).cloned()).collect())
+    }
+
+    /// Loads the documents with the given IDs using a single `$in` query,
+    /// keyed by their ID. IDs without a matching document are simply absent
+    /// from the result; use `load_many()` for reporting them, or for getting
+    /// the documents in the order of `ids`.
+    pub fn find_by_ids(&self, ids: &[Uid<T>]) -> Result<HashMap<Uid<T>, T>>
+        where T: cursor::Item,
+              T::Id: Hash + Clone + Debug,
+    {
+        let message = || format!("error in {}::find_by_ids({:?})", T::NAME, ids);
+        let mut found = HashMap::with_capacity(ids.len());
+
+        if ids.is_empty() {
+            return Ok(found);
+        }
+
+        let raw_ids = ids
+            .iter()
+            .map(|id| bson::to_bson(id).map_err(From::from))
+            .collect::<Result<Vec<_>>>()
+            .chain(&message)?;
+
+        for result in self.find_many(doc!{ "_id": { "$in": raw_ids } }).chain(&message)? {
+            let entity = result.chain(&message)?;
+
+            if let Some(id) = entity.id().cloned() {
+                found.insert(id, entity);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Loads the documents with the given IDs using a single `$in` query.
+    /// The found documents are returned in the order of `ids`, along with
+    /// the IDs that have no matching document.
+    pub fn load_many(&self, ids: &[Uid<T>]) -> Result<LoadedMany<T>>
+        where T: cursor::Item + Clone,
+              T::Id: Hash + Clone + Debug,
+    {
+        let by_id = self.find_by_ids(ids)?;
+        let mut loaded = LoadedMany {
+            found: Vec::with_capacity(by_id.len()),
+            missing: Vec::new(),
+        };
+
+        for id in ids {
+            match by_id.get(id) {
+                Some(entity) => loaded.found.push(entity.clone()),
+                None => loaded.missing.push(id.clone()),
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Returns a loader which batches and deduplicates lookups by ID, and
+    /// caches the loaded documents. See the [`loader`](../loader/index.html)
+    /// module.
+    pub fn loader(&self) -> Loader<'_, T> where T: cursor::Item + Clone, T::Id: Hash + Clone + Debug {
+        Loader::new(self)
+    }
+
+    /// Retrieves all documents satisfying the query, sorted by `_id`. If the
+    /// cursor is lost, e.g. because of a failover, the query is re-issued
+    /// transparently, continuing after the last retrieved document.
+    pub fn find_many_resumable<Q: Query<T>>(&self, query: Q) -> scan::Resumable<'_, T, Q> {
+        scan::Resumable::new(self, query)
+    }
+
+    /// Iterates over all documents of the collection in ascending order of
+    /// `_id`, requesting at most `page_size` documents at once, each page by
+    /// a separate query. Suitable for maintenance jobs which must visit every
+    /// document with bounded memory; see `scan::Paged::resume_after()` for
+    /// continuing an interrupted scan.
+    pub fn iter_all_sorted_by_id(&self, page_size: usize) -> scan::Paged<'_, T> {
+        scan::Paged::new(self, page_size)
+    }
+
+    /// Splits the collection into at most `n_partitions` disjoint ranges of
+    /// `_id`s, which together cover every document, including the ones
+    /// inserted later. The boundaries are chosen from a random sample of the
+    /// `_id`s, so the partitions contain roughly the same number of
+    /// documents. Fewer partitions are returned if the collection is small.
+    ///
+    /// The `_id`s of the collection are expected to be of the same BSON type;
+    /// see `scan::Partition` for how other types are handled.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn scan_partitions(&self, n_partitions: usize) -> Result<Vec<scan::Partition>> {
+        if n_partitions <= 1 {
+            return Ok(vec![scan::Partition::default()]);
+        }
+
+        let message = || format!("error in {}::scan_partitions({})", T::NAME, n_partitions);
+        let sample_size = n_partitions.saturating_mul(SAMPLES_PER_PARTITION) as i64;
+        let pipeline = vec![
+            doc!{ "$sample": { "size": sample_size } },
+            doc!{ "$project": { "_id": 1 } },
+            doc!{ "$sort": { "_id": 1 } },
+        ];
+        let sample = self.inner
+            .aggregate(pipeline, T::aggregate_options().with_default_max_time().into())
+            .chain(&message)?
+            .map(|result| {
+                let mut doc = result.chain(&message)?;
+                doc.remove("_id").ok_or_else(|| Error::new(
+                    MissingId, format!("{} document without `_id` in sample", T::NAME)
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(partitions_from_sample(&sample, n_partitions))
+    }
+
+    /// Splits a scan of the whole collection into at most `n_partitions`
+    /// disjoint ranges of `_id`s using `scan_partitions()`, and returns an
+    /// independent cursor over the documents of each, sorted by `_id`. The
+    /// cursors can be consumed in parallel, e.g. on separate threads.
+    pub fn par_scan(&self, n_partitions: usize) -> Result<Vec<Cursor<T>>>
+        where T: cursor::Item
+    {
+        self.scan_partitions(n_partitions)?
+            .into_iter()
+            .map(|partition| self.find_many(partition))
+            .collect()
+    }
+
+    /// Splits the results of the query into pages of at most `page_size`
+    /// items, which can be requested by number or, using continuation
+    /// tokens, by keyset pagination.
+    pub fn paginate<Q: Query<T>>(&self, query: Q, page_size: usize) -> Paginator<'_, T, Q> {
+        Paginator::new(self, query, page_size)
+    }
+
+    /// Follows a capped collection, yielding the documents matching the
+    /// filter in insertion order, including the ones inserted after the
+    /// cursor was opened, using a tailable `awaitData` cursor. Iteration
+    /// blocks while the server waits for new documents, and it ends when
+    /// none arrives within the await period of the server, or when the
+    /// cursor is invalidated, e.g. because the collection isn't capped. To
+    /// keep following the collection, tail it again, filtering for
+    /// documents newer than the last one seen.
+    ///
+    /// The default time limit of the [`timeout`](../timeout/index.html)
+    /// module isn't applied, because it would limit the lifetime of the
+    /// cursor.
+    pub fn tail(&self, filter: Document) -> Result<Cursor<T>>
+        where T: cursor::Item
+    {
+        let message = || format!("error in {}::tail({:#?})", T::NAME, filter);
+        let options = FindOptions {
+            cursor_type: CursorType::TailableAwait,
+            sort: None,
+            limit: None,
+            skip: None,
+            ..renamed_options::<T>(T::query_options())
+        };
+
+        self.inner
+            .find(live::<T>(renamed::<T>(filter.clone())).into(), options.into())
+            .chain(&message)
+            .map(|crs| {
+                Cursor::from_cursor_and_transform(crs, strict_transform::<T, Document>)
+                    .with_profile(T::SERDE_PROFILE)
+            })
+    }
+
+    /// Retrieves all documents satisfying the query, as they were at the
+    /// cluster time of the snapshot session, or as seen by the transaction.
+    /// See the [`snapshot`](../snapshot/index.html) and
+    /// [`transaction`](../transaction/index.html) modules for details.
+    pub fn find_many_in<S, Q>(&self, session: &S, query: Q) -> Result<Vec<Q::Output>>
+        where S: ReadSession,
+              Q: Query<T>,
+    {
+        let options = query_options::<T, Q>(&query).with_default_max_time();
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        let command = find_command(self.inner.name(), filter, options, &query.command_options());
+
+        self.session_cursor(session, command, strict_transform::<T, Q>)
+            .chain(|| format!("error in {}::find_many_in({:#?})", T::NAME, query))
+    }
+
+    /// Runs an aggregation pipeline on the data as it was at the cluster
+    /// time of the snapshot session, or as seen by the transaction. See the
+    /// [`snapshot`](../snapshot/index.html) and
+    /// [`transaction`](../transaction/index.html) modules for details.
+    pub fn aggregate_in<S, P>(&self, session: &S, pipeline: P) -> Result<Vec<P::Output>>
+        where S: ReadSession,
+              P: Pipeline<T>,
+    {
+        let options = pipeline.options().with_default_max_time();
+        let mut command = doc!{
+            "aggregate": self.inner.name(),
+            "pipeline": live_stages::<T>(pipeline.stages()).into_iter().map(Bson::Document).collect::<Vec<_>>(),
+            "cursor": {},
+        };
+
+        if let Some(max_time_ms) = options.max_time_ms {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+
+        self.session_cursor(session, command, P::transform)
+            .chain(|| format!("error in {}::aggregate_in({:#?})", T::NAME, pipeline))
+    }
+
+    /// Retrieves all documents satisfying the query, unless the token is
+    /// canceled or its deadline passes first, in which case the query is
+    /// killed on the server, and a `Canceled` error is returned. The time
+    /// left until the deadline is sent as `maxTimeMS`. See the
+    /// [`cancel`](../cancel/index.html) module for details.
+    pub fn find_many_cancellable<Q: Query<T>>(&self, query: Q, token: &CancellationToken)
+        -> Result<Vec<Q::Output>>
+    {
+        let options = query_options::<T, Q>(&query).with_default_max_time();
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        let command = find_command(self.inner.name(), filter, options, &query.command_options());
+
+        self.cancellable_cursor(command, token, strict_transform::<T, Q>)
+            .chain(|| format!("error in {}::find_many_cancellable({:#?})", T::NAME, query))
+    }
+
+    /// Runs an aggregation pipeline, unless the token is canceled or its
+    /// deadline passes first; see `find_many_cancellable()`.
+    pub fn aggregate_cancellable<P: Pipeline<T>>(&self, pipeline: P, token: &CancellationToken)
+        -> Result<Vec<P::Output>>
+    {
+        let options = pipeline.options().with_default_max_time();
+        let stages = live_stages::<T>(pipeline.stages());
+        let mut command = doc!{
+            "aggregate": self.inner.name(),
+            "pipeline": stages.into_iter().map(Bson::Document).collect::<Vec<_>>(),
+            "cursor": {},
+        };
+
+        if let Some(max_time_ms) = options.max_time_ms {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+
+        self.cancellable_cursor(command, token, P::transform)
+            .chain(|| format!("error in {}::aggregate_cancellable({:#?})", T::NAME, pipeline))
+    }
+
+    /// Runs a cursor-returning read command with the cancellation token,
+    /// and reads all batches of the resulting cursor. The token is checked
+    /// before each batch; if it is canceled, the cursor is killed.
+    fn cancellable_cursor<O>(
+        &self,
+        mut command: Document,
+        token: &CancellationToken,
+        transform: fn(Document) -> Result<Bson>,
+    ) -> Result<Vec<O>>
+        where O: for<'a> Deserialize<'a>
+    {
+        token.check()?;
+        token.attach(&mut command);
+
+        let db = &self.inner.db;
+        let active = token.register(db, &self.inner.name());
+        let mut reply = db
+            .command(command, CommandType::Suppressed, None)
+            .map_err(|error| token.interrupted(error.into()))?;
+        let mut batch_key = "firstBatch";
+        let mut results = Vec::new();
+
+        loop {
+            let mut cursor = reply
+                .remove("cursor")
+                .ok_or_else(|| Error::new(MissingDocumentField, "no `cursor` in reply"))
+                .and_then(Bson::try_into_doc)?;
+            let id = cursor.get_i64("id")?;
+
+            active.set_cursor(id);
+
+            match cursor.remove(batch_key) {
+                Some(Bson::Array(batch)) => for item in batch {
+                    let document = item.try_into_doc()?;
+                    results.push(T::SERDE_PROFILE.deserialize(transform(document)?)?);
+                },
+                _ => return Err(Error::new(
+                    MissingDocumentField,
+                    format!("no `cursor.{}` array in reply", batch_key)
+                )),
+            }
+
+            if id == 0 {
+                return Ok(results);
+            }
+            if let Err(error) = token.check() {
+                active.kill_cursor().ok();
+                return Err(error);
+            }
+
+            let get_more = doc!{
+                "getMore": id,
+                "collection": self.inner.name(),
+            };
+
+            reply = db
+                .command(get_more, CommandType::Suppressed, None)
+                .map_err(|error| token.interrupted(error.into()))?;
+            batch_key = "nextBatch";
+        }
+    }
+
+    /// Runs a cursor-returning read command in the snapshot session or
+    /// transaction, and reads all batches of the resulting cursor.
+    fn session_cursor<S, O>(
+        &self,
+        session: &S,
+        mut command: Document,
+        transform: fn(Document) -> Result<Bson>,
+    ) -> Result<Vec<O>>
+        where S: ReadSession,
+              O: for<'a> Deserialize<'a>,
+    {
+        session.attach(&mut command);
+
+        let db = &self.inner.db;
+        let mut reply = db.command(command, CommandType::Suppressed, None)?;
+        let mut batch_key = "firstBatch";
+        let mut results = Vec::new();
+
+        loop {
+            let mut cursor = reply
+                .remove("cursor")
+                .ok_or_else(|| Error::new(MissingDocumentField, "no `cursor` in reply"))
+                .and_then(Bson::try_into_doc)?;
+            let id = cursor.get_i64("id")?;
+
+            match cursor.remove(batch_key) {
+                Some(Bson::Array(batch)) => for item in batch {
+                    let document = item.try_into_doc()?;
+                    results.push(T::SERDE_PROFILE.deserialize(transform(document)?)?);
+                },
+                _ => return Err(Error::new(
+                    MissingDocumentField,
+                    format!("no `cursor.{}` array in reply", batch_key)
+                )),
+            }
+
+            if id == 0 {
+                return Ok(results);
+            }
+
+            let mut get_more = doc!{
+                "getMore": id,
+                "collection": self.inner.name(),
+            };
+
+            session.attach_get_more(&mut get_more);
+            reply = db.command(get_more, CommandType::Suppressed, None)?;
+            batch_key = "nextBatch";
+        }
     }
 
     /// Inserts a single document.
+    ///
+    /// Documents bigger than `T::bson_size_limit()` are rejected
+    /// without contacting the server.
     pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
-        let doc = serialize_document(entity)?;
+        self.insert_document(serialize_entity(entity)?)
+    }
+
+    /// Helper for `insert_one()` and `ScopedCollection::insert_one()`,
+    /// which inserts an already serialized entity.
+    pub(crate) fn insert_document(&self, mut doc: Document) -> Result<Uid<T>> {
+        generate_id::<T>(&mut doc)?;
+        stamp_inserted::<T>(&mut doc);
+        check_entity_document::<T>(&doc)?;
         let write_concern = T::insert_options().write_concern;
         let message = || format!("error in {}::insert_one()", T::NAME);
 
@@ -124,7 +1424,7 @@ impl<T: Doc> Collection<T> {
             .chain(&message)
             .and_then(|result| {
                 if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
+                    Err(write_error::<T>(message(), error))
                 } else if let Some(id) = result.inserted_id {
                     from_bson(id).chain(
                         || format!("can't deserialize ID for {}", T::NAME)
@@ -135,6 +1435,117 @@ impl<T: Doc> Collection<T> {
             })
     }
 
+    /// Inserts a single document, unless it would violate a unique index,
+    /// in which case the existing document matching `unique_filter` is
+    /// returned instead, e.g. for creating a user by email only once.
+    /// `unique_filter` should select the conflicting document by the fields
+    /// of the unique index, e.g. `doc!{ "email": &user.email }`.
+    ///
+    /// If the insert fails with a duplicate key error, but no document
+    /// matches `unique_filter` (e.g. because the key was a duplicate in
+    /// another index), the duplicate key error is returned.
+    pub fn insert_or_fetch(&self, entity: &T, unique_filter: Document)
+        -> Result<InsertOrFetch<Uid<T>, T>>
+    {
+        let error = match self.insert_one(entity) {
+            Ok(id) => return Ok(InsertOrFetch::Inserted(id)),
+            Err(e) => e,
+        };
+
+        if !error.is_duplicate_key() {
+            return Err(error);
+        }
+
+        match self.find_one(unique_filter)? {
+            Some(existing) => Ok(InsertOrFetch::Existing(existing)),
+            None => Err(error),
+        }
+    }
+
+    /// Executes raw write operations in a single ordered bulk write, which
+    /// stops at the first failing operation. Duplicate key errors on unique
+    /// indexes declared by `T::indexes()` are reported as `UniqueViolation`.
+    pub fn bulk_write(&self, writes: Vec<WriteModel>) -> Result<BulkWriteResult> {
+        let message = || format!("error in {}::bulk_write()", T::NAME);
+        let mut result = self.inner.bulk_write(writes, true);
+
+        match result.bulk_write_exception.take() {
+            Some(error) => Err(bulk_write_error::<T>(message(), error)),
+            None => Ok(result),
+        }
+    }
+
+    /// Returns a writer which buffers inserts and updates, and sends them
+    /// to this collection in bulk.
+    pub fn batched_writer(&self, options: batch::Options) -> BatchedWriter<'_, T> {
+        BatchedWriter::new(self, options)
+    }
+
+    /// Inserts a single document, tagged with the given idempotency key in
+    /// the `IDEMPOTENCY_KEY_FIELD` field. If the insert is retried with the
+    /// same key, e.g. after an ambiguous network error, the unique index
+    /// created by `create_idempotency_index()` prevents a duplicate, and the
+    /// ID of the previously inserted document is returned instead, with
+    /// `already_applied` set to `true`.
+    ///
+    /// The key field is stored alongside the fields of the entity, so `T`
+    /// must tolerate it when deserializing (i.e. it must not be annotated
+    /// with `#[serde(deny_unknown_fields)]`).
+    pub fn insert_one_idempotent(&self, entity: &T, key: &str)
+        -> Result<IdempotentInsertResult<Uid<T>>>
+    {
+        let mut doc = serialize_entity(entity)?;
+        generate_id::<T>(&mut doc)?;
+        stamp_inserted::<T>(&mut doc);
+        doc.insert(IDEMPOTENCY_KEY_FIELD, key);
+        check_entity_document::<T>(&doc)?;
+        let write_concern = T::insert_options().write_concern;
+        let message = || format!("error in {}::insert_one_idempotent({:?})", T::NAME, key);
+
+        let result = self.inner
+            .insert_one(doc, write_concern)
+            .chain(&message)?;
+
+        match result.write_exception {
+            Some(ref error) if is_idempotency_conflict(error) => {
+                self.find_idempotent_id(key).map(|id| IdempotentInsertResult {
+                    id,
+                    already_applied: true,
+                })
+            }
+            Some(error) => Err(write_error::<T>(message(), error)),
+            None => {
+                let raw_id = result.inserted_id.ok_or_else(
+                    || Error::new(MissingId, message() + ": missing `inserted_id`")
+                )?;
+                let id = from_bson(raw_id).chain(
+                    || format!("can't deserialize ID for {}", T::NAME)
+                )?;
+
+                Ok(IdempotentInsertResult { id, already_applied: false })
+            }
+        }
+    }
+
+    /// Retrieves the ID of the document previously inserted with the given
+    /// idempotency key.
+    fn find_idempotent_id(&self, key: &str) -> Result<Uid<T>> {
+        let message = || format!("can't find {} with idempotency key {:?}", T::NAME, key);
+        let options = FindOptions {
+            projection: Some(doc!{ "_id": 1 }),
+            ..Default::default()
+        };
+        let mut doc = self.inner
+            .find_one(Some(doc!{ IDEMPOTENCY_KEY_FIELD: key }), Some(options))
+            .chain(&message)?
+            .ok_or_else(|| Error::new(MissingId, message()))?;
+        let id = doc.remove("_id").ok_or_else(
+            || Error::new(MissingId, message() + ": missing `_id`")
+        )?;
+
+        from_bson(id).chain(|| format!("can't deserialize ID for {}", T::NAME))
+    }
+
     /// Inserts many documents.
     ///
     /// If this method fails to insert all documents, the returned error will
@@ -144,6 +1555,9 @@ impl<T: Doc> Collection<T> {
     /// for IDs which couldn't be deserialized.
     ///
     /// The context map can be accessed as: `error.context::<InsertManyErrorContext<T>>()`
+    ///
+    /// If any of the documents is bigger than `T::bson_size_limit()`, then
+    /// none of them is inserted, and the server isn't contacted at all.
     pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
         where I: IntoIterator,
               I::Item: Borrow<T>,
@@ -151,17 +1565,38 @@ impl<T: Doc> Collection<T> {
               T::Id: Clone + Debug,
               T: 'static,
     {
-        let values = entities.into_iter();
-        let n_docs = values.len();
-        let docs = serialize_documents(values)?;
+        let docs = entities
+            .into_iter()
+            .map(|value| serialize_entity(value.borrow()))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.insert_documents(docs)
+    }
+
+    /// Helper for `insert_many()` and `ScopedCollection::insert_many()`,
+    /// which inserts already serialized entities.
+    pub(crate) fn insert_documents(&self, mut docs: Vec<Document>) -> Result<BTreeMap<u64, Uid<T>>>
+        where T::Id: Clone + Debug,
+              T: 'static,
+    {
+        let n_docs = docs.len();
         let options = T::insert_options();
         let message = || format!("error in {}::insert_many()", T::NAME);
 
+        for doc in &mut docs {
+            generate_id::<T>(doc)?;
+            stamp_inserted::<T>(doc);
+        }
+
         // MongoDB complains if you try to insert 0 documents, but that's silly.
         if n_docs == 0 {
             return Ok(BTreeMap::new());
         }
 
+        for doc in &docs {
+            check_entity_document::<T>(doc)?;
+        }
+
         self.inner
             .insert_many(docs, options.into())
             .chain(&message)
@@ -179,7 +1614,7 @@ impl<T: Doc> Collection<T> {
                 if let Some(error) = result.bulk_write_exception {
                     // If there was an insertion error, report an error, but
                     // return all the IDs of the inserted documents anyway.
-                    Err(Error::with_cause(message(), error)
+                    Err(bulk_write_error::<T>(message(), error)
                         .with_context::<InsertManyErrorContext<T>>(ids))
                 } else if ids.len() == n_docs {
                     // If there's exacly one ID returned for each document,
@@ -227,30 +1662,149 @@ impl<T: Doc> Collection<T> {
             .and_then(UpsertOneResult::from_raw)
     }
 
+    /// Replaces a document based on its `_id`, provided that its stored
+    /// version still equals `entity.version()`, i.e. nobody else has written
+    /// it since it was read. The stored version is incremented atomically,
+    /// and so is the version of `entity` if the replacement succeeds.
+    ///
+    /// Returns a `StaleVersion` error if the document has been modified or
+    /// deleted in the meantime, and a `MissingDocumentField` error if `T`
+    /// isn't versioned; see `Doc::version_field()`.
+    pub fn replace_entity_versioned(&self, entity: &mut T) -> Result<()> where T: Debug {
+        let field = version_field::<T>()?;
+        let version = entity.version().ok_or_else(|| Error::new(
+            MissingDocumentField,
+            format!("{} document has no version", T::NAME)
+        ))?;
+        let mut document = serialize_entity(&*entity)?;
+        stamp_replaced::<T>(&mut document);
+        check_entity_document::<T>(&document)?;
+        let id = document.remove("_id").ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in entity of type {}", T::NAME))
+        )?;
+        let mut filter = doc!{ "_id": id };
+        let options = UpdateOptions {
+            upsert: Some(false),
+            write_concern: T::update_options().into(),
+        };
+        let message = || format!("error in {}::replace_entity_versioned({:#?})", T::NAME, entity);
+
+        filter.insert(field, version);
+        document.insert(field, version + 1);
+
+        let result = self.inner
+            .replace_one(filter, document, options.into())
+            .chain(&message)?;
+
+        if let Some(error) = result.write_exception {
+            return Err(write_error::<T>(message(), error));
+        }
+        if result.matched_count == 0 {
+            return Err(stale_version::<T>(version));
+        }
+
+        entity.set_version(version + 1);
+
+        Ok(())
+    }
+
+    /// Updates the document with the `_id` of `old`, writing only the fields
+    /// in which `new` differs from `old` via `$set` and `$unset`, instead of
+    /// replacing the entire document. Concurrent updates of other fields are
+    /// therefore preserved. The fields are compared as described at
+    /// `diff::diff()`; encrypted fields are written as a whole.
+    ///
+    /// If the two values are identical, nothing is written, and the result
+    /// only reports whether the document exists. Returns a `MissingId` error
+    /// if `old` has no `_id`, and an `InvalidUpdate` error if `new` has a
+    /// different one.
+    pub fn save_changes(&self, old: &T, new: &T) -> Result<UpdateOneResult> where T: Debug {
+        let message = || format!("error in {}::save_changes({:#?}, {:#?})", T::NAME, old, new);
+        let before = T::SERDE_PROFILE.serialize(old).chain(&message)?;
+        let after = T::SERDE_PROFILE.serialize(new).chain(&message)?;
+
+        self.save_document_changes(before, after, &message)
+    }
+
+    /// Helper for `save_changes()` and `Tracked::save()`, which compares the
+    /// serialized forms of the old and the new value of an entity.
+    pub(crate) fn save_document_changes<F>(&self, mut before: Document, mut after: Document, message: F)
+        -> Result<UpdateOneResult>
+        where F: Copy + FnOnce() -> String
+    {
+        let id = before.remove("_id").ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in entity of type {}", T::NAME))
+        )?;
+
+        if after.remove("_id").as_ref() != Some(&id) {
+            return Err(Error::new(
+                ErrorKind::InvalidUpdate,
+                format!("the changed {} has a different `_id` than the original", T::NAME)
+            ));
+        }
+
+        let opaque: Vec<_> = T::encrypted_fields().iter().map(|field| field.name).collect();
+        let changes = diff::documents_opaque(&before, &after, &opaque);
+
+        if changes.is_empty() {
+            let matched = self.count(doc!{ "_id": id }).chain(message)? > 0;
+            return Ok(UpdateOneResult { matched, modified: false });
+        }
+
+        let filter = live::<T>(doc!{ "_id": id });
+        let change = update_document::<T>(changes.to_update(), false)?;
+        let options = UpdateOptions {
+            upsert: Some(false),
+            write_concern: T::update_options().into(),
+        };
+        let array_filters = ArrayFilters::default();
+        let command_options = options::Extra::default();
+
+        self.update_one_internal(filter, change, options, &array_filters, &command_options, message)
+            .and_then(UpdateOneResult::from_raw)
+    }
+
     /// Helper for the `{...}_entity` convenience methods above.
     fn update_entity_internal(&self, entity: &T, upsert: bool) -> Result<UpdateResult>
         where T: Debug
     {
-        let mut document = serialize_document(entity)?;
+        let message = || format!("error in {}::{}_entity({:#?})",
+                                 T::NAME,
+                                 if upsert { "upsert" } else { "replace" },
+                                 entity);
+
+        self.replace_document(serialize_entity(entity)?, &Document::new(), upsert, message)
+    }
+
+    /// Replaces the document with the `_id` of the serialized entity
+    /// `document`, provided that it also matches `scope`, or inserts it
+    /// if `upsert` is `true`.
+    pub(crate) fn replace_document<F>(
+        &self,
+        mut document: Document,
+        scope: &Document,
+        upsert: bool,
+        message: F,
+    ) -> Result<UpdateResult>
+        where F: Fn() -> String
+    {
+        stamp_replaced::<T>(&mut document);
+        check_entity_document::<T>(&document)?;
         let id = document.remove("_id").ok_or_else(
             || Error::new(MissingId, format!("No `_id` in entity of type {}", T::NAME))
         )?;
-        let filter = doc!{ "_id": id };
+        let filter = and_filters(scope, doc!{ "_id": id });
         let options = UpdateOptions {
             upsert: upsert.into(),
             write_concern: T::update_options().into(),
         };
-        let message = || format!("error in {}::{}_entity({:#?})",
-                                 T::NAME,
-                                 if upsert { "upsert" } else { "replace" },
-                                 entity);
 
         self.inner
             .replace_one(filter, document, options.into())
             .chain(&message)
             .and_then(|result| {
                 if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
+                    Err(write_error::<T>(message(), error))
                 } else {
                     Ok(result)
                 }
@@ -262,32 +1816,68 @@ impl<T: Doc> Collection<T> {
     /// This method only works with update operators (with field names starting
     /// with `$`), i.e. it does **not** replace entire documents.
     pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
-        let filter = update.filter();
-        let change = update.update();
+        let filter = live::<T>(renamed::<T>(update.filter()));
+        let change = update_document::<T>(update.update(), false)?;
         let options = UpdateOptions {
             upsert: Some(false),
             write_concern: update.options().into(),
         };
+        let array_filters = renamed_array_filters::<T>(update.array_filters());
+        let command_options = update.command_options();
         let message = || format!("error in {}::update_one({:#?})", T::NAME, update);
 
-        self.update_one_internal(filter, change, options, &message)
+        self.update_one_internal(filter, change, options, &array_filters, &command_options, &message)
             .and_then(UpdateOneResult::from_raw)
     }
 
+    /// Updates a single document, provided that its stored version equals
+    /// `version`, and increments the stored version atomically. Returns the
+    /// new version, or a `StaleVersion` error if no document matching the
+    /// query criteria has the expected version.
+    pub fn update_one_versioned<U: Update<T>>(&self, version: i64, update: U) -> Result<i64> {
+        let field = version_field::<T>()?;
+        let mut filter = live::<T>(renamed::<T>(update.filter()));
+        let mut change = update_document::<T>(update.update(), false)?;
+        let options = UpdateOptions {
+            upsert: Some(false),
+            write_concern: update.options().into(),
+        };
+        let array_filters = renamed_array_filters::<T>(update.array_filters());
+        let command_options = update.command_options();
+        let message = || format!("error in {}::update_one_versioned({}, {:#?})", T::NAME, version, update);
+        let mut increment = change.get_document("$inc").ok().cloned().unwrap_or_default();
+
+        increment.insert(field, 1_i64);
+        change.insert("$inc", increment);
+        filter.insert(field, version);
+
+        let result = self
+            .update_one_internal(filter, change, options, &array_filters, &command_options, &message)
+            .and_then(UpdateOneResult::from_raw)?;
+
+        if result.matched {
+            Ok(version + 1)
+        } else {
+            Err(stale_version::<T>(version))
+        }
+    }
+
     /// Upserts a single document.
     ///
     /// This method only works with update operators (with field names starting
     /// with `$`), i.e. it does **not** replace entire documents.
     pub fn upsert_one<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertOneResult<Uid<T>>> {
-        let filter = upsert.filter();
-        let change = upsert.upsert();
+        let filter = live::<T>(renamed::<T>(upsert.filter()));
+        let change = update_document::<T>(upsert.upsert(), true)?;
         let options = UpdateOptions {
             upsert: Some(true),
             write_concern: upsert.options().into(),
         };
+        let array_filters = renamed_array_filters::<T>(upsert.array_filters());
+        let command_options = upsert.command_options();
         let message = || format!("error in {}::upsert_one({:#?})", T::NAME, upsert);
 
-        self.update_one_internal(filter, change, options, &message)
+        self.update_one_internal(filter, change, options, &array_filters, &command_options, &message)
             .and_then(UpsertOneResult::from_raw)
     }
 
@@ -300,33 +1890,167 @@ impl<T: Doc> Collection<T> {
         filter: Document,
         change: Document,
         options: UpdateOptions,
+        array_filters: &ArrayFilters,
+        command_options: &options::Extra,
         message: F,
     ) -> Result<UpdateResult> {
+        check_targeted::<T>(&filter, true).chain(message)?;
+
+        if !array_filters.is_empty() || !command_options.is_empty() {
+            return self.update_command(filter, change, options, false, array_filters, command_options)
+                .chain(message);
+        }
+
         self.inner
             .update_one(filter, change, options.into())
             .chain(message)
             .and_then(|result| {
                 if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
+                    Err(write_error::<T>(message(), error))
                 } else {
                     Ok(result)
                 }
             })
     }
 
+    /// Runs an `update` command with array filters or command options,
+    /// which the driver can't send, and converts the reply to an
+    /// `UpdateResult`. Write errors are returned as an `Err`.
+    fn update_command(
+        &self,
+        filter: Document,
+        change: Document,
+        options: UpdateOptions,
+        multi: bool,
+        array_filters: &ArrayFilters,
+        command_options: &options::Extra,
+    ) -> Result<UpdateResult> {
+        let mut statement = doc!{
+            "q": filter,
+            "u": change,
+            "upsert": options.upsert.unwrap_or(false),
+            "multi": multi,
+        };
+
+        if !array_filters.is_empty() {
+            statement.insert("arrayFilters", array_filters.clone());
+        }
+
+        command_options.apply_to_statement(&mut statement);
+
+        let mut command = doc!{
+            "update": self.inner.name(),
+            "updates": [statement],
+        };
+
+        if let Some(write_concern) = options.write_concern {
+            command.insert("writeConcern", write_concern.to_bson());
+        }
+
+        command_options.apply_to_command(&mut command);
+
+        let reply = self.inner.db.command(command, CommandType::Suppressed, None)?;
+
+        match command_write_error::<T>(&reply, "update") {
+            Some(error) => Err(error),
+            None => Ok(UpdateResult::new(reply, None)),
+        }
+    }
+
+    /// Updates a single document, then runs the query, which is guaranteed
+    /// to observe the update. See the [`consistency`](../consistency/index.html)
+    /// module for the exact guarantees.
+    pub fn update_then_find_one<U, Q>(&self, update: U, query: Q)
+        -> Result<(UpdateOneResult, Option<Q::Output>)>
+        where U: Update<T>,
+              Q: Query<T>,
+    {
+        let result = self.update_one(ReadYourWrites(update))?;
+        let output = self.find_one(ReadYourWrites(query))?;
+        Ok((result, output))
+    }
+
+    /// Upserts a single document, then runs the query, which is guaranteed
+    /// to observe the upsert. See the [`consistency`](../consistency/index.html)
+    /// module for the exact guarantees.
+    pub fn upsert_then_find_one<U, Q>(&self, upsert: U, query: Q)
+        -> Result<(UpsertOneResult<Uid<T>>, Option<Q::Output>)>
+        where U: Upsert<T>,
+              Q: Query<T>,
+    {
+        let result = self.upsert_one(ReadYourWrites(upsert))?;
+        let output = self.find_one(ReadYourWrites(query))?;
+        Ok((result, output))
+    }
+
+    /// Upserts a single document and returns it as it is after the upsert,
+    /// along with whether it was inserted or an existing one was updated,
+    /// using a single `findAndModify` command.
+    ///
+    /// This method only works with update operators (with field names starting
+    /// with `$`), i.e. it does **not** replace entire documents.
+    pub fn upsert_and_fetch<U: Upsert<T>>(&self, upsert: U) -> Result<Upserted<T>> {
+        let filter = live::<T>(renamed::<T>(upsert.filter()));
+        let message = || format!("error in {}::upsert_and_fetch({:#?})", T::NAME, upsert);
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
+
+        let mut command = doc!{
+            "findAndModify": self.inner.name(),
+            "query": filter,
+            "update": update_document::<T>(upsert.upsert(), true)?,
+            "upsert": true,
+            "new": true,
+            "writeConcern": upsert.options().to_bson(),
+        };
+        let array_filters = renamed_array_filters::<T>(upsert.array_filters());
+        let command_options = upsert.command_options();
+
+        if !array_filters.is_empty() {
+            command.insert("arrayFilters", array_filters);
+        }
+
+        command_options.apply_to_statement(&mut command);
+        command_options.apply_to_command(&mut command);
+
+        let mut reply = self.inner
+            .db
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        let updated = reply
+            .get_document("lastErrorObject")
+            .and_then(|info| info.get_bool("updatedExisting"))
+            .chain(&message)?;
+        let document = reply
+            .remove("value")
+            .ok_or_else(|| Error::new(MissingDocumentField, "no `value` in reply"))
+            .and_then(Bson::try_into_doc)
+            .chain(&message)?;
+        let entity = T::SERDE_PROFILE.deserialize(loaded::<T>(document)?.into()).chain(&message)?;
+
+        Ok(if updated {
+            Upserted::Updated(entity)
+        } else {
+            Upserted::Inserted(entity)
+        })
+    }
+
     /// Updates multiple documents.
     ///
     /// This method only works with update operators (with field names starting
     /// with `$`), i.e. it does **not** replace entire documents.
     pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
-        let filter = update.filter();
-        let change = update.update();
+        let filter = live::<T>(renamed::<T>(update.filter()));
+        let change = update_document::<T>(update.update(), false)?;
         let options = UpdateOptions {
             upsert: Some(false),
             write_concern: update.options().into(),
         };
+        let array_filters = renamed_array_filters::<T>(update.array_filters());
+        let command_options = update.command_options();
         let message = || format!("error in {}::update_many({:#?})", T::NAME, update);
-        self.update_many_internal(filter, change, options, &message)
+        self.update_many_internal(filter, change, options, &array_filters, &command_options, &message)
     }
 
     /// Upserts multiple documents (updates many or inserts one if none found).
@@ -334,14 +2058,16 @@ impl<T: Doc> Collection<T> {
     /// This method only works with update operators (with field names starting
     /// with `$`), i.e. it does **not** replace entire documents.
     pub fn upsert_many<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertManyResult> {
-        let filter = upsert.filter();
-        let change = upsert.upsert();
+        let filter = live::<T>(renamed::<T>(upsert.filter()));
+        let change = update_document::<T>(upsert.upsert(), true)?;
         let options = UpdateOptions {
             upsert: Some(true),
             write_concern: upsert.options().into(),
         };
+        let array_filters = renamed_array_filters::<T>(upsert.array_filters());
+        let command_options = upsert.command_options();
         let message = || format!("error in {}::upsert_many({:#?})", T::NAME, upsert);
-        self.update_many_internal(filter, change, options, &message)
+        self.update_many_internal(filter, change, options, &array_filters, &command_options, &message)
     }
 
     /// Updates or upserts multiple documents.
@@ -353,14 +2079,23 @@ impl<T: Doc> Collection<T> {
         filter: Document,
         change: Document,
         options: UpdateOptions,
+        array_filters: &ArrayFilters,
+        command_options: &options::Extra,
         message: F,
     ) -> Result<UpdateManyResult> {
-        self.inner
-            .update_many(filter, change, options.into())
-            .chain(message)
+        check_targeted::<T>(&filter, false).chain(message)?;
+
+        let raw_result = if array_filters.is_empty() && command_options.is_empty() {
+            self.inner.update_many(filter, change, options.into()).chain(message)
+        } else {
+            self.update_command(filter, change, options, true, array_filters, command_options)
+                .chain(message)
+        };
+
+        raw_result
             .and_then(|result| {
                 if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
+                    Err(write_error::<T>(message(), error))
                 } else {
                     let num_matched = int_to_usize_with_msg(result.matched_count, "# of matched documents")?;
                     let num_modified = int_to_usize_with_msg(result.modified_count, "# of modified documents")?;
@@ -401,67 +2136,391 @@ impl<T: Doc> Collection<T> {
             })
             .collect::<Result<_>>()?;
 
-        let criterion = doc!{
-            "_id": {
-                "$in": ids
-            }
+        let criterion = doc!{
+            "_id": {
+                "$in": ids
+            }
+        };
+
+        self.delete_many(criterion).chain(
+            || format!("error in {}::delete_entities(...)", T::NAME)
+        )
+    }
+
+    /// Deletes one document. Returns `true` if one was found and deleted.
+    ///
+    /// If `T` is soft-deleted, the document is marked as deleted instead;
+    /// see `Doc::deleted_at_field()`.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        let message = || format!("error in {}::delete_one({:#?})", T::NAME, query);
+        let filter = live::<T>(deletion_filter::<T>(query.filter())?);
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
+
+        let command_options = query.command_options();
+
+        if let Some(field) = T::deleted_at_field() {
+            let change = doc!{ "$currentDate": { field: true } };
+            let options = UpdateOptions {
+                upsert: Some(false),
+                write_concern: query.options().into(),
+            };
+            let array_filters = ArrayFilters::default();
+
+            return self
+                .update_one_internal(filter, change, options, &array_filters, &command_options, &message)
+                .map(|result| result.matched_count > 0);
+        }
+
+        if !command_options.is_empty() {
+            return self.delete_command(filter, 1, query.options(), &command_options)
+                .chain(&message)
+                .map(|num_deleted| num_deleted > 0);
+        }
+
+        self.inner
+            .delete_one(filter, query.options().into())
+            .chain(&message)
+            .and_then(|result| {
+                if let Some(error) = result.write_exception {
+                    Err(write_error::<T>(message(), error))
+                } else {
+                    Ok(result.deleted_count > 0)
+                }
+            })
+    }
+
+    /// Deletes many documents. Returns the number of deleted documents.
+    ///
+    /// If `T` is soft-deleted, the documents are marked as deleted instead;
+    /// see `Doc::deleted_at_field()`.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::delete_many({:#?})", T::NAME, query);
+        let filter = live::<T>(deletion_filter::<T>(query.filter())?);
+
+        check_targeted::<T>(&filter, false).chain(&message)?;
+
+        match T::deleted_at_field() {
+            Some(field) => self.set_deleted(filter, doc!{ "$currentDate": { field: true } }, &query, &message),
+            None => self.delete_many_internal(filter, &query, &message),
+        }
+    }
+
+    /// Permanently removes the documents matching the query, whether or not
+    /// they have been soft-deleted. Returns the number of removed documents.
+    /// For types which aren't soft-deleted, this is the same as `delete_many()`.
+    pub fn purge<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::purge({:#?})", T::NAME, query);
+        let filter = deletion_filter::<T>(query.filter())?;
+
+        check_targeted::<T>(&filter, false).chain(&message)?;
+
+        self.delete_many_internal(filter, &query, &message)
+    }
+
+    /// Retrieves the soft-deleted documents satisfying the query. Returns a
+    /// `MissingDocumentField` error if `T` isn't soft-deleted.
+    pub fn find_deleted<Q>(&self, query: Q) -> Result<Cursor<Q::Output>>
+        where Q: Query<T>,
+              Q::Output: cursor::Item,
+    {
+        let field = deleted_at_field::<T>()?;
+        let mut filter = renamed::<T>(query.filter());
+
+        filter.insert(field, doc!{ "$ne": Bson::Null });
+
+        self.find_many_internal(filter, &query)
+            .chain(|| format!("error in {}::find_deleted({:#?})", T::NAME, query))
+    }
+
+    /// Brings the soft-deleted documents matching the query back to life.
+    /// Returns the number of restored documents, or a `MissingDocumentField`
+    /// error if `T` isn't soft-deleted.
+    pub fn undelete<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::undelete({:#?})", T::NAME, query);
+        let field = deleted_at_field::<T>()?;
+        let mut filter = renamed::<T>(query.filter());
+
+        filter.insert(field, doc!{ "$ne": Bson::Null });
+        check_targeted::<T>(&filter, false).chain(&message)?;
+
+        self.set_deleted(filter, doc!{ "$unset": { field: "" } }, &query, &message)
+    }
+
+    /// Sets or clears the soft-deletion mark of the documents matching the
+    /// (already renamed) filter. Returns the number of matched documents.
+    fn set_deleted<Q, F>(&self, filter: Document, change: Document, query: &Q, message: F)
+        -> Result<usize>
+        where Q: Delete<T>,
+              F: Copy + FnOnce() -> String,
+    {
+        let options = UpdateOptions {
+            upsert: Some(false),
+            write_concern: query.options().into(),
+        };
+        let array_filters = ArrayFilters::default();
+
+        self.update_many_internal(filter, change, options, &array_filters, &query.command_options(), message)
+            .map(|result| result.num_matched)
+    }
+
+    /// Removes the documents matching the (already renamed) filter.
+    fn delete_many_internal<Q, F>(&self, filter: Document, query: &Q, message: F) -> Result<usize>
+        where Q: Delete<T>,
+              F: Copy + FnOnce() -> String,
+    {
+        let command_options = query.command_options();
+
+        if !command_options.is_empty() {
+            return self.delete_command(filter, 0, query.options(), &command_options).chain(message);
+        }
+
+        self.inner
+            .delete_many(filter, query.options().into())
+            .chain(message)
+            .and_then(|result| {
+                if let Some(error) = result.write_exception {
+                    Err(write_error::<T>(message(), error))
+                } else {
+                    int_to_usize_with_msg(result.deleted_count, "# of deleted documents")
+                }
+            })
+    }
+
+    /// Runs a `delete` command with command options, which the driver can't
+    /// send. Returns the number of deleted documents; write errors are
+    /// returned as an `Err`.
+    fn delete_command(
+        &self,
+        filter: Document,
+        limit: i32,
+        write_concern: WriteConcern,
+        command_options: &options::Extra,
+    ) -> Result<usize> {
+        let mut statement = doc!{ "q": filter, "limit": limit };
+
+        command_options.apply_to_statement(&mut statement);
+
+        let mut command = doc!{
+            "delete": self.inner.name(),
+            "deletes": [statement],
+            "writeConcern": write_concern.to_bson(),
+        };
+
+        command_options.apply_to_command(&mut command);
+
+        let reply = self.inner.db.command(command, CommandType::Suppressed, None)?;
+
+        match command_write_error::<T>(&reply, "delete") {
+            Some(error) => Err(error),
+            None => int_to_usize_with_msg(reply.get_i32("n").unwrap_or_default(), "# of deleted documents"),
+        }
+    }
+
+    /// Inserts a single document as part of the transaction. If the
+    /// document has no `_id`, one is generated by `Doc::generate_id()`, or
+    /// failing that, a new `ObjectId` is generated for it.
+    pub fn insert_one_in(&self, txn: &Transaction, entity: &T) -> Result<Uid<T>> {
+        let mut doc = serialize_entity(entity)?;
+        generate_id::<T>(&mut doc)?;
+        stamp_inserted::<T>(&mut doc);
+        check_entity_document::<T>(&doc)?;
+
+        if !doc.contains_key("_id") {
+            doc.insert("_id", ObjectId::new()?);
+        }
+
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        let command = doc!{
+            "insert": self.inner.name(),
+            "documents": [doc],
+        };
+
+        self.write_in(txn, command)
+            .chain(|| format!("error in {}::insert_one_in()", T::NAME))?;
+
+        from_bson(id).chain(|| format!("can't deserialize ID for {}", T::NAME))
+    }
+
+    /// Updates a single document as part of the transaction.
+    pub fn update_one_in<U: Update<T>>(&self, txn: &Transaction, update: U) -> Result<UpdateOneResult> {
+        let message = || format!("error in {}::update_one_in({:#?})", T::NAME, update);
+        let filter = live::<T>(renamed::<T>(update.filter()));
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
+
+        self.update_in(txn, filter, &update, false)
+            .chain(&message)
+            .and_then(UpdateOneResult::from_raw)
+    }
+
+    /// Updates multiple documents as part of the transaction.
+    pub fn update_many_in<U: Update<T>>(&self, txn: &Transaction, update: U) -> Result<UpdateManyResult> {
+        let message = || format!("error in {}::update_many_in({:#?})", T::NAME, update);
+        let filter = live::<T>(renamed::<T>(update.filter()));
+
+        check_targeted::<T>(&filter, false).chain(&message)?;
+
+        let result = self.update_in(txn, filter, &update, true).chain(&message)?;
+        let num_matched = int_to_usize_with_msg(result.matched_count, "# of matched documents")?;
+        let num_modified = int_to_usize_with_msg(result.modified_count, "# of modified documents")?;
+
+        Ok(UpdateManyResult { num_matched, num_modified })
+    }
+
+    /// Deletes a single document as part of the transaction. Returns `true`
+    /// if a document was deleted.
+    ///
+    /// If `T` is soft-deleted, the document is marked as deleted instead;
+    /// see `Doc::deleted_at_field()`.
+    pub fn delete_one_in<Q: Delete<T>>(&self, txn: &Transaction, query: Q) -> Result<bool> {
+        let message = || format!("error in {}::delete_one_in({:#?})", T::NAME, query);
+        let filter = live::<T>(deletion_filter::<T>(query.filter())?);
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
+
+        self.delete_in(txn, filter, 1, &query.command_options())
+            .chain(&message)
+            .map(|num_deleted| num_deleted > 0)
+    }
+
+    /// Deletes many documents as part of the transaction. Returns the number
+    /// of deleted documents.
+    ///
+    /// If `T` is soft-deleted, the documents are marked as deleted instead;
+    /// see `Doc::deleted_at_field()`.
+    pub fn delete_many_in<Q: Delete<T>>(&self, txn: &Transaction, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::delete_many_in({:#?})", T::NAME, query);
+        let filter = live::<T>(deletion_filter::<T>(query.filter())?);
+
+        check_targeted::<T>(&filter, false).chain(&message)?;
+
+        self.delete_in(txn, filter, 0, &query.command_options()).chain(&message)
+    }
+
+    /// Runs an `update` command with a single statement in the transaction.
+    fn update_in<U: Update<T>>(&self, txn: &Transaction, filter: Document, update: &U, multi: bool)
+        -> Result<UpdateResult>
+    {
+        let mut statement = doc!{
+            "q": filter,
+            "u": update_document::<T>(update.update(), false)?,
+            "upsert": false,
+            "multi": multi,
+        };
+        let array_filters = renamed_array_filters::<T>(update.array_filters());
+
+        let command_options = update.command_options();
+
+        if !array_filters.is_empty() {
+            statement.insert("arrayFilters", array_filters);
+        }
+
+        command_options.apply_to_statement(&mut statement);
+
+        let mut command = doc!{
+            "update": self.inner.name(),
+            "updates": [statement],
         };
 
-        self.delete_many(criterion).chain(
-            || format!("error in {}::delete_entities(...)", T::NAME)
-        )
+        command_options.apply_to_command(&mut command);
+
+        self.write_in(txn, command).map(|reply| UpdateResult::new(reply, None))
     }
 
-    /// Deletes one document. Returns `true` if one was found and deleted.
-    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
-        let message = || format!("error in {}::delete_one({:#?})", T::NAME, query);
-        self.inner
-            .delete_one(query.filter(), query.options().into())
-            .chain(&message)
-            .and_then(|result| {
-                if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
-                } else {
-                    Ok(result.deleted_count > 0)
+    /// Runs a `delete` command with a single statement in the transaction,
+    /// returning the number of deleted documents. If `T` is soft-deleted, an
+    /// `update` command setting the deletion mark is run instead, returning
+    /// the number of matched documents.
+    fn delete_in(&self, txn: &Transaction, filter: Document, limit: i32, command_options: &options::Extra)
+        -> Result<usize>
+    {
+        let mut command = match T::deleted_at_field() {
+            Some(field) => {
+                let mut statement = doc!{
+                    "q": filter,
+                    "u": { "$currentDate": { field: true } },
+                    "upsert": false,
+                    "multi": limit != 1,
+                };
+                command_options.apply_to_statement(&mut statement);
+                doc!{
+                    "update": self.inner.name(),
+                    "updates": [statement],
                 }
-            })
+            }
+            None => {
+                let mut statement = doc!{ "q": filter, "limit": limit };
+                command_options.apply_to_statement(&mut statement);
+                doc!{
+                    "delete": self.inner.name(),
+                    "deletes": [statement],
+                }
+            }
+        };
+
+        command_options.apply_to_command(&mut command);
+
+        let reply = self.write_in(txn, command)?;
+
+        int_to_usize_with_msg(reply.get_i32("n").unwrap_or_default(), "# of deleted documents")
     }
 
-    /// Deletes many documents. Returns the number of deleted documents.
-    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
-        let message = || format!("error in {}::delete_many({:#?})", T::NAME, query);
-        self.inner
-            .delete_many(query.filter(), query.options().into())
-            .chain(&message)
-            .and_then(|result| {
-                if let Some(error) = result.write_exception {
-                    Err(Error::with_cause(message(), error))
-                } else {
-                    int_to_usize_with_msg(result.deleted_count, "# of deleted documents")
-                }
-            })
+    /// Runs a write command in the transaction. Write errors are returned
+    /// as an `Err`.
+    fn write_in(&self, txn: &Transaction, mut command: Document) -> Result<Document> {
+        txn.attach_command(&mut command);
+
+        let reply = self.inner.db.command(command, CommandType::Suppressed, None)?;
+
+        match command_write_error::<T>(&reply, "transactional write") {
+            Some(error) => Err(error),
+            None => Ok(reply),
+        }
     }
 
     /// Deletes a single document based on the query criteria,
     /// returning it if it was found.
+    ///
+    /// If `T` is soft-deleted, the document is marked as deleted instead,
+    /// and it is returned as it was before; see `Doc::deleted_at_field()`.
     pub fn find_one_and_delete<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
-        let query_options = query.options();
-        let find_delete_options = FindOneAndDeleteOptions {
-            max_time_ms: query_options.max_time_ms,
-            projection: query_options.projection,
-            sort: query_options.sort,
-            write_concern: None, // TODO(H2CO3): do something intelligent here
+        let query_options = query_options::<T, Q>(&query).with_default_max_time();
+        let filter = live::<T>(deletion_filter::<T>(query.filter())?);
+        let message = || format!("error in {}::find_one_and_delete({:#?})", T::NAME, query);
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
+
+        let result = match T::deleted_at_field() {
+            Some(field) => {
+                let find_update_options = FindOneAndUpdateOptions {
+                    return_document: Some(ReturnDocument::Before),
+                    max_time_ms: query_options.max_time_ms,
+                    projection: query_options.projection,
+                    sort: query_options.sort,
+                    upsert: Some(false),
+                    ..Default::default()
+                };
+                let change = doc!{ "$currentDate": { field: true } };
+                self.inner.find_one_and_update(filter, change, find_update_options.into())
+            }
+            None => {
+                let find_delete_options = FindOneAndDeleteOptions {
+                    max_time_ms: query_options.max_time_ms,
+                    projection: query_options.projection,
+                    sort: query_options.sort,
+                    write_concern: None, // TODO(H2CO3): do something intelligent here
+                };
+                self.inner.find_one_and_delete(filter, find_delete_options.into())
+            }
         };
 
-        self.inner
-            .find_one_and_delete(query.filter(), find_delete_options.into())
-            .chain(|| format!(
-                "error in {}::find_one_and_delete({:#?})", T::NAME, query
-            ))
+        result
+            .chain(&message)
             .and_then(|opt| match opt {
                 Some(document) => {
-                    let transformed = Q::transform(document)?;
-                    from_bson(transformed).map_err(From::from)
+                    let transformed = strict_transform::<T, Q>(document)?;
+                    T::SERDE_PROFILE.deserialize(transformed)
                 }
                 None => Ok(None)
             })
@@ -470,33 +2529,73 @@ impl<T: Doc> Collection<T> {
     /// Replaces a single document based on the query criteria.
     /// Returns the original document if found.
     ///
-    /// This method does **not** provide an option for returning the updated
-    /// document, since it already **requires** the presence of a replacement.
+    /// See `find_one_and_replace_returning()` for returning the replaced
+    /// document instead, e.g. in order to obtain its projected fields.
     pub fn find_one_and_replace<Q: Query<T>>(&self, query: Q, replacement: &T) -> Result<Option<Q::Output>>
         where T: Debug
     {
-        let query_options = query.options();
+        self.find_one_and_replace_returning(query, replacement, ReturnDocument::Before, false)
+    }
+
+    /// Replaces a single document based on the query criteria, or inserts
+    /// the replacement if none is found and `upsert` is `true`. Returns the
+    /// original or the replaced document, depending on `return_document`.
+    /// The sort order of the query decides which document is replaced if
+    /// several of them match.
+    pub fn find_one_and_replace_returning<Q: Query<T>>(
+        &self,
+        query: Q,
+        replacement: &T,
+        return_document: ReturnDocument,
+        upsert: bool,
+    ) -> Result<Option<Q::Output>>
+        where T: Debug
+    {
+        let message = || format!(
+            "error in {}::find_one_and_replace_returning({:#?}, {:#?})",
+            T::NAME, query, replacement
+        );
+        let doc = serialize_entity(replacement)?;
+
+        self.find_one_and_replace_document(&query, doc, return_document, upsert, message)
+    }
+
+    /// Helper for `find_one_and_replace_returning()` and
+    /// `ScopedCollection::find_one_and_replace()`, which replaces a document
+    /// with an already serialized entity.
+    pub(crate) fn find_one_and_replace_document<Q, F>(
+        &self,
+        query: &Q,
+        mut doc: Document,
+        return_document: ReturnDocument,
+        upsert: bool,
+        message: F,
+    ) -> Result<Option<Q::Output>>
+        where Q: Query<T>,
+              F: Fn() -> String,
+    {
+        let query_options = query_options::<T, Q>(query).with_default_max_time();
         let find_replace_options = FindOneAndUpdateOptions {
-            return_document: Some(ReturnDocument::Before),
+            return_document: Some(return_document),
             max_time_ms: query_options.max_time_ms,
             projection: query_options.projection,
             sort: query_options.sort,
-            upsert: Some(false),
+            upsert: Some(upsert),
             ..Default::default()
         };
-        let filter = query.filter();
-        let doc = serialize_document(replacement)?;
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        stamp_replaced::<T>(&mut doc);
+        check_entity_document::<T>(&doc)?;
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
 
         self.inner
             .find_one_and_replace(filter, doc, find_replace_options.into())
-            .chain(|| format!(
-                "error in {}::find_one_and_replace({:#?}, {:#?})",
-                T::NAME, query, replacement
-            ))
+            .chain(&message)
             .and_then(|opt| match opt {
                 Some(document) => {
-                    let transformed = Q::transform(document)?;
-                    from_bson(transformed).map_err(From::from)
+                    let transformed = strict_transform::<T, Q>(document)?;
+                    T::SERDE_PROFILE.deserialize(transformed)
                 }
                 None => Ok(None)
             })
@@ -508,19 +2607,32 @@ impl<T: Doc> Collection<T> {
     /// separate update and upsert functions.** The options returned by the
     /// `update` argument decide whether an update or an upsert happens.
     pub fn find_one_and_update<U: FindAndUpdate<T>>(&self, update: U) -> Result<Option<U::Output>> {
-        let filter = update.filter();
-        let change = update.update();
-        let options = update.options();
+        let filter = live::<T>(renamed::<T>(update.filter()));
+        let mut options = update.options().with_default_max_time();
+        let change = update_document::<T>(update.update(), options.upsert.unwrap_or(false))?;
+        let projection = update.projection();
+        let sort = update.sort();
+
+        if !projection.is_empty() {
+            options.projection = Some(projection.into());
+        }
+        if !sort.is_empty() {
+            options.sort = Some(sort.into());
+        }
+
+        options.sort = options.sort.map(renamed::<T>);
+        options.projection = options.projection.map(renamed::<T>);
+        let message = || format!("error in {}::find_one_and_update({:#?})", T::NAME, update);
+
+        check_targeted::<T>(&filter, true).chain(&message)?;
 
         self.inner
             .find_one_and_update(filter, change, options.into())
-            .chain(|| format!(
-                "error in {}::find_one_and_update({:#?})", T::NAME, update
-            ))
+            .chain(&message)
             .and_then(|opt| match opt {
                 Some(document) => {
-                    let transformed = U::transform(document)?;
-                    from_bson(transformed).map_err(From::from)
+                    let transformed = loaded::<T>(document).and_then(U::transform)?;
+                    T::SERDE_PROFILE.deserialize(transformed)
                 }
                 None => Ok(None)
             })
@@ -554,7 +2666,7 @@ pub struct UpdateOneResult {
 
 impl UpdateOneResult {
     /// Converts a MongoDB `UpdateResult` to an Avocado `UpdateOneResult`.
-    fn from_raw(result: UpdateResult) -> Result<Self> {
+    pub(crate) fn from_raw(result: UpdateResult) -> Result<Self> {
         if let Some(error) = result.write_exception {
             Err(Error::with_cause("couldn't perform single update", error))
         } else {
@@ -566,6 +2678,622 @@ impl UpdateOneResult {
     }
 }
 
+/// The outcome of a `load_many()` operation.
+pub struct LoadedMany<T: Doc> {
+    /// The documents found, in the order of the requested IDs.
+    pub found: Vec<T>,
+    /// The requested IDs without a matching document, in the order requested.
+    pub missing: Vec<Uid<T>>,
+}
+
+impl<T: Doc> LoadedMany<T> {
+    /// Returns `true` if a document was found for every requested ID.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Returns the found documents if none are missing, otherwise a
+    /// `MissingId` error listing the missing IDs.
+    pub fn into_complete(self) -> Result<Vec<T>> where T::Id: Debug {
+        if self.missing.is_empty() {
+            Ok(self.found)
+        } else {
+            Err(Error::new(
+                MissingId,
+                format!("no {} found with _id {:?}", T::NAME, self.missing)
+            ))
+        }
+    }
+}
+
+impl<T: Doc + Debug> Debug for LoadedMany<T> where T::Id: Debug {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("LoadedMany")
+            .field("found", &self.found)
+            .field("missing", &self.missing)
+            .finish()
+    }
+}
+
+/// The outcome of a successful `insert_one_idempotent()` operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IdempotentInsertResult<Id> {
+    /// The ID of the inserted document, or that of the document previously
+    /// inserted with the same idempotency key.
+    pub id: Id,
+    /// Whether a document with the same idempotency key already existed,
+    /// i.e. the insert had already been applied by an earlier attempt.
+    pub already_applied: bool,
+}
+
+/// Options for `Collection::copy_to()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyOptions {
+    /// Only documents matching this filter are copied. Default: all documents.
+    pub filter: Document,
+    /// The number of documents read and inserted at once. Default: 1000.
+    pub batch_size: usize,
+    /// Whether to create the indexes given by `T::indexes()` on the target
+    /// collection before copying. Default: `true`.
+    pub create_indexes: bool,
+    /// The name of the target collection. Default: `T::NAME`.
+    pub target_name: Option<String>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            filter: Document::new(),
+            batch_size: 1000,
+            create_indexes: true,
+            target_name: None,
+        }
+    }
+}
+
+/// The progress of a `Collection::copy_to()` operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CopyProgress {
+    /// The number of documents copied so far.
+    pub documents: usize,
+    /// The number of batches copied so far.
+    pub batches: usize,
+}
+
+/// The maximal number of documents inserted by a single `insert` command.
+pub const MAX_INSERT_BATCH_LEN: usize = 100_000;
+
+/// The progress of a `Collection::insert_stream()` operation.
+#[derive(Debug, Default)]
+pub struct InsertStreamProgress {
+    /// The number of documents inserted so far.
+    pub documents: usize,
+    /// The number of batches sent so far.
+    pub batches: usize,
+    /// The errors encountered so far, along with the index of the batch
+    /// they occurred in.
+    pub errors: Vec<(usize, Error)>,
+}
+
+/// How `Collection::restore()` treats documents whose `_id` already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RestorePolicy {
+    /// Insert every document; fail if any `_id` already exists.
+    Insert,
+    /// Replace existing documents with the same `_id`, insert the rest.
+    Replace,
+}
+
+/// The outcome of a successful `Collection::restore()` operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RestoreResult {
+    /// The number of newly inserted documents.
+    pub inserted: usize,
+    /// The number of existing documents that were replaced.
+    pub replaced: usize,
+}
+
+/// An index as it exists on the server, returned by
+/// `Collection::list_indexes_typed()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingIndex {
+    /// The keys and the options of the index.
+    pub model: IndexModel,
+    /// The filter of a partial index.
+    pub partial_filter_expression: Option<Document>,
+    /// The collation of the index, if it differs from the simple one.
+    pub collation: Option<Document>,
+}
+
+impl ExistingIndex {
+    /// Converts an index specification document, as returned by the
+    /// `listIndexes` command, to an `ExistingIndex`.
+    #[allow(clippy::cast_precision_loss)]
+    fn from_document(mut spec: Document) -> Result<Self> {
+        let keys = spec
+            .remove("key")
+            .ok_or_else(|| Error::new(MissingDocumentField, "index without `key`"))
+            .and_then(Bson::try_into_doc)?
+            .into_iter()
+            .map(|(field, order)| (field, normalize_int(order)))
+            .collect();
+
+        let int = |key| spec.get(key).cloned().map(normalize_int).and_then(|value| match value {
+            Bson::I32(n) => Some(n),
+            _ => None,
+        });
+        let float = |key| match spec.get(key) {
+            Some(&Bson::FloatingPoint(x)) => Some(x),
+            Some(&Bson::I32(n)) => Some(f64::from(n)),
+            Some(&Bson::I64(n)) => Some(n as f64),
+            _ => None,
+        };
+        let options = IndexOptions {
+            name: spec.get_str("name").ok().map(String::from),
+            unique: spec.get_bool("unique").ok(),
+            sparse: spec.get_bool("sparse").ok(),
+            background: spec.get_bool("background").ok(),
+            expire_after_seconds: int("expireAfterSeconds"),
+            version: int("v"),
+            default_language: spec.get_str("default_language").ok().map(String::from),
+            language_override: spec.get_str("language_override").ok().map(String::from),
+            text_version: int("textIndexVersion"),
+            weights: spec.get_document("weights").ok().cloned(),
+            sphere_version: int("2dsphereIndexVersion"),
+            bits: int("bits"),
+            min: float("min"),
+            max: float("max"),
+            bucket_size: int("bucketSize"),
+            ..IndexOptions::default()
+        };
+
+        Ok(ExistingIndex {
+            model: IndexModel { keys, options },
+            partial_filter_expression: spec.get_document("partialFilterExpression").ok().cloned(),
+            collation: spec.get_document("collation").ok().cloned(),
+        })
+    }
+}
+
+/// The outcome of a `Collection::validate()` operation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationResult {
+    /// The full name (`database.collection`) of the validated collection.
+    pub ns: String,
+    /// Whether the collection is free of corruption.
+    pub valid: bool,
+    /// The number of documents in the collection.
+    #[serde(rename = "nrecords")]
+    pub num_records: i64,
+    /// The number of documents not conforming to the validation rules of
+    /// the collection.
+    #[serde(default, rename = "nInvalidDocuments")]
+    pub num_invalid_documents: i64,
+    /// The number of indexes of the collection.
+    #[serde(default, rename = "nIndexes")]
+    pub num_indexes: i64,
+    /// The number of keys of each index, by index name.
+    #[serde(default)]
+    pub keys_per_index: BTreeMap<String, i64>,
+    /// The record IDs of corrupt documents, if the server reports them.
+    #[serde(default)]
+    pub corrupt_records: Vec<Bson>,
+    /// The problems found, if any.
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /// Issues which aren't errors, e.g. an incomplete (non-`full`) check.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// A single group of the result of `Collection::count_by()`.
+#[derive(Debug, Clone, Deserialize)]
+struct GroupCount<K> {
+    /// The value of the grouping key.
+    #[serde(rename = "_id")]
+    key: K,
+    /// The number of documents in the group.
+    count: i64,
+}
+
+/// Rejects documents with fields unknown to `T` if it opted into strict
+/// mode via `Doc::strict_fields()`. The idempotency key field, which is
+/// managed by the collection itself, is always allowed.
+fn check_strict_fields<T: Doc>(doc: &Document) -> Result<()> {
+    match T::strict_fields() {
+        Some(fields) => {
+            let mut known = fields.to_vec();
+            known.push(IDEMPOTENCY_KEY_FIELD);
+            check_known_fields(doc, &known, T::NAME)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Applies the strict mode check and the `after_load` hook of `T` to a raw
+/// document read from the collection, then the transformation of the query.
+pub(crate) fn strict_transform<T: Doc, Q: Query<T>>(doc: Document) -> Result<Bson> {
+    loaded::<T>(doc).and_then(Q::transform)
+}
+
+/// Applies the strict mode check of `T` to a raw document read from the
+/// collection, decrypts its encrypted fields, then runs its `after_load`
+/// hook; see `Doc::after_load()`.
+pub(crate) fn loaded<T: Doc>(mut doc: Document) -> Result<Document> {
+    check_strict_fields::<T>(&doc)?;
+    decrypt_fields::<T>(&mut doc)?;
+    T::after_load(&mut doc)?;
+    Ok(doc)
+}
+
+/// Serializes an entity about to be inserted or replaced, runs its
+/// `before_insert` hook, then encrypts its encrypted fields.
+pub(crate) fn serialize_entity<T: Doc>(entity: &T) -> Result<Document> {
+    prepare_entity_document(entity, T::SERDE_PROFILE.serialize(entity)?)
+}
+
+/// Runs the `before_insert` hook of an entity on its serialized form `doc`,
+/// then encrypts its encrypted fields.
+pub(crate) fn prepare_entity_document<T: Doc>(entity: &T, mut doc: Document) -> Result<Document> {
+    entity.before_insert(&mut doc)?;
+    encrypt_fields::<T>(&mut doc)?;
+    Ok(doc)
+}
+
+/// Performs the client-side checks of a serialized entity before writing it:
+/// its size must not exceed `T::bson_size_limit()`, and if
+/// `T::check_field_names()`, all of its field names must be valid.
+pub(crate) fn check_entity_document<T: Doc>(doc: &Document) -> Result<()> {
+    check_document_size(doc, T::bson_size_limit(), T::NAME)?;
+
+    if T::check_field_names() {
+        check_field_names(doc, T::NAME)?;
+    }
+
+    Ok(())
+}
+
+/// Runs the `before_update` hook of `T` on an update document, translates
+/// its field names, encrypts the values it sets in encrypted fields, then
+/// sets its timestamp fields.
+pub(crate) fn update_document<T: Doc>(mut change: Document, upsert: bool) -> Result<Document> {
+    T::before_update(&mut change)?;
+
+    let mut stored = renamed::<T>(change);
+    encrypt_update::<T>(&mut stored)?;
+
+    Ok(stamp_update::<T>(stored, upsert))
+}
+
+/// Runs the `before_delete` hook of `T` on the filter of a deletion, then
+/// translates its field names.
+pub(crate) fn deletion_filter<T: Doc>(filter: Document) -> Result<Document> {
+    T::before_delete(&filter)?;
+    Ok(renamed::<T>(filter))
+}
+
+/// Converts a failed write to an error. Duplicate key errors on a unique
+/// index declared by `T::indexes()` become `UniqueViolation` errors.
+fn write_error<T: Doc>(message: String, error: WriteException) -> Error {
+    let server_error = ServerInfo {
+        collection: Some(T::NAME.into()),
+        ..ServerInfo::from_write_exception(&error)
+    };
+    let violation = error.write_error.as_ref().and_then(
+        |e| unique_violation::<T>(&message, e.code, &e.message)
+    );
+
+    violation
+        .unwrap_or_else(|| Error::with_cause(message, error))
+        .with_context::<ServerInfo>(server_error)
+}
+
+/// Assigns a client-side generated ID to a document about to be inserted,
+/// unless it already has one; see `Doc::generate_id()`.
+pub(crate) fn generate_id<T: Doc>(doc: &mut Document) -> Result<()> {
+    match doc.get("_id") {
+        None | Some(&Bson::Null) => {}
+        Some(_) => return Ok(()),
+    }
+
+    if let Some(id) = T::generate_id() {
+        doc.insert("_id", bson::to_bson(&id)?);
+    }
+
+    Ok(())
+}
+
+/// Sets the timestamp fields of a document about to be inserted to the
+/// current time; see `Doc::created_at_field()` and `Doc::updated_at_field()`.
+pub(crate) fn stamp_inserted<T: Doc>(doc: &mut Document) {
+    let now = Bson::UtcDatetime(Utc::now());
+
+    for field in T::created_at_field().into_iter().chain(T::updated_at_field()) {
+        doc.insert(field, now.clone());
+    }
+}
+
+/// Sets the `updated_at` field of a replacement document to the current time.
+pub(crate) fn stamp_replaced<T: Doc>(doc: &mut Document) {
+    if let Some(field) = T::updated_at_field() {
+        doc.insert(field, Bson::UtcDatetime(Utc::now()));
+    }
+}
+
+/// Makes an update set the `updated_at` field to the current time of the
+/// server, and, if it is an upsert, the `created_at` field of an inserted
+/// document as well.
+fn stamp_update<T: Doc>(mut change: Document, upsert: bool) -> Document {
+    if let Some(field) = T::updated_at_field() {
+        let mut current_date = change.get_document("$currentDate").ok().cloned().unwrap_or_default();
+        current_date.insert(field, true);
+        change.insert("$currentDate", current_date);
+    }
+
+    if let (true, Some(field)) = (upsert, T::created_at_field()) {
+        let mut set_on_insert = change.get_document("$setOnInsert").ok().cloned().unwrap_or_default();
+        set_on_insert.insert(field, Bson::UtcDatetime(Utc::now()));
+        change.insert("$setOnInsert", set_on_insert);
+    }
+
+    change
+}
+
+/// Returns the name of the version field of `T`, or an error if `T` isn't
+/// versioned.
+fn version_field<T: Doc>() -> Result<&'static str> {
+    T::version_field().ok_or_else(|| Error::new(
+        MissingDocumentField,
+        format!("{} has no version field", T::NAME)
+    ))
+}
+
+/// Restricts a filter to the documents which aren't soft-deleted, unless it
+/// already constrains the deletion mark itself; see `Doc::deleted_at_field()`.
+pub(crate) fn live<T: Doc>(mut filter: Document) -> Document {
+    if let Some(field) = T::deleted_at_field() {
+        if !filter.contains_key(field) {
+            filter.insert(field, Bson::Null);
+        }
+    }
+
+    filter
+}
+
+/// Restricts an aggregation pipeline to the documents which aren't
+/// soft-deleted, by prepending a `$match` stage if necessary, or by adding
+/// the condition to the first stage if that must remain the first.
+pub(crate) fn live_stages<T: Doc>(mut stages: Vec<Document>) -> Vec<Document> {
+    let field = match T::deleted_at_field() {
+        Some(field) => field,
+        None => return stages,
+    };
+
+    if let Some(stage) = stages.first_mut() {
+        // `$geoNear` and a `$match` with `$text` must be the first stage,
+        // so they are restricted to live documents themselves.
+        if let Some(&mut Bson::Document(ref mut geo_near)) = stage.get_mut("$geoNear") {
+            let mut query = match geo_near.remove("query") {
+                Some(Bson::Document(query)) => query,
+                _ => Document::new(),
+            };
+            and_not_deleted(&mut query, field);
+            geo_near.insert("query", query);
+            return stages;
+        }
+        if let Some(&mut Bson::Document(ref mut filter)) = stage.get_mut("$match") {
+            if filter.contains_key("$text") {
+                and_not_deleted(filter, field);
+                return stages;
+            }
+        }
+        // Change streams must be the first stage, and they report deletions.
+        if stage.contains_key("$changeStream") {
+            return stages;
+        }
+    }
+
+    stages.insert(0, doc!{ "$match": { field: Bson::Null } });
+    stages
+}
+
+/// Adds the condition that the deletion mark `field` is unset to the
+/// top-level `$and` of `filter`.
+fn and_not_deleted(filter: &mut Document, field: &str) {
+    let mut conditions = match filter.remove("$and") {
+        Some(Bson::Array(conditions)) => conditions,
+        Some(condition) => vec![condition],
+        None => Vec::new(),
+    };
+
+    conditions.push(Bson::from(doc!{ field: Bson::Null }));
+    filter.insert("$and", conditions);
+}
+
+/// Returns the name of the deletion mark of `T`, or an error if `T` isn't
+/// soft-deleted.
+fn deleted_at_field<T: Doc>() -> Result<&'static str> {
+    T::deleted_at_field().ok_or_else(|| Error::new(
+        MissingDocumentField,
+        format!("{} isn't soft-deleted", T::NAME)
+    ))
+}
+
+/// The error of a versioned write which found no document with `version`.
+fn stale_version<T: Doc>(version: i64) -> Error {
+    Error::new(
+        ErrorKind::StaleVersion,
+        format!("{} document was modified or deleted since version {}", T::NAME, version)
+    )
+}
+
+/// Converts a failed bulk write to an error. If any of the documents
+/// violated a unique index declared by `T::indexes()`, the result is a
+/// `UniqueViolation` error, naming the first such index.
+fn bulk_write_error<T: Doc>(message: String, error: BulkWriteException) -> Error {
+    let server_error = ServerInfo {
+        collection: Some(T::NAME.into()),
+        ..ServerInfo::from_bulk_write_exception(&error)
+    };
+    let violation = error.write_errors.iter().filter_map(
+        |e| unique_violation::<T>(&message, e.code, &e.message)
+    ).next();
+
+    violation
+        .unwrap_or_else(|| Error::with_cause(message, error))
+        .with_context::<ServerInfo>(server_error)
+}
+
+/// If a write error is a duplicate key error on one of the unique indexes
+/// of `T`, returns a `UniqueViolation` error naming the index.
+pub(crate) fn unique_violation<T: Doc>(message: &str, code: i32, server_message: &str) -> Option<Error> {
+    if code != DUPLICATE_KEY_ERROR_CODE {
+        return None;
+    }
+
+    // The server reports e.g. `E11000 duplicate key error collection:
+    // db.User index: email_unique dup key: { : "joe@example.com" }`.
+    let index = server_message.split(" index: ").nth(1)?.split_whitespace().next()?;
+    let constraint = T::indexes()
+        .into_iter()
+        .filter(|model| model.options.unique.unwrap_or(false))
+        .map(|model| {
+            let keys = &model.keys;
+            model.options.name.unwrap_or_else(|| default_index_name(keys))
+        })
+        .find(|name| name == index)?;
+
+    Some(Error::new(
+        ErrorKind::UniqueViolation,
+        format!("{}: unique constraint `{}` violated", message, constraint)
+    ).with_context::<UniqueViolation>(UniqueViolation { constraint }))
+}
+
+/// Converts the first of the `writeErrors` in the reply of a write command,
+/// if any, to an error. `operation` describes the write in the message.
+fn command_write_error<T: Doc>(reply: &Document, operation: &str) -> Option<Error> {
+    let server_error = ServerInfo::from_reply(reply)?.with_operation(T::NAME, operation);
+    let write_error = server_error.write_errors.first()?;
+    let message = format!("{} of {} failed", operation, T::NAME);
+    let error = unique_violation::<T>(&message, write_error.code, &write_error.message).unwrap_or_else(
+        || Error::new(MongoDbError, format!("{}: {}", message, write_error.message))
+    );
+
+    Some(error.with_context::<ServerInfo>(server_error))
+}
+
+/// Returns the name MongoDB generates for an index without an explicit
+/// name, e.g. `email_1_created_-1` for `{ email: 1, created: -1 }`.
+pub(crate) fn default_index_name(keys: &Document) -> String {
+    keys.iter()
+        .map(|(key, value)| match *value {
+            Bson::String(ref kind) => format!("{}_{}", key, kind),
+            ref order => format!("{}_{}", key, order),
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Builds a `find` command from a filter and the options and the command
+/// options of a query. The time limit of the command options takes
+/// precedence over that of the query options.
+pub(crate) fn find_command(
+    name: String,
+    filter: Document,
+    options: FindOptions,
+    command_options: &options::Extra,
+) -> Document {
+    let mut find = doc!{
+        "find": name,
+        "filter": filter,
+    };
+
+    if let Some(sort) = options.sort {
+        find.insert("sort", sort);
+    }
+    if let Some(projection) = options.projection {
+        find.insert("projection", projection);
+    }
+    if let Some(skip) = options.skip {
+        find.insert("skip", skip);
+    }
+    if let Some(limit) = options.limit {
+        find.insert("limit", limit);
+    }
+    if let Some(batch_size) = options.batch_size {
+        find.insert("batchSize", batch_size);
+    }
+    if let Some(max_time_ms) = command_options.max_time_ms.or(options.max_time_ms) {
+        find.insert("maxTimeMS", max_time_ms);
+    }
+
+    command_options.apply_to_statement(&mut find);
+
+    find
+}
+
+/// Renames the fields of a filter or update document according to
+/// `T::field_naming()`.
+pub(crate) fn renamed<T: Doc>(doc: Document) -> Document {
+    T::field_naming().rename_document(doc)
+}
+
+/// Renames the fields of array filters according to `T::field_naming()`.
+pub(crate) fn renamed_array_filters<T: Doc>(array_filters: ArrayFilters) -> ArrayFilters {
+    let filters: Vec<_> = array_filters.filters().iter().cloned().map(renamed::<T>).collect();
+    ArrayFilters::from(filters)
+}
+
+/// Renames the fields of the sort order and the projection of a query
+/// according to `T::field_naming()`.
+fn renamed_options<T: Doc>(options: FindOptions) -> FindOptions {
+    FindOptions {
+        sort: options.sort.map(renamed::<T>),
+        projection: options.projection.map(renamed::<T>),
+        ..options
+    }
+}
+
+/// Returns the options of a query, with the projection and the sort order
+/// of the query taking precedence over those of the options, and the fields
+/// renamed.
+pub(crate) fn query_options<T: Doc, Q: Query<T>>(query: &Q) -> FindOptions {
+    let mut options = query.options();
+    let projection = query.projection();
+    let sort = query.sort();
+
+    if !projection.is_empty() {
+        options.projection = Some(projection.into());
+    }
+    if !sort.is_empty() {
+        options.sort = Some(sort.into());
+    }
+
+    renamed_options::<T>(options)
+}
+
+/// Converts integral numbers which fit into an `i32` to `Bson::I32`, since
+/// the server may report e.g. index key orders as doubles or 64-bit ints.
+pub(crate) fn normalize_int(value: Bson) -> Bson {
+    let narrowed = match value {
+        Bson::I64(n) => i32::try_from(n).ok(),
+        Bson::FloatingPoint(x) if x.fract() == 0.0 => float_to_i64(x).and_then(|n| i32::try_from(n).ok()),
+        _ => None,
+    };
+
+    narrowed.map_or(value, Bson::I32)
+}
+
+/// Returns `true` if a write failed because the idempotency key of the
+/// document already exists in the collection.
+fn is_idempotency_conflict(error: &WriteException) -> bool {
+    error.write_error.as_ref().map_or(false, |write_error| {
+        write_error.code == DUPLICATE_KEY_ERROR_CODE
+            && write_error.message.contains(IDEMPOTENCY_INDEX_NAME)
+    })
+}
+
 /// The outcome of a successful `upsert_one()` operation.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UpsertOneResult<Id> {
@@ -579,7 +3307,7 @@ pub struct UpsertOneResult<Id> {
 
 impl<Id: for<'a> Deserialize<'a>> UpsertOneResult<Id> {
     /// Converts a MongoDB `UpdateResult` to an Avocado `UpsertOneResult`.
-    fn from_raw(result: UpdateResult) -> Result<Self> {
+    pub(crate) fn from_raw(result: UpdateResult) -> Result<Self> {
         let matched = result.matched_count > 0;
         let modified = result.modified_count > 0;
         let upserted_id = match result.upserted_id {
@@ -602,6 +3330,71 @@ impl<Id: for<'a> Deserialize<'a>> UpsertOneResult<Id> {
     }
 }
 
+/// The outcome of a successful `upsert_and_fetch()` operation: the document
+/// as it is after the upsert, tagged with what happened to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Upserted<T> {
+    /// No document matched the filter, so this one was inserted.
+    Inserted(T),
+    /// An existing document matched the filter, and it was updated.
+    Updated(T),
+}
+
+/// The outcome of a successful `Collection::insert_or_fetch()` operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InsertOrFetch<Id, T> {
+    /// No conflicting document existed, so the entity was inserted with
+    /// this ID.
+    Inserted(Id),
+    /// A document conflicting with the entity already existed.
+    Existing(T),
+}
+
+impl<Id, T> InsertOrFetch<Id, T> {
+    /// Returns `true` if the entity was inserted.
+    pub fn is_inserted(&self) -> bool {
+        match *self {
+            InsertOrFetch::Inserted(_) => true,
+            InsertOrFetch::Existing(_) => false,
+        }
+    }
+
+    /// Returns `true` if an existing document was fetched.
+    pub fn is_existing(&self) -> bool {
+        !self.is_inserted()
+    }
+
+    /// Returns the existing document, if any.
+    pub fn existing(self) -> Option<T> {
+        match self {
+            InsertOrFetch::Inserted(_) => None,
+            InsertOrFetch::Existing(entity) => Some(entity),
+        }
+    }
+}
+
+impl<T> Upserted<T> {
+    /// Returns `true` if the document was inserted.
+    pub fn is_inserted(&self) -> bool {
+        match *self {
+            Upserted::Inserted(_) => true,
+            Upserted::Updated(_) => false,
+        }
+    }
+
+    /// Returns `true` if an existing document was updated.
+    pub fn is_updated(&self) -> bool {
+        !self.is_inserted()
+    }
+
+    /// Returns the document, regardless of whether it was inserted or updated.
+    pub fn into_inner(self) -> T {
+        match self {
+            Upserted::Inserted(entity) | Upserted::Updated(entity) => entity,
+        }
+    }
+}
+
 /// The outcome of a successful `update_many()` or `upsert_many()` operation.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UpdateManyResult {
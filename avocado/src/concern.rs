@@ -0,0 +1,286 @@
+//! Read preferences and write concerns of collections and operations.
+//!
+//! A collection obtained using `DatabaseExt::collection_with_options()` uses
+//! the read preference and the write concern of its
+//! [`CollectionOptions`](struct.CollectionOptions.html) for the operations
+//! which don't specify their own, e.g. to route analytics queries to
+//! secondaries. The options of individual operations can be overridden by
+//! wrapping them:
+//!
+//! * [`WithReadPreference`](struct.WithReadPreference.html) routes a query,
+//!   a count, a `distinct` or an aggregation according to the given read
+//!   preference;
+//! * [`Acknowledged`](struct.Acknowledged.html) makes an update, an
+//!   upsert, a deletion or a find-and-update wait for the acknowledgement
+//!   demanded by the given write concern, e.g. journaled by a number of
+//!   replica set members.
+//!
+//! The write concerns of updates, upserts and deletions default to
+//! `Doc::update_options()`, `Doc::upsert_options()` and
+//! `Doc::delete_options()`, so only inserts and find-and-update operations
+//! fall back to the write concern of the collection.
+//!
+//! Read concerns aren't supported by the driver for individual operations;
+//! reads in a [`snapshot`](../snapshot/index.html) session or in a
+//! [`transaction`](../transaction/index.html) specify their read concern.
+
+use bson::{ Bson, Document };
+use mongodb::common::{ ReadPreference, WriteConcern };
+use mongodb::coll::options::{
+    FindOptions, CountOptions, DistinctOptions, AggregateOptions, FindOneAndUpdateOptions,
+};
+use crate::{
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
+    doc::Doc,
+    ops::*,
+    error::Result,
+};
+
+/// The defaults of the operations on a collection.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionOptions {
+    /// Where reads are routed, unless an operation specifies otherwise.
+    /// Defaults to the read preference of the client.
+    pub read_preference: Option<ReadPreference>,
+    /// The acknowledgement required for writes, unless an operation
+    /// specifies otherwise. Defaults to the write concern of the client.
+    pub write_concern: Option<WriteConcern>,
+}
+
+/// Wraps a read operation so that it is routed according to a read
+/// preference.
+#[derive(Debug, Clone)]
+pub struct WithReadPreference<O>(pub O, pub ReadPreference);
+
+impl<T: Doc, Q: Count<T>> Count<T> for WithReadPreference<Q> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn options(&self) -> CountOptions {
+        CountOptions {
+            read_preference: Some(self.1.clone()),
+            ..self.0.options()
+        }
+    }
+}
+
+impl<T: Doc, Q: Distinct<T>> Distinct<T> for WithReadPreference<Q> {
+    type Output = Q::Output;
+
+    const FIELD: &'static str = Q::FIELD;
+
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn transform(raw: Bson) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> DistinctOptions {
+        DistinctOptions {
+            read_preference: Some(self.1.clone()),
+            ..self.0.options()
+        }
+    }
+}
+
+impl<T: Doc, P: Pipeline<T>> Pipeline<T> for WithReadPreference<P> {
+    type Output = P::Output;
+
+    fn stages(&self) -> Vec<Document> {
+        self.0.stages()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        P::transform(raw)
+    }
+
+    fn options(&self) -> AggregateOptions {
+        AggregateOptions {
+            read_preference: Some(self.1.clone()),
+            ..self.0.options()
+        }
+    }
+}
+
+impl<T: Doc, Q: Query<T>> Query<T> for WithReadPreference<Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            read_preference: Some(self.1.clone()),
+            ..self.0.options()
+        }
+    }
+
+    fn projection(&self) -> Projection {
+        self.0.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.0.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+/// Wraps a write operation so that it requires the acknowledgement demanded
+/// by a write concern.
+#[derive(Debug, Clone)]
+pub struct Acknowledged<O>(pub O, pub WriteConcern);
+
+impl<T: Doc, U: Update<T>> Update<T> for Acknowledged<U> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn update(&self) -> Document {
+        self.0.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.1.clone()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.0.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, U: Upsert<T>> Upsert<T> for Acknowledged<U> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn upsert(&self) -> Document {
+        self.0.upsert()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.1.clone()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.0.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, Q: Delete<T>> Delete<T> for Acknowledged<Q> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.1.clone()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for Acknowledged<U> {
+    type Output = U::Output;
+
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn update(&self) -> Document {
+        self.0.update()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        U::transform(raw)
+    }
+
+    fn options(&self) -> FindOneAndUpdateOptions {
+        FindOneAndUpdateOptions {
+            write_concern: Some(self.1.clone()),
+            ..self.0.options()
+        }
+    }
+
+    fn projection(&self) -> Projection {
+        self.0.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.0.sort()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Document, oid::ObjectId };
+    use mongodb::common::{ ReadMode, ReadPreference, WriteConcern };
+    use crate::{ doc::Doc, uid::Uid, ops::* };
+    use super::{ WithReadPreference, Acknowledged };
+
+    /// A document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Event {
+        /// The unique ID of the event.
+        #[serde(rename = "_id")]
+        id: Uid<Event>,
+    }
+
+    impl Doc for Event {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Event";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn override_options() {
+        let secondary = ReadPreference::new(ReadMode::Secondary, None);
+        let journaled = WriteConcern { w: 2, w_timeout: 1000, j: true, ..WriteConcern::new() };
+        let filter = doc!{ "kind": "click" };
+
+        let query = WithReadPreference(filter.clone(), secondary);
+        let options = Query::<Event>::options(&query);
+        assert_eq!(Query::<Event>::filter(&query), filter);
+        match options.read_preference.map(|preference| preference.mode) {
+            Some(ReadMode::Secondary) => {}
+            other => panic!("unexpected read mode: {:?}", other),
+        }
+
+        let delete: Acknowledged<Document> = Acknowledged(filter.clone(), journaled);
+        let options = Delete::<Event>::options(&delete);
+        assert_eq!(Delete::<Event>::filter(&delete), filter);
+        assert_eq!(options.w, 2);
+        assert_eq!(options.w_timeout, 1000);
+        assert!(options.j);
+    }
+}
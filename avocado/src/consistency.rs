@@ -0,0 +1,177 @@
+//! Operations whose reads are guaranteed to observe preceding writes.
+//!
+//! Reading back a document right after writing it only works reliably if the
+//! write has been acknowledged and made durable, and if the read is served
+//! by the primary, which applied the write. Getting this right for each
+//! operation separately is error-prone, so wrapping an operation into a
+//! [`ReadYourWrites`](struct.ReadYourWrites.html) adjusts its options
+//! accordingly:
+//!
+//! * writes (updates, upserts and deletions) wait for an acknowledged,
+//!   journaled write, i.e. `w >= 1` and `j = true`;
+//! * reads (queries and counts) are routed to the primary.
+//!
+//! `Collection::update_then_find_one()` and `Collection::upsert_then_find_one()`
+//! combine a write and a subsequent read in a single call.
+
+use mongodb::common::{ ReadMode, ReadPreference, WriteConcern };
+use mongodb::coll::options::{ FindOptions, CountOptions };
+use bson::{ Bson, Document };
+use crate::{
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
+    doc::Doc,
+    ops::*,
+    error::Result,
+};
+
+/// Wraps an operation so that its writes are visible to subsequent reads
+/// wrapped in the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ReadYourWrites<O>(pub O);
+
+/// Strengthens a write concern so that the write is acknowledged and
+/// journaled before the operation returns.
+fn durable(mut write_concern: WriteConcern) -> WriteConcern {
+    write_concern.w = write_concern.w.max(1);
+    write_concern.j = true;
+    write_concern
+}
+
+/// The read preference routing reads to the primary.
+fn primary() -> Option<ReadPreference> {
+    Some(ReadPreference::new(ReadMode::Primary, None))
+}
+
+impl<T: Doc, Q: Count<T>> Count<T> for ReadYourWrites<Q> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn options(&self) -> CountOptions {
+        CountOptions {
+            read_preference: primary(),
+            ..self.0.options()
+        }
+    }
+}
+
+impl<T: Doc, Q: Query<T>> Query<T> for ReadYourWrites<Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            read_preference: primary(),
+            ..self.0.options()
+        }
+    }
+
+    fn projection(&self) -> Projection {
+        self.0.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.0.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, U: Update<T>> Update<T> for ReadYourWrites<U> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn update(&self) -> Document {
+        self.0.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        durable(self.0.options())
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.0.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, U: Upsert<T>> Upsert<T> for ReadYourWrites<U> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn upsert(&self) -> Document {
+        self.0.upsert()
+    }
+
+    fn options(&self) -> WriteConcern {
+        durable(self.0.options())
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.0.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T: Doc, Q: Delete<T>> Delete<T> for ReadYourWrites<Q> {
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn options(&self) -> WriteConcern {
+        durable(self.0.options())
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::common::{ ReadMode, WriteConcern };
+    use super::{ durable, primary };
+
+    #[test]
+    fn durable_write_concern() {
+        let unacknowledged = WriteConcern { w: 0, ..WriteConcern::new() };
+        let replicated = WriteConcern { w: 3, w_timeout: 500, ..WriteConcern::new() };
+
+        let options = durable(unacknowledged);
+        assert_eq!(options.w, 1);
+        assert!(options.j);
+
+        let options = durable(replicated);
+        assert_eq!(options.w, 3);
+        assert_eq!(options.w_timeout, 500);
+        assert!(options.j);
+    }
+
+    #[test]
+    fn primary_read_preference() {
+        match primary().map(|preference| preference.mode) {
+            Some(ReadMode::Primary) => {}
+            other => panic!("unexpected read mode: {:?}", other),
+        }
+    }
+}
@@ -1,11 +1,56 @@
 //! Typed, generic wrapper around MongoDB `Cursor`s.
+//!
+//! A [`Cursor`](struct.Cursor.html) fetches the results of a query from the
+//! server in batches and deserializes them one by one as it is iterated, so
+//! iterating over it instead of collecting it into a `Vec` keeps the memory
+//! usage bounded by the size of a batch. The batch size and the lifetime of
+//! the server-side cursor can be tuned by wrapping the query into a
+//! [`WithCursorOptions`](struct.WithCursorOptions.html).
 
 use std::iter::FromIterator;
 use std::marker::PhantomData;
+use std::hash::Hash;
+use std::collections::HashMap;
 use std::fmt::{ self, Write };
+#[cfg(feature = "rayon")]
+use std::collections::VecDeque;
 use serde::Deserialize;
-use bson::{ Bson, Document, from_bson };
-use crate::error::{ Error, ErrorKind, Result, ResultExt };
+use bson::{ Bson, Document };
+use mongodb::coll::options::FindOptions;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use crate::{
+    doc::Doc,
+    uid::Uid,
+    ops::Query,
+    options,
+    projection::Projection,
+    profile,
+    sort::Sort,
+    error::{ Error, ErrorKind, ServerInfo, Result, ResultExt, reply_code },
+};
+
+/// Types that a `Cursor` can yield.
+///
+/// When the `rayon` feature is enabled, the documents of each batch are
+/// deserialized in parallel on worker threads, so the type must also be
+/// `Send` in that case.
+#[cfg(not(feature = "rayon"))]
+pub trait Item: for<'a> Deserialize<'a> {}
+
+/// Types that a `Cursor` can yield.
+///
+/// When the `rayon` feature is enabled, the documents of each batch are
+/// deserialized in parallel on worker threads, so the type must also be
+/// `Send` in that case.
+#[cfg(feature = "rayon")]
+pub trait Item: for<'a> Deserialize<'a> + Send {}
+
+#[cfg(not(feature = "rayon"))]
+impl<T> Item for T where T: for<'a> Deserialize<'a> {}
+
+#[cfg(feature = "rayon")]
+impl<T> Item for T where T: for<'a> Deserialize<'a> + Send {}
 
 /// A typed wrapper around the MongoDB `Cursor` type.
 pub struct Cursor<T> {
@@ -13,11 +58,16 @@ pub struct Cursor<T> {
     inner: mongodb::cursor::Cursor,
     /// The function applied to each returned `Document` before deserialization.
     transform: fn(Document) -> Result<Bson>,
+    /// The profile the transformed documents are deserialized with.
+    profile: profile::Profile,
+    /// The already-deserialized, not yet yielded items of the current batch.
+    #[cfg(feature = "rayon")]
+    buffer: VecDeque<Result<T>>,
     /// Just here so that the type parameter is used.
     _marker: PhantomData<T>,
 }
 
-impl<T> Cursor<T> where T: for<'a> Deserialize<'a> {
+impl<T: Item> Cursor<T> {
     /// Creates a strongly-typed cursor from an untyped MongoDB cursor
     /// and a transformation function.
     #[doc(hidden)]
@@ -28,11 +78,22 @@ impl<T> Cursor<T> where T: for<'a> Deserialize<'a> {
         Cursor {
             inner,
             transform,
+            profile: profile::Profile::DEFAULT,
+            #[cfg(feature = "rayon")]
+            buffer: VecDeque::new(),
             _marker: PhantomData,
         }
     }
 
+    /// Deserializes the documents according to `profile` instead of the
+    /// default profile.
+    #[doc(hidden)]
+    pub fn with_profile(self, profile: profile::Profile) -> Self {
+        Cursor { profile, ..self }
+    }
+
     /// Reads the remaining documents available in the current batch.
+    #[cfg(not(feature = "rayon"))]
     pub fn next_batch<C: FromIterator<T>>(&mut self) -> Result<C> {
         self.inner
             .drain_current_batch()
@@ -40,7 +101,21 @@ impl<T> Cursor<T> where T: for<'a> Deserialize<'a> {
             .and_then(|docs| self.transform_and_deserialize_many(docs))
     }
 
+    /// Reads the remaining documents available in the current batch.
+    #[cfg(feature = "rayon")]
+    pub fn next_batch<C: FromIterator<T>>(&mut self) -> Result<C> {
+        if self.buffer.is_empty() {
+            self.inner
+                .drain_current_batch()
+                .chain("couldn't retrieve next batch")
+                .and_then(|docs| self.transform_and_deserialize_many(docs))
+        } else {
+            self.buffer.drain(..).collect()
+        }
+    }
+
     /// Retrieves the next at most `n` documents.
+    #[cfg(not(feature = "rayon"))]
     pub fn next_n<C: FromIterator<T>>(&mut self, n: usize) -> Result<C> {
         self.inner
             .next_n(n)
@@ -48,54 +123,269 @@ impl<T> Cursor<T> where T: for<'a> Deserialize<'a> {
             .and_then(|docs| self.transform_and_deserialize_many(docs))
     }
 
+    /// Retrieves the next at most `n` documents.
+    #[cfg(feature = "rayon")]
+    pub fn next_n<C: FromIterator<T>>(&mut self, n: usize) -> Result<C> {
+        let n_buffered = n.min(self.buffer.len());
+        let buffered: Vec<_> = self.buffer.drain(..n_buffered).collect();
+        let docs = self.inner
+            .next_n(n - n_buffered)
+            .chain("couldn't retrieve documents")?;
+        let fetched: Vec<_> = self.transform_and_deserialize_many(docs)?;
+
+        buffered.into_iter().chain(fetched.into_iter().map(Ok)).collect()
+    }
+
     /// Checks whether there are any more documents for the cursor to yield.
+    #[cfg(not(feature = "rayon"))]
     pub fn has_next(&mut self) -> Result<bool> {
         self.inner.has_next().chain("cursor error")
     }
 
+    /// Checks whether there are any more documents for the cursor to yield.
+    #[cfg(feature = "rayon")]
+    pub fn has_next(&mut self) -> Result<bool> {
+        if self.buffer.is_empty() {
+            self.inner.has_next().chain("cursor error")
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Consumes the cursor, collecting the items into a map keyed by the
+    /// value `key` returns for each item. If several items have the same
+    /// key, the last one wins.
+    pub fn collect_map_by<K, F>(self, mut key: F) -> Result<HashMap<K, T>>
+        where K: Eq + Hash,
+              F: FnMut(&T) -> K,
+    {
+        self.map(|result| result.map(|item| (key(&item), item))).collect()
+    }
+
+    /// Consumes the cursor, collecting the documents into a map keyed by
+    /// their `_id`. Returns a `MissingId` error if a document has no `_id`.
+    pub fn collect_map_by_id(self) -> Result<HashMap<Uid<T>, T>>
+        where T: Doc,
+              T::Id: Hash + Clone,
+    {
+        self.map(|result| {
+            let item = result?;
+            let id = item.id().cloned().ok_or_else(|| Error::new(
+                ErrorKind::MissingId,
+                format!("{} document without `_id` in cursor", T::NAME)
+            ))?;
+
+            Ok((id, item))
+        }).collect()
+    }
+
+    /// Consumes the cursor, grouping the items by the value `key` returns
+    /// for each item. Within each group, items keep the order of the cursor.
+    pub fn group_by<K, F>(self, mut key: F) -> Result<HashMap<K, Vec<T>>>
+        where K: Eq + Hash,
+              F: FnMut(&T) -> K,
+    {
+        let mut groups = HashMap::new();
+
+        for result in self {
+            let item = result?;
+            groups.entry(key(&item)).or_insert_with(Vec::new).push(item);
+        }
+
+        Ok(groups)
+    }
+
     /// Transforms and tries to deserialize a single document.
-    fn transform_and_deserialize_one(&self, mut doc: Document) -> Result<T> {
+    fn transform_and_deserialize_one(
+        transform: fn(Document) -> Result<Bson>,
+        profile: profile::Profile,
+        mut doc: Document,
+    ) -> Result<T> {
         // For some reason, the driver hands us back an `Ok(Document)` even if
         // the document itself represents an error. We catch this here.
         if let Some(Bson::String(mut errmsg)) = doc.remove("$err") {
-            if let Ok(code) = doc.get_i32("code") {
-                write!(errmsg, " (code: {})", code).ok();
-            } else if let Ok(code) = doc.get_i64("code") {
+            let server_error = ServerInfo {
+                code: reply_code(&doc),
+                message: errmsg.clone(),
+                ..ServerInfo::default()
+            };
+
+            if let Some(code) = server_error.code {
                 write!(errmsg, " (code: {})", code).ok();
             }
 
-            return Err(Error::new(ErrorKind::MongoDbError, errmsg));
+            return Err(Error::new(ErrorKind::MongoDbError, errmsg).with_context::<ServerInfo>(server_error));
         }
 
-        (self.transform)(doc).and_then(|b| from_bson(b).map_err(From::from))
+        transform(doc).and_then(|b| profile.deserialize(b))
     }
 
     /// Transforms and tries to deserialize a vector of documents.
+    #[cfg(not(feature = "rayon"))]
     fn transform_and_deserialize_many<C>(&self, docs: Vec<Document>) -> Result<C>
         where C: FromIterator<T>
     {
+        let transform = self.transform;
+        let profile = self.profile;
+
         docs.into_iter()
-            .map(|doc| self.transform_and_deserialize_one(doc))
+            .map(|doc| Self::transform_and_deserialize_one(transform, profile, doc))
+            .collect()
+    }
+
+    /// Transforms and tries to deserialize a vector of documents,
+    /// in parallel, preserving their order.
+    #[cfg(feature = "rayon")]
+    fn transform_and_deserialize_many<C>(&self, docs: Vec<Document>) -> Result<C>
+        where C: FromIterator<T>
+    {
+        self.transform_and_deserialize_parallel(docs).into_iter().collect()
+    }
+
+    /// Transforms and deserializes each document in parallel, keeping the
+    /// individual results so that they can be yielded one by one.
+    #[cfg(feature = "rayon")]
+    fn transform_and_deserialize_parallel(&self, docs: Vec<Document>) -> Vec<Result<T>> {
+        let transform = self.transform;
+        let profile = self.profile;
+
+        docs.into_par_iter()
+            .map(|doc| Self::transform_and_deserialize_one(transform, profile, doc))
             .collect()
     }
 }
 
-impl<T> Iterator for Cursor<T> where T: for<'a> Deserialize<'a> {
+#[cfg(not(feature = "rayon"))]
+impl<T: Item> Iterator for Cursor<T> {
     type Item = Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let transform = self.transform;
+        let profile = self.profile;
+
         self.inner
             .next()
             .map(|result| {
                 result
                     .chain("can't step Cursor")
-                    .and_then(|doc| self.transform_and_deserialize_one(doc))
+                    .and_then(|doc| Self::transform_and_deserialize_one(transform, profile, doc))
             })
     }
 }
 
-impl<T> fmt::Debug for Cursor<T> where T: for<'a> Deserialize<'a> {
+/// With the `rayon` feature, a whole batch is fetched and deserialized in
+/// parallel whenever the already deserialized items run out.
+#[cfg(feature = "rayon")]
+impl<T: Item> Iterator for Cursor<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            match self.inner.has_next().chain("can't step Cursor") {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(error) => return Some(Err(error)),
+            }
+
+            match self.inner.drain_current_batch().chain("can't step Cursor") {
+                Ok(docs) => {
+                    let items = self.transform_and_deserialize_parallel(docs);
+                    self.buffer.extend(items);
+                }
+                Err(error) => return Some(Err(error)),
+            }
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+/// Settings of the server-side cursor of a query. Unspecified settings
+/// are taken from the options of the wrapped query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Options {
+    /// The number of documents in each batch returned by the server.
+    pub batch_size: Option<i32>,
+    /// Prevents the server from closing the cursor after 10 minutes of
+    /// inactivity. Such a cursor must be exhausted or dropped eventually.
+    pub no_cursor_timeout: bool,
+    /// The server-side time limit of the query, in milliseconds.
+    pub max_time_ms: Option<i64>,
+}
+
+impl Options {
+    /// Overrides the corresponding fields of the query options.
+    fn apply(self, options: FindOptions) -> FindOptions {
+        FindOptions {
+            batch_size: self.batch_size.or(options.batch_size),
+            no_cursor_timeout: self.no_cursor_timeout || options.no_cursor_timeout,
+            max_time_ms: self.max_time_ms.or(options.max_time_ms),
+            ..options
+        }
+    }
+}
+
+/// Wraps a query so that its cursor is opened with the given settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WithCursorOptions<Q>(pub Q, pub Options);
+
+impl<T: Doc, Q: Query<T>> Query<T> for WithCursorOptions<Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        self.0.filter()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.1.apply(self.0.options())
+    }
+
+    fn projection(&self) -> Projection {
+        self.0.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.0.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.0.command_options()
+    }
+}
+
+impl<T> fmt::Debug for Cursor<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Cursor").finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mongodb::coll::options::FindOptions;
+    use super::Options;
+
+    #[test]
+    fn cursor_options_override_query_options() {
+        let query_options = FindOptions {
+            batch_size: Some(10),
+            max_time_ms: Some(500),
+            limit: Some(1000),
+            ..FindOptions::default()
+        };
+        let cursor_options = Options {
+            batch_size: Some(50),
+            no_cursor_timeout: true,
+            ..Options::default()
+        };
+        let options = cursor_options.apply(query_options);
+
+        assert_eq!(options.batch_size, Some(50));
+        assert_eq!(options.max_time_ms, Some(500));
+        assert_eq!(options.limit, Some(1000));
+        assert!(options.no_cursor_timeout);
+    }
+}
@@ -0,0 +1,193 @@
+//! Dates and times in filters and updates.
+//!
+//! BSON stores dates as milliseconds since the Unix epoch, in UTC. `bson`
+//! already converts `chrono::DateTime<Utc>` to a BSON datetime, but not
+//! datetimes in other time zones, `SystemTime`s or, with the `time` feature,
+//! `time::OffsetDateTime`s. [`BsonDateTime`](struct.BsonDateTime.html)
+//! converts from all of these and into `Bson`, so it can be used wherever
+//! a filter or update expects an `Into<Bson>` value, instead of computing
+//! milliseconds by hand.
+//!
+//! [`between()`](fn.between.html), [`since()`](fn.since.html) and
+//! [`before()`](fn.before.html) build the operator expressions of the most
+//! common date range conditions:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate chrono;
+//! # extern crate avocado;
+//! #
+//! # use chrono::{ TimeZone, Utc, FixedOffset };
+//! # use avocado::datetime::{ BsonDateTime, between };
+//! #
+//! # fn main() {
+//! let start = FixedOffset::east(3600).ymd(2019, 3, 1).and_hms(1, 0, 0);
+//! let end = Utc.ymd(2019, 4, 1).and_hms(0, 0, 0);
+//!
+//! // Orders placed in March 2019, UTC
+//! let filter = doc!{ "placed_at": between(start, end) };
+//!
+//! assert_eq!(filter, doc!{
+//!     "placed_at": {
+//!         "$gte": Utc.ymd(2019, 3, 1).and_hms(0, 0, 0),
+//!         "$lt": end,
+//!     }
+//! });
+//!
+//! let update = doc!{ "$set": { "shipped_at": BsonDateTime::now() } };
+//! # }
+//! ```
+
+use std::time::SystemTime;
+use std::fmt::{ Display, Formatter, Result as FmtResult };
+use bson::{ Bson, Document, UtcDateTime };
+use chrono::{ DateTime, TimeZone, Utc };
+use serde::{ Serialize, Serializer, Deserialize, Deserializer };
+#[cfg(feature = "time")]
+use time::OffsetDateTime;
+
+/// A point in time, represented in BSON as a UTC datetime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BsonDateTime(pub DateTime<Utc>);
+
+impl BsonDateTime {
+    /// The current time.
+    pub fn now() -> Self {
+        BsonDateTime(Utc::now())
+    }
+}
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for BsonDateTime {
+    fn from(datetime: DateTime<Tz>) -> Self {
+        BsonDateTime(datetime.with_timezone(&Utc))
+    }
+}
+
+impl From<UtcDateTime> for BsonDateTime {
+    fn from(datetime: UtcDateTime) -> Self {
+        BsonDateTime(datetime.0)
+    }
+}
+
+impl From<SystemTime> for BsonDateTime {
+    fn from(time: SystemTime) -> Self {
+        BsonDateTime(time.into())
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<OffsetDateTime> for BsonDateTime {
+    fn from(datetime: OffsetDateTime) -> Self {
+        BsonDateTime(Utc.timestamp(datetime.timestamp(), datetime.nanosecond()))
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<BsonDateTime> for OffsetDateTime {
+    fn from(datetime: BsonDateTime) -> Self {
+        let nanos = time::Duration::nanoseconds(i64::from(datetime.0.timestamp_subsec_nanos()));
+        OffsetDateTime::from_unix_timestamp(datetime.0.timestamp()) + nanos
+    }
+}
+
+impl From<BsonDateTime> for DateTime<Utc> {
+    fn from(datetime: BsonDateTime) -> Self {
+        datetime.0
+    }
+}
+
+impl From<BsonDateTime> for Bson {
+    fn from(datetime: BsonDateTime) -> Self {
+        Bson::UtcDatetime(datetime.0)
+    }
+}
+
+impl Display for BsonDateTime {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// Serialized as a BSON datetime, like `bson::UtcDateTime`, rather than as
+/// the string `chrono` would produce.
+impl Serialize for BsonDateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        UtcDateTime(self.0).serialize(serializer)
+    }
+}
+
+impl<'a> Deserialize<'a> for BsonDateTime {
+    fn deserialize<D: Deserializer<'a>>(deserializer: D) -> Result<Self, D::Error> {
+        UtcDateTime::deserialize(deserializer).map(From::from)
+    }
+}
+
+/// Returns an operator expression matching the dates in the half-open
+/// interval `[start, end)`, i.e. `{ "$gte": start, "$lt": end }`, so that
+/// adjacent intervals don't overlap.
+pub fn between<S, E>(start: S, end: E) -> Document
+    where S: Into<BsonDateTime>,
+          E: Into<BsonDateTime>,
+{
+    doc!{
+        "$gte": start.into(),
+        "$lt": end.into(),
+    }
+}
+
+/// Returns an operator expression matching the dates at or after `start`.
+pub fn since<S: Into<BsonDateTime>>(start: S) -> Document {
+    doc!{ "$gte": start.into() }
+}
+
+/// Returns an operator expression matching the dates strictly before `end`.
+pub fn before<E: Into<BsonDateTime>>(end: E) -> Document {
+    doc!{ "$lt": end.into() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, UNIX_EPOCH };
+    use bson::{ Bson, to_bson, from_bson };
+    use chrono::{ TimeZone, Utc, FixedOffset };
+    use super::{ BsonDateTime, between, since, before };
+
+    #[test]
+    fn conversions() {
+        let utc = Utc.ymd(2019, 6, 30).and_hms_milli(22, 0, 0, 250);
+        let local = FixedOffset::west(5 * 3600).ymd(2019, 6, 30).and_hms_milli(17, 0, 0, 250);
+        let system = UNIX_EPOCH + Duration::from_millis(utc.timestamp_millis() as u64);
+
+        assert_eq!(BsonDateTime::from(local), BsonDateTime(utc));
+        assert_eq!(BsonDateTime::from(system), BsonDateTime(utc));
+        assert_eq!(Bson::from(BsonDateTime(utc)), Bson::UtcDatetime(utc));
+
+        let bson = to_bson(&BsonDateTime(utc)).unwrap();
+        assert_eq!(bson, Bson::UtcDatetime(utc));
+        assert_eq!(from_bson::<BsonDateTime>(bson).unwrap(), BsonDateTime(utc));
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_crate() {
+        use time::OffsetDateTime;
+
+        let utc = Utc.ymd(2019, 6, 30).and_hms_milli(22, 0, 0, 250);
+        let offset = OffsetDateTime::from_unix_timestamp(utc.timestamp())
+            + time::Duration::milliseconds(250);
+
+        assert_eq!(BsonDateTime::from(offset), BsonDateTime(utc));
+        assert_eq!(OffsetDateTime::from(BsonDateTime(utc)), offset);
+    }
+
+    #[test]
+    fn ranges() {
+        let start = Utc.ymd(2019, 1, 1).and_hms(0, 0, 0);
+        let end = Utc.ymd(2020, 1, 1).and_hms(0, 0, 0);
+
+        assert_eq!(between(start, end), doc!{ "$gte": start, "$lt": end });
+        assert_eq!(since(start), doc!{ "$gte": start });
+        assert_eq!(before(end), doc!{ "$lt": end });
+    }
+}
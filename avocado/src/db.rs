@@ -1,23 +1,57 @@
 //! Represents a MongoDB database.
 
-use mongodb::db::ThreadedDatabase;
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use serde::de::DeserializeOwned;
+use bson::{ Bson, Document, from_bson };
+use mongodb::{ CommandType, ThreadedClient };
+use mongodb::db::{ Database, ThreadedDatabase };
 use crate::{
     coll::Collection,
+    concern::CollectionOptions,
+    transaction::{ Session, Transaction },
+    index_sync::{ self, IndexDiff },
+    monitor::{ self, CommandListener, ListenerHandle },
+    server::{ self, BuildInfo, HelloReply },
     doc::Doc,
     ext::DocumentExt,
-    error::{ ErrorKind, Result, ResultExt },
+    bsn::BsonExt,
+    utils::float_to_i64,
+    error::{ Error, ErrorKind, ServerInfo, Result, ResultExt },
 };
 
 #[cfg(feature = "schema_validation")]
 use magnet_schema::BsonSchema;
 #[cfg(feature = "schema_validation")]
-use crate::uid::Uid;
+use crate::{
+    uid::Uid,
+    literal::{ ValidationLevel, ValidationAction },
+};
 
 /// Methods augmenting MongoDB `ThreadedDatabase` types.
 pub trait DatabaseExt: ThreadedDatabase {
     /// Returns an existing collection without dropping/recreating it.
     fn existing_collection<T: Doc>(&self) -> Collection<T> {
-        self.collection(T::NAME).into()
+        self.existing_collection_named(T::NAME)
+    }
+
+    /// Returns an existing collection of `T` named `name` instead of
+    /// `T::NAME`, e.g. one prefixed by the name of a tenant.
+    fn existing_collection_named<T: Doc>(&self, name: &str) -> Collection<T> {
+        self.collection(name).into()
+    }
+
+    /// Returns an existing collection, whose operations use the read
+    /// preference and the write concern of `options` by default.
+    fn collection_with_options<T: Doc>(&self, options: CollectionOptions) -> Collection<T> {
+        self.collection_with_prefs(
+            T::NAME,
+            false,
+            options.read_preference,
+            options.write_concern,
+        ).into()
     }
 
     /// Creates a fresh, empty collection. **Drops any existing collection
@@ -29,67 +63,659 @@ pub trait DatabaseExt: ThreadedDatabase {
         where T: Doc + BsonSchema,
               Uid<T>: BsonSchema,
     {
-        use bson::Bson;
-        use mongodb::CommandType;
-        use crate::bsn::BsonExt;
-        use crate::error::Error;
-
-        self.drop_collection(T::NAME).chain("error dropping collection")?;
-
-        // Add the `_id` field's spec to the top-level document's BSON schema.
-        let schema = {
-            let mut schema = T::bson_schema();
-            let mut properties = schema
-                .remove_document("properties")
-                .and_then(Bson::try_into_doc)?;
-
-            if properties.contains_key("_id") {
-                let id_schema = properties.get_document("_id")?;
-
-                if
-                    *id_schema != Uid::<T>::bson_schema()
-                    &&
-                    *id_schema != Option::<Uid<T>>::bson_schema()
-                {
-                    return Err(Error::new(ErrorKind::BsonSchema, "BSON schema mismatch for _id"));
-                }
-            } else {
-                properties.insert("_id", Uid::<T>::bson_schema());
-            }
+        self.empty_collection_named(T::NAME)
+    }
 
-            schema.insert("properties", properties);
-            schema
-        };
-        let command = doc! {
-            "create": T::NAME,
-            "validator": { "$jsonSchema": schema },
-        };
+    /// Like `empty_collection()`, but the collection is named `name` instead
+    /// of `T::NAME`. This allows e.g. a separate collection per tenant, named
+    /// `format!("{}_{}", tenant, T::NAME)`.
+    #[cfg(feature = "schema_validation")]
+    fn empty_collection_named<T>(&self, name: &str) -> Result<Collection<T>>
+        where T: Doc + BsonSchema,
+              Uid<T>: BsonSchema,
+    {
+        self.drop_collection(name).chain("error dropping collection")?;
+
+        let mut command = create_command::<T>(name);
+        command.insert("validator", doc!{ "$jsonSchema": validator_schema::<T>()? });
         let reply = self.command(command, CommandType::CreateCollection, None)?;
-        let err = || Error::new(
-            ErrorKind::MongoDbError,
-            format!("couldn't create {}: {}", T::NAME, reply)
-        );
-        let success = reply.get("ok").and_then(Bson::try_as_bool).ok_or_else(&err)?;
-
-        if success {
-            let coll = self.existing_collection();
-            coll.create_indexes()?;
-            Ok(coll)
+        check_create_reply(name, &reply)?;
+
+        let coll = self.existing_collection_named(name);
+        coll.create_indexes()?;
+        Ok(coll)
+    }
+
+    /// Returns the collection, installing the `$jsonSchema` validator based on
+    /// the `BsonSchema` impl of the document type, enforced according to
+    /// `level` and `action`. The collection is created if it doesn't exist
+    /// yet; otherwise, its validator is replaced using `collMod`, without
+    /// dropping any data. Also creates indexes specified via the
+    /// `T::indexes()` method.
+    #[cfg(feature = "schema_validation")]
+    fn create_collection_validated<T>(
+        &self,
+        level: ValidationLevel,
+        action: ValidationAction,
+    ) -> Result<Collection<T>>
+        where T: Doc + BsonSchema,
+              Uid<T>: BsonSchema,
+    {
+        let exists = self
+            .list_collections(Some(doc!{ "name": T::NAME }))
+            .chain("error listing collections")?
+            .next()
+            .map_or(Ok(false), |result| result.map(|_| true))
+            .chain("error listing collections")?;
+
+        let mut command = if exists {
+            doc!{ "collMod": T::NAME }
         } else {
-            Err(err())
-        }
+            create_command::<T>(T::NAME)
+        };
+        command.insert("validator", doc!{ "$jsonSchema": validator_schema::<T>()? });
+        command.insert("validationLevel", level);
+        command.insert("validationAction", action);
+
+        let reply = self.command(command, CommandType::CreateCollection, None)?;
+        check_create_reply(T::NAME, &reply)?;
+
+        let coll = self.existing_collection();
+        coll.create_indexes()?;
+        Ok(coll)
     }
 
     /// Creates a fresh, empty collection. **Drops any existing collection
     /// with the same name.** Recreates the collection **without** the BSON
     /// schema validator. Also creates indexes specified via the `T::indexes()`
-    /// method.
+    /// method, and sets the default collation given by `T::collation()` and
+    /// the size limits given by `T::capped()`.
     fn empty_collection_novalidate<T: Doc>(&self) -> Result<Collection<T>> {
-        self.drop_collection(T::NAME).chain("error dropping collection")?;
+        self.empty_collection_novalidate_named(T::NAME)
+    }
+
+    /// Like `empty_collection_novalidate()`, but the collection is named
+    /// `name` instead of `T::NAME`.
+    fn empty_collection_novalidate_named<T: Doc>(&self, name: &str) -> Result<Collection<T>> {
+        self.drop_collection(name).chain("error dropping collection")?;
+
+        if T::collation().is_some() || T::capped().is_some() {
+            let reply = self.command(create_command::<T>(name), CommandType::CreateCollection, None)?;
+            check_create_reply(name, &reply)?;
+        }
+
+        let coll = self.existing_collection_named(name);
+        coll.create_indexes()?;
+        Ok(coll)
+    }
+
+    /// Returns the collection, creating it with the default collation given
+    /// by `T::collation()` if it doesn't exist yet. If it does exist, ensures
+    /// that its collation agrees with `T::collation()`, so that queries
+    /// indeed inherit the expected collation. Doesn't drop any data. Also
+    /// creates indexes specified via the `T::indexes()` method.
+    fn ensure_collection<T: Doc>(&self) -> Result<Collection<T>> {
+        let existing = self
+            .list_collections(Some(doc!{ "name": T::NAME }))
+            .chain("error listing collections")?
+            .next()
+            .map_or(Ok(None), |result| result.map(Some))
+            .chain("error listing collections")?;
+
+        match existing {
+            Some(spec) => check_collation::<T>(&spec)?,
+            None => {
+                let reply = self.command(create_command::<T>(T::NAME), CommandType::CreateCollection, None)?;
+                check_create_reply(T::NAME, &reply)?;
+            }
+        }
+
+        let coll = self.existing_collection();
+        coll.create_indexes()?;
+        Ok(coll)
+    }
+
+    /// Creates a capped collection for `T`, holding at most `size` bytes
+    /// and, if specified, at most `max` documents, regardless of
+    /// `T::capped()`, with the default collation given by `T::collation()`.
+    /// The oldest documents are removed once a limit is reached. Also
+    /// creates indexes specified via the `T::indexes()` method. Fails if the
+    /// collection already exists.
+    fn create_capped<T: Doc>(&self, size: i64, max: Option<i64>) -> Result<Collection<T>> {
+        let mut command = create_command::<T>(T::NAME);
+        set_capped(&mut command, size, max);
+
+        let reply = self.command(command, CommandType::CreateCollection, None)?;
+        check_create_reply(T::NAME, &reply)?;
+
         let coll = self.existing_collection();
         coll.create_indexes()?;
         Ok(coll)
     }
+
+    /// Drops the collection of `T`, including its indexes. Succeeds if
+    /// the collection doesn't exist.
+    fn drop_collection_of<T: Doc>(&self) -> Result<()> {
+        self.drop_collection(T::NAME)
+            .chain(|| format!("error dropping collection {}", T::NAME))
+    }
+
+    /// Renames the collection of `T` to `new_name` within the same database,
+    /// using the `renameCollection` admin command. If a collection named
+    /// `new_name` exists, it is dropped if `drop_target` is set; otherwise
+    /// the command fails.
+    fn rename_collection<T: Doc>(&self, new_name: &str, drop_target: bool) -> Result<()>
+        where Self: Borrow<Database>
+    {
+        let db: &Database = self.borrow();
+        let command = doc!{
+            "renameCollection": format!("{}.{}", db.name, T::NAME),
+            "to": format!("{}.{}", db.name, new_name),
+            "dropTarget": drop_target,
+        };
+        let message = || format!("error renaming collection {} to {}", T::NAME, new_name);
+        let reply = db.client
+            .db("admin")
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        check_reply(&reply).chain(&message)
+    }
+
+    /// Returns the name, type and options of every collection and view of
+    /// the database, using the `listCollections` command.
+    fn list_collections_typed(&self) -> Result<Vec<CollectionInfo>> {
+        let message = "error in DatabaseExt::list_collections_typed()";
+
+        self.list_collections(None)
+            .chain(message)?
+            .map(|result| result.chain(message).and_then(CollectionInfo::from_document))
+            .collect()
+    }
+
+    /// Returns storage statistics of the collection of `T`, using the
+    /// `collStats` command. All sizes are in bytes.
+    fn collection_stats<T: Doc>(&self) -> Result<CollectionStats> {
+        let message = || format!("error in DatabaseExt::collection_stats::<{}>()", T::NAME);
+        let reply = self
+            .command(doc!{ "collStats": T::NAME }, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        check_reply(&reply).chain(&message)?;
+        CollectionStats::from_reply(&reply).chain("can't parse reply of `collStats`")
+    }
+
+    /// Synchronizes the indexes of the collection of `T` with `T::indexes()`.
+    /// See `Collection::sync_indexes()`.
+    fn sync_indexes<T: Doc>(&self, options: index_sync::Options) -> Result<IndexDiff> {
+        self.existing_collection::<T>().sync_indexes(options)
+    }
+
+    /// Returns storage statistics of the whole database, using the
+    /// `dbStats` command. All sizes are in bytes.
+    fn stats(&self) -> Result<DatabaseStats> {
+        let reply = self
+            .command(doc!{ "dbStats": 1 }, CommandType::Suppressed, None)
+            .chain("error in DatabaseExt::stats()")?;
+
+        DatabaseStats::from_reply(&reply).chain("can't parse reply of `dbStats`")
+    }
+
+    /// Runs an arbitrary database command, e.g. one without a dedicated
+    /// method, and deserializes its reply as `R`. A reply with `ok: 0` is
+    /// returned as an error, with the code and the message of the server
+    /// available as `error.server_error()`.
+    fn run_command_as<R: DeserializeOwned>(&self, command: Document) -> Result<R> {
+        let name = command.keys().next().cloned().unwrap_or_default();
+        let message = || format!("error in DatabaseExt::run_command_as(`{}`)", name);
+        let reply = self
+            .command(command, CommandType::Suppressed, None)
+            .chain(&message)?;
+
+        check_reply(&reply).chain(&message)?;
+        from_bson(reply.into()).chain(&message)
+    }
+
+    /// Returns the version and the build of the server, using the
+    /// `buildInfo` command.
+    fn build_info(&self) -> Result<BuildInfo> {
+        self.run_command_as(doc!{ "buildInfo": 1 })
+    }
+
+    /// Returns the role of the server and the topology of the deployment,
+    /// using the `isMaster` command, which all server versions support.
+    fn hello(&self) -> Result<HelloReply> {
+        self.run_command_as(doc!{ "isMaster": 1 })
+    }
+
+    /// Returns the most commonly used parts of the status of the server,
+    /// using the `serverStatus` command.
+    fn server_status(&self) -> Result<server::Status> {
+        self.run_command_as(doc!{ "serverStatus": 1 })
+    }
+
+    /// Checks that the server responds, using the `ping` command, and returns
+    /// the round-trip time. See also `server::HealthCheck`.
+    fn ping(&self) -> Result<Duration> {
+        let start = Instant::now();
+        self.run_command_as::<Document>(doc!{ "ping": 1 })?;
+        Ok(start.elapsed())
+    }
+
+    /// Registers a listener notified of every command sent by the client of
+    /// this database. See the [`monitor`](../monitor/index.html) module.
+    fn add_command_listener<L>(&self, listener: L) -> Result<ListenerHandle>
+        where Self: Borrow<Database>,
+              L: CommandListener + 'static,
+    {
+        let db: &Database = self.borrow();
+        monitor::add_listener(&db.client, None, Arc::new(listener))
+    }
+
+    /// Runs `f` in a transaction of a new session, committing it if `f`
+    /// succeeds and aborting it otherwise. See the
+    /// [`transaction`](../transaction/index.html) module for details.
+    fn transaction<F, R>(&self, f: F) -> Result<R>
+        where Self: Borrow<Database>,
+              F: FnOnce(&Transaction) -> Result<R>,
+    {
+        Session::start(self.borrow())?.with_transaction(f)
+    }
 }
 
 impl<T: ThreadedDatabase> DatabaseExt for T {}
+
+/// Database-level statistics, returned by `DatabaseExt::stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DatabaseStats {
+    /// The number of collections.
+    pub collections: i64,
+    /// The number of views.
+    pub views: i64,
+    /// The number of documents in all collections.
+    pub objects: i64,
+    /// The average size of a document.
+    pub avg_obj_size: f64,
+    /// The total uncompressed size of all documents.
+    pub data_size: i64,
+    /// The storage allocated for documents, including free space.
+    pub storage_size: i64,
+    /// The number of indexes of all collections.
+    pub indexes: i64,
+    /// The storage allocated for indexes.
+    pub index_size: i64,
+    /// `storage_size + index_size`, if reported by the server (4.4+).
+    pub total_size: Option<i64>,
+    /// The used space of the file system storing the data, if reported.
+    pub fs_used_size: Option<i64>,
+    /// The total size of the file system storing the data, if reported.
+    pub fs_total_size: Option<i64>,
+}
+
+impl DatabaseStats {
+    /// Extracts the statistics from the reply of the `dbStats` command.
+    fn from_reply(reply: &Document) -> Result<Self> {
+        let required = |key: &str| optional_number(reply, key).ok_or_else(|| Error::new(
+            ErrorKind::MissingDocumentField,
+            format!("missing or non-numeric field `{}`", key)
+        ));
+        let optional = |key: &str| optional_number(reply, key).and_then(float_to_i64);
+        let integer = |key: &str| required(key).and_then(|x| integral(key, x));
+
+        Ok(DatabaseStats {
+            collections: integer("collections")?,
+            views: optional("views").unwrap_or(0),
+            objects: integer("objects")?,
+            avg_obj_size: required("avgObjSize")?,
+            data_size: integer("dataSize")?,
+            storage_size: integer("storageSize")?,
+            indexes: integer("indexes")?,
+            index_size: integer("indexSize")?,
+            total_size: optional("totalSize"),
+            fs_used_size: optional("fsUsedSize"),
+            fs_total_size: optional("fsTotalSize"),
+        })
+    }
+}
+
+/// A collection or view, as described by `listCollections`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionInfo {
+    /// The name of the collection or view.
+    pub name: String,
+    /// The type, i.e. `collection` or `view`, or `timeseries` on newer
+    /// servers.
+    pub kind: String,
+    /// Whether the collection is read-only, e.g. because it is a view.
+    pub read_only: bool,
+    /// Whether the collection is capped.
+    pub capped: bool,
+    /// The default collation of the collection, if any.
+    pub collation: Option<Document>,
+    /// The validator of the collection, if any.
+    pub validator: Option<Document>,
+    /// All options of the collection, as reported by the server.
+    pub options: Document,
+}
+
+impl CollectionInfo {
+    /// Converts an entry of the output of `listCollections`.
+    fn from_document(mut spec: Document) -> Result<Self> {
+        let name = match spec.remove("name") {
+            Some(Bson::String(name)) => name,
+            _ => return Err(Error::new(ErrorKind::MissingDocumentField, "collection without `name`")),
+        };
+        let kind = match spec.remove("type") {
+            Some(Bson::String(kind)) => kind,
+            _ => String::from("collection"),
+        };
+        let read_only = spec
+            .get_document("info")
+            .and_then(|info| info.get_bool("readOnly"))
+            .unwrap_or(false);
+        let options = spec.get_document("options").ok().cloned().unwrap_or_default();
+
+        Ok(CollectionInfo {
+            name,
+            kind,
+            read_only,
+            capped: options.get_bool("capped").unwrap_or(false),
+            collation: options.get_document("collation").ok().cloned(),
+            validator: options.get_document("validator").ok().cloned(),
+            options,
+        })
+    }
+}
+
+/// Statistics of a single collection, returned by
+/// `DatabaseExt::collection_stats()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CollectionStats {
+    /// The number of documents.
+    pub count: i64,
+    /// The total uncompressed size of the documents.
+    pub size: i64,
+    /// The average size of a document, or 0 if there are no documents.
+    pub avg_obj_size: f64,
+    /// The storage allocated for documents, including free space.
+    pub storage_size: i64,
+    /// The number of indexes.
+    pub num_indexes: i64,
+    /// The storage allocated for all indexes.
+    pub total_index_size: i64,
+    /// The storage allocated for each index, by index name.
+    pub index_sizes: BTreeMap<String, i64>,
+    /// Whether the collection is capped.
+    pub capped: bool,
+    /// The maximal number of documents of a capped collection, if limited.
+    pub max: Option<i64>,
+}
+
+impl CollectionStats {
+    /// Extracts the statistics from the reply of the `collStats` command.
+    fn from_reply(reply: &Document) -> Result<Self> {
+        let required = |key: &str| optional_number(reply, key).ok_or_else(|| Error::new(
+            ErrorKind::MissingDocumentField,
+            format!("missing or non-numeric field `{}`", key)
+        ));
+        let integer = |key: &str| required(key).and_then(|x| integral(key, x));
+        let index_sizes = reply
+            .get_document("indexSizes")
+            .map(|sizes| sizes.keys().filter_map(|name| {
+                optional_number(sizes, name).and_then(float_to_i64).map(|size| (name.clone(), size))
+            }).collect())
+            .unwrap_or_default();
+        let capped = reply.get_bool("capped").unwrap_or(false);
+
+        Ok(CollectionStats {
+            count: integer("count")?,
+            size: integer("size")?,
+            avg_obj_size: optional_number(reply, "avgObjSize").unwrap_or(0.0),
+            storage_size: integer("storageSize")?,
+            num_indexes: integer("nindexes")?,
+            total_index_size: integer("totalIndexSize")?,
+            index_sizes,
+            capped,
+            max: if capped {
+                optional_number(reply, "max").and_then(float_to_i64).filter(|&max| max > 0)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Converts a numeric field of a statistics reply to an `i64`.
+fn integral(key: &str, x: f64) -> Result<i64> {
+    float_to_i64(x).ok_or_else(|| Error::new(
+        ErrorKind::IntConversionOverflow,
+        format!("field `{}` ({}) overflows `i64`", key, x)
+    ))
+}
+
+/// Returns the value of a numeric field of any BSON number type as `f64`.
+/// The server reports sizes as 32-bit or 64-bit integers or doubles,
+/// depending on their magnitude and on the storage engine.
+#[allow(clippy::cast_precision_loss)]
+fn optional_number(doc: &Document, key: &str) -> Option<f64> {
+    match doc.get(key) {
+        Some(&Bson::I32(n)) => Some(f64::from(n)),
+        Some(&Bson::I64(n)) => Some(n as f64),
+        Some(&Bson::FloatingPoint(x)) => Some(x),
+        _ => None,
+    }
+}
+
+/// Returns the `$jsonSchema` of the documents of `T`, based on their
+/// `BsonSchema` impl, including the schema of the `_id` field.
+///
+/// The schema of an enum stored as internally tagged documents is the
+/// disjunction of the schemas of its variants. Each of them is checked
+/// for an `_id`, and requires the tag field, so that exactly one of them
+/// matches a valid document.
+#[cfg(feature = "schema_validation")]
+fn validator_schema<T>() -> Result<Document>
+    where T: Doc + BsonSchema,
+          Uid<T>: BsonSchema,
+{
+    let schema = T::bson_schema();
+
+    match T::tag_field() {
+        Some(tag_field) if !schema.contains_key("properties") => {
+            tagged_validator_schema::<T>(schema, tag_field)
+        }
+        _ => {
+            let mut checked_schema = with_id_schema::<T>(schema)?;
+            unrequire_fields(&mut checked_schema, T::defaulted_fields());
+            Ok(checked_schema)
+        }
+    }
+}
+
+/// Returns the `$jsonSchema` of a tagged enum, given the `anyOf` or `oneOf`
+/// schema of its variants.
+#[cfg(feature = "schema_validation")]
+fn tagged_validator_schema<T>(mut schema: Document, tag_field: &str) -> Result<Document>
+    where T: Doc + BsonSchema,
+          Uid<T>: BsonSchema,
+{
+    let key = if schema.contains_key("oneOf") { "oneOf" } else { "anyOf" };
+    let variants = match schema.remove(key) {
+        Some(Bson::Array(array)) => array,
+        _ => return Err(Error::new(
+            ErrorKind::BsonSchema,
+            format!("BSON schema of tagged enum {} has no variants", T::NAME)
+        )),
+    };
+    let checked = variants
+        .into_iter()
+        .map(|variant| match variant {
+            Bson::Document(variant_schema) => {
+                let mut checked_schema = with_id_schema::<T>(variant_schema)?;
+                require_field(&mut checked_schema, tag_field);
+                Ok(Bson::Document(checked_schema))
+            }
+            _ => Err(Error::new(ErrorKind::BsonSchema, "BSON schema of variant isn't a document")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    schema.insert("oneOf", checked);
+    Ok(schema)
+}
+
+/// Adds the `_id` field of `T` to the object schema of its documents, or
+/// checks it if the schema already has one.
+#[cfg(feature = "schema_validation")]
+fn with_id_schema<T>(mut schema: Document) -> Result<Document>
+    where T: Doc + BsonSchema,
+          Uid<T>: BsonSchema,
+{
+    let mut properties = schema
+        .remove_document("properties")
+        .and_then(Bson::try_into_doc)?;
+
+    if properties.contains_key("_id") {
+        let id_schema = properties.get_document("_id")?;
+
+        if
+            *id_schema != Uid::<T>::bson_schema()
+            &&
+            *id_schema != Option::<Uid<T>>::bson_schema()
+        {
+            return Err(Error::new(ErrorKind::BsonSchema, "BSON schema mismatch for _id"));
+        }
+    } else {
+        properties.insert("_id", Uid::<T>::bson_schema());
+    }
+
+    schema.insert("properties", properties);
+    Ok(schema)
+}
+
+/// Adds `field` to the required fields of an object schema.
+#[cfg(feature = "schema_validation")]
+fn require_field(schema: &mut Document, field: &str) {
+    let mut required = match schema.remove("required") {
+        Some(Bson::Array(fields)) => fields,
+        _ => Vec::new(),
+    };
+
+    if !required.contains(&Bson::from(field)) {
+        required.push(Bson::from(field));
+    }
+
+    schema.insert("required", required);
+}
+
+/// Removes `fields` from the required fields of an object schema, since
+/// documents lacking them still deserialize using their default values.
+#[cfg(feature = "schema_validation")]
+fn unrequire_fields(schema: &mut Document, fields: &[&str]) {
+    if fields.is_empty() {
+        return;
+    }
+
+    let required: Vec<_> = match schema.remove("required") {
+        Some(Bson::Array(required)) => required
+            .into_iter()
+            .filter(|field| match *field {
+                Bson::String(ref name) => !fields.contains(&name.as_str()),
+                _ => true,
+            })
+            .collect(),
+        _ => return,
+    };
+
+    if !required.is_empty() {
+        schema.insert("required", required);
+    }
+}
+
+/// Returns the `create` command for the collection of `T` named `name`,
+/// including the default collation and the size limits of a capped
+/// collection, if any.
+fn create_command<T: Doc>(name: &str) -> Document {
+    let mut command = doc!{ "create": name };
+
+    if let Some(collation) = T::collation() {
+        command.insert("collation", collation);
+    }
+    if let Some(capped) = T::capped() {
+        set_capped(&mut command, capped.size, capped.max);
+    }
+
+    command
+}
+
+/// Makes a `create` command create a capped collection.
+fn set_capped(command: &mut Document, size: i64, max: Option<i64>) {
+    command.insert("capped", true);
+    command.insert("size", size);
+
+    if let Some(max_docs) = max {
+        command.insert("max", max_docs);
+    }
+}
+
+/// Returns an error if the reply to a command indicates failure.
+fn check_reply(reply: &Document) -> Result<()> {
+    match reply.get("ok").and_then(Bson::try_as_bool) {
+        Some(true) => Ok(()),
+        _ => {
+            let error = Error::new(
+                ErrorKind::MongoDbError,
+                format!("command failed: {}", reply.get_str("errmsg").unwrap_or("unknown error"))
+            );
+
+            Err(match ServerInfo::from_reply(reply) {
+                Some(server_error) => error.with_context::<ServerInfo>(server_error),
+                None => error,
+            })
+        }
+    }
+}
+
+/// Returns an error if the reply to a `create` command for the collection
+/// named `name` indicates failure.
+fn check_create_reply(name: &str, reply: &Document) -> Result<()> {
+    let err = || Error::new(
+        ErrorKind::MongoDbError,
+        format!("couldn't create {}: {}", name, reply)
+    );
+    let success = reply.get("ok").and_then(Bson::try_as_bool).ok_or_else(&err)?;
+
+    if success {
+        Ok(())
+    } else {
+        Err(err())
+    }
+}
+
+/// Ensures that the collation of an existing collection, as described by
+/// the output of `listCollections`, agrees with `T::collation()`. The server
+/// fills in defaults for unspecified collation options, so only the options
+/// specified by `T::collation()` are compared.
+fn check_collation<T: Doc>(spec: &Document) -> Result<()> {
+    let expected = T::collation();
+    let actual = spec
+        .get_document("options")
+        .and_then(|options| options.get_document("collation"))
+        .ok();
+    let matches = match (expected.as_ref(), actual) {
+        (None, None) => true,
+        (Some(wanted), Some(found)) => {
+            wanted.iter().all(|(key, value)| found.get(key) == Some(value))
+        }
+        (None, Some(_)) | (Some(_), None) => false,
+    };
+
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::CollationMismatch,
+            format!("collection {} has collation {:?}, but {:?} was expected",
+                    T::NAME, actual, expected)
+        ))
+    }
+}
@@ -0,0 +1,274 @@
+//! Keeping denormalized copies of documents consistent.
+//!
+//! Read-optimized schemas often embed a copy of some fields of another
+//! document, e.g. the name and avatar of the author in each post, instead of
+//! looking them up on every read. Such an embedded copy is represented by a
+//! [`Denormalized`](struct.Denormalized.html) field, which stores the `_id`
+//! of the source document along with the copied fields.
+//!
+//! The source type declares where it is embedded by implementing
+//! [`Denormalize`](trait.Denormalize.html). Whenever a source document
+//! changes, [`propagate()`](fn.propagate.html) fans out `$set` updates to
+//! every declared embedding, so that the copies reflect the new values.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! use avocado::denorm::{ Denormalize, Denormalized, Embedding };
+//!
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//!     email: String,
+//! }
+//!
+//! #[derive(Debug, Serialize, Deserialize)]
+//! struct Author {
+//!     name: String,
+//! }
+//!
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! struct Post {
+//!     _id: Uid<Post>,
+//!     author: Denormalized<User, Author>,
+//!     comments: Vec<Denormalized<User, Author>>,
+//! }
+//!
+//! impl Denormalize for User {
+//!     fn embeddings() -> Vec<Embedding> {
+//!         vec![
+//!             Embedding::one("Post", "author", &["name"]),
+//!             Embedding::many("Post", "comments", &["name"]),
+//!         ]
+//!     }
+//! }
+//! #
+//! # fn main() {}
+//! ```
+
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use serde::{ Serialize, Deserialize };
+use bson::{ Bson, Document };
+use mongodb::CommandType;
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::{
+    doc::Doc,
+    uid::Uid,
+    utils::int_to_usize_with_msg,
+    error::{ Error, ErrorKind::{ MissingId, MongoDbError }, Result, ResultExt },
+};
+
+/// A copy of some fields (`V`) of a document of type `S`, embedded in
+/// another document. Serialized as the fields of `V` plus the `_id` of the
+/// source document.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "S::Id: Serialize, V: Serialize",
+    deserialize = "S::Id: for<'a> Deserialize<'a>, V: for<'a> Deserialize<'a>",
+))]
+pub struct Denormalized<S: Doc, V> {
+    /// The ID of the source document.
+    #[serde(rename = "_id")]
+    pub id: Uid<S>,
+    /// The copied fields.
+    #[serde(flatten)]
+    pub value: V,
+}
+
+impl<S: Doc, V> Denormalized<S, V> {
+    /// Creates a copy of the fields `value` of the source document `id`.
+    pub fn new(id: Uid<S>, value: V) -> Self {
+        Denormalized { id, value }
+    }
+}
+
+impl<S: Doc, V: Clone> Clone for Denormalized<S, V> where S::Id: Clone {
+    fn clone(&self) -> Self {
+        Denormalized {
+            id: self.id.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<S: Doc, V: PartialEq> PartialEq for Denormalized<S, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.value == other.value
+    }
+}
+
+impl<S: Doc, V: Debug> Debug for Denormalized<S, V> where S::Id: Debug {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Denormalized")
+            .field("id", &self.id)
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+/// Describes where copies of a source document are embedded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Embedding {
+    /// The name of the collection containing the copies.
+    pub collection: &'static str,
+    /// The (dotted) path of the embedded copy, or of the array of copies.
+    pub path: &'static str,
+    /// The copied fields of the source document.
+    pub fields: &'static [&'static str],
+    /// Whether `path` refers to an array of copies instead of a single one.
+    pub array: bool,
+}
+
+impl Embedding {
+    /// A single copy embedded at `path` in documents of `collection`.
+    pub fn one(collection: &'static str, path: &'static str, fields: &'static [&'static str]) -> Self {
+        Embedding { collection, path, fields, array: false }
+    }
+
+    /// An array of copies embedded at `path` in documents of `collection`.
+    pub fn many(collection: &'static str, path: &'static str, fields: &'static [&'static str]) -> Self {
+        Embedding { collection, path, fields, array: true }
+    }
+}
+
+/// Implemented by documents of which denormalized copies are embedded in
+/// other documents.
+pub trait Denormalize: Doc {
+    /// The places where copies of this type of document are embedded.
+    fn embeddings() -> Vec<Embedding>;
+}
+
+/// Updates every denormalized copy of `source` according to
+/// `S::embeddings()`. Call this after changing the source document.
+/// Returns the number of modified documents.
+///
+/// Copied fields which are missing from the serialized source document
+/// are removed from the copies.
+pub fn propagate<S: Denormalize>(db: &Database, source: &S) -> Result<usize> {
+    let source_id = source.id().ok_or_else(
+        || Error::new(MissingId, format!("can't propagate {} without `_id`", S::NAME))
+    )?;
+    let id = bson::to_bson(source_id)?;
+    let document = S::SERDE_PROFILE.serialize(source)?;
+    let mut num_modified = 0;
+
+    for embedding in S::embeddings() {
+        let command = update_command(&embedding, &id, &document);
+        let reply = db
+            .command(command, CommandType::Suppressed, None)
+            .chain(|| format!("can't propagate {} to {}.{}",
+                              S::NAME, embedding.collection, embedding.path))?;
+
+        if let Ok(errors) = reply.get_array("writeErrors") {
+            return Err(Error::new(
+                MongoDbError,
+                format!("can't propagate {} to {}.{}: {:?}",
+                        S::NAME, embedding.collection, embedding.path, errors)
+            ));
+        }
+
+        let n = reply.get_i32("nModified").unwrap_or(0);
+        num_modified += int_to_usize_with_msg(n, "# of modified documents")?;
+    }
+
+    Ok(num_modified)
+}
+
+/// The element identifier used in `arrayFilters` for arrays of copies.
+const ELEMENT: &str = "avocadoCopy";
+
+/// Builds the `update` command refreshing the copies of the source
+/// document `id`, given its current serialized form.
+fn update_command(embedding: &Embedding, id: &Bson, source: &Document) -> Document {
+    let prefix = if embedding.array {
+        format!("{}.$[{}]", embedding.path, ELEMENT)
+    } else {
+        embedding.path.to_owned()
+    };
+    let mut set = Document::new();
+    let mut unset = Document::new();
+
+    for &field in embedding.fields {
+        let path = format!("{}.{}", prefix, field);
+
+        match source.get(field) {
+            Some(value) => { set.insert(path, value.clone()); }
+            None => { unset.insert(path, ""); }
+        }
+    }
+
+    let mut update = Document::new();
+
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+
+    let mut query = Document::new();
+    query.insert(format!("{}._id", embedding.path), id.clone());
+
+    let mut statement = doc!{
+        "q": query,
+        "u": update,
+        "multi": true,
+    };
+
+    if embedding.array {
+        let mut element = Document::new();
+        element.insert(format!("{}._id", ELEMENT), id.clone());
+        statement.insert("arrayFilters", vec![Bson::from(element)]);
+    }
+
+    doc!{
+        "update": embedding.collection,
+        "updates": [statement],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use super::{ Embedding, update_command };
+
+    #[test]
+    fn single_copy_update() {
+        let source = doc!{ "_id": 7, "name": "Jane", "email": "jane@example.com" };
+        let embedding = Embedding::one("Post", "meta.author", &["name", "avatar"]);
+
+        assert_eq!(update_command(&embedding, &Bson::I32(7), &source), doc!{
+            "update": "Post",
+            "updates": [{
+                "q": { "meta.author._id": 7 },
+                "u": {
+                    "$set": { "meta.author.name": "Jane" },
+                    "$unset": { "meta.author.avatar": "" },
+                },
+                "multi": true,
+            }],
+        });
+    }
+
+    #[test]
+    fn array_of_copies_update() {
+        let source = doc!{ "_id": 7, "name": "Jane" };
+        let embedding = Embedding::many("Post", "comments", &["name"]);
+
+        assert_eq!(update_command(&embedding, &Bson::I32(7), &source), doc!{
+            "update": "Post",
+            "updates": [{
+                "q": { "comments._id": 7 },
+                "u": { "$set": { "comments.$[avocadoCopy].name": "Jane" } },
+                "multi": true,
+                "arrayFilters": [{ "avocadoCopy._id": 7 }],
+            }],
+        });
+    }
+}
@@ -0,0 +1,371 @@
+//! Structural comparison of documents, yielding the set of changed fields.
+//!
+//! A `ChangeSet` can also be turned into an update document via
+//! `ChangeSet::to_update()`, which `Collection::save_changes()` uses for
+//! writing only the modified fields of an entity.
+
+use std::slice;
+use std::vec;
+use std::iter::FromIterator;
+use serde::Serialize;
+use bson::{ Bson, Document };
+use crate::{
+    bsn::serialize_document,
+    error::Result,
+};
+
+/// A single change of a field between two versions of a document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Change {
+    /// The field didn't exist in the old document, but it does in the new one.
+    Added {
+        /// The dotted path of the field.
+        path: String,
+        /// The value of the field in the new document.
+        value: Bson,
+    },
+    /// The field existed in the old document, but it doesn't in the new one.
+    Removed {
+        /// The dotted path of the field.
+        path: String,
+        /// The value of the field in the old document.
+        value: Bson,
+    },
+    /// The field exists in both documents, but with different values.
+    Modified {
+        /// The dotted path of the field.
+        path: String,
+        /// The value of the field in the old document.
+        before: Bson,
+        /// The value of the field in the new document.
+        after: Bson,
+    },
+}
+
+impl Change {
+    /// Returns the dotted path of the changed field.
+    pub fn path(&self) -> &str {
+        match *self {
+            Change::Added { ref path, .. } => path,
+            Change::Removed { ref path, .. } => path,
+            Change::Modified { ref path, .. } => path,
+        }
+    }
+}
+
+/// The list of changes between two versions of a document, in the order of
+/// the fields of the old document followed by those only in the new one.
+///
+/// Embedded documents are compared recursively, and their changed fields are
+/// reported using MongoDB's dot notation, e.g. `address.city`. Arrays and
+/// values of any other type are compared as a whole.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangeSet {
+    /// The individual field changes.
+    changes: Vec<Change>,
+}
+
+impl ChangeSet {
+    /// Returns `true` if the two compared documents were identical.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the number of changed fields.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Returns an iterator over the changes.
+    pub fn iter(&self) -> slice::Iter<'_, Change> {
+        self.changes.iter()
+    }
+
+    /// Returns the dotted paths of all changed fields.
+    pub fn paths(&self) -> Vec<&str> {
+        self.changes.iter().map(Change::path).collect()
+    }
+
+    /// Returns the change affecting the field with the given dotted path.
+    pub fn get(&self, path: &str) -> Option<&Change> {
+        self.changes.iter().find(|change| change.path() == path)
+    }
+
+    /// Returns an update document turning the old document into the new
+    /// one: added and modified fields are `$set`, removed ones `$unset`.
+    /// Returns an empty document if there are no changes.
+    pub fn to_update(&self) -> Document {
+        let mut set = Document::new();
+        let mut unset = Document::new();
+
+        for change in &self.changes {
+            match *change {
+                Change::Added { ref path, ref value } => {
+                    set.insert(path.as_str(), value.clone());
+                }
+                Change::Modified { ref path, ref after, .. } => {
+                    set.insert(path.as_str(), after.clone());
+                }
+                Change::Removed { ref path, .. } => {
+                    unset.insert(path.as_str(), "");
+                }
+            }
+        }
+
+        let mut update = Document::new();
+
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+
+        update
+    }
+}
+
+impl FromIterator<Change> for ChangeSet {
+    fn from_iter<I: IntoIterator<Item = Change>>(iter: I) -> Self {
+        ChangeSet {
+            changes: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for ChangeSet {
+    type Item = Change;
+    type IntoIter = vec::IntoIter<Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChangeSet {
+    type Item = &'a Change;
+    type IntoIter = slice::Iter<'a, Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.changes.iter()
+    }
+}
+
+/// Serializes both values and computes the changes between them.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate serde_derive;
+/// # extern crate avocado;
+/// #
+/// # use avocado::diff::{ diff, Change };
+/// # use avocado::prelude::*;
+/// #
+/// #[derive(Debug, Serialize)]
+/// struct Address {
+///     city: String,
+///     zip: Option<String>,
+/// }
+///
+/// #[derive(Debug, Serialize)]
+/// struct Person {
+///     name: String,
+///     address: Address,
+/// }
+///
+/// # fn main() -> AvocadoResult<()> {
+/// let old = Person {
+///     name: String::from("Alice"),
+///     address: Address { city: String::from("Budapest"), zip: None },
+/// };
+/// let new = Person {
+///     name: String::from("Alice"),
+///     address: Address { city: String::from("Vienna"), zip: None },
+/// };
+/// let changes = diff(&old, &new)?;
+///
+/// assert_eq!(changes.paths(), vec!["address.city"]);
+/// assert_eq!(changes.get("address.city"), Some(&Change::Modified {
+///     path: String::from("address.city"),
+///     before: Bson::from("Budapest"),
+///     after: Bson::from("Vienna"),
+/// }));
+/// # Ok(())
+/// # }
+/// ```
+pub fn diff<T: Serialize>(old: &T, new: &T) -> Result<ChangeSet> {
+    let old_doc = serialize_document(old)?;
+    let new_doc = serialize_document(new)?;
+    Ok(documents(&old_doc, &new_doc))
+}
+
+/// Computes the changes between two raw BSON documents.
+pub fn documents(old: &Document, new: &Document) -> ChangeSet {
+    documents_opaque(old, new, &[])
+}
+
+/// Computes the changes between two raw BSON documents, like
+/// `documents()`, except that the fields with the `opaque` dotted
+/// paths are compared as a whole even if they are embedded documents.
+/// This is needed e.g. for encrypted fields, which can only be written
+/// as a whole.
+pub fn documents_opaque(old: &Document, new: &Document, opaque: &[&str]) -> ChangeSet {
+    let mut changes = Vec::new();
+    diff_into(&mut changes, "", old, new, opaque);
+    ChangeSet { changes }
+}
+
+/// Appends the changes between `old` and `new` to `changes`, prefixing the
+/// keys with `prefix`, which is either empty or ends with a dot. Embedded
+/// documents at the `opaque` paths aren't compared recursively.
+fn diff_into(
+    changes: &mut Vec<Change>,
+    prefix: &str,
+    old: &Document,
+    new: &Document,
+    opaque: &[&str],
+) {
+    for (key, before) in old {
+        let path = format!("{}{}", prefix, key);
+
+        match new.get(key) {
+            None => changes.push(Change::Removed {
+                path,
+                value: before.clone(),
+            }),
+            Some(after) => match (before, after) {
+                (&Bson::Document(ref old_doc), &Bson::Document(ref new_doc))
+                    if !opaque.contains(&path.as_str()) => {
+                    diff_into(changes, &(path + "."), old_doc, new_doc, opaque)
+                }
+                _ => if before != after {
+                    changes.push(Change::Modified {
+                        path,
+                        before: before.clone(),
+                        after: after.clone(),
+                    })
+                },
+            },
+        }
+    }
+
+    for (key, after) in new {
+        if !old.contains_key(key) {
+            changes.push(Change::Added {
+                path: format!("{}{}", prefix, key),
+                value: after.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use super::{ Change, documents, documents_opaque };
+
+    #[test]
+    fn identical_documents() {
+        let doc = doc!{
+            "name": "foo",
+            "inner": { "value": 42 },
+            "array": [1, 2, 3],
+        };
+
+        assert!(documents(&doc, &doc).is_empty());
+    }
+
+    #[test]
+    fn added_removed_modified() {
+        let old = doc!{
+            "same": true,
+            "removed": "bar",
+            "modified": 1,
+            "array": [1, 2, 3],
+            "inner": {
+                "kept": null,
+                "gone": 3.5,
+                "changed": "old",
+            },
+            "became_doc": 0,
+        };
+        let new = doc!{
+            "same": true,
+            "modified": 2,
+            "array": [1, 2],
+            "inner": {
+                "kept": null,
+                "changed": "new",
+                "fresh": "hello",
+            },
+            "became_doc": { "x": 0 },
+            "added": [],
+        };
+        let changes = documents(&old, &new);
+
+        assert_eq!(changes.paths(), vec![
+            "removed",
+            "modified",
+            "array",
+            "inner.gone",
+            "inner.changed",
+            "inner.fresh",
+            "became_doc",
+            "added",
+        ]);
+        assert_eq!(changes.get("removed"), Some(&Change::Removed {
+            path: String::from("removed"),
+            value: Bson::from("bar"),
+        }));
+        assert_eq!(changes.get("inner.changed"), Some(&Change::Modified {
+            path: String::from("inner.changed"),
+            before: Bson::from("old"),
+            after: Bson::from("new"),
+        }));
+        assert_eq!(changes.get("became_doc"), Some(&Change::Modified {
+            path: String::from("became_doc"),
+            before: Bson::I32(0),
+            after: bson!({ "x": 0 }),
+        }));
+        assert_eq!(changes.get("added"), Some(&Change::Added {
+            path: String::from("added"),
+            value: bson!([]),
+        }));
+        assert!(changes.get("same").is_none());
+    }
+
+    #[test]
+    fn opaque_fields_and_update() {
+        let old = doc!{
+            "name": "foo",
+            "secret": { "pin": 1234, "hint": "year" },
+            "address": { "city": "Budapest", "zip": "1011" },
+        };
+        let new = doc!{
+            "name": "foo",
+            "secret": { "pin": 4321, "hint": "year" },
+            "address": { "city": "Vienna" },
+            "email": "foo@example.com",
+        };
+        let changes = documents_opaque(&old, &new, &["secret"]);
+
+        assert_eq!(changes.paths(), vec![
+            "secret",
+            "address.city",
+            "address.zip",
+            "email",
+        ]);
+        assert_eq!(changes.to_update(), doc!{
+            "$set": {
+                "secret": { "pin": 4321, "hint": "year" },
+                "address.city": "Vienna",
+                "email": "foo@example.com",
+            },
+            "$unset": {
+                "address.zip": "",
+            },
+        });
+        assert!(documents(&old, &old).to_update().is_empty());
+    }
+}
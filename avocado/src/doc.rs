@@ -1,6 +1,8 @@
 //! A document is a direct member of a collection.
 
 use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use bson::{ Bson, Document };
 use mongodb::{
     common::WriteConcern,
     coll::options::{
@@ -13,18 +15,60 @@ use mongodb::{
         FindOneAndUpdateOptions,
     },
 };
-use crate::uid::Uid;
+use crate::{
+    uid::Uid,
+    shard,
+    encrypt::EncryptedField,
+    profile,
+    bsn::{ self, document_size },
+    error::Result,
+};
+
+pub use crate::bsn::ExtendedJsonMode;
+
+/// The maximal size of a document accepted by MongoDB, in bytes.
+pub const MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+
+/// The size limits of a capped collection, returned by `Doc::capped()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Capped {
+    /// The maximal total size of the documents, in bytes.
+    pub size: i64,
+    /// The maximal number of documents, if limited.
+    pub max: Option<i64>,
+}
+
+/// Names the collection of a generic document type after one of its type
+/// parameters, e.g. the payload of an event envelope.
+///
+/// When deriving `Doc` for a type with type parameters, the collection is
+/// named after the type itself by default, so all instantiations share it.
+/// `#[avocado(name_from = "P")]` names it by `P::COLLECTION_NAME` instead,
+/// so that e.g. `Event<OrderPlaced>` and `Event<UserSignedUp>` are stored
+/// separately.
+pub trait CollectionName {
+    /// The name of the collection of the documents parameterized by `Self`.
+    const COLLECTION_NAME: &'static str;
+}
 
 /// Implemented by top-level (direct collection member) documents only.
 /// These types always have an associated top-level name and an `_id` field.
 pub trait Doc: Serialize + for<'a> Deserialize<'a> {
     /// The type of the unique IDs for the document. A good default choice
     /// is `ObjectId`. TODO(H2CO3): make it default to `ObjectId` (#29661).
-    type Id: Eq + Serialize + for <'a> Deserialize<'a>;
+    type Id: Eq + Serialize + for <'a> Deserialize<'a> + Send + Sync;
 
     /// The name of the collection within the database.
     const NAME: &'static str;
 
+    /// How `Collection` stores and reads the serialized form of the type;
+    /// see the [`profile`](../profile/index.html) module. Defaults to
+    /// storing exactly what `serde` produces.
+    ///
+    /// When deriving `Doc`, this is set by
+    /// `#[avocado(serde_profile = "path::to::CONST")]`.
+    const SERDE_PROFILE: profile::Profile = profile::Profile::DEFAULT;
+
     /// Get the unique ID of this document if it exists.
     fn id(&self) -> Option<&Uid<Self>>;
 
@@ -39,6 +83,273 @@ pub trait Doc: Serialize + for<'a> Deserialize<'a> {
         Vec::new()
     }
 
+    /// Returns the size of this document when encoded as BSON, in bytes.
+    fn bson_size_estimate(&self) -> Result<usize> {
+        Self::SERDE_PROFILE.serialize(self).and_then(|doc| document_size(&doc))
+    }
+
+    /// Converts this document to MongoDB Extended JSON, as produced by
+    /// `mongoexport`.
+    fn to_extended_json(&self, mode: ExtendedJsonMode) -> Result<Value> {
+        Self::SERDE_PROFILE.serialize(self).map(|doc| bsn::to_extended_json(&Bson::Document(doc), mode))
+    }
+
+    /// Parses a document from MongoDB Extended JSON, in either mode.
+    fn from_extended_json(value: Value) -> Result<Self> {
+        let raw = bsn::from_extended_json(value)?;
+        Self::SERDE_PROFILE.deserialize(raw)
+    }
+
+    /// The maximal size of a single document, in bytes. Documents bigger
+    /// than this are rejected before they are sent to the server. Defaults
+    /// to `MAX_DOCUMENT_SIZE`, the hard limit imposed by MongoDB, but it can
+    /// be overridden in order to enforce a lower, soft limit.
+    fn bson_size_limit() -> usize {
+        MAX_DOCUMENT_SIZE
+    }
+
+    /// The default collation of the collection, e.g.
+    /// `doc!{ "locale": "en", "strength": 2 }` for case-insensitive
+    /// comparisons. If set, the collection is created with this collation
+    /// by the methods of `DatabaseExt`, and every operation that doesn't
+    /// specify a collation explicitly inherits it on the server side.
+    /// Defaults to `None`, i.e. simple binary comparison of strings.
+    ///
+    /// When deriving `Doc`, this can be set using the
+    /// `#[doc_collation(locale = "...", ...)]` attribute.
+    fn collation() -> Option<Document> {
+        None
+    }
+
+    /// The size limits of the collection, if it is capped. A capped
+    /// collection is created with these limits by the methods of
+    /// `DatabaseExt`, and it removes its oldest documents once a limit is
+    /// reached, e.g. for logs and lightweight queues which can be followed
+    /// using `Collection::tail()`. Defaults to `None`.
+    ///
+    /// When deriving `Doc`, this can be set using the
+    /// `#[capped(size = ..., max = ...)]` attribute.
+    fn capped() -> Option<Capped> {
+        None
+    }
+
+    /// The shard key of the collection, if it is sharded. Updates and
+    /// deletions whose filter doesn't include the shard key are detected;
+    /// see the [`shard`](../shard/index.html) module. Defaults to `None`.
+    ///
+    /// When deriving `Doc`, this can be set using the
+    /// `#[shard_key(fields(...))]` attribute.
+    fn shard_key() -> Option<shard::Key> {
+        None
+    }
+
+    /// The names of all top-level fields of the serialized document, if reads
+    /// should be strict. In strict mode, reading a stored document which has
+    /// any other field results in an `UnknownField` error, which helps to
+    /// detect writers bypassing the schema. Defaults to `None`, i.e. lenient
+    /// reads which silently ignore unknown fields.
+    ///
+    /// When deriving `Doc`, this can be turned on by `#[avocado(strict)]`.
+    fn strict_fields() -> Option<&'static [&'static str]> {
+        None
+    }
+
+    /// The names of the top-level fields which may be missing from stored
+    /// documents, because they are filled in with a default value when
+    /// deserialized, e.g. fields added by a newer version of the application
+    /// to documents already written by an older one. These fields are not
+    /// `required` by the `$jsonSchema` validator of the collection, so old
+    /// and new documents can coexist during a rolling schema migration.
+    /// Defaults to no fields.
+    ///
+    /// When deriving `Doc`, this lists the fields annotated with
+    /// `#[serde(default)]` or `#[serde(default = "path")]`, or every field
+    /// if the type itself has a `#[serde(default)]` attribute.
+    fn defaulted_fields() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Whether the field names of written documents are checked on the
+    /// client side. If so, inserting or replacing a document which has a
+    /// field name, at any depth, that contains a `.` or starts with a `$`
+    /// fails with an `InvalidFieldName` error naming its path, instead of an
+    /// opaque server error (or silent acceptance by MongoDB 5.0 and newer,
+    /// which makes such fields hard to query). Defaults to `false`, since
+    /// the check traverses the whole document.
+    ///
+    /// When deriving `Doc`, this can be turned on by
+    /// `#[avocado(check_field_names)]`.
+    fn check_field_names() -> bool {
+        false
+    }
+
+    /// Generates a new ID on the client side, for a document inserted
+    /// through a `Collection` without an `_id`, i.e. with an `_id` field of
+    /// type `Option<Uid<Self>>` which is `None`. Defaults to `None`, in which
+    /// case an `ObjectId` is generated by the driver, so types with other
+    /// kinds of IDs, e.g. UUIDs, snowflake IDs or ULIDs, should override it.
+    ///
+    /// When deriving `Doc`, this is set by the `#[id_generator = "path"]`
+    /// attribute, where `path` names a function returning a `Self::Id`,
+    /// e.g. `#[id_generator = "uuid::Uuid::new_v4"]`.
+    fn generate_id() -> Option<Self::Id> {
+        None
+    }
+
+    /// The name of the stored integer field holding the version of the
+    /// document, if writes to it use optimistic concurrency control; see
+    /// `Collection::replace_entity_versioned()`. Defaults to `None`.
+    ///
+    /// When deriving `Doc`, this is set by annotating an `i64` field with
+    /// `#[avocado(version)]`, which also implements `version()` and
+    /// `set_version()` accordingly.
+    fn version_field() -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the version of this document, if it is versioned.
+    fn version(&self) -> Option<i64> {
+        None
+    }
+
+    /// Changes the version of this document. Does nothing by default.
+    fn set_version(&mut self, _version: i64) {}
+
+    /// The name of the stored field set to the current time when a document
+    /// is inserted through a `Collection`. Defaults to `None`.
+    ///
+    /// When deriving `Doc`, this is set by annotating a field, typically of
+    /// type `bson::UtcDateTime`, with `#[avocado(created_at)]`.
+    fn created_at_field() -> Option<&'static str> {
+        None
+    }
+
+    /// The name of the stored field set to the current time whenever a
+    /// document is inserted, replaced or updated through a `Collection`;
+    /// updates use `$currentDate`, i.e. the time of the server. Defaults to
+    /// `None`.
+    ///
+    /// When deriving `Doc`, this is set by annotating a field, typically of
+    /// type `bson::UtcDateTime`, with `#[avocado(updated_at)]`.
+    fn updated_at_field() -> Option<&'static str> {
+        None
+    }
+
+    /// The name of the stored field marking a document as soft-deleted, if
+    /// documents of this type are never removed by `Collection::delete_one()`
+    /// and `delete_many()`, but have this field set to the current time
+    /// instead. Documents in which the field is set are then excluded from
+    /// counts, queries, updates, deletions and aggregations; they can still
+    /// be retrieved using `Collection::find_deleted()`, restored using
+    /// `undelete()` and removed for good using `purge()`. Defaults to `None`.
+    ///
+    /// This includes the operations run in a transaction or a snapshot
+    /// session, and `find_one_and_delete()`, which marks the document too.
+    /// Raw commands are not affected.
+    ///
+    /// When deriving `Doc`, this is set by annotating a field, typically of
+    /// type `Option<bson::UtcDateTime>`, with `#[avocado(deleted_at)]`.
+    fn deleted_at_field() -> Option<&'static str> {
+        None
+    }
+
+    /// How field names in filter, update, sort and projection documents map
+    /// to the names of the stored fields. Collections written by other
+    /// applications, e.g. in Node.js, often use camelCase field names; with
+    /// `FieldNaming::CamelCase`, the Rust snake_case names can be used in
+    /// queries and updates, and they are translated before being sent to
+    /// the server. Defaults to `FieldNaming::Verbatim`.
+    ///
+    /// When deriving `Doc`, this is set to `FieldNaming::CamelCase` by
+    /// `#[serde(rename_all = "camelCase")]`, which also makes the field
+    /// names of the derived indexes and shard key camelCase.
+    fn field_naming() -> FieldNaming {
+        FieldNaming::Verbatim
+    }
+
+    /// The stored fields which are encrypted on the client side, and how;
+    /// see the [`encrypt`](../encrypt/index.html) module. Defaults to none.
+    ///
+    /// When deriving `Doc`, a field is encrypted by annotating it with
+    /// `#[avocado(encrypted)]`, or `#[avocado(encrypted = "deterministic")]`
+    /// if it must support equality filters.
+    fn encrypted_fields() -> &'static [EncryptedField] {
+        &[]
+    }
+
+    /// The name of the collection recording the writes to this type, if it
+    /// is audited; see `audit::Log::for_doc()`. Defaults to `None`.
+    ///
+    /// When deriving `Doc`, `#[avocado(audited)]` sets this to the name of
+    /// the collection followed by `_audit`, while `#[avocado(audited = "name")]`
+    /// sets it to a custom name.
+    fn audit_collection() -> Option<&'static str> {
+        None
+    }
+
+    /// For an enum stored as internally tagged documents, the name of the
+    /// field holding the tag of the variant; see the
+    /// [`variant`](../variant/index.html) module. Defaults to `None`.
+    ///
+    /// When deriving `Doc` for an enum, this is set by `#[serde(tag = "...")]`.
+    fn tag_field() -> Option<&'static str> {
+        None
+    }
+
+    /// The tags of all variants of an enum stored as internally tagged
+    /// documents, in declaration order. Defaults to none.
+    fn variant_tags() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// The tag of the variant of this entity, if the type is an enum stored
+    /// as internally tagged documents. Defaults to `None`.
+    fn variant_tag(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Called with an entity and its serialized form before it is inserted,
+    /// replaced or upserted as a whole through a `Collection`. It can
+    /// validate the entity, returning an error to abort the write, or change
+    /// the fields to be stored, e.g. to maintain denormalized data. Runs
+    /// before the timestamp fields are set. Does nothing by default.
+    ///
+    /// When deriving `Doc`, this is set by `#[avocado(before_insert = "path")]`,
+    /// where `path` names a function with the same signature.
+    fn before_insert(&self, _doc: &mut Document) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with each update document of an update, upsert or
+    /// find-and-update through a `Collection`, before its field names are
+    /// translated and before it is sent to the server. It can reject the
+    /// update or add operators to it. Does nothing by default.
+    ///
+    /// When deriving `Doc`, this is set by `#[avocado(before_update = "path")]`.
+    fn before_update(_update: &mut Document) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with the filter of each deletion through a `Collection`,
+    /// including soft deletions and purges, before it is sent to the server.
+    /// It can reject the deletion, e.g. to audit or forbid unfiltered ones.
+    /// Does nothing by default.
+    ///
+    /// When deriving `Doc`, this is set by `#[avocado(before_delete = "path")]`.
+    fn before_delete(_filter: &Document) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with each raw document read from the collection by a query
+    /// or a find-and-modify operation through a `Collection`, before it is
+    /// deserialized, so it also applies to projected results. It can fill
+    /// in derived fields, or reject the document. Does nothing by default.
+    ///
+    /// When deriving `Doc`, this is set by `#[avocado(after_load = "path")]`.
+    fn after_load(_doc: &mut Document) -> Result<()> {
+        Ok(())
+    }
+
     /// Options for a count-only query.
     fn count_options() -> CountOptions {
         Default::default()
@@ -84,3 +395,140 @@ pub trait Doc: Serialize + for<'a> Deserialize<'a> {
         Default::default()
     }
 }
+
+/// A mapping from field names used in code to stored field names.
+///
+/// Only field names are renamed, i.e. keys not starting with `$`; operator
+/// expressions are searched recursively. Each component of a dotted path is
+/// renamed separately, except for array indexes, positional operators, and
+/// names starting with an underscore, such as `_id`, which are kept as-is.
+#[derive(Debug, Clone, Copy)]
+pub enum FieldNaming {
+    /// Field names are stored as they are written.
+    Verbatim,
+    /// `snake_case` field names are stored as `camelCase`.
+    CamelCase,
+    /// Each component of field paths is renamed by the function.
+    Custom(fn(&str) -> String),
+}
+
+impl FieldNaming {
+    /// Renames a single field path, e.g. `"home_address.zip_code"` to
+    /// `"homeAddress.zipCode"` in the case of `CamelCase`.
+    pub fn rename_path(self, path: &str) -> String {
+        let rename: fn(&str) -> String = match self {
+            FieldNaming::Verbatim => return path.into(),
+            FieldNaming::CamelCase => camel_case,
+            FieldNaming::Custom(rename) => rename,
+        };
+
+        path.split('.')
+            .map(|component| {
+                let kept = component.starts_with('$')
+                    || component.starts_with('_')
+                    || component.bytes().all(|b| b.is_ascii_digit());
+
+                if kept {
+                    component.into()
+                } else {
+                    rename(component)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// Renames the fields of a filter, update, sort or projection document.
+    pub fn rename_document(self, doc: Document) -> Document {
+        if let FieldNaming::Verbatim = self {
+            return doc;
+        }
+
+        doc.into_iter()
+            .map(|(name, value)| {
+                let key = if name.starts_with('$') { name } else { self.rename_path(&name) };
+                (key, self.rename_value(value))
+            })
+            .collect()
+    }
+
+    /// Renames the fields of the documents in `value`, recursively.
+    fn rename_value(self, value: Bson) -> Bson {
+        match value {
+            Bson::Document(doc) => Bson::Document(self.rename_document(doc)),
+            Bson::Array(items) => Bson::Array(
+                items.into_iter().map(|item| self.rename_value(item)).collect()
+            ),
+            other => other,
+        }
+    }
+}
+
+impl Default for FieldNaming {
+    fn default() -> Self {
+        FieldNaming::Verbatim
+    }
+}
+
+/// Converts a `snake_case` name to `camelCase`, the same way as
+/// `#[serde(rename_all = "camelCase")]` does.
+fn camel_case(name: &str) -> String {
+    let mut camel = String::with_capacity(name.len());
+    let mut capitalize = false;
+
+    for ch in name.chars() {
+        if ch == '_' {
+            capitalize = true;
+        } else if capitalize {
+            camel.push(ch.to_ascii_uppercase());
+            capitalize = false;
+        } else {
+            camel.push(ch);
+        }
+    }
+
+    camel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FieldNaming;
+
+    #[test]
+    fn camel_case_paths() {
+        let naming = FieldNaming::CamelCase;
+
+        assert_eq!(naming.rename_path("created_at"), "createdAt");
+        assert_eq!(naming.rename_path("home_address.zip_code"), "homeAddress.zipCode");
+        assert_eq!(naming.rename_path("line_items.0.unit_price"), "lineItems.0.unitPrice");
+        assert_eq!(naming.rename_path("line_items.$.unit_price"), "lineItems.$.unitPrice");
+        assert_eq!(naming.rename_path("_id"), "_id");
+        assert_eq!(naming.rename_path("alreadyCamel"), "alreadyCamel");
+        assert_eq!(FieldNaming::Verbatim.rename_path("created_at"), "created_at");
+    }
+
+    #[test]
+    fn camel_case_documents() {
+        let naming = FieldNaming::CamelCase;
+        let filter = doc!{
+            "_id": 1,
+            "$or": [
+                { "first_name": { "$regex": "^a_b" } },
+                { "tags": { "$elemMatch": { "tag_name": "x_y" } } },
+            ],
+        };
+        let update = doc!{ "$set": { "last_login": 0 }, "$inc": { "login_count": 1 } };
+
+        assert_eq!(naming.rename_document(filter), doc!{
+            "_id": 1,
+            "$or": [
+                { "firstName": { "$regex": "^a_b" } },
+                { "tags": { "$elemMatch": { "tagName": "x_y" } } },
+            ],
+        });
+        assert_eq!(naming.rename_document(update), doc!{
+            "$set": { "lastLogin": 0 },
+            "$inc": { "loginCount": 1 },
+        });
+    }
+}
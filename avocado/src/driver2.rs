@@ -0,0 +1,1022 @@
+//! Compatibility with the official `mongodb` 2.x driver, enabled by the
+//! `driver2` feature.
+//!
+//! The rest of the crate is built on the legacy `mongodb` 0.3 driver. This
+//! module lets `Doc` types and the operation traits of the
+//! [`ops`](../ops/index.html) module be used with the synchronous API of the
+//! official driver instead, through a
+//! [`SyncCollection`](struct.SyncCollection.html). It also converts
+//! documents, index models, options and errors between the two drivers,
+//! for code migrating from one to the other piecemeal.
+//!
+//! The two drivers use different major versions of the `bson` crate.
+//! Documents are converted by re-encoding them as raw BSON, which preserves
+//! every value, including the order of the fields.
+//!
+//! `SyncCollection` covers the counts, queries, aggregations, inserts,
+//! updates, upserts, deletions and find-and-modify operations of
+//! `Collection`, and [`SyncDatabaseExt`](trait.SyncDatabaseExt.html) the
+//! creation and dropping of collections. Sessions, transactions, change
+//! streams, caching and the other extensions of `Collection` remain
+//! specific to the legacy driver. The hooks, field naming, timestamps, soft
+//! deletion and encryption of `Doc` types are applied the same way as by
+//! `Collection`, and duplicate keys on the unique indexes of `T` are
+//! reported as `UniqueViolation` errors too, but the read preference, the
+//! hint and the write concern of options are ignored.
+//!
+//! The official driver can also hand out documents as raw BSON bytes.
+//! `SyncCollection::find_raw()` returns them undecoded, and
+//! [`from_raw()`](fn.from_raw.html) deserializes them directly from the
+//! bytes, without building an intermediate `Document`, into types which may
+//! borrow their `&str` and `&[u8]` fields from the raw documents. The
+//! legacy driver always decodes documents on its own, so `Collection` has
+//! no such fast path.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! # extern crate mongodb2;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::driver2::{ SyncCollection, SyncDatabaseExt };
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! let client = mongodb2::sync::Client::with_uri_str("mongodb://localhost:27017/")?;
+//! let users: SyncCollection<User> = client.database("app").existing_collection();
+//!
+//! users.create_indexes()?;
+//! users.insert_one(&User { _id: Uid::new_oid()?, name: String::from("Alice") })?;
+//!
+//! let alice = users.find_one(doc!{ "name": "Alice" })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use std::error::Error as StdError;
+use serde::de::{ Deserialize, DeserializeOwned };
+use bson2::{ RawDocument, RawDocumentBuf };
+use bson::{ Bson, Document, from_bson };
+use mongodb::coll::options::{
+    IndexModel, IndexOptions, FindOptions, AggregateOptions, FindOneAndUpdateOptions, ReturnDocument,
+};
+use crate::{
+    coll::{
+        UpdateOneResult, UpdateManyResult, UpsertOneResult, UpsertManyResult,
+        renamed, renamed_array_filters, live, live_stages, loaded, query_options,
+        strict_transform, serialize_entity, generate_id, stamp_inserted, stamp_replaced,
+        update_document, deletion_filter, check_entity_document, unique_violation,
+    },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    update::ArrayFilters,
+    utils::{ MaxTime, int_to_usize_with_msg },
+    error::{
+        Error, ErrorKind, ErrorExt, ServerInfo, WriteErrorInfo, Result, ResultExt,
+    },
+};
+
+/// Converts a document of the legacy driver to one of the official driver.
+pub fn document_to_v2(doc: &Document) -> Result<bson2::Document> {
+    let mut bytes = Vec::new();
+    bson::encode_document(&mut bytes, doc)?;
+    bson2::Document::from_reader(&mut bytes.as_slice()).map_err(Into::into)
+}
+
+/// Converts a document of the official driver to one of the legacy driver.
+pub fn document_from_v2(doc: &bson2::Document) -> Result<Document> {
+    let mut bytes = Vec::new();
+    doc.to_writer(&mut bytes)?;
+    bson::decode_document(&mut bytes.as_slice()).map_err(Into::into)
+}
+
+/// Deserializes a raw document, e.g. one returned by
+/// `SyncCollection::find_raw()`, directly from its bytes. No tree of BSON
+/// values is allocated, and the `&str` and `&[u8]` fields of `O` borrow
+/// from `raw` instead of being copied.
+pub fn from_raw<'a, O: Deserialize<'a>>(raw: &'a RawDocument) -> Result<O> {
+    bson2::from_slice(raw.as_bytes()).map_err(Into::into)
+}
+
+/// Converts a BSON value of the official driver to one of the legacy driver.
+pub fn bson_from_v2(value: bson2::Bson) -> Result<Bson> {
+    let mut doc = document_from_v2(&bson2::doc!{ "value": value })?;
+    doc.remove("value").ok_or_else(|| Error::new(
+        ErrorKind::MissingDocumentField, "converted value disappeared"
+    ))
+}
+
+/// Converts an index of the legacy driver, e.g. an element of
+/// `Doc::indexes()`, to one of the official driver.
+pub fn index_model(model: &IndexModel) -> Result<mongodb2::IndexModel> {
+    let keys = document_to_v2(&model.keys)?;
+    let options = options_v2(index_options_document(&model.options))?;
+
+    Ok(mongodb2::IndexModel::builder().keys(keys).options(Some(options)).build())
+}
+
+/// Converts the options of a query of the legacy driver to those of the
+/// official driver. The read preference and the cursor type are ignored.
+pub fn find_options(options: &FindOptions) -> Result<mongodb2::options::FindOptions> {
+    options_v2(find_options_document(options))
+}
+
+/// Returns the options of a query in the format of the `find` command.
+fn find_options_document(options: &FindOptions) -> Document {
+    let mut doc = Document::new();
+
+    if let Some(ref sort) = options.sort {
+        doc.insert("sort", sort.clone());
+    }
+    if let Some(ref projection) = options.projection {
+        doc.insert("projection", projection.clone());
+    }
+    if let Some(skip) = options.skip {
+        doc.insert("skip", skip);
+    }
+    if let Some(limit) = options.limit {
+        doc.insert("limit", limit);
+    }
+    if let Some(batch_size) = options.batch_size {
+        doc.insert("batchSize", batch_size);
+    }
+    if let Some(max_time_ms) = options.max_time_ms {
+        doc.insert("maxTimeMS", max_time_ms);
+    }
+    if let Some(ref comment) = options.comment {
+        doc.insert("comment", comment.clone());
+    }
+    if options.no_cursor_timeout {
+        doc.insert("noCursorTimeout", true);
+    }
+    if options.allow_partial_results {
+        doc.insert("allowPartialResults", true);
+    }
+
+    doc
+}
+
+/// Returns the options of an index in the format of `createIndexes`.
+fn index_options_document(options: &IndexOptions) -> Document {
+    let mut doc = Document::new();
+
+    if let Some(ref name) = options.name {
+        doc.insert("name", name.clone());
+    }
+    if let Some(unique) = options.unique {
+        doc.insert("unique", unique);
+    }
+    if let Some(sparse) = options.sparse {
+        doc.insert("sparse", sparse);
+    }
+    if let Some(background) = options.background {
+        doc.insert("background", background);
+    }
+    if let Some(expire_after_seconds) = options.expire_after_seconds {
+        doc.insert("expireAfterSeconds", expire_after_seconds);
+    }
+    if let Some(version) = options.version {
+        doc.insert("v", version);
+    }
+    if let Some(ref default_language) = options.default_language {
+        doc.insert("default_language", default_language.clone());
+    }
+    if let Some(ref language_override) = options.language_override {
+        doc.insert("language_override", language_override.clone());
+    }
+    if let Some(text_version) = options.text_version {
+        doc.insert("textIndexVersion", text_version);
+    }
+    if let Some(ref weights) = options.weights {
+        doc.insert("weights", weights.clone());
+    }
+    if let Some(sphere_version) = options.sphere_version {
+        doc.insert("2dsphereIndexVersion", sphere_version);
+    }
+    if let Some(bits) = options.bits {
+        doc.insert("bits", bits);
+    }
+    if let Some(min) = options.min {
+        doc.insert("min", min);
+    }
+    if let Some(max) = options.max {
+        doc.insert("max", max);
+    }
+    if let Some(bucket_size) = options.bucket_size {
+        doc.insert("bucketSize", bucket_size);
+    }
+
+    doc
+}
+
+/// Deserializes options of the official driver from their representation
+/// in commands, which both drivers agree on.
+fn options_v2<O: DeserializeOwned>(options: Document) -> Result<O> {
+    bson2::from_document(document_to_v2(&options)?).map_err(Into::into)
+}
+
+/// Converts the options of an aggregation of the legacy driver to those of
+/// the official driver. The read preference is ignored.
+pub fn aggregate_options(options: &AggregateOptions) -> mongodb2::options::AggregateOptions {
+    let mut converted = mongodb2::options::AggregateOptions::default();
+
+    converted.allow_disk_use = options.allow_disk_use;
+    converted.batch_size = u32::try_from(options.batch_size).ok().filter(|&size| size > 0);
+    converted.max_time = max_time(options.max_time_ms);
+
+    converted
+}
+
+/// Converts the options of a find-and-update operation of the legacy driver
+/// to those of the official driver. The write concern is ignored.
+pub fn find_one_and_update_options(options: &FindOneAndUpdateOptions)
+    -> Result<mongodb2::options::FindOneAndUpdateOptions>
+{
+    let mut converted = mongodb2::options::FindOneAndUpdateOptions::default();
+
+    converted.return_document = options.return_document.as_ref().map(return_document);
+    converted.max_time = max_time(options.max_time_ms);
+    converted.projection = options.projection.as_ref().map(document_to_v2).transpose()?;
+    converted.sort = options.sort.as_ref().map(document_to_v2).transpose()?;
+    converted.upsert = options.upsert;
+
+    Ok(converted)
+}
+
+/// Converts which document a find-and-modify operation returns.
+fn return_document(which: &ReturnDocument) -> mongodb2::options::ReturnDocument {
+    match *which {
+        ReturnDocument::Before => mongodb2::options::ReturnDocument::Before,
+        ReturnDocument::After => mongodb2::options::ReturnDocument::After,
+    }
+}
+
+/// Converts a time limit in milliseconds, i.e. `maxTimeMS`, to a `Duration`.
+fn max_time(max_time_ms: Option<i64>) -> Option<Duration> {
+    max_time_ms.and_then(|ms| u64::try_from(ms).ok()).map(Duration::from_millis)
+}
+
+/// Methods augmenting the databases of the official driver, the counterpart
+/// of `db::DatabaseExt` for `SyncCollection`s.
+pub trait SyncDatabaseExt {
+    /// Returns an existing collection of `T`, named `T::NAME`.
+    fn existing_collection<T: Doc>(&self) -> SyncCollection<T>;
+
+    /// Returns an existing collection of `T` named `name` instead of
+    /// `T::NAME`, e.g. one prefixed by the name of a tenant.
+    fn existing_collection_named<T: Doc>(&self, name: &str) -> SyncCollection<T>;
+
+    /// Creates a fresh, empty collection. **Drops any existing collection
+    /// with the same name.** Also creates the indexes of `T`. Unlike
+    /// `DatabaseExt::empty_collection()`, it installs no `$jsonSchema`
+    /// validator.
+    fn empty_collection_novalidate<T: Doc>(&self) -> Result<SyncCollection<T>>;
+
+    /// Drops the collection of `T`, if it exists.
+    fn drop_collection_of<T: Doc>(&self) -> Result<()>;
+
+    /// Runs a database command, and deserializes its reply as `R`.
+    fn run_command_as<R: DeserializeOwned>(&self, command: Document) -> Result<R>;
+}
+
+impl SyncDatabaseExt for mongodb2::sync::Database {
+    fn existing_collection<T: Doc>(&self) -> SyncCollection<T> {
+        SyncCollection::new(self)
+    }
+
+    fn existing_collection_named<T: Doc>(&self, name: &str) -> SyncCollection<T> {
+        SyncCollection::named(self, name)
+    }
+
+    fn empty_collection_novalidate<T: Doc>(&self) -> Result<SyncCollection<T>> {
+        self.drop_collection_of::<T>()?;
+
+        let collection = self.existing_collection::<T>();
+        collection.create_indexes()?;
+
+        Ok(collection)
+    }
+
+    fn drop_collection_of<T: Doc>(&self) -> Result<()> {
+        self.collection::<bson2::Document>(T::NAME)
+            .drop(None)
+            .chain(|| format!("error dropping collection {}", T::NAME))
+    }
+
+    fn run_command_as<R: DeserializeOwned>(&self, command: Document) -> Result<R> {
+        let name = command.keys().next().cloned().unwrap_or_default();
+        let message = || format!("error in SyncDatabaseExt::run_command_as(`{}`)", name);
+        let reply = self
+            .run_command(document_to_v2(&command)?, None)
+            .chain(&message)?;
+
+        from_bson(document_from_v2(&reply)?.into()).chain(&message)
+    }
+}
+
+/// A collection of `T` accessed through the synchronous API of the official
+/// driver. See the module-level documentation for what is supported.
+pub struct SyncCollection<T: Doc> {
+    /// The backing collection of raw documents.
+    inner: mongodb2::sync::Collection<bson2::Document>,
+    /// Marks the type of the documents.
+    marker: PhantomData<T>,
+}
+
+impl<T: Doc> SyncCollection<T> {
+    /// Returns the collection of `T` in the database, named `T::NAME`.
+    pub fn new(db: &mongodb2::sync::Database) -> Self {
+        Self::named(db, T::NAME)
+    }
+
+    /// Returns the collection named `name` in the database.
+    pub fn named(db: &mongodb2::sync::Database, name: &str) -> Self {
+        SyncCollection {
+            inner: db.collection(name),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the backing collection of raw documents.
+    pub fn inner(&self) -> &mongodb2::sync::Collection<bson2::Document> {
+        &self.inner
+    }
+
+    /// Creates the indexes of `T`; see `Doc::indexes()`.
+    pub fn create_indexes(&self) -> Result<()> {
+        let message = || format!("can't create indexes on {}", T::NAME);
+        let models = T::indexes()
+            .iter()
+            .map(index_model)
+            .collect::<Result<Vec<_>>>()
+            .chain(&message)?;
+
+        if models.is_empty() {
+            return Ok(());
+        }
+
+        self.inner.create_indexes(models, None).map(drop).chain(&message)
+    }
+
+    /// Returns the number of documents matching the query criteria.
+    pub fn count<Q: Count<T>>(&self, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::count({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(renamed::<T>(query.filter())))?;
+        let options = query.options().with_default_max_time();
+        let mut doc = Document::new();
+
+        if let Some(skip) = options.skip.filter(|&skip| skip > 0) {
+            doc.insert("skip", skip);
+        }
+        if let Some(limit) = options.limit.filter(|&limit| limit > 0) {
+            doc.insert("limit", limit);
+        }
+        if let Some(max_time_ms) = options.max_time_ms {
+            doc.insert("maxTimeMS", max_time_ms);
+        }
+
+        let n = self.inner
+            .count_documents(filter, options_v2::<mongodb2::options::CountOptions>(doc)?)
+            .chain(&message)?;
+
+        count_to_usize(n, "# of documents")
+    }
+
+    /// Retrieves a single document satisfying the query, if one exists.
+    pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        let message = || format!("error in {}::find_one({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(renamed::<T>(query.filter())))?;
+        let find_options = query_options::<T, Q>(&query).with_default_max_time();
+        let options: mongodb2::options::FindOneOptions = options_v2(find_options_document(&find_options))?;
+        let raw = self.inner.find_one(filter, options).chain(&message)?;
+
+        match raw {
+            Some(doc) => loaded_from_v2::<T, Q>(&doc).map(Some).chain(&message),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieves all documents satisfying the query.
+    pub fn find_many<Q: Query<T>>(&self, query: Q) -> Result<Vec<Q::Output>> {
+        let message = || format!("error in {}::find_many({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(renamed::<T>(query.filter())))?;
+        let options = find_options(&query_options::<T, Q>(&query).with_default_max_time())?;
+
+        self.inner
+            .find(filter, options)
+            .chain(&message)?
+            .map(|item| item.chain(&message).and_then(|doc| loaded_from_v2::<T, Q>(&doc)))
+            .collect()
+    }
+
+    /// Retrieves all documents satisfying the query as raw BSON, to be
+    /// deserialized by `from_raw()`. The filter and the options of the query
+    /// are applied as by `find_many()`, but the documents are returned as
+    /// stored: the strict mode check, the decryption of encrypted fields,
+    /// the `after_load` hook and `Q::transform()` are all skipped.
+    ///
+    /// ```no_run
+    /// # #[macro_use]
+    /// # extern crate serde_derive;
+    /// # #[macro_use]
+    /// # extern crate avocado_derive;
+    /// # extern crate avocado;
+    /// # extern crate mongodb2;
+    /// #
+    /// # use avocado::prelude::*;
+    /// # use avocado::driver2::{ SyncCollection, from_raw };
+    /// #
+    /// # #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    /// # struct User {
+    /// #     _id: Uid<User>,
+    /// #     name: String,
+    /// # }
+    /// #
+    /// #[derive(Debug, Deserialize)]
+    /// struct UserName<'a> {
+    ///     name: &'a str,
+    /// }
+    ///
+    /// # fn main() -> AvocadoResult<()> {
+    /// # let client = mongodb2::sync::Client::with_uri_str("mongodb://localhost:27017/")?;
+    /// # let users: SyncCollection<User> = SyncCollection::new(&client.database("app"));
+    /// let raw = users.find_raw(doc!{})?;
+    /// let names = raw
+    ///     .iter()
+    ///     .map(|doc| from_raw(doc))
+    ///     .collect::<AvocadoResult<Vec<UserName>>>()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn find_raw<Q: Query<T>>(&self, query: Q) -> Result<Vec<RawDocumentBuf>> {
+        let message = || format!("error in {}::find_raw({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(renamed::<T>(query.filter())))?;
+        let options = find_options(&query_options::<T, Q>(&query).with_default_max_time())?;
+
+        self.inner
+            .clone_with_type::<RawDocumentBuf>()
+            .find(filter, options)
+            .chain(&message)?
+            .map(|doc| doc.chain(&message))
+            .collect()
+    }
+
+    /// Runs an aggregation pipeline, restricted to the documents which
+    /// aren't soft-deleted.
+    pub fn aggregate<P: Pipeline<T>>(&self, pipeline: P) -> Result<Vec<P::Output>> {
+        let message = || format!("error in {}::aggregate({:#?})", T::NAME, pipeline);
+        let stages = live_stages::<T>(pipeline.stages())
+            .iter()
+            .map(document_to_v2)
+            .collect::<Result<Vec<_>>>()?;
+        let options = aggregate_options(&pipeline.options().with_default_max_time());
+
+        self.inner
+            .aggregate(stages, options)
+            .chain(&message)?
+            .map(|item| {
+                let doc = document_from_v2(&item.chain(&message)?)?;
+                T::SERDE_PROFILE.deserialize(P::transform(doc)?)
+            })
+            .collect()
+    }
+
+    /// Inserts a single document. Returns its `_id`.
+    pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
+        let message = || format!("error in {}::insert_one()", T::NAME);
+        let result = self.inner
+            .insert_one(inserted_document(entity)?, None)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        from_bson(bson_from_v2(result.inserted_id)?).chain(
+            || format!("can't deserialize ID for {}", T::NAME)
+        )
+    }
+
+    /// Inserts many documents. Returns the `_id` of each document, keyed by
+    /// its index among `entities`.
+    pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
+        where I: IntoIterator,
+              I::Item: Borrow<T>,
+    {
+        let message = || format!("error in {}::insert_many()", T::NAME);
+        let docs = entities
+            .into_iter()
+            .map(|entity| inserted_document(entity.borrow()))
+            .collect::<Result<Vec<_>>>()?;
+
+        // MongoDB complains if you try to insert 0 documents, but that's silly.
+        if docs.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let result = self.inner
+            .insert_many(docs, None)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        result.inserted_ids
+            .into_iter()
+            .map(|(index, id)| {
+                let uid = from_bson(bson_from_v2(id)?).chain(
+                    || format!("can't deserialize ID for {}", T::NAME)
+                )?;
+                Ok((index as u64, uid))
+            })
+            .collect()
+    }
+
+    /// Updates a single document.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        let message = || format!("error in {}::update_one({:#?})", T::NAME, update);
+        let (filter, change, options) = update_parts::<T>(
+            update.filter(), update.update(), false, update.array_filters()
+        )?;
+        let result = self.inner
+            .update_one(filter, change, options)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        Ok(UpdateOneResult {
+            matched: result.matched_count > 0,
+            modified: result.modified_count > 0,
+        })
+    }
+
+    /// Updates all documents matching the query criteria.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        let message = || format!("error in {}::update_many({:#?})", T::NAME, update);
+        let (filter, change, options) = update_parts::<T>(
+            update.filter(), update.update(), false, update.array_filters()
+        )?;
+        let result = self.inner
+            .update_many(filter, change, options)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        Ok(UpdateManyResult {
+            num_matched: count_to_usize(result.matched_count, "# of matched documents")?,
+            num_modified: count_to_usize(result.modified_count, "# of modified documents")?,
+        })
+    }
+
+    /// Upserts a single document.
+    ///
+    /// This method only works with update operators (with field names starting
+    /// with `$`), i.e. it does **not** replace entire documents.
+    pub fn upsert_one<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertOneResult<Uid<T>>> {
+        let message = || format!("error in {}::upsert_one({:#?})", T::NAME, upsert);
+        let (filter, change, mut options) = update_parts::<T>(
+            upsert.filter(), upsert.upsert(), true, upsert.array_filters()
+        )?;
+
+        options.upsert = Some(true);
+
+        let result = self.inner
+            .update_one(filter, change, options)
+            .map_err(|error| write_error::<T>(message(), error))?;
+        let upserted_id = match result.upserted_id {
+            Some(id) => Some(from_bson(bson_from_v2(id)?).chain("can't deserialize upserted ID")?),
+            None => None,
+        };
+
+        Ok(UpsertOneResult {
+            matched: result.matched_count > 0,
+            modified: result.modified_count > 0,
+            upserted_id,
+        })
+    }
+
+    /// Upserts multiple documents (updates many or inserts one if none found).
+    ///
+    /// This method only works with update operators (with field names starting
+    /// with `$`), i.e. it does **not** replace entire documents.
+    pub fn upsert_many<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertManyResult> {
+        let message = || format!("error in {}::upsert_many({:#?})", T::NAME, upsert);
+        let (filter, change, mut options) = update_parts::<T>(
+            upsert.filter(), upsert.upsert(), true, upsert.array_filters()
+        )?;
+
+        options.upsert = Some(true);
+
+        let result = self.inner
+            .update_many(filter, change, options)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        Ok(UpsertManyResult {
+            num_matched: count_to_usize(result.matched_count, "# of matched documents")?,
+            num_modified: count_to_usize(result.modified_count, "# of modified documents")?,
+        })
+    }
+
+    /// Deletes (or soft-deletes) a single document. Returns `true` if one
+    /// was deleted.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        let message = || format!("error in {}::delete_one({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(deletion_filter::<T>(query.filter())?))?;
+
+        match soft_deletion::<T>()? {
+            Some(change) => self.inner
+                .update_one(filter, change, None)
+                .map(|result| result.matched_count > 0)
+                .chain(&message),
+            None => self.inner
+                .delete_one(filter, None)
+                .map(|result| result.deleted_count > 0)
+                .chain(&message),
+        }
+    }
+
+    /// Deletes (or soft-deletes) all documents matching the query criteria.
+    /// Returns the number of deleted documents.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        let message = || format!("error in {}::delete_many({:#?})", T::NAME, query);
+        let filter = document_to_v2(&live::<T>(deletion_filter::<T>(query.filter())?))?;
+        let n = match soft_deletion::<T>()? {
+            Some(change) => self.inner
+                .update_many(filter, change, None)
+                .map(|result| result.matched_count)
+                .chain(&message)?,
+            None => self.inner
+                .delete_many(filter, None)
+                .map(|result| result.deleted_count)
+                .chain(&message)?,
+        };
+
+        count_to_usize(n, "# of deleted documents")
+    }
+
+    /// Deletes (or soft-deletes) a single document based on the query
+    /// criteria, returning it as it was, if it was found.
+    pub fn find_one_and_delete<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        let message = || format!("error in {}::find_one_and_delete({:#?})", T::NAME, query);
+        let query_options = query_options::<T, Q>(&query).with_default_max_time();
+        let filter = document_to_v2(&live::<T>(deletion_filter::<T>(query.filter())?))?;
+        let projection = query_options.projection.as_ref().map(document_to_v2).transpose()?;
+        let sort = query_options.sort.as_ref().map(document_to_v2).transpose()?;
+
+        let raw = match soft_deletion::<T>()? {
+            Some(change) => {
+                let mut options = mongodb2::options::FindOneAndUpdateOptions::default();
+                options.return_document = Some(mongodb2::options::ReturnDocument::Before);
+                options.max_time = max_time(query_options.max_time_ms);
+                options.projection = projection;
+                options.sort = sort;
+                self.inner.find_one_and_update(filter, change, options)
+            }
+            None => {
+                let mut options = mongodb2::options::FindOneAndDeleteOptions::default();
+                options.max_time = max_time(query_options.max_time_ms);
+                options.projection = projection;
+                options.sort = sort;
+                self.inner.find_one_and_delete(filter, options)
+            }
+        };
+
+        match raw.chain(&message)? {
+            Some(doc) => loaded_from_v2::<T, Q>(&doc).map(Some).chain(&message),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces a single document based on the query criteria.
+    /// Returns the original document if found.
+    pub fn find_one_and_replace<Q: Query<T>>(&self, query: Q, replacement: &T) -> Result<Option<Q::Output>> {
+        let message = || format!("error in {}::find_one_and_replace({:#?})", T::NAME, query);
+        let query_options = query_options::<T, Q>(&query).with_default_max_time();
+        let filter = document_to_v2(&live::<T>(renamed::<T>(query.filter())))?;
+        let mut doc = serialize_entity(replacement)?;
+        stamp_replaced::<T>(&mut doc);
+        check_entity_document::<T>(&doc)?;
+
+        let mut options = mongodb2::options::FindOneAndReplaceOptions::default();
+        options.return_document = Some(mongodb2::options::ReturnDocument::Before);
+        options.max_time = max_time(query_options.max_time_ms);
+        options.projection = query_options.projection.as_ref().map(document_to_v2).transpose()?;
+        options.sort = query_options.sort.as_ref().map(document_to_v2).transpose()?;
+
+        let raw = self.inner
+            .find_one_and_replace(filter, document_to_v2(&doc)?, options)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        match raw {
+            Some(document) => loaded_from_v2::<T, Q>(&document).map(Some).chain(&message),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds a single document based on query criteria and updates it.
+    /// The options returned by the `update` argument decide whether an
+    /// update or an upsert happens, like in `Collection::find_one_and_update()`.
+    pub fn find_one_and_update<U: FindAndUpdate<T>>(&self, update: U) -> Result<Option<U::Output>> {
+        let message = || format!("error in {}::find_one_and_update({:#?})", T::NAME, update);
+        let filter = document_to_v2(&live::<T>(renamed::<T>(update.filter())))?;
+        let mut options = update.options().with_default_max_time();
+        let change = update_document::<T>(update.update(), options.upsert.unwrap_or(false))?;
+        let projection = update.projection();
+        let sort = update.sort();
+
+        if !projection.is_empty() {
+            options.projection = Some(projection.into());
+        }
+        if !sort.is_empty() {
+            options.sort = Some(sort.into());
+        }
+
+        options.sort = options.sort.map(renamed::<T>);
+        options.projection = options.projection.map(renamed::<T>);
+
+        let raw = self.inner
+            .find_one_and_update(filter, document_to_v2(&change)?, find_one_and_update_options(&options)?)
+            .map_err(|error| write_error::<T>(message(), error))?;
+
+        match raw {
+            Some(doc) => {
+                let transformed = loaded::<T>(document_from_v2(&doc)?).and_then(U::transform)?;
+                T::SERDE_PROFILE.deserialize(transformed).map(Some).chain(&message)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T: Doc> Clone for SyncCollection<T> {
+    fn clone(&self) -> Self {
+        SyncCollection {
+            inner: self.inner.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc> Debug for SyncCollection<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("SyncCollection")
+            .field("name", &self.inner.name())
+            .finish()
+    }
+}
+
+/// Converts a document read from the collection to the output of a query,
+/// the same way `Collection` does.
+fn loaded_from_v2<T: Doc, Q: Query<T>>(doc: &bson2::Document) -> Result<Q::Output> {
+    let transformed = strict_transform::<T, Q>(document_from_v2(doc)?)?;
+    T::SERDE_PROFILE.deserialize(transformed)
+}
+
+/// Serializes an entity to be inserted, the same way `Collection` does.
+fn inserted_document<T: Doc>(entity: &T) -> Result<bson2::Document> {
+    let mut doc = serialize_entity(entity)?;
+    generate_id::<T>(&mut doc)?;
+    stamp_inserted::<T>(&mut doc);
+    check_entity_document::<T>(&doc)?;
+    document_to_v2(&doc)
+}
+
+/// Returns the filter, the update document and the options of an update or
+/// an upsert, with the field names translated and the hooks of `T` applied.
+fn update_parts<T: Doc>(
+    filter: Document,
+    update: Document,
+    upsert: bool,
+    array_filters: ArrayFilters,
+) -> Result<(bson2::Document, bson2::Document, mongodb2::options::UpdateOptions)> {
+    let mut options = mongodb2::options::UpdateOptions::default();
+
+    if !array_filters.is_empty() {
+        let filters = renamed_array_filters::<T>(array_filters)
+            .filters()
+            .iter()
+            .map(document_to_v2)
+            .collect::<Result<Vec<_>>>()?;
+
+        options.array_filters = Some(filters);
+    }
+
+    Ok((
+        document_to_v2(&live::<T>(renamed::<T>(filter)))?,
+        document_to_v2(&update_document::<T>(update, upsert)?)?,
+        options,
+    ))
+}
+
+/// Converts a count reported by the official driver to a `usize`.
+fn count_to_usize(n: u64, msg: &str) -> Result<usize> {
+    let signed = i64::try_from(n).map_err(|_| Error::new(
+        ErrorKind::IntConversionOverflow,
+        format!("{} ({}) overflows `i64`", msg, n)
+    ))?;
+
+    int_to_usize_with_msg(signed, msg)
+}
+
+/// Converts a failed write to an error. Like in `Collection`, duplicate key
+/// errors on a unique index declared by `T::indexes()` become
+/// `UniqueViolation` errors.
+fn write_error<T: Doc>(message: String, error: mongodb2::error::Error) -> Error {
+    use mongodb2::error::{ ErrorKind as Kind, WriteFailure };
+
+    let violation = match *error.kind {
+        Kind::Write(WriteFailure::WriteError(ref e)) => unique_violation::<T>(&message, e.code, &e.message),
+        Kind::BulkWrite(ref e) => e.write_errors.iter().flatten().find_map(
+            |failure| unique_violation::<T>(&message, failure.code, &failure.message)
+        ),
+        _ => None,
+    };
+    let reported = server_error(&error);
+    let converted = violation.unwrap_or_else(|| Error::with_cause(message, error));
+
+    match reported {
+        Some(context) => converted.with_context::<ServerInfo>(ServerInfo {
+            collection: Some(T::NAME.into()),
+            ..context
+        }),
+        None => converted,
+    }
+}
+
+/// Returns the update marking documents of `T` as deleted, if `T` is
+/// soft-deleted.
+fn soft_deletion<T: Doc>() -> Result<Option<bson2::Document>> {
+    match T::deleted_at_field() {
+        Some(field) => document_to_v2(&doc!{ "$currentDate": { field: true } }).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Extracts the error reported by the server, if any.
+fn server_error(error: &mongodb2::error::Error) -> Option<ServerInfo> {
+    use mongodb2::error::{ ErrorKind as Kind, WriteFailure };
+
+    let write_error_info = |code, code_name: Option<&String>, message: &String| WriteErrorInfo {
+        index: None,
+        code,
+        code_name: code_name.cloned(),
+        message: message.clone(),
+    };
+
+    match *error.kind {
+        Kind::Command(ref e) => Some(ServerInfo {
+            code: Some(e.code),
+            code_name: Some(e.code_name.clone()),
+            message: e.message.clone(),
+            ..ServerInfo::default()
+        }),
+        Kind::Write(WriteFailure::WriteError(ref e)) => Some(ServerInfo {
+            message: e.message.clone(),
+            write_errors: vec![write_error_info(e.code, e.code_name.as_ref(), &e.message)],
+            ..ServerInfo::default()
+        }),
+        Kind::Write(WriteFailure::WriteConcernError(ref e)) => Some(ServerInfo {
+            message: e.message.clone(),
+            write_concern_error: Some(write_error_info(e.code, Some(&e.code_name), &e.message)),
+            ..ServerInfo::default()
+        }),
+        Kind::BulkWrite(ref e) => Some(ServerInfo {
+            write_errors: e.write_errors.iter().flatten().map(|write_error| WriteErrorInfo {
+                index: Some(write_error.index),
+                ..write_error_info(write_error.code, write_error.code_name.as_ref(), &write_error.message)
+            }).collect(),
+            write_concern_error: e.write_concern_error.as_ref().map(
+                |wce| write_error_info(wce.code, Some(&wce.code_name), &wce.message)
+            ),
+            ..ServerInfo::default()
+        }),
+        _ => None,
+    }
+}
+
+impl From<mongodb2::error::Error> for Error {
+    fn from(error: mongodb2::error::Error) -> Self {
+        let reported = server_error(&error);
+        let converted = Self::with_cause("MongoDB error", error);
+
+        match reported {
+            Some(context) => converted.with_context::<ServerInfo>(context),
+            None => converted,
+        }
+    }
+}
+
+impl ErrorExt for mongodb2::error::Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::MongoDbError
+    }
+
+    fn as_std_error(&self) -> &(dyn StdError + 'static) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use mongodb::coll::options::{
+        IndexModel, IndexOptions, AggregateOptions, FindOneAndUpdateOptions, ReturnDocument,
+    };
+    use bson2::RawDocumentBuf;
+    use super::{
+        document_to_v2, document_from_v2, index_model, from_raw,
+        aggregate_options, find_one_and_update_options,
+    };
+
+    #[test]
+    fn document_round_trip() {
+        let doc = doc!{
+            "name": "foo",
+            "count": 42_i64,
+            "ratio": 0.5,
+            "inner": { "tags": ["a", "b"], "flag": true },
+            "nothing": null,
+        };
+        let converted = document_to_v2(&doc).unwrap();
+
+        assert_eq!(converted.get_str("name").unwrap(), "foo");
+        assert_eq!(converted.get_i64("count").unwrap(), 42);
+        assert_eq!(document_from_v2(&converted).unwrap(), doc);
+    }
+
+    #[test]
+    fn borrowed_from_raw() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Entry<'a> {
+            name: &'a str,
+            tags: Vec<&'a str>,
+            count: i64,
+        }
+
+        let doc = bson2::doc!{
+            "name": "foo",
+            "tags": ["a", "b"],
+            "count": 42_i64,
+            "extra": { "ignored": true },
+        };
+        let raw = RawDocumentBuf::from_document(&doc).unwrap();
+        let entry: Entry = from_raw(&raw).unwrap();
+
+        assert_eq!(entry, Entry { name: "foo", tags: vec!["a", "b"], count: 42 });
+    }
+
+    #[test]
+    fn index_model_options() {
+        let model = IndexModel {
+            keys: doc!{ "email": 1 },
+            options: IndexOptions {
+                name: Some(String::from("email_unique")),
+                unique: Some(true),
+                expire_after_seconds: Some(60),
+                ..Default::default()
+            },
+        };
+        let converted = index_model(&model).unwrap();
+        let options = converted.options.expect("no options");
+
+        assert_eq!(converted.keys, bson2::doc!{ "email": 1 });
+        assert_eq!(options.name.as_ref().map(String::as_str), Some("email_unique"));
+        assert_eq!(options.unique, Some(true));
+        assert_eq!(options.expire_after.map(|d| d.as_secs()), Some(60));
+    }
+
+    #[test]
+    fn aggregate_and_find_and_modify_options() {
+        let options = aggregate_options(&AggregateOptions {
+            allow_disk_use: Some(true),
+            batch_size: 0,
+            max_time_ms: Some(1500),
+            ..AggregateOptions::new()
+        });
+
+        assert_eq!(options.allow_disk_use, Some(true));
+        assert_eq!(options.batch_size, None);
+        assert_eq!(options.max_time, Some(Duration::from_millis(1500)));
+
+        let options = find_one_and_update_options(&FindOneAndUpdateOptions {
+            return_document: Some(ReturnDocument::After),
+            max_time_ms: Some(-1),
+            sort: Some(doc!{ "age": -1 }),
+            upsert: Some(true),
+            ..FindOneAndUpdateOptions::new()
+        }).unwrap();
+
+        assert!(match options.return_document {
+            Some(mongodb2::options::ReturnDocument::After) => true,
+            _ => false,
+        });
+        assert_eq!(options.max_time, None);
+        assert_eq!(options.sort, Some(bson2::doc!{ "age": -1 }));
+        assert_eq!(options.projection, None);
+        assert_eq!(options.upsert, Some(true));
+    }
+}
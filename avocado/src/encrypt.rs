@@ -0,0 +1,465 @@
+//! Client-side encryption of individual fields.
+//!
+//! Fields of a `Doc` annotated with `#[avocado(encrypted)]` are encrypted by
+//! `Collection` before documents are sent to the server, and decrypted after
+//! they are read, so the server, its logs and its backups only ever see the
+//! ciphertext. Encryption is performed by the [`FieldCipher`](trait.FieldCipher.html)
+//! installed process-wide using [`set_cipher()`](fn.set_cipher.html); with
+//! the `encryption` feature, [`AesGcmCipher`](struct.AesGcmCipher.html)
+//! implements it using AES-256-GCM and the keys of a caller-provided
+//! [`KeyProvider`](trait.KeyProvider.html).
+//!
+//! Encrypted values are stored as binary values of subtype `0x80`, so the
+//! server can't compare them, and they can't be indexed or queried, except
+//! for fields encrypted in deterministic mode, i.e. annotated with
+//! `#[avocado(encrypted = "deterministic")]`. The same value of such a field
+//! always has the same ciphertext, so equality filters work if their operand
+//! is encrypted using [`filter_value()`](fn.filter_value.html):
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::encrypt::filter_value;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct Patient {
+//!     _id: Uid<Patient>,
+//!     name: String,
+//!     #[avocado(encrypted = "deterministic")]
+//!     ssn: String,
+//!     #[avocado(encrypted)]
+//!     diagnosis: Option<String>,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! # let patients: Collection<Patient> = db.existing_collection();
+//! let filter = doc!{ "ssn": filter_value::<Patient, _>("ssn", "078-05-1120")? };
+//! let patient = patients.find_one(filter)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Deterministic encryption reveals which documents share a value, so it
+//! should only be used for fields that need to be looked up by equality.
+//! Null values, e.g. of `Option` fields which are `None`, are stored as-is.
+//! Besides whole documents, the values of encrypted fields set by the
+//! `$set` and `$setOnInsert` operators of updates are encrypted; other
+//! update operators can't be applied to encrypted fields.
+
+use std::fmt::Debug;
+use std::sync::{ Arc, RwLock };
+use bson::{ Bson, Document, spec::BinarySubtype };
+use crate::{
+    doc::Doc,
+    bsn::{ write_document, read_document },
+    error::{ Error, ErrorKind, Result },
+};
+
+/// The binary subtype of encrypted values. Subtypes from `0x80` up are
+/// reserved for user-defined data.
+pub const ENCRYPTED_SUBTYPE: u8 = 0x80;
+
+/// The key of the single field of the document wrapping a plaintext value,
+/// so that its BSON type is preserved across encryption.
+const VALUE_KEY: &str = "v";
+
+/// How the values of an encrypted field are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EncryptionMode {
+    /// Encrypting the same value twice results in different ciphertexts.
+    /// This is the safer choice, but the field can't be filtered on.
+    Randomized,
+    /// Encrypting the same value always results in the same ciphertext,
+    /// so that equality filters and unique indexes work on the field.
+    Deterministic,
+}
+
+/// An encrypted field of a document type; see `Doc::encrypted_fields()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EncryptedField {
+    /// The name of the field in the stored document.
+    pub name: &'static str,
+    /// How the values of the field are encrypted.
+    pub mode: EncryptionMode,
+}
+
+/// An authenticated cipher encrypting and decrypting the values of fields.
+pub trait FieldCipher: Debug + Send + Sync {
+    /// Encrypts a serialized value. The result must contain everything
+    /// needed by `decrypt()` apart from the keys, e.g. the nonce and the ID
+    /// of the key used. In `Deterministic` mode, the result must only depend
+    /// on the plaintext and the key.
+    fn encrypt(&self, plaintext: &[u8], mode: EncryptionMode) -> Result<Vec<u8>>;
+
+    /// Decrypts a ciphertext produced by `encrypt()`, in either mode. Must
+    /// fail if the ciphertext has been tampered with.
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+lazy_static! {
+    /// The process-wide cipher, if one is installed.
+    static ref CIPHER: RwLock<Option<Arc<dyn FieldCipher>>> = RwLock::new(None);
+}
+
+/// Installs the cipher used for encrypting and decrypting fields from now
+/// on, replacing the previous one.
+pub fn set_cipher<C: FieldCipher + 'static>(cipher: C) {
+    let mut installed = CIPHER.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *installed = Some(Arc::new(cipher));
+}
+
+/// Uninstalls the cipher. Reading or writing documents with encrypted
+/// fields fails afterwards, until another one is installed.
+pub fn clear_cipher() {
+    let mut installed = CIPHER.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *installed = None;
+}
+
+/// Returns the installed cipher.
+fn cipher() -> Result<Arc<dyn FieldCipher>> {
+    let installed = CIPHER.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    installed.clone().ok_or_else(|| Error::new(
+        ErrorKind::Encryption,
+        "no field cipher installed; call `avocado::encrypt::set_cipher()` first"
+    ))
+}
+
+/// Encrypts a single value using the installed cipher. `Null` is returned
+/// as-is.
+pub fn encrypted_value(value: &Bson, mode: EncryptionMode) -> Result<Bson> {
+    if *value == Bson::Null {
+        return Ok(Bson::Null);
+    }
+
+    let mut plaintext = Vec::new();
+    let mut wrapper = Document::new();
+
+    wrapper.insert(VALUE_KEY, value.clone());
+    write_document(&mut plaintext, &wrapper)?;
+
+    let ciphertext = cipher()?.encrypt(&plaintext, mode)?;
+
+    Ok(Bson::Binary(BinarySubtype::UserDefined(ENCRYPTED_SUBTYPE), ciphertext))
+}
+
+/// Decrypts a value produced by `encrypted_value()`. `Null` is returned as-is,
+/// but any other value which isn't encrypted results in an error, since it
+/// must have been written bypassing the encryption.
+pub fn decrypt_value(value: &Bson) -> Result<Bson> {
+    let ciphertext = match *value {
+        Bson::Null => return Ok(Bson::Null),
+        Bson::Binary(BinarySubtype::UserDefined(ENCRYPTED_SUBTYPE), ref bytes) => bytes,
+        _ => return Err(Error::new(
+            ErrorKind::Encryption,
+            format!("expected an encrypted value, found `{}`", value)
+        )),
+    };
+    let plaintext = cipher()?.decrypt(ciphertext)?;
+    let mut wrapper = read_document(&mut plaintext.as_slice())?.ok_or_else(
+        || Error::new(ErrorKind::Encryption, "decrypted value is empty")
+    )?;
+
+    wrapper.remove(VALUE_KEY).ok_or_else(
+        || Error::new(ErrorKind::Encryption, "decrypted value is malformed")
+    )
+}
+
+/// Encrypts `value` as the field `field` of `T` is encrypted, for use as the
+/// operand of an equality filter, e.g. `$eq` or `$in`. Fails if the field
+/// isn't encrypted deterministically, since nothing would match otherwise.
+pub fn filter_value<T, V>(field: &str, value: V) -> Result<Bson>
+    where T: Doc,
+          V: Into<Bson>,
+{
+    match encrypted_field::<T>(field) {
+        Some(EncryptionMode::Deterministic) => encrypted_value(&value.into(), EncryptionMode::Deterministic),
+        Some(EncryptionMode::Randomized) => Err(Error::new(
+            ErrorKind::Encryption,
+            format!("field `{}` of {} is encrypted randomly, so it can't be filtered on", field, T::NAME)
+        )),
+        None => Err(Error::new(
+            ErrorKind::Encryption,
+            format!("field `{}` of {} isn't encrypted", field, T::NAME)
+        )),
+    }
+}
+
+/// Returns the encryption mode of a field of `T`, if it is encrypted.
+fn encrypted_field<T: Doc>(name: &str) -> Option<EncryptionMode> {
+    T::encrypted_fields()
+        .iter()
+        .find(|field| field.name == name)
+        .map(|field| field.mode)
+}
+
+/// Encrypts the encrypted fields of `T` in a document about to be written.
+pub(crate) fn encrypt_fields<T: Doc>(doc: &mut Document) -> Result<()> {
+    for field in T::encrypted_fields() {
+        if let Some(value) = doc.get_mut(field.name) {
+            *value = encrypted_value(value, field.mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decrypts the encrypted fields of `T` in a document read from the server.
+pub(crate) fn decrypt_fields<T: Doc>(doc: &mut Document) -> Result<()> {
+    for field in T::encrypted_fields() {
+        if let Some(value) = doc.get_mut(field.name) {
+            *value = decrypt_value(value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Encrypts the values of the encrypted fields of `T` set by an update.
+/// Fails if the update applies any other operator to an encrypted field.
+pub(crate) fn encrypt_update<T: Doc>(update: &mut Document) -> Result<()> {
+    if T::encrypted_fields().is_empty() {
+        return Ok(());
+    }
+
+    let operators: Vec<_> = update.keys().cloned().collect();
+
+    for operator in operators {
+        let fields = match update.get_mut(&operator) {
+            Some(&mut Bson::Document(ref mut fields)) => fields,
+            _ => continue,
+        };
+
+        if operator == "$set" || operator == "$setOnInsert" {
+            encrypt_fields::<T>(fields)?;
+        } else if let Some(field) = T::encrypted_fields().iter().find(
+            |field| fields.contains_key(field.name)
+        ) {
+            return Err(Error::new(
+                ErrorKind::Encryption,
+                format!("`{}` can't be applied to encrypted field `{}` of {}", operator, field.name, T::NAME)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+pub use self::aes::{ AesGcmCipher, KeyProvider, Key };
+
+/// The AES-256-GCM implementation of `FieldCipher`.
+#[cfg(feature = "encryption")]
+mod aes {
+    use std::fmt::Debug;
+    use aes_gcm::Aes256Gcm;
+    use aes_gcm::aead::{ Aead, NewAead, generic_array::GenericArray };
+    use hmac::{ Hmac, Mac };
+    use sha2::Sha256;
+    use crate::error::{ Error, ErrorKind, Result };
+    use super::{ FieldCipher, EncryptionMode };
+
+    /// A 256-bit key.
+    pub type Key = [u8; 32];
+
+    /// The version of the ciphertext format.
+    const FORMAT_VERSION: u8 = 1;
+
+    /// The length of the header: the format version and the key ID.
+    const HEADER_LEN: usize = 5;
+
+    /// The length of a nonce.
+    const NONCE_LEN: usize = 12;
+
+    /// Supplies the keys used by `AesGcmCipher`, e.g. from a key management
+    /// service. Every key has an ID, which is stored with the ciphertexts,
+    /// so that keys can be rotated: new values are encrypted using the
+    /// current key, while old ones remain readable as long as their key is
+    /// provided.
+    pub trait KeyProvider: Debug + Send + Sync {
+        /// Returns the ID of the key used for encrypting new values, and the
+        /// key itself.
+        fn current_key(&self) -> Result<(u32, Key)>;
+
+        /// Returns the key with the given ID.
+        fn key(&self, id: u32) -> Result<Key>;
+    }
+
+    /// Encrypts fields using AES-256-GCM. A ciphertext consists of a format
+    /// version byte, the big-endian ID of the key, a 96-bit nonce, and the
+    /// encrypted value followed by the authentication tag. In randomized
+    /// mode, the nonce is random. In deterministic mode, it is derived from
+    /// the plaintext by HMAC-SHA-256, keyed by a subkey of the current key,
+    /// i.e. as a synthetic IV. Deterministic ciphertexts change when the
+    /// current key is rotated, so values encrypted with the old key must be
+    /// rewritten for equality filters to keep matching them.
+    #[derive(Debug)]
+    pub struct AesGcmCipher<K> {
+        /// The source of the keys.
+        keys: K,
+    }
+
+    impl<K: KeyProvider> AesGcmCipher<K> {
+        /// Creates a cipher using the keys supplied by `keys`.
+        pub fn new(keys: K) -> Self {
+            AesGcmCipher { keys }
+        }
+    }
+
+    impl<K: KeyProvider> FieldCipher for AesGcmCipher<K> {
+        fn encrypt(&self, plaintext: &[u8], mode: EncryptionMode) -> Result<Vec<u8>> {
+            let (key_id, key) = self.keys.current_key()?;
+            let nonce: [u8; NONCE_LEN] = match mode {
+                EncryptionMode::Randomized => rand::random(),
+                EncryptionMode::Deterministic => synthetic_nonce(&key, plaintext),
+            };
+            let ciphertext = Aes256Gcm::new(GenericArray::from_slice(&key))
+                .encrypt(GenericArray::from_slice(&nonce), plaintext)
+                .map_err(|_| Error::new(ErrorKind::Encryption, "AES-GCM encryption failed"))?;
+            let mut output = Vec::with_capacity(HEADER_LEN + NONCE_LEN + ciphertext.len());
+
+            output.push(FORMAT_VERSION);
+            output.extend_from_slice(&key_id.to_be_bytes());
+            output.extend_from_slice(&nonce);
+            output.extend_from_slice(&ciphertext);
+
+            Ok(output)
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+            if ciphertext.len() < HEADER_LEN + NONCE_LEN || ciphertext[0] != FORMAT_VERSION {
+                return Err(Error::new(ErrorKind::Encryption, "malformed AES-GCM ciphertext"));
+            }
+
+            let mut key_id = [0_u8; 4];
+            key_id.copy_from_slice(&ciphertext[1..HEADER_LEN]);
+
+            let key = self.keys.key(u32::from_be_bytes(key_id))?;
+            let nonce = &ciphertext[HEADER_LEN..HEADER_LEN + NONCE_LEN];
+
+            Aes256Gcm::new(GenericArray::from_slice(&key))
+                .decrypt(GenericArray::from_slice(nonce), &ciphertext[HEADER_LEN + NONCE_LEN..])
+                .map_err(|_| Error::new(
+                    ErrorKind::Encryption,
+                    "AES-GCM decryption failed: wrong key or corrupt ciphertext"
+                ))
+        }
+    }
+
+    /// Derives the nonce of a deterministically encrypted plaintext. The
+    /// HMAC key is itself derived from the encryption key, so that the same
+    /// key isn't used for two different primitives.
+    fn synthetic_nonce(key: &Key, plaintext: &[u8]) -> [u8; NONCE_LEN] {
+        let mut mac_key = hmac_sha256(key, b"avocado field encryption SIV key");
+        let tag = hmac_sha256(&mac_key, plaintext);
+        let mut nonce = [0_u8; NONCE_LEN];
+
+        nonce.copy_from_slice(&tag[..NONCE_LEN]);
+        mac_key.iter_mut().for_each(|byte| *byte = 0);
+
+        nonce
+    }
+
+    /// Computes the HMAC-SHA-256 of `data` with `key`.
+    fn hmac_sha256(key: &[u8], data: &[u8]) -> Key {
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+        let mut output = [0_u8; 32];
+
+        mac.input(data);
+        output.copy_from_slice(&mac.result().code());
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, spec::BinarySubtype };
+    use crate::error::{ Error, ErrorKind, ErrorExt, Result };
+    use super::*;
+
+    /// A toy cipher which XORs every byte, for testing the plumbing only.
+    #[derive(Debug, Clone, Copy)]
+    struct Xor;
+
+    impl FieldCipher for Xor {
+        fn encrypt(&self, plaintext: &[u8], _mode: EncryptionMode) -> Result<Vec<u8>> {
+            Ok(plaintext.iter().map(|byte| byte ^ 0x5a).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+            if ciphertext.is_empty() {
+                return Err(Error::new(ErrorKind::Encryption, "empty ciphertext"));
+            }
+            Ok(ciphertext.iter().map(|byte| byte ^ 0x5a).collect())
+        }
+    }
+
+    #[test]
+    fn values_and_fields() {
+        set_cipher(Xor);
+
+        let value = Bson::from(vec![Bson::I32(1), Bson::String("two".into())]);
+        let encrypted = encrypted_value(&value, EncryptionMode::Randomized).unwrap();
+
+        match encrypted {
+            Bson::Binary(BinarySubtype::UserDefined(ENCRYPTED_SUBTYPE), _) => {}
+            _ => panic!("unexpected encrypted value: {}", encrypted),
+        }
+
+        assert_eq!(decrypt_value(&encrypted).unwrap(), value);
+        assert_eq!(encrypted_value(&Bson::Null, EncryptionMode::Deterministic).unwrap(), Bson::Null);
+        assert_eq!(decrypt_value(&Bson::Null).unwrap(), Bson::Null);
+        assert_eq!(decrypt_value(&Bson::I32(3)).unwrap_err().kind(), ErrorKind::Encryption);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn aes_gcm_cipher() {
+        #[derive(Debug)]
+        struct Keys;
+
+        impl KeyProvider for Keys {
+            fn current_key(&self) -> Result<(u32, Key)> {
+                Ok((2, [2; 32]))
+            }
+
+            fn key(&self, id: u32) -> Result<Key> {
+                match id {
+                    1 => Ok([1; 32]),
+                    2 => Ok([2; 32]),
+                    _ => Err(Error::new(ErrorKind::Encryption, format!("no key {}", id))),
+                }
+            }
+        }
+
+        let cipher = AesGcmCipher::new(Keys);
+        let plaintext = b"078-05-1120";
+
+        let random1 = cipher.encrypt(plaintext, EncryptionMode::Randomized).unwrap();
+        let random2 = cipher.encrypt(plaintext, EncryptionMode::Randomized).unwrap();
+        assert_ne!(random1, random2);
+        assert_eq!(cipher.decrypt(&random1).unwrap(), plaintext);
+
+        let fixed1 = cipher.encrypt(plaintext, EncryptionMode::Deterministic).unwrap();
+        let fixed2 = cipher.encrypt(plaintext, EncryptionMode::Deterministic).unwrap();
+        assert_eq!(fixed1, fixed2);
+        assert_eq!(cipher.decrypt(&fixed1).unwrap(), plaintext);
+
+        let mut tampered = fixed1.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(cipher.decrypt(&tampered).unwrap_err().kind(), ErrorKind::Encryption);
+
+        let mut unknown_key = fixed1.clone();
+        unknown_key[4] = 3;
+        assert!(cipher.decrypt(&unknown_key).is_err());
+    }
+}
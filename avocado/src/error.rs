@@ -4,14 +4,16 @@ use std::fmt;
 use std::error;
 use std::result;
 use std::ops::Deref;
+use std::convert::TryFrom;
 use std::borrow::Cow;
 use bson::ValueAccessError;
 use backtrace::Backtrace;
-use typemap::{ DebugMap, Key };
+use typemap::{ ShareDebugMap, Key };
+use crate::utils::int_to_usize_with_msg;
 
 /// Slightly augmented trait for backtrace-able errors.
 #[allow(clippy::stutter)]
-pub trait ErrorExt: error::Error {
+pub trait ErrorExt: error::Error + Send + Sync {
     /// Similar to `std::error::Error::source()`, but with richer type info.
     fn reason(&self) -> Option<&(dyn ErrorExt + 'static)> {
         None
@@ -123,6 +125,65 @@ pub enum ErrorKind {
     IntConversionOverflow,
     /// There was an error in the BSON schema for a type.
     BsonSchema,
+    /// A document exceeds the maximal allowed size when encoded as BSON.
+    DocumentTooLarge,
+    /// A document written through a scoped collection would fall outside
+    /// of the scope.
+    ScopeViolation,
+    /// An operation was rejected because the circuit breaker is open.
+    CircuitOpen,
+    /// A document read in strict mode contains a field unknown to its type.
+    UnknownField,
+    /// The collation of an existing collection differs from the expected one.
+    CollationMismatch,
+    /// An I/O error occurred, e.g. while reading or writing an archive.
+    Io,
+    /// A write was rejected because it would violate a unique index declared
+    /// by the document type. The name of the index is available via
+    /// `error.context::<UniqueViolation>()`.
+    UniqueViolation,
+    /// An update or deletion doesn't include the shard key of a collection
+    /// declared as strictly sharded.
+    UntargetedWrite,
+    /// A regular expression pattern is syntactically invalid.
+    InvalidRegex,
+    /// An operation was abandoned before it could produce a result.
+    Canceled,
+    /// Migrations are already being run by another process.
+    MigrationLocked,
+    /// A versioned document was modified or deleted by someone else since
+    /// it was read, so a write based on the old version was rejected.
+    StaleVersion,
+    /// A set of migrations is inconsistent, e.g. two of them have the same
+    /// version, or an applied migration is unknown.
+    InvalidMigration,
+    /// An aggregation pipeline is malformed, e.g. it writes its output to
+    /// a collection other than the expected one.
+    InvalidPipeline,
+    /// An operator in a filter or update document can't be evaluated
+    /// client-side, e.g. by `matcher::matches()` or a `mock::Collection`.
+    UnsupportedOperator,
+    /// A filter document is malformed, e.g. it contains an empty `$and` or
+    /// an unknown operator. The individual problems are available via
+    /// `error.context::<InvalidFilter>()`.
+    InvalidFilter,
+    /// An update document is malformed, e.g. it mixes update operators
+    /// with plain fields.
+    InvalidUpdate,
+    /// A continuation token of keyset pagination is malformed, or it was
+    /// issued for a different sort order.
+    InvalidPageToken,
+    /// An encrypted field couldn't be encrypted or decrypted, e.g. because
+    /// no cipher is installed, a key is missing, or a ciphertext is corrupt.
+    Encryption,
+    /// The key of a tenant can't be used in database and collection names.
+    InvalidTenant,
+    /// A fixture file is malformed, e.g. it contains a reference to another
+    /// fixture which isn't of the form `"collection/label"`.
+    InvalidFixture,
+    /// A document to be written contains a field name which the server
+    /// would reject, i.e. one containing a `.` or starting with a `$`.
+    InvalidFieldName,
 }
 
 impl ErrorKind {
@@ -145,6 +206,28 @@ impl ErrorKind {
             IntConversionUnderflow    => "integer conversion underflowed",
             IntConversionOverflow     => "integer conversion overflowed",
             BsonSchema                => "error in BSON schema",
+            DocumentTooLarge          => "document too large",
+            ScopeViolation            => "document outside of collection scope",
+            CircuitOpen               => "circuit breaker open",
+            UnknownField              => "unknown document field",
+            CollationMismatch         => "collation mismatch",
+            Io                        => "I/O error",
+            UniqueViolation           => "unique constraint violated",
+            UntargetedWrite           => "write not targeted by shard key",
+            InvalidRegex              => "invalid regular expression",
+            Canceled                  => "operation canceled",
+            MigrationLocked           => "migrations locked by another run",
+            StaleVersion              => "stale document version",
+            InvalidMigration          => "invalid migration",
+            InvalidPipeline           => "invalid aggregation pipeline",
+            UnsupportedOperator       => "unsupported operator",
+            InvalidFilter             => "invalid filter",
+            InvalidUpdate             => "invalid update",
+            InvalidPageToken          => "invalid page token",
+            Encryption                => "field encryption error",
+            InvalidTenant             => "invalid tenant key",
+            InvalidFixture            => "invalid fixture",
+            InvalidFieldName          => "invalid field name",
         }
     }
 }
@@ -155,7 +238,250 @@ impl fmt::Display for ErrorKind {
     }
 }
 
-/// The central error type for Avocado.
+/// Context info of `ErrorKind::UniqueViolation` errors.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UniqueViolation {
+    /// The name of the violated unique index, e.g. `"email_unique"`.
+    pub constraint: String,
+}
+
+impl Key for UniqueViolation {
+    type Value = Self;
+}
+
+/// A single problem found in a filter document by `filter::validate()`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FilterProblem {
+    /// The dotted path of the offending key within the filter, e.g.
+    /// `"$or.1.age.$size"`.
+    pub path: String,
+    /// What is wrong with it.
+    pub message: String,
+}
+
+/// Context info of `ErrorKind::InvalidFilter` errors.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct InvalidFilter {
+    /// All problems found in the filter, in document order.
+    pub problems: Vec<FilterProblem>,
+}
+
+impl Key for InvalidFilter {
+    type Value = Self;
+}
+
+/// The server error code of duplicate key errors.
+pub const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+/// The server error codes of operations which exceeded a time limit.
+pub const TIMEOUT_ERROR_CODES: &[i32] = &[
+    50,    // MaxTimeMSExpired
+    89,    // NetworkTimeout
+    262,   // ExceededTimeLimit
+];
+
+/// The server error codes signalling transient conditions, as listed by the
+/// retryable writes specification of MongoDB.
+pub const TRANSIENT_ERROR_CODES: &[i32] = &[
+    6,     // HostUnreachable
+    7,     // HostNotFound
+    89,    // NetworkTimeout
+    91,    // ShutdownInProgress
+    189,   // PrimarySteppedDown
+    262,   // ExceededTimeLimit
+    9001,  // SocketException
+    10107, // NotMaster
+    11600, // InterruptedAtShutdown
+    11602, // InterruptedDueToReplStateChange
+    13435, // NotMasterNoSlaveOk
+    13436, // NotMasterOrSecondary
+];
+
+/// A single error in the `writeErrors` or `writeConcernError` field of the
+/// reply to a write.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WriteErrorInfo {
+    /// The index of the failed write within a bulk write, if applicable.
+    pub index: Option<usize>,
+    /// The error code returned by the server.
+    pub code: i32,
+    /// The name of the error code, e.g. `"DuplicateKey"`, if known.
+    pub code_name: Option<String>,
+    /// The error message of the server.
+    pub message: String,
+}
+
+/// Context info of errors reported by the server, available as
+/// `error.server_error()`, or `error.context::<ServerInfo>()` on the error
+/// that was created from the reply of the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ServerInfo {
+    /// The error code of a failed command, e.g. `10107` (`NotMaster`) for a
+    /// write sent to a stepped-down primary.
+    pub code: Option<i32>,
+    /// The name of the error code, e.g. `"NotMaster"`, if known.
+    pub code_name: Option<String>,
+    /// The error message of the server.
+    pub message: String,
+    /// The errors of individual writes.
+    pub write_errors: Vec<WriteErrorInfo>,
+    /// The error of the write concern, e.g. a replication timeout.
+    pub write_concern_error: Option<WriteErrorInfo>,
+    /// The collection the failed operation was executed on, if known.
+    pub collection: Option<String>,
+    /// The name of the failed operation, e.g. `"update"`, if known.
+    pub operation: Option<String>,
+}
+
+impl Key for ServerInfo {
+    type Value = Self;
+}
+
+impl ServerInfo {
+    /// Extracts the error reported in the reply to a command, if any, i.e.
+    /// if the command failed, or if it has write or write concern errors.
+    pub fn from_reply(reply: &bson::Document) -> Option<Self> {
+        let ok = reply.get("ok").map_or(false, |ok| match *ok {
+            bson::Bson::FloatingPoint(x) => x != 0.0,
+            bson::Bson::I32(x) => x != 0,
+            bson::Bson::I64(x) => x != 0,
+            bson::Bson::Boolean(x) => x,
+            _ => false,
+        });
+        let write_errors: Vec<_> = reply
+            .get_array("writeErrors")
+            .map(|errors| errors.iter().filter_map(|error| match *error {
+                bson::Bson::Document(ref doc) => Some(WriteErrorInfo::from_document(doc)),
+                _ => None,
+            }).collect())
+            .unwrap_or_default();
+        let write_concern_error = reply
+            .get_document("writeConcernError")
+            .ok()
+            .map(WriteErrorInfo::from_document);
+
+        if ok && write_errors.is_empty() && write_concern_error.is_none() {
+            return None;
+        }
+
+        Some(ServerInfo {
+            code: if ok { None } else { reply_code(reply) },
+            code_name: reply.get_str("codeName").ok().map(Into::into),
+            message: reply.get_str("errmsg").unwrap_or_default().into(),
+            write_errors,
+            write_concern_error,
+            collection: None,
+            operation: None,
+        })
+    }
+
+    /// Sets the collection and the name of the failed operation.
+    pub fn with_operation<C, O>(mut self, collection: C, operation: O) -> Self
+        where C: Into<String>,
+              O: Into<String>,
+    {
+        self.collection = Some(collection.into());
+        self.operation = Some(operation.into());
+        self
+    }
+
+    /// Returns every error code in the error: the code of the command,
+    /// those of the write errors, and that of the write concern error.
+    pub fn codes<'a>(&'a self) -> impl Iterator<Item = i32> + 'a {
+        self.code
+            .into_iter()
+            .chain(self.write_errors.iter().map(|error| error.code))
+            .chain(self.write_concern_error.iter().map(|error| error.code))
+    }
+
+    /// Returns `true` if any of the error codes is one of `codes`.
+    pub fn has_any_code(&self, codes: &[i32]) -> bool {
+        self.codes().any(|code| codes.contains(&code))
+    }
+
+    /// Converts a write exception of the driver.
+    pub(crate) fn from_write_exception(error: &mongodb::coll::error::WriteException) -> Self {
+        ServerInfo {
+            message: error.message.clone(),
+            write_errors: error.write_error.iter().map(|e| WriteErrorInfo {
+                index: None,
+                code: e.code,
+                code_name: None,
+                message: e.message.clone(),
+            }).collect(),
+            write_concern_error: error.write_concern_error.as_ref().map(|e| WriteErrorInfo {
+                index: None,
+                code: e.code,
+                code_name: None,
+                message: e.message.clone(),
+            }),
+            ..ServerInfo::default()
+        }
+    }
+
+    /// Converts a bulk write exception of the driver.
+    pub(crate) fn from_bulk_write_exception(error: &mongodb::coll::error::BulkWriteException) -> Self {
+        ServerInfo {
+            message: error.message.clone(),
+            write_errors: error.write_errors.iter().map(|e| WriteErrorInfo {
+                index: int_to_usize_with_msg(e.index, "index of write error").ok(),
+                code: e.code,
+                code_name: None,
+                message: e.message.clone(),
+            }).collect(),
+            write_concern_error: error.write_concern_error.as_ref().map(|e| WriteErrorInfo {
+                index: None,
+                code: e.code,
+                code_name: None,
+                message: e.message.clone(),
+            }),
+            ..ServerInfo::default()
+        }
+    }
+
+    /// Extracts the server error, if any, from an error of another type,
+    /// e.g. a write exception of the driver.
+    fn from_cause(cause: &(dyn error::Error + 'static)) -> Option<Self> {
+        use mongodb::coll::error::{ WriteException, BulkWriteException };
+
+        if let Some(exception) = cause.downcast_ref::<WriteException>() {
+            Some(Self::from_write_exception(exception))
+        } else if let Some(exception) = cause.downcast_ref::<BulkWriteException>() {
+            Some(Self::from_bulk_write_exception(exception))
+        } else if let Some(&mongodb::Error::CodedError(ref code)) = cause.downcast_ref::<mongodb::Error>() {
+            Some(ServerInfo {
+                code: Some(*code as i32),
+                code_name: Some(format!("{:?}", code)),
+                message: cause.to_string(),
+                ..ServerInfo::default()
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl WriteErrorInfo {
+    /// Parses an element of `writeErrors` or a `writeConcernError`.
+    fn from_document(error: &bson::Document) -> Self {
+        WriteErrorInfo {
+            index: error.get_i32("index").ok().and_then(
+                |index| int_to_usize_with_msg(index, "index of write error").ok()
+            ),
+            code: reply_code(error).unwrap_or_default(),
+            code_name: error.get_str("codeName").ok().map(Into::into),
+            message: error.get_str("errmsg").unwrap_or_default().into(),
+        }
+    }
+}
+
+/// Reads the `code` field of a reply or of a write error.
+pub(crate) fn reply_code(doc: &bson::Document) -> Option<i32> {
+    doc.get_i32("code").ok().or_else(|| doc.get_i64("code").ok().and_then(|code| i32::try_from(code).ok()))
+}
+
+/// The central error type for Avocado. It is `Send + Sync`, so it can be
+/// returned from other threads.
 #[derive(Debug)]
 pub struct Error {
     /// The structured, "machine-readable" kind of this error.
@@ -167,7 +493,7 @@ pub struct Error {
     /// The backtrace, if any.
     backtrace: Option<Backtrace>,
     /// Additional context info, if any.
-    context: DebugMap,
+    context: ShareDebugMap,
 }
 
 impl Error {
@@ -197,7 +523,7 @@ impl Error {
             message: message.into(),
             cause: None,
             backtrace: Some(Backtrace::new()),
-            context: DebugMap::custom(),
+            context: ShareDebugMap::custom(),
         }
     }
 
@@ -237,33 +563,111 @@ impl Error {
         } else {
             None
         };
+        let mut context = ShareDebugMap::custom();
+
+        if let Some(server_error) = ServerInfo::from_cause(cause.as_std_error()) {
+            context.insert::<ServerInfo>(server_error);
+        }
+
         let cause: Option<Box<dyn ErrorExt>> = Some(Box::new(cause));
-        let context = DebugMap::custom();
 
         Error { kind, message, cause, backtrace, context }
     }
 
     /// Returns additional context info if any.
     pub fn context<K: Key>(&self) -> Option<&K::Value>
-        where K::Value: fmt::Debug
+        where K::Value: fmt::Debug + Send + Sync
     {
         self.context.get::<K>()
     }
 
     /// Augments the error with additional context info.
     pub fn set_context<K: Key>(&mut self, value: K::Value) -> Option<K::Value>
-        where K::Value: fmt::Debug
+        where K::Value: fmt::Debug + Send + Sync
     {
         self.context.insert::<K>(value)
     }
 
     /// Builder-style setter for agumenting the error with context info.
     pub fn with_context<K: Key>(mut self, value: K::Value) -> Self
-        where K::Value: fmt::Debug
+        where K::Value: fmt::Debug + Send + Sync
     {
         self.set_context::<K>(value);
         self
     }
+
+    /// Returns the error reported by the server, if this error or any of
+    /// its causes was reported by the server.
+    pub fn server_error(&self) -> Option<&ServerInfo> {
+        self.chain().find_map(|error| error.context::<ServerInfo>())
+    }
+
+    /// Returns `true` if the server rejected a write because it would have
+    /// violated a unique index, including those not declared by the
+    /// document type, i.e. not reported as `ErrorKind::UniqueViolation`.
+    pub fn is_duplicate_key(&self) -> bool {
+        self.kind == ErrorKind::UniqueViolation
+            || self.server_error().map_or(false, |e| e.has_any_code(&[DUPLICATE_KEY_ERROR_CODE]))
+    }
+
+    /// Returns `true` if the operation exceeded a time limit, e.g. the
+    /// `maxTimeMS` of a query, or the network timeout.
+    pub fn is_timeout(&self) -> bool {
+        self.server_error().map_or(false, |e| e.has_any_code(TIMEOUT_ERROR_CODES))
+            || self.io_errors().any(|e| match e.kind() {
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => true,
+                _ => false,
+            })
+    }
+
+    /// Returns `true` if the connection to the server failed, or no
+    /// suitable server could be selected, e.g. during an election.
+    pub fn is_network_error(&self) -> bool {
+        self.io_errors().next().is_some() || self.chain().any(|error| {
+            let cause = error.reason().and_then(|c| c.as_std_error().downcast_ref::<mongodb::Error>());
+
+            match cause {
+                // The driver gives up selecting a server, e.g. while the
+                // replica set elects a new primary.
+                Some(&mongodb::Error::OperationError(ref message)) => {
+                    message.starts_with("No servers available")
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Returns `true` if the operation may succeed when repeated, i.e. if
+    /// it failed because of a network error or a server error code listed
+    /// in `TRANSIENT_ERROR_CODES`. See also the [`retry`](../retry/index.html)
+    /// module.
+    pub fn is_transient(&self) -> bool {
+        self.is_network_error()
+            || self.server_error().map_or(false, |e| e.has_any_code(TRANSIENT_ERROR_CODES))
+    }
+
+    /// Iterates over this error and the causes which are also `Error`s.
+    fn chain(&self) -> impl Iterator<Item = &Error> {
+        let mut next = Some(self);
+
+        std::iter::from_fn(move || {
+            let current = next?;
+            next = current.reason().and_then(|cause| cause.as_std_error().downcast_ref::<Error>());
+            Some(current)
+        })
+    }
+
+    /// Iterates over the I/O errors among the causes of this error.
+    fn io_errors(&self) -> impl Iterator<Item = &std::io::Error> {
+        self.chain().filter_map(|error| {
+            let cause = error.reason()?.as_std_error();
+
+            cause.downcast_ref::<std::io::Error>().or_else(|| match cause.downcast_ref::<mongodb::Error>() {
+                Some(&mongodb::Error::IoError(ref io_error)) => Some(io_error),
+                _ => None,
+            })
+        })
+    }
 }
 
 impl ErrorExt for Error {
@@ -355,11 +759,18 @@ macro_rules! impl_error_type {
     }
 }
 
+impl_error_type! { std::io::Error,     Io,                 "I/O error" }
 impl_error_type! { serde_json::Error,  JsonTranscoding,    "JSON transcoding error" }
 impl_error_type! { bson::EncoderError, BsonEncoding,       "BSON encoding error" }
 impl_error_type! { bson::DecoderError, BsonDecoding,       "BSON decoding error" }
 impl_error_type! { bson::oid::Error,   ObjectIdGeneration, "ObjectId generation error" }
 impl_error_type! { mongodb::Error,     MongoDbError,       "MongoDB error" }
+#[cfg(feature = "regex")]
+impl_error_type! { regex::Error,       InvalidRegex,       "invalid regular expression" }
+#[cfg(feature = "driver2")]
+impl_error_type! { bson2::ser::Error,  BsonEncoding,       "BSON encoding error" }
+#[cfg(feature = "driver2")]
+impl_error_type! { bson2::de::Error,   BsonDecoding,       "BSON decoding error" }
 impl_error_type! {
     mongodb::coll::error::WriteException,
     MongoDbWriteException,
@@ -370,3 +781,66 @@ impl_error_type! {
     MongoDbBulkWriteException,
     "MongoDB bulk write exception"
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{ Error, ErrorKind, ServerInfo, WriteErrorInfo, ResultExt };
+
+    #[test]
+    fn server_error_from_reply() {
+        assert_eq!(ServerInfo::from_reply(&doc!{ "ok": 1, "n": 3 }), None);
+
+        let reply = doc!{
+            "ok": 1,
+            "n": 0,
+            "writeErrors": [
+                { "index": 0, "code": 11000, "errmsg": "E11000 duplicate key error" },
+            ],
+            "writeConcernError": { "code": 64, "codeName": "WriteConcernFailed", "errmsg": "timeout" },
+        };
+        let server_error = ServerInfo::from_reply(&reply).expect("no server error");
+
+        assert_eq!(server_error.code, None);
+        assert_eq!(server_error.write_errors, vec![WriteErrorInfo {
+            index: Some(0),
+            code: 11000,
+            code_name: None,
+            message: "E11000 duplicate key error".into(),
+        }]);
+        assert_eq!(server_error.codes().collect::<Vec<_>>(), [11000, 64]);
+
+        let failed = ServerInfo::from_reply(&doc!{
+            "ok": 0.0,
+            "code": 50,
+            "codeName": "MaxTimeMSExpired",
+            "errmsg": "operation exceeded time limit",
+        }).expect("no server error");
+
+        assert_eq!(failed.code, Some(50));
+        assert_eq!(failed.code_name.as_ref().map(String::as_str), Some("MaxTimeMSExpired"));
+    }
+
+    #[test]
+    fn error_predicates() {
+        let server_error = |code| Error::new(ErrorKind::MongoDbError, "command failed")
+            .with_context::<ServerInfo>(ServerInfo {
+                code: Some(code),
+                ..ServerInfo::default()
+            });
+        let chained = Err::<(), _>(server_error(11000)).chain("insert failed").unwrap_err();
+
+        assert!(chained.is_duplicate_key());
+        assert!(!chained.is_transient());
+        assert!(server_error(50).is_timeout());
+        assert!(server_error(10107).is_transient());
+        assert!(!server_error(10107).is_timeout());
+
+        let io_error = Error::from(io::Error::from(io::ErrorKind::TimedOut));
+
+        assert!(io_error.is_network_error());
+        assert!(io_error.is_timeout());
+        assert!(io_error.is_transient());
+        assert!(io_error.server_error().is_none());
+    }
+}
@@ -0,0 +1,207 @@
+//! Structured results of the `explain` command.
+//!
+//! [`Collection::explain_with()`](../coll/struct.Collection.html#method.explain_with)
+//! and [`explain_pipeline()`](../coll/struct.Collection.html#method.explain_pipeline)
+//! return a [`Report`](struct.Report.html), which holds the
+//! winning and the rejected plans of a query or of the initial, query-like
+//! part of an aggregation pipeline, and, depending on the requested
+//! [`ExplainVerbosity`](../literal/enum.ExplainVerbosity.html), the execution
+//! statistics of the winning plan. The complete reply of the server is kept
+//! as well, since its format varies between server versions.
+
+use bson::{ Bson, Document, from_bson };
+use crate::error::{ Error, ErrorKind::MissingDocumentField, Result, ResultExt };
+
+/// The name of the query plan stage scanning a whole collection.
+pub(crate) const COLLECTION_SCAN_STAGE: &str = "COLLSCAN";
+
+/// The name of the query plan stage scanning an index.
+pub(crate) const INDEX_SCAN_STAGE: &str = "IXSCAN";
+
+/// The query plans and statistics reported by the `explain` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// The plan chosen by the query planner.
+    pub winning_plan: Document,
+    /// The plans considered, but rejected, by the query planner.
+    pub rejected_plans: Vec<Document>,
+    /// The execution statistics, unless the verbosity was `QueryPlanner`.
+    pub execution_stats: Option<ExecutionStats>,
+    /// The complete reply of the server.
+    pub raw: Document,
+}
+
+/// Execution statistics of the winning plan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionStats {
+    /// Whether the plan was executed successfully.
+    #[serde(default)]
+    pub execution_success: bool,
+    /// The number of documents returned.
+    #[serde(default)]
+    pub n_returned: i64,
+    /// The total time spent executing the plan, in milliseconds.
+    #[serde(default)]
+    pub execution_time_millis: i64,
+    /// The number of index keys scanned.
+    #[serde(default)]
+    pub total_keys_examined: i64,
+    /// The number of documents scanned.
+    #[serde(default)]
+    pub total_docs_examined: i64,
+}
+
+impl Report {
+    /// Parses the reply of the `explain` command. For aggregations, the
+    /// plans are reported either at the top level, or by the initial
+    /// `$cursor` stage, depending on the version of the server.
+    pub fn from_reply(raw: Document) -> Result<Self> {
+        let message = "can't parse reply of `explain`";
+        let section = query_section(&raw).ok_or_else(|| Error::new(
+            MissingDocumentField, "no `queryPlanner` in reply of `explain`"
+        ))?;
+        let planner = section.get_document("queryPlanner").chain(message)?;
+        let winning_plan = planner.get_document("winningPlan").chain(message)?.clone();
+        let rejected_plans = planner
+            .get_array("rejectedPlans")
+            .map(|plans| plans.iter().filter_map(Bson::as_document).cloned().collect())
+            .unwrap_or_default();
+        let execution_stats = match section.get_document("executionStats") {
+            Ok(stats) => Some(from_bson(stats.clone().into()).chain(message)?),
+            Err(_) => None,
+        };
+
+        Ok(Report { winning_plan, rejected_plans, execution_stats, raw })
+    }
+
+    /// Returns the names of all stages of the winning plan, in depth-first
+    /// order, starting with the root stage.
+    pub fn stages(&self) -> Vec<String> {
+        let mut stages = Vec::new();
+
+        visit_stages(&self.winning_plan, &mut |stage| {
+            if let Ok(name) = stage.get_str("stage") {
+                stages.push(name.to_owned());
+            }
+        });
+
+        stages
+    }
+
+    /// Returns the names of the indexes scanned by the winning plan.
+    pub fn used_indexes(&self) -> Vec<String> {
+        let mut indexes = Vec::new();
+
+        visit_stages(&self.winning_plan, &mut |stage| {
+            if stage.get_str("stage").ok() == Some(INDEX_SCAN_STAGE) {
+                if let Ok(name) = stage.get_str("indexName") {
+                    indexes.push(name.to_owned());
+                }
+            }
+        });
+
+        indexes
+    }
+
+    /// Returns `true` if the winning plan scans the whole collection.
+    pub fn is_collection_scan(&self) -> bool {
+        self.stages().iter().any(|stage| stage == COLLECTION_SCAN_STAGE)
+    }
+}
+
+/// Returns the part of an `explain` reply describing the query, i.e. the
+/// document containing the `queryPlanner` and `executionStats` sections.
+fn query_section(reply: &Document) -> Option<&Document> {
+    if reply.contains_key("queryPlanner") {
+        return Some(reply);
+    }
+
+    reply
+        .get_array("stages")
+        .ok()?
+        .first()?
+        .as_document()?
+        .get_document("$cursor")
+        .ok()
+}
+
+/// Calls `f` on the stage and all of its input stages, recursively.
+/// Depending on the stage type, inputs are stored either in a single
+/// `inputStage` document or in an `inputStages` array.
+pub(crate) fn visit_stages<F: FnMut(&Document)>(stage: &Document, f: &mut F) {
+    f(stage);
+
+    if let Ok(input) = stage.get_document("inputStage") {
+        visit_stages(input, f);
+    }
+
+    if let Ok(inputs) = stage.get_array("inputStages") {
+        for input in inputs {
+            if let Bson::Document(ref input_stage) = *input {
+                visit_stages(input_stage, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Report, ExecutionStats };
+
+    #[test]
+    fn parse_find_reply() {
+        let output = Report::from_reply(doc!{
+            "queryPlanner": {
+                "winningPlan": {
+                    "stage": "FETCH",
+                    "inputStage": { "stage": "IXSCAN", "indexName": "name_1" },
+                },
+                "rejectedPlans": [{ "stage": "COLLSCAN" }],
+            },
+            "executionStats": {
+                "executionSuccess": true,
+                "nReturned": 3,
+                "executionTimeMillis": 1,
+                "totalKeysExamined": 3,
+                "totalDocsExamined": 3_i64,
+            },
+            "ok": 1.0,
+        }).unwrap();
+
+        assert_eq!(output.stages(), ["FETCH", "IXSCAN"]);
+        assert_eq!(output.used_indexes(), ["name_1"]);
+        assert_eq!(output.rejected_plans, vec![doc!{ "stage": "COLLSCAN" }]);
+        assert!(!output.is_collection_scan());
+        assert_eq!(output.execution_stats, Some(ExecutionStats {
+            execution_success: true,
+            n_returned: 3,
+            execution_time_millis: 1,
+            total_keys_examined: 3,
+            total_docs_examined: 3,
+        }));
+    }
+
+    #[test]
+    fn parse_aggregate_reply() {
+        let output = Report::from_reply(doc!{
+            "stages": [
+                {
+                    "$cursor": {
+                        "queryPlanner": {
+                            "winningPlan": { "stage": "COLLSCAN" },
+                            "rejectedPlans": [],
+                        },
+                    },
+                },
+                { "$group": { "_id": "$name" } },
+            ],
+            "ok": 1.0,
+        }).unwrap();
+
+        assert!(output.is_collection_scan());
+        assert!(output.rejected_plans.is_empty());
+        assert_eq!(output.execution_stats, None);
+        assert!(Report::from_reply(doc!{ "ok": 1.0 }).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Typed aggregation expressions, as used in pipeline stages and in `$expr`
+//! filters.
+//!
+//! An [`Expr`](enum.Expr.html) is built from field references, constant
+//! values and operators, and it converts to the BSON expected by MongoDB.
+//! Arithmetic uses the usual Rust operators, e.g. finding the projects which
+//! went over their budget by more than 10%:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::expr::field;
+//! # use avocado::filter::expr;
+//! #
+//! # fn main() {
+//! let filter = expr(field("spent").gt(field("budget") * 1.1));
+//! assert_eq!(filter, doc!{
+//!     "$expr": { "$gt": ["$spent", { "$multiply": ["$budget", 1.1] }] }
+//! });
+//! # }
+//! ```
+//!
+//! Type-testing expressions make queries typical of schema clean-up jobs
+//! easy to write. For example, finding the documents in which `price` is
+//! stored as anything but a number:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::expr::{ field, is_number };
+//! # use avocado::filter::{ expr, not };
+//! #
+//! # fn main() {
+//! let filter = not(expr(is_number(field("price"))));
+//! assert_eq!(filter, doc!{
+//!     "$nor": [{ "$expr": { "$isNumber": "$price" } }]
+//! });
+//! # }
+//! ```
+
+use std::ops::{ Add, Sub, Mul, Div, Rem, Not };
+use bson::{ Bson, Document };
+use crate::literal::BsonType;
+
+/// An aggregation expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// The value of a field, given by its path in dot notation.
+    Field(String),
+    /// A constant value. Values which MongoDB would interpret as expressions,
+    /// such as strings starting with `$`, are wrapped in a `$literal`.
+    Value(Bson),
+    /// An operator with a single argument, e.g. `{ $abs: <arg> }`.
+    Unary(&'static str, Box<Expr>),
+    /// An operator with an argument list, e.g. `{ $add: [<arg>, ...] }`.
+    Nary(&'static str, Vec<Expr>),
+    /// A conditional: if the first expression is `true`, the value is that
+    /// of the second one, otherwise that of the third one.
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Returns a constant expression.
+    pub fn value<V: Into<Bson>>(value: V) -> Self {
+        Expr::Value(value.into())
+    }
+
+    /// Returns a `$gt` comparison.
+    pub fn gt<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$gt", other)
+    }
+
+    /// Returns a `$gte` comparison.
+    pub fn gte<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$gte", other)
+    }
+
+    /// Returns a `$lt` comparison.
+    pub fn lt<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$lt", other)
+    }
+
+    /// Returns a `$lte` comparison.
+    pub fn lte<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$lte", other)
+    }
+
+    /// Returns an `$eq` comparison.
+    pub fn equals<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$eq", other)
+    }
+
+    /// Returns a `$ne` comparison.
+    pub fn not_equals<E: Into<Expr>>(self, other: E) -> Self {
+        self.binary("$ne", other)
+    }
+
+    /// Returns the logical conjunction of `self` and `other`.
+    pub fn and<E: Into<Expr>>(self, other: E) -> Self {
+        self.variadic("$and", other)
+    }
+
+    /// Returns the logical disjunction of `self` and `other`.
+    pub fn or<E: Into<Expr>>(self, other: E) -> Self {
+        self.variadic("$or", other)
+    }
+
+    /// Returns the absolute value of `self`.
+    pub fn abs(self) -> Self {
+        Expr::Unary("$abs", Box::new(self))
+    }
+
+    /// Returns a binary operator applied to `self` and `other`.
+    fn binary<E: Into<Expr>>(self, name: &'static str, other: E) -> Self {
+        Expr::Nary(name, vec![self, other.into()])
+    }
+
+    /// Returns an associative operator applied to `self` and `other`,
+    /// flattening nested applications of the same operator into a single
+    /// argument list, e.g. `a + b + c` into `{ $add: [a, b, c] }`.
+    fn variadic<E: Into<Expr>>(self, name: &'static str, other: E) -> Self {
+        match self {
+            Expr::Nary(op, mut args) if op == name => {
+                args.push(other.into());
+                Expr::Nary(op, args)
+            }
+            lhs => lhs.binary(name, other),
+        }
+    }
+}
+
+/// Returns a reference to the value of the field at `path`, in dot notation,
+/// e.g. `field("address.city")` is `"$address.city"`.
+pub fn field<S: Into<String>>(path: S) -> Expr {
+    Expr::Field(path.into())
+}
+
+/// Returns a `$cond` expression: `then` if `condition` is `true`, `otherwise`
+/// if it isn't.
+pub fn cond<C, T, E>(condition: C, then: T, otherwise: E) -> Expr
+    where C: Into<Expr>,
+          T: Into<Expr>,
+          E: Into<Expr>,
+{
+    Expr::Cond(
+        Box::new(condition.into()),
+        Box::new(then.into()),
+        Box::new(otherwise.into()),
+    )
+}
+
+/// Returns a `$type` expression, which evaluates to the alias of the BSON
+/// type of `expression`, e.g. `"string"`, or to `"missing"`.
+pub fn type_of<E: Into<Expr>>(expression: E) -> Expr {
+    Expr::Unary("$type", Box::new(expression.into()))
+}
+
+/// Returns an `$isNumber` expression, which evaluates to `true` if the value
+/// of `expression` is an `int`, `long`, `double` or `decimal`.
+pub fn is_number<E: Into<Expr>>(expression: E) -> Expr {
+    Expr::Unary("$isNumber", Box::new(expression.into()))
+}
+
+/// Returns an expression which evaluates to `true` if the value of
+/// `expression` is of any of the types in `types`.
+pub fn is_type<E: Into<Expr>>(expression: E, types: BsonType) -> Expr {
+    let aliases = match Bson::from(types) {
+        Bson::Array(aliases) => aliases,
+        alias => vec![alias],
+    };
+
+    Expr::Nary("$in", vec![type_of(expression), Expr::Value(Bson::Array(aliases))])
+}
+
+impl From<Bson> for Expr {
+    fn from(value: Bson) -> Self {
+        Expr::Value(value)
+    }
+}
+
+impl From<bool> for Expr {
+    fn from(value: bool) -> Self {
+        Expr::Value(value.into())
+    }
+}
+
+impl From<i32> for Expr {
+    fn from(value: i32) -> Self {
+        Expr::Value(value.into())
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(value: i64) -> Self {
+        Expr::Value(value.into())
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(value: f64) -> Self {
+        Expr::Value(value.into())
+    }
+}
+
+impl From<Expr> for Bson {
+    fn from(expr: Expr) -> Self {
+        match expr {
+            Expr::Field(path) => Bson::String(format!("${}", path)),
+            Expr::Value(value) => if needs_literal(&value) {
+                bson!({ "$literal": value })
+            } else {
+                value
+            },
+            Expr::Unary(name, arg) => {
+                let mut doc = Document::new();
+                doc.insert(name, Bson::from(*arg));
+                Bson::Document(doc)
+            }
+            Expr::Nary(name, args) => {
+                let mut doc = Document::new();
+                doc.insert(name, args.into_iter().map(Bson::from).collect::<Vec<_>>());
+                Bson::Document(doc)
+            }
+            Expr::Cond(condition, then, otherwise) => bson!({
+                "$cond": {
+                    "if": Bson::from(*condition),
+                    "then": Bson::from(*then),
+                    "else": Bson::from(*otherwise),
+                }
+            }),
+        }
+    }
+}
+
+/// Implements an arithmetic operator trait for `Expr`, in terms of either
+/// `Expr::binary()` or `Expr::variadic()`.
+macro_rules! impl_arithmetic {
+    ($trait_name:ident, $method:ident, $operator:expr, $combine:ident) => {
+        impl<E: Into<Expr>> $trait_name<E> for Expr {
+            type Output = Expr;
+
+            fn $method(self, other: E) -> Expr {
+                self.$combine($operator, other)
+            }
+        }
+    }
+}
+
+impl_arithmetic! { Add, add, "$add",      variadic }
+impl_arithmetic! { Sub, sub, "$subtract", binary   }
+impl_arithmetic! { Mul, mul, "$multiply", variadic }
+impl_arithmetic! { Div, div, "$divide",   binary   }
+impl_arithmetic! { Rem, rem, "$mod",      binary   }
+
+impl Not for Expr {
+    type Output = Expr;
+
+    fn not(self) -> Expr {
+        Expr::Unary("$not", Box::new(self))
+    }
+}
+
+/// Returns `true` if MongoDB would interpret `value` as an expression rather
+/// than as a constant, i.e. if it is, or it contains, a string starting with
+/// `$`, or if it is a document, which could contain operators.
+fn needs_literal(value: &Bson) -> bool {
+    match *value {
+        Bson::String(ref s) => s.starts_with('$'),
+        Bson::Array(ref items) => items.iter().any(needs_literal),
+        Bson::Document(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use crate::literal::BsonType;
+    use super::{ Expr, field, cond, type_of, is_number, is_type };
+
+    #[test]
+    fn type_tests() {
+        assert_eq!(Bson::from(type_of(field("price"))), bson!({ "$type": "$price" }));
+        assert_eq!(Bson::from(is_number(field("a.b"))), bson!({ "$isNumber": "$a.b" }));
+        assert_eq!(Bson::from(is_type(field("price"), BsonType::STRING)), bson!({
+            "$in": [{ "$type": "$price" }, ["string"]]
+        }));
+        assert_eq!(Bson::from(is_type(field("at"), BsonType::DATE | BsonType::TIMESTAMP)), bson!({
+            "$in": [{ "$type": "$at" }, ["timestamp", "date"]]
+        }));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let total = field("price") * field("quantity") + field("shipping") + 5;
+
+        assert_eq!(Bson::from(total), bson!({
+            "$add": [{ "$multiply": ["$price", "$quantity"] }, "$shipping", 5]
+        }));
+        assert_eq!(Bson::from((field("a") - 1) / 2 % 3), bson!({
+            "$mod": [{ "$divide": [{ "$subtract": ["$a", 1] }, 2] }, 3]
+        }));
+    }
+
+    #[test]
+    fn logic_and_conditionals() {
+        let expr = cond(
+            field("qty").gte(250).and(!field("discontinued")),
+            field("price").abs(),
+            Expr::value("n/a"),
+        );
+
+        assert_eq!(Bson::from(expr), bson!({
+            "$cond": {
+                "if": {
+                    "$and": [
+                        { "$gte": ["$qty", 250] },
+                        { "$not": "$discontinued" },
+                    ]
+                },
+                "then": { "$abs": "$price" },
+                "else": "n/a",
+            }
+        }));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(Bson::from(Expr::value("$5")), bson!({ "$literal": "$5" }));
+        assert_eq!(Bson::from(Expr::value(bson!(["a", "$b"]))), bson!({
+            "$literal": ["a", "$b"]
+        }));
+        assert_eq!(Bson::from(field("x").equals(Expr::value("y"))), bson!({
+            "$eq": ["$x", "y"]
+        }));
+    }
+}
@@ -0,0 +1,955 @@
+//! Helpers for building filter documents with logical operators.
+//!
+//! MongoDB doesn't allow `$not` at the top level of a filter: it only
+//! negates a single operator expression of a field, e.g.
+//! `{ "age": { "$not": { "$gte": 18 } } }`. Negating a whole filter must be
+//! spelled as a `$nor` with a single clause instead, which is what
+//! [`not()`](fn.not.html) produces.
+//!
+//! Filters can also be evaluated against documents held by the client, e.g.
+//! cached data, using [`matcher::matches()`](../matcher/fn.matches.html).
+//!
+//! Filters assembled from several parts can be checked before they are sent
+//! with [`validate()`](fn.validate.html), which reports every problem the
+//! server would reject, and flattened with [`normalize()`](fn.normalize.html).
+//! Queries received as (Extended) JSON are converted and validated in one
+//! step by [`from_json()`](fn.from_json.html):
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::filter::{ validate, normalize };
+//! # use avocado::error::{ ErrorKind, ErrorExt, InvalidFilter };
+//! #
+//! # fn main() {
+//! let filter = doc!{
+//!     "$and": [
+//!         { "$and": [{ "age": { "$gte": 18 } }, { "country": "HU" }] },
+//!         { "$or": [{ "$or": [{ "vip": true }] }, { "spent": { "$gt": 1000 } }] },
+//!     ]
+//! };
+//! assert_eq!(normalize(filter), doc!{
+//!     "age": { "$gte": 18 },
+//!     "country": "HU",
+//!     "$or": [{ "vip": true }, { "spent": { "$gt": 1000 } }],
+//! });
+//!
+//! let error = validate(&doc!{ "$or": [], "tags": { "$size": -1 } }).unwrap_err();
+//! let problems = &error.context::<InvalidFilter>().unwrap().problems;
+//! assert_eq!(error.kind(), ErrorKind::InvalidFilter);
+//! assert_eq!(problems[0].path, "$or");
+//! assert_eq!(problems[1].path, "tags.$size");
+//! # }
+//! ```
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::filter::{ not, nor_fields };
+//! #
+//! # fn main() {
+//! // Neither a guest nor banned
+//! let filter = not(doc!{ "$or": [{ "role": "guest" }, { "banned": true }] });
+//! assert_eq!(filter, doc!{
+//!     "$nor": [{ "$or": [{ "role": "guest" }, { "banned": true }] }]
+//! });
+//!
+//! // None of the fields match
+//! let filter = nor_fields(doc!{ "role": "guest", "banned": true });
+//! assert_eq!(filter, doc!{
+//!     "$nor": [{ "role": "guest" }, { "banned": true }]
+//! });
+//! # }
+//! ```
+
+use bson::{ Bson, Document };
+use serde_json::Value;
+#[cfg(feature = "regex")]
+use regex::{ Regex, RegexBuilder };
+#[cfg(feature = "regex")]
+use crate::literal::RegexOpts;
+use crate::{
+    literal::{ Language, TextFlags, BitMask, BsonType },
+    matcher::is_operator_document,
+    bsn::{ BsonExt, from_extended_json },
+    error::{ Error, ErrorKind, Result, FilterProblem, InvalidFilter },
+};
+
+/// Builds a filter document like `doc!`, additionally accepting operator
+/// calls and arbitrary key expressions.
+///
+/// * A key is a string literal, an identifier, or a parenthesized
+///   expression, e.g. a `path::Path` returned by `User::fields().email()`.
+/// * A braced value is a sub-document whose entries may be operator calls:
+///   `op(value)` becomes `"$op": value`, and `op { ... }` becomes
+///   `"$op": { ... }`, e.g. `"age": { gte(18), lt(65) }` becomes
+///   `"age": { "$gte": 18, "$lt": 65 }`. Operators are spelled as in
+///   MongoDB without the `$`, so `in`, `type` and `elemMatch` work too.
+///   Plain `key: value` entries are kept as they are, so braces can also
+///   denote embedded documents.
+/// * Any other value is passed to `doc!` verbatim, so array literals and
+///   expressions work as usual. Operands containing top-level commas must
+///   be parenthesized or bracketed, e.g. `mod([4, 1])`.
+///
+/// The result is built by `doc!`, so the macros of `bson` must be in scope.
+/// Since the input is consumed one token at a time, very large filters may
+/// require raising the `recursion_limit` of the crate.
+///
+/// `flt!` doesn't know the type of the documents it filters. For a strict
+/// variant which checks field paths and literal values against the fields
+/// declared by `#[derive(Doc)]` at compile time, see the `checked_flt!`
+/// macro of `avocado_derive`.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # #[macro_use]
+/// # extern crate avocado;
+/// #
+/// # fn main() {
+/// let status = "status";
+/// let min_age = 18;
+/// let filter = flt!{
+///     "name": "Alice",
+///     (status): { in(["active", "pending"]) },
+///     "age": { gte(min_age), lt(65) },
+///     "tags": { not { size(0) } },
+///     "orders": { elemMatch { "total": { gt(100) }, "paid": true } },
+/// };
+///
+/// assert_eq!(filter, doc!{
+///     "name": "Alice",
+///     "status": { "$in": ["active", "pending"] },
+///     "age": { "$gte": 18, "$lt": 65 },
+///     "tags": { "$not": { "$size": 0 } },
+///     "orders": { "$elemMatch": { "total": { "$gt": 100 }, "paid": true } },
+/// });
+/// # }
+/// ```
+#[macro_export]
+macro_rules! flt {
+    (@entries [$($out:tt)*]) => {
+        doc!{ $($out)* }
+    };
+    (@entries [$($out:tt)*] $op:ident ( $($operand:tt)* ) $(, $($rest:tt)*)?) => {
+        $crate::flt!(@entries [$($out)* (concat!("$", stringify!($op))): $($operand)*,] $($($rest)*)?)
+    };
+    (@entries [$($out:tt)*] $op:ident { $($operand:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::flt!(@entries [$($out)* (concat!("$", stringify!($op))): ($crate::flt!{ $($operand)* }),] $($($rest)*)?)
+    };
+    (@entries [$($out:tt)*] $key:tt : { $($value:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::flt!(@entries [$($out)* ($key): ($crate::flt!{ $($value)* }),] $($($rest)*)?)
+    };
+    (@entries [$($out:tt)*] $key:tt : $($rest:tt)*) => {
+        $crate::flt!(@value [$($out)* ($key):] $($rest)*)
+    };
+    (@value [$($out:tt)*]) => {
+        $crate::flt!(@entries [$($out)*,])
+    };
+    (@value [$($out:tt)*] , $($rest:tt)*) => {
+        $crate::flt!(@entries [$($out)*,] $($rest)*)
+    };
+    (@value [$($out:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::flt!(@value [$($out)* $next] $($rest)*)
+    };
+    ($($body:tt)*) => {
+        $crate::flt!(@entries [] $($body)*)
+    };
+}
+
+/// Negates a filter: the result matches exactly the documents that `filter`
+/// doesn't match. Since `$not` isn't allowed at the top level, the filter
+/// is wrapped in a single-clause `$nor`.
+///
+/// An already negated filter, i.e. a `$nor` with a single clause, is
+/// unwrapped instead of being negated twice.
+pub fn not(filter: Document) -> Document {
+    if filter.len() == 1 {
+        if let Some(&Bson::Array(ref clauses)) = filter.get("$nor") {
+            if clauses.len() == 1 {
+                if let Bson::Document(ref inner) = clauses[0] {
+                    return inner.clone();
+                }
+            }
+        }
+    }
+
+    doc!{ "$nor": [filter] }
+}
+
+/// Returns a filter matching the documents which match none of `filters`.
+pub fn nor<I>(filters: I) -> Document
+    where I: IntoIterator<Item = Document>
+{
+    let clauses: Vec<Bson> = filters.into_iter().map(Bson::Document).collect();
+    doc!{ "$nor": clauses }
+}
+
+/// Returns a filter matching the documents for which none of the fields of
+/// `fields` match, e.g. `{ a: 1, b: 2 }` becomes `{ $nor: [{ a: 1 }, { b: 2 }] }`.
+///
+/// This differs from `not(fields)`, which matches the documents for which
+/// not *all* of the fields match.
+pub fn nor_fields(fields: Document) -> Document {
+    nor(fields.into_iter().map(|(key, value)| {
+        let mut clause = Document::new();
+        clause.insert(key, value);
+        clause
+    }))
+}
+
+/// Returns a filter matching the documents for which the aggregation
+/// expression `expression` evaluates to `true`. See the
+/// [`expr`](../expr/index.html) module for building expressions.
+pub fn expr<E: Into<Bson>>(expression: E) -> Document {
+    doc!{ "$expr": expression.into() }
+}
+
+/// Returns a `$mod` operator expression, matching numbers which leave
+/// `remainder` when divided by `divisor`.
+pub fn modulo(divisor: i64, remainder: i64) -> Document {
+    doc!{ "$mod": [divisor, remainder] }
+}
+
+/// Returns a `$where` filter, matching the documents for which the
+/// JavaScript expression or function `code` returns `true`. The document
+/// is available as `this`.
+///
+/// `$where` can't use indexes, and it is evaluated for every document
+/// scanned; prefer `expr()` where possible.
+pub fn where_js<S: Into<String>>(code: S) -> Document {
+    doc!{ "$where": Bson::JavaScriptCode(code.into()) }
+}
+
+/// Attaches a `$comment` to a filter, which shows up in the profiler and in
+/// the logs of the server, making the query easy to identify.
+pub fn with_comment<S: Into<String>>(mut filter: Document, comment: S) -> Document {
+    filter.insert("$comment", comment.into());
+    filter
+}
+
+/// Returns an operator expression matching arrays with at least one element
+/// of any of the types in `types`.
+///
+/// Unlike a plain `{ $type: ... }` condition, this never matches a field that
+/// isn't an array.
+pub fn any_element_of_type(types: BsonType) -> Document {
+    doc!{ "$elemMatch": { "$type": types } }
+}
+
+/// Returns an operator expression matching arrays all elements of which are
+/// of any of the types in `types`, e.g. for finding arrays of numbers in
+/// which some element is stored as a string. Empty arrays match too, and so
+/// do fields that aren't arrays.
+pub fn all_elements_of_type(types: BsonType) -> Document {
+    doc!{ "$not": { "$elemMatch": { "$not": { "$type": types } } } }
+}
+
+/// Returns a `$bitsAllSet` operator expression, matching numeric or binary
+/// values in which all bits of `mask` are set.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::filter::{ bits_all_set, bits_any_clear };
+/// #
+/// # fn main() {
+/// let filter = doc!{
+///     "permissions": bits_all_set(0b0110_i64),
+///     "flags": bits_any_clear(vec![1_u32, 5]),
+/// };
+/// assert_eq!(filter, doc!{
+///     "permissions": { "$bitsAllSet": 6_i64 },
+///     "flags": { "$bitsAnyClear": [1_i64, 5_i64] },
+/// });
+/// # }
+/// ```
+pub fn bits_all_set<M: Into<BitMask>>(mask: M) -> Document {
+    doc!{ "$bitsAllSet": mask.into() }
+}
+
+/// Returns a `$bitsAllClear` operator expression, matching numeric or binary
+/// values in which all bits of `mask` are clear.
+pub fn bits_all_clear<M: Into<BitMask>>(mask: M) -> Document {
+    doc!{ "$bitsAllClear": mask.into() }
+}
+
+/// Returns a `$bitsAnySet` operator expression, matching numeric or binary
+/// values in which at least one bit of `mask` is set.
+pub fn bits_any_set<M: Into<BitMask>>(mask: M) -> Document {
+    doc!{ "$bitsAnySet": mask.into() }
+}
+
+/// Returns a `$bitsAnyClear` operator expression, matching numeric or binary
+/// values in which at least one bit of `mask` is clear.
+pub fn bits_any_clear<M: Into<BitMask>>(mask: M) -> Document {
+    doc!{ "$bitsAnyClear": mask.into() }
+}
+
+/// Returns a full-text search filter for the terms and phrases in `search`,
+/// using the text index of the collection. If no language is given, the
+/// `default_language` of the index is used.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::filter::text;
+/// # use avocado::literal::{ Language, TextFlags };
+/// #
+/// # fn main() {
+/// let flags = TextFlags::DIACRITIC_SENSITIVE;
+/// let filter = text("\"fair trade\" -decaf", Some(Language::English), flags);
+/// assert_eq!(filter, doc!{
+///     "$text": {
+///         "$search": "\"fair trade\" -decaf",
+///         "$language": "english",
+///         "$caseSensitive": false,
+///         "$diacriticSensitive": true,
+///     }
+/// });
+/// # }
+/// ```
+pub fn text(search: &str, language: Option<Language>, flags: TextFlags) -> Document {
+    let mut spec = doc!{ "$search": search };
+
+    if let Some(lang) = language {
+        spec.insert("$language", lang);
+    }
+
+    spec.insert("$caseSensitive", flags.contains(TextFlags::CASE_SENSITIVE));
+    spec.insert("$diacriticSensitive", flags.contains(TextFlags::DIACRITIC_SENSITIVE));
+
+    doc!{ "$text": spec }
+}
+
+/// Returns a `{ $regex, $options }` operator expression, after checking that
+/// `pattern` is a valid regular expression with the given options. An
+/// invalid pattern results in an `InvalidRegex` error right away, instead
+/// of a failing query later.
+///
+/// The pattern is checked using the `regex` crate, whose syntax is a subset
+/// of the PCRE syntax understood by MongoDB: it lacks look-around assertions
+/// and backreferences. Patterns using these are rejected, even though the
+/// server would accept them; use a plain `$regex` for those.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::filter::regex_checked;
+/// # use avocado::literal::RegexOpts;
+/// # use avocado::error::{ ErrorKind, ErrorExt };
+/// #
+/// # fn main() {
+/// let name = regex_checked("^foo[0-9]+", RegexOpts::IGNORE_CASE).unwrap();
+/// assert_eq!(doc!{ "name": name }, doc!{
+///     "name": { "$regex": "^foo[0-9]+", "$options": "i" }
+/// });
+///
+/// let error = regex_checked("^foo[0-9+", RegexOpts::default()).unwrap_err();
+/// assert_eq!(error.kind(), ErrorKind::InvalidRegex);
+/// # }
+/// ```
+#[cfg(feature = "regex")]
+pub fn regex_checked(pattern: &str, options: RegexOpts) -> Result<Document> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(options.contains(RegexOpts::IGNORE_CASE))
+        .multi_line(options.contains(RegexOpts::LINE_ANCHOR))
+        .ignore_whitespace(options.contains(RegexOpts::EXTENDED))
+        .dot_matches_new_line(options.contains(RegexOpts::DOT_NEWLINE))
+        .build()?;
+
+    Ok(doc!{
+        "$regex": pattern,
+        "$options": options,
+    })
+}
+
+/// Converts a compiled regular expression of the `regex` crate to a
+/// `$regex` operator expression. Since the pattern has already been
+/// compiled, it is known to be valid.
+///
+/// A leading group of inline flags, e.g. `(?im)`, is translated to the
+/// corresponding `RegexOpts`. The `u` flag is dropped, since the server
+/// always matches UTF-8. The `U` (swap greed) flag has no equivalent, and
+/// results in an `InvalidRegex` error. Options set using a `RegexBuilder`
+/// aren't part of the pattern, so they are lost; use inline flags instead.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate regex;
+/// # extern crate avocado;
+/// #
+/// # use regex::Regex;
+/// # use avocado::filter::from_regex;
+/// #
+/// # fn main() {
+/// let regex = Regex::new("(?ix)^ foo [0-9]+").unwrap();
+/// assert_eq!(from_regex(&regex).unwrap(), doc!{
+///     "$regex": "^ foo [0-9]+",
+///     "$options": "ix",
+/// });
+/// # }
+/// ```
+#[cfg(feature = "regex")]
+pub fn from_regex(regex: &Regex) -> Result<Document> {
+    let source = regex.as_str();
+    let (flags, pattern) = match leading_flags(source) {
+        Some(end) => (&source[2..end], &source[end + 1..]),
+        None => ("", source),
+    };
+    let mut options = RegexOpts::default();
+
+    for flag in flags.chars() {
+        options |= match flag {
+            'i' => RegexOpts::IGNORE_CASE,
+            'm' => RegexOpts::LINE_ANCHOR,
+            'x' => RegexOpts::EXTENDED,
+            's' => RegexOpts::DOT_NEWLINE,
+            'u' => RegexOpts::default(),
+            _ => return Err(Error::new(
+                ErrorKind::InvalidRegex,
+                format!("flag `{}` of regex {:?} isn't supported by MongoDB", flag, source)
+            )),
+        };
+    }
+
+    Ok(doc!{
+        "$regex": pattern,
+        "$options": options,
+    })
+}
+
+/// Returns the index of the closing parenthesis of the group of inline flags
+/// at the beginning of a pattern, e.g. `(?i)`, if any. Groups which clear
+/// flags, e.g. `(?i-s)`, are left alone, since the server understands them.
+#[cfg(feature = "regex")]
+fn leading_flags(pattern: &str) -> Option<usize> {
+    if !pattern.starts_with("(?") {
+        return None;
+    }
+
+    let end = pattern.find(')')?;
+    let flags = &pattern[2..end];
+
+    if !flags.is_empty() && flags.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+/// The top-level operators which don't refer to a field.
+const TOP_LEVEL_OPERATORS: &[&str] = &[
+    "$and", "$or", "$nor", "$expr", "$text", "$where", "$comment", "$jsonSchema",
+];
+
+/// The operators which may appear in the condition of a field.
+const FIELD_OPERATORS: &[&str] = &[
+    "$eq", "$ne", "$gt", "$gte", "$lt", "$lte", "$in", "$nin", "$all",
+    "$exists", "$type", "$mod", "$regex", "$options", "$size", "$elemMatch",
+    "$not", "$bitsAllSet", "$bitsAllClear", "$bitsAnySet", "$bitsAnyClear",
+    "$geoWithin", "$geoIntersects", "$near", "$nearSphere",
+    "$maxDistance", "$minDistance",
+];
+
+/// Checks a filter for mistakes which the server would only report when the
+/// query is executed: logical operators without clauses, unknown operators,
+/// operands of the wrong kind, a negative `$size`, a zero `$mod` divisor,
+/// regex options given both inline and in `$options`, and `$not` applied to
+/// a `$regex` operator, which servers before 4.0.7 reject (use a regular
+/// expression value instead).
+///
+/// All problems are collected; the returned `InvalidFilter` error lists them
+/// in its message, and individually via `error.context::<InvalidFilter>()`.
+pub fn validate(filter: &Document) -> Result<()> {
+    let mut problems = Vec::new();
+    check_filter(filter, "", &mut problems);
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let message = problems
+        .iter()
+        .map(|entry| format!("{}: {}", entry.path, entry.message))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    Err(Error::new(ErrorKind::InvalidFilter, format!("invalid filter: {}", message))
+        .with_context::<InvalidFilter>(InvalidFilter { problems }))
+}
+
+/// Simplifies a filter without changing which documents it matches: the
+/// clauses of `$and`s are merged into the enclosing filter when their fields
+/// don't collide, nested `$and`s and `$or`s are flattened, single-clause
+/// `$or`s are unwrapped, and double negations (`$nor` of a single-clause
+/// `$nor`) are removed. Malformed logical operators are left unchanged.
+pub fn normalize(filter: Document) -> Document {
+    let mut normalized = Document::new();
+    let mut conjuncts = Vec::new();
+
+    for (key, value) in filter {
+        let clauses = match logical_clauses(&key, &value) {
+            Some(clauses) => clauses,
+            None => {
+                normalized.insert(key, value);
+                continue;
+            }
+        };
+
+        match key.as_str() {
+            "$and" => conjuncts.extend(clauses.into_iter().map(normalize)),
+            "$or" => {
+                let mut disjuncts = Vec::new();
+
+                for clause in clauses.into_iter().map(normalize) {
+                    match single_logical(&clause, "$or") {
+                        Some(nested) => disjuncts.extend(nested),
+                        None => disjuncts.push(clause),
+                    }
+                }
+
+                if disjuncts.len() == 1 {
+                    conjuncts.extend(disjuncts);
+                } else {
+                    normalized.insert(key, documents_to_bson(disjuncts));
+                }
+            }
+            _ => {
+                let negated: Vec<_> = clauses.into_iter().map(normalize).collect();
+                let double = match *negated.as_slice() {
+                    [ref clause] => single_logical(clause, "$nor").filter(|inner| inner.len() == 1),
+                    _ => None,
+                };
+
+                match double {
+                    Some(inner) => conjuncts.extend(inner),
+                    None => { normalized.insert(key, documents_to_bson(negated)); }
+                }
+            }
+        }
+    }
+
+    let mut leftover = Vec::new();
+
+    for mut conjunct in conjuncts {
+        if let Some(nested) = logical_clauses_of(&mut conjunct, "$and") {
+            leftover.extend(nested);
+        }
+
+        if conjunct.keys().any(|key| normalized.contains_key(key)) {
+            leftover.push(conjunct);
+        } else {
+            for (key, value) in conjunct {
+                normalized.insert(key, value);
+            }
+        }
+    }
+
+    if !leftover.is_empty() {
+        normalized.insert("$and", documents_to_bson(leftover));
+    }
+
+    normalized
+}
+
+/// Builds a filter from a JSON query, as sent by e.g. an HTTP client.
+///
+/// Extended JSON values, such as `{ "$oid": "..." }` and
+/// `{ "$date": "2019-03-01T00:00:00Z" }`, are converted to the BSON values
+/// they stand for (see [`bsn::from_extended_json()`](../bsn/fn.from_extended_json.html)).
+/// The resulting filter is then checked using [`validate()`](fn.validate.html),
+/// so malformed queries are rejected before they reach the server. This
+/// doesn't restrict which fields or operators may be used, e.g. `$where`,
+/// so the JSON should still come from a trusted source.
+pub fn from_json(query: Value) -> Result<Document> {
+    let filter = from_extended_json(query)?.try_into_doc()?;
+    validate(&filter)?;
+    Ok(filter)
+}
+
+/// Returns the clauses of a well-formed, non-empty `$and`, `$or` or `$nor`.
+fn logical_clauses(key: &str, value: &Bson) -> Option<Vec<Document>> {
+    if key != "$and" && key != "$or" && key != "$nor" {
+        return None;
+    }
+
+    match *value {
+        Bson::Array(ref items) if !items.is_empty() => items
+            .iter()
+            .map(|item| match *item {
+                Bson::Document(ref clause) => Some(clause.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Returns the clauses of a filter consisting of a single logical operator.
+fn single_logical(filter: &Document, operator: &str) -> Option<Vec<Document>> {
+    if filter.len() == 1 {
+        filter.get(operator).and_then(|value| logical_clauses(operator, value))
+    } else {
+        None
+    }
+}
+
+/// Removes a well-formed logical operator from a filter, and returns its
+/// clauses.
+fn logical_clauses_of(filter: &mut Document, operator: &str) -> Option<Vec<Document>> {
+    let clauses = filter.get(operator).and_then(|value| logical_clauses(operator, value))?;
+    filter.remove(operator);
+    Some(clauses)
+}
+
+/// Converts clauses to the BSON array of a logical operator.
+fn documents_to_bson(clauses: Vec<Document>) -> Bson {
+    Bson::Array(clauses.into_iter().map(Bson::Document).collect())
+}
+
+/// Appends a key to a dotted path.
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Records a problem found at `path`.
+fn problem<S: Into<String>>(problems: &mut Vec<FilterProblem>, path: &str, message: S) {
+    problems.push(FilterProblem {
+        path: path.to_owned(),
+        message: message.into(),
+    });
+}
+
+/// Checks a (sub-)filter, whose keys are field names or top-level operators.
+fn check_filter(filter: &Document, path: &str, problems: &mut Vec<FilterProblem>) {
+    for (key, value) in filter {
+        let here = join_path(path, key);
+
+        match key.as_str() {
+            "$and" | "$or" | "$nor" => match *value {
+                Bson::Array(ref clauses) if clauses.is_empty() => {
+                    problem(problems, &here, format!("{} must have at least one clause", key));
+                }
+                Bson::Array(ref clauses) => for (index, clause) in clauses.iter().enumerate() {
+                    let clause_path = join_path(&here, &index.to_string());
+
+                    match *clause {
+                        Bson::Document(ref sub_filter) => check_filter(sub_filter, &clause_path, problems),
+                        _ => problem(problems, &clause_path, "clause must be a document"),
+                    }
+                },
+                _ => problem(problems, &here, format!("{} must be an array of filters", key)),
+            },
+            "$text" | "$jsonSchema" => match *value {
+                Bson::Document(_) => {}
+                _ => problem(problems, &here, format!("{} must be a document", key)),
+            },
+            _ if TOP_LEVEL_OPERATORS.contains(&key.as_str()) => {}
+            _ if key.starts_with('$') => problem(problems, &here, "unknown top-level operator"),
+            _ => check_condition(value, &here, problems),
+        }
+    }
+}
+
+/// Checks the condition of a field, which is either a value to compare with
+/// or a document of operators.
+fn check_condition(condition: &Bson, path: &str, problems: &mut Vec<FilterProblem>) {
+    let operators = match *condition {
+        Bson::Document(ref operators) if is_operator_document(operators) => operators,
+        _ => return,
+    };
+
+    for (operator, operand) in operators {
+        let here = join_path(path, operator);
+
+        if !operator.starts_with('$') {
+            problem(problems, &here, "field name mixed with operators");
+            continue;
+        }
+        if !FIELD_OPERATORS.contains(&operator.as_str()) {
+            problem(problems, &here, "unknown operator");
+            continue;
+        }
+
+        match operator.as_str() {
+            "$in" | "$nin" | "$all" => match *operand {
+                Bson::Array(_) => {}
+                _ => problem(problems, &here, "operand must be an array"),
+            },
+            "$size" => match *operand {
+                Bson::I32(n) if n >= 0 => {}
+                Bson::I64(n) if n >= 0 => {}
+                Bson::FloatingPoint(x) if x >= 0.0 && x.fract() == 0.0 => {}
+                _ => problem(problems, &here, "operand must be a non-negative integer"),
+            },
+            "$mod" => match *operand {
+                Bson::Array(ref items) if items.len() == 2 => match items[0] {
+                    Bson::I32(0) | Bson::I64(0) => problem(problems, &here, "divisor must not be zero"),
+                    Bson::FloatingPoint(x) if x.abs() < 1.0 => {
+                        problem(problems, &here, "divisor must not be zero");
+                    }
+                    Bson::I32(_) | Bson::I64(_) | Bson::FloatingPoint(_) => {}
+                    _ => problem(problems, &here, "divisor must be a number"),
+                },
+                _ => problem(problems, &here, "operand must be [divisor, remainder]"),
+            },
+            "$regex" => match *operand {
+                Bson::String(_) => {}
+                Bson::RegExp(_, ref flags) => if !flags.is_empty() && operators.contains_key("$options") {
+                    problem(problems, &here, "options given both inline and in $options");
+                },
+                _ => problem(problems, &here, "operand must be a string or a regular expression"),
+            },
+            "$options" => if !operators.contains_key("$regex") {
+                problem(problems, &here, "$options without $regex");
+            },
+            "$not" => match *operand {
+                Bson::Document(ref negated) if !negated.is_empty() && is_operator_document(negated) => {
+                    if negated.contains_key("$regex") {
+                        problem(problems, &here, "$not of $regex; use a regular expression value instead");
+                    }
+                    check_condition(operand, &here, problems);
+                }
+                Bson::RegExp(..) => {}
+                _ => problem(problems, &here, "operand must be an operator expression or a regular expression"),
+            },
+            "$elemMatch" => match *operand {
+                Bson::Document(ref element) if is_operator_document(element) => {
+                    check_condition(operand, &here, problems);
+                }
+                Bson::Document(ref element) => check_filter(element, &here, problems),
+                _ => problem(problems, &here, "operand must be a document"),
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, spec::BinarySubtype };
+    use crate::literal::{ Language, TextFlags, BsonType };
+    use super::{ not, nor, nor_fields, text };
+    use super::{ bits_all_set, bits_all_clear, bits_any_set, bits_any_clear };
+    use super::{ expr, any_element_of_type, all_elements_of_type };
+    use super::{ modulo, where_js, with_comment };
+    use super::{ validate, normalize };
+    use crate::error::{ ErrorKind, ErrorExt, InvalidFilter };
+    use crate::expr::{ field, is_number };
+
+    #[test]
+    fn negation() {
+        let filter = doc!{ "name": "foo", "age": { "$gt": 18 } };
+
+        assert_eq!(not(filter.clone()), doc!{
+            "$nor": [{ "name": "foo", "age": { "$gt": 18 } }]
+        });
+        assert_eq!(not(not(filter.clone())), filter);
+        assert_eq!(not(doc!{}), doc!{ "$nor": [{}] });
+    }
+
+    #[test]
+    fn nor_of_fields() {
+        assert_eq!(nor(vec![doc!{ "a": 1 }, doc!{ "b": 2 }]), doc!{
+            "$nor": [{ "a": 1 }, { "b": 2 }]
+        });
+        assert_eq!(nor_fields(doc!{ "a": 1, "b": { "$exists": true } }), doc!{
+            "$nor": [{ "a": 1 }, { "b": { "$exists": true } }]
+        });
+    }
+
+    #[test]
+    fn text_search() {
+        assert_eq!(text("coffee", None, TextFlags::default()), doc!{
+            "$text": {
+                "$search": "coffee",
+                "$caseSensitive": false,
+                "$diacriticSensitive": false,
+            }
+        });
+        assert_eq!(text("Kaffee", Some(Language::German), TextFlags::all()), doc!{
+            "$text": {
+                "$search": "Kaffee",
+                "$language": "german",
+                "$caseSensitive": true,
+                "$diacriticSensitive": true,
+            }
+        });
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(bits_all_set(5_i64), doc!{ "$bitsAllSet": 5_i64 });
+        assert_eq!(bits_all_clear(vec![0_u32, 3]), doc!{ "$bitsAllClear": [0_i64, 3_i64] });
+        assert_eq!(bits_any_set(vec![0b1000_0001_u8]), doc!{
+            "$bitsAnySet": Bson::Binary(BinarySubtype::Generic, vec![0b1000_0001])
+        });
+        assert_eq!(bits_any_clear(0_i64), doc!{ "$bitsAnyClear": 0_i64 });
+    }
+
+    #[test]
+    fn type_tests() {
+        assert_eq!(expr(is_number(field("price"))), doc!{
+            "$expr": { "$isNumber": "$price" }
+        });
+        assert_eq!(any_element_of_type(BsonType::STRING), doc!{
+            "$elemMatch": { "$type": "string" }
+        });
+        assert_eq!(all_elements_of_type(BsonType::INT | BsonType::LONG), doc!{
+            "$not": { "$elemMatch": { "$not": { "$type": ["int", "long"] } } }
+        });
+    }
+
+    #[test]
+    fn shell_operators() {
+        let filter = with_comment(doc!{ "qty": modulo(4, 0) }, "even batches");
+
+        assert_eq!(filter, doc!{
+            "qty": { "$mod": [4_i64, 0_i64] },
+            "$comment": "even batches",
+        });
+        assert_eq!(
+            where_js("this.credits == this.debits"),
+            doc!{ "$where": Bson::JavaScriptCode("this.credits == this.debits".into()) }
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert!(validate(&doc!{}).is_ok());
+        assert!(validate(&doc!{
+            "name": { "$in": ["a", "b"] },
+            "tags": { "$size": 0, "$all": ["x"] },
+            "age": { "$not": { "$gt": 18 }, "$mod": [2, 0] },
+            "email": { "$regex": "@example\\.com$", "$options": "i" },
+            "orders": { "$elemMatch": { "total": { "$gt": 10 } } },
+            "$or": [{ "a": 1 }, { "b": { "$exists": false } }],
+            "$comment": "valid",
+        }).is_ok());
+
+        let error = validate(&doc!{
+            "$and": [],
+            "$or": [{ "a": { "$foo": 1 } }, 42],
+            "$bogus": 1,
+            "tags": { "$size": -1, "$in": "x" },
+            "age": { "$mod": [0, 1], "$gt": 1, "eq": 2 },
+            "name": { "$not": { "$regex": "^a" }, "$options": "i" },
+            "email": { "$regex": Bson::RegExp("x".into(), "i".into()), "$options": "m" },
+        }).unwrap_err();
+        let paths: Vec<_> = error
+            .context::<InvalidFilter>()
+            .unwrap()
+            .problems
+            .iter()
+            .map(|problem| problem.path.as_str())
+            .collect();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidFilter);
+        assert_eq!(paths, [
+            "$and",
+            "$or.0.a.$foo",
+            "$or.1",
+            "$bogus",
+            "tags.$size",
+            "tags.$in",
+            "age.$mod",
+            "age.eq",
+            "name.$not",
+            "name.$options",
+            "email.$regex",
+        ]);
+    }
+
+    #[test]
+    fn normalization() {
+        let filter = doc!{ "a": 1, "b": { "$gt": 2 } };
+        assert_eq!(normalize(filter.clone()), filter);
+
+        assert_eq!(
+            normalize(doc!{ "$and": [{ "a": 1 }, { "$and": [{ "b": 2 }, { "c": 3 }] }] }),
+            doc!{ "a": 1, "b": 2, "c": 3 }
+        );
+        assert_eq!(
+            normalize(doc!{ "a": 1, "$and": [{ "a": { "$lt": 5 } }, { "b": 2 }] }),
+            doc!{ "a": 1, "b": 2, "$and": [{ "a": { "$lt": 5 } }] }
+        );
+        assert_eq!(
+            normalize(doc!{ "$or": [{ "a": 1 }, { "$or": [{ "b": 2 }, { "c": 3 }] }] }),
+            doc!{ "$or": [{ "a": 1 }, { "b": 2 }, { "c": 3 }] }
+        );
+        assert_eq!(normalize(doc!{ "$or": [{ "a": 1 }] }), doc!{ "a": 1 });
+        assert_eq!(normalize(not(not(doc!{ "$nor": [{ "a": 1 }] }))), doc!{ "$nor": [{ "a": 1 }] });
+        assert_eq!(normalize(doc!{ "$nor": [{ "$nor": [{ "a": 1, "b": 2 }] }] }), doc!{ "a": 1, "b": 2 });
+        assert_eq!(
+            normalize(doc!{ "$and": [{ "$or": [{ "a": 1 }, { "b": 1 }] }, { "$or": [{ "c": 1 }, { "d": 1 }] }] }),
+            doc!{
+                "$or": [{ "a": 1 }, { "b": 1 }],
+                "$and": [{ "$or": [{ "c": 1 }, { "d": 1 }] }],
+            }
+        );
+        assert_eq!(normalize(doc!{ "$and": [] }), doc!{ "$and": [] });
+    }
+
+    #[test]
+    fn json_queries() {
+        use chrono::{ TimeZone, Utc };
+        use bson::oid::ObjectId;
+        use super::from_json;
+
+        let hex = "5c7e5b1f0d4c8a2b3c4d5e6f";
+        let query = json!({
+            "owner": { "$oid": hex },
+            "created": { "$gte": { "$date": "2019-03-01T00:00:00Z" } },
+            "views": { "$gt": { "$numberLong": "9007199254740993" } },
+            "$or": [{ "title": { "$regex": "^a", "$options": "i" } }, { "pinned": true }],
+        });
+
+        assert_eq!(from_json(query).unwrap(), doc!{
+            "owner": ObjectId::with_string(hex).unwrap(),
+            "created": { "$gte": Utc.ymd(2019, 3, 1).and_hms(0, 0, 0) },
+            "views": { "$gt": 9_007_199_254_740_993_i64 },
+            "$or": [{ "title": { "$regex": "^a", "$options": "i" } }, { "pinned": true }],
+        });
+
+        assert_eq!(
+            from_json(json!({ "owner": { "$oid": "nope" } })).unwrap_err().kind(),
+            ErrorKind::ObjectIdGeneration
+        );
+        assert_eq!(
+            from_json(json!({ "$or": [] })).unwrap_err().kind(),
+            ErrorKind::InvalidFilter
+        );
+        assert!(from_json(json!([{ "a": 1 }])).is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn compiled_regex() {
+        use regex::Regex;
+        use super::from_regex;
+
+        let convert = |pattern| from_regex(&Regex::new(pattern).unwrap());
+
+        assert_eq!(convert("^foo$").unwrap(), doc!{ "$regex": "^foo$", "$options": "" });
+        assert_eq!(convert("(?smu)a.b").unwrap(), doc!{ "$regex": "a.b", "$options": "ms" });
+        assert_eq!(convert("(?i:a)b").unwrap(), doc!{ "$regex": "(?i:a)b", "$options": "" });
+        assert_eq!(convert("(?i-s)a").unwrap(), doc!{ "$regex": "(?i-s)a", "$options": "" });
+        assert_eq!(convert("(?U)a+").unwrap_err().kind(), ErrorKind::InvalidRegex);
+    }
+}
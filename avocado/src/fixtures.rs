@@ -0,0 +1,596 @@
+//! Loading fixtures, i.e. documents seeding the collections used by tests.
+//!
+//! A fixture file holds the documents of a single collection, either as
+//! JSON or as BSON:
+//!
+//! * A `.json` file contains, in MongoDB Extended JSON, either an array of
+//!   documents or an object mapping labels to documents.
+//! * A `.bson` file contains a sequence of documents, as written by
+//!   `mongodump`.
+//!
+//! Documents without an `_id` get a deterministic `ObjectId`, derived from
+//! the name of the collection and the label of the document (for arrays and
+//! BSON files, its index in the file), so that tests can refer to them via
+//! [`object_id()`](fn.object_id.html) or [`uid()`](fn.uid.html). A document
+//! refers to another fixture by `{ "$fixture": "collection/label" }`, which
+//! is replaced by the `_id` of that fixture before inserting.
+//!
+//! ```json
+//! {
+//!     "alice": { "name": "Alice", "manager": { "$fixture": "User/bob" } },
+//!     "bob": { "name": "Bob", "manager": null }
+//! }
+//! ```
+//!
+//! Each document is deserialized as the entity type of the collection, and
+//! inserted via `Collection::insert_many()`, so malformed fixtures are
+//! reported before anything is written, and hooks and timestamps apply as
+//! usual. A [`FixtureSet`](struct.FixtureSet.html) loads the fixture files
+//! of several collections from a directory, optionally emptying the
+//! collections first, and inserts the referenced collections before the
+//! referencing ones.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::fixtures::{ self, FixtureSet };
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//!     manager: Option<Uid<User>>,
+//! }
+//!
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct Post {
+//!     _id: Uid<Post>,
+//!     author: Uid<User>,
+//!     title: String,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! // A single file, without truncating the collection.
+//! let users = fixtures::load::<User, _>(&db, "tests/fixtures/User.json")?;
+//! assert_eq!(users[0]._id, fixtures::uid::<User>("alice"));
+//!
+//! // `tests/fixtures/User.json` and `tests/fixtures/Post.json`; the users
+//! // are inserted first if the posts refer to them.
+//! let loaded = FixtureSet::new("tests/fixtures")
+//!     .with::<Post>()
+//!     .with::<User>()
+//!     .truncate(true)
+//!     .load(&db)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::mem;
+use std::io::Cursor;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use std::path::{ Path, PathBuf };
+use std::collections::{ HashMap, BTreeSet };
+use bson::{ Bson, Document, oid::ObjectId };
+use mongodb::db::Database;
+use serde_json::Value;
+use crate::{
+    db::DatabaseExt,
+    doc::Doc,
+    uid::Uid,
+    error::{ Error, ErrorKind, Result, ResultExt },
+};
+
+/// The key of a reference to another fixture.
+const REFERENCE_KEY: &str = "$fixture";
+
+/// The offset basis of the 64-bit FNV-1a hash.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The prime of the 64-bit FNV-1a hash.
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// The IDs of the fixtures which specify their own `_id`, keyed by the name
+/// of the collection and the label of the fixture.
+type ExplicitIds = HashMap<(String, String), Bson>;
+
+/// Returns the deterministic `ObjectId` of the fixture with the given label,
+/// in the named collection. The result is stable across runs, platforms and
+/// versions of this crate.
+pub fn object_id(collection: &str, label: &str) -> ObjectId {
+    let key = format!("{}/{}", collection, label);
+    let high = fnv1a(FNV_OFFSET_BASIS, key.as_bytes());
+    let low = fnv1a(high, key.as_bytes());
+    let mut bytes = [0_u8; 12];
+
+    bytes[..8].copy_from_slice(&high.to_be_bytes());
+    bytes[8..].copy_from_slice(&low.to_be_bytes()[..4]);
+
+    ObjectId::with_bytes(bytes)
+}
+
+/// Returns the deterministic ID of the fixture of type `T` with the given
+/// label, in the collection `T::NAME`.
+pub fn uid<T: Doc<Id = ObjectId>>(label: &str) -> Uid<T> {
+    Uid::from_raw(object_id(T::NAME, label))
+}
+
+/// Loads the fixtures in the file at `path` into the collection of `T`,
+/// and returns the inserted entities, in the order of the file. References
+/// to other collections resolve to deterministic IDs, since the fixtures
+/// of those aren't known.
+pub fn load<T, P>(db: &Database, path: P) -> Result<Vec<T>>
+    where T: Doc + 'static,
+          T::Id: Clone + Debug,
+          P: AsRef<Path>,
+{
+    let file = path.as_ref();
+    let message = || format!("can't load fixtures of {} from {}", T::NAME, file.display());
+    let fixtures = read_fixtures(file).chain(&message)?;
+    let mut ids = ExplicitIds::new();
+
+    collect_explicit_ids(T::NAME, &fixtures, &mut ids);
+
+    let docs = prepare(T::NAME, fixtures, &ids).chain(&message)?;
+
+    insert_fixtures::<T>(db, docs).chain(&message)
+}
+
+/// The fixture files of several collections in a directory, loaded in the
+/// order of the references among them.
+#[derive(Debug, Clone)]
+pub struct FixtureSet {
+    /// The directory containing the fixture files.
+    dir: PathBuf,
+    /// Whether to delete all documents of the collections before loading.
+    truncate: bool,
+    /// The collections to load, in the order they were added.
+    collections: Vec<FixtureCollection>,
+}
+
+/// A type-erased collection to be loaded by a `FixtureSet`.
+#[derive(Clone, Copy)]
+struct FixtureCollection {
+    /// The name of the collection, which is also the stem of its file.
+    name: &'static str,
+    /// Deserializes and inserts the prepared documents.
+    insert: fn(&Database, Vec<Document>) -> Result<usize>,
+    /// Deletes all documents of the collection.
+    truncate: fn(&Database) -> Result<usize>,
+}
+
+impl FixtureSet {
+    /// Creates an empty set of fixtures, read from the directory `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FixtureSet {
+            dir: dir.into(),
+            truncate: false,
+            collections: Vec::new(),
+        }
+    }
+
+    /// Adds the collection of `T`, whose fixtures are read from the file
+    /// `{T::NAME}.json` or `{T::NAME}.bson` in the directory.
+    pub fn with<T>(mut self) -> Self
+        where T: Doc + 'static,
+              T::Id: Clone + Debug,
+    {
+        self.collections.push(FixtureCollection {
+            name: T::NAME,
+            insert: |db, docs| insert_fixtures::<T>(db, docs).map(|values| values.len()),
+            truncate: |db| db.existing_collection::<T>().purge(Document::new()),
+        });
+        self
+    }
+
+    /// Sets whether all documents of the collections are deleted before
+    /// loading the fixtures. The default is not to delete anything.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Loads the fixtures of all collections. Returns the names of the
+    /// collections and the number of documents inserted into each, in the
+    /// order they were loaded. Collections referenced by the fixtures of
+    /// another collection are loaded before it, unless the references are
+    /// circular, in which case the order of `with()` calls decides.
+    pub fn load(&self, db: &Database) -> Result<Vec<(&'static str, usize)>> {
+        let message = || format!("can't load fixtures from {}", self.dir.display());
+        let mut fixtures = Vec::with_capacity(self.collections.len());
+        let mut ids = ExplicitIds::new();
+
+        for collection in &self.collections {
+            let file = self.fixture_file(collection.name).chain(&message)?;
+            let file_fixtures = read_fixtures(&file).chain(
+                || format!("can't read fixtures of {} from {}", collection.name, file.display())
+            )?;
+
+            collect_explicit_ids(collection.name, &file_fixtures, &mut ids);
+            fixtures.push(file_fixtures);
+        }
+
+        let names: Vec<_> = self.collections.iter().map(|collection| collection.name).collect();
+        let references: Vec<_> = fixtures.iter().map(|file_fixtures| {
+            let mut collections = BTreeSet::new();
+
+            for fixture in file_fixtures {
+                referenced_collections(&Bson::Document(fixture.doc.clone()), &mut collections);
+            }
+
+            collections
+        }).collect();
+        let order = loading_order(&names, &references);
+
+        if self.truncate {
+            for collection in &self.collections {
+                (collection.truncate)(db).chain(
+                    || format!("can't truncate collection {}", collection.name)
+                )?;
+            }
+        }
+
+        let mut pending: Vec<_> = fixtures.into_iter().map(Some).collect();
+        let mut loaded = Vec::with_capacity(order.len());
+
+        for index in order {
+            let collection = &self.collections[index];
+            let load_message = || format!("can't load fixtures of {}", collection.name);
+            let file_fixtures = pending[index].take().unwrap_or_default();
+            let docs = prepare(collection.name, file_fixtures, &ids).chain(&load_message)?;
+            let count = (collection.insert)(db, docs).chain(&load_message)?;
+
+            loaded.push((collection.name, count));
+        }
+
+        Ok(loaded)
+    }
+
+    /// Returns the path of the JSON or BSON fixture file of a collection.
+    fn fixture_file(&self, name: &str) -> Result<PathBuf> {
+        ["json", "bson"]
+            .iter()
+            .map(|extension| self.dir.join(format!("{}.{}", name, extension)))
+            .find(|file| file.is_file())
+            .ok_or_else(|| Error::new(
+                ErrorKind::Io,
+                format!("neither {}.json nor {}.bson exists", name, name)
+            ))
+    }
+}
+
+impl Debug for FixtureCollection {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        formatter.debug_struct("FixtureCollection").field("name", &self.name).finish()
+    }
+}
+
+/// A document in a fixture file.
+#[derive(Debug, Clone, PartialEq)]
+struct Fixture {
+    /// The label of the document, or its index in the file.
+    label: String,
+    /// The document as written in the file.
+    doc: Document,
+}
+
+/// Reads a JSON or BSON fixture file, depending on its extension.
+fn read_fixtures(file: &Path) -> Result<Vec<Fixture>> {
+    let bytes = fs::read(file)?;
+
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => parse_json(&bytes),
+        Some("bson") => parse_bson(&bytes),
+        _ => Err(Error::new(
+            ErrorKind::InvalidFixture,
+            "the extension of a fixture file must be `.json` or `.bson`"
+        )),
+    }
+}
+
+/// Parses an array of documents or an object of labelled documents.
+fn parse_json(bytes: &[u8]) -> Result<Vec<Fixture>> {
+    let labelled: Vec<(String, Value)> = match serde_json::from_slice(bytes)? {
+        Value::Array(values) => values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| (index.to_string(), value))
+            .collect(),
+        Value::Object(values) => values.into_iter().collect(),
+        _ => return Err(Error::new(
+            ErrorKind::InvalidFixture,
+            "a JSON fixture file must contain an array or an object"
+        )),
+    };
+
+    labelled
+        .into_iter()
+        .map(|(label, value)| match Bson::from(value) {
+            Bson::Document(doc) => Ok(Fixture { label, doc }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidFixture,
+                format!("fixture `{}` is not a document", label)
+            )),
+        })
+        .collect()
+}
+
+/// Parses a sequence of BSON documents, labelled by their indexes.
+fn parse_bson(bytes: &[u8]) -> Result<Vec<Fixture>> {
+    let mut reader = Cursor::new(bytes);
+    let mut fixtures = Vec::new();
+
+    while reader.position() < bytes.len() as u64 {
+        let doc = bson::decode_document(&mut reader)?;
+        let label = fixtures.len().to_string();
+
+        fixtures.push(Fixture { label, doc });
+    }
+
+    Ok(fixtures)
+}
+
+/// Records the IDs of the fixtures which specify their own `_id`.
+fn collect_explicit_ids(collection: &str, fixtures: &[Fixture], ids: &mut ExplicitIds) {
+    for fixture in fixtures {
+        if let Some(id) = fixture.doc.get("_id") {
+            ids.insert((collection.to_owned(), fixture.label.clone()), id.clone());
+        }
+    }
+}
+
+/// Assigns the deterministic IDs of the fixtures without an `_id`, and
+/// resolves the references to other fixtures.
+fn prepare(collection: &str, fixtures: Vec<Fixture>, ids: &ExplicitIds) -> Result<Vec<Document>> {
+    fixtures
+        .into_iter()
+        .map(|fixture| {
+            let Fixture { label, mut doc } = fixture;
+
+            if !doc.contains_key("_id") {
+                doc.insert("_id", object_id(collection, &label));
+            }
+
+            resolve_document_references(doc, ids).chain(
+                || format!("invalid fixture `{}/{}`", collection, label)
+            )
+        })
+        .collect()
+}
+
+/// If `value` is a `{ "$fixture": "collection/label" }` reference, returns
+/// the collection and the label it refers to.
+fn as_reference(value: &Bson) -> Option<Result<(&str, &str)>> {
+    let doc = match *value {
+        Bson::Document(ref doc) if doc.len() == 1 => doc,
+        _ => return None,
+    };
+    let reference = doc.get(REFERENCE_KEY)?;
+    let parsed = match *reference {
+        Bson::String(ref path) => {
+            let mut parts = path.splitn(2, '/');
+            match (parts.next(), parts.next()) {
+                (Some(collection), Some(label)) if !collection.is_empty() && !label.is_empty() => {
+                    Some((collection, label))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    Some(parsed.ok_or_else(|| Error::new(
+        ErrorKind::InvalidFixture,
+        format!("a fixture reference must be of the form \"collection/label\", not {}", reference)
+    )))
+}
+
+/// Replaces the references to other fixtures with their IDs.
+fn resolve_references(value: &mut Bson, ids: &ExplicitIds) -> Result<()> {
+    let id = match as_reference(value) {
+        Some(reference) => {
+            let (collection, label) = reference?;
+            ids.get(&(collection.to_owned(), label.to_owned()))
+                .cloned()
+                .unwrap_or_else(|| Bson::ObjectId(object_id(collection, label)))
+        }
+        None => {
+            match *value {
+                Bson::Document(ref mut doc) => {
+                    let fields = mem::replace(doc, Document::new());
+                    *doc = resolve_document_references(fields, ids)?;
+                }
+                Bson::Array(ref mut array) => {
+                    for item in array {
+                        resolve_references(item, ids)?;
+                    }
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+    };
+
+    *value = id;
+
+    Ok(())
+}
+
+/// Replaces the references to other fixtures in the fields of `doc` with
+/// their IDs, retaining the order of the fields.
+fn resolve_document_references(doc: Document, ids: &ExplicitIds) -> Result<Document> {
+    doc.into_iter()
+        .map(|(key, mut field)| {
+            resolve_references(&mut field, ids)?;
+            Ok((key, field))
+        })
+        .collect()
+}
+
+/// Collects the names of the collections referenced in `value`.
+fn referenced_collections(value: &Bson, collections: &mut BTreeSet<String>) {
+    match as_reference(value) {
+        Some(Ok((collection, _))) => {
+            collections.insert(collection.to_owned());
+        }
+        Some(Err(_)) => {}
+        None => match *value {
+            Bson::Document(ref doc) => {
+                for (_, field) in doc {
+                    referenced_collections(field, collections);
+                }
+            }
+            Bson::Array(ref array) => {
+                for item in array {
+                    referenced_collections(item, collections);
+                }
+            }
+            _ => {}
+        },
+    }
+}
+
+/// Returns the indexes of the collections in the order they should be
+/// loaded: each after the collections it references, except for circular
+/// references, which are broken in favor of the earlier collection.
+fn loading_order(names: &[&str], references: &[BTreeSet<String>]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(names.len());
+    let mut loaded = vec![false; names.len()];
+
+    while order.len() < names.len() {
+        let is_ready = |index: usize| references[index].iter().all(|referenced| {
+            names
+                .iter()
+                .enumerate()
+                .all(|(other, &name)| other == index || name != referenced || loaded[other])
+        });
+        let pending = || (0..names.len()).filter(|&index| !loaded[index]);
+        let next = pending()
+            .find(|&index| is_ready(index))
+            .or_else(|| pending().next());
+
+        match next {
+            Some(index) => {
+                loaded[index] = true;
+                order.push(index);
+            }
+            None => break,
+        }
+    }
+
+    order
+}
+
+/// Computes the 64-bit FNV-1a hash of `bytes`, starting from `seed`.
+fn fnv1a(seed: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(seed, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
+/// Deserializes the prepared documents as `T`, then inserts them.
+fn insert_fixtures<T>(db: &Database, docs: Vec<Document>) -> Result<Vec<T>>
+    where T: Doc + 'static,
+          T::Id: Clone + Debug,
+{
+    let values = docs
+        .into_iter()
+        .map(|doc| {
+            let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+            bson::from_bson(Bson::Document(doc)).chain(
+                || format!("fixture with _id {} is not a valid {}", id, T::NAME)
+            )
+        })
+        .collect::<Result<Vec<T>>>()?;
+
+    db.existing_collection::<T>().insert_many(&values)?;
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use bson::Bson;
+    use super::{
+        ExplicitIds, object_id, parse_json, parse_bson, prepare,
+        collect_explicit_ids, referenced_collections, loading_order,
+    };
+
+    #[test]
+    fn deterministic_ids() {
+        assert_eq!(object_id("User", "alice"), object_id("User", "alice"));
+        assert_ne!(object_id("User", "alice"), object_id("User", "bob"));
+        assert_ne!(object_id("User", "alice"), object_id("Post", "alice"));
+    }
+
+    #[test]
+    fn json_fixtures_and_references() {
+        let labelled = parse_json(br#"{
+            "alice": { "name": "Alice", "manager": { "$fixture": "User/bob" } },
+            "bob": { "_id": 42, "name": "Bob", "posts": [{ "$fixture": "Post/0" }] }
+        }"#).unwrap();
+        let listed = parse_json(br#"[{ "title": "Hello" }]"#).unwrap();
+        let mut ids = ExplicitIds::new();
+
+        collect_explicit_ids("User", &labelled, &mut ids);
+
+        let users = prepare("User", labelled, &ids).unwrap();
+        let posts = prepare("Post", listed, &ids).unwrap();
+
+        assert_eq!(users[0], doc!{
+            "name": "Alice",
+            "manager": 42_i64,
+            "_id": object_id("User", "alice"),
+        });
+        assert_eq!(users[1], doc!{
+            "_id": 42_i64,
+            "name": "Bob",
+            "posts": [object_id("Post", "0")],
+        });
+        assert_eq!(posts[0], doc!{ "title": "Hello", "_id": object_id("Post", "0") });
+
+        let invalid = parse_json(br#"[{ "author": { "$fixture": "alice" } }]"#).unwrap();
+        assert!(prepare("Post", invalid, &ids).is_err());
+        assert!(parse_json(b"42").is_err());
+    }
+
+    #[test]
+    fn bson_fixtures() {
+        let mut bytes = Vec::new();
+        bson::encode_document(&mut bytes, &doc!{ "x": 1 }).unwrap();
+        bson::encode_document(&mut bytes, &doc!{ "x": 2 }).unwrap();
+
+        let fixtures = parse_bson(&bytes).unwrap();
+
+        assert_eq!(fixtures.len(), 2);
+        assert_eq!(fixtures[1].label, "1");
+        assert_eq!(fixtures[1].doc, doc!{ "x": 2 });
+    }
+
+    #[test]
+    fn referenced_collections_first() {
+        let references = |names: &[&str]| -> BTreeSet<String> {
+            names.iter().map(|&name| name.to_owned()).collect()
+        };
+        let mut found = BTreeSet::new();
+
+        referenced_collections(
+            &Bson::Document(doc!{ "a": [{ "$fixture": "User/x" }], "b": { "$fixture": "Tag/y" } }),
+            &mut found,
+        );
+        assert_eq!(found, references(&["Tag", "User"]));
+
+        let names = ["Comment", "Post", "User"];
+        let acyclic = [references(&["Post", "User"]), references(&["User"]), references(&[])];
+        assert_eq!(loading_order(&names, &acyclic), vec![2, 1, 0]);
+
+        let cyclic = [references(&["Post"]), references(&["Comment"]), references(&["User"])];
+        assert_eq!(loading_order(&names, &cyclic), vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,527 @@
+//! GeoJSON geometries and geospatial query operators.
+//!
+//! The geometry types serialize to, and deserialize from, GeoJSON objects as
+//! stored and queried by MongoDB, e.g. `{ type: "Point", coordinates: [lng, lat] }`.
+//! The functions of this module build the operator expression of a single
+//! field, for use in filter documents:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::geo::{ Point, near_sphere };
+//! #
+//! # fn main() {
+//! let filter = doc!{
+//!     "location": near_sphere(Point::new(-73.97, 40.77), Some(500.0), None),
+//! };
+//! assert_eq!(filter, doc!{
+//!     "location": {
+//!         "$nearSphere": {
+//!             "$geometry": { "type": "Point", "coordinates": [-73.97, 40.77] },
+//!             "$maxDistance": 500.0,
+//!         }
+//!     }
+//! });
+//! # }
+//! ```
+//!
+//! Fields queried with `$near` and `$nearSphere` must be indexed by a
+//! `2dsphere` index, or by a `2d` index for legacy coordinate pairs. Such an
+//! index can be declared on the field itself, by `#[avocado(geo_index)]` or
+//! `#[avocado(geo_index = "2d")]`.
+//!
+//! The [`Near`](struct.Near.html) aggregation, usually run using
+//! `Collection::geo_near()`, returns the documents nearest to a point along
+//! with their computed distance, e.g. for finding the closest stores:
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::geo::{ Point, NearOptions };
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct Store {
+//!     _id: Uid<Store>,
+//!     name: String,
+//!     #[avocado(geo_index)]
+//!     location: Point,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let stores: Collection<Store> = db.existing_collection();
+//! let options = NearOptions {
+//!     max_distance: Some(2000.0),
+//!     limit: Some(5),
+//!     ..Default::default()
+//! };
+//!
+//! for result in stores.geo_near(Point::new(-73.97, 40.77), options)? {
+//!     let result = result?;
+//!     println!("{} is {:.0} m away", result.doc.name, result.distance);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use bson::{ Bson, Document, to_bson };
+use crate::{
+    doc::Doc,
+    ops::Pipeline,
+    error::{ Error, ErrorKind, Result },
+};
+
+/// The field the distance computed by `$geoNear` is stored in temporarily.
+const DISTANCE_FIELD: &str = "avocadoGeoDistance";
+
+/// A position: longitude and latitude, in this order, in degrees.
+pub type Position = [f64; 2];
+
+/// A single position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    /// The longitude and the latitude of the point.
+    pub coordinates: Position,
+}
+
+impl Point {
+    /// Creates a point from its longitude and latitude.
+    pub fn new(longitude: f64, latitude: f64) -> Self {
+        Point { coordinates: [longitude, latitude] }
+    }
+}
+
+/// A path of two or more positions.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LineString {
+    /// The positions along the path.
+    pub coordinates: Vec<Position>,
+}
+
+/// A polygon, possibly with holes. Each ring is closed, i.e. its first and
+/// last positions are the same; the first ring is the exterior boundary,
+/// the rest are holes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Polygon {
+    /// The rings of the polygon.
+    pub coordinates: Vec<Vec<Position>>,
+}
+
+impl Polygon {
+    /// Creates a polygon without holes from the positions of its exterior
+    /// boundary. The ring is closed automatically if necessary.
+    pub fn new<I: IntoIterator<Item = Position>>(exterior: I) -> Self {
+        let mut ring: Vec<_> = exterior.into_iter().collect();
+
+        if ring.first() != ring.last() {
+            let first = ring[0];
+            ring.push(first);
+        }
+
+        Polygon { coordinates: vec![ring] }
+    }
+}
+
+/// A set of polygons.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MultiPolygon {
+    /// The rings of each polygon.
+    pub coordinates: Vec<Vec<Vec<Position>>>,
+}
+
+/// Any of the supported GeoJSON geometries, tagged with its type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    /// A single position.
+    Point(Point),
+    /// A path.
+    LineString(LineString),
+    /// A polygon.
+    Polygon(Polygon),
+    /// A set of polygons.
+    MultiPolygon(MultiPolygon),
+}
+
+impl From<Point> for Geometry {
+    fn from(point: Point) -> Self {
+        Geometry::Point(point)
+    }
+}
+
+impl From<LineString> for Geometry {
+    fn from(line: LineString) -> Self {
+        Geometry::LineString(line)
+    }
+}
+
+impl From<Polygon> for Geometry {
+    fn from(polygon: Polygon) -> Self {
+        Geometry::Polygon(polygon)
+    }
+}
+
+impl From<MultiPolygon> for Geometry {
+    fn from(polygons: MultiPolygon) -> Self {
+        Geometry::MultiPolygon(polygons)
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<Geometry> for Bson {
+    fn from(geometry: Geometry) -> Self {
+        to_bson(&geometry).unwrap_or_default()
+    }
+}
+
+/// Matches documents whose geometry lies entirely within `geometry`, which
+/// should be a `Polygon` or a `MultiPolygon`.
+pub fn within<G: Into<Geometry>>(geometry: G) -> Document {
+    doc!{ "$geoWithin": { "$geometry": geometry.into() } }
+}
+
+/// Matches documents whose geometry intersects `geometry`.
+pub fn intersects<G: Into<Geometry>>(geometry: G) -> Document {
+    doc!{ "$geoIntersects": { "$geometry": geometry.into() } }
+}
+
+/// Matches documents near `point`, nearest first, using spherical geometry
+/// on a `2dsphere` index. Distances are in meters.
+pub fn near(point: Point, max_distance: Option<f64>, min_distance: Option<f64>) -> Document {
+    proximity("$near", point, max_distance, min_distance)
+}
+
+/// Matches documents near `point`, nearest first, always using spherical
+/// geometry. Distances are in meters.
+pub fn near_sphere(point: Point, max_distance: Option<f64>, min_distance: Option<f64>) -> Document {
+    proximity("$nearSphere", point, max_distance, min_distance)
+}
+
+/// Matches documents whose legacy coordinate pair lies within the circle of
+/// `radius` (in radians) around `center`, using spherical geometry.
+pub fn within_center_sphere(center: Position, radius: f64) -> Document {
+    doc!{ "$geoWithin": { "$centerSphere": [position_bson(center), radius] } }
+}
+
+/// Matches documents whose legacy coordinate pair lies within the box with
+/// corners `bottom_left` and `top_right`, using planar geometry.
+pub fn within_box(bottom_left: Position, top_right: Position) -> Document {
+    doc!{ "$geoWithin": { "$box": [position_bson(bottom_left), position_bson(top_right)] } }
+}
+
+/// Matches documents near the legacy coordinate pair `point`, nearest first,
+/// using planar geometry on a `2d` index. The distance is in the units of
+/// the coordinate system.
+pub fn near_legacy(point: Position, max_distance: Option<f64>) -> Document {
+    let mut expr = doc!{ "$near": position_bson(point) };
+
+    if let Some(max) = max_distance {
+        expr.insert("$maxDistance", max);
+    }
+
+    expr
+}
+
+/// Options of a `Near` aggregation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearOptions {
+    /// The geospatially indexed field to use. Only required if there are
+    /// several geospatial indexes. Default: `None`.
+    pub key: Option<String>,
+    /// Additional conditions the returned documents must satisfy.
+    /// Default: none.
+    pub filter: Document,
+    /// Documents farther than this from the point are not returned.
+    /// Default: `None`.
+    pub max_distance: Option<f64>,
+    /// Documents closer than this to the point are not returned.
+    /// Default: `None`.
+    pub min_distance: Option<f64>,
+    /// The maximal number of documents to return. Default: `None`.
+    pub limit: Option<usize>,
+    /// Whether to use spherical geometry on a `2dsphere` index, with the
+    /// point given as GeoJSON, and distances in meters. Otherwise, planar
+    /// geometry is used on a `2d` index, with the point given as a legacy
+    /// coordinate pair, and distances in the units of the coordinate system.
+    /// Default: `true`.
+    pub spherical: bool,
+    /// The factor all returned distances are multiplied by, e.g. 0.001 for
+    /// kilometers instead of meters. Default: `None`.
+    pub distance_multiplier: Option<f64>,
+}
+
+impl Default for NearOptions {
+    fn default() -> Self {
+        NearOptions {
+            key: None,
+            filter: Document::new(),
+            max_distance: None,
+            min_distance: None,
+            limit: None,
+            spherical: true,
+            distance_multiplier: None,
+        }
+    }
+}
+
+/// A document found by a `Near` aggregation, with its distance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NearResult<T> {
+    /// The distance of the document from the point, multiplied by the
+    /// `distance_multiplier` if one was specified.
+    pub distance: f64,
+    /// The document.
+    pub doc: T,
+}
+
+/// Finds the documents of type `T` nearest to a point, nearest first, and
+/// computes their distance from it, using a `$geoNear` aggregation.
+pub struct Near<T: Doc> {
+    /// The point to measure distances from.
+    pub point: Point,
+    /// The index, filters and limits of the search.
+    pub options: NearOptions,
+    /// Only here so that `T` is used.
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Doc> Near<T> {
+    /// Searches for the documents nearest to `point`.
+    pub fn new(point: Point, options: NearOptions) -> Self {
+        Near {
+            point,
+            options,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc> Clone for Near<T> {
+    fn clone(&self) -> Self {
+        Near::new(self.point, self.options.clone())
+    }
+}
+
+impl<T: Doc> Debug for Near<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Near")
+            .field("collection", &T::NAME)
+            .field("point", &self.point)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl<T: Doc> Pipeline<T> for Near<T> {
+    type Output = NearResult<T>;
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn stages(&self) -> Vec<Document> {
+        let options = &self.options;
+        let near = if options.spherical {
+            Bson::from(Geometry::Point(self.point))
+        } else {
+            position_bson(self.point.coordinates)
+        };
+        let mut spec = doc!{
+            "near": near,
+            "distanceField": DISTANCE_FIELD,
+            "spherical": options.spherical,
+        };
+
+        if let Some(ref key) = options.key {
+            spec.insert("key", key.as_str());
+        }
+        if !options.filter.is_empty() {
+            spec.insert("query", options.filter.clone());
+        }
+        if let Some(max_distance) = options.max_distance {
+            spec.insert("maxDistance", max_distance);
+        }
+        if let Some(min_distance) = options.min_distance {
+            spec.insert("minDistance", min_distance);
+        }
+        if let Some(multiplier) = options.distance_multiplier {
+            spec.insert("distanceMultiplier", multiplier);
+        }
+
+        let mut stages = vec![doc!{ "$geoNear": spec }];
+
+        if let Some(limit) = options.limit {
+            stages.push(doc!{ "$limit": limit as i64 });
+        }
+
+        stages
+    }
+
+    fn transform(mut raw: Document) -> Result<Bson> {
+        let distance = raw.remove(DISTANCE_FIELD).ok_or_else(|| Error::new(
+            ErrorKind::MissingDocumentField,
+            format!("missing `{}` field in $geoNear result", DISTANCE_FIELD)
+        ))?;
+
+        Ok(Bson::from(doc!{ "distance": distance, "doc": raw }))
+    }
+}
+
+/// Builds a `$near` or `$nearSphere` expression around a GeoJSON point.
+fn proximity(
+    operator: &str,
+    point: Point,
+    max_distance: Option<f64>,
+    min_distance: Option<f64>,
+) -> Document {
+    let mut spec = doc!{ "$geometry": Geometry::Point(point) };
+
+    if let Some(max) = max_distance {
+        spec.insert("$maxDistance", max);
+    }
+    if let Some(min) = min_distance {
+        spec.insert("$minDistance", min);
+    }
+
+    let mut expr = Document::new();
+    expr.insert(operator, spec);
+    expr
+}
+
+/// Converts a legacy coordinate pair to a BSON array.
+fn position_bson(position: Position) -> Bson {
+    Bson::Array(vec![position[0].into(), position[1].into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId, from_bson, to_bson };
+    use crate::{ doc::Doc, uid::Uid, ops::Pipeline };
+    use super::{
+        Point, LineString, Polygon, MultiPolygon, Geometry, Near, NearOptions,
+        near, intersects, within_center_sphere, near_legacy,
+    };
+
+    /// A document type with a location.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Store {
+        /// The unique ID of the store.
+        _id: Uid<Store>,
+    }
+
+    impl Doc for Store {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Store";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self._id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self._id = id;
+        }
+    }
+
+    #[test]
+    fn geometry_round_trip() {
+        let geometries = vec![
+            Geometry::from(Point::new(19.04, 47.5)),
+            Geometry::from(LineString { coordinates: vec![[0.0, 0.0], [1.0, 1.0]] }),
+            Geometry::from(Polygon::new(vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]])),
+            Geometry::from(MultiPolygon {
+                coordinates: vec![Polygon::new(vec![[0.0, 0.0], [0.0, 1.0], [1.0, 1.0]]).coordinates],
+            }),
+        ];
+
+        for geometry in geometries {
+            let bson = to_bson(&geometry).unwrap();
+            assert_eq!(from_bson::<Geometry>(bson).unwrap(), geometry);
+        }
+    }
+
+    #[test]
+    fn geojson_format() {
+        let polygon = Polygon::new(vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]]);
+
+        assert_eq!(Bson::from(Geometry::from(polygon)), Bson::from(doc!{
+            "type": "Polygon",
+            "coordinates": [[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0]]],
+        }));
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(near(Point::new(1.0, 2.0), Some(10.0), Some(5.0)), doc!{
+            "$near": {
+                "$geometry": { "type": "Point", "coordinates": [1.0, 2.0] },
+                "$maxDistance": 10.0,
+                "$minDistance": 5.0,
+            }
+        });
+        assert_eq!(intersects(Point::new(1.0, 2.0)), doc!{
+            "$geoIntersects": {
+                "$geometry": { "type": "Point", "coordinates": [1.0, 2.0] },
+            }
+        });
+        assert_eq!(within_center_sphere([1.0, 2.0], 0.5), doc!{
+            "$geoWithin": { "$centerSphere": [[1.0, 2.0], 0.5] }
+        });
+        assert_eq!(near_legacy([1.0, 2.0], None), doc!{ "$near": [1.0, 2.0] });
+    }
+
+    #[test]
+    fn geo_near_stages() {
+        let options = NearOptions {
+            filter: doc!{ "open": true },
+            max_distance: Some(1000.0),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let search = Near::<Store>::new(Point::new(1.0, 2.0), options);
+
+        assert_eq!(search.stages(), vec![
+            doc!{
+                "$geoNear": {
+                    "near": { "type": "Point", "coordinates": [1.0, 2.0] },
+                    "distanceField": "avocadoGeoDistance",
+                    "spherical": true,
+                    "query": { "open": true },
+                    "maxDistance": 1000.0,
+                }
+            },
+            doc!{ "$limit": 3_i64 },
+        ]);
+
+        let planar = NearOptions { spherical: false, ..Default::default() };
+
+        assert_eq!(Near::<Store>::new(Point::new(1.0, 2.0), planar).stages(), vec![
+            doc!{
+                "$geoNear": {
+                    "near": [1.0, 2.0],
+                    "distanceField": "avocadoGeoDistance",
+                    "spherical": false,
+                }
+            },
+        ]);
+    }
+
+    #[test]
+    fn geo_near_transform() {
+        let raw = doc!{ "_id": 1, "name": "corner shop", "avocadoGeoDistance": 12.5 };
+
+        assert_eq!(<Near<Store> as Pipeline<Store>>::transform(raw).unwrap(), Bson::from(doc!{
+            "distance": 12.5,
+            "doc": { "_id": 1, "name": "corner shop" },
+        }));
+        assert!(<Near<Store> as Pipeline<Store>>::transform(doc!{ "_id": 1 }).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Synchronizing the indexes of a collection with the declared ones.
+//!
+//! `Collection::create_indexes()` only ever adds indexes: an index removed
+//! from `T::indexes()` keeps slowing down writes, and an index whose keys or
+//! options changed under the same name makes index creation fail. Instead,
+//! `Collection::sync_indexes()` compares the declared indexes with the ones
+//! reported by `listIndexes`, and returns an [`IndexDiff`](struct.IndexDiff.html)
+//! describing what it changed, or, in a dry run, what it would change.
+//!
+//! Indexes are matched by name. Indexes without an explicit name are named
+//! the way MongoDB names them, e.g. `email_1` for `{ email: 1 }`.
+
+use mongodb::coll::options::IndexModel;
+use crate::coll::{ ExistingIndex, default_index_name, normalize_int };
+
+/// The name of the index on `_id`, which can't be dropped.
+const ID_INDEX_NAME: &str = "_id_";
+
+/// Controls what `Collection::sync_indexes()` is allowed to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Options {
+    /// Drop the indexes which are not declared, and recreate the declared
+    /// ones whose keys or options differ from the existing ones.
+    /// Default: `false`, i.e. only missing indexes are created.
+    pub drop_stale: bool,
+    /// Only compute the differences, without modifying any index.
+    /// Default: `false`.
+    pub dry_run: bool,
+}
+
+/// The differences between the declared and the existing indexes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IndexDiff {
+    /// The declared indexes which don't exist.
+    pub missing: Vec<IndexModel>,
+    /// The declared indexes which exist with different keys or options.
+    pub changed: Vec<IndexModel>,
+    /// The names of the existing indexes which are not declared.
+    pub stale: Vec<String>,
+}
+
+impl IndexDiff {
+    /// Compares the `declared` indexes with the `existing` ones. The index
+    /// on `_id` is never reported as stale.
+    pub fn between(declared: &[IndexModel], existing: &[ExistingIndex]) -> Self {
+        let mut diff = IndexDiff::default();
+
+        for model in declared {
+            let name = index_name(model);
+            let current = existing.iter().find(|index| index_name(&index.model) == name);
+
+            match current {
+                None => diff.missing.push(model.clone()),
+                Some(index) if !same_index(model, &index.model) => diff.changed.push(model.clone()),
+                Some(_) => {}
+            }
+        }
+
+        diff.stale = existing
+            .iter()
+            .map(|index| index_name(&index.model))
+            .filter(|name| name != ID_INDEX_NAME)
+            .filter(|name| declared.iter().all(|model| index_name(model) != *name))
+            .collect();
+
+        diff
+    }
+
+    /// Returns `true` if the existing indexes match the declared ones.
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty() && self.stale.is_empty()
+    }
+}
+
+/// Returns the explicit name of the index, or the one MongoDB generates.
+pub(crate) fn index_name(model: &IndexModel) -> String {
+    model.options.name.clone().unwrap_or_else(|| default_index_name(&model.keys))
+}
+
+/// Returns `true` if two indexes of the same name have the same keys, in
+/// the same order, and the same options affecting their contents.
+fn same_index(declared: &IndexModel, existing: &IndexModel) -> bool {
+    let keys_equal = declared.keys.len() == existing.keys.len() && declared.keys
+        .iter()
+        .zip(existing.keys.iter())
+        .all(|((k1, v1), (k2, v2))| k1 == k2 && normalize_int(v1.clone()) == normalize_int(v2.clone()));
+
+    let (lhs, rhs) = (&declared.options, &existing.options);
+
+    keys_equal
+        && lhs.unique.unwrap_or(false) == rhs.unique.unwrap_or(false)
+        && lhs.sparse.unwrap_or(false) == rhs.sparse.unwrap_or(false)
+        && lhs.expire_after_seconds == rhs.expire_after_seconds
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+    use mongodb::coll::options::{ IndexModel, IndexOptions };
+    use crate::coll::ExistingIndex;
+    use super::IndexDiff;
+
+    fn index(keys: Document, options: IndexOptions) -> IndexModel {
+        IndexModel { keys, options }
+    }
+
+    fn existing(keys: Document, options: IndexOptions) -> ExistingIndex {
+        ExistingIndex {
+            model: index(keys, options),
+            partial_filter_expression: None,
+            collation: None,
+        }
+    }
+
+    #[test]
+    fn diff_by_name() {
+        let unique = IndexOptions { unique: Some(true), ..Default::default() };
+        let id = IndexOptions { name: Some(String::from("_id_")), ..Default::default() };
+        let declared = vec![
+            index(doc!{ "email": 1 }, unique),
+            index(doc!{ "name": 1_i64, "age": -1_i64 }, Default::default()),
+            index(doc!{ "created": 1 }, Default::default()),
+        ];
+        let existing = vec![
+            existing(doc!{ "_id": 1 }, id),
+            existing(doc!{ "email": 1 }, Default::default()),
+            existing(doc!{ "name": 1, "age": -1 }, Default::default()),
+            existing(doc!{ "legacy": 1 }, Default::default()),
+        ];
+        let diff = IndexDiff::between(&declared, &existing);
+
+        assert_eq!(diff.missing, &declared[2..]);
+        assert_eq!(diff.changed, &declared[..1]);
+        assert_eq!(diff.stale, ["legacy_1"]);
+        assert!(!diff.is_empty());
+        assert!(IndexDiff::between(&[], &existing[..1]).is_empty());
+    }
+}
@@ -125,6 +125,12 @@
 //!     and the same holds for `Product`. When deriving `Doc`, it is controlled
 //!     by the `#[id_type = "..."]` attribute on the struct declaration. If you
 //!     don't specify this attribute, the raw ID type will default to `ObjectId`.
+//!     The `_id` field itself must be a `Uid<Self>` or an `Option<Uid<Self>>`;
+//!     the derive rejects a raw ID type, or a `Uid` of another type, at
+//!     compile time.
+//!     IDs of other types can be generated on the client side when inserting
+//!     a document without an `_id`, by naming a generator function using e.g.
+//!     `#[id_generator = "uuid::Uuid::new_v4"]`.
 //!   * the `NAME` associated constant describes and identifies the collection
 //!     of values of this type.
 //!
@@ -149,11 +155,155 @@
 //! which are specified in the `#[options(fn_name = "path", ...)]` attribute.
 //! The implementation of the other methods will be left in the default state.
 //!
+//! By default, reading a stored document which contains fields unknown to
+//! the Rust type silently ignores those fields. Annotating the type with
+//! `#[avocado(strict)]` makes such reads fail instead, which helps detect
+//! other writers that bypass the schema. (This is implemented through the
+//! `Doc::strict_fields()` method, so it can't be combined with fields that
+//! are `#[serde(flatten)]`ed, as their names aren't known statically.)
+//!
+//! Conversely, fields which older versions of the application didn't write
+//! can be given a default value with `#[serde(default)]` or
+//! `#[serde(default = "path")]`. Documents lacking them then deserialize
+//! normally, and `Doc::defaulted_fields()` lists them so that the
+//! `$jsonSchema` validator installed by `DatabaseExt::empty_collection()`
+//! and friends doesn't require them either, allowing rolling upgrades.
+//!
+//! The default collation of the collection can be specified using e.g.
+//! `#[doc_collation(locale = "en", strength = 2)]`. The collection is then
+//! created with this collation by `DatabaseExt::empty_collection()` and
+//! friends, and `DatabaseExt::ensure_collection()` verifies that an existing
+//! collection has it. Queries inherit the collation of the collection, so
+//! case-insensitive matching needn't be requested per operation. Besides
+//! `locale`, the supported options are `strength` (1 to 5), `case_level`,
+//! `case_first`, `alternate`, `max_variable`, `numeric_ordering`,
+//! `backwards` and `normalization`, mirroring MongoDB's collation document.
+//!
+//! A collection declared using e.g. `#[capped(size = 1048576, max = 1000)]`
+//! is created as a capped collection of at most `size` bytes and, if
+//! specified, `max` documents, which discards its oldest documents once a
+//! limit is reached. `Collection::tail()` follows such a collection as new
+//! documents are inserted, which suits append-only logs and simple queues.
+//!
+//! An `i64` field annotated with `#[avocado(version)]` holds the version of
+//! the document, enabling optimistic concurrency control: writes made by
+//! `Collection::replace_entity_versioned()` and `update_one_versioned()`
+//! only succeed if nobody else has written the document since it was read,
+//! and they fail with a `StaleVersion` error otherwise.
+//!
+//! Fields annotated with `#[avocado(created_at)]` and `#[avocado(updated_at)]`,
+//! typically of type `bson::UtcDateTime`, are maintained by `Collection`:
+//! inserts set both to the current time, and replacements and updates set
+//! the latter, so that call sites needn't remember doing so.
+//!
+//! Annotating an optional timestamp field with `#[avocado(deleted_at)]`
+//! makes deletions soft: `Collection::delete_one()` and `delete_many()` set
+//! the field instead of removing the document, and reads, updates and
+//! aggregations skip documents in which it is set. `find_deleted()`,
+//! `undelete()` and `purge()` operate on the soft-deleted documents.
+//!
+//! Lifecycle hooks let cross-cutting concerns, such as validation, auditing
+//! or the maintenance of denormalized fields, live next to the type instead
+//! of at every call site. `#[avocado(before_insert = "path")]`,
+//! `before_update`, `before_delete` and `after_load` name functions which
+//! `Collection` calls with the serialized entity, the update document, the
+//! deletion filter and each raw document read, respectively; an error
+//! returned by a hook aborts the operation. See `Doc::before_insert()` and
+//! the methods following it for their exact signatures.
+//!
+//! Fields annotated with `#[avocado(encrypted)]` are encrypted on the client
+//! side before being written, and decrypted after being read, by the cipher
+//! installed through the [`encrypt`](encrypt/index.html) module.
+//! `#[avocado(encrypted = "deterministic")]` keeps equality filters working.
+//!
+//! Besides structs, `Doc` can be derived for enums which are internally
+//! tagged, i.e. annotated with `#[serde(tag = "...")]`, provided that every
+//! variant has named fields, including the `_id` field. All variants are
+//! stored in the same collection, and the [`variant`](variant/index.html)
+//! module restricts queries, updates and deletions to a single variant.
+//! `Collection::of_variant()` also reads and inserts the documents of a
+//! variant as a separate struct, named by `#[avocado(subtype = "...")]`.
+//!
+//! Types with type parameters, e.g. an `Event<P>` envelope around payloads of
+//! various types, can derive `Doc` as well, as long as the type parameters
+//! are bounded so that the type implements `Serialize` and `Deserialize`,
+//! e.g. `P: Serialize + DeserializeOwned`. Every instantiation is stored in
+//! the collection named after the type, unless `#[avocado(name_from = "P")]`
+//! takes the name from `P`'s implementation of `doc::CollectionName`.
+//!
+//! The collection of a type is named after the type by default.
+//! `#[collection_name = "..."]` names it explicitly, while
+//! `#[collection_naming = "snake_case_plural"]` converts the type name
+//! according to a `rename_all` rule, optionally pluralized; e.g. the
+//! documents of an `OrderEntry` are stored in `order_entries`. At runtime,
+//! `DatabaseExt::empty_collection_named()` and its siblings use another
+//! name altogether, e.g. one prefixed by the name of a tenant. In
+//! multi-tenant applications, a [`tenant::ScopedDatabase`](tenant/index.html)
+//! applies such a naming convention to every collection of a tenant, or
+//! selects a separate database for each tenant.
+//!
+//! Types annotated with `#[avocado(audited)]` name an audit log collection,
+//! by default their collection name followed by `_audit`. Writes performed
+//! through an [`audit::Audited`](audit/index.html) view are recorded there,
+//! along with the actor and attributes of an `audit::Context`, the time and
+//! the changed fields of every document.
+//!
+//! Types of embedded documents can `#[derive(Subdoc)]`, which generates
+//! methods returning the dotted paths of their fields for use in filters
+//! and updates, so that they needn't be written as string literals; see the
+//! [`path`](path/index.html) module. Deriving `Doc` implements `Subdoc` as
+//! well (so the two shouldn't be derived together), thus a typo such as
+//! `User::fields().emial()` is a compile-time error instead of a filter that
+//! silently matches nothing:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! #[serde(rename_all = "camelCase")]
+//! struct User {
+//!     #[serde(rename = "_id")]
+//!     id: Uid<User>,
+//!     email_address: String,
+//! }
+//!
+//! # fn main() {
+//! let email = User::fields().email_address();
+//! let filter = doc!{ (email.clone()): "joe@example.com" };
+//!
+//! assert_eq!(email, "emailAddress");
+//! assert_eq!(filter, doc!{ "emailAddress": "joe@example.com" });
+//! # }
+//! ```
+//!
+//! For whole filters, the `checked_flt!` macro of `avocado_derive` takes the
+//! type and a document in the syntax of `doc!`, whose keys are paths of Rust
+//! field names. It translates them into stored names, and rejects unknown
+//! fields and literals that can't match the type of their field, e.g.
+//! `checked_flt!(User, { "email_address": { "$in": [1, 2] } })`; see the
+//! [`path::kind`](path/kind/index.html) module.
+//!
+//! The shard key of a sharded collection is declared using e.g.
+//! `#[shard_key(fields(tenant = "ascending", _id = "hashed"))]`, where each
+//! field is either `"ascending"` or `"hashed"`. Updates and deletions whose
+//! filter doesn't include the shard key are then detected, and with
+//! `#[shard_key(fields(...), strict)]`, rejected; see the `shard` module.
+//!
 //! ### Deriving `Doc` with indexes
 //!
 //! The `#[index(...)]` attribute can be applied to a type several times in
 //! order to generate index specifications and implement the `Doc::indexes()`
-//! static method. An example is provided below:
+//! static method. Besides the indexed `keys(...)`, it accepts the `name`,
+//! `unique` and `sparse` options, `expire_after_seconds` for TTL indexes,
+//! `default_language`, `language_override` and `weights(...)` for text
+//! indexes, and `min`, `max`, `bits` and `bucket_size` for geospatial ones.
+//! An example is provided below:
 //!
 //! ```
 //! # #[macro_use]
@@ -307,6 +457,10 @@
 //!   The `unique` and `sparse` switches are either boolean-valued key-value
 //!   pairs, or bare words. Specifying a bare word is equivalent with setting
 //!   it to `true`, e.g. `unique` is the same as `unique = true`.
+//!   A write violating a `unique` index fails with an error of kind
+//!   `ErrorKind::UniqueViolation`, and `error.context::<UniqueViolation>()`
+//!   tells the `name` of the violated index (or the name MongoDB generated
+//!   for it), e.g. for translating conflicts into precise HTTP 409 messages.
 //! * The rest of the supported options are:
 //!   * `max = 85.0` &mdash; maximal longitude/latitude for `2d` indexes.
 //!     This must be a floating-point number in the range `[-180, +180]`.
@@ -319,6 +473,9 @@
 //!   * `default_language = "french"` &mdash; default language of a text index.
 //!   * `language_override = "lang"` &mdash; field name that indicates the
 //!     language of a document.
+//!   * `weights(title = 10, body = 2)` &mdash; relative weights of the fields
+//!     of a text index, integers between 1 and 99999. Unlisted fields have
+//!     weight 1. See [`text::Search`](text/struct.Search.html).
 //!
 //! ### Collections and Databases
 //!
@@ -556,14 +713,8 @@
 //!         raw.remove_str("description")
 //!     }
 //!
-//!     fn options(&self) -> FindOptions {
-//!         FindOptions {
-//!             projection: Some(doc!{
-//!                 "_id": false,
-//!                 "description": true,
-//!             }),
-//!             ..Default::default()
-//!         }
+//!     fn projection(&self) -> Projection {
+//!         Projection::including(vec!["description"]).exclude("_id")
 //!     }
 //! }
 //!
@@ -663,6 +814,47 @@
 //!   validation via the `magnet_schema` crate.
 //! * `raw_uuid` (default): augments the [`Uid`](uid/struct.Uid.html) type
 //!   with convenience methods for working with UUID-based entity/document IDs.
+//! * `rayon`: deserializes the documents of each batch received by a
+//!   [`Cursor`](cursor/struct.Cursor.html) in parallel. With this feature,
+//!   the types yielded by cursors must be `Send`.
+//! * `regex`: enables [`filter::regex_checked()`](filter/fn.regex_checked.html),
+//!   which validates regular expressions before they are sent to the server,
+//!   and [`filter::from_regex()`](filter/fn.from_regex.html), which converts
+//!   compiled regular expressions to filters. It also lets
+//!   [`matcher`](matcher/index.html) evaluate `$regex` conditions.
+//! * `async`: enables the [`asynchronous`](asynchronous/index.html) module,
+//!   providing collections whose operations return futures and streams,
+//!   executed on a pool of background threads.
+//! * `log`: enables [`monitor::LogListener`](monitor/struct.LogListener.html),
+//!   which logs the commands sent to the server using the `log` crate.
+//! * `metrics`: enables [`monitor::MetricsListener`](monitor/struct.MetricsListener.html),
+//!   which records command counts, latencies and payload sizes using the
+//!   `metrics` crate.
+//! * `time`: lets [`datetime::BsonDateTime`](datetime/struct.BsonDateTime.html)
+//!   convert from and to `time::OffsetDateTime`, so that values of the `time`
+//!   crate can be used in filters and updates.
+//! * `encryption`: enables [`encrypt::AesGcmCipher`](encrypt/index.html),
+//!   which encrypts the fields annotated with `#[avocado(encrypted)]` using
+//!   AES-256-GCM and the keys of a caller-provided `KeyProvider`.
+//! * `tls`: lets [`client::Options`](client/struct.Options.html)
+//!   connect using TLS, by enabling the `ssl` feature of the driver.
+//! * `driver2`: enables the [`driver2`](driver2/index.html) module, which
+//!   provides collections backed by the synchronous API of the official
+//!   `mongodb` 2.x driver, conversions of documents, indexes, options
+//!   and errors between the two drivers, and the zero-copy deserialization
+//!   of raw documents.
+//!
+//! The `testing` feature, which enables the feature of the same name of
+//! `avocado_derive`, makes `#[avocado(factory)]` generate fake-data
+//! builders for `Doc` types; see the [`testing`](testing/index.html) module.
+//!
+//! Business logic can be unit-tested without a running server against a
+//! [`mock::Collection`](mock/struct.Collection.html), which evaluates
+//! filters and updates on documents held in memory.
+//!
+//! Integration tests can seed their collections from JSON or BSON files with
+//! the [`fixtures`](fixtures/index.html) module, which assigns deterministic
+//! IDs to the documents, so that assertions can refer to them.
 
 #![doc(html_root_url = "https://docs.rs/avocado/0.6.0")]
 #![deny(missing_debug_implementations, missing_copy_implementations,
@@ -696,24 +888,113 @@ extern crate mongodb;
 extern crate bson;
 #[macro_use]
 extern crate serde_derive;
-extern crate serde;
+#[doc(hidden)]
+pub extern crate serde;
+#[macro_use]
 extern crate serde_json;
 extern crate backtrace;
+extern crate base64;
+extern crate chrono;
+#[doc(hidden)]
+pub extern crate inventory;
+#[macro_use]
+extern crate lazy_static;
 
 #[cfg(feature = "schema_validation")]
 extern crate magnet_schema;
 #[cfg(feature = "raw_uuid")]
 extern crate uuid;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "time")]
+extern crate time;
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+#[cfg(feature = "encryption")]
+extern crate hmac;
+#[cfg(feature = "encryption")]
+extern crate sha2;
+#[cfg(feature = "encryption")]
+extern crate rand;
+#[cfg(feature = "driver2")]
+extern crate mongodb2;
+#[cfg(feature = "driver2")]
+extern crate bson2;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "metrics")]
+#[macro_use]
+extern crate metrics;
 
+pub mod client;
 pub mod db;
 pub mod coll;
 pub mod cursor;
 pub mod doc;
+pub mod schema;
+pub mod variant;
 pub mod uid;
 pub mod ops;
+pub mod diff;
+pub mod tracked;
+pub mod filter;
+pub mod matcher;
+pub mod expr;
+pub mod update;
+pub mod projection;
+pub mod path;
+pub mod sort;
+pub mod pipeline;
+pub mod audit;
+pub mod attribution;
+pub mod scope;
+pub mod tenant;
+pub mod server;
+pub mod breaker;
+pub mod retry;
+pub mod batch;
+pub mod denorm;
+pub mod scan;
+pub mod paginate;
+pub mod cache;
+pub mod loader;
+pub mod seq;
+pub mod shard;
+pub mod snapshot;
+pub mod transaction;
+pub mod geo;
+pub mod text;
+pub mod plan;
+pub mod explain;
+pub mod index_sync;
+pub mod migration;
+pub mod relation;
+pub mod xref;
+pub mod change_stream;
+pub mod consistency;
+pub mod monitor;
+pub mod concern;
+pub mod options;
+pub mod timeout;
+pub mod cancel;
 pub mod literal;
+pub mod datetime;
+pub mod profile;
+pub mod encrypt;
 pub mod error;
 pub mod ext;
+pub mod testing;
+pub mod fixtures;
+pub mod mock;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "driver2")]
+pub mod driver2;
 pub mod prelude;
 
 mod bsn;
@@ -3,7 +3,7 @@
 
 use std::str;
 use std::fmt;
-use bson::{ Bson, to_bson };
+use bson::{ Bson, to_bson, spec::BinarySubtype };
 use serde::{
     ser::{ Serialize, Serializer, SerializeSeq },
     de::{ Deserialize, Deserializer, Visitor, SeqAccess },
@@ -424,7 +424,7 @@ static TYPE_NAMES: &[(BsonType, &str)] = &[
     (BsonType::BOOL,                  "bool"),
     (BsonType::DOUBLE,                "double"),
     (BsonType::INT,                   "int"),
-    (BsonType::LONG,                  "int"),
+    (BsonType::LONG,                  "long"),
     (BsonType::DECIMAL,               "decimal"),
     (BsonType::OBJECT_ID,             "objectId"),
     (BsonType::TIMESTAMP,             "timestamp"),
@@ -509,6 +509,139 @@ impl<'a> Visitor<'a> for BsonTypeVisitor {
     }
 }
 
+/// Which documents the `$jsonSchema` validator of a collection applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationLevel {
+    /// No validation.
+    Off,
+    /// Validate every insert and update.
+    Strict,
+    /// Validate inserts, and updates of documents which are already valid.
+    Moderate,
+}
+
+/// The default validation level is `Strict`, like MongoDB's.
+impl Default for ValidationLevel {
+    fn default() -> Self {
+        ValidationLevel::Strict
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<ValidationLevel> for Bson {
+    fn from(level: ValidationLevel) -> Self {
+        to_bson(&level).unwrap_or_default()
+    }
+}
+
+/// What happens to a write producing a document which fails validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationAction {
+    /// Reject the write.
+    Error,
+    /// Allow the write, but log a warning on the server.
+    Warn,
+}
+
+/// The default validation action is `Error`, like MongoDB's.
+impl Default for ValidationAction {
+    fn default() -> Self {
+        ValidationAction::Error
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<ValidationAction> for Bson {
+    fn from(action: ValidationAction) -> Self {
+        to_bson(&action).unwrap_or_default()
+    }
+}
+
+/// How much information the `explain` command reports about a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExplainVerbosity {
+    /// Only the plan chosen by the query planner, without executing it.
+    QueryPlanner,
+    /// The chosen plan, executed, along with its execution statistics.
+    ExecutionStats,
+    /// Like `ExecutionStats`, including the partial execution of the
+    /// rejected plans during plan selection.
+    AllPlansExecution,
+}
+
+/// The default verbosity is `QueryPlanner`, which doesn't run the query.
+impl Default for ExplainVerbosity {
+    fn default() -> Self {
+        ExplainVerbosity::QueryPlanner
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<ExplainVerbosity> for Bson {
+    fn from(verbosity: ExplainVerbosity) -> Self {
+        to_bson(&verbosity).unwrap_or_default()
+    }
+}
+
+/// What a `$merge` stage does with a result document whose `_id` (or other
+/// `on` fields) matches an existing document of the target collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WhenMatched {
+    /// Replaces the existing document.
+    Replace,
+    /// Keeps the existing document.
+    KeepExisting,
+    /// Merges the fields of the two documents.
+    Merge,
+    /// Stops the aggregation with an error.
+    Fail,
+}
+
+/// The default is `Merge`, as on the server.
+impl Default for WhenMatched {
+    fn default() -> Self {
+        WhenMatched::Merge
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<WhenMatched> for Bson {
+    fn from(action: WhenMatched) -> Self {
+        to_bson(&action).unwrap_or_default()
+    }
+}
+
+/// What a `$merge` stage does with a result document which doesn't match
+/// any document of the target collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WhenNotMatched {
+    /// Inserts the document.
+    Insert,
+    /// Drops the document.
+    Discard,
+    /// Stops the aggregation with an error.
+    Fail,
+}
+
+/// The default is `Insert`, as on the server.
+impl Default for WhenNotMatched {
+    fn default() -> Self {
+        WhenNotMatched::Insert
+    }
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<WhenNotMatched> for Bson {
+    fn from(action: WhenNotMatched) -> Self {
+        to_bson(&action).unwrap_or_default()
+    }
+}
+
 bitflags! {
     /// Options for matching text against a regular expression.
     /// Useful with the `$regex` operator. E.g.:
@@ -663,3 +796,148 @@ impl From<DateTimeType> for Bson {
         to_bson(&ty).unwrap_or_default()
     }
 }
+
+/// A language supported by MongoDB's text search, determining the stop words
+/// and the rules for stemming. `None` disables both.
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::literal::Language;
+/// #
+/// # fn main() {
+/// let search = doc!{
+///     "$search": "chocolat",
+///     "$language": Language::French,
+/// };
+/// assert_eq!(search, doc!{
+///     "$search": "chocolat",
+///     "$language": "french",
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    /// Danish.
+    Danish,
+    /// Dutch.
+    Dutch,
+    /// English.
+    English,
+    /// Finnish.
+    Finnish,
+    /// French.
+    French,
+    /// German.
+    German,
+    /// Hungarian.
+    Hungarian,
+    /// Italian.
+    Italian,
+    /// Norwegian.
+    Norwegian,
+    /// Portuguese.
+    Portuguese,
+    /// Romanian.
+    Romanian,
+    /// Russian.
+    Russian,
+    /// Spanish.
+    Spanish,
+    /// Swedish.
+    Swedish,
+    /// Turkish.
+    Turkish,
+    /// No language: only simple tokenization, without stop words and stemming.
+    None,
+}
+
+/// See the explanation for `BsonType` as to why this impl is possible.
+impl From<Language> for Bson {
+    fn from(language: Language) -> Self {
+        to_bson(&language).unwrap_or_default()
+    }
+}
+
+bitflags! {
+    /// Options for matching text against a `$text` search, see
+    /// [`filter::text()`](../filter/fn.text.html). By default, a text search
+    /// ignores both case and diacritic marks.
+    #[derive(Default)]
+    pub struct TextFlags: u8 {
+        /// Distinguish between upper and lower case letters.
+        const CASE_SENSITIVE      = 0b0000_0001;
+        /// Distinguish between letters with and without diacritic marks,
+        /// e.g. `é` and `e`.
+        const DIACRITIC_SENSITIVE = 0b0000_0010;
+    }
+}
+
+/// The operand of the bitwise query operators, e.g. `$bitsAllSet`: the bits
+/// to test, given in any of the forms MongoDB understands.
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::literal::BitMask;
+/// #
+/// # fn main() {
+/// let filter = doc!{
+///     "a": { "$bitsAllSet": BitMask::from(0b101_i64) },
+///     "b": { "$bitsAnyClear": BitMask::from(vec![0_u32, 2]) },
+/// };
+/// assert_eq!(filter, doc!{
+///     "a": { "$bitsAllSet": 5_i64 },
+///     "b": { "$bitsAnyClear": [0_i64, 2_i64] },
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BitMask {
+    /// A non-negative integer; its set bits are the tested ones.
+    Integer(i64),
+    /// Binary data, the bits of which are numbered starting from the least
+    /// significant bit of the first byte.
+    Binary(Vec<u8>),
+    /// The zero-based positions of the tested bits.
+    Positions(Vec<u32>),
+}
+
+impl From<i64> for BitMask {
+    fn from(mask: i64) -> Self {
+        BitMask::Integer(mask)
+    }
+}
+
+impl From<Vec<u8>> for BitMask {
+    fn from(bytes: Vec<u8>) -> Self {
+        BitMask::Binary(bytes)
+    }
+}
+
+impl From<Vec<u32>> for BitMask {
+    fn from(positions: Vec<u32>) -> Self {
+        BitMask::Positions(positions)
+    }
+}
+
+impl From<BitMask> for Bson {
+    fn from(mask: BitMask) -> Self {
+        match mask {
+            BitMask::Integer(n) => Bson::I64(n),
+            BitMask::Binary(bytes) => Bson::Binary(BinarySubtype::Generic, bytes),
+            BitMask::Positions(positions) => Bson::Array(
+                positions.into_iter().map(|pos| Bson::I64(pos.into())).collect()
+            ),
+        }
+    }
+}
+
+impl Serialize for BitMask {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        Bson::from(self.clone()).serialize(ser)
+    }
+}
@@ -0,0 +1,221 @@
+//! Batching and deduplicating lookups by ID.
+//!
+//! Resolvers of a GraphQL query typically load related documents one at a
+//! time, e.g. the author of each of a hundred posts, which results in a
+//! hundred queries for often the same few authors. A
+//! [`Loader`](struct.Loader.html) collects the IDs requested within a
+//! scope, such as the resolution of a single GraphQL request, deduplicates
+//! them, and fetches all of them using a single `find_by_ids()` query once
+//! the first result is actually needed. The documents are then cached for
+//! the lifetime of the loader, so a loader should be created per request,
+//! not shared between requests.
+//!
+//! With the `async` feature, `AsyncCollection::loader()` returns a loader
+//! whose futures are batched automatically: every lookup started before the
+//! first one of them is polled is fetched by the same query.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! # let author_ids: Vec<Uid<User>> = Vec::new();
+//! let users: Collection<User> = db.existing_collection();
+//! let loader = users.loader();
+//!
+//! // Queue the lookups first...
+//! for id in &author_ids {
+//!     loader.request(id.clone());
+//! }
+//!
+//! // ...then the first `load()` fetches all of them at once.
+//! for id in &author_ids {
+//!     let author = loader.load(id)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::hash::Hash;
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Mutex, MutexGuard, PoisonError };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use crate::{
+    coll::Collection,
+    cursor,
+    doc::Doc,
+    uid::Uid,
+    error::Result,
+};
+
+/// Collects lookups by ID and performs them in a single batched query.
+pub struct Loader<'a, T: Doc> {
+    /// The collection the documents are loaded from.
+    collection: &'a Collection<T>,
+    /// The cached documents and the queued IDs.
+    state: Mutex<LoaderState<T>>,
+}
+
+/// The mutable state of a `Loader`.
+struct LoaderState<T: Doc> {
+    /// The documents loaded so far, `None` if there is no document with
+    /// the given ID.
+    cache: HashMap<Uid<T>, Option<T>>,
+    /// The requested IDs which haven't been fetched yet.
+    pending: HashSet<Uid<T>>,
+}
+
+impl<'a, T> Loader<'a, T>
+    where T: Doc + cursor::Item + Clone,
+          T::Id: Hash + Clone + Debug,
+{
+    /// Creates a loader with an empty cache.
+    pub fn new(collection: &'a Collection<T>) -> Self {
+        Loader {
+            collection,
+            state: Mutex::new(LoaderState {
+                cache: HashMap::new(),
+                pending: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Returns the collection the documents are loaded from.
+    pub fn collection(&self) -> &Collection<T> {
+        self.collection
+    }
+
+    /// Queues the lookup of `id`, unless its document is already cached.
+    /// Nothing is fetched until the next `load()` or `dispatch()`.
+    pub fn request(&self, id: Uid<T>) {
+        let mut state = self.lock_state();
+
+        if !state.cache.contains_key(&id) {
+            state.pending.insert(id);
+        }
+    }
+
+    /// Queues the lookup of every ID in `ids`.
+    pub fn request_many<I>(&self, ids: I) where I: IntoIterator<Item = Uid<T>> {
+        let mut state = self.lock_state();
+
+        for id in ids {
+            if !state.cache.contains_key(&id) {
+                state.pending.insert(id);
+            }
+        }
+    }
+
+    /// Returns the document with the given ID, or `None` if there is none.
+    /// If it isn't cached yet, it is fetched together with every other
+    /// queued ID.
+    pub fn load(&self, id: &Uid<T>) -> Result<Option<T>> {
+        let mut state = self.lock_state();
+
+        if let Some(entity) = state.cache.get(id) {
+            return Ok(entity.clone());
+        }
+
+        state.pending.insert(id.clone());
+        self.fetch_pending(&mut state)?;
+
+        Ok(state.cache.get(id).cloned().unwrap_or_default())
+    }
+
+    /// Returns the documents with the given IDs, in the same order, using
+    /// at most one query. An item is `None` if there is no such document.
+    pub fn load_many(&self, ids: &[Uid<T>]) -> Result<Vec<Option<T>>> {
+        let mut state = self.lock_state();
+
+        for id in ids {
+            if !state.cache.contains_key(id) {
+                state.pending.insert(id.clone());
+            }
+        }
+
+        self.fetch_pending(&mut state)?;
+
+        Ok(ids
+           .iter()
+           .map(|id| state.cache.get(id).cloned().unwrap_or_default())
+           .collect())
+    }
+
+    /// Fetches every queued ID using a single query. Returns the number of
+    /// distinct IDs fetched, which is 0 if nothing was queued.
+    pub fn dispatch(&self) -> Result<usize> {
+        let mut state = self.lock_state();
+        self.fetch_pending(&mut state)
+    }
+
+    /// Caches an already loaded document, so that it isn't fetched again.
+    pub fn prime(&self, entity: T) {
+        if let Some(id) = entity.id().cloned() {
+            let mut state = self.lock_state();
+            state.pending.remove(&id);
+            state.cache.insert(id, Some(entity));
+        }
+    }
+
+    /// Removes the document with the given ID from the cache, e.g. after
+    /// it has been modified, so that it is fetched again when requested.
+    pub fn clear(&self, id: &Uid<T>) {
+        self.lock_state().cache.remove(id);
+    }
+
+    /// Empties the cache.
+    pub fn clear_all(&self) {
+        self.lock_state().cache.clear();
+    }
+
+    /// Fetches the queued IDs and caches the results. If the query fails,
+    /// the IDs remain queued.
+    fn fetch_pending(&self, state: &mut LoaderState<T>) -> Result<usize> {
+        if state.pending.is_empty() {
+            return Ok(0);
+        }
+
+        let ids: Vec<_> = state.pending.iter().cloned().collect();
+        let mut found = self.collection.find_by_ids(&ids)?;
+
+        for id in &ids {
+            let entity = found.remove(id);
+            state.cache.insert(id.clone(), entity);
+        }
+
+        state.pending.clear();
+
+        Ok(ids.len())
+    }
+
+    /// Locks the state. It is consistent at all times, so poisoning is ignored.
+    fn lock_state(&self) -> MutexGuard<'_, LoaderState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<'a, T: Doc> Debug for Loader<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        f.debug_struct("Loader")
+            .field("collection", &T::NAME)
+            .field("num_cached", &state.cache.len())
+            .field("num_pending", &state.pending.len())
+            .finish()
+    }
+}
@@ -0,0 +1,610 @@
+//! Client-side evaluation of filter documents.
+//!
+//! [`matches()`](fn.matches.html) decides whether a document satisfies a
+//! filter, following the matching rules of the server: conditions on a
+//! dotted path are evaluated against every value reachable through embedded
+//! documents and arrays, a condition on an array field is satisfied if the
+//! array itself or any of its elements satisfies it, numbers of different
+//! widths compare equal if their values are equal, and comparisons only
+//! match values of the same kind (type bracketing), so that e.g.
+//! `{ "$gt": 10 }` never matches a string. This lets filters be applied to
+//! documents which aren't in the database, e.g. to the full documents of
+//! change stream events, or to cached data; it is also what
+//! [`mock::Collection`](../mock/struct.Collection.html) uses.
+//!
+//! The supported operators are `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`,
+//! `$in`, `$nin`, `$all`, `$exists`, `$type`, `$mod`, `$size`, `$elemMatch`,
+//! `$not`, `$and`, `$or`, `$nor` and `$comment`, and, with the `regex`
+//! feature, `$regex` and regular expression values. Any other operator,
+//! e.g. `$expr`, `$text`, `$where` or the geospatial ones, results in an
+//! `UnsupportedOperator` error instead of a possibly wrong answer.
+//! Collations aren't taken into account; strings compare by code point.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::matcher::matches;
+//! # use avocado::prelude::*;
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! let order = doc!{
+//!     "status": "shipped",
+//!     "total": 42.5,
+//!     "items": [ { "sku": "A-1", "qty": 2 }, { "sku": "B-7", "qty": 1 } ],
+//! };
+//!
+//! assert!(matches(&order, &doc!{ "total": { "$gte": 40 }, "items.sku": "B-7" })?);
+//! assert!(matches(&order, &doc!{ "$or": [ { "status": "pending" }, { "items.qty": { "$gt": 1 } } ] })?);
+//! assert!(!matches(&order, &doc!{ "total": { "$gt": "40" } })?);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::slice;
+use std::fmt::Debug;
+use std::cmp::Ordering;
+use bson::{ Bson, Document };
+#[cfg(feature = "regex")]
+use regex::RegexBuilder;
+use crate::{
+    doc::Doc,
+    coll::renamed,
+    utils::{ int_to_usize_with_msg, float_to_i64 },
+    error::{ Error, ErrorKind, Result },
+};
+
+/// Returns `true` if `doc` satisfies the query filter `filter`.
+///
+/// Returns an `UnsupportedOperator` error if the filter contains an operator
+/// which can't be evaluated client-side.
+pub fn matches(doc: &Document, filter: &Document) -> Result<bool> {
+    for (key, condition) in filter {
+        let satisfied = match key.as_str() {
+            "$and" => all_match(doc, key, condition)?.iter().all(|&m| m),
+            "$or"  => all_match(doc, key, condition)?.iter().any(|&m| m),
+            "$nor" => !all_match(doc, key, condition)?.iter().any(|&m| m),
+            "$comment" => true,
+            _ if key.starts_with('$') => return Err(unsupported(key)),
+            _ => matches_condition(&lookup(doc, key), condition)?,
+        };
+
+        if !satisfied {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns `true` if the BSON representation of `entity` satisfies `filter`,
+/// whose field names are first mapped according to `T::field_naming()`,
+/// just like those of filters sent to the server.
+pub fn matches_entity<T: Doc>(entity: &T, filter: &Document) -> Result<bool> {
+    let doc = T::SERDE_PROFILE.serialize(entity)?;
+    matches(&doc, &renamed::<T>(filter.clone()))
+}
+
+/// Evaluates each sub-filter of a logical operator against the document.
+fn all_match(doc: &Document, operator: &str, operand: &Bson) -> Result<Vec<bool>> {
+    match *operand {
+        Bson::Array(ref filters) if !filters.is_empty() => filters
+            .iter()
+            .map(|item| match *item {
+                Bson::Document(ref filter) => matches(doc, filter),
+                _ => Err(invalid_operand(operator, item)),
+            })
+            .collect(),
+        _ => Err(invalid_operand(operator, operand)),
+    }
+}
+
+/// Returns `true` if the values of a field satisfy a condition, which is
+/// either a value to compare with or a document of operators.
+fn matches_condition(values: &[&Bson], condition: &Bson) -> Result<bool> {
+    match *condition {
+        Bson::Document(ref operators) if is_operator_document(operators) => {
+            for (operator, operand) in operators {
+                let satisfied = match operator.as_str() {
+                    // `$options` is evaluated along with its `$regex`.
+                    "$options" => true,
+                    "$regex" => {
+                        let options = match operators.get("$options") {
+                            Some(&Bson::String(ref options)) => options.as_str(),
+                            Some(other) => return Err(invalid_operand("$options", other)),
+                            None => "",
+                        };
+                        match *operand {
+                            Bson::String(ref pattern) => matches_regex(values, pattern, options)?,
+                            Bson::RegExp(ref pattern, ref inline) => {
+                                matches_regex(values, pattern, &format!("{}{}", inline, options))?
+                            }
+                            _ => return Err(invalid_operand(operator, operand)),
+                        }
+                    }
+                    _ => matches_operator(values, operator, operand)?,
+                };
+
+                if !satisfied {
+                    return Ok(false);
+                }
+            }
+
+            Ok(true)
+        }
+        Bson::RegExp(ref pattern, ref options) => matches_regex(values, pattern, options),
+        _ => Ok(equals_any(values, condition)),
+    }
+}
+
+/// Evaluates a single query operator against the values of a field.
+fn matches_operator(values: &[&Bson], operator: &str, operand: &Bson) -> Result<bool> {
+    let ordered = |accept: fn(Ordering) -> bool| candidates(values).any(
+        |value| compare(value, operand).map_or(false, accept)
+    );
+
+    Ok(match operator {
+        "$eq"  => equals_any(values, operand),
+        "$ne"  => !equals_any(values, operand),
+        "$gt"  => ordered(|ordering| ordering == Ordering::Greater),
+        "$gte" => ordered(|ordering| ordering != Ordering::Less),
+        "$lt"  => ordered(|ordering| ordering == Ordering::Less),
+        "$lte" => ordered(|ordering| ordering != Ordering::Greater),
+        "$in"  => array_operand(operator, operand)?.iter().any(|item| equals_any(values, item)),
+        "$nin" => !array_operand(operator, operand)?.iter().any(|item| equals_any(values, item)),
+        "$all" => {
+            let items = array_operand(operator, operand)?;
+            !items.is_empty() && items.iter().all(|item| equals_any(values, item))
+        }
+        "$exists" => values.is_empty() != is_truthy(operand),
+        "$not" => match *operand {
+            Bson::Document(ref operators) if is_operator_document(operators) => {
+                !matches_condition(values, operand)?
+            }
+            Bson::RegExp(..) => !matches_condition(values, operand)?,
+            _ => return Err(invalid_operand(operator, operand)),
+        },
+        "$type" => {
+            let types = match *operand {
+                Bson::Array(ref types) => types.as_slice(),
+                _ => slice::from_ref(operand),
+            };
+            let mut found = false;
+
+            for expected in types {
+                found |= match *expected {
+                    Bson::String(ref alias) => {
+                        candidates(values).any(|value| has_type_alias(value, alias))
+                    }
+                    _ => {
+                        let code = integer(expected).ok_or_else(|| invalid_operand(operator, expected))?;
+                        candidates(values).any(|value| i64::from(type_code(value)) == code)
+                    }
+                };
+            }
+
+            found
+        }
+        "$mod" => match *array_operand(operator, operand)? {
+            [ref divisor, ref remainder] => match (integer(divisor), integer(remainder)) {
+                (Some(0), _) | (None, _) | (_, None) => return Err(invalid_operand(operator, operand)),
+                (Some(d), Some(r)) => candidates(values).any(
+                    |value| float(value).and_then(float_to_i64).map_or(false, |x| x % d == r)
+                ),
+            },
+            _ => return Err(invalid_operand(operator, operand)),
+        },
+        "$size" => {
+            let size = integer(operand).ok_or_else(|| invalid_operand(operator, operand))?;
+            let expected = int_to_usize_with_msg(size, "$size")?;
+            values.iter().any(|value| match **value {
+                Bson::Array(ref items) => items.len() == expected,
+                _ => false,
+            })
+        }
+        "$elemMatch" => {
+            let condition = match *operand {
+                Bson::Document(ref condition) => condition,
+                _ => return Err(invalid_operand(operator, operand)),
+            };
+            let mut found = false;
+
+            for value in values {
+                if let Bson::Array(ref items) = **value {
+                    for item in items {
+                        found |= if is_operator_document(condition) {
+                            matches_condition(&[item], operand)?
+                        } else if let Bson::Document(ref element) = *item {
+                            matches(element, condition)?
+                        } else {
+                            false
+                        };
+                    }
+                }
+            }
+
+            found
+        }
+        _ => return Err(unsupported(operator)),
+    })
+}
+
+/// Returns `true` if any string value of a field, or any string element of
+/// an array value, matches the regular expression.
+#[cfg(feature = "regex")]
+fn matches_regex(values: &[&Bson], pattern: &str, options: &str) -> Result<bool> {
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(options.contains('i'))
+        .multi_line(options.contains('m'))
+        .ignore_whitespace(options.contains('x'))
+        .dot_matches_new_line(options.contains('s'))
+        .build()?;
+
+    Ok(candidates(values).any(|value| match *value {
+        Bson::String(ref string) | Bson::Symbol(ref string) => regex.is_match(string),
+        _ => false,
+    }))
+}
+
+/// Without the `regex` feature, regular expressions can't be evaluated.
+#[cfg(not(feature = "regex"))]
+fn matches_regex(_: &[&Bson], _: &str, _: &str) -> Result<bool> {
+    Err(unsupported("$regex without the `regex` feature"))
+}
+
+/// Returns `true` if a condition document consists of query operators,
+/// rather than being a document to compare with.
+pub(crate) fn is_operator_document(doc: &Document) -> bool {
+    doc.keys().next().map_or(false, |key| key.starts_with('$'))
+}
+
+/// Returns `true` if any value of a field, or any element of an array value,
+/// equals `expected`. A `null` also matches a missing field.
+fn equals_any(values: &[&Bson], expected: &Bson) -> bool {
+    if *expected == Bson::Null && values.is_empty() {
+        return true;
+    }
+
+    candidates(values).any(|value| compare(value, expected) == Some(Ordering::Equal))
+}
+
+/// The values of a field, followed by the elements of those which are
+/// arrays, which are what comparisons are performed against.
+fn candidates<'a>(values: &'a [&'a Bson]) -> impl Iterator<Item = &'a Bson> + 'a {
+    let elements = values.iter().flat_map(|value| match **value {
+        Bson::Array(ref items) => items.as_slice(),
+        _ => &[],
+    });
+
+    values.iter().cloned().chain(elements)
+}
+
+/// Returns the values at a dotted path. Arrays along the path are traversed,
+/// so `"a.b"` yields the `b` field of each element of `a`, and numeric path
+/// components index into arrays.
+fn lookup<'a>(doc: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let components: Vec<&str> = path.split('.').collect();
+    let mut values = Vec::new();
+
+    if let Some((head, rest)) = components.split_first() {
+        if let Some(value) = doc.get(head) {
+            resolve(value, rest, &mut values);
+        }
+    }
+
+    values
+}
+
+/// Collects the values at the remaining path components into `values`.
+fn resolve<'a>(value: &'a Bson, path: &[&str], values: &mut Vec<&'a Bson>) {
+    let (head, rest) = match path.split_first() {
+        Some((head, rest)) => (*head, rest),
+        None => {
+            values.push(value);
+            return;
+        }
+    };
+
+    match *value {
+        Bson::Document(ref doc) => if let Some(field) = doc.get(head) {
+            resolve(field, rest, values);
+        },
+        Bson::Array(ref items) => {
+            if let Some(item) = head.parse::<usize>().ok().and_then(|index| items.get(index)) {
+                resolve(item, rest, values);
+            }
+            for item in items {
+                if let Bson::Document(_) = *item {
+                    resolve(item, path, values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The numeric code of the type of a value, as accepted by `$type`.
+fn type_code(value: &Bson) -> i32 {
+    match *value {
+        Bson::FloatingPoint(_) => 1,
+        Bson::String(_) => 2,
+        Bson::Document(_) => 3,
+        Bson::Array(_) => 4,
+        Bson::Binary(..) => 5,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::UtcDatetime(_) => 9,
+        Bson::Null => 10,
+        Bson::RegExp(..) => 11,
+        Bson::JavaScriptCode(_) => 13,
+        Bson::Symbol(_) => 14,
+        Bson::JavaScriptCodeWithScope(..) => 15,
+        Bson::I32(_) => 16,
+        Bson::TimeStamp(_) => 17,
+        Bson::I64(_) => 18,
+    }
+}
+
+/// Returns `true` if the type of a value has the given `$type` alias, e.g.
+/// `"objectId"`, or is numeric and the alias is `"number"`.
+fn has_type_alias(value: &Bson, alias: &str) -> bool {
+    let name = match type_code(value) {
+        1 => "double",
+        2 => "string",
+        3 => "object",
+        4 => "array",
+        5 => "binData",
+        7 => "objectId",
+        8 => "bool",
+        9 => "date",
+        10 => "null",
+        11 => "regex",
+        13 => "javascript",
+        14 => "symbol",
+        15 => "javascriptWithScope",
+        16 => "int",
+        17 => "timestamp",
+        18 => "long",
+        _ => "decimal",
+    };
+
+    name == alias || (alias == "number" && type_rank(value) == type_rank(&Bson::I32(0)))
+}
+
+/// The position of a BSON type in MongoDB's comparison order. Values of
+/// different types are only compared with each other when sorting.
+fn type_rank(value: &Bson) -> u8 {
+    match *value {
+        Bson::Null => 1,
+        Bson::FloatingPoint(_) | Bson::I32(_) | Bson::I64(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(..) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::UtcDatetime(_) => 9,
+        Bson::TimeStamp(_) => 10,
+        Bson::RegExp(..) => 11,
+        _ => 12,
+    }
+}
+
+/// Compares two values of the same kind, e.g. numbers of any width with
+/// each other. Returns `None` if they aren't comparable.
+fn compare(lhs: &Bson, rhs: &Bson) -> Option<Ordering> {
+    if type_rank(lhs) != type_rank(rhs) {
+        return None;
+    }
+
+    match (lhs, rhs) {
+        (&Bson::String(ref x), &Bson::String(ref y)) |
+        (&Bson::Symbol(ref x), &Bson::Symbol(ref y)) |
+        (&Bson::String(ref x), &Bson::Symbol(ref y)) |
+        (&Bson::Symbol(ref x), &Bson::String(ref y)) => Some(x.cmp(y)),
+        (&Bson::ObjectId(ref x), &Bson::ObjectId(ref y)) => Some(x.bytes().cmp(&y.bytes())),
+        (&Bson::Boolean(x), &Bson::Boolean(y)) => Some(x.cmp(&y)),
+        (&Bson::UtcDatetime(ref x), &Bson::UtcDatetime(ref y)) => Some(x.cmp(y)),
+        (&Bson::TimeStamp(x), &Bson::TimeStamp(y)) => Some(x.cmp(&y)),
+        _ => match (integer(lhs), integer(rhs)) {
+            (Some(x), Some(y)) => Some(x.cmp(&y)),
+            _ => match (float(lhs), float(rhs)) {
+                (Some(x), Some(y)) => x.partial_cmp(&y),
+                _ if lhs == rhs => Some(Ordering::Equal),
+                _ => None,
+            },
+        },
+    }
+}
+
+/// Orders two documents according to a sort specification, placing missing
+/// fields first, like `null`s.
+pub(crate) fn compare_by(lhs: &Document, rhs: &Document, sort: &Document) -> Ordering {
+    for (path, direction) in sort {
+        let left = lookup(lhs, path).first().cloned().cloned().unwrap_or(Bson::Null);
+        let right = lookup(rhs, path).first().cloned().cloned().unwrap_or(Bson::Null);
+        let ordering = type_rank(&left)
+            .cmp(&type_rank(&right))
+            .then_with(|| compare(&left, &right).unwrap_or(Ordering::Equal));
+        let directed = if float(direction).map_or(false, |d| d < 0.0) {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+
+        if directed != Ordering::Equal {
+            return directed;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Returns the value of an integer, or of a float without a fractional part.
+pub(crate) fn integer(value: &Bson) -> Option<i64> {
+    match *value {
+        Bson::I32(n) => Some(i64::from(n)),
+        Bson::I64(n) => Some(n),
+        Bson::FloatingPoint(x) if x.fract() == 0.0 && x.abs() < 9.0e15 => float_to_i64(x),
+        _ => None,
+    }
+}
+
+/// Returns the value of a number as a float.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn float(value: &Bson) -> Option<f64> {
+    match *value {
+        Bson::I32(n) => Some(f64::from(n)),
+        Bson::I64(n) => Some(n as f64),
+        Bson::FloatingPoint(x) => Some(x),
+        _ => None,
+    }
+}
+
+/// Interprets the operand of e.g. `$exists` as a boolean.
+fn is_truthy(value: &Bson) -> bool {
+    match *value {
+        Bson::Boolean(b) => b,
+        Bson::Null => false,
+        _ => float(value).map_or(true, |x| x != 0.0),
+    }
+}
+
+/// Returns the elements of the array operand of e.g. `$in`.
+pub(crate) fn array_operand<'a>(operator: &str, operand: &'a Bson) -> Result<&'a [Bson]> {
+    match *operand {
+        Bson::Array(ref items) => Ok(items),
+        _ => Err(invalid_operand(operator, operand)),
+    }
+}
+
+
+/// The error for an operator which can't be evaluated client-side.
+pub(crate) fn unsupported(operator: &str) -> Error {
+    Error::new(
+        ErrorKind::UnsupportedOperator,
+        format!("{} can't be evaluated client-side", operator)
+    )
+}
+
+/// The error for an operator applied to an operand of the wrong kind.
+pub(crate) fn invalid_operand<D: Debug>(operator: &str, operand: &D) -> Error {
+    Error::new(
+        ErrorKind::IllTypedDocumentField,
+        format!("invalid operand for {}: {:?}", operator, operand)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, Document };
+    use crate::error::{ ErrorKind, ErrorExt };
+    use super::matches;
+
+    fn user() -> Document {
+        doc!{
+            "_id": 1,
+            "name": "Alice",
+            "age": 34,
+            "score": 7.5,
+            "tags": ["admin", "editor"],
+            "address": { "city": "Budapest", "zip": "1011" },
+            "orders": [ { "total": 12, "paid": true }, { "total": 40, "paid": false } ],
+            "nickname": Bson::Null,
+        }
+    }
+
+    fn check(filter: Document) -> bool {
+        matches(&user(), &filter).unwrap()
+    }
+
+    #[test]
+    fn equality_and_paths() {
+        assert!(check(doc!{}));
+        assert!(check(doc!{ "name": "Alice" }));
+        assert!(!check(doc!{ "name": "Bob" }));
+        assert!(check(doc!{ "age": 34_i64 }));
+        assert!(check(doc!{ "age": 34.0 }));
+        assert!(check(doc!{ "tags": "editor" }));
+        assert!(check(doc!{ "tags": ["admin", "editor"] }));
+        assert!(!check(doc!{ "tags": ["editor", "admin"] }));
+        assert!(check(doc!{ "address.city": "Budapest" }));
+        assert!(check(doc!{ "orders.total": 40 }));
+        assert!(check(doc!{ "orders.1.paid": false }));
+        assert!(check(doc!{ "nickname": Bson::Null }));
+        assert!(check(doc!{ "missing": Bson::Null }));
+        assert!(!check(doc!{ "name": Bson::Null }));
+    }
+
+    #[test]
+    fn comparison_operators() {
+        assert!(check(doc!{ "age": { "$gt": 30, "$lte": 34 } }));
+        assert!(!check(doc!{ "age": { "$lt": 34 } }));
+        assert!(check(doc!{ "score": { "$gte": 7 } }));
+        assert!(check(doc!{ "name": { "$gt": "Aaron" } }));
+        assert!(!check(doc!{ "name": { "$gt": 10 } }));
+        assert!(check(doc!{ "orders.total": { "$gt": 30 } }));
+        assert!(check(doc!{ "age": { "$ne": 35 } }));
+        assert!(!check(doc!{ "tags": { "$ne": "admin" } }));
+        assert!(check(doc!{ "name": { "$in": ["Bob", "Alice"] } }));
+        assert!(check(doc!{ "tags": { "$in": ["viewer", "editor"] } }));
+        assert!(check(doc!{ "name": { "$nin": ["Bob"] } }));
+        assert!(check(doc!{ "tags": { "$all": ["editor", "admin"] } }));
+        assert!(check(doc!{ "tags": { "$size": 2 } }));
+        assert!(check(doc!{ "name": { "$exists": true }, "missing": { "$exists": false } }));
+        assert!(check(doc!{ "nickname": { "$exists": true } }));
+        assert!(check(doc!{ "age": { "$not": { "$gt": 40 } } }));
+        assert!(check(doc!{ "orders": { "$elemMatch": { "total": { "$gt": 30 }, "paid": false } } }));
+        assert!(!check(doc!{ "orders": { "$elemMatch": { "total": { "$gt": 30 }, "paid": true } } }));
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert!(check(doc!{ "$and": [ { "name": "Alice" }, { "age": 34 } ] }));
+        assert!(!check(doc!{ "$and": [ { "name": "Alice" }, { "age": 35 } ] }));
+        assert!(check(doc!{ "$or": [ { "name": "Bob" }, { "age": 34 } ] }));
+        assert!(check(doc!{ "$nor": [ { "name": "Bob" }, { "age": 35 } ] }));
+        assert!(!check(doc!{ "$nor": [ { "name": "Alice" } ] }));
+    }
+
+    #[test]
+    fn unsupported_operators() {
+        let error = matches(&user(), &doc!{ "$where": "true" }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsupportedOperator);
+
+        let error = matches(&user(), &doc!{ "location": { "$near": [0, 0] } }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsupportedOperator);
+
+        let error = matches(&user(), &doc!{ "$or": "name" }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::IllTypedDocumentField);
+    }
+
+    #[test]
+    fn type_and_mod() {
+        assert!(check(doc!{ "age": { "$type": "int" } }));
+        assert!(check(doc!{ "age": { "$type": "number" }, "score": { "$type": 1 } }));
+        assert!(check(doc!{ "name": { "$type": ["bool", "string"] } }));
+        assert!(check(doc!{ "tags": { "$type": "array" } }));
+        assert!(check(doc!{ "tags": { "$type": "string" } }));
+        assert!(!check(doc!{ "address": { "$type": "long" } }));
+        assert!(check(doc!{ "age": { "$mod": [5, 4] } }));
+        assert!(check(doc!{ "score": { "$mod": [7, 0] } }));
+        assert!(!check(doc!{ "age": { "$mod": [5, 0] } }));
+
+        let error = matches(&user(), &doc!{ "age": { "$mod": [0, 0] } }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::IllTypedDocumentField);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regular_expressions() {
+        assert!(check(doc!{ "name": { "$regex": "^al", "$options": "i" } }));
+        assert!(!check(doc!{ "name": { "$regex": "^al" } }));
+        assert!(check(doc!{ "tags": Bson::RegExp("^edit".into(), String::new()) }));
+        assert!(check(doc!{ "name": { "$not": Bson::RegExp("^B".into(), String::new()) } }));
+
+        let error = matches(&user(), &doc!{ "name": { "$regex": "(" } }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidRegex);
+    }
+}
@@ -0,0 +1,313 @@
+//! Versioned schema migrations.
+//!
+//! A [`Migration`](trait.Migration.html) performs a single, versioned change
+//! of the database, e.g. backfilling a new field or renaming a collection,
+//! and knows how to revert it. A set of [`Migrations`](struct.Migrations.html)
+//! applies the ones which haven't been applied yet, in increasing order of
+//! version, and records each applied version in the `_avocado_migrations`
+//! collection. A lock document in the same collection prevents several
+//! processes, e.g. replicas of a service starting at the same time, from
+//! running migrations concurrently.
+//!
+//! ```no_run
+//! # extern crate mongodb;
+//! # extern crate avocado;
+//! #
+//! # use mongodb::db::ThreadedDatabase;
+//! # use avocado::prelude::*;
+//! # use avocado::migration::{ Migration, Migrations };
+//! #
+//! #[derive(Debug)]
+//! struct AddUserRoles;
+//!
+//! impl Migration for AddUserRoles {
+//!     fn version(&self) -> i64 {
+//!         2019_03_01
+//!     }
+//!
+//!     fn up(&self, db: &Database) -> AvocadoResult<()> {
+//!         db.collection("User").update_many(
+//!             doc!{ "roles": { "$exists": false } },
+//!             doc!{ "$set": { "roles": [] } },
+//!             None,
+//!         )?;
+//!         Ok(())
+//!     }
+//!
+//!     fn down(&self, db: &Database) -> AvocadoResult<()> {
+//!         db.collection("User").update_many(doc!{}, doc!{ "$unset": { "roles": "" } }, None)?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let migrations = Migrations::new().with(AddUserRoles);
+//! let applied = migrations.migrate_up(&db)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Migrations aren't transactional: if one fails, the ones applied before it
+//! remain applied and recorded, and the failing one should leave the database
+//! in a state from which it can be run again.
+
+use std::collections::BTreeSet;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use chrono::Utc;
+use bson::Bson;
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::error::{
+    Error, ErrorKind::{ MigrationLocked, InvalidMigration }, Result, ResultExt,
+    DUPLICATE_KEY_ERROR_CODE,
+};
+
+/// The name of the collection storing the applied versions and the lock.
+pub const MIGRATIONS_COLLECTION_NAME: &str = "_avocado_migrations";
+
+/// The `_id` of the lock document.
+const LOCK_ID: &str = "lock";
+
+/// A single, reversible change of the database.
+pub trait Migration: Debug {
+    /// The version of the migration, unique among all migrations. Dates,
+    /// such as `2019_03_01`, make convenient versions.
+    fn version(&self) -> i64;
+
+    /// A human-readable description, recorded along with the version.
+    /// Empty by default.
+    fn description(&self) -> &str {
+        ""
+    }
+
+    /// Applies the change.
+    fn up(&self, db: &Database) -> Result<()>;
+
+    /// Reverts the change.
+    fn down(&self, db: &Database) -> Result<()>;
+}
+
+/// A registry of migrations, and the runner applying or reverting them.
+#[derive(Default)]
+pub struct Migrations {
+    /// The registered migrations, in increasing order of version.
+    migrations: Vec<Box<dyn Migration>>,
+}
+
+impl Migrations {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a migration.
+    pub fn with<M: Migration + 'static>(mut self, migration: M) -> Self {
+        let index = self.migrations
+            .iter()
+            .position(|m| m.version() > migration.version())
+            .unwrap_or_else(|| self.migrations.len());
+
+        self.migrations.insert(index, Box::new(migration));
+        self
+    }
+
+    /// Returns the versions of the registered migrations, in increasing order.
+    pub fn versions(&self) -> Vec<i64> {
+        self.migrations.iter().map(|m| m.version()).collect()
+    }
+
+    /// Returns the versions recorded as applied in the database.
+    pub fn applied(&self, db: &Database) -> Result<BTreeSet<i64>> {
+        let message = "can't read applied migrations";
+
+        db.collection(MIGRATIONS_COLLECTION_NAME)
+            .find(Some(doc!{ "_id": { "$type": "long" } }), None)
+            .chain(message)?
+            .map(|result| {
+                let record = result.chain(message)?;
+                record.get_i64("_id").chain(message)
+            })
+            .collect()
+    }
+
+    /// Returns the versions of the registered migrations which haven't been
+    /// applied yet, in increasing order.
+    pub fn pending(&self, db: &Database) -> Result<Vec<i64>> {
+        let applied = self.applied(db)?;
+
+        Ok(self.versions().into_iter().filter(|v| !applied.contains(v)).collect())
+    }
+
+    /// Applies the pending migrations in increasing order of version.
+    /// Returns the versions applied by this call.
+    pub fn migrate_up(&self, db: &Database) -> Result<Vec<i64>> {
+        self.check()?;
+
+        let _lock = Lock::acquire(db)?;
+        let applied = self.applied(db)?;
+        let records = db.collection(MIGRATIONS_COLLECTION_NAME);
+        let mut versions = Vec::new();
+
+        for migration in self.migrations.iter().filter(|m| !applied.contains(&m.version())) {
+            let version = migration.version();
+            let message = || format!("error applying migration {}", version);
+
+            migration.up(db).chain(&message)?;
+
+            let record = doc!{
+                "_id": version,
+                "description": migration.description(),
+                "applied_at": Bson::UtcDatetime(Utc::now()),
+            };
+            let reply = records.insert_one(record, None).chain(&message)?;
+
+            if let Some(error) = reply.write_exception {
+                return Err(Error::with_cause(message(), error));
+            }
+
+            versions.push(version);
+        }
+
+        Ok(versions)
+    }
+
+    /// Reverts the applied migrations with a version greater than `target`,
+    /// in decreasing order of version. Returns the versions reverted by this
+    /// call. Fails without reverting anything if one of them isn't registered.
+    pub fn migrate_down_to(&self, db: &Database, target: i64) -> Result<Vec<i64>> {
+        self.check()?;
+
+        let _lock = Lock::acquire(db)?;
+        let records = db.collection(MIGRATIONS_COLLECTION_NAME);
+        let reverted: Vec<_> = self.applied(db)?
+            .into_iter()
+            .rev()
+            .take_while(|&version| version > target)
+            .map(|version| {
+                self.migrations
+                    .iter()
+                    .find(|m| m.version() == version)
+                    .ok_or_else(|| Error::new(
+                        InvalidMigration,
+                        format!("applied migration {} is not registered", version)
+                    ))
+            })
+            .collect::<Result<_>>()?;
+        let mut versions = Vec::new();
+
+        for migration in reverted {
+            let version = migration.version();
+            let message = || format!("error reverting migration {}", version);
+
+            migration.down(db).chain(&message)?;
+            records.delete_one(doc!{ "_id": version }, None).chain(&message)?;
+
+            versions.push(version);
+        }
+
+        Ok(versions)
+    }
+
+    /// Removes the lock left behind by a run which was killed before it
+    /// could release the lock. Only call this if no migrations are running.
+    pub fn force_unlock(db: &Database) -> Result<()> {
+        db.collection(MIGRATIONS_COLLECTION_NAME)
+            .delete_one(doc!{ "_id": LOCK_ID }, None)
+            .map(drop)
+            .chain("can't remove migration lock")
+    }
+
+    /// Ensures that no two migrations have the same version.
+    fn check(&self) -> Result<()> {
+        let versions = self.versions();
+
+        match versions.windows(2).find(|pair| pair[0] == pair[1]) {
+            Some(pair) => Err(Error::new(
+                InvalidMigration,
+                format!("several migrations have version {}", pair[0])
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Debug for Migrations {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Migrations")
+            .field("migrations", &self.migrations)
+            .finish()
+    }
+}
+
+/// The lock held while migrations are running. It is released when dropped.
+struct Lock<'a> {
+    /// The database the lock document is stored in.
+    db: &'a Database,
+}
+
+impl<'a> Lock<'a> {
+    /// Inserts the lock document, failing with `MigrationLocked` if it
+    /// already exists.
+    fn acquire(db: &'a Database) -> Result<Self> {
+        let message = "can't acquire migration lock";
+        let reply = db
+            .collection(MIGRATIONS_COLLECTION_NAME)
+            .insert_one(doc!{ "_id": LOCK_ID, "acquired_at": Bson::UtcDatetime(Utc::now()) }, None)
+            .chain(message)?;
+
+        match reply.write_exception {
+            None => Ok(Lock { db }),
+            Some(ref error) if error.write_error.as_ref().map_or(
+                false, |e| e.code == DUPLICATE_KEY_ERROR_CODE
+            ) => Err(Error::new(
+                MigrationLocked,
+                "migrations are being run by another process; if not, \
+                 remove the lock using `Migrations::force_unlock()`"
+            )),
+            Some(error) => Err(Error::with_cause(message, error)),
+        }
+    }
+}
+
+impl<'a> Drop for Lock<'a> {
+    fn drop(&mut self) {
+        // A lock left behind can be removed by `Migrations::force_unlock()`.
+        Migrations::force_unlock(self.db).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::db::Database;
+    use crate::error::{ ErrorExt, ErrorKind, Result };
+    use super::{ Migration, Migrations };
+
+    #[derive(Debug)]
+    struct Noop(i64);
+
+    impl Migration for Noop {
+        fn version(&self) -> i64 {
+            self.0
+        }
+
+        fn up(&self, _: &Database) -> Result<()> {
+            Ok(())
+        }
+
+        fn down(&self, _: &Database) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ordered_and_unique_versions() {
+        let migrations = Migrations::new().with(Noop(3)).with(Noop(1)).with(Noop(2));
+
+        assert_eq!(migrations.versions(), [1, 2, 3]);
+        assert!(migrations.check().is_ok());
+
+        let duplicate = migrations.with(Noop(2)).check().unwrap_err();
+        assert_eq!(duplicate.kind(), ErrorKind::InvalidMigration);
+    }
+}
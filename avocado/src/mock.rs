@@ -0,0 +1,557 @@
+//! An in-memory stand-in for a collection, for unit testing business logic
+//! without a running `mongod`.
+//!
+//! A mock [`Collection`](struct.Collection.html) stores raw documents in
+//! memory and executes the same `Query`, `Count`, `Update` and `Delete`
+//! operations as a `coll::Collection`, by evaluating their filter documents with
+//! [`matcher::matches()`](../matcher/fn.matches.html). Field renaming, soft
+//! deletion and the sort order, skip and limit of queries are honored.
+//!
+//! Filters may use the operators supported by the `matcher` module. Updates
+//! may use `$set`, `$unset`, `$inc`, `$push` and `$currentDate`, or be
+//! replacement documents. Other operators, e.g. `$expr` or `$rename`, result
+//! in an `UnsupportedOperator` error rather than a silently wrong result.
+//! Projections, collations and unique indexes other than that on `_id` are
+//! ignored.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::mock::Collection;
+//! # use bson::Document;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//!     age: u32,
+//! }
+//!
+//! /// The business logic under test.
+//! #[derive(Debug, Clone, Copy)]
+//! struct AnonymizeMinors;
+//!
+//! impl Update<User> for AnonymizeMinors {
+//!     fn filter(&self) -> Document {
+//!         doc!{ "age": { "$lt": 18 } }
+//!     }
+//!
+//!     fn update(&self) -> Document {
+//!         doc!{ "$set": { "name": "anonymous" } }
+//!     }
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! let users = Collection::<User>::new();
+//!
+//! for &(name, age) in &[("Alice", 34), ("Bob", 17), ("Carol", 52)] {
+//!     users.insert_one(&User { _id: Uid::new_oid()?, name: name.into(), age })?;
+//! }
+//!
+//! let result = users.update_many(AnonymizeMinors)?;
+//! assert_eq!(result.num_modified, 1);
+//!
+//! let adults = users.find_many(doc!{ "age": { "$gte": 18 } })?;
+//! assert_eq!(adults.len(), 2);
+//! assert_eq!(users.count(doc!{ "name": { "$in": ["anonymous", "nobody"] } })?, 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::borrow::Borrow;
+use std::sync::{ Mutex, MutexGuard };
+use bson::{ Bson, Document, oid::ObjectId, from_bson };
+use chrono::Utc;
+use crate::{
+    coll::{
+        UpdateOneResult, UpdateManyResult, generate_id, live, renamed, query_options,
+        strict_transform, serialize_entity, deletion_filter,
+    },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    encrypt::encrypt_update,
+    matcher::{
+        matches, compare_by, is_operator_document, integer, float,
+        array_operand, unsupported, invalid_operand,
+    },
+    utils::int_to_usize_with_msg,
+    error::{ Error, ErrorKind, ServerInfo, Result, ResultExt, DUPLICATE_KEY_ERROR_CODE },
+};
+
+/// An in-memory collection of documents of type `T`.
+#[derive(Debug)]
+pub struct Collection<T: Doc> {
+    /// The stored documents, in insertion order.
+    documents: Mutex<Vec<Document>>,
+    /// Just so that the type parameter is used.
+    _marker: PhantomData<T>,
+}
+
+impl<T: Doc> Default for Collection<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Doc> Collection<T> {
+    /// Creates an empty collection.
+    pub fn new() -> Self {
+        Self::from_documents(Vec::new())
+    }
+
+    /// Creates a collection holding the given raw documents, e.g. fixtures
+    /// which don't deserialize as `T`.
+    pub fn from_documents(documents: Vec<Document>) -> Self {
+        Collection {
+            documents: Mutex::new(documents),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns a copy of the stored raw documents, in insertion order.
+    pub fn documents(&self) -> Vec<Document> {
+        self.lock().clone()
+    }
+
+    /// Returns the number of documents satisfying the query.
+    pub fn count<Q: Count<T>>(&self, query: Q) -> Result<usize> {
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        let options = query.options();
+        let message = || format!("error in {}::count({:#?})", T::NAME, query);
+        let matching = self.matching(&filter).chain(&message)?;
+        let skip = options.skip.map_or(Ok(0), |n| int_to_usize_with_msg(n, "skip"))?;
+        let count = matching.len().saturating_sub(skip);
+
+        match options.limit {
+            Some(limit) if limit > 0 => Ok(count.min(int_to_usize_with_msg(limit, "limit")?)),
+            _ => Ok(count),
+        }
+    }
+
+    /// Retrieves a single document satisfying the query, if one exists.
+    pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        self.find(&query, Some(1))
+            .map(|results| results.into_iter().next())
+            .chain(|| format!("error in {}::find_one({:#?})", T::NAME, query))
+    }
+
+    /// Retrieves all documents satisfying the query.
+    pub fn find_many<Q: Query<T>>(&self, query: Q) -> Result<Vec<Q::Output>> {
+        self.find(&query, None)
+            .chain(|| format!("error in {}::find_many({:#?})", T::NAME, query))
+    }
+
+    /// Inserts a single document. Fails with a duplicate key error if a
+    /// document with the same `_id` already exists.
+    pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
+        let mut doc = serialize_entity(entity)?;
+        generate_id::<T>(&mut doc)?;
+
+        if doc.get("_id").map_or(true, |id| *id == Bson::Null) {
+            doc.insert("_id", ObjectId::new()?);
+        }
+
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        let mut documents = self.lock();
+
+        if documents.iter().any(|existing| existing.get("_id") == Some(&id)) {
+            return Err(duplicate_key::<T>(&id));
+        }
+
+        documents.push(doc);
+
+        from_bson(id).chain(|| format!("can't deserialize ID for {}", T::NAME))
+    }
+
+    /// Inserts many documents, stopping at the first failure. Returns the
+    /// IDs of the inserted documents, keyed by their index.
+    pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
+        where I: IntoIterator,
+              I::Item: Borrow<T>,
+    {
+        let mut ids = BTreeMap::new();
+
+        for (index, entity) in (0..).zip(entities) {
+            ids.insert(index, self.insert_one(entity.borrow())?);
+        }
+
+        Ok(ids)
+    }
+
+    /// Replaces an entity by ID.
+    pub fn replace_entity(&self, entity: &T) -> Result<UpdateOneResult> {
+        let doc = serialize_entity(entity)?;
+        let id = entity.id().ok_or_else(|| Error::new(
+            ErrorKind::MissingId,
+            format!("{} to be replaced has no ID", T::NAME)
+        ))?;
+        let filter = doc!{ "_id": bson::to_bson(id)? };
+
+        self.update_matching(&filter, &doc, false)
+            .map(|(matched, modified)| UpdateOneResult {
+                matched: matched > 0,
+                modified: modified > 0,
+            })
+            .chain(|| format!("error in {}::replace_entity()", T::NAME))
+    }
+
+    /// Updates a single document.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        let filter = live::<T>(renamed::<T>(update.filter()));
+        let mut change = update.update();
+
+        T::before_update(&mut change)?;
+
+        let mut stored = renamed::<T>(change);
+        encrypt_update::<T>(&mut stored)?;
+
+        self.update_matching(&filter, &stored, false)
+            .map(|(matched, modified)| UpdateOneResult {
+                matched: matched > 0,
+                modified: modified > 0,
+            })
+            .chain(|| format!("error in {}::update_one({:#?})", T::NAME, update))
+    }
+
+    /// Updates all documents satisfying the query.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        let filter = live::<T>(renamed::<T>(update.filter()));
+        let mut change = update.update();
+
+        T::before_update(&mut change)?;
+
+        let mut stored = renamed::<T>(change);
+        encrypt_update::<T>(&mut stored)?;
+
+        self.update_matching(&filter, &stored, true)
+            .map(|(num_matched, num_modified)| UpdateManyResult { num_matched, num_modified })
+            .chain(|| format!("error in {}::update_many({:#?})", T::NAME, update))
+    }
+
+    /// Deletes an entity by ID. Returns `true` if it was found and deleted.
+    pub fn delete_entity(&self, entity: &T) -> Result<bool> {
+        let id = entity.id().ok_or_else(|| Error::new(
+            ErrorKind::MissingId,
+            format!("{} to be deleted has no ID", T::NAME)
+        ))?;
+
+        self.delete_one(doc!{ "_id": bson::to_bson(id)? })
+    }
+
+    /// Deletes one document. Returns `true` if one was found and deleted.
+    ///
+    /// If `T` is soft-deleted, the document is marked as deleted instead.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        self.delete_matching(&live::<T>(deletion_filter::<T>(query.filter())?), false)
+            .map(|num_deleted| num_deleted > 0)
+            .chain(|| format!("error in {}::delete_one({:#?})", T::NAME, query))
+    }
+
+    /// Deletes all documents satisfying the query. Returns the number of
+    /// deleted documents.
+    ///
+    /// If `T` is soft-deleted, the documents are marked as deleted instead.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        self.delete_matching(&live::<T>(deletion_filter::<T>(query.filter())?), true)
+            .chain(|| format!("error in {}::delete_many({:#?})", T::NAME, query))
+    }
+
+    /// Returns the indexes of the documents satisfying the filter.
+    fn matching(&self, filter: &Document) -> Result<Vec<usize>> {
+        let documents = self.lock();
+        let mut indexes = Vec::new();
+
+        for (index, doc) in documents.iter().enumerate() {
+            if matches(doc, filter)? {
+                indexes.push(index);
+            }
+        }
+
+        Ok(indexes)
+    }
+
+    /// Runs a query, returning at most `max` results if given.
+    fn find<Q: Query<T>>(&self, query: &Q, max: Option<usize>) -> Result<Vec<Q::Output>> {
+        let filter = live::<T>(renamed::<T>(query.filter()));
+        let options = query_options::<T, Q>(query);
+        let mut found = Vec::new();
+
+        for doc in self.lock().iter() {
+            if matches(doc, &filter)? {
+                found.push(doc.clone());
+            }
+        }
+
+        if let Some(ref sort) = options.sort {
+            found.sort_by(|lhs, rhs| compare_by(lhs, rhs, sort));
+        }
+
+        let skip = options.skip.map_or(Ok(0), |n| int_to_usize_with_msg(n, "skip"))?;
+        let limit = match options.limit {
+            Some(n) if n != 0 => Some(int_to_usize_with_msg(n.abs(), "limit")?),
+            _ => None,
+        };
+        let take = match (limit, max) {
+            (Some(m), Some(n)) => m.min(n),
+            (Some(n), None) | (None, Some(n)) => n,
+            (None, None) => usize::max_value(),
+        };
+
+        found
+            .into_iter()
+            .skip(skip)
+            .take(take)
+            .map(|doc| T::SERDE_PROFILE.deserialize(strict_transform::<T, Q>(doc)?))
+            .collect()
+    }
+
+    /// Applies an update to the first or all matching documents. Returns the
+    /// number of matched and modified documents.
+    fn update_matching(&self, filter: &Document, change: &Document, multi: bool)
+        -> Result<(usize, usize)>
+    {
+        let mut documents = self.lock();
+        let mut num_matched = 0;
+        let mut num_modified = 0;
+
+        for doc in documents.iter_mut() {
+            if !matches(doc, filter)? {
+                continue;
+            }
+
+            let updated = apply_update(doc, change)?;
+
+            num_matched += 1;
+
+            if updated != *doc {
+                *doc = updated;
+                num_modified += 1;
+            }
+            if !multi {
+                break;
+            }
+        }
+
+        Ok((num_matched, num_modified))
+    }
+
+    /// Deletes, or marks as deleted, the first or all matching documents.
+    /// Returns the number of deleted documents.
+    fn delete_matching(&self, filter: &Document, multi: bool) -> Result<usize> {
+        let mut documents = self.lock();
+        let mut deleted = Vec::new();
+
+        for (index, doc) in documents.iter().enumerate() {
+            if matches(doc, filter)? {
+                deleted.push(index);
+
+                if !multi {
+                    break;
+                }
+            }
+        }
+
+        for &index in deleted.iter().rev() {
+            match T::deleted_at_field() {
+                Some(field) => { documents[index].insert(field, Bson::UtcDatetime(Utc::now())); }
+                None => { documents.remove(index); }
+            }
+        }
+
+        Ok(deleted.len())
+    }
+
+    /// Locks the stored documents. A panic in another thread can't leave
+    /// them inconsistent, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, Vec<Document>> {
+        self.documents.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns the result of applying an update document, or a replacement
+/// document, to `doc`.
+fn apply_update(doc: &Document, change: &Document) -> Result<Document> {
+    if !is_operator_document(change) {
+        let mut replacement = Document::new();
+
+        if let Some(id) = doc.get("_id") {
+            replacement.insert("_id", id.clone());
+        }
+        for (key, value) in change {
+            replacement.insert(key.as_str(), value.clone());
+        }
+
+        return Ok(replacement);
+    }
+
+    let mut updated = doc.clone();
+
+    for (operator, operand) in change {
+        let fields = match *operand {
+            Bson::Document(ref fields) => fields,
+            _ => return Err(invalid_operand(operator, operand)),
+        };
+
+        for (path, value) in fields {
+            match operator.as_str() {
+                "$set" => set_path(&mut updated, path, value.clone())?,
+                "$unset" => unset_path(&mut updated, path),
+                "$currentDate" => set_path(&mut updated, path, Bson::UtcDatetime(Utc::now()))?,
+                "$inc" => {
+                    let current = get_path(&updated, path).cloned().unwrap_or(Bson::I32(0));
+                    set_path(&mut updated, path, add(&current, value)?)?;
+                }
+                "$push" => {
+                    let mut items = match get_path(&updated, path) {
+                        Some(&Bson::Array(ref items)) => items.clone(),
+                        None => Vec::new(),
+                        Some(other) => return Err(invalid_operand(operator, other)),
+                    };
+                    match *value {
+                        Bson::Document(ref modifiers) => match modifiers.get("$each") {
+                            Some(each) => items.extend_from_slice(array_operand("$each", each)?),
+                            None => items.push(value.clone()),
+                        },
+                        _ => items.push(value.clone()),
+                    }
+                    set_path(&mut updated, path, Bson::Array(items))?;
+                }
+                _ => return Err(unsupported(operator)),
+            }
+        }
+    }
+
+    Ok(updated)
+}
+
+/// Adds two numbers for `$inc`, widening the result as necessary.
+fn add(current: &Bson, increment: &Bson) -> Result<Bson> {
+    match (current, increment) {
+        (&Bson::I32(x), &Bson::I32(y)) => Ok(x.checked_add(y).map_or_else(
+            || Bson::I64(i64::from(x) + i64::from(y)),
+            Bson::I32,
+        )),
+        (&Bson::I32(_), &Bson::I64(_)) |
+        (&Bson::I64(_), &Bson::I32(_)) |
+        (&Bson::I64(_), &Bson::I64(_)) => match (integer(current), integer(increment)) {
+            (Some(x), Some(y)) => x.checked_add(y).map(Bson::I64).ok_or_else(
+                || Error::new(ErrorKind::IntConversionOverflow, "$inc overflowed")
+            ),
+            _ => Err(invalid_operand("$inc", increment)),
+        },
+        _ => match (float(current), float(increment)) {
+            (Some(x), Some(y)) => Ok(Bson::FloatingPoint(x + y)),
+            _ => Err(invalid_operand("$inc", current)),
+        },
+    }
+}
+
+/// Returns the value at a dotted path through embedded documents.
+pub(crate) fn get_path<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    match path.find('.') {
+        None => doc.get(path),
+        Some(dot) => match doc.get(&path[..dot]) {
+            Some(&Bson::Document(ref inner)) => get_path(inner, &path[dot + 1..]),
+            _ => None,
+        },
+    }
+}
+
+/// Sets the value at a dotted path, creating embedded documents as needed.
+fn set_path(doc: &mut Document, path: &str, value: Bson) -> Result<()> {
+    let dot = match path.find('.') {
+        Some(dot) => dot,
+        None => {
+            doc.insert(path, value);
+            return Ok(());
+        }
+    };
+    let head = &path[..dot];
+
+    if !doc.contains_key(head) {
+        doc.insert(head, Document::new());
+    }
+
+    match doc.get_mut(head) {
+        Some(&mut Bson::Document(ref mut inner)) => set_path(inner, &path[dot + 1..], value),
+        _ => Err(unsupported(&format!("setting `{}` inside a non-document", path))),
+    }
+}
+
+/// Removes the value at a dotted path through embedded documents, if any.
+fn unset_path(doc: &mut Document, path: &str) {
+    match path.find('.') {
+        None => { doc.remove(path); }
+        Some(dot) => if let Some(&mut Bson::Document(ref mut inner)) = doc.get_mut(&path[..dot]) {
+            unset_path(inner, &path[dot + 1..]);
+        },
+    }
+}
+
+/// The error for a duplicate `_id`, shaped like the one the server reports.
+fn duplicate_key<T: Doc>(id: &Bson) -> Error {
+    let message = format!("E11000 duplicate key error collection: {} index: _id_ dup key: {}", T::NAME, id);
+
+    Error::new(ErrorKind::MongoDbWriteException, message.clone())
+        .with_context::<ServerInfo>(ServerInfo {
+            code: Some(DUPLICATE_KEY_ERROR_CODE),
+            code_name: Some(String::from("DuplicateKey")),
+            message,
+            collection: Some(String::from(T::NAME)),
+            operation: Some(String::from("insert")),
+            ..ServerInfo::default()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, Document };
+    use crate::error::{ ErrorKind, ErrorExt };
+    use super::apply_update;
+
+    fn user() -> Document {
+        doc!{
+            "_id": 1,
+            "name": "Alice",
+            "age": 34,
+            "score": 7.5,
+            "tags": ["admin", "editor"],
+            "address": { "city": "Budapest", "zip": "1011" },
+            "nickname": Bson::Null,
+        }
+    }
+
+    #[test]
+    fn updates() {
+        let updated = apply_update(&user(), &doc!{
+            "$set": { "address.city": "Szeged", "tier": "gold" },
+            "$unset": { "nickname": "" },
+            "$inc": { "age": 1, "score": 0.5, "logins": 3 },
+            "$push": { "tags": "owner" },
+        }).unwrap();
+
+        assert_eq!(updated.get_str("tier").unwrap(), "gold");
+        assert_eq!(updated.get_document("address").unwrap().get_str("city").unwrap(), "Szeged");
+        assert!(!updated.contains_key("nickname"));
+        assert_eq!(updated.get_i32("age").unwrap(), 35);
+        assert_eq!(updated.get("score"), Some(&Bson::FloatingPoint(8.0)));
+        assert_eq!(updated.get_i32("logins").unwrap(), 3);
+        assert_eq!(updated.get_array("tags").unwrap().len(), 3);
+
+        let replaced = apply_update(&user(), &doc!{ "name": "Bob" }).unwrap();
+        assert_eq!(replaced, doc!{ "_id": 1, "name": "Bob" });
+
+        let error = apply_update(&user(), &doc!{ "$rename": { "name": "first" } }).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnsupportedOperator);
+    }
+}
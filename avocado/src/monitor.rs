@@ -0,0 +1,537 @@
+//! Instrumentation of the commands sent to the server.
+//!
+//! A [`CommandListener`](trait.CommandListener.html) is notified when a
+//! command is started, and when it succeeds or fails, e.g. for tracing slow
+//! queries or exporting metrics. Listeners are registered either for every
+//! command of a client, via `DatabaseExt::add_command_listener()`, or only
+//! for the commands on a single collection, via
+//! `Collection::add_command_listener()`. They are notified of commands sent
+//! by Avocado and by the driver alike.
+//!
+//! Filters are passed to listeners **redacted**: the structure and the
+//! operators are kept, but every value is replaced by `"?"`, so that logs
+//! don't contain personal data. See [`redact()`](fn.redact.html).
+//!
+//! With the `log` feature, a [`LogListener`](struct.LogListener.html) logs
+//! every command using the `log` crate, and logs slow ones as warnings.
+//! With the `metrics` feature, a
+//! [`MetricsListener`](struct.MetricsListener.html) records the number, the
+//! latency and the payload sizes of commands using the `metrics` crate.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::monitor::redact;
+//! #
+//! # fn main() {
+//! let filter = doc!{ "email": "joe@example.com", "age": { "$gte": 18 } };
+//!
+//! assert_eq!(redact(&filter), doc!{ "email": "?", "age": { "$gte": "?" } });
+//! # }
+//! ```
+
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::collections::HashMap;
+use std::time::Duration;
+use std::fmt::Debug;
+use bson::{ Bson, Document };
+use mongodb::{
+    Client, ThreadedClient,
+    CommandStarted as DriverStarted,
+    CommandResult as DriverResult,
+};
+use crate::{
+    bsn::document_size,
+    error::{ Result, ResultExt },
+};
+
+/// The placeholder replacing values in redacted filters.
+pub const REDACTED_VALUE: &str = "?";
+
+/// Receives the events of the commands sent to the server. Every method
+/// does nothing by default. Listeners are called synchronously on the
+/// thread executing the command, so they should be quick.
+pub trait CommandListener: Debug + Send + Sync {
+    /// Called before a command is sent.
+    fn started(&self, _event: &CommandStarted) {}
+
+    /// Called after a command succeeded.
+    fn succeeded(&self, _event: &CommandSucceeded) {}
+
+    /// Called after a command failed.
+    fn failed(&self, _event: &CommandFailed) {}
+}
+
+/// A command about to be sent to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandStarted {
+    /// The name of the command, e.g. `"find"` or `"update"`.
+    pub command_name: String,
+    /// The name of the database the command is run on.
+    pub database: String,
+    /// The name of the collection the command operates on, if any.
+    pub collection: Option<String>,
+    /// The redacted filter of the command, if it has one, e.g. the filter
+    /// of a `find`, or the query of the first statement of an `update`.
+    pub filter: Option<Document>,
+    /// The size of the command encoded as BSON, in bytes.
+    pub size: usize,
+    /// Identifies the command; it is the same in the completion event.
+    pub request_id: i64,
+}
+
+/// A command which succeeded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSucceeded {
+    /// The name of the command.
+    pub command_name: String,
+    /// The name of the database, if known.
+    pub database: Option<String>,
+    /// The name of the collection the command operated on, if any.
+    pub collection: Option<String>,
+    /// How long the command took, including the round trip.
+    pub duration: Duration,
+    /// The size of the reply encoded as BSON, in bytes.
+    pub reply_size: usize,
+    /// Identifies the command.
+    pub request_id: i64,
+}
+
+/// A command which failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandFailed {
+    /// The name of the command.
+    pub command_name: String,
+    /// The name of the database, if known.
+    pub database: Option<String>,
+    /// The name of the collection the command operated on, if any.
+    pub collection: Option<String>,
+    /// How long the command took until it failed.
+    pub duration: Duration,
+    /// The description of the error.
+    pub error: String,
+    /// Identifies the command.
+    pub request_id: i64,
+}
+
+/// Identifies a registered listener, so that it can be removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ListenerHandle(u64);
+
+/// Registers a listener for the commands sent by `client`, optionally only
+/// those operating on the collection named `collection`. Usually called
+/// through `DatabaseExt::add_command_listener()` or
+/// `Collection::add_command_listener()`.
+pub fn add_listener(
+    client: &Client,
+    collection: Option<&str>,
+    listener: Arc<dyn CommandListener>,
+) -> Result<ListenerHandle> {
+    let client_id = client_id(client);
+    let mut registry = registry();
+
+    // The driver only accepts plain functions as hooks, so they are
+    // installed once per client, and they dispatch via the registry.
+    // The hooks are stored in the state shared by every handle of the
+    // client, so installing them through a fresh handle is sufficient.
+    if !registry.clients.contains(&client_id) {
+        let mut shared = client.clone();
+        shared.add_start_hook(on_started).chain("can't add command start hook")?;
+        shared.add_completion_hook(on_completed).chain("can't add command completion hook")?;
+        registry.clients.push(client_id);
+    }
+
+    registry.next_handle += 1;
+
+    let handle = ListenerHandle(registry.next_handle);
+
+    registry.listeners.push(Registration {
+        handle,
+        client_id,
+        collection: collection.map(Into::into),
+        listener,
+    });
+
+    Ok(handle)
+}
+
+/// Unregisters a listener. Returns `false` if it wasn't registered.
+pub fn remove_listener(handle: ListenerHandle) -> bool {
+    let mut registry = registry();
+    let count = registry.listeners.len();
+
+    registry.listeners.retain(|registration| registration.handle != handle);
+    registry.listeners.len() < count
+}
+
+/// Replaces every value in a filter with `REDACTED_VALUE`, retaining the
+/// field names and the operators.
+pub fn redact(filter: &Document) -> Document {
+    filter
+        .iter()
+        .map(|(key, value)| (key.clone(), redact_value(value)))
+        .collect()
+}
+
+/// Redacts a single value of a filter. Arrays of subdocuments, e.g. the
+/// operands of `$and`, are redacted element-wise; other arrays as a whole.
+fn redact_value(value: &Bson) -> Bson {
+    match *value {
+        Bson::Document(ref doc) => Bson::Document(redact(doc)),
+        Bson::Array(ref items) if items.iter().all(|item| item.as_document().is_some()) => {
+            Bson::Array(items.iter().map(redact_value).collect())
+        }
+        _ => Bson::String(REDACTED_VALUE.into()),
+    }
+}
+
+/// A listener registered for a client.
+#[derive(Debug)]
+struct Registration {
+    /// Identifies the registration.
+    handle: ListenerHandle,
+    /// Identifies the client.
+    client_id: usize,
+    /// Restricts the notifications to the commands on this collection.
+    collection: Option<String>,
+    /// The listener to be notified.
+    listener: Arc<dyn CommandListener>,
+}
+
+/// What is remembered about a started command until it completes.
+#[derive(Debug)]
+struct Pending {
+    /// The name of the database.
+    database: String,
+    /// The name of the collection, if any.
+    collection: Option<String>,
+}
+
+/// The listeners of every client.
+#[derive(Debug, Default)]
+struct Registry {
+    /// The clients the hooks of which are installed.
+    clients: Vec<usize>,
+    /// The registered listeners.
+    listeners: Vec<Registration>,
+    /// The commands in flight, by client and request ID.
+    pending: HashMap<(usize, i64), Pending>,
+    /// The last listener handle issued.
+    next_handle: u64,
+}
+
+impl Registry {
+    /// Returns the listeners interested in a command of a client.
+    fn listeners_for(&self, client_id: usize, collection: Option<&str>)
+        -> Vec<Arc<dyn CommandListener>>
+    {
+        self.listeners
+            .iter()
+            .filter(|registration| {
+                registration.client_id == client_id && registration.collection.as_ref().map_or(
+                    true, |name| Some(name.as_str()) == collection
+                )
+            })
+            .map(|registration| registration.listener.clone())
+            .collect()
+    }
+}
+
+lazy_static! {
+    /// The process-wide registry of listeners.
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// Locks the registry. A panicking listener is called without holding the
+/// lock, so poisoning is ignored.
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Identifies a client by the address of its shared state, since clones of
+/// a client share their hooks.
+fn client_id(client: &Client) -> usize {
+    let inner: *const _ = &**client;
+    inner as usize
+}
+
+/// The start hook installed in the driver.
+fn on_started(client: Client, driver_event: &DriverStarted) {
+    let client_id = client_id(&client);
+    let collection = command_collection(&driver_event.command_name, &driver_event.command);
+    let listeners = {
+        let mut registry = registry();
+
+        registry.pending.insert((client_id, driver_event.request_id), Pending {
+            database: driver_event.database_name.clone(),
+            collection: collection.clone(),
+        });
+        registry.listeners_for(client_id, collection.as_ref().map(String::as_str))
+    };
+
+    if listeners.is_empty() {
+        return;
+    }
+
+    let event = CommandStarted {
+        command_name: driver_event.command_name.clone(),
+        database: driver_event.database_name.clone(),
+        filter: command_filter(&driver_event.command_name, &driver_event.command).map(|f| redact(&f)),
+        collection,
+        size: document_size(&driver_event.command).unwrap_or_default(),
+        request_id: driver_event.request_id,
+    };
+
+    for listener in listeners {
+        listener.started(&event);
+    }
+}
+
+/// The completion hook installed in the driver.
+fn on_completed(client: Client, driver_event: &DriverResult) {
+    let client_id = client_id(&client);
+    let request_id = match *driver_event {
+        DriverResult::Success { request_id, .. } | DriverResult::Failure { request_id, .. } => request_id,
+    };
+    let (pending, listeners) = {
+        let mut registry = registry();
+        let pending = registry.pending.remove(&(client_id, request_id));
+        let collection = pending.as_ref().and_then(|p| p.collection.as_ref()).map(String::as_str);
+        let listeners = registry.listeners_for(client_id, collection);
+
+        (pending, listeners)
+    };
+
+    if listeners.is_empty() {
+        return;
+    }
+
+    let (database, collection) = match pending {
+        Some(Pending { database, collection }) => (Some(database), collection),
+        None => (None, None),
+    };
+
+    match *driver_event {
+        DriverResult::Success { duration, ref command_name, ref reply, .. } => {
+            let event = CommandSucceeded {
+                command_name: command_name.clone(),
+                database,
+                collection,
+                duration: Duration::from_nanos(duration),
+                reply_size: document_size(reply).unwrap_or_default(),
+                request_id,
+            };
+
+            for listener in listeners {
+                listener.succeeded(&event);
+            }
+        }
+        DriverResult::Failure { duration, ref command_name, failure, .. } => {
+            let event = CommandFailed {
+                command_name: command_name.clone(),
+                database,
+                collection,
+                duration: Duration::from_nanos(duration),
+                error: failure.to_string(),
+                request_id,
+            };
+
+            for listener in listeners {
+                listener.failed(&event);
+            }
+        }
+    }
+}
+
+/// Returns the name of the collection a command operates on. Most commands
+/// name it as their first value, but `getMore` names it separately.
+fn command_collection(command_name: &str, command: &Document) -> Option<String> {
+    if command_name == "getMore" {
+        return command.get_str("collection").ok().map(Into::into);
+    }
+
+    match command.iter().next() {
+        Some((_, &Bson::String(ref name))) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Returns the filter of a command, if it has one.
+fn command_filter(command_name: &str, command: &Document) -> Option<Document> {
+    let first_statement = |key| match command.get_array(key).ok()?.first() {
+        Some(&Bson::Document(ref statement)) => statement.get_document("q").ok().cloned(),
+        _ => None,
+    };
+
+    match command_name {
+        "find" => command.get_document("filter").ok().cloned(),
+        "count" | "distinct" | "findAndModify" => command.get_document("query").ok().cloned(),
+        "update" => first_statement("updates"),
+        "delete" => first_statement("deletes"),
+        "aggregate" => match command.get_array("pipeline").ok()?.first() {
+            Some(&Bson::Document(ref stage)) => stage.get_document("$match").ok().cloned(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Logs commands using the `log` crate: started and succeeded commands at
+/// the `debug` level, slow commands as warnings and failed ones as errors.
+#[cfg(feature = "log")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LogListener {
+    /// Commands taking at least this long are logged as warnings.
+    pub slow_threshold: Duration,
+}
+
+#[cfg(feature = "log")]
+impl Default for LogListener {
+    fn default() -> Self {
+        LogListener {
+            slow_threshold: Duration::from_millis(100),
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl CommandListener for LogListener {
+    fn started(&self, event: &CommandStarted) {
+        debug!(
+            target: "avocado",
+            "#{} {} on {}.{} started, filter: {:?}",
+            event.request_id,
+            event.command_name,
+            event.database,
+            event.collection.as_ref().map_or("", String::as_str),
+            event.filter,
+        );
+    }
+
+    fn succeeded(&self, event: &CommandSucceeded) {
+        let level = if event.duration >= self.slow_threshold {
+            log::Level::Warn
+        } else {
+            log::Level::Debug
+        };
+
+        log!(
+            target: "avocado",
+            level,
+            "#{} {} on {} succeeded in {:?}",
+            event.request_id,
+            event.command_name,
+            event.collection.as_ref().map_or("", String::as_str),
+            event.duration,
+        );
+    }
+
+    fn failed(&self, event: &CommandFailed) {
+        error!(
+            target: "avocado",
+            "#{} {} on {} failed in {:?}: {}",
+            event.request_id,
+            event.command_name,
+            event.collection.as_ref().map_or("", String::as_str),
+            event.duration,
+            event.error,
+        );
+    }
+}
+
+/// Records metrics of commands using the `metrics` facade crate, labeled by
+/// `command` (e.g. `insert`, `find`, `update` or `delete`) and `collection`:
+///
+/// * `avocado.commands`: counter of started commands;
+/// * `avocado.command_failures`: counter of failed commands;
+/// * `avocado.command_duration`: timing of completed commands, in nanoseconds;
+/// * `avocado.command_size`: histogram of command sizes, in bytes;
+/// * `avocado.reply_size`: histogram of reply sizes, in bytes.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MetricsListener;
+
+#[cfg(feature = "metrics")]
+impl CommandListener for MetricsListener {
+    fn started(&self, event: &CommandStarted) {
+        let collection = event.collection.clone().unwrap_or_default();
+
+        counter!(
+            "avocado.commands", 1,
+            "command" => event.command_name.clone(), "collection" => collection.clone()
+        );
+        value!(
+            "avocado.command_size", event.size as u64,
+            "command" => event.command_name.clone(), "collection" => collection
+        );
+    }
+
+    fn succeeded(&self, event: &CommandSucceeded) {
+        let collection = event.collection.clone().unwrap_or_default();
+
+        timing!(
+            "avocado.command_duration", duration_nanos(event.duration),
+            "command" => event.command_name.clone(), "collection" => collection.clone()
+        );
+        value!(
+            "avocado.reply_size", event.reply_size as u64,
+            "command" => event.command_name.clone(), "collection" => collection
+        );
+    }
+
+    fn failed(&self, event: &CommandFailed) {
+        let collection = event.collection.clone().unwrap_or_default();
+
+        counter!(
+            "avocado.command_failures", 1,
+            "command" => event.command_name.clone(), "collection" => collection.clone()
+        );
+        timing!(
+            "avocado.command_duration", duration_nanos(event.duration),
+            "command" => event.command_name.clone(), "collection" => collection
+        );
+    }
+}
+
+/// Converts a duration to nanoseconds, saturating at `u64::MAX`.
+#[cfg(feature = "metrics")]
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ redact, command_collection, command_filter };
+
+    #[test]
+    fn redact_filter() {
+        let filter = doc!{
+            "$or": [{ "name": "Joe" }, { "tags": { "$in": ["a", "b"] } }],
+            "deleted_at": null,
+        };
+
+        assert_eq!(redact(&filter), doc!{
+            "$or": [{ "name": "?" }, { "tags": { "$in": "?" } }],
+            "deleted_at": "?",
+        });
+    }
+
+    #[test]
+    fn command_details() {
+        let update = doc!{
+            "update": "User",
+            "updates": [{ "q": { "email": "joe@example.com" }, "u": { "$set": { "age": 42 } } }],
+        };
+        let get_more = doc!{ "getMore": 1234_i64, "collection": "User" };
+
+        assert_eq!(command_collection("update", &update), Some("User".into()));
+        assert_eq!(command_filter("update", &update), Some(doc!{ "email": "joe@example.com" }));
+        assert_eq!(command_collection("getMore", &get_more), Some("User".into()));
+        assert_eq!(command_filter("getMore", &get_more), None);
+        assert_eq!(command_collection("ping", &doc!{ "ping": 1 }), None);
+    }
+}
@@ -13,6 +13,10 @@ use mongodb::coll::options::{
 };
 use crate::{
     doc::Doc,
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
     error::Result,
 };
 
@@ -107,6 +111,27 @@ pub trait Query<T: Doc>: Debug {
     fn options(&self) -> FindOptions {
         T::query_options()
     }
+
+    /// The fields of the matching documents to return. Defaults to an empty
+    /// projection, meaning that the projection of `options()` (if any) is
+    /// used; otherwise, this one takes precedence.
+    fn projection(&self) -> Projection {
+        Projection::default()
+    }
+
+    /// The order of the returned documents. Defaults to an empty sort order,
+    /// meaning that the sort order of `options()` (if any) is used;
+    /// otherwise, this one takes precedence.
+    fn sort(&self) -> Sort {
+        Sort::default()
+    }
+
+    /// The index hint, collation and time limit of this query. The time
+    /// limit takes precedence over that of `options()`. Defaults to none
+    /// of them.
+    fn command_options(&self) -> options::Extra {
+        options::Extra::default()
+    }
 }
 
 /// An update (but not an upsert) operation.
@@ -121,6 +146,18 @@ pub trait Update<T: Doc>: Debug {
     fn options(&self) -> WriteConcern {
         T::update_options()
     }
+
+    /// The conditions of the `$[<identifier>]` positional operators in the
+    /// update. Defaults to none.
+    fn array_filters(&self) -> ArrayFilters {
+        ArrayFilters::default()
+    }
+
+    /// The index hint, collation and time limit of this operation.
+    /// Defaults to none of them.
+    fn command_options(&self) -> options::Extra {
+        options::Extra::default()
+    }
 }
 
 /// An upsert (update or insert) operation.
@@ -135,6 +172,18 @@ pub trait Upsert<T: Doc>: Debug {
     fn options(&self) -> WriteConcern {
         T::upsert_options()
     }
+
+    /// The conditions of the `$[<identifier>]` positional operators in the
+    /// upsert. Defaults to none.
+    fn array_filters(&self) -> ArrayFilters {
+        ArrayFilters::default()
+    }
+
+    /// The index hint, collation and time limit of this operation.
+    /// Defaults to none of them.
+    fn command_options(&self) -> options::Extra {
+        options::Extra::default()
+    }
 }
 
 /// A deletion / removal operation.
@@ -146,6 +195,12 @@ pub trait Delete<T: Doc>: Debug {
     fn options(&self) -> WriteConcern {
         T::delete_options()
     }
+
+    /// The index hint, collation and time limit of this operation.
+    /// Defaults to none of them.
+    fn command_options(&self) -> options::Extra {
+        options::Extra::default()
+    }
 }
 
 /// An operation for querying and updating the same document atomically,
@@ -174,6 +229,20 @@ pub trait FindAndUpdate<T: Doc>: Debug {
     fn options(&self) -> FindOneAndUpdateOptions {
         T::find_and_update_options()
     }
+
+    /// The fields of the returned document. Defaults to an empty projection,
+    /// meaning that the projection of `options()` (if any) is used;
+    /// otherwise, this one takes precedence.
+    fn projection(&self) -> Projection {
+        Projection::default()
+    }
+
+    /// The order deciding which document is updated if several of them
+    /// match. Defaults to an empty sort order, meaning that the sort order
+    /// of `options()` (if any) is used; otherwise, this one takes precedence.
+    fn sort(&self) -> Sort {
+        Sort::default()
+    }
 }
 
 /////////////////////////////////////////////
@@ -258,6 +327,18 @@ impl<T: Doc, Q: Query<T>> Query<T> for &Q {
     fn options(&self) -> FindOptions {
         (**self).options()
     }
+
+    fn projection(&self) -> Projection {
+        (**self).projection()
+    }
+
+    fn sort(&self) -> Sort {
+        (**self).sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        (**self).command_options()
+    }
 }
 
 impl<T: Doc, U: Update<T>> Update<T> for &U {
@@ -272,6 +353,14 @@ impl<T: Doc, U: Update<T>> Update<T> for &U {
     fn options(&self) -> WriteConcern {
         (**self).options()
     }
+
+    fn array_filters(&self) -> ArrayFilters {
+        (**self).array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        (**self).command_options()
+    }
 }
 
 impl<T: Doc, U: Upsert<T>> Upsert<T> for &U {
@@ -286,6 +375,14 @@ impl<T: Doc, U: Upsert<T>> Upsert<T> for &U {
     fn options(&self) -> WriteConcern {
         (**self).options()
     }
+
+    fn array_filters(&self) -> ArrayFilters {
+        (**self).array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        (**self).command_options()
+    }
 }
 
 impl<T: Doc, Q: Delete<T>> Delete<T> for &Q {
@@ -296,6 +393,10 @@ impl<T: Doc, Q: Delete<T>> Delete<T> for &Q {
     fn options(&self) -> WriteConcern {
         (**self).options()
     }
+
+    fn command_options(&self) -> options::Extra {
+        (**self).command_options()
+    }
 }
 
 impl<T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for &U {
@@ -316,4 +417,12 @@ impl<T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for &U {
     fn options(&self) -> FindOneAndUpdateOptions {
         (**self).options()
     }
+
+    fn projection(&self) -> Projection {
+        (**self).projection()
+    }
+
+    fn sort(&self) -> Sort {
+        (**self).sort()
+    }
 }
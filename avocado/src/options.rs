@@ -0,0 +1,156 @@
+//! Index hints, collations and time limits of queries, updates and deletions.
+//!
+//! The driver can't send these with queries, updates and deletions. The
+//! [`Extra`](struct.Extra.html) returned by the
+//! `command_options()` method of `Query`, `Update`, `Upsert` and `Delete`
+//! can force the use of an index, compare strings according to the rules
+//! of a locale, or limit the time spent executing the operation. If any of
+//! them is specified, the operation is sent as a raw command.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::options::{ Extra, Hint };
+//! #
+//! # fn main() {
+//! let options = Extra::new()
+//!     .hint("email_1")
+//!     .collation(doc!{ "locale": "en", "strength": 2 })
+//!     .max_time_ms(500);
+//!
+//! assert_eq!(options.hint, Some(Hint::Name("email_1".into())));
+//! assert!(!options.is_empty());
+//! assert!(Extra::new().is_empty());
+//! # }
+//! ```
+
+use bson::{ Bson, Document };
+
+/// The index to be used by an operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hint {
+    /// The name of the index.
+    Name(String),
+    /// The key specification of the index, e.g. `{ "email": 1 }`.
+    Keys(Document),
+}
+
+impl From<&str> for Hint {
+    fn from(name: &str) -> Self {
+        Hint::Name(name.into())
+    }
+}
+
+impl From<String> for Hint {
+    fn from(name: String) -> Self {
+        Hint::Name(name)
+    }
+}
+
+impl From<Document> for Hint {
+    fn from(keys: Document) -> Self {
+        Hint::Keys(keys)
+    }
+}
+
+impl From<Hint> for Bson {
+    fn from(hint: Hint) -> Self {
+        match hint {
+            Hint::Name(name) => Bson::String(name),
+            Hint::Keys(keys) => Bson::Document(keys),
+        }
+    }
+}
+
+/// Options of a query, update, upsert or deletion which the driver can't send.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extra {
+    /// The index to use for finding the matching documents.
+    pub hint: Option<Hint>,
+    /// The collation used for comparing strings, e.g.
+    /// `{ "locale": "en", "strength": 2 }` for case-insensitive matching.
+    /// Defaults to the collation of the collection.
+    pub collation: Option<Document>,
+    /// The time limit of the operation, in milliseconds.
+    pub max_time_ms: Option<i64>,
+}
+
+impl Extra {
+    /// Returns options which don't specify anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the index to use.
+    pub fn hint<H: Into<Hint>>(mut self, hint: H) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Sets the collation.
+    pub fn collation(mut self, collation: Document) -> Self {
+        self.collation = Some(collation);
+        self
+    }
+
+    /// Sets the time limit, in milliseconds.
+    pub fn max_time_ms(mut self, max_time_ms: i64) -> Self {
+        self.max_time_ms = Some(max_time_ms);
+        self
+    }
+
+    /// Returns `true` if none of the options are specified, i.e. if the
+    /// operation can be sent by the driver.
+    pub fn is_empty(&self) -> bool {
+        self.hint.is_none() && self.collation.is_none() && self.max_time_ms.is_none()
+    }
+
+    /// Adds the hint and the collation to a `find` command, or to an update
+    /// or delete statement.
+    pub(crate) fn apply_to_statement(&self, statement: &mut Document) {
+        if let Some(ref hint) = self.hint {
+            statement.insert("hint", hint.clone());
+        }
+        if let Some(ref collation) = self.collation {
+            statement.insert("collation", collation.clone());
+        }
+    }
+
+    /// Adds the time limit to an `update` or `delete` command.
+    pub(crate) fn apply_to_command(&self, command: &mut Document) {
+        if let Some(max_time_ms) = self.max_time_ms {
+            command.insert("maxTimeMS", max_time_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extra;
+
+    #[test]
+    fn apply_options() {
+        let options = Extra::new()
+            .hint(doc!{ "email": 1 })
+            .collation(doc!{ "locale": "fr" })
+            .max_time_ms(100);
+        let mut statement = doc!{ "q": {}, "limit": 0 };
+        let mut command = doc!{ "delete": "User" };
+
+        options.apply_to_statement(&mut statement);
+        options.apply_to_command(&mut command);
+
+        assert_eq!(statement, doc!{
+            "q": {},
+            "limit": 0,
+            "hint": { "email": 1 },
+            "collation": { "locale": "fr" },
+        });
+        assert_eq!(command, doc!{ "delete": "User", "maxTimeMS": 100_i64 });
+
+        Extra::new().apply_to_statement(&mut command);
+        assert_eq!(command, doc!{ "delete": "User", "maxTimeMS": 100_i64 });
+    }
+}
@@ -0,0 +1,370 @@
+//! Paginating the results of a query, e.g. for the list endpoints of a web API.
+//!
+//! A [`Paginator`](struct.Paginator.html) supports two styles of pagination.
+//! [`page()`](struct.Paginator.html#method.page) returns numbered pages using
+//! `skip` and `limit`, along with the total number of matching documents.
+//! It's simple, and clients can jump to any page, but the server still
+//! has to walk over all skipped documents, and items shift between pages
+//! when documents are inserted or deleted in the meantime.
+//!
+//! [`after()`](struct.Paginator.html#method.after) implements keyset (a.k.a.
+//! cursor-based) pagination instead: each page ends with an opaque
+//! continuation token encoding the sort key of its last item, and the next
+//! page is requested by filtering for the documents sorted after that key.
+//! This is fast at any depth if the sort key is indexed, and no item is
+//! skipped or repeated under concurrent modification. To make the sort key
+//! unique, `_id` is appended to the sort order of the query unless it's
+//! already part of it. The sort fields must be present in every matching
+//! document, and they must not be projected out.
+//!
+//! In both styles, the `skip` and `limit` options of the query are ignored.
+
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use bson::{ Bson, Document, from_bson };
+use mongodb::coll::options::FindOptions;
+use crate::{
+    coll::Collection,
+    doc::Doc,
+    ops::Query,
+    options,
+    projection::Projection,
+    matcher::float,
+    mock::get_path,
+    error::{ Error, ErrorKind, Result },
+};
+
+/// Splits the results of a query into pages of a fixed size. Usually
+/// created by `Collection::paginate()`.
+pub struct Paginator<'a, T: Doc, Q: Query<T>> {
+    /// The queried collection.
+    collection: &'a Collection<T>,
+    /// The paginated query.
+    query: Q,
+    /// The maximal number of items on a page.
+    page_size: usize,
+}
+
+impl<'a, T: Doc, Q: Query<T>> Paginator<'a, T, Q> {
+    /// Creates a paginator returning at most `page_size` items per page.
+    /// A page size of 0 is treated as 1.
+    pub fn new(collection: &'a Collection<T>, query: Q, page_size: usize) -> Self {
+        Paginator {
+            collection,
+            query,
+            page_size: page_size.max(1),
+        }
+    }
+
+    /// Returns the maximal number of items on a page.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns the page with the given 0-based index, along with the total
+    /// number of documents matching the query. Requesting a page past the
+    /// end yields an empty page.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn page(&self, number: usize) -> Result<OffsetPage<Q::Output>> {
+        let total_items = self.collection.count(self.query.filter())?;
+        let page = PageQuery {
+            query: &self.query,
+            after: None,
+            skip: Some(number.saturating_mul(self.page_size) as i64),
+            limit: self.page_size as i64,
+            sort: None,
+        };
+        let items = self.fetch(page)?
+            .into_iter()
+            .map(transform::<T, Q>)
+            .collect::<Result<_>>()?;
+
+        Ok(OffsetPage {
+            items,
+            number,
+            page_size: self.page_size,
+            total_items,
+        })
+    }
+
+    /// Returns the page following the one which `token` was returned with,
+    /// or the first page if `token` is `None`. A token is only valid for
+    /// a query with the same sort order.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn after(&self, token: Option<&str>) -> Result<KeysetPage<Q::Output>> {
+        let sort = keyset_sort(self.query.options().sort);
+        let after = match token {
+            Some(encoded) => Some(keyset_filter(&sort, &decode_token(encoded, &sort)?)),
+            None => None,
+        };
+        let page = PageQuery {
+            query: &self.query,
+            after,
+            skip: None,
+            // One more item than needed tells whether there is a next page.
+            limit: self.page_size as i64 + 1,
+            sort: Some(sort.clone()),
+        };
+        let mut raw = self.fetch(page)?;
+        let next_token = if raw.len() > self.page_size {
+            raw.truncate(self.page_size);
+
+            match raw.last() {
+                Some(last) => Some(encode_token(&sort, last)?),
+                None => None,
+            }
+        } else {
+            None
+        };
+        let items = raw
+            .into_iter()
+            .map(transform::<T, Q>)
+            .collect::<Result<_>>()?;
+
+        Ok(KeysetPage { items, next_token })
+    }
+
+    /// Retrieves the raw documents of a page.
+    fn fetch(&self, page: PageQuery<Q>) -> Result<Vec<Document>> {
+        self.collection.find_many(page)?.collect()
+    }
+}
+
+impl<'a, T: Doc, Q: Query<T>> Debug for Paginator<'a, T, Q> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Paginator")
+            .field("collection", &self.collection)
+            .field("query", &self.query)
+            .field("page_size", &self.page_size)
+            .finish()
+    }
+}
+
+/// A numbered page, returned by `Paginator::page()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetPage<O> {
+    /// The items on the page.
+    pub items: Vec<O>,
+    /// The 0-based index of the page.
+    pub number: usize,
+    /// The maximal number of items on a page.
+    pub page_size: usize,
+    /// The number of documents matching the query, on all pages.
+    pub total_items: usize,
+}
+
+impl<O> OffsetPage<O> {
+    /// Returns the number of pages needed for all matching documents.
+    pub fn total_pages(&self) -> usize {
+        (self.total_items + self.page_size - 1) / self.page_size
+    }
+
+    /// Returns `true` if there are pages after this one.
+    pub fn has_next(&self) -> bool {
+        self.number + 1 < self.total_pages()
+    }
+}
+
+/// A page of keyset pagination, returned by `Paginator::after()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysetPage<O> {
+    /// The items on the page.
+    pub items: Vec<O>,
+    /// The token to pass to `Paginator::after()` for the next page, or
+    /// `None` if this is the last page. It's URL-safe, so it can be sent
+    /// to clients as is.
+    pub next_token: Option<String>,
+}
+
+/// The query requesting a single page: the original query, with its sort,
+/// skip and limit options replaced, and optionally restricted to the
+/// documents after the key of the last item of the previous page.
+#[derive(Debug)]
+struct PageQuery<'q, Q> {
+    /// The original query.
+    query: &'q Q,
+    /// Matches the documents after the previous page, if any.
+    after: Option<Document>,
+    /// The number of skipped documents.
+    skip: Option<i64>,
+    /// The maximal number of documents.
+    limit: i64,
+    /// The sort order, if it differs from that of the original query.
+    sort: Option<Document>,
+}
+
+impl<'q, T: Doc, Q: Query<T>> Query<T> for PageQuery<'q, Q> {
+    /// Raw documents, so that the paginator can look at their sort key.
+    type Output = Document;
+
+    fn filter(&self) -> Document {
+        let filter = self.query.filter();
+
+        match self.after {
+            None => filter,
+            Some(ref after) if filter.is_empty() => after.clone(),
+            Some(ref after) => doc!{ "$and": [filter, after.clone()] },
+        }
+    }
+
+    fn options(&self) -> FindOptions {
+        let mut options = self.query.options();
+
+        options.skip = self.skip;
+        options.limit = Some(self.limit);
+
+        if let Some(ref sort) = self.sort {
+            options.sort = Some(sort.clone());
+        }
+
+        options
+    }
+
+    fn projection(&self) -> Projection {
+        self.query.projection()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.query.command_options()
+    }
+}
+
+/// Converts a raw document to the output type of the query.
+fn transform<T: Doc, Q: Query<T>>(raw: Document) -> Result<Q::Output> {
+    Q::transform(raw).and_then(|bson| from_bson(bson).map_err(From::from))
+}
+
+/// Returns the sort order of keyset pagination: that of the query, made
+/// unique by appending `_id` if necessary.
+fn keyset_sort(requested: Option<Document>) -> Document {
+    let mut sort = requested.unwrap_or_default();
+
+    if !sort.contains_key("_id") {
+        sort.insert("_id", 1);
+    }
+
+    sort
+}
+
+/// Returns the filter matching the documents sorted strictly after `key`,
+/// e.g. `{ "$or": [{ "a": { "$gt": 1 } }, { "a": 1, "_id": { "$gt": 2 } }] }`
+/// for the sort `{ "a": 1, "_id": 1 }` and the key `{ "a": 1, "_id": 2 }`.
+fn keyset_filter(sort: &Document, key: &Document) -> Document {
+    let mut clauses = Vec::new();
+    let mut equal = Document::new();
+
+    for (field, direction) in sort {
+        let value = key.get(field).cloned().unwrap_or(Bson::Null);
+        let operator = if float(direction).map_or(false, |d| d < 0.0) { "$lt" } else { "$gt" };
+        let mut condition = Document::new();
+        let mut clause = equal.clone();
+
+        condition.insert(operator, value.clone());
+        clause.insert(field.clone(), condition);
+        clauses.push(clause);
+        equal.insert(field.clone(), value);
+    }
+
+    if clauses.len() == 1 {
+        clauses.pop().unwrap_or_default()
+    } else {
+        doc!{ "$or": clauses.into_iter().map(Bson::Document).collect::<Vec<_>>() }
+    }
+}
+
+/// Encodes the sort key of a document as a URL-safe continuation token.
+fn encode_token(sort: &Document, last: &Document) -> Result<String> {
+    let mut key = Document::new();
+
+    for field in sort.keys() {
+        let value = get_path(last, field).ok_or_else(|| Error::new(
+            ErrorKind::MissingDocumentField,
+            format!("sort field `{}` is missing from a paginated document", field)
+        ))?;
+
+        key.insert(field.clone(), value.clone());
+    }
+
+    let mut bytes = Vec::new();
+    bson::encode_document(&mut bytes, &key)?;
+
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// Decodes a continuation token, checking that it belongs to the sort order.
+fn decode_token(token: &str, sort: &Document) -> Result<Document> {
+    let invalid = || Error::new(
+        ErrorKind::InvalidPageToken,
+        format!("page token `{}` is malformed or belongs to another sort order", token)
+    );
+    let bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    let key = bson::decode_document(&mut bytes.as_slice()).map_err(|_| invalid())?;
+
+    if key.keys().eq(sort.keys()) {
+        Ok(key)
+    } else {
+        Err(invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{ ErrorExt, ErrorKind };
+    use super::{ OffsetPage, keyset_sort, keyset_filter, encode_token, decode_token };
+
+    #[test]
+    fn page_counts() {
+        let page = |number, total_items| OffsetPage::<()> {
+            items: Vec::new(),
+            number,
+            page_size: 10,
+            total_items,
+        };
+
+        assert_eq!(page(0, 0).total_pages(), 0);
+        assert!(!page(0, 0).has_next());
+        assert_eq!(page(0, 10).total_pages(), 1);
+        assert!(!page(0, 10).has_next());
+        assert_eq!(page(0, 11).total_pages(), 2);
+        assert!(page(0, 11).has_next());
+        assert!(!page(1, 11).has_next());
+    }
+
+    #[test]
+    fn keyset_filters() {
+        assert_eq!(keyset_sort(None), doc!{ "_id": 1 });
+        assert_eq!(keyset_sort(Some(doc!{ "_id": -1 })), doc!{ "_id": -1 });
+
+        let sort = keyset_sort(Some(doc!{ "score": -1, "name": 1 }));
+        assert_eq!(sort, doc!{ "score": -1, "name": 1, "_id": 1 });
+
+        assert_eq!(keyset_filter(&doc!{ "_id": 1 }, &doc!{ "_id": 7 }), doc!{ "_id": { "$gt": 7 } });
+        assert_eq!(
+            keyset_filter(&sort, &doc!{ "score": 90, "name": "bob", "_id": 7 }),
+            doc!{
+                "$or": [
+                    { "score": { "$lt": 90 } },
+                    { "score": 90, "name": { "$gt": "bob" } },
+                    { "score": 90, "name": "bob", "_id": { "$gt": 7 } },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        let sort = doc!{ "stats.score": -1, "_id": 1 };
+        let last = doc!{ "_id": 7, "name": "bob", "stats": { "score": 90 } };
+        let token = encode_token(&sort, &last).unwrap();
+
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(decode_token(&token, &sort).unwrap(), doc!{ "stats.score": 90, "_id": 7 });
+
+        let other_sort = doc!{ "_id": 1 };
+        assert_eq!(decode_token(&token, &other_sort).unwrap_err().kind(), ErrorKind::InvalidPageToken);
+        assert_eq!(decode_token("not a token!", &sort).unwrap_err().kind(), ErrorKind::InvalidPageToken);
+        assert_eq!(
+            encode_token(&sort, &doc!{ "_id": 7 }).unwrap_err().kind(),
+            ErrorKind::MissingDocumentField
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! Statically-checked paths of fields, including fields of embedded documents.
+//!
+//! Filters and updates refer to fields by their stored names, and to fields
+//! of embedded documents by dotted paths such as `"address.city"`. Spelled
+//! out as string literals, these silently stop matching anything when a
+//! field is renamed. Instead, `#[derive(Subdoc)]` on the type of an embedded
+//! document generates a method for each of its fields, which returns the
+//! [`Path`](struct.Path.html) of the field, respecting
+//! `#[serde(rename)]` and `#[serde(rename_all)]`. A field whose type is
+//! itself `Subdoc` is annotated with `#[avocado(subdoc)]`, and its method
+//! then returns the field paths of the nested type, prefixed with the path
+//! of the field. `Vec`s, `Option`s and `Box`es of `Subdoc` types can be
+//! annotated as well, since MongoDB uses the same dotted paths for fields of
+//! documents inside arrays.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! use avocado::path::Subdoc;
+//!
+//! #[derive(Debug, Serialize, Deserialize, Subdoc)]
+//! struct Geo {
+//!     lat: f64,
+//!     lng: f64,
+//! }
+//!
+//! #[derive(Debug, Serialize, Deserialize, Subdoc)]
+//! #[serde(rename_all = "camelCase")]
+//! struct Address {
+//!     city: String,
+//!     postal_code: String,
+//!     #[avocado(subdoc)]
+//!     geo: Option<Geo>,
+//! }
+//!
+//! # fn main() {
+//! assert_eq!(Address::fields().postal_code(), "postalCode");
+//! assert_eq!(Address::fields_at("address").city(), "address.city");
+//! assert_eq!(Address::fields_at("address").geo().lat(), "address.geo.lat");
+//! # }
+//! ```
+//!
+//! A `Path` converts into a `String`, so it can be passed to the
+//! builders of the [`update`](../update/index.html) module, and used as a
+//! key of the `doc!` macro when parenthesized, e.g.
+//! `doc!{ (Address::fields().city()): "Budapest" }`.
+//!
+//! The `checked_flt!` macro builds an entire filter from such paths, written
+//! as dotted string keys of Rust field names, and also checks at compile
+//! time that each literal can match the type of its field:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! #[derive(Debug, Serialize, Deserialize, Subdoc)]
+//! #[serde(rename_all = "camelCase")]
+//! struct Address {
+//!     city: String,
+//!     postal_code: Option<String>,
+//! }
+//!
+//! #[derive(Debug, Serialize, Deserialize, Subdoc)]
+//! struct Person {
+//!     age: u32,
+//!     #[avocado(subdoc)]
+//!     address: Address,
+//! }
+//!
+//! # fn main() {
+//! let filter = checked_flt!(Person, {
+//!     "age": { "$gte": 18 },
+//!     "address.postal_code": { "$in": ["1011", "1024"] },
+//! });
+//!
+//! assert_eq!(filter, doc!{
+//!     "age": { "$gte": 18 },
+//!     "address.postalCode": { "$in": ["1011", "1024"] },
+//! });
+//! # }
+//! ```
+//!
+//! Misspelling `"address.postal_code"` or comparing `"age"` to a string
+//! fails to compile. Keys starting with `$` other than `$and`, `$or` and
+//! `$nor` are not checked, nor are the values of operators other than the
+//! comparison operators, `$regex`, `$in`, `$nin`, `$all` and `$not`, and
+//! only literals are checked, not other expressions.
+
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::fmt::{ Display, Formatter, Result as FmtResult };
+use bson::Bson;
+
+/// The dotted path of a field, relative to the top-level document.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Path(Cow<'static, str>);
+
+impl Path {
+    /// Returns the path of the field with the given stored name, at the top
+    /// level of the document.
+    pub fn new<S: Into<Cow<'static, str>>>(path: S) -> Self {
+        Path(path.into())
+    }
+
+    /// Returns the empty path, denoting the top-level document itself.
+    pub fn root() -> Self {
+        Path(Cow::Borrowed(""))
+    }
+
+    /// Returns `true` if this is the path of the top-level document.
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the path of the field named `name` inside the document at
+    /// this path. Fields at the top level don't allocate.
+    pub fn field(&self, name: &'static str) -> Self {
+        if self.is_root() {
+            Path(Cow::Borrowed(name))
+        } else {
+            Path(Cow::Owned(format!("{}.{}", self.0, name)))
+        }
+    }
+
+    /// Returns the path as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the field path expression referring to this field in an
+    /// aggregation pipeline, i.e. the path prefixed with `$`.
+    pub fn expr(&self) -> Bson {
+        Bson::String(format!("${}", self.0))
+    }
+}
+
+impl Deref for Path {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Path {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Path {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&'static str> for Path {
+    fn from(path: &'static str) -> Self {
+        Path(Cow::Borrowed(path))
+    }
+}
+
+impl From<String> for Path {
+    fn from(path: String) -> Self {
+        Path(Cow::Owned(path))
+    }
+}
+
+impl From<Path> for String {
+    fn from(path: Path) -> Self {
+        path.0.into_owned()
+    }
+}
+
+impl PartialEq<str> for Path {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Path {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// A type which is stored as an embedded document. Usually derived.
+pub trait Subdoc {
+    /// Provides the paths of the fields of this type.
+    type Fields;
+
+    /// Returns the paths of the fields of this type, when it is embedded at
+    /// the path `prefix`.
+    fn fields_at<P: Into<Path>>(prefix: P) -> Self::Fields;
+
+    /// Returns the paths of the fields of this type, relative to itself.
+    fn fields() -> Self::Fields {
+        Self::fields_at(Path::root())
+    }
+}
+
+impl<T: Subdoc> Subdoc for Option<T> {
+    type Fields = T::Fields;
+
+    fn fields_at<P: Into<Path>>(prefix: P) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+impl<T: Subdoc> Subdoc for Vec<T> {
+    type Fields = T::Fields;
+
+    fn fields_at<P: Into<Path>>(prefix: P) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+impl<T: Subdoc> Subdoc for Box<T> {
+    type Fields = T::Fields;
+
+    fn fields_at<P: Into<Path>>(prefix: P) -> Self::Fields {
+        T::fields_at(prefix)
+    }
+}
+
+/// Markers describing the BSON types of fields and of literals, used by the
+/// `checked_flt!` macro of `avocado_derive` for checking at compile time
+/// that the literals of a filter can match the fields they are compared to.
+/// `#[derive(Subdoc)]` and `#[derive(Doc)]` classify the type of each field
+/// by its name, e.g. `String` as `Str` and `Option<i32>` as
+/// `Nullable<Number>`. Types which can't be classified are `Any`, and accept
+/// any literal.
+pub mod kind {
+    use std::marker::PhantomData;
+
+    /// A field of a type which isn't known, accepting any literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Any;
+
+    /// A string field, or a string literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Str;
+
+    /// A numeric field, or an integer or floating-point literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Number;
+
+    /// A boolean field, or a `true` or `false` literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Bool;
+
+    /// The `null` literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Null;
+
+    /// An array literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct List;
+
+    /// An embedded document literal.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Object;
+
+    /// An optional field, whose value is either `null` or of kind `K`.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Nullable<K>(PhantomData<K>);
+
+    /// An array field with elements of kind `K`. Like MongoDB itself, it
+    /// accepts both whole arrays and single elements.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Array<K>(PhantomData<K>);
+
+    /// Implemented by field kinds for the kinds of literals a field can be
+    /// compared to.
+    pub trait Accepts<L> {}
+
+    impl<L> Accepts<L> for Any {}
+    impl Accepts<Str> for Str {}
+    impl Accepts<Number> for Number {}
+    impl Accepts<Bool> for Bool {}
+
+    impl<K> Accepts<Null> for Nullable<K> {}
+    impl<K: Accepts<Str>> Accepts<Str> for Nullable<K> {}
+    impl<K: Accepts<Number>> Accepts<Number> for Nullable<K> {}
+    impl<K: Accepts<Bool>> Accepts<Bool> for Nullable<K> {}
+    impl<K: Accepts<List>> Accepts<List> for Nullable<K> {}
+    impl<K: Accepts<Object>> Accepts<Object> for Nullable<K> {}
+
+    impl<K> Accepts<List> for Array<K> {}
+    impl<K: Accepts<Null>> Accepts<Null> for Array<K> {}
+    impl<K: Accepts<Str>> Accepts<Str> for Array<K> {}
+    impl<K: Accepts<Number>> Accepts<Number> for Array<K> {}
+    impl<K: Accepts<Bool>> Accepts<Bool> for Array<K> {}
+    impl<K: Accepts<Object>> Accepts<Object> for Array<K> {}
+
+    /// Compiles only if a field of kind `K` can be compared to a literal of
+    /// kind `L`. Does nothing at runtime.
+    pub fn check<K: Accepts<L>, L>(_field: K, _literal: L) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Path;
+
+    #[test]
+    fn nested_paths() {
+        let root = Path::root();
+        let address = root.field("address");
+        let city = address.field("city");
+
+        assert!(root.is_root());
+        assert_eq!(address, "address");
+        assert_eq!(city, "address.city");
+        assert_eq!(city.expr(), bson::Bson::from("$address.city"));
+        assert_eq!(String::from(city), "address.city");
+    }
+}
@@ -0,0 +1,550 @@
+//! A typed builder for aggregation pipelines.
+//!
+//! Instead of hand-writing an array of stage documents, a
+//! [`Pipeline`](struct.Pipeline.html) is built from typed
+//! [`Stage`](enum.Stage.html)s, and it can be passed directly to
+//! `Collection::aggregate_pipeline()`. The type parameter `O` is the type
+//! of the resulting values; it is `T` by default, and it can be changed
+//! using `output()` after a stage that reshapes the documents:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::ops::Pipeline as PipelineOp;
+//! # use avocado::pipeline::Pipeline;
+//! #
+//! # #[derive(Debug, Serialize, Deserialize)]
+//! # struct Order { _id: Uid<Order> }
+//! #
+//! # impl Doc for Order {
+//! #     type Id = ObjectId;
+//! #     const NAME: &'static str = "Order";
+//! #     fn id(&self) -> Option<&Uid<Self>> { Some(&self._id) }
+//! #     fn set_id(&mut self, id: Uid<Self>) { self._id = id; }
+//! # }
+//! #
+//! # fn main() {
+//! #[derive(Debug, Deserialize)]
+//! struct Revenue {
+//!     _id: String,
+//!     total: f64,
+//! }
+//!
+//! let pipeline = Pipeline::<Order>::new()
+//!     .filter(doc!{ "status": "shipped" })
+//!     .group("$customer", doc!{ "total": { "$sum": "$amount" } })
+//!     .sort(doc!{ "total": -1 })
+//!     .limit(10)
+//!     .output::<Revenue>();
+//!
+//! assert_eq!(pipeline.stages(), vec![
+//!     doc!{ "$match": { "status": "shipped" } },
+//!     doc!{ "$group": { "_id": "$customer", "total": { "$sum": "$amount" } } },
+//!     doc!{ "$sort": { "total": -1 } },
+//!     doc!{ "$limit": 10_i64 },
+//! ]);
+//! # }
+//! ```
+//!
+//! A pipeline can also materialize its results into the collection of
+//! another document type, by ending it with `out()` or `merge_into()` and
+//! running it with `Collection::aggregate_into()`, which returns the typed
+//! output collection. Map-reduce isn't supported; it is deprecated in
+//! favor of such pipelines.
+
+use std::marker::PhantomData;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use serde::Deserialize;
+use bson::{ Bson, Document };
+use crate::{
+    doc::Doc,
+    ops::Pipeline as PipelineOp,
+    literal::{ WhenMatched, WhenNotMatched },
+};
+
+/// A single stage of an aggregation pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// `$match`: only passes on the documents matching the filter.
+    Match(Document),
+    /// `$project`: includes, excludes or computes fields.
+    Project(Document),
+    /// `$addFields`: adds computed fields, keeping all existing ones.
+    AddFields(Document),
+    /// `$group`: groups documents by an expression.
+    Group {
+        /// The expression grouped by, which becomes the `_id` of the group.
+        id: Bson,
+        /// The accumulated fields of each group, e.g. `{ total: { $sum: 1 } }`.
+        fields: Document,
+    },
+    /// `$sort`: orders the documents.
+    Sort(Document),
+    /// `$limit`: passes on at most this many documents.
+    Limit(usize),
+    /// `$skip`: drops this many documents.
+    Skip(usize),
+    /// `$unwind`: outputs a document for each element of an array field.
+    Unwind {
+        /// The path of the array field, without the leading `$`.
+        path: String,
+        /// Whether documents with a missing, `null` or empty array are kept.
+        preserve_null_and_empty_arrays: bool,
+        /// The name of the field receiving the index of the element, if any.
+        include_array_index: Option<String>,
+    },
+    /// `$lookup`: a left outer join with another collection.
+    Lookup {
+        /// The name of the joined collection.
+        from: String,
+        /// The field of the input documents.
+        local_field: String,
+        /// The field of the documents of the joined collection.
+        foreign_field: String,
+        /// The name of the array field receiving the joined documents.
+        as_field: String,
+    },
+    /// `$count`: outputs a single document with the number of input
+    /// documents in the named field.
+    Count(String),
+    /// `$facet`: runs several sub-pipelines on the same input documents,
+    /// storing the results of each in the named field.
+    Facet(Vec<(String, Vec<Stage>)>),
+    /// `$replaceRoot`: replaces each document by an embedded document.
+    ReplaceRoot(Bson),
+    /// `$sample`: randomly selects this many documents.
+    Sample(usize),
+    /// `$out`: replaces the named collection with the output documents.
+    /// Must be the last stage.
+    Out(String),
+    /// `$merge`: merges the output documents into the named collection.
+    /// Must be the last stage.
+    Merge {
+        /// The name of the target collection.
+        into: String,
+        /// What to do with output documents whose `_id` matches an
+        /// existing document.
+        when_matched: WhenMatched,
+        /// What to do with output documents not matching any document.
+        when_not_matched: WhenNotMatched,
+    },
+    /// Any other stage, verbatim.
+    Raw(Document),
+}
+
+impl From<Stage> for Document {
+    #[allow(clippy::cast_possible_wrap)]
+    fn from(stage: Stage) -> Self {
+        match stage {
+            Stage::Match(filter) => doc!{ "$match": filter },
+            Stage::Project(projection) => doc!{ "$project": projection },
+            Stage::AddFields(fields) => doc!{ "$addFields": fields },
+            Stage::Group { id, fields } => {
+                let mut group = doc!{ "_id": id };
+
+                for (key, value) in fields {
+                    group.insert(key, value);
+                }
+
+                doc!{ "$group": group }
+            }
+            Stage::Sort(sort) => doc!{ "$sort": sort },
+            Stage::Limit(limit) => doc!{ "$limit": limit as i64 },
+            Stage::Skip(skip) => doc!{ "$skip": skip as i64 },
+            Stage::Unwind { path, preserve_null_and_empty_arrays, include_array_index } => {
+                let mut unwind = doc!{
+                    "path": format!("${}", path),
+                    "preserveNullAndEmptyArrays": preserve_null_and_empty_arrays,
+                };
+
+                if let Some(index) = include_array_index {
+                    unwind.insert("includeArrayIndex", index);
+                }
+
+                doc!{ "$unwind": unwind }
+            }
+            Stage::Lookup { from, local_field, foreign_field, as_field } => doc!{
+                "$lookup": {
+                    "from": from,
+                    "localField": local_field,
+                    "foreignField": foreign_field,
+                    "as": as_field,
+                }
+            },
+            Stage::Count(field) => doc!{ "$count": field },
+            Stage::Facet(facets) => {
+                let mut spec = Document::new();
+
+                for (name, sub_pipeline) in facets {
+                    let stages: Vec<_> = sub_pipeline
+                        .into_iter()
+                        .map(|sub_stage| Bson::Document(sub_stage.into()))
+                        .collect();
+                    spec.insert(name, stages);
+                }
+
+                doc!{ "$facet": spec }
+            }
+            Stage::ReplaceRoot(root) => doc!{ "$replaceRoot": { "newRoot": root } },
+            Stage::Sample(size) => doc!{ "$sample": { "size": size as i64 } },
+            Stage::Out(collection) => doc!{ "$out": collection },
+            Stage::Merge { into, when_matched, when_not_matched } => doc!{
+                "$merge": {
+                    "into": into,
+                    "whenMatched": when_matched,
+                    "whenNotMatched": when_not_matched,
+                }
+            },
+            Stage::Raw(raw) => raw,
+        }
+    }
+}
+
+/// A `$group` accumulator computing a single value per group, as used by
+/// `Collection::group_by()`. The operands are expressions, e.g. `"$amount"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Accumulator {
+    /// The number of documents in the group.
+    Count,
+    /// `$sum`: the sum of the operand.
+    Sum(Bson),
+    /// `$avg`: the average of the operand.
+    Avg(Bson),
+    /// `$min`: the minimum of the operand.
+    Min(Bson),
+    /// `$max`: the maximum of the operand.
+    Max(Bson),
+    /// `$first`: the operand of the first document of the group.
+    First(Bson),
+    /// `$last`: the operand of the last document of the group.
+    Last(Bson),
+    /// `$push`: an array of the operand of every document of the group.
+    Push(Bson),
+    /// `$addToSet`: an array of the distinct values of the operand.
+    AddToSet(Bson),
+}
+
+impl From<Accumulator> for Bson {
+    fn from(accumulator: Accumulator) -> Self {
+        let (operator, operand) = match accumulator {
+            Accumulator::Count              => ("$sum", Bson::I32(1)),
+            Accumulator::Sum(operand)       => ("$sum", operand),
+            Accumulator::Avg(operand)       => ("$avg", operand),
+            Accumulator::Min(operand)       => ("$min", operand),
+            Accumulator::Max(operand)       => ("$max", operand),
+            Accumulator::First(operand)     => ("$first", operand),
+            Accumulator::Last(operand)      => ("$last", operand),
+            Accumulator::Push(operand)      => ("$push", operand),
+            Accumulator::AddToSet(operand)  => ("$addToSet", operand),
+        };
+        let mut document = Document::new();
+
+        document.insert(operator, operand);
+        Bson::Document(document)
+    }
+}
+
+/// An aggregation pipeline on the collection of `T`, yielding values of
+/// type `O`.
+pub struct Pipeline<T: Doc, O = T> {
+    /// The stages of the pipeline, in order.
+    stages: Vec<Stage>,
+    /// Only here so that `T` and `O` are used.
+    _marker: PhantomData<fn() -> (T, O)>,
+}
+
+impl<T: Doc> Pipeline<T> {
+    /// Creates an empty pipeline, which yields all documents of the
+    /// collection.
+    pub fn new() -> Self {
+        Pipeline {
+            stages: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc, O> Pipeline<T, O> {
+    /// Appends a stage.
+    pub fn stage(mut self, stage: Stage) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Appends a `$match` stage.
+    pub fn filter(self, filter: Document) -> Self {
+        self.stage(Stage::Match(filter))
+    }
+
+    /// Appends a `$project` stage.
+    pub fn project(self, projection: Document) -> Self {
+        self.stage(Stage::Project(projection))
+    }
+
+    /// Appends an `$addFields` stage.
+    pub fn add_fields(self, fields: Document) -> Self {
+        self.stage(Stage::AddFields(fields))
+    }
+
+    /// Appends a `$group` stage, grouping by `id`.
+    pub fn group<B: Into<Bson>>(self, id: B, fields: Document) -> Self {
+        self.stage(Stage::Group { id: id.into(), fields })
+    }
+
+    /// Appends a `$sort` stage.
+    pub fn sort(self, sort: Document) -> Self {
+        self.stage(Stage::Sort(sort))
+    }
+
+    /// Appends a `$limit` stage.
+    pub fn limit(self, limit: usize) -> Self {
+        self.stage(Stage::Limit(limit))
+    }
+
+    /// Appends a `$skip` stage.
+    pub fn skip(self, skip: usize) -> Self {
+        self.stage(Stage::Skip(skip))
+    }
+
+    /// Appends an `$unwind` stage for the array at `path`, dropping
+    /// documents without array elements.
+    pub fn unwind<S: Into<String>>(self, path: S) -> Self {
+        self.stage(Stage::Unwind {
+            path: path.into(),
+            preserve_null_and_empty_arrays: false,
+            include_array_index: None,
+        })
+    }
+
+    /// Appends a `$lookup` stage, joining the documents of the collection
+    /// `from` whose `foreign_field` equals the `local_field` of the input
+    /// documents into the array field `as_field`.
+    pub fn lookup(self, from: &str, local_field: &str, foreign_field: &str, as_field: &str) -> Self {
+        self.stage(Stage::Lookup {
+            from: from.into(),
+            local_field: local_field.into(),
+            foreign_field: foreign_field.into(),
+            as_field: as_field.into(),
+        })
+    }
+
+    /// Appends a `$count` stage, storing the count in `field`.
+    pub fn count<S: Into<String>>(self, field: S) -> Self {
+        self.stage(Stage::Count(field.into()))
+    }
+
+    /// Appends a `$facet` stage with the given named sub-pipelines.
+    pub fn facet<I, S>(self, facets: I) -> Self
+        where I: IntoIterator<Item = (S, Vec<Stage>)>,
+              S: Into<String>,
+    {
+        let named = facets.into_iter().map(|(name, stages)| (name.into(), stages)).collect();
+        self.stage(Stage::Facet(named))
+    }
+
+    /// Appends a `$replaceRoot` stage.
+    pub fn replace_root<B: Into<Bson>>(self, root: B) -> Self {
+        self.stage(Stage::ReplaceRoot(root.into()))
+    }
+
+    /// Appends a `$sample` stage.
+    pub fn sample(self, size: usize) -> Self {
+        self.stage(Stage::Sample(size))
+    }
+
+    /// Appends an `$out` stage, replacing the collection of `U` with the
+    /// output documents, which must therefore be `U`s. The pipeline can
+    /// then be run by `Collection::aggregate_into()`.
+    pub fn out<U: Doc>(self) -> Pipeline<T, U> {
+        self.stage(Stage::Out(U::NAME.into())).output()
+    }
+
+    /// Appends a `$merge` stage, merging the output documents, which must
+    /// therefore be `U`s, into the collection of `U` by `_id`. The pipeline
+    /// can then be run by `Collection::aggregate_into()`.
+    pub fn merge_into<U: Doc>(self, when_matched: WhenMatched, when_not_matched: WhenNotMatched)
+        -> Pipeline<T, U>
+    {
+        self.stage(Stage::Merge {
+            into: U::NAME.into(),
+            when_matched,
+            when_not_matched,
+        }).output()
+    }
+
+    /// Changes the type of the values yielded by the pipeline, e.g. after
+    /// a `$group` or a `$project` stage.
+    pub fn output<U>(self) -> Pipeline<T, U> {
+        Pipeline {
+            stages: self.stages,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc> Default for Pipeline<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Doc, O> Clone for Pipeline<T, O> {
+    fn clone(&self) -> Self {
+        Pipeline {
+            stages: self.stages.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc, O> Debug for Pipeline<T, O> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Pipeline")
+            .field("collection", &T::NAME)
+            .field("stages", &self.stages)
+            .finish()
+    }
+}
+
+impl<T: Doc, O> From<Pipeline<T, O>> for Vec<Document> {
+    fn from(pipeline: Pipeline<T, O>) -> Self {
+        pipeline.stages.into_iter().map(Into::into).collect()
+    }
+}
+
+impl<T, O> PipelineOp<T> for Pipeline<T, O>
+    where T: Doc,
+          O: for<'a> Deserialize<'a>,
+{
+    type Output = O;
+
+    fn stages(&self) -> Vec<Document> {
+        self.clone().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId };
+    use crate::{ doc::Doc, uid::Uid, ops::Pipeline as PipelineOp };
+    use crate::literal::{ WhenMatched, WhenNotMatched };
+    use super::{ Pipeline, Stage, Accumulator };
+
+    /// A document type to aggregate.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Post {
+        /// The unique ID of the post.
+        _id: Uid<Post>,
+    }
+
+    impl Doc for Post {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Post";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self._id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self._id = id;
+        }
+    }
+
+    #[test]
+    fn stages() {
+        let pipeline = Pipeline::<Post>::new()
+            .unwind("tags")
+            .lookup("User", "author", "_id", "author")
+            .replace_root("$author")
+            .skip(5)
+            .sample(3)
+            .count("n");
+
+        assert_eq!(pipeline.stages(), vec![
+            doc!{ "$unwind": { "path": "$tags", "preserveNullAndEmptyArrays": false } },
+            doc!{
+                "$lookup": {
+                    "from": "User",
+                    "localField": "author",
+                    "foreignField": "_id",
+                    "as": "author",
+                }
+            },
+            doc!{ "$replaceRoot": { "newRoot": "$author" } },
+            doc!{ "$skip": 5_i64 },
+            doc!{ "$sample": { "size": 3_i64 } },
+            doc!{ "$count": "n" },
+        ]);
+    }
+
+    #[test]
+    fn output_stages() {
+        let out = Pipeline::<Post>::new().sample(1).out::<Post>();
+        let merge = Pipeline::<Post>::new().merge_into::<Post>(
+            WhenMatched::KeepExisting,
+            WhenNotMatched::Discard,
+        );
+
+        assert_eq!(out.stages(), vec![
+            doc!{ "$sample": { "size": 1_i64 } },
+            doc!{ "$out": "Post" },
+        ]);
+        assert_eq!(merge.stages(), vec![doc!{
+            "$merge": {
+                "into": "Post",
+                "whenMatched": "keepExisting",
+                "whenNotMatched": "discard",
+            }
+        }]);
+    }
+
+    #[test]
+    fn accumulators() {
+        assert_eq!(Bson::from(Accumulator::Count), Bson::from(doc!{ "$sum": 1 }));
+        assert_eq!(
+            Bson::from(Accumulator::Max("$amount".into())),
+            Bson::from(doc!{ "$max": "$amount" })
+        );
+        assert_eq!(
+            Bson::from(Accumulator::AddToSet("$tags".into())),
+            Bson::from(doc!{ "$addToSet": "$tags" })
+        );
+    }
+
+    #[test]
+    fn facets() {
+        let pipeline = Pipeline::<Post>::new().facet(vec![
+            ("by_tag", vec![
+                Stage::Unwind {
+                    path: "tags".into(),
+                    preserve_null_and_empty_arrays: true,
+                    include_array_index: Some("position".into()),
+                },
+                Stage::Group { id: "$tags".into(), fields: doc!{ "n": { "$sum": 1 } } },
+            ]),
+            ("total", vec![Stage::Count("n".into())]),
+        ]);
+
+        assert_eq!(pipeline.stages(), vec![doc!{
+            "$facet": {
+                "by_tag": [
+                    {
+                        "$unwind": {
+                            "path": "$tags",
+                            "preserveNullAndEmptyArrays": true,
+                            "includeArrayIndex": "position",
+                        }
+                    },
+                    { "$group": { "_id": "$tags", "n": { "$sum": 1 } } },
+                ],
+                "total": [{ "$count": "n" }],
+            }
+        }]);
+    }
+}
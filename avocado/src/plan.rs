@@ -0,0 +1,265 @@
+//! Warming up and pinning the query plans of critical queries.
+//!
+//! Right after a deploy or a restart, the plan cache of the server is empty,
+//! so the first executions of every query shape go through plan selection,
+//! which shows up as latency spikes. A [`Warmup`](struct.Warmup.html)
+//! registers the critical queries of the application and, when `run()` at
+//! startup, executes each of them once with a single-document batch so that
+//! the winning plans are cached. Queries for which the planner tends to
+//! choose badly can also be pinned to specific indexes using index filters
+//! (`planCacheSetFilter`):
+//!
+//! ```ignore
+//! Warmup::new()
+//!     .query(&UsersByEmail("x@y.z".into()))
+//!     .pinned(&RecentOrders(Utc::now()), &["placed_at_-1"])
+//!     .run(&db)?;
+//! ```
+//!
+//! Plans are cached per query *shape*, i.e. the structure of the filter, the
+//! sort and the projection, so the concrete values in the registered queries
+//! don't matter. Index filters don't survive a restart, so `run()` should be
+//! called every time the application starts.
+
+use bson::{ Bson, Document };
+use mongodb::CommandType;
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::{
+    doc::Doc,
+    ops::Query,
+    error::{ Result, ResultExt },
+};
+
+/// The parts of a query which determine its cached plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryShape {
+    /// The name of the queried collection.
+    pub collection: String,
+    /// The filter of the query.
+    pub filter: Document,
+    /// The sort order of the query, if any.
+    pub sort: Option<Document>,
+    /// The projection of the query, if any.
+    pub projection: Option<Document>,
+    /// The names of the indexes the planner is restricted to. If empty,
+    /// the planner is free to choose any index.
+    pub indexes: Vec<String>,
+}
+
+impl QueryShape {
+    /// Returns the shape of a query on the collection of `T`.
+    pub fn of<T: Doc, Q: Query<T>>(query: &Q) -> Self {
+        let options = query.options();
+        let projection = query.projection();
+        let sort = query.sort();
+
+        QueryShape {
+            collection: T::NAME.into(),
+            filter: query.filter(),
+            sort: if sort.is_empty() {
+                options.sort
+            } else {
+                Some(sort.into())
+            },
+            projection: if projection.is_empty() {
+                options.projection
+            } else {
+                Some(projection.into())
+            },
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Adds the fields describing the shape to a plan cache command.
+    fn add_to(&self, command: &mut Document) {
+        command.insert("query", self.filter.clone());
+
+        if let Some(ref sort) = self.sort {
+            command.insert("sort", sort.clone());
+        }
+        if let Some(ref projection) = self.projection {
+            command.insert("projection", projection.clone());
+        }
+    }
+
+    /// Returns the `planCacheSetFilter` command pinning the shape to
+    /// its indexes.
+    fn set_filter_command(&self) -> Document {
+        let indexes: Vec<Bson> = self.indexes.iter().map(|name| name.as_str().into()).collect();
+        let mut command = doc!{ "planCacheSetFilter": self.collection.as_str() };
+        self.add_to(&mut command);
+        command.insert("indexes", indexes);
+        command
+    }
+
+    /// Returns the `planCacheClearFilters` command removing the index
+    /// filter of the shape.
+    fn clear_filter_command(&self) -> Document {
+        let mut command = doc!{ "planCacheClearFilters": self.collection.as_str() };
+        self.add_to(&mut command);
+        command
+    }
+
+    /// Returns a `find` command executing the query with a single-document
+    /// batch, which is enough for the winning plan to be cached.
+    fn find_command(&self) -> Document {
+        let mut command = doc!{
+            "find": self.collection.as_str(),
+            "filter": self.filter.clone(),
+            "batchSize": 1,
+            "singleBatch": true,
+        };
+
+        if let Some(ref sort) = self.sort {
+            command.insert("sort", sort.clone());
+        }
+        if let Some(ref projection) = self.projection {
+            command.insert("projection", projection.clone());
+        }
+
+        command
+    }
+}
+
+/// A list of critical queries whose plans are warmed up, and optionally
+/// pinned, at startup.
+#[derive(Debug, Clone, Default)]
+pub struct Warmup {
+    /// The shapes of the registered queries.
+    shapes: Vec<QueryShape>,
+}
+
+impl Warmup {
+    /// Creates an empty warm-up list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a query whose plan is to be warmed up.
+    pub fn query<T: Doc, Q: Query<T>>(self, query: &Q) -> Self {
+        self.shape(QueryShape::of(query))
+    }
+
+    /// Registers a query which is to be restricted to the named indexes.
+    pub fn pinned<T, Q>(self, query: &Q, indexes: &[&str]) -> Self
+        where T: Doc,
+              Q: Query<T>,
+    {
+        let shape = QueryShape {
+            indexes: indexes.iter().map(|&name| name.into()).collect(),
+            ..QueryShape::of(query)
+        };
+
+        self.shape(shape)
+    }
+
+    /// Registers a query shape.
+    pub fn shape(mut self, shape: QueryShape) -> Self {
+        self.shapes.push(shape);
+        self
+    }
+
+    /// Returns the registered query shapes.
+    pub fn shapes(&self) -> &[QueryShape] {
+        &self.shapes
+    }
+
+    /// Sets the index filters of the pinned queries, then executes every
+    /// registered query once, so that its plan is cached.
+    pub fn run(&self, db: &Database) -> Result<()> {
+        for shape in &self.shapes {
+            if !shape.indexes.is_empty() {
+                db.command(shape.set_filter_command(), CommandType::Suppressed, None)
+                    .chain(|| format!("can't pin plan of {:?}", shape))?;
+            }
+
+            db.command(shape.find_command(), CommandType::Suppressed, None)
+                .chain(|| format!("can't warm up plan of {:?}", shape))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the index filters set by `run()` for the pinned queries.
+    pub fn unpin(&self, db: &Database) -> Result<()> {
+        for shape in self.shapes.iter().filter(|shape| !shape.indexes.is_empty()) {
+            db.command(shape.clear_filter_command(), CommandType::Suppressed, None)
+                .chain(|| format!("can't unpin plan of {:?}", shape))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Document, oid::ObjectId };
+    use mongodb::coll::options::FindOptions;
+    use crate::{ doc::Doc, uid::Uid, ops::Query };
+    use super::Warmup;
+
+    /// A document type with critical queries.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Order {
+        /// The unique ID of the order.
+        _id: Uid<Order>,
+    }
+
+    impl Doc for Order {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Order";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self._id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self._id = id;
+        }
+    }
+
+    /// The most recent orders of a customer.
+    #[derive(Debug)]
+    struct RecentOrders;
+
+    impl Query<Order> for RecentOrders {
+        type Output = Order;
+
+        fn filter(&self) -> Document {
+            doc!{ "customer": "alice" }
+        }
+
+        fn options(&self) -> FindOptions {
+            FindOptions {
+                sort: Some(doc!{ "placed_at": -1 }),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let warmup = Warmup::new().pinned::<Order, _>(&RecentOrders, &["customer_1_placed_at_-1"]);
+        let shape = &warmup.shapes()[0];
+
+        assert_eq!(shape.set_filter_command(), doc!{
+            "planCacheSetFilter": "Order",
+            "query": { "customer": "alice" },
+            "sort": { "placed_at": -1 },
+            "indexes": ["customer_1_placed_at_-1"],
+        });
+        assert_eq!(shape.clear_filter_command(), doc!{
+            "planCacheClearFilters": "Order",
+            "query": { "customer": "alice" },
+            "sort": { "placed_at": -1 },
+        });
+        assert_eq!(shape.find_command(), doc!{
+            "find": "Order",
+            "filter": { "customer": "alice" },
+            "batchSize": 1,
+            "singleBatch": true,
+            "sort": { "placed_at": -1 },
+        });
+    }
+}
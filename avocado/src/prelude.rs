@@ -2,11 +2,17 @@
 //! and types for convenience, including ones from crates `bson` and `mongodb`.
 
 pub use crate::{
+    client::connect,
     db::DatabaseExt,
     coll::{ Collection, InsertManyErrorContext },
+    cursor::{ Cursor, Options as CursorOptions, WithCursorOptions },
     doc::Doc,
     uid::Uid,
     ops::*,
+    options::{ Extra as CommandOptions, Hint },
+    projection::Projection,
+    path::{ Path as FieldPath, Subdoc },
+    sort::Sort,
     ext::*,
     literal::{ IndexType, Order, BsonType },
     error::Error as AvocadoError,
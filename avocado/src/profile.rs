@@ -0,0 +1,419 @@
+//! Per-collection tweaks of the stored representation of documents.
+//!
+//! Domain types are often shared between a database layer and e.g. a JSON
+//! API, so the serde attributes on them can't always be chosen for the sake
+//! of MongoDB alone. A [`Profile`](struct.Profile.html), specified
+//! by `Doc::SERDE_PROFILE`, adjusts how `Collection` stores and reads the
+//! documents of a type instead:
+//!
+//! * [`NoneFields`](enum.NoneFields.html) decides whether fields with a
+//!   value of `None` are stored as `null` or left out entirely.
+//! * [`UnsignedRepr`](enum.UnsignedRepr.html) decides what happens to
+//!   unsigned integers which don't fit in an `i64`, the widest BSON integer.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::profile::{ Profile, NoneFields, UnsignedRepr };
+//! #
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct Counter {
+//!     hits: u64,
+//!     note: Option<String>,
+//! }
+//!
+//! # fn main() -> avocado::error::Result<()> {
+//! const COMPACT: Profile = Profile {
+//!     none_fields: NoneFields::Skip,
+//!     unsigned: UnsignedRepr::String,
+//! };
+//!
+//! let counter = Counter { hits: u64::max_value(), note: None };
+//! let doc = COMPACT.serialize(&counter)?;
+//!
+//! assert_eq!(doc, doc!{ "hits": "18446744073709551615" });
+//! assert_eq!(COMPACT.deserialize::<Counter>(doc.into())?, counter);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::vec;
+use serde::{
+    Serialize,
+    de::{
+        Deserialize, DeserializeOwned, DeserializeSeed, Deserializer,
+        Visitor, MapAccess, SeqAccess, EnumAccess, VariantAccess,
+        IntoDeserializer, Error as DeError,
+    },
+};
+use serde_json::Value;
+use bson::{ Bson, Document, Decoder, DecoderError, DecoderResult, from_bson };
+use crate::{
+    bsn::BsonExt,
+    error::{ Error, ErrorKind, Result },
+};
+
+/// How `Collection` maps the serialized form of a type to stored BSON.
+/// The default profile stores exactly what `serde` produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Profile {
+    /// How fields with a value of `None` are stored.
+    pub none_fields: NoneFields,
+    /// How unsigned integers exceeding `i64::max_value()` are stored.
+    pub unsigned: UnsignedRepr,
+}
+
+/// How fields serialized as `null`, e.g. `Option`s with a value of `None`,
+/// are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NoneFields {
+    /// The field is stored with a value of `null`.
+    Null,
+    /// The field is left out of the stored document. Missing `Option`
+    /// fields are deserialized as `None`, so this reads back the same, but
+    /// it saves space, and `{ field: { $exists: false } }` finds the
+    /// documents without a value. Elements of arrays are kept as `null`.
+    Skip,
+}
+
+/// How unsigned integers which can't be represented by an `i64` are stored.
+/// Smaller values are always stored as 64-bit integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnsignedRepr {
+    /// Serializing such a value is a `BsonNumberRepr` error.
+    Int64,
+    /// Such a value is stored as a string of decimal digits. When reading,
+    /// a string of digits is accepted wherever a `u64` is expected. Note
+    /// that the server doesn't compare or sort these strings numerically.
+    String,
+}
+
+impl Profile {
+    /// The profile storing exactly what `serde` produces.
+    pub const DEFAULT: Profile = Profile {
+        none_fields: NoneFields::Null,
+        unsigned: UnsignedRepr::Int64,
+    };
+
+    /// Serializes a value, which must serialize as a map, into a document
+    /// according to this profile.
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Document> {
+        serde_json::to_value(value)
+            .map_err(From::from)
+            .and_then(|json| self.convert(json))
+            .and_then(BsonExt::try_into_doc)
+    }
+
+    /// Deserializes a value stored according to this profile.
+    pub fn deserialize<T: DeserializeOwned>(self, value: Bson) -> Result<T> {
+        match self.unsigned {
+            UnsignedRepr::Int64 => from_bson(value).map_err(From::from),
+            UnsignedRepr::String => T::deserialize(Lenient(value)).map_err(From::from),
+        }
+    }
+
+    /// Converts serialized JSON into BSON, the same way `JsonExt::try_into_bson()`
+    /// does, except for the tweaks of this profile.
+    fn convert(self, value: Value) -> Result<Bson> {
+        match value {
+            Value::Number(n) => if n.is_i64() || n.is_f64() {
+                bson::to_bson(&n).map_err(Into::into)
+            } else {
+                match self.unsigned {
+                    UnsignedRepr::String => Ok(Bson::String(n.to_string())),
+                    UnsignedRepr::Int64 => Err(Error::new(
+                        ErrorKind::BsonNumberRepr,
+                        format!("Value `{}` can't be represented in BSON", n)
+                    )),
+                }
+            },
+
+            Value::Array(values) => values
+                .into_iter()
+                .map(|item| self.convert(item))
+                .collect::<Result<Vec<_>>>()
+                .map(Bson::from),
+
+            Value::Object(values) => values
+                .into_iter()
+                .filter(|&(_, ref item)| !(item.is_null() && self.none_fields == NoneFields::Skip))
+                .map(|(k, v)| self.convert(v).map(|b| (k, b)))
+                .collect::<Result<Document>>()
+                .map(Bson::from_extended_document),
+
+            other => Ok(other.into()),
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::DEFAULT
+    }
+}
+
+/// Deserializes BSON the same way `bson::Decoder` does, but also accepts
+/// `u64`s stored as strings of decimal digits, at any depth.
+struct Lenient(Bson);
+
+impl<'de> Deserializer<'de> for Lenient {
+    type Error = DecoderError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> DecoderResult<V::Value> {
+        match self.0 {
+            Bson::Document(doc) => {
+                let len = doc.len();
+                let entries: Vec<_> = doc.into_iter().collect();
+                visitor.visit_map(LenientMap { entries: entries.into_iter(), value: None, len })
+            }
+            Bson::Array(items) => {
+                let len = items.len();
+                visitor.visit_seq(LenientSeq { items: items.into_iter(), len })
+            }
+            other => Decoder::new(other).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> DecoderResult<V::Value> {
+        if let Bson::String(ref digits) = self.0 {
+            if let Ok(number) = digits.parse() {
+                return visitor.visit_u64(number);
+            }
+        }
+
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> DecoderResult<V::Value> {
+        match self.0 {
+            Bson::Null => visitor.visit_none(),
+            other => visitor.visit_some(Lenient(other)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> DecoderResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> DecoderResult<V::Value> {
+        match self.0 {
+            Bson::Document(doc) => {
+                let mut entries = doc.into_iter();
+
+                match (entries.next(), entries.next()) {
+                    (Some((variant, value)), None) => visitor.visit_enum(LenientEnum { variant, value }),
+                    _ => Err(DecoderError::custom(
+                        format!("expected a single-key document for enum `{}`", name)
+                    )),
+                }
+            }
+            other => Decoder::new(other).deserialize_enum(name, variants, visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// The fields of a document being deserialized by `Lenient`.
+struct LenientMap {
+    /// The fields not yet visited.
+    entries: vec::IntoIter<(String, Bson)>,
+    /// The value belonging to the last visited key.
+    value: Option<Bson>,
+    /// The total number of fields.
+    len: usize,
+}
+
+impl<'de> MapAccess<'de> for LenientMap {
+    type Error = DecoderError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> DecoderResult<Option<K::Value>> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> DecoderResult<V::Value> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Lenient(value)),
+            None => Err(DecoderError::custom("map value requested before its key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// The items of an array being deserialized by `Lenient`.
+struct LenientSeq {
+    /// The items not yet visited.
+    items: vec::IntoIter<Bson>,
+    /// The total number of items.
+    len: usize,
+}
+
+impl<'de> SeqAccess<'de> for LenientSeq {
+    type Error = DecoderError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> DecoderResult<Option<T::Value>> {
+        match self.items.next() {
+            Some(item) => seed.deserialize(Lenient(item)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// An enum variant with a value, stored as a single-key document.
+struct LenientEnum {
+    /// The name of the variant.
+    variant: String,
+    /// The value of the variant.
+    value: Bson,
+}
+
+impl<'de> EnumAccess<'de> for LenientEnum {
+    type Error = DecoderError;
+    type Variant = Lenient;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> DecoderResult<(V::Value, Lenient)> {
+        let name = IntoDeserializer::<DecoderError>::into_deserializer(self.variant);
+        let variant = seed.deserialize(name)?;
+        Ok((variant, Lenient(self.value)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Lenient {
+    type Error = DecoderError;
+
+    fn unit_variant(self) -> DecoderResult<()> {
+        <()>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> DecoderResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> DecoderResult<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> DecoderResult<V::Value> {
+        self.deserialize_any(visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use bson::Bson;
+    use crate::error::{ ErrorExt, ErrorKind };
+    use super::{ Profile, NoneFields, UnsignedRepr };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Quota {
+        Unlimited,
+        Limited(u64),
+        Window { max: u64, seconds: Option<u32> },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        name: String,
+        balance: u64,
+        nickname: Option<String>,
+        quotas: Vec<Quota>,
+        counters: BTreeMap<String, u64>,
+    }
+
+    fn account(balance: u64) -> Account {
+        let mut counters = BTreeMap::new();
+        counters.insert("logins".to_owned(), u64::max_value());
+
+        Account {
+            name: "12345".to_owned(),
+            balance,
+            nickname: None,
+            quotas: vec![
+                Quota::Unlimited,
+                Quota::Limited(u64::max_value()),
+                Quota::Window { max: 1 << 63, seconds: None },
+            ],
+            counters,
+        }
+    }
+
+    #[test]
+    fn default_profile() {
+        let profile = Profile::default();
+        let small = Account {
+            quotas: Vec::new(),
+            counters: BTreeMap::new(),
+            ..account(42)
+        };
+
+        assert_eq!(profile, Profile::DEFAULT);
+        assert_eq!(profile.serialize(&small).unwrap(), doc!{
+            "name": "12345",
+            "balance": 42_i64,
+            "nickname": Bson::Null,
+            "quotas": [],
+            "counters": {},
+        });
+
+        let error = profile.serialize(&account(u64::max_value())).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::BsonNumberRepr);
+    }
+
+    #[test]
+    fn skip_none_and_unsigned_strings() {
+        let profile = Profile {
+            none_fields: NoneFields::Skip,
+            unsigned: UnsignedRepr::String,
+        };
+        let original = account(u64::max_value() - 1);
+        let doc = profile.serialize(&original).unwrap();
+
+        assert_eq!(doc, doc!{
+            "name": "12345",
+            "balance": "18446744073709551614",
+            "quotas": [
+                "Unlimited",
+                { "Limited": "18446744073709551615" },
+                { "Window": { "max": "9223372036854775808" } },
+            ],
+            "counters": { "logins": "18446744073709551615" },
+        });
+
+        let loaded: Account = profile.deserialize(doc.into()).unwrap();
+        assert_eq!(loaded, original);
+    }
+}
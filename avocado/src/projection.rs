@@ -0,0 +1,340 @@
+//! Typed projections, for fetching only some of the fields of documents.
+//!
+//! A [`Projection`](struct.Projection.html) either includes or excludes
+//! fields, and it may also trim arrays (`$slice`), select a single array
+//! element (`$elemMatch`) or add the relevance of a full-text search match
+//! (`$meta: "textScore"`). A query applies it by overriding
+//! `Query::projection()`, in which case its `Output` type only needs to
+//! contain the projected fields:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::projection::Projection;
+//! # use bson::Document;
+//! #
+//! # fn main() {
+//! let projection = Projection::new()
+//!     .include("title")
+//!     .slice("comments", -5)
+//!     .elem_match("votes", doc!{ "user": "alice" })
+//!     .text_score("score");
+//!
+//! assert_eq!(Document::from(projection), doc!{
+//!     "title": 1,
+//!     "comments": { "$slice": -5_i64 },
+//!     "votes": { "$elemMatch": { "user": "alice" } },
+//!     "score": { "$meta": "textScore" },
+//! });
+//! # }
+//! ```
+//!
+//! Inclusions and exclusions can't be mixed, except for excluding `_id`,
+//! which is otherwise always returned. MongoDB rejects other mixtures.
+//!
+//! `Projection::of::<V>()` includes exactly the fields of a struct `V`, as
+//! named by its `Deserialize` impl, and `Collection::find_as::<V, _>()`
+//! applies it to a query, so that e.g. a listing reads lightweight view
+//! structs instead of entire documents.
+
+use std::result;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use serde::de::{ DeserializeOwned, Deserializer, Visitor, Error as DeError };
+use serde::de::value::Error as ValueError;
+use bson::{ Bson, Document };
+use mongodb::coll::options::FindOptions;
+use crate::{
+    doc::Doc,
+    ops::Query,
+    sort::Sort,
+    options,
+    error::Result,
+};
+
+/// A projection of the fields of the documents returned by a query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Projection {
+    /// The projected fields and their specifications.
+    fields: Document,
+}
+
+impl Projection {
+    /// Returns an empty projection, which returns documents in their
+    /// entirety.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a projection including exactly the `fields` (and `_id`).
+    pub fn including<I, S>(fields: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: Into<String>,
+    {
+        fields.into_iter().fold(Self::new(), Projection::include)
+    }
+
+    /// Returns a projection including exactly the fields of the struct `V`,
+    /// as named by its `Deserialize` impl, so Serde renames are honored.
+    /// `_id` is excluded unless `V` has a field of that name. Returns an
+    /// empty projection, i.e. entire documents, if `V` isn't deserialized
+    /// from a fixed set of fields, e.g. if it's a map, or a struct with a
+    /// `#[serde(flatten)]` field.
+    pub fn of<V: DeserializeOwned>() -> Self {
+        let mut fields: &'static [&'static str] = &[];
+
+        // The error only signals that the field names have been collected.
+        let _ = V::deserialize(FieldCollector { fields: &mut fields });
+
+        if fields.is_empty() {
+            return Self::new();
+        }
+
+        let projection = Self::including(fields.iter().cloned());
+
+        if fields.contains(&"_id") {
+            projection
+        } else {
+            projection.exclude("_id")
+        }
+    }
+
+    /// Returns a projection excluding the `fields`.
+    pub fn excluding<I, S>(fields: I) -> Self
+        where I: IntoIterator<Item = S>,
+              S: Into<String>,
+    {
+        fields.into_iter().fold(Self::new(), Projection::exclude)
+    }
+
+    /// Includes the field.
+    pub fn include<S: Into<String>>(self, field: S) -> Self {
+        self.with(field, 1)
+    }
+
+    /// Excludes the field.
+    pub fn exclude<S: Into<String>>(self, field: S) -> Self {
+        self.with(field, 0)
+    }
+
+    /// Returns only the first `count` elements of the array field, or the
+    /// last ones if `count` is negative.
+    pub fn slice<S: Into<String>>(self, field: S, count: i64) -> Self {
+        self.with(field, doc!{ "$slice": count })
+    }
+
+    /// Returns at most `limit` elements of the array field, after skipping
+    /// `skip` elements. A negative `skip` counts from the end of the array.
+    pub fn slice_range<S: Into<String>>(self, field: S, skip: i64, limit: i64) -> Self {
+        self.with(field, doc!{ "$slice": [skip, limit] })
+    }
+
+    /// Returns only the first element of the array field which matches
+    /// `condition`, or omits the field if there is none.
+    pub fn elem_match<S: Into<String>>(self, field: S, condition: Document) -> Self {
+        self.with(field, doc!{ "$elemMatch": condition })
+    }
+
+    /// Adds the relevance score of a `$text` query as the field.
+    pub fn text_score<S: Into<String>>(self, field: S) -> Self {
+        self.with(field, doc!{ "$meta": "textScore" })
+    }
+
+    /// Adds a field with an arbitrary projection specification. If the
+    /// field is already projected, the last specification wins.
+    pub fn with<S: Into<String>, B: Into<Bson>>(mut self, field: S, spec: B) -> Self {
+        self.fields.insert(field.into(), spec.into());
+        self
+    }
+
+    /// Returns `true` if no fields are projected, i.e. documents are
+    /// returned in their entirety.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the projection document.
+    pub fn fields(&self) -> &Document {
+        &self.fields
+    }
+}
+
+impl From<Document> for Projection {
+    /// Uses the projection document verbatim.
+    fn from(fields: Document) -> Self {
+        Projection { fields }
+    }
+}
+
+impl From<Projection> for Document {
+    fn from(projection: Projection) -> Self {
+        projection.fields
+    }
+}
+
+impl From<Projection> for Bson {
+    fn from(projection: Projection) -> Self {
+        Bson::Document(projection.fields)
+    }
+}
+
+/// A `Deserializer` which collects the names of the fields of a struct,
+/// then bails out without producing a value.
+struct FieldCollector<'a> {
+    /// Where the field names are stored.
+    fields: &'a mut &'static [&'static str],
+}
+
+impl<'a, 'de> Deserializer<'de> for FieldCollector<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> result::Result<V::Value, Self::Error> {
+        Err(ValueError::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> result::Result<V::Value, Self::Error> {
+        *self.fields = fields;
+        Err(ValueError::custom("field names collected"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Wraps a query so that its results are deserialized as the view type `V`,
+/// and only the fields of `V` are fetched; see `Projection::of()`.
+#[derive(Debug)]
+pub struct AsView<V, Q> {
+    /// The wrapped query.
+    query: Q,
+    /// Marks the type of the results.
+    marker: PhantomData<V>,
+}
+
+impl<V, Q> AsView<V, Q> {
+    /// Reads the results of `query` as `V`.
+    pub fn new(query: Q) -> Self {
+        AsView { query, marker: PhantomData }
+    }
+
+    /// Returns the wrapped query.
+    pub fn into_inner(self) -> Q {
+        self.query
+    }
+}
+
+impl<T, V, Q> Query<T> for AsView<V, Q>
+    where T: Doc,
+          V: DeserializeOwned + Debug,
+          Q: Query<T>,
+{
+    type Output = V;
+
+    fn filter(&self) -> Document {
+        self.query.filter()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.query.options()
+    }
+
+    /// The projection of the wrapped query if it has one, otherwise the
+    /// fields of `V`.
+    fn projection(&self) -> Projection {
+        let projection = self.query.projection();
+
+        if projection.is_empty() {
+            Projection::of::<V>()
+        } else {
+            projection
+        }
+    }
+
+    fn sort(&self) -> Sort {
+        self.query.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.query.command_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bson::Document;
+    use super::Projection;
+
+    #[test]
+    fn include_exclude() {
+        assert_eq!(Document::from(Projection::including(vec!["a", "b.c"])), doc!{
+            "a": 1,
+            "b.c": 1,
+        });
+        assert_eq!(Document::from(Projection::excluding(vec!["secret"])), doc!{
+            "secret": 0,
+        });
+        assert_eq!(Document::from(Projection::including(vec!["name"]).exclude("_id")), doc!{
+            "name": 1,
+            "_id": 0,
+        });
+        assert!(Projection::new().is_empty());
+    }
+
+    #[test]
+    fn array_operators() {
+        let projection = Projection::new()
+            .slice_range("log", -10, 5)
+            .elem_match("items", doc!{ "qty": { "$gt": 3 } });
+
+        assert_eq!(projection.fields(), &doc!{
+            "log": { "$slice": [-10_i64, 5_i64] },
+            "items": { "$elemMatch": { "qty": { "$gt": 3 } } },
+        });
+    }
+
+    #[test]
+    fn of_view_struct() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Summary {
+            title: String,
+            #[serde(rename = "authorName")]
+            author: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct WithId {
+            #[serde(rename = "_id")]
+            id: String,
+            title: String,
+        }
+
+        assert_eq!(Document::from(Projection::of::<Summary>()), doc!{
+            "title": 1,
+            "authorName": 1,
+            "_id": 0,
+        });
+        assert_eq!(Document::from(Projection::of::<WithId>()), doc!{
+            "_id": 1,
+            "title": 1,
+        });
+        assert!(Projection::of::<HashMap<String, String>>().is_empty());
+        assert!(Projection::of::<Document>().is_empty());
+    }
+}
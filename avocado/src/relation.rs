@@ -0,0 +1,207 @@
+//! Typed references between documents.
+//!
+//! A [`Ref<T>`](struct.Ref.html) field states that it refers to a document
+//! of type `T`, instead of being a bare `Uid<T>` whose meaning is up to the
+//! reader. It is stored as the `_id` of the referenced document, so it is
+//! compatible with existing data and with indexes and queries on the field.
+//! For interoperability with other drivers, a `DBRef` document of the form
+//! `{ $ref: <collection>, $id: <ID> }` is accepted when deserializing, too.
+//!
+//! The referenced document is only loaded when asked for, either one at a
+//! time using [`Ref::fetch()`](struct.Ref.html#method.fetch), or in bulk
+//! using [`Collection::populate()`](../coll/struct.Collection.html#method.populate),
+//! which resolves any number of references using a single `$in` query.
+//! References to documents in another database are modelled by
+//! [`XRef`](../xref/struct.XRef.html) instead.
+
+use std::result;
+use std::hash::{ Hash, Hasher };
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use serde::{
+    ser::{ Serialize, Serializer },
+    de::{ Deserialize, Deserializer, Error as DeError },
+};
+use mongodb::db::Database;
+use crate::{
+    db::DatabaseExt,
+    doc::Doc,
+    uid::Uid,
+    error::{ Result, ResultExt },
+};
+
+#[cfg(feature = "schema_validation")]
+use magnet_schema::BsonSchema;
+
+/// A reference to a document of type `T` in the same database.
+pub struct Ref<T: Doc>(Uid<T>);
+
+impl<T: Doc> Ref<T> {
+    /// Creates a reference to the document with the given ID.
+    pub fn new(id: Uid<T>) -> Self {
+        Ref(id)
+    }
+
+    /// Returns the ID of the referenced document.
+    pub fn id(&self) -> &Uid<T> {
+        &self.0
+    }
+
+    /// Converts the reference into the ID of the referenced document.
+    pub fn into_id(self) -> Uid<T> {
+        self.0
+    }
+
+    /// Loads the referenced document from `db`. Returns `Ok(None)` if the
+    /// reference is dangling.
+    pub fn fetch(&self, db: &Database) -> Result<Option<T>> {
+        let filter = doc!{ "_id": bson::to_bson(&self.0)? };
+
+        db.existing_collection::<T>()
+            .find_one(filter)
+            .chain(|| format!("can't fetch referenced {}", T::NAME))
+    }
+}
+
+impl<T: Doc> From<Uid<T>> for Ref<T> {
+    fn from(id: Uid<T>) -> Self {
+        Ref(id)
+    }
+}
+
+impl<T: Doc> From<Ref<T>> for Uid<T> {
+    fn from(reference: Ref<T>) -> Self {
+        reference.0
+    }
+}
+
+// The following traits are implemented manually in order to relax trait
+// bounds, just like for `Uid<T>`.
+
+impl<T: Doc> Clone for Ref<T> where T::Id: Clone {
+    fn clone(&self) -> Self {
+        Ref(self.0.clone())
+    }
+}
+
+impl<T: Doc> PartialEq for Ref<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T: Doc> Eq for Ref<T> {}
+
+impl<T: Doc> Hash for Ref<T> where T::Id: Hash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<T: Doc> Debug for Ref<T> where T::Id: Debug {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_tuple(&format!("Ref<{}>", T::NAME))
+            .field(self.0.as_ref())
+            .finish()
+    }
+}
+
+impl<T: Doc> Serialize for Ref<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// The serialized forms accepted for a `Ref`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRef<I> {
+    /// A `DBRef` document.
+    DbRef {
+        /// The name of the collection containing the referenced document.
+        #[serde(rename = "$ref")]
+        collection: String,
+        /// The ID of the referenced document.
+        #[serde(rename = "$id")]
+        id: I,
+    },
+    /// The bare ID of the referenced document.
+    Id(I),
+}
+
+impl<'a, T: Doc> Deserialize<'a> for Ref<T> {
+    fn deserialize<D: Deserializer<'a>>(deserializer: D) -> result::Result<Self, D::Error> {
+        match RawRef::<Uid<T>>::deserialize(deserializer)? {
+            RawRef::Id(id) => Ok(Ref(id)),
+            RawRef::DbRef { collection, id } => if collection == T::NAME {
+                Ok(Ref(id))
+            } else {
+                Err(D::Error::custom(format!(
+                    "reference to collection `{}` where `{}` was expected",
+                    collection, T::NAME
+                )))
+            },
+        }
+    }
+}
+
+#[cfg(feature = "schema_validation")]
+impl<T: Doc> BsonSchema for Ref<T> where T::Id: BsonSchema {
+    fn bson_schema() -> bson::Document {
+        T::Id::bson_schema()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId };
+    use bson::{ from_bson, to_bson };
+    use crate::{ doc::Doc, uid::Uid };
+    use super::Ref;
+
+    /// A referenced document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Author {
+        /// The unique ID of the author.
+        #[serde(rename = "_id")]
+        id: Uid<Author>,
+    }
+
+    impl Doc for Author {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Author";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn serialize_as_id() {
+        let reference = Ref::new(Uid::<Author>::from_oid_bytes([3; 12]));
+        let serialized = to_bson(&reference).unwrap();
+
+        assert_eq!(serialized, Bson::ObjectId(ObjectId::with_bytes([3; 12])));
+        assert_eq!(from_bson::<Ref<Author>>(serialized).unwrap(), reference);
+    }
+
+    #[test]
+    fn deserialize_dbref() {
+        let reference = Ref::new(Uid::<Author>::from_oid_bytes([3; 12]));
+        let dbref = Bson::from(doc!{
+            "$ref": "Author",
+            "$id": ObjectId::with_bytes([3; 12]),
+        });
+        let other = Bson::from(doc!{
+            "$ref": "Book",
+            "$id": ObjectId::with_bytes([3; 12]),
+        });
+
+        assert_eq!(from_bson::<Ref<Author>>(dbref).unwrap(), reference);
+        assert!(from_bson::<Ref<Author>>(other).is_err());
+    }
+}
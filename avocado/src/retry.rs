@@ -0,0 +1,319 @@
+//! Retrying writes which fail because of transient errors.
+//!
+//! Network errors and primary elections make writes fail even though
+//! repeating them a moment later would succeed. A
+//! [`Policy`](struct.Policy.html) decides which errors are
+//! transient, and repeats an operation with an exponentially growing delay
+//! until it succeeds, fails with a non-transient error, or runs out of
+//! attempts. A [`RetryingCollection`](struct.RetryingCollection.html),
+//! obtained by `Collection::retrying()`, applies a policy to every write.
+//!
+//! An error is considered transient if it is a network error, if no server
+//! could be selected, or if the server reported one of the error codes in
+//! `Policy::retryable_codes`, e.g. `10107` (`NotMaster`). These default
+//! to `error::TRANSIENT_ERROR_CODES`.
+//!
+//! **The server doesn't deduplicate retried writes**, so a write which was
+//! applied, but whose acknowledgement was lost, is applied again. This is
+//! harmless for idempotent writes, e.g. replacements, `$set` updates and
+//! deletions by ID, and for inserts of documents with client-generated IDs,
+//! which fail with a duplicate key error. It is not harmless for e.g. `$inc`
+//! or `$push` updates; those are better retried using a
+//! [`Transaction`](../transaction/struct.Transaction.html).
+//!
+//! ```
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use avocado::retry::Policy;
+//! # use avocado::error::ServerInfo;
+//! # use avocado::prelude::*;
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! let policy = Policy {
+//!     max_attempts: 3,
+//!     initial_backoff: Duration::from_millis(1),
+//!     ..Policy::default()
+//! };
+//! let mut attempts = 0;
+//!
+//! // `coll.update_one(...)` or any other fallible operation goes here
+//! let result = policy.call(|| {
+//!     attempts += 1;
+//!
+//!     if attempts < 3 {
+//!         Err(AvocadoError::new(AvocadoErrorKind::MongoDbError, "election in progress")
+//!             .with_context::<ServerInfo>(ServerInfo {
+//!                 code: Some(10107),
+//!                 ..ServerInfo::default()
+//!             }))
+//!     } else {
+//!         Ok(attempts)
+//!     }
+//! })?;
+//!
+//! assert_eq!(result, 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::thread;
+use std::collections::{ BTreeMap, BTreeSet };
+use std::fmt::Debug;
+use std::time::Duration;
+use crate::{
+    coll::{
+        Collection, Upserted,
+        UpdateOneResult, UpdateManyResult, UpsertOneResult, UpsertManyResult,
+    },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    error::{ Error, Result, TRANSIENT_ERROR_CODES },
+};
+
+/// Decides which failed operations are repeated, how many times, and how
+/// long to wait between attempts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    /// The maximal number of times an operation is executed, including the
+    /// first attempt. `0` and `1` both mean that it is never retried.
+    pub max_attempts: usize,
+    /// The delay before the first retry. It is doubled for each further
+    /// retry.
+    pub initial_backoff: Duration,
+    /// The upper bound of the delay between two attempts.
+    pub max_backoff: Duration,
+    /// The server error codes after which an operation is retried.
+    pub retryable_codes: BTreeSet<i32>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retryable_codes: TRANSIENT_ERROR_CODES.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Policy {
+    /// Executes `op`, and executes it again after a delay as long as it
+    /// fails with a transient error and the attempts aren't exhausted.
+    /// Returns the result of the last attempt.
+    pub fn call<F, R>(&self, mut op: F) -> Result<R>
+        where F: FnMut() -> Result<R>
+    {
+        let mut attempt = 1;
+
+        loop {
+            match op() {
+                Err(ref error) if attempt < self.max_attempts && self.is_retryable(error) => {
+                    thread::sleep(self.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns `true` if `error` signals a transient condition according to
+    /// this policy, i.e. if it is a network error, or any of its causes was
+    /// reported by the server with one of `retryable_codes`.
+    pub fn is_retryable(&self, error: &Error) -> bool {
+        error.is_network_error() || error.server_error().map_or(
+            false, |e| e.codes().any(|code| self.retryable_codes.contains(&code))
+        )
+    }
+
+    /// Returns the delay after the given (1-based) failed attempt.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let mut backoff = self.initial_backoff;
+
+        for _ in 1..attempt {
+            if backoff >= self.max_backoff {
+                break;
+            }
+            backoff *= 2;
+        }
+
+        backoff.min(self.max_backoff)
+    }
+}
+
+/// A view of a typed collection whose writes are retried according to a
+/// policy. Reads are available through the underlying collection.
+#[derive(Debug)]
+pub struct RetryingCollection<'a, T: Doc> {
+    /// The underlying collection.
+    collection: &'a Collection<T>,
+    /// Decides which failed writes are retried.
+    policy: Policy,
+}
+
+impl<'a, T: Doc> RetryingCollection<'a, T> {
+    /// Retries the writes to `collection` according to `policy`.
+    /// This is usually called through `Collection::retrying()`.
+    pub fn new(collection: &'a Collection<T>, policy: Policy) -> Self {
+        RetryingCollection { collection, policy }
+    }
+
+    /// Returns the underlying collection, which doesn't retry.
+    pub fn collection(&self) -> &'a Collection<T> {
+        self.collection
+    }
+
+    /// Returns the retry policy.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Inserts a single document, retrying on transient errors.
+    pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
+        self.policy.call(|| self.collection.insert_one(entity))
+    }
+
+    /// Inserts many documents, retrying on transient errors.
+    pub fn insert_many(&self, entities: &[T]) -> Result<BTreeMap<u64, Uid<T>>>
+        where T::Id: Clone + Debug,
+              T: 'static,
+    {
+        self.policy.call(|| self.collection.insert_many(entities))
+    }
+
+    /// Replaces an entity by ID, retrying on transient errors.
+    pub fn replace_entity(&self, entity: &T) -> Result<UpdateOneResult> where T: Debug {
+        self.policy.call(|| self.collection.replace_entity(entity))
+    }
+
+    /// Replaces or inserts an entity by ID, retrying on transient errors.
+    pub fn upsert_entity(&self, entity: &T) -> Result<UpsertOneResult<Uid<T>>> where T: Debug {
+        self.policy.call(|| self.collection.upsert_entity(entity))
+    }
+
+    /// Updates a single document, retrying on transient errors.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        self.policy.call(|| self.collection.update_one(&update))
+    }
+
+    /// Updates all matching documents, retrying on transient errors.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        self.policy.call(|| self.collection.update_many(&update))
+    }
+
+    /// Upserts a single document, retrying on transient errors.
+    pub fn upsert_one<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertOneResult<Uid<T>>> {
+        self.policy.call(|| self.collection.upsert_one(&upsert))
+    }
+
+    /// Upserts a single document and fetches it, retrying on transient
+    /// errors.
+    pub fn upsert_and_fetch<U: Upsert<T>>(&self, upsert: U) -> Result<Upserted<T>> {
+        self.policy.call(|| self.collection.upsert_and_fetch(&upsert))
+    }
+
+    /// Upserts all matching documents, retrying on transient errors.
+    pub fn upsert_many<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertManyResult> {
+        self.policy.call(|| self.collection.upsert_many(&upsert))
+    }
+
+    /// Deletes an entity by ID, retrying on transient errors.
+    pub fn delete_entity(&self, entity: &T) -> Result<bool> where T: Debug {
+        self.policy.call(|| self.collection.delete_entity(entity))
+    }
+
+    /// Deletes a single document, retrying on transient errors.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        self.policy.call(|| self.collection.delete_one(&query))
+    }
+
+    /// Deletes all matching documents, retrying on transient errors.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        self.policy.call(|| self.collection.delete_many(&query))
+    }
+
+    /// Finds a single document and updates it, retrying on transient errors.
+    pub fn find_one_and_update<U: FindAndUpdate<T>>(&self, update: U) -> Result<Option<U::Output>> {
+        self.policy.call(|| self.collection.find_one_and_update(&update))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+    use crate::error::{ Error, ErrorKind, ServerInfo, Result, ResultExt };
+    use super::Policy;
+
+    /// A policy which doesn't wait between attempts.
+    fn policy(max_attempts: usize) -> Policy {
+        Policy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(0),
+            ..Policy::default()
+        }
+    }
+
+    /// An error reported by the server with the given code.
+    fn server_error(code: i32) -> Error {
+        Error::new(ErrorKind::MongoDbError, "write failed")
+            .with_context::<ServerInfo>(ServerInfo {
+                code: Some(code),
+                ..ServerInfo::default()
+            })
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let attempts = Cell::new(0);
+        let result = policy(3).call(|| -> Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(server_error(10107)).chain("update of User failed")
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let result = policy(3).call(|| {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() == 1 { Err(server_error(189)) } else { Ok(attempts.get()) }
+        });
+
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let attempts = Cell::new(0);
+        let result = policy(5).call(|| -> Result<()> {
+            attempts.set(attempts.get() + 1);
+            Err(server_error(11000))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        assert!(!policy(5).is_retryable(&Error::new(ErrorKind::BsonDecoding, "bad document")));
+        assert!(policy(5).is_retryable(&Error::from(std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset
+        ))));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = Policy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..Policy::default()
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+}
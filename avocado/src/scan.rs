@@ -0,0 +1,602 @@
+//! Long collection scans which survive lost cursors and failovers.
+//!
+//! A cursor can't outlive the server that created it: if the primary steps
+//! down, or the cursor times out on the server (`CursorNotFound`), a
+//! multi-hour export would otherwise have to start over. A
+//! [`Resumable`](struct.Resumable.html) scan instead re-issues the query
+//! after such an error, restricted to the documents with an `_id` greater
+//! than that of the last document it yielded. For this to work, the scan is
+//! always sorted by ascending `_id`, replacing any sort order of the query.
+//!
+//! Documents inserted or modified during the scan may or may not be seen,
+//! just like with a plain cursor; however, no document is yielded twice.
+//!
+//! A [`Paged`](struct.Paged.html) scan, for maintenance jobs which must
+//! touch every document of a collection, doesn't keep a cursor open at all:
+//! it reads the collection in pages of a fixed number of documents, each
+//! one requested by a separate query for the next range of `_id`s. Thus, at
+//! most one page is held in memory, and the scan can be continued after
+//! the last processed `_id` by a new process.
+//!
+//! `Collection::par_scan()` splits a full-collection scan into disjoint
+//! [`Partition`](struct.Partition.html)s, i.e. ranges of `_id`s of
+//! roughly equal size, estimated from a random sample of the `_id`s, and
+//! returns an independent cursor for each of them. The cursors can then be
+//! consumed on separate threads, e.g. by a reindexing or an export job.
+
+use std::thread;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use bson::{ Bson, Document, from_bson };
+use mongodb::coll::options::FindOptions;
+use crate::{
+    coll::Collection,
+    cursor::Cursor,
+    doc::Doc,
+    ops::Query,
+    options,
+    projection::Projection,
+    error::{ Error, ErrorExt, ErrorKind::{ MissingId, MongoDbError }, Result },
+};
+
+/// Iterates over the results of a query sorted by `_id`, transparently
+/// re-issuing the query where it left off if the cursor is lost.
+pub struct Resumable<'a, T: Doc, Q: Query<T>> {
+    /// The scanned collection.
+    collection: &'a Collection<T>,
+    /// The original query.
+    query: Q,
+    /// The current cursor, if the query has been issued.
+    cursor: Option<Cursor<Document>>,
+    /// The `_id` of the last yielded document.
+    last_seen: Option<Bson>,
+    /// The number of yielded documents.
+    num_yielded: usize,
+    /// The maximal number of consecutive failed attempts.
+    max_retries: u32,
+    /// The delay before the first attempt to re-issue the query.
+    retry_delay: Duration,
+    /// The number of consecutive failed attempts so far.
+    failures: u32,
+    /// The total number of times the query was re-issued.
+    restarts: u32,
+    /// Whether the scan is exhausted or failed permanently.
+    done: bool,
+}
+
+impl<'a, T: Doc, Q: Query<T>> Resumable<'a, T, Q> {
+    /// Creates a scan over the results of `query`, with default settings:
+    /// at most 5 consecutive retries, the first one after 1 second, with
+    /// the delay doubling for each subsequent one. The query isn't issued
+    /// until the first item is requested.
+    ///
+    /// This is usually called through `Collection::find_many_resumable()`.
+    pub fn new(collection: &'a Collection<T>, query: Q) -> Self {
+        Resumable {
+            collection,
+            query,
+            cursor: None,
+            last_seen: None,
+            num_yielded: 0,
+            max_retries: 5,
+            retry_delay: Duration::from_secs(1),
+            failures: 0,
+            restarts: 0,
+            done: false,
+        }
+    }
+
+    /// Sets the maximal number of consecutive failed attempts after which
+    /// the error is returned instead of re-issuing the query.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the delay before the first attempt to re-issue the query.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Returns the `_id` of the last yielded document. It can be used
+    /// to continue the scan in a new process, e.g. after a crash.
+    pub fn last_seen(&self) -> Option<&Bson> {
+        self.last_seen.as_ref()
+    }
+
+    /// Returns the number of times the query was re-issued.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Returns `true` if the query has a limit and as many documents have
+    /// already been yielded, so re-issuing the query is unnecessary.
+    #[allow(clippy::cast_possible_wrap)]
+    fn limit_reached(&self) -> bool {
+        match self.query.options().limit {
+            Some(limit) if limit > 0 => self.num_yielded as i64 >= limit,
+            _ => false,
+        }
+    }
+
+    /// Records the `_id` of a raw document and deserializes it.
+    fn accept(&mut self, mut raw: Document) -> Result<Q::Output> {
+        let id = raw.remove("_id").ok_or_else(|| Error::new(
+            MissingId,
+            format!("{} document without `_id` in resumable scan", T::NAME)
+        ))?;
+
+        // `_id` is put back so that the output can contain it.
+        raw.insert("_id", id.clone());
+        self.last_seen = Some(id);
+        self.num_yielded += 1;
+
+        Q::transform(raw).and_then(|bson| from_bson(bson).map_err(From::from))
+    }
+
+    /// Decides whether to re-issue the query after an error. Returns the
+    /// error back if it isn't caused by the server or the connection, or if
+    /// there were too many consecutive failed attempts.
+    fn retry(&mut self, error: Error) -> Option<Error> {
+        if error.kind() != MongoDbError || self.failures >= self.max_retries {
+            self.done = true;
+            return Some(error);
+        }
+
+        thread::sleep(self.retry_delay * 2_u32.pow(self.failures.min(16)));
+        self.failures += 1;
+        self.restarts += 1;
+        self.cursor = None;
+
+        None
+    }
+}
+
+impl<'a, T: Doc, Q: Query<T>> Iterator for Resumable<'a, T, Q> {
+    type Item = Result<Q::Output>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.cursor.is_none() {
+                if self.limit_reached() {
+                    self.done = true;
+                    break;
+                }
+
+                let continuation = Continuation {
+                    query: &self.query,
+                    after: self.last_seen.as_ref(),
+                    num_yielded: self.num_yielded,
+                };
+
+                match self.collection.find_many(continuation) {
+                    Ok(cursor) => self.cursor = Some(cursor),
+                    Err(error) => match self.retry(error) {
+                        Some(fatal) => return Some(Err(fatal)),
+                        None => continue,
+                    },
+                }
+            }
+
+            match self.cursor.as_mut().and_then(Iterator::next) {
+                Some(Ok(raw)) => {
+                    self.failures = 0;
+                    return Some(self.accept(raw));
+                }
+                Some(Err(error)) => if let Some(fatal) = self.retry(error) {
+                    return Some(Err(fatal));
+                },
+                None => self.done = true,
+            }
+        }
+
+        None
+    }
+}
+
+impl<'a, T: Doc, Q: Query<T>> Debug for Resumable<'a, T, Q> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Resumable")
+            .field("collection", &self.collection)
+            .field("query", &self.query)
+            .field("last_seen", &self.last_seen)
+            .field("num_yielded", &self.num_yielded)
+            .field("max_retries", &self.max_retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("restarts", &self.restarts)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+/// Iterates over all documents of a collection in ascending order of `_id`,
+/// one page at a time. Usually created by `Collection::iter_all_sorted_by_id()`.
+pub struct Paged<'a, T: Doc> {
+    /// The scanned collection.
+    collection: &'a Collection<T>,
+    /// The maximal number of documents requested by a single query.
+    page_size: usize,
+    /// The not yet yielded documents of the current page.
+    page: VecDeque<Document>,
+    /// The `_id` of the last document of the last requested page.
+    page_end: Option<Bson>,
+    /// The `_id` of the last yielded document.
+    last_seen: Option<Bson>,
+    /// Whether the last page has been requested.
+    done: bool,
+}
+
+impl<'a, T: Doc> Paged<'a, T> {
+    /// Creates a scan over the whole collection, requesting at most
+    /// `page_size` documents at once. A page size of 0 is treated as 1.
+    pub fn new(collection: &'a Collection<T>, page_size: usize) -> Self {
+        Paged {
+            collection,
+            page_size: page_size.max(1),
+            page: VecDeque::new(),
+            page_end: None,
+            last_seen: None,
+            done: false,
+        }
+    }
+
+    /// Continues a previous scan: only documents with an `_id` greater than
+    /// `id` are visited.
+    pub fn resume_after(mut self, id: Bson) -> Self {
+        self.page_end = Some(id.clone());
+        self.last_seen = Some(id);
+        self
+    }
+
+    /// Returns the `_id` of the last yielded document. It can be persisted
+    /// and passed to `resume_after()` to continue the scan later.
+    pub fn last_seen(&self) -> Option<&Bson> {
+        self.last_seen.as_ref()
+    }
+
+    /// Requests the next page of documents.
+    fn fetch_page(&mut self) -> Result<()> {
+        let page = Page {
+            after: self.page_end.clone(),
+            page_size: self.page_size,
+        };
+        let docs: Vec<Document> = self.collection.find_many(page)?.collect::<Result<_>>()?;
+
+        if docs.len() < self.page_size {
+            self.done = true;
+        }
+        if let Some(id) = docs.last().and_then(|doc| doc.get("_id")) {
+            self.page_end = Some(id.clone());
+        }
+
+        self.page = docs.into();
+        Ok(())
+    }
+}
+
+impl<'a, T: Doc> Iterator for Paged<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(error) = self.fetch_page() {
+                self.done = true;
+                return Some(Err(error));
+            }
+        }
+
+        self.page.pop_front().map(|raw| {
+            self.last_seen = raw.get("_id").cloned();
+            from_bson(raw.into()).map_err(From::from)
+        })
+    }
+}
+
+impl<'a, T: Doc> Debug for Paged<'a, T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Paged")
+            .field("collection", &self.collection)
+            .field("page_size", &self.page_size)
+            .field("buffered", &self.page.len())
+            .field("page_end", &self.page_end)
+            .field("last_seen", &self.last_seen)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+/// The number of sampled `_id`s per partition when partitioning a scan.
+/// More samples make the sizes of the partitions more even.
+pub(crate) const SAMPLES_PER_PARTITION: usize = 32;
+
+/// A range of `_id`s, one of the disjoint parts of a partitioned scan of a
+/// collection; see `Collection::scan_partitions()`. As a query, it returns
+/// the documents in the range, sorted by `_id`.
+///
+/// The lower bound is inclusive and the upper bound is exclusive. Since
+/// MongoDB only compares values of the same BSON type, the partition without
+/// a lower bound also contains every document whose `_id` is of a different
+/// type than its upper bound, so that the partitions always cover the whole
+/// collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Partition {
+    /// The smallest `_id` in the range, or `None` if it's the first range.
+    pub min: Option<Bson>,
+    /// The first `_id` after the range, or `None` if it's the last range.
+    pub max: Option<Bson>,
+}
+
+impl Partition {
+    /// Splits the whole range of `_id`s at the given boundaries, which must
+    /// be distinct and sorted in ascending order. Returns one more partition
+    /// than the number of boundaries.
+    pub fn split_at(boundaries: Vec<Bson>) -> Vec<Partition> {
+        let mut partitions = Vec::with_capacity(boundaries.len() + 1);
+        let mut min = None;
+
+        for boundary in boundaries {
+            partitions.push(Partition { min, max: Some(boundary.clone()) });
+            min = Some(boundary);
+        }
+
+        partitions.push(Partition { min, max: None });
+        partitions
+    }
+}
+
+impl<T: Doc> Query<T> for Partition {
+    type Output = T;
+
+    fn filter(&self) -> Document {
+        match (&self.min, &self.max) {
+            (None, None) => Document::new(),
+            (None, Some(max)) => doc!{ "_id": { "$not": { "$gte": max.clone() } } },
+            (Some(min), None) => doc!{ "_id": { "$gte": min.clone() } },
+            (Some(min), Some(max)) => doc!{ "_id": { "$gte": min.clone(), "$lt": max.clone() } },
+        }
+    }
+
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            sort: Some(doc!{ "_id": 1 }),
+            ..T::query_options()
+        }
+    }
+}
+
+/// Chooses the boundaries of at most `n_partitions` partitions from a
+/// sample of `_id`s sorted in ascending order, so that about the same
+/// number of sampled `_id`s falls into each partition.
+pub(crate) fn partitions_from_sample(sample: &[Bson], n_partitions: usize) -> Vec<Partition> {
+    let mut boundaries: Vec<Bson> = Vec::new();
+
+    for i in 1..n_partitions {
+        let boundary = match sample.get(i * sample.len() / n_partitions) {
+            Some(boundary) => boundary,
+            None => break,
+        };
+
+        if boundaries.last() != Some(boundary) && sample.first() != Some(boundary) {
+            boundaries.push(boundary.clone());
+        }
+    }
+
+    Partition::split_at(boundaries)
+}
+
+/// The query requesting a single page of a `Paged` scan.
+#[derive(Debug, Clone)]
+struct Page {
+    /// The `_id` of the last document of the previous page, if any.
+    after: Option<Bson>,
+    /// The maximal number of documents in the page.
+    page_size: usize,
+}
+
+impl<T: Doc> Query<T> for Page {
+    /// Raw documents, so that the scan can look at their `_id`.
+    type Output = Document;
+
+    fn filter(&self) -> Document {
+        match self.after {
+            Some(ref id) => doc!{ "_id": { "$gt": id.clone() } },
+            None => Document::new(),
+        }
+    }
+
+    #[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            sort: Some(doc!{ "_id": 1 }),
+            limit: Some(self.page_size as i64),
+            batch_size: Some(self.page_size.min(i32::max_value() as usize) as i32),
+            ..T::query_options()
+        }
+    }
+}
+
+/// The query issued (or re-issued) by a resumable scan: the original query,
+/// sorted by `_id`, continuing after the last yielded document.
+#[derive(Debug)]
+struct Continuation<'q, Q> {
+    /// The original query.
+    query: &'q Q,
+    /// The `_id` of the last yielded document, if any.
+    after: Option<&'q Bson>,
+    /// The number of already yielded documents.
+    num_yielded: usize,
+}
+
+impl<'q, T: Doc, Q: Query<T>> Query<T> for Continuation<'q, Q> {
+    /// Raw documents, so that the scan can look at their `_id`.
+    type Output = Document;
+
+    fn filter(&self) -> Document {
+        let filter = self.query.filter();
+
+        match self.after {
+            None => filter,
+            Some(id) => {
+                let after = doc!{ "_id": { "$gt": id.clone() } };
+
+                if filter.is_empty() {
+                    after
+                } else {
+                    doc!{ "$and": [filter, after] }
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn options(&self) -> FindOptions {
+        let mut options = self.query.options();
+        options.sort = Some(doc!{ "_id": 1 });
+
+        // Skipped documents have already been skipped by the first query,
+        // and yielded documents count towards the limit.
+        if self.after.is_some() {
+            let num_yielded = self.num_yielded as i64;
+            options.skip = None;
+            options.limit = options.limit.map(|limit| {
+                if limit > 0 { limit - num_yielded } else { limit }
+            });
+        }
+
+        options
+    }
+
+    fn projection(&self) -> Projection {
+        self.query.projection()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.query.command_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, Document };
+    use mongodb::coll::options::FindOptions;
+    use crate::{ doc::Doc, uid::Uid, ops::Query, cursor::Cursor, error::Result };
+    use super::{ Continuation, Partition, partitions_from_sample };
+
+    /// A minimal document type to run queries against.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Event {
+        /// The unique ID.
+        _id: Uid<Event>,
+    }
+
+    impl Doc for Event {
+        type Id = i32;
+
+        const NAME: &'static str = "Event";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self._id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self._id = id;
+        }
+    }
+
+    /// A query with paging options.
+    #[derive(Debug)]
+    struct Paged;
+
+    impl Query<Event> for Paged {
+        type Output = Event;
+
+        fn filter(&self) -> Document {
+            doc!{ "kind": "click" }
+        }
+
+        fn options(&self) -> FindOptions {
+            FindOptions {
+                skip: Some(10),
+                limit: Some(100),
+                sort: Some(doc!{ "kind": -1 }),
+                ..FindOptions::new()
+            }
+        }
+    }
+
+    #[test]
+    fn first_query() {
+        let query = Continuation { query: &Paged, after: None, num_yielded: 0 };
+        let options = Query::<Event>::options(&query);
+
+        assert_eq!(Query::<Event>::filter(&query), doc!{ "kind": "click" });
+        assert_eq!(options.sort, Some(doc!{ "_id": 1 }));
+        assert_eq!(options.skip, Some(10));
+        assert_eq!(options.limit, Some(100));
+    }
+
+    #[test]
+    fn continued_query() {
+        let id = Bson::I32(42);
+        let query = Continuation { query: &Paged, after: Some(&id), num_yielded: 30 };
+        let options = Query::<Event>::options(&query);
+
+        assert_eq!(Query::<Event>::filter(&query), doc!{
+            "$and": [{ "kind": "click" }, { "_id": { "$gt": 42 } }]
+        });
+        assert_eq!(options.sort, Some(doc!{ "_id": 1 }));
+        assert_eq!(options.skip, None);
+        assert_eq!(options.limit, Some(70));
+
+        let all = doc!{};
+        let query = Continuation { query: &all, after: Some(&id), num_yielded: 30 };
+
+        assert_eq!(Query::<Event>::filter(&query), doc!{ "_id": { "$gt": 42 } });
+    }
+
+    #[test]
+    fn partition_filters() {
+        let partitions = Partition::split_at(vec![Bson::I32(10), Bson::I32(20)]);
+        let filters: Vec<_> = partitions.iter().map(Query::<Event>::filter).collect();
+
+        assert_eq!(filters, [
+            doc!{ "_id": { "$not": { "$gte": 10 } } },
+            doc!{ "_id": { "$gte": 10, "$lt": 20 } },
+            doc!{ "_id": { "$gte": 20 } },
+        ]);
+        assert_eq!(Query::<Event>::options(&partitions[0]).sort, Some(doc!{ "_id": 1 }));
+        assert_eq!(Query::<Event>::filter(&Partition::default()), Document::new());
+    }
+
+    #[test]
+    fn partitions_of_sample() {
+        let sample: Vec<_> = (0..12).map(Bson::I32).collect();
+        let bounds = |partitions: Vec<Partition>| -> Vec<_> {
+            partitions.into_iter().map(|p| (p.min, p.max)).collect()
+        };
+
+        assert_eq!(bounds(partitions_from_sample(&sample, 3)), [
+            (None, Some(Bson::I32(4))),
+            (Some(Bson::I32(4)), Some(Bson::I32(8))),
+            (Some(Bson::I32(8)), None),
+        ]);
+
+        // Duplicate boundaries of a small sample are merged.
+        assert_eq!(partitions_from_sample(&sample[..2], 4).len(), 2);
+        assert_eq!(partitions_from_sample(&[], 4), [Partition::default()]);
+        assert_eq!(partitions_from_sample(&sample, 1), [Partition::default()]);
+    }
+
+    /// Fails to compile unless values of type `T` can be sent to another thread.
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn partition_cursors_are_send() {
+        // The cursors returned by `par_scan()` are consumed on other threads,
+        // and their results are sent back.
+        assert_send::<Cursor<Event>>();
+        assert_send::<Result<Vec<Event>>>();
+    }
+}
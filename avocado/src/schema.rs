@@ -0,0 +1,371 @@
+//! A runtime registry of every `#[derive(Doc)]` type linked into the binary.
+//!
+//! The `Doc` derive macro registers metadata about each type it is applied
+//! to: the collection name, the serialized fields and their Rust types, and
+//! the indexes. The registry can be enumerated or exported as JSON, e.g. for
+//! generating documentation or checking contracts between services.
+//!
+//! The registry can also export a [JSON Schema](https://json-schema.org/)
+//! (draft 2020-12) for each type, describing its JSON representation, e.g.
+//! for publishing accurate schemas of documents exposed by an HTTP API. The
+//! schemas are derived from the Rust types of the fields as written in the
+//! source code: primitives, strings, `Option`s, sequences, sets, maps, IDs
+//! and timestamps are described precisely, while fields of other types are
+//! left unconstrained.
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! #[index(keys(email = "ascending"), unique)]
+//! struct Account {
+//!     _id: Uid<Account>,
+//!     email: String,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! let registry = avocado::schema::registry();
+//! let account = registry.get("Account").expect("`Account` not registered");
+//!
+//! assert_eq!(account.fields[1].name, "email");
+//! assert_eq!(account.fields[1].ty, "String");
+//!
+//! let json = registry.to_json()?;
+//! assert!(json.as_array().map_or(false, |docs| !docs.is_empty()));
+//!
+//! let schema = account.json_schema();
+//! assert_eq!(schema["properties"]["email"]["type"], "string");
+//! assert_eq!(schema["required"][1], "email");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::slice;
+use serde_json::{ Value, Map };
+use mongodb::coll::options::IndexModel;
+use crate::error::Result;
+
+/// Metadata of a single field of a `Doc` type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FieldMetadata {
+    /// The name of the field in the serialized document.
+    pub name: &'static str,
+    /// The Rust type of the field, as written in the source code.
+    pub ty: &'static str,
+}
+
+/// Metadata of a `Doc` type, as registered by the derive macro.
+#[derive(Debug, Clone, Copy)]
+pub struct DocMetadata {
+    /// The name of the Rust type.
+    pub type_name: &'static str,
+    /// The name of the collection, i.e. `Doc::NAME`.
+    pub name: &'static str,
+    /// The raw type of the `_id` field, i.e. `Doc::Id`.
+    pub id_type: &'static str,
+    /// The serialized fields of the document, in declaration order.
+    pub fields: &'static [FieldMetadata],
+    /// Returns the indexes of the collection, i.e. `Doc::indexes()`.
+    pub indexes: fn() -> Vec<IndexModel>,
+}
+
+inventory::collect!(DocMetadata);
+
+impl DocMetadata {
+    /// Exports the metadata as a JSON object.
+    pub fn to_json(&self) -> Result<Value> {
+        let fields: Vec<_> = self.fields
+            .iter()
+            .map(|field| json!({ "name": field.name, "type": field.ty }))
+            .collect();
+        let indexes = (self.indexes)()
+            .into_iter()
+            .map(|index| Ok(json!({
+                "keys": serde_json::to_value(&index.keys)?,
+                "name": index.options.name,
+                "unique": index.options.unique.unwrap_or(false),
+                "sparse": index.options.sparse.unwrap_or(false),
+            })))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(json!({
+            "typeName": self.type_name,
+            "name": self.name,
+            "idType": self.id_type,
+            "fields": fields,
+            "indexes": indexes,
+        }))
+    }
+
+    /// Returns a JSON Schema (draft 2020-12) describing the JSON
+    /// representation of the document. Fields of type `Option<_>` are
+    /// nullable and not required; all other fields are required.
+    pub fn json_schema(&self) -> Value {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+
+        for field in self.fields {
+            let ty = normalize_type(field.ty);
+            let (schema, optional) = match generic_parts(&ty) {
+                ("Option", ref args) if args.len() == 1 => {
+                    (json!({ "anyOf": [self.type_schema(args[0]), { "type": "null" }] }), true)
+                }
+                _ => (self.type_schema(&ty), false),
+            };
+
+            properties.insert(field.name.into(), schema);
+
+            if !optional {
+                required.push(field.name);
+            }
+        }
+
+        json!({
+            "$schema": JSON_SCHEMA_DIALECT,
+            "title": self.type_name,
+            "description": format!("A document of the `{}` collection", self.name),
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        })
+    }
+
+    /// Returns the schema of a (whitespace-free) Rust type.
+    fn type_schema(&self, ty: &str) -> Value {
+        let (head, args) = generic_parts(ty);
+
+        match (head, args.as_slice()) {
+            ("bool", []) => json!({ "type": "boolean" }),
+            ("i8", []) | ("i16", []) | ("i32", []) | ("i64", []) | ("isize", []) => {
+                json!({ "type": "integer" })
+            }
+            ("u8", []) | ("u16", []) | ("u32", []) | ("u64", []) | ("usize", []) => {
+                json!({ "type": "integer", "minimum": 0 })
+            }
+            ("f32", []) | ("f64", []) => json!({ "type": "number" }),
+            ("char", []) => json!({ "type": "string", "minLength": 1, "maxLength": 1 }),
+            ("String", []) | ("str", []) | ("Cow", ["str"]) => {
+                json!({ "type": "string" })
+            }
+            ("ObjectId", []) => json!({
+                "type": "object",
+                "properties": {
+                    "$oid": { "type": "string", "pattern": "^[0-9a-fA-F]{24}$" },
+                },
+                "required": ["$oid"],
+            }),
+            ("DateTime", _) | ("NaiveDateTime", []) => {
+                json!({ "type": "string", "format": "date-time" })
+            }
+            ("NaiveDate", []) => json!({ "type": "string", "format": "date" }),
+            ("Uuid", []) => json!({ "type": "string", "format": "uuid" }),
+            ("Uid", [referent]) if *referent == "Self" || strip_path(referent) == self.type_name => {
+                self.type_schema(&normalize_type(self.id_type))
+            }
+            ("Option", [inner]) => json!({ "anyOf": [self.type_schema(inner), { "type": "null" }] }),
+            ("Box", [inner]) | ("Rc", [inner]) | ("Arc", [inner]) => self.type_schema(inner),
+            ("Vec", [item]) | ("VecDeque", [item]) | ("LinkedList", [item]) => {
+                json!({ "type": "array", "items": self.type_schema(item) })
+            }
+            ("HashSet", [item]) | ("BTreeSet", [item]) => {
+                json!({ "type": "array", "items": self.type_schema(item), "uniqueItems": true })
+            }
+            ("HashMap", [_, value]) | ("BTreeMap", [_, value]) => {
+                json!({ "type": "object", "additionalProperties": self.type_schema(value) })
+            }
+            _ => json!({ "title": ty }),
+        }
+    }
+}
+
+/// A snapshot of the metadata of all registered `Doc` types,
+/// ordered by collection name.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    /// The registered types.
+    docs: Vec<&'static DocMetadata>,
+}
+
+impl Registry {
+    /// Returns the number of registered types.
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    /// Returns `true` if no types are registered.
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// Returns an iterator over the metadata of the registered types.
+    pub fn iter(&self) -> slice::Iter<'_, &'static DocMetadata> {
+        self.docs.iter()
+    }
+
+    /// Returns the metadata of the type stored in the named collection.
+    /// If several types share the collection, the first one is returned.
+    pub fn get(&self, name: &str) -> Option<&'static DocMetadata> {
+        self.docs.iter().cloned().find(|doc| doc.name == name)
+    }
+
+    /// Exports the JSON Schema of every registered type, as a JSON object
+    /// keyed by the names of the Rust types.
+    pub fn json_schemas(&self) -> Value {
+        self.docs
+            .iter()
+            .map(|doc| (doc.type_name.to_owned(), doc.json_schema()))
+            .collect::<Map<_, _>>()
+            .into()
+    }
+
+    /// Exports the metadata of all registered types as a JSON array.
+    pub fn to_json(&self) -> Result<Value> {
+        self.docs
+            .iter()
+            .map(|doc| doc.to_json())
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array)
+    }
+}
+
+impl<'a> IntoIterator for &'a Registry {
+    type Item = &'a &'static DocMetadata;
+    type IntoIter = slice::Iter<'a, &'static DocMetadata>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// The URI identifying the JSON Schema dialect of the exported schemas.
+const JSON_SCHEMA_DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Removes whitespace and outer references from a stringified Rust type,
+/// e.g. `& 'static str` becomes `str` and `Vec < u8 >` becomes `Vec<u8>`.
+fn normalize_type(raw: &str) -> String {
+    let mut ty = raw.trim();
+
+    while ty.starts_with('&') {
+        ty = ty[1..].trim_start();
+
+        if ty.starts_with('\'') {
+            ty = ty.find(char::is_whitespace).map_or("", |pos| ty[pos..].trim_start());
+        }
+        if ty.starts_with("mut ") {
+            ty = ty[3..].trim_start();
+        }
+    }
+
+    ty.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Strips the module path off of a type, e.g. `std::string::String`
+/// becomes `String`. Generic arguments are left intact.
+fn strip_path(ty: &str) -> &str {
+    let end = ty.find('<').unwrap_or_else(|| ty.len());
+
+    match ty[..end].rfind("::") {
+        Some(pos) => &ty[pos + 2..],
+        None => ty,
+    }
+}
+
+/// Splits a (whitespace-free) type into the name of the type constructor,
+/// without its module path, and the top-level generic arguments.
+/// Lifetime arguments are skipped.
+fn generic_parts(full: &str) -> (&str, Vec<&str>) {
+    let ty = strip_path(full);
+    let (start, end) = match (ty.find('<'), ty.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (start, end),
+        _ => return (ty, Vec::new()),
+    };
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = start + 1;
+
+    for (i, c) in ty[..end].char_indices().skip(start + 1) {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&ty[arg_start..i]);
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    args.push(&ty[arg_start..end]);
+    args.retain(|arg| !arg.is_empty() && !arg.starts_with('\''));
+
+    (&ty[..start], args)
+}
+
+/// Collects the metadata of every `#[derive(Doc)]` type linked into the binary.
+pub fn registry() -> Registry {
+    let mut docs: Vec<_> = inventory::iter::<DocMetadata>.into_iter().collect();
+    docs.sort_by_key(|doc| (doc.name, doc.type_name));
+    Registry { docs }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ normalize_type, generic_parts, DocMetadata, FieldMetadata };
+
+    #[test]
+    fn parse_types() {
+        assert_eq!(normalize_type("& 'static str"), "str");
+        assert_eq!(normalize_type("&mut Vec < u8 >"), "Vec<u8>");
+        assert_eq!(normalize_type("std :: string :: String"), "std::string::String");
+
+        assert_eq!(generic_parts("u64"), ("u64", vec![]));
+        assert_eq!(generic_parts("std::vec::Vec<u8>"), ("Vec", vec!["u8"]));
+        assert_eq!(
+            generic_parts("HashMap<String,Vec<(i32,i64)>>"),
+            ("HashMap", vec!["String", "Vec<(i32,i64)>"])
+        );
+        assert_eq!(generic_parts("Cow<'a,str>"), ("Cow", vec!["str"]));
+    }
+
+    #[test]
+    fn json_schema() {
+        let metadata = DocMetadata {
+            type_name: "Account",
+            name: "accounts",
+            id_type: "ObjectId",
+            fields: &[
+                FieldMetadata { name: "_id", ty: "Uid < Account >" },
+                FieldMetadata { name: "nick", ty: "Option < String >" },
+                FieldMetadata { name: "tags", ty: "BTreeSet < String >" },
+                FieldMetadata { name: "owner", ty: "Uid < User >" },
+                FieldMetadata { name: "score", ty: "u32" },
+            ],
+            indexes: Vec::new,
+        };
+        let schema = metadata.json_schema();
+
+        assert_eq!(schema["$schema"], "https://json-schema.org/draft/2020-12/schema");
+        assert_eq!(schema["title"], "Account");
+        assert_eq!(schema["type"], "object");
+        assert_eq!(schema["required"], json!(["_id", "tags", "owner", "score"]));
+
+        let properties = &schema["properties"];
+        assert_eq!(properties["_id"]["required"], json!(["$oid"]));
+        assert_eq!(properties["nick"], json!({
+            "anyOf": [{ "type": "string" }, { "type": "null" }]
+        }));
+        assert_eq!(properties["tags"], json!({
+            "type": "array",
+            "items": { "type": "string" },
+            "uniqueItems": true,
+        }));
+        assert_eq!(properties["owner"], json!({ "title": "Uid<User>" }));
+        assert_eq!(properties["score"], json!({ "type": "integer", "minimum": 0 }));
+    }
+}
@@ -0,0 +1,606 @@
+//! Collection handles that restrict every operation to a fixed scope.
+//!
+//! A scope is a filter document, e.g. `doc!{ "tenant": "acme" }` or
+//! `doc!{ "deleted_at": { "$exists": false } }`, which is combined with the
+//! filter of every read and write performed through a
+//! [`ScopedCollection`](struct.ScopedCollection.html). Documents inserted
+//! or replaced through such a handle also receive the plain (non-operator)
+//! field values of the scope, so they are guaranteed to be visible from
+//! within the scope, even if the Rust type doesn't declare those fields.
+
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use bson::{ Bson, Document };
+use mongodb::common::WriteConcern;
+use mongodb::coll::options::{
+    FindOptions,
+    CountOptions,
+    DistinctOptions,
+    AggregateOptions,
+    FindOneAndUpdateOptions,
+    ReturnDocument,
+};
+use crate::{
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
+    coll::{
+        Collection, UpdateOneResult, UpsertOneResult, UpdateManyResult, UpsertManyResult,
+        prepare_entity_document,
+    },
+    cursor::{ self, Cursor },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    error::{ Error, ErrorKind::{ MissingId, ScopeViolation }, Result },
+};
+
+/// A view of a typed collection in which every operation is restricted to
+/// the documents matching the scope filter.
+#[derive(Debug)]
+pub struct ScopedCollection<'a, T: Doc> {
+    /// The underlying collection.
+    collection: &'a Collection<T>,
+    /// The filter restricting every operation.
+    scope: Document,
+}
+
+impl<'a, T: Doc> ScopedCollection<'a, T> {
+    /// Restricts the collection to the documents matching `scope`.
+    /// This is usually called through `Collection::scoped()`.
+    pub fn new(collection: &'a Collection<T>, scope: Document) -> Self {
+        ScopedCollection { collection, scope }
+    }
+
+    /// Returns the underlying, unrestricted collection.
+    pub fn collection(&self) -> &'a Collection<T> {
+        self.collection
+    }
+
+    /// Returns the scope filter.
+    pub fn scope(&self) -> &Document {
+        &self.scope
+    }
+
+    /// Returns the number of documents in scope matching the query criteria.
+    pub fn count<Q: Count<T>>(&self, query: Q) -> Result<usize> {
+        self.collection.count(self.wrap(query))
+    }
+
+    /// Returns the distinct values of a certain field within the scope.
+    pub fn distinct<Q, C>(&self, query: Q) -> Result<C>
+        where Q: Distinct<T>,
+              C: FromIterator<Q::Output>,
+    {
+        self.collection.distinct(self.wrap(query))
+    }
+
+    /// Runs an aggregation pipeline on the documents in scope.
+    pub fn aggregate<P>(&self, pipeline: P) -> Result<Cursor<P::Output>>
+        where P: Pipeline<T>,
+              P::Output: cursor::Item,
+    {
+        self.collection.aggregate(self.wrap(pipeline))
+    }
+
+    /// Retrieves a single document in scope satisfying the query.
+    pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        self.collection.find_one(self.wrap(query))
+    }
+
+    /// Retrieves all documents in scope satisfying the query.
+    pub fn find_many<Q>(&self, query: Q) -> Result<Cursor<Q::Output>>
+        where Q: Query<T>,
+              Q::Output: cursor::Item,
+    {
+        self.collection.find_many(self.wrap(query))
+    }
+
+    /// Inserts a single document, adding the field values of the scope.
+    pub fn insert_one(&self, entity: &T) -> Result<Uid<T>> {
+        self.collection.insert_document(self.scoped_document(entity)?)
+    }
+
+    /// Inserts many documents, adding the field values of the scope to each.
+    pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
+        where I: IntoIterator,
+              I::Item: Borrow<T>,
+              T::Id: Clone + Debug,
+              T: 'static,
+    {
+        let docs = entities
+            .into_iter()
+            .map(|entity| self.scoped_document(entity.borrow()))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.collection.insert_documents(docs)
+    }
+
+    /// Replaces the document with the `_id` of `entity`, provided that it is
+    /// in scope, adding the field values of the scope to the replacement.
+    pub fn replace_entity(&self, entity: &T) -> Result<UpdateOneResult> where T: Debug {
+        let message = || format!("error in scoped {}::replace_entity({:#?})", T::NAME, entity);
+        let doc = self.scoped_document(entity)?;
+
+        self.collection
+            .replace_document(doc, &self.scope, false, message)
+            .and_then(UpdateOneResult::from_raw)
+    }
+
+    /// Replaces the document with the `_id` of `entity` if it is in scope,
+    /// or inserts it otherwise, adding the field values of the scope. If a
+    /// document with the same `_id` exists outside the scope, the insertion
+    /// fails with a duplicate key error instead of overwriting it.
+    pub fn upsert_entity(&self, entity: &T) -> Result<UpsertOneResult<Uid<T>>> where T: Debug {
+        let message = || format!("error in scoped {}::upsert_entity({:#?})", T::NAME, entity);
+        let doc = self.scoped_document(entity)?;
+
+        self.collection
+            .replace_document(doc, &self.scope, true, message)
+            .and_then(UpsertOneResult::from_raw)
+    }
+
+    /// Replaces a single document in scope matching the query, adding the
+    /// field values of the scope to the replacement. Returns the original
+    /// document if found.
+    pub fn find_one_and_replace<Q: Query<T>>(&self, query: Q, replacement: &T)
+        -> Result<Option<Q::Output>>
+        where T: Debug
+    {
+        let scoped = self.wrap(query);
+        let message = || format!(
+            "error in scoped {}::find_one_and_replace({:#?}, {:#?})",
+            T::NAME, scoped, replacement
+        );
+        let doc = self.scoped_document(replacement)?;
+
+        self.collection.find_one_and_replace_document(
+            &scoped, doc, ReturnDocument::Before, false, message
+        )
+    }
+
+    /// Updates a single document in scope.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        self.collection.update_one(self.wrap(update))
+    }
+
+    /// Updates all matching documents in scope.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        self.collection.update_many(self.wrap(update))
+    }
+
+    /// Upserts a single document in scope. Since the scope is part of the
+    /// filter, its equality conditions also apply to the inserted document.
+    pub fn upsert_one<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertOneResult<Uid<T>>> {
+        self.collection.upsert_one(self.wrap(upsert))
+    }
+
+    /// Upserts many documents in scope.
+    pub fn upsert_many<U: Upsert<T>>(&self, upsert: U) -> Result<UpsertManyResult> {
+        self.collection.upsert_many(self.wrap(upsert))
+    }
+
+    /// Deletes a single entity based on its identity, if it is in scope.
+    pub fn delete_entity(&self, entity: &T) -> Result<bool> {
+        let id = entity.id().ok_or_else(
+            || Error::new(MissingId, format!("No `_id` in entity of type {}", T::NAME))
+        )?;
+        let id_bson = bson::to_bson(id)?;
+
+        self.delete_one(doc!{ "_id": id_bson })
+    }
+
+    /// Deletes one document in scope.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        self.collection.delete_one(self.wrap(query))
+    }
+
+    /// Deletes all matching documents in scope.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        self.collection.delete_many(self.wrap(query))
+    }
+
+    /// Deletes a single document in scope, returning it if it was found.
+    pub fn find_one_and_delete<Q: Query<T>>(&self, query: Q) -> Result<Option<Q::Output>> {
+        self.collection.find_one_and_delete(self.wrap(query))
+    }
+
+    /// Finds a single document in scope and updates it.
+    pub fn find_one_and_update<U: FindAndUpdate<T>>(&self, update: U) -> Result<Option<U::Output>> {
+        self.collection.find_one_and_update(self.wrap(update))
+    }
+
+    /// Restricts an operation to the scope.
+    fn wrap<O>(&self, op: O) -> ScopedOp<'_, O> {
+        ScopedOp { scope: &self.scope, op }
+    }
+
+    /// Serializes the entity, adds the plain field values of the scope to
+    /// it, then prepares it for writing like `Collection` does. Returns an
+    /// error if the entity already has a different value for such a field.
+    fn scoped_document(&self, entity: &T) -> Result<Document> {
+        let mut doc = T::SERDE_PROFILE.serialize(entity)?;
+        add_scope_fields(&self.scope, &mut doc, T::NAME)?;
+        prepare_entity_document(entity, doc)
+    }
+}
+
+/// Adds the plain field values of `scope` to a serialized document of the
+/// type named `type_name`. Dotted keys, e.g. `owner.tenant`, are set in
+/// nested documents, and top-level operators such as `$or` are skipped. A
+/// `null` field, e.g. an `Option` which is `None`, counts as missing.
+/// Returns an error if the document already has another value for such a
+/// field.
+fn add_scope_fields(scope: &Document, doc: &mut Document, type_name: &str) -> Result<()> {
+    for (key, value) in scope {
+        if key.starts_with('$') || is_operator_condition(value) {
+            continue;
+        }
+
+        add_scope_field(doc, key, value, key, type_name)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the field at the dotted `path` of `doc`, which is the scope field
+/// `scope_key`, to `value`, creating the missing nested documents.
+fn add_scope_field(
+    doc: &mut Document,
+    path: &str,
+    value: &Bson,
+    scope_key: &str,
+    type_name: &str,
+) -> Result<()> {
+    let mut segments = path.splitn(2, '.');
+    let key = segments.next().unwrap_or(path);
+
+    if let Some(rest) = segments.next() {
+        match doc.get_mut(key) {
+            Some(&mut Bson::Document(ref mut nested)) => {
+                return add_scope_field(nested, rest, value, scope_key, type_name);
+            }
+            Some(&mut Bson::Null) | None => {}
+            Some(existing) => return Err(scope_violation(type_name, scope_key, existing, value)),
+        }
+
+        let mut nested = Document::new();
+        add_scope_field(&mut nested, rest, value, scope_key, type_name)?;
+        doc.insert(key, nested);
+
+        return Ok(());
+    }
+
+    match doc.get(key) {
+        Some(existing) if *existing != Bson::Null && existing != value => {
+            Err(scope_violation(type_name, scope_key, existing, value))
+        }
+        Some(existing) if existing == value => Ok(()),
+        _ => {
+            doc.insert(key, value.clone());
+            Ok(())
+        }
+    }
+}
+
+/// The error of a document whose field `key` contradicts the scope.
+fn scope_violation(type_name: &str, key: &str, existing: &Bson, value: &Bson) -> Error {
+    Error::new(
+        ScopeViolation,
+        format!("{} field `{}` is {}, but the scope requires {}",
+                type_name, key, existing, value)
+    )
+}
+
+/// Restricts an aggregation pipeline to the scope by inserting a `$match`
+/// stage. It comes first, unless the first stage must remain the first one,
+/// i.e. `$geoNear`, `$search`, `$changeStream` or a `$match` with `$text`,
+/// in which case the scope is matched right after it.
+fn scoped_stages(scope: &Document, mut stages: Vec<Document>) -> Vec<Document> {
+    let position = match stages.first() {
+        Some(first) if must_be_first_stage(first) => 1,
+        _ => 0,
+    };
+
+    stages.insert(position, doc!{ "$match": scope.clone() });
+    stages
+}
+
+/// Returns `true` if the pipeline stage is only allowed as the first one.
+fn must_be_first_stage(stage: &Document) -> bool {
+    ["$geoNear", "$search", "$changeStream"].iter().any(|&name| stage.contains_key(name))
+        || stage.get_document("$match").map_or(false, |filter| filter.contains_key("$text"))
+}
+
+/// Returns `true` if the value of a filter field is an operator document,
+/// e.g. `{ "$exists": false }`, instead of a plain value to be matched.
+fn is_operator_condition(value: &Bson) -> bool {
+    match *value {
+        Bson::Document(ref doc) => doc.keys().next().map_or(false, |key| key.starts_with('$')),
+        _ => false,
+    }
+}
+
+/// Combines the scope with a filter so that both must be satisfied.
+pub(crate) fn and_filters(scope: &Document, filter: Document) -> Document {
+    if scope.is_empty() {
+        filter
+    } else if filter.is_empty() {
+        scope.clone()
+    } else {
+        doc!{ "$and": [scope.clone(), filter] }
+    }
+}
+
+/// An operation whose filter is restricted to a scope.
+#[derive(Debug, Clone)]
+struct ScopedOp<'a, O> {
+    /// The scope filter.
+    scope: &'a Document,
+    /// The wrapped operation.
+    op: O,
+}
+
+impl<'a, T: Doc, Q: Count<T>> Count<T> for ScopedOp<'a, Q> {
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn options(&self) -> CountOptions {
+        self.op.options()
+    }
+}
+
+impl<'a, T: Doc, Q: Distinct<T>> Distinct<T> for ScopedOp<'a, Q> {
+    type Output = Q::Output;
+
+    const FIELD: &'static str = Q::FIELD;
+
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn transform(raw: Bson) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> DistinctOptions {
+        self.op.options()
+    }
+}
+
+impl<'a, T: Doc, P: Pipeline<T>> Pipeline<T> for ScopedOp<'a, P> {
+    type Output = P::Output;
+
+    fn stages(&self) -> Vec<Document> {
+        scoped_stages(self.scope, self.op.stages())
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        P::transform(raw)
+    }
+
+    fn options(&self) -> AggregateOptions {
+        self.op.options()
+    }
+}
+
+impl<'a, T: Doc, Q: Query<T>> Query<T> for ScopedOp<'a, Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.op.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.op.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.op.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<'a, T: Doc, U: Update<T>> Update<T> for ScopedOp<'a, U> {
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn update(&self) -> Document {
+        self.op.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.op.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<'a, T: Doc, U: Upsert<T>> Upsert<T> for ScopedOp<'a, U> {
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn upsert(&self) -> Document {
+        self.op.upsert()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.op.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<'a, T: Doc, Q: Delete<T>> Delete<T> for ScopedOp<'a, Q> {
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.op.options()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.op.command_options()
+    }
+}
+
+impl<'a, T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for ScopedOp<'a, U> {
+    type Output = U::Output;
+
+    fn filter(&self) -> Document {
+        and_filters(self.scope, self.op.filter())
+    }
+
+    fn update(&self) -> Document {
+        self.op.update()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        U::transform(raw)
+    }
+
+    fn options(&self) -> FindOneAndUpdateOptions {
+        self.op.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.op.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.op.sort()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{ ErrorExt, ErrorKind };
+    use super::{ and_filters, is_operator_condition, add_scope_fields, scoped_stages };
+
+    #[test]
+    fn combine_filters() {
+        let scope = doc!{ "tenant": "acme" };
+
+        assert_eq!(and_filters(&scope, doc!{}), scope);
+        assert_eq!(and_filters(&doc!{}, doc!{ "x": 1 }), doc!{ "x": 1 });
+        assert_eq!(and_filters(&scope, doc!{ "x": 1 }), doc!{
+            "$and": [{ "tenant": "acme" }, { "x": 1 }]
+        });
+    }
+
+    #[test]
+    fn scope_fields() {
+        let scope = doc!{ "tenant": "acme", "deleted_at": { "$exists": false } };
+
+        let mut missing = doc!{ "_id": 1 };
+        add_scope_fields(&scope, &mut missing, "User").unwrap();
+        assert_eq!(missing, doc!{ "_id": 1, "tenant": "acme" });
+
+        let mut null = doc!{ "_id": 2, "tenant": null };
+        add_scope_fields(&scope, &mut null, "User").unwrap();
+        assert_eq!(null, doc!{ "_id": 2, "tenant": "acme" });
+
+        let mut same = doc!{ "_id": 3, "tenant": "acme" };
+        add_scope_fields(&scope, &mut same, "User").unwrap();
+        assert_eq!(same, doc!{ "_id": 3, "tenant": "acme" });
+
+        let mut other = doc!{ "_id": 4, "tenant": "initech" };
+        let error = add_scope_fields(&scope, &mut other, "User").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ScopeViolation);
+    }
+
+    #[test]
+    fn nested_scope_fields() {
+        let scope = doc!{
+            "owner.tenant": "acme",
+            "owner.region.name": "eu",
+            "$or": [{ "public": true }, { "shared": true }],
+        };
+
+        let mut missing = doc!{ "_id": 1 };
+        add_scope_fields(&scope, &mut missing, "User").unwrap();
+        assert_eq!(missing, doc!{
+            "_id": 1,
+            "owner": { "tenant": "acme", "region": { "name": "eu" } },
+        });
+
+        let mut partial = doc!{ "_id": 2, "owner": { "name": "bob", "region": null } };
+        add_scope_fields(&scope, &mut partial, "User").unwrap();
+        assert_eq!(partial, doc!{
+            "_id": 2,
+            "owner": { "name": "bob", "tenant": "acme", "region": { "name": "eu" } },
+        });
+
+        let mut other = doc!{ "_id": 3, "owner": { "tenant": "initech" } };
+        let error = add_scope_fields(&scope, &mut other, "User").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ScopeViolation);
+
+        let mut scalar = doc!{ "_id": 4, "owner": "bob" };
+        let error = add_scope_fields(&scope, &mut scalar, "User").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ScopeViolation);
+    }
+
+    #[test]
+    fn scoped_pipeline() {
+        let scope = doc!{ "tenant": "acme" };
+        let group = doc!{ "$group": { "_id": "$kind" } };
+
+        assert_eq!(scoped_stages(&scope, vec![group.clone()]), vec![
+            doc!{ "$match": { "tenant": "acme" } },
+            group.clone(),
+        ]);
+
+        let first_stages = vec![
+            doc!{ "$geoNear": { "near": [0.0, 0.0], "distanceField": "distance" } },
+            doc!{ "$match": { "$text": { "$search": "coffee" } } },
+            doc!{ "$search": { "text": { "query": "coffee", "path": "name" } } },
+            doc!{ "$changeStream": {} },
+        ];
+
+        for first in first_stages {
+            assert_eq!(scoped_stages(&scope, vec![first.clone(), group.clone()]), vec![
+                first,
+                doc!{ "$match": { "tenant": "acme" } },
+                group.clone(),
+            ]);
+        }
+    }
+
+    #[test]
+    fn operator_conditions() {
+        assert!(is_operator_condition(&bson!({ "$exists": false })));
+        assert!(!is_operator_condition(&bson!({ "nested": 1 })));
+        assert!(!is_operator_condition(&bson!("acme")));
+        assert!(!is_operator_condition(&bson!({})));
+    }
+}
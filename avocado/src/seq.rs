@@ -0,0 +1,163 @@
+//! Sequential, human-readable identifiers.
+//!
+//! A [`Sequence`](struct.Sequence.html) implements the usual counters
+//! collection pattern: each named counter is a document in the
+//! `_avocado_sequences` collection, which is incremented atomically with
+//! `findAndModify` and created on first use by the same upsert. The values
+//! of a counter start at 1 and are strictly increasing, so they make good
+//! invoice or ticket numbers, but there may be gaps in them, e.g. if a
+//! value is drawn and the document using it is never inserted.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::seq::Sequence;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! #[id_type = "i64"]
+//! struct Invoice {
+//!     _id: Uid<Invoice>,
+//!     total: f64,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let sequence = Sequence::new(&db);
+//! let number = sequence.next("order_number")?;
+//!
+//! // Draws from the counter named after the document type, `Invoice`.
+//! let invoice = Invoice { _id: sequence.next_uid()?, total: 99.5 };
+//! # Ok(())
+//! # }
+//! ```
+
+use std::i64;
+use std::ops::Range;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use mongodb::coll::Collection;
+use mongodb::coll::options::{ FindOneAndUpdateOptions, ReturnDocument };
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::{
+    doc::Doc,
+    uid::Uid,
+    utils::MaxTime,
+    error::{ Error, ErrorKind, Result, ResultExt },
+};
+
+/// The name of the collection storing the counters.
+pub const SEQUENCES_COLLECTION_NAME: &str = "_avocado_sequences";
+
+/// The field of a counter document holding its last value.
+const VALUE_FIELD: &str = "value";
+
+/// A set of named counters, stored in a collection.
+pub struct Sequence {
+    /// The collection storing one document per counter, keyed by its name.
+    counters: Collection,
+}
+
+impl Sequence {
+    /// Stores the counters in the `_avocado_sequences` collection of `db`.
+    pub fn new(db: &Database) -> Self {
+        Self::with_collection_name(db, SEQUENCES_COLLECTION_NAME)
+    }
+
+    /// Stores the counters in the collection named `name` of `db`.
+    pub fn with_collection_name(db: &Database, name: &str) -> Self {
+        Sequence { counters: db.collection(name) }
+    }
+
+    /// Increments the counter `name` and returns its new value. The first
+    /// value of a counter is 1.
+    pub fn next(&self, name: &str) -> Result<u64> {
+        self.reserve(name, 1).map(|range| range.start)
+    }
+
+    /// Increments the counter `name` by `count` at once, and returns the
+    /// `count` consecutive values reserved this way.
+    pub fn reserve(&self, name: &str, count: u64) -> Result<Range<u64>> {
+        if count > i64::MAX as u64 {
+            return Err(Error::new(
+                ErrorKind::IntConversionOverflow,
+                format!("can't reserve {} values of sequence `{}`", count, name)
+            ));
+        }
+
+        #[allow(clippy::cast_possible_wrap)]
+        let increment = count as i64;
+        let message = || format!("error in Sequence::reserve({:?}, {})", name, count);
+        let options = FindOneAndUpdateOptions {
+            return_document: Some(ReturnDocument::After),
+            upsert: Some(true),
+            ..Default::default()
+        };
+        let counter = self.counters
+            .find_one_and_update(
+                doc!{ "_id": name },
+                doc!{ "$inc": { VALUE_FIELD: increment } },
+                Some(options.with_default_max_time()),
+            )
+            .chain(&message)?
+            .ok_or_else(|| Error::new(ErrorKind::MongoDbError, message()))?;
+        let last = value_to_u64(counter.get_i64(VALUE_FIELD).chain(&message)?, name)?;
+
+        if last < count {
+            return Err(Error::new(
+                ErrorKind::IntConversionUnderflow,
+                format!("sequence `{}` had a negative value before reserving {}", name, count)
+            ));
+        }
+
+        Ok(last + 1 - count .. last + 1)
+    }
+
+    /// Returns the last value drawn from the counter `name`, or 0 if none
+    /// has been drawn yet. The counter is not modified.
+    pub fn current(&self, name: &str) -> Result<u64> {
+        let message = || format!("error in Sequence::current({:?})", name);
+        let counter = self.counters
+            .find_one(Some(doc!{ "_id": name }), None)
+            .chain(&message)?;
+
+        match counter {
+            Some(document) => value_to_u64(document.get_i64(VALUE_FIELD).chain(&message)?, name),
+            None => Ok(0),
+        }
+    }
+
+    /// Draws the next identifier of `T` from the counter named `T::NAME`.
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn next_uid<T: Doc<Id = i64>>(&self) -> Result<Uid<T>> {
+        // Counters are stored as `i64`, so their values always fit.
+        self.next(T::NAME).map(|value| Uid::from_raw(value as i64))
+    }
+}
+
+impl Debug for Sequence {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Sequence")
+            .field("counters", &self.counters.name())
+            .finish()
+    }
+}
+
+/// Converts the stored value of a counter to `u64`. Counters only ever
+/// increase from 0, so a negative value means that it has been tampered with.
+#[allow(clippy::cast_sign_loss)]
+fn value_to_u64(value: i64, name: &str) -> Result<u64> {
+    if value < 0 {
+        Err(Error::new(
+            ErrorKind::IntConversionUnderflow,
+            format!("sequence `{}` has a negative value ({})", name, value)
+        ))
+    } else {
+        Ok(value as u64)
+    }
+}
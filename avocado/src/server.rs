@@ -0,0 +1,264 @@
+//! Information about the server and the topology, and health checks.
+//!
+//! The replies of `buildInfo`, `isMaster` and `serverStatus` are available
+//! as typed structs via the corresponding methods of
+//! [`DatabaseExt`](../db/trait.DatabaseExt.html). A
+//! [`HealthCheck`](struct.HealthCheck.html) combines a round trip to the
+//! server with the role of the server in the topology, e.g. for the
+//! readiness probe of a service.
+//!
+//! ```no_run
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use avocado::prelude::*;
+//! # use avocado::server::HealthCheck;
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! let db = client.db("avocado_example_db");
+//! let check = HealthCheck {
+//!     max_latency: Some(Duration::from_millis(250)),
+//!     require_writable: true,
+//! };
+//! let status = check.run(&db)?;
+//!
+//! if !status.is_healthy() {
+//!     eprintln!("database not ready: {}", status.problems.join(", "));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::{ Duration, Instant };
+use crate::{
+    db::DatabaseExt,
+    error::{ Result, ResultExt },
+};
+
+/// The value of `msg` in the `isMaster` reply of a `mongos` router.
+const MONGOS_MSG: &str = "isdbgrid";
+
+/// The reply of the `buildInfo` command, returned by
+/// `DatabaseExt::build_info()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    /// The version of the server, e.g. `"4.0.10"`.
+    pub version: String,
+    /// The components of the version, e.g. `[4, 0, 10, 0]`.
+    #[serde(default)]
+    pub version_array: Vec<i32>,
+    /// The commit hash the server was built from.
+    #[serde(default)]
+    pub git_version: String,
+    /// The maximal size of a BSON document, in bytes.
+    #[serde(default)]
+    pub max_bson_object_size: i64,
+    /// Whether the server was built with debug information.
+    #[serde(default)]
+    pub debug: bool,
+    /// The storage engines available, if reported by the server.
+    #[serde(default)]
+    pub storage_engines: Vec<String>,
+}
+
+/// The role of a server in the topology of a deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// A stand-alone server, not part of a replica set.
+    Standalone,
+    /// The primary of a replica set, accepting writes.
+    Primary,
+    /// A secondary of a replica set.
+    Secondary,
+    /// An arbiter of a replica set, holding no data.
+    Arbiter,
+    /// A `mongos` router of a sharded cluster.
+    Mongos,
+    /// Any other state, e.g. a recovering or starting member.
+    Other,
+}
+
+/// The reply of the `isMaster` command, returned by `DatabaseExt::hello()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloReply {
+    /// Whether the server accepts writes.
+    #[serde(rename = "ismaster", alias = "isWritablePrimary", default)]
+    pub is_writable_primary: bool,
+    /// Whether the server is a secondary of a replica set.
+    #[serde(default)]
+    pub secondary: bool,
+    /// Whether the server is an arbiter of a replica set.
+    #[serde(default)]
+    pub arbiter_only: bool,
+    /// The name of the replica set, if the server is a member of one.
+    pub set_name: Option<String>,
+    /// The data-bearing members of the replica set.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// The address of the current primary, if known.
+    pub primary: Option<String>,
+    /// The address of the server itself, if it is a replica set member.
+    pub me: Option<String>,
+    /// `"isdbgrid"` if the server is a `mongos` router.
+    pub msg: Option<String>,
+    /// The lowest wire protocol version the server supports.
+    #[serde(default)]
+    pub min_wire_version: i32,
+    /// The highest wire protocol version the server supports.
+    #[serde(default)]
+    pub max_wire_version: i32,
+}
+
+impl HelloReply {
+    /// Returns the role of the server in the topology.
+    pub fn role(&self) -> Role {
+        if self.msg.as_ref().map_or(false, |msg| msg == MONGOS_MSG) {
+            Role::Mongos
+        } else if self.set_name.is_none() {
+            if self.is_writable_primary {
+                Role::Standalone
+            } else {
+                Role::Other
+            }
+        } else if self.is_writable_primary {
+            Role::Primary
+        } else if self.secondary {
+            Role::Secondary
+        } else if self.arbiter_only {
+            Role::Arbiter
+        } else {
+            Role::Other
+        }
+    }
+}
+
+/// The connection counters of `serverStatus`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStats {
+    /// The number of open incoming connections.
+    pub current: i64,
+    /// The number of further incoming connections the server accepts.
+    pub available: i64,
+    /// The number of incoming connections created since startup.
+    #[serde(default)]
+    pub total_created: i64,
+}
+
+/// The most commonly used parts of the reply of the `serverStatus` command,
+/// returned by `DatabaseExt::server_status()`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    /// The host name and port of the server.
+    pub host: String,
+    /// The version of the server.
+    pub version: String,
+    /// The kind of the process, i.e. `mongod` or `mongos`.
+    pub process: String,
+    /// The time since the server was started, in seconds.
+    pub uptime: f64,
+    /// The time since the server was started, in milliseconds.
+    #[serde(default)]
+    pub uptime_millis: i64,
+    /// The connection counters, if reported.
+    pub connections: Option<ConnectionStats>,
+}
+
+/// Settings of a health check, e.g. for a readiness probe. The default
+/// check only requires the server to respond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct HealthCheck {
+    /// The maximal acceptable round-trip time of a command.
+    pub max_latency: Option<Duration>,
+    /// Whether the server must accept writes, i.e. be a stand-alone server,
+    /// a replica set primary, or a `mongos` router.
+    pub require_writable: bool,
+}
+
+impl HealthCheck {
+    /// Runs the `isMaster` command, measuring its round-trip time, and
+    /// checks the reply against the settings. Returns an error only if the
+    /// server couldn't be reached; the problems found are reported in the
+    /// returned status.
+    pub fn run<D: DatabaseExt>(&self, db: &D) -> Result<HealthStatus> {
+        let start = Instant::now();
+        let reply = db.hello().chain("health check failed")?;
+        let latency = start.elapsed();
+        let role = reply.role();
+        let mut problems = Vec::new();
+
+        if let Some(max_latency) = self.max_latency {
+            if latency > max_latency {
+                problems.push(format!("latency {:?} exceeds {:?}", latency, max_latency));
+            }
+        }
+
+        if self.require_writable {
+            match role {
+                Role::Standalone | Role::Primary | Role::Mongos => {}
+                _ => problems.push(format!("server is not writable (role: {:?})", role)),
+            }
+        }
+
+        Ok(HealthStatus {
+            latency,
+            role,
+            set_name: reply.set_name,
+            problems,
+        })
+    }
+}
+
+/// The outcome of a `HealthCheck`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// The round-trip time of the `isMaster` command.
+    pub latency: Duration,
+    /// The role of the server in the topology.
+    pub role: Role,
+    /// The name of the replica set, if the server is a member of one.
+    pub set_name: Option<String>,
+    /// The reasons the server is unhealthy; empty if it is healthy.
+    pub problems: Vec<String>,
+}
+
+impl HealthStatus {
+    /// Returns `true` if no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use super::{ HelloReply, Role };
+
+    /// Returns the role reported by the `isMaster` reply.
+    fn role_of(reply: bson::Document) -> Role {
+        bson::from_bson::<HelloReply>(Bson::Document(reply))
+            .expect("can't deserialize reply")
+            .role()
+    }
+
+    #[test]
+    fn roles() {
+        assert_eq!(role_of(doc!{ "ismaster": true, "ok": 1.0 }), Role::Standalone);
+        assert_eq!(role_of(doc!{ "ismaster": true, "msg": "isdbgrid" }), Role::Mongos);
+        assert_eq!(role_of(doc!{ "isWritablePrimary": true, "setName": "rs0" }), Role::Primary);
+        assert_eq!(
+            role_of(doc!{ "ismaster": false, "secondary": true, "setName": "rs0" }),
+            Role::Secondary
+        );
+        assert_eq!(
+            role_of(doc!{ "ismaster": false, "arbiterOnly": true, "setName": "rs0" }),
+            Role::Arbiter
+        );
+        assert_eq!(role_of(doc!{ "ismaster": false, "setName": "rs0" }), Role::Other);
+    }
+}
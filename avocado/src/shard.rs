@@ -0,0 +1,126 @@
+//! Shard keys and the detection of untargeted writes.
+//!
+//! On a sharded collection, an update or a deletion whose filter doesn't
+//! constrain the shard key can't be routed to a single shard: it is
+//! broadcast to all of them ("scatter-gather"), which is expensive, and
+//! single-document updates and deletions of this kind are rejected by the
+//! server unless they select the document by its `_id`.
+//!
+//! If `Doc::shard_key()` returns a [`Key`](struct.Key.html) —
+//! when deriving `Doc`, declare it using e.g.
+//! `#[shard_key(fields(tenant = "ascending", _id = "hashed"))]` — then
+//! the write methods of `Collection` check the filter of every update and
+//! deletion before sending it to the server. Untargeted writes are counted
+//! (see [`untargeted_write_count()`](fn.untargeted_write_count.html)), and
+//! in strict mode (`#[shard_key(fields(...), strict)]`), they are rejected
+//! with an `UntargetedWrite` error instead.
+//!
+//! `Collection::shard_collection()` shards the collection by the declared
+//! shard key.
+
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use bson::{ Bson, Document };
+use crate::{
+    doc::Doc,
+    error::{ Error, ErrorKind::UntargetedWrite, Result },
+};
+
+/// The number of untargeted writes detected so far.
+static UNTARGETED_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// The shard key of a collection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Key {
+    /// The fields of the shard key, e.g. `doc!{ "tenant": 1, "_id": "hashed" }`.
+    pub keys: Document,
+    /// Whether to reject writes not constraining every field of the key,
+    /// instead of only counting them.
+    pub strict: bool,
+}
+
+/// Returns the number of untargeted updates and deletions detected in this
+/// process, including the rejected ones. Useful for alerting and tests.
+pub fn untargeted_write_count() -> usize {
+    UNTARGETED_WRITES.load(Ordering::SeqCst)
+}
+
+/// Returns `true` if `filter` constrains every field of the shard key,
+/// either at the top level or in a clause of a top-level `$and`. If `single`
+/// is `true`, i.e. the write affects at most one document, constraining
+/// `_id` is also sufficient, as the server can route such a write too.
+pub fn is_targeted(filter: &Document, shard_key: &Document, single: bool) -> bool {
+    if single && constrains(filter, "_id") {
+        return true;
+    }
+
+    shard_key.keys().all(|field| constrains(filter, field))
+}
+
+/// Checks whether a write with the filter `filter` is targeted by the shard
+/// key of `T`, if it has one. An untargeted write is counted, and in strict
+/// mode, it results in an `UntargetedWrite` error.
+pub fn check_targeted<T: Doc>(filter: &Document, single: bool) -> Result<()> {
+    let shard_key = match T::shard_key() {
+        Some(shard_key) => shard_key,
+        None => return Ok(()),
+    };
+
+    if is_targeted(filter, &shard_key.keys, single) {
+        return Ok(());
+    }
+
+    UNTARGETED_WRITES.fetch_add(1, Ordering::SeqCst);
+
+    if shard_key.strict {
+        Err(Error::new(UntargetedWrite, format!(
+            "filter {} of {} write doesn't include shard key {}",
+            filter, T::NAME, shard_key.keys
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns `true` if the filter has a condition on `field` which has to be
+/// fulfilled by every matching document.
+fn constrains(filter: &Document, field: &str) -> bool {
+    if filter.contains_key(field) {
+        return true;
+    }
+
+    match filter.get("$and") {
+        Some(&Bson::Array(ref clauses)) => clauses.iter().any(|clause| match *clause {
+            Bson::Document(ref subfilter) => constrains(subfilter, field),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_targeted;
+
+    #[test]
+    fn targeted_filters() {
+        let key = doc!{ "tenant": 1, "region": "hashed" };
+
+        assert!(is_targeted(&doc!{ "tenant": "acme", "region": "eu", "x": 1 }, &key, false));
+        assert!(is_targeted(&doc!{
+            "tenant": "acme",
+            "$and": [{ "x": 1 }, { "region": { "$in": ["eu", "us"] } }],
+        }, &key, false));
+        assert!(!is_targeted(&doc!{ "tenant": "acme" }, &key, false));
+        assert!(!is_targeted(&doc!{
+            "$or": [{ "tenant": "acme", "region": "eu" }],
+        }, &key, false));
+    }
+
+    #[test]
+    fn single_document_by_id() {
+        let key = doc!{ "tenant": 1 };
+
+        assert!(is_targeted(&doc!{ "_id": 42 }, &key, true));
+        assert!(!is_targeted(&doc!{ "_id": 42 }, &key, false));
+    }
+}
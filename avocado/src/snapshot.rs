@@ -0,0 +1,81 @@
+//! Reading several collections at a single point in time.
+//!
+//! Exporting related collections one query after another may observe some
+//! writes in one collection but not in another, yielding an inconsistent
+//! export. A [`Session`](struct.Session.html) pins a cluster
+//! time when it is started; every read performed through it, i.e. by
+//! `Collection::find_many_in()` and `Collection::aggregate_in()`, uses the
+//! `snapshot` read concern at that time, so all of them observe the data
+//! exactly as it was when the session started.
+//!
+//! Snapshot reads require a replica set or a sharded cluster running MongoDB
+//! 5.0 or newer. The server only keeps the history needed for a snapshot
+//! for a limited time (`minSnapshotHistoryWindowInSeconds`, 5 minutes by
+//! default); reads of a session older than that fail with an error. Since
+//! the driver has no session support, results are read eagerly.
+
+use bson::{ Bson, Document };
+use mongodb::CommandType;
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::error::{ Error, ErrorKind::MissingDocumentField, Result, ResultExt };
+
+/// A point in time which reads can be pinned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// The cluster time all reads of the session observe.
+    at_cluster_time: Bson,
+}
+
+impl Session {
+    /// Starts a session at the current cluster time, as reported by the
+    /// server in reply to a `ping`.
+    pub fn start(db: &Database) -> Result<Self> {
+        let mut reply = db
+            .command(doc!{ "ping": 1 }, CommandType::Suppressed, None)
+            .chain("can't start snapshot session")?;
+
+        reply
+            .remove("operationTime")
+            .map(Self::at_cluster_time)
+            .ok_or_else(|| Error::new(
+                MissingDocumentField,
+                "no `operationTime` in server reply; snapshot reads require a replica set"
+            ))
+    }
+
+    /// Creates a session pinned to a known cluster time, e.g. the
+    /// `operationTime` of an earlier write, given as a BSON timestamp.
+    pub fn at_cluster_time(at_cluster_time: Bson) -> Self {
+        Session { at_cluster_time }
+    }
+
+    /// Returns the cluster time the session is pinned to.
+    pub fn cluster_time(&self) -> &Bson {
+        &self.at_cluster_time
+    }
+
+    /// Returns the read concern to be sent with every read of the session.
+    pub fn read_concern(&self) -> Document {
+        doc!{
+            "level": "snapshot",
+            "atClusterTime": self.at_cluster_time.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Bson;
+    use super::Session;
+
+    #[test]
+    fn read_concern() {
+        let session = Session::at_cluster_time(Bson::TimeStamp(42 << 32 | 7));
+
+        assert_eq!(session.cluster_time(), &Bson::TimeStamp(42 << 32 | 7));
+        assert_eq!(session.read_concern(), doc!{
+            "level": "snapshot",
+            "atClusterTime": Bson::TimeStamp(42 << 32 | 7),
+        });
+    }
+}
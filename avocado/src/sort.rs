@@ -0,0 +1,172 @@
+//! Typed sort specifications.
+//!
+//! A [`Sort`](struct.Sort.html) lists the fields by which the
+//! results of a query are sorted, in decreasing order of precedence, each
+//! one either in an [`Order`](../literal/enum.Order.html) or by the
+//! relevance of a full-text search match. A query applies it by overriding
+//! `Query::sort()`:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::sort::Sort;
+//! # use avocado::literal::Order;
+//! # use bson::Document;
+//! #
+//! # fn main() {
+//! let sort = Sort::new()
+//!     .text_score("score")
+//!     .by("established.year", Order::Descending)
+//!     .ascending("name");
+//!
+//! assert_eq!(Document::from(sort.clone()), doc!{
+//!     "score": { "$meta": "textScore" },
+//!     "established.year": -1,
+//!     "name": 1,
+//! });
+//! assert_eq!(Document::from(sort.reversed()), doc!{
+//!     "score": { "$meta": "textScore" },
+//!     "established.year": 1,
+//!     "name": -1,
+//! });
+//! # }
+//! ```
+//!
+//! A compound index can only support a sort in which the fields appear in
+//! the same order as in the index, with either the same direction as in the
+//! index for each field, or the opposite one for each field. `reversed()`
+//! produces the latter from the former.
+
+use bson::{ Bson, Document };
+use crate::literal::Order;
+
+/// The sort key of a single field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// Sort by the value of the field, in the given order.
+    Value(Order),
+    /// Sort by decreasing relevance of a `$text` query. The field must also
+    /// be projected as `{ $meta: "textScore" }`; see
+    /// `Projection::text_score()`.
+    TextScore,
+}
+
+impl From<Order> for Key {
+    fn from(order: Order) -> Self {
+        Key::Value(order)
+    }
+}
+
+impl From<Key> for Bson {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Value(order) => order.into(),
+            Key::TextScore => bson!({ "$meta": "textScore" }),
+        }
+    }
+}
+
+/// The fields by which the results of a query are sorted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Sort {
+    /// The fields and their sort keys, most significant first.
+    keys: Vec<(String, Key)>,
+}
+
+impl Sort {
+    /// Returns an empty sort order, which leaves the results unsorted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sorts by the field, after the previously added fields. If the field
+    /// has already been added, its sort key is replaced instead.
+    pub fn by<S: Into<String>, K: Into<Key>>(mut self, field: S, key: K) -> Self {
+        let path = field.into();
+        let new_key = key.into();
+
+        match self.keys.iter_mut().find(|&&mut (ref name, _)| *name == path) {
+            Some(&mut (_, ref mut old_key)) => *old_key = new_key,
+            None => self.keys.push((path, new_key)),
+        }
+
+        self
+    }
+
+    /// Sorts by the field in ascending order.
+    pub fn ascending<S: Into<String>>(self, field: S) -> Self {
+        self.by(field, Order::Ascending)
+    }
+
+    /// Sorts by the field in descending order.
+    pub fn descending<S: Into<String>>(self, field: S) -> Self {
+        self.by(field, Order::Descending)
+    }
+
+    /// Sorts by the text score projected as the field.
+    pub fn text_score<S: Into<String>>(self, field: S) -> Self {
+        self.by(field, Key::TextScore)
+    }
+
+    /// Returns the sort order with the direction of every field flipped.
+    /// Text scores are always sorted in decreasing order, so they are left
+    /// as-is.
+    pub fn reversed(self) -> Self {
+        let keys = self.keys.into_iter().map(|(field, key)| {
+            let flipped = match key {
+                Key::Value(Order::Ascending) => Key::Value(Order::Descending),
+                Key::Value(Order::Descending) => Key::Value(Order::Ascending),
+                Key::TextScore => Key::TextScore,
+            };
+            (field, flipped)
+        });
+
+        Sort { keys: keys.collect() }
+    }
+
+    /// Returns `true` if there are no fields to sort by.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the fields and their sort keys, most significant first.
+    pub fn keys(&self) -> &[(String, Key)] {
+        &self.keys
+    }
+}
+
+impl From<Sort> for Document {
+    fn from(sort: Sort) -> Self {
+        sort.keys.into_iter().map(|(field, key)| (field, key.into())).collect()
+    }
+}
+
+impl From<Sort> for Bson {
+    fn from(sort: Sort) -> Self {
+        Bson::Document(sort.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Document;
+    use crate::literal::Order;
+    use super::{ Sort, Key };
+
+    #[test]
+    fn replaces_existing_field() {
+        let sort = Sort::new()
+            .ascending("a")
+            .descending("b")
+            .by("a", Order::Descending);
+
+        assert_eq!(sort.keys(), &[
+            (String::from("a"), Key::Value(Order::Descending)),
+            (String::from("b"), Key::Value(Order::Descending)),
+        ][..]);
+        assert_eq!(Document::from(sort), doc!{ "a": -1, "b": -1 });
+        assert!(Sort::new().is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+//! Separating the data of the tenants of a multi-tenant application.
+//!
+//! A [`ScopedDatabase`](struct.ScopedDatabase.html) hands out the
+//! collections of a single [`Tenant`](struct.Tenant.html), so that the
+//! naming convention of per-tenant collections or databases is stated once,
+//! where the tenant of a request is determined, rather than at every query
+//! site. Depending on the [`Isolation`](enum.Isolation.html), a tenant owns
+//! either a set of prefixed collections in a shared database, or a database
+//! of its own.
+//!
+//! For separating tenants sharing the same collections by a field of the
+//! documents instead, see the [`scope`](../scope/index.html) module.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::tenant::{ Tenant, Isolation, ScopedDatabase };
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct Invoice {
+//!     _id: Uid<Invoice>,
+//!     total: f64,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! let db = client.db("billing");
+//! let tenant = Tenant::new("acme")?;
+//! let scoped = ScopedDatabase::new(&db, tenant, Isolation::CollectionPrefix);
+//!
+//! // Operates on the collection `acme_Invoice` of the database `billing`.
+//! let invoices: Collection<Invoice> = scoped.existing_collection();
+//! let count = invoices.count(doc!{})?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{ Debug, Display, Formatter, Result as FmtResult };
+use mongodb::ThreadedClient;
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::{
+    coll::Collection,
+    db::DatabaseExt,
+    doc::Doc,
+    error::{ Error, ErrorKind, Result, ResultExt },
+};
+
+#[cfg(feature = "schema_validation")]
+use magnet_schema::BsonSchema;
+#[cfg(feature = "schema_validation")]
+use crate::uid::Uid;
+
+/// The separator between the key of a tenant and the rest of a name.
+const SEPARATOR: char = '_';
+
+/// The key identifying a tenant, e.g. the name of a customer. It consists
+/// of ASCII letters, digits and hyphens only, so that it can be embedded in
+/// database and collection names unambiguously.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tenant {
+    /// The key of the tenant.
+    key: String,
+}
+
+impl Tenant {
+    /// Creates a tenant with the given key. Returns an error if the key is
+    /// empty or it contains characters other than ASCII letters, digits
+    /// and hyphens.
+    pub fn new<S: Into<String>>(key: S) -> Result<Self> {
+        let string = key.into();
+        let valid = !string.is_empty()
+            && string.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+
+        if valid {
+            Ok(Tenant { key: string })
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidTenant,
+                format!("tenant key {:?} must consist of ASCII letters, digits and hyphens", string)
+            ))
+        }
+    }
+
+    /// Returns the key of the tenant.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns `name` prefixed with the key of the tenant.
+    fn prefixed(&self, name: &str) -> String {
+        format!("{}{}{}", self.key, SEPARATOR, name)
+    }
+}
+
+impl Display for Tenant {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(&self.key)
+    }
+}
+
+/// How the data of the tenants is separated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Isolation {
+    /// Every tenant has its own collections in the shared database, named
+    /// `<tenant>_<T::NAME>`.
+    CollectionPrefix,
+    /// Every tenant has a database of its own on the same client, named
+    /// `<database>_<tenant>`, containing collections named `T::NAME`.
+    Database,
+}
+
+/// A database restricted to the collections of a single tenant.
+///
+/// It is cheap to create, so it can be created for every request.
+#[derive(Clone)]
+pub struct ScopedDatabase {
+    /// The database containing the collections of the tenant.
+    db: Database,
+    /// The tenant owning the collections.
+    tenant: Tenant,
+    /// How the collections of the tenant are separated from the others.
+    isolation: Isolation,
+}
+
+impl ScopedDatabase {
+    /// Restricts the `shared` database to the collections of `tenant`.
+    /// With `Isolation::Database`, the database of the tenant is selected
+    /// on the client of `shared`.
+    pub fn new(shared: &Database, tenant: Tenant, isolation: Isolation) -> Self {
+        let db = match isolation {
+            Isolation::CollectionPrefix => shared.clone(),
+            Isolation::Database => {
+                shared.client.db(&format!("{}{}{}", shared.name, SEPARATOR, tenant.key))
+            }
+        };
+
+        ScopedDatabase { db, tenant, isolation }
+    }
+
+    /// Returns the tenant owning the collections.
+    pub fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+
+    /// Returns how the collections of the tenant are separated.
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /// Returns the database containing the collections of the tenant.
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Returns the name of the collection of `T` belonging to the tenant.
+    pub fn collection_name<T: Doc>(&self) -> String {
+        match self.isolation {
+            Isolation::CollectionPrefix => self.tenant.prefixed(T::NAME),
+            Isolation::Database => T::NAME.into(),
+        }
+    }
+
+    /// Returns the existing collection of `T` belonging to the tenant.
+    pub fn existing_collection<T: Doc>(&self) -> Collection<T> {
+        self.db.existing_collection_named(&self.collection_name::<T>())
+    }
+
+    /// Creates the empty collection of `T` belonging to the tenant, with
+    /// the `$jsonSchema` validator and the indexes of `T`. **Drops any
+    /// existing collection of `T` of the tenant.**
+    #[cfg(feature = "schema_validation")]
+    pub fn empty_collection<T>(&self) -> Result<Collection<T>>
+        where T: Doc + BsonSchema,
+              Uid<T>: BsonSchema,
+    {
+        self.db.empty_collection_named(&self.collection_name::<T>())
+    }
+
+    /// Creates the empty collection of `T` belonging to the tenant, with
+    /// the indexes of `T` but without a validator. **Drops any existing
+    /// collection of `T` of the tenant.**
+    pub fn empty_collection_novalidate<T: Doc>(&self) -> Result<Collection<T>> {
+        self.db.empty_collection_novalidate_named(&self.collection_name::<T>())
+    }
+
+    /// Drops the collection of `T` belonging to the tenant. Succeeds if the
+    /// collection doesn't exist.
+    pub fn drop_collection_of<T: Doc>(&self) -> Result<()> {
+        let name = self.collection_name::<T>();
+
+        self.db
+            .drop_collection(&name)
+            .chain(|| format!("error dropping collection {}", name))
+    }
+
+    /// Returns the names of all collections belonging to the tenant.
+    pub fn collection_names(&self) -> Result<Vec<String>> {
+        let message = || format!("error listing collections of tenant {}", self.tenant);
+
+        match self.isolation {
+            Isolation::CollectionPrefix => {
+                let prefix = self.tenant.prefixed("");
+                let filter = doc!{ "name": { "$regex": format!("^{}", prefix) } };
+                let names = self.db.collection_names(Some(filter)).chain(&message)?;
+
+                Ok(names.into_iter().filter(|name| name.starts_with(&prefix)).collect())
+            }
+            Isolation::Database => self.db.collection_names(None).chain(&message),
+        }
+    }
+
+    /// Drops all data of the tenant: its collections, or its database.
+    pub fn drop_tenant(&self) -> Result<()> {
+        let message = || format!("error dropping data of tenant {}", self.tenant);
+
+        match self.isolation {
+            Isolation::CollectionPrefix => {
+                for name in self.collection_names()? {
+                    self.db.drop_collection(&name).chain(&message)?;
+                }
+                Ok(())
+            }
+            Isolation::Database => self.db.drop_database().chain(&message),
+        }
+    }
+}
+
+impl Debug for ScopedDatabase {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("ScopedDatabase")
+            .field("db", &self.db.name)
+            .field("tenant", &self.tenant)
+            .field("isolation", &self.isolation)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tenant;
+
+    #[test]
+    fn tenant_key() {
+        assert_eq!(Tenant::new("acme-42").unwrap().key(), "acme-42");
+        assert_eq!(Tenant::new("acme").unwrap().prefixed("Invoice"), "acme_Invoice");
+
+        for key in &["", "acme_corp", "acme.corp", "acme corp", "$acme", "\u{e1}cme"] {
+            assert!(Tenant::new(*key).is_err(), "{:?} was accepted", key);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Assertion helpers for integration tests.
+//!
+//! These helpers inspect the query plan reported by the server (see
+//! `Collection::explain()`), so that a test fails as soon as a query
+//! regresses to a collection scan, e.g. after an index or a filter has been
+//! changed inadvertently.
+//!
+//! With the `testing` feature, `#[avocado(factory)]`
+//! generates a `{Type}Factory` for a `Doc` type, which builds values with
+//! fake data, and lets individual fields be overridden before inserting:
+//!
+//! ```ignore
+//! #[derive(Debug, Serialize, Deserialize, Doc)]
+//! #[avocado(factory)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     email: String,
+//!     admin: bool,
+//! }
+//!
+//! let user = UserFactory::new().email("x@y.z").insert(&users)?;
+//! let admin = UserFactory::new().with(|user| user.admin = true).build();
+//! ```
+//!
+//! The fake value of each field is provided by the [`Fake`](trait.Fake.html)
+//! trait, which has to be implemented for any other field types.
+//!
+//! A [`TestDb`](struct.TestDb.html) is a uniquely-named database which is
+//! dropped at the end of the test, so that tests don't leak databases nor
+//! collide with each other.
+
+use std::{ env, process, thread };
+use std::ops::Deref;
+use std::convert::TryFrom;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::collections::{ HashMap, BTreeMap, HashSet, BTreeSet };
+use std::hash::Hash;
+use std::marker::PhantomData;
+use bson::{ Document, oid::ObjectId };
+use chrono::{ DateTime, Utc };
+#[cfg(feature = "raw_uuid")]
+use uuid::Uuid;
+use mongodb::{ Client, ThreadedClient };
+use mongodb::db::Database;
+#[cfg(feature = "schema_validation")]
+use magnet_schema::BsonSchema;
+use crate::{
+    coll::Collection,
+    db::DatabaseExt,
+    doc::Doc,
+    uid::Uid,
+    error::Result,
+    ops::Query,
+    explain::{ COLLECTION_SCAN_STAGE, INDEX_SCAN_STAGE, visit_stages },
+};
+
+/// Returns the names of all stages of the winning plan in an `explain` reply,
+/// in depth-first order, starting with the root stage.
+pub fn plan_stages(explain: &Document) -> Vec<String> {
+    let mut stages = Vec::new();
+
+    if let Some(plan) = winning_plan(explain) {
+        visit_stages(plan, &mut |stage| {
+            if let Ok(name) = stage.get_str("stage") {
+                stages.push(name.to_owned());
+            }
+        });
+    }
+
+    stages
+}
+
+/// Returns the names of the indexes scanned by the winning plan in an
+/// `explain` reply. The result is empty if the query scans the collection.
+pub fn used_indexes(explain: &Document) -> Vec<String> {
+    let mut indexes = Vec::new();
+
+    if let Some(plan) = winning_plan(explain) {
+        visit_stages(plan, &mut |stage| {
+            if stage.get_str("stage").ok() == Some(INDEX_SCAN_STAGE) {
+                if let Ok(name) = stage.get_str("indexName") {
+                    indexes.push(name.to_owned());
+                }
+            }
+        });
+    }
+
+    indexes
+}
+
+/// Asserts that the server would execute the query using the named index.
+///
+/// # Panics
+///
+/// If the query can't be explained, or if the winning plan doesn't scan
+/// the named index. The panic message contains the stages of the plan.
+pub fn assert_uses_index<T, Q>(collection: &Collection<T>, query: Q, index_name: &str)
+    where T: Doc,
+          Q: Query<T>,
+{
+    let explain = collection.explain(query).unwrap_or_else(
+        |error| panic!("can't explain query on {}: {}", T::NAME, error)
+    );
+    let indexes = used_indexes(&explain);
+
+    assert!(
+        indexes.iter().any(|name| name == index_name),
+        "query on {} doesn't use index `{}`; indexes used: {:?}, plan stages: {:?}",
+        T::NAME, index_name, indexes, plan_stages(&explain)
+    );
+}
+
+/// Asserts that the server would execute the query without scanning the
+/// whole collection, i.e. using some index.
+///
+/// # Panics
+///
+/// If the query can't be explained, or if the winning plan contains a
+/// collection scan. The panic message contains the stages of the plan.
+pub fn assert_no_collection_scan<T, Q>(collection: &Collection<T>, query: Q)
+    where T: Doc,
+          Q: Query<T>,
+{
+    let explain = collection.explain(query).unwrap_or_else(
+        |error| panic!("can't explain query on {}: {}", T::NAME, error)
+    );
+    let stages = plan_stages(&explain);
+
+    assert!(
+        stages.iter().all(|stage| stage != COLLECTION_SCAN_STAGE),
+        "query on {} scans the collection; plan stages: {:?}",
+        T::NAME, stages
+    );
+}
+
+/// The environment variable which, if set, makes a `TestDb` keep its
+/// database when it is dropped during a panic, i.e. when the test fails, so
+/// that the data can be inspected.
+pub const KEEP_ON_FAILURE_VAR: &str = "AVOCADO_KEEP_TEST_DB";
+
+/// The sequence number of the next `TestDb` in the process.
+static NEXT_TEST_DB: AtomicUsize = AtomicUsize::new(0);
+
+/// A throwaway database with a unique name, dropped along with all of its
+/// collections when the `TestDb` goes out of scope. Tests using separate
+/// instances don't interfere with each other, even if they run in parallel
+/// and use the same document types.
+///
+/// If the environment variable named by `KEEP_ON_FAILURE_VAR` is set, the
+/// database of a failed test is kept, and its name is printed to `stderr`.
+///
+/// ```no_run
+/// # #[macro_use]
+/// # extern crate serde_derive;
+/// # #[macro_use]
+/// # extern crate avocado_derive;
+/// # extern crate avocado;
+/// #
+/// # use avocado::prelude::*;
+/// # use avocado::testing::TestDb;
+/// #
+/// #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+/// struct Answer {
+///     _id: Uid<Answer>,
+///     value: i32,
+/// }
+///
+/// # fn main() -> AvocadoResult<()> {
+/// let client = Client::with_uri("mongodb://localhost:27017/")?;
+/// let db = TestDb::new(&client);
+/// let answers: Collection<Answer> = db.collection()?;
+///
+/// answers.insert_one(&Answer { _id: Uid::new_oid()?, value: 42 })?;
+/// assert_eq!(answers.count(doc!{})?, 1);
+/// # Ok(())
+/// # } // the database is dropped here
+/// ```
+pub struct TestDb {
+    /// The client the database was created with, used for dropping it.
+    client: Client,
+    /// The database itself.
+    db: Database,
+    /// The unique name of the database.
+    name: String,
+}
+
+impl TestDb {
+    /// Creates a database with a unique name. The server creates it lazily,
+    /// upon the first write.
+    pub fn new(client: &Client) -> Self {
+        let now = Utc::now();
+        let name = format!(
+            "avocado_test_{}_{}{:03}_{}",
+            process::id(),
+            now.timestamp(),
+            now.timestamp_subsec_millis(),
+            NEXT_TEST_DB.fetch_add(1, Ordering::SeqCst),
+        );
+        let db = client.db(&name);
+
+        TestDb { client: client.clone(), db, name }
+    }
+
+    /// Returns the unique name of the database.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns an empty collection of `T`, with the indexes, collation and
+    /// size limits of `T`, but without a schema validator.
+    pub fn collection<T: Doc>(&self) -> Result<Collection<T>> {
+        self.db.empty_collection_novalidate()
+    }
+
+    /// Returns an empty collection of `T`, with the `$jsonSchema` validator
+    /// based on the `BsonSchema` impl of `T`, and with the indexes of `T`.
+    #[cfg(feature = "schema_validation")]
+    pub fn validated_collection<T>(&self) -> Result<Collection<T>>
+        where T: Doc + BsonSchema,
+              Uid<T>: BsonSchema,
+    {
+        self.db.empty_collection()
+    }
+}
+
+impl Deref for TestDb {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl Debug for TestDb {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        formatter.debug_struct("TestDb").field("name", &self.name).finish()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        if thread::panicking() && env::var_os(KEEP_ON_FAILURE_VAR).is_some() {
+            eprintln!("keeping database `{}` of the failed test", self.name);
+            return;
+        }
+
+        // Errors can't be reported from `drop()`, and a database left behind
+        // is harmless, so a failure to drop it is ignored.
+        let _ = self.client.drop_database(&self.name);
+    }
+}
+
+/// The sequence number of the next value built by a factory.
+static NEXT_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a number which is different for every factory-built value in
+/// the process, so that fake values of fields with a unique index don't
+/// collide.
+pub fn next_sequence() -> u64 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst) as u64
+}
+
+/// Types that can produce a fake value for a field, used by the factories
+/// generated by `#[avocado(factory)]`.
+pub trait Fake: Sized {
+    /// Returns a fake value for the field named `field` of the value with
+    /// sequence number `seq` (see `next_sequence()`).
+    fn fake(field: &str, seq: u64) -> Self;
+}
+
+impl Fake for String {
+    /// E.g. `"email-42"`.
+    fn fake(field: &str, seq: u64) -> Self {
+        format!("{}-{}", field, seq)
+    }
+}
+
+impl Fake for bool {
+    fn fake(_: &str, _: u64) -> Self {
+        false
+    }
+}
+
+/// Implements `Fake` for integer types, returning the sequence number,
+/// which saturates at the maximum of the narrower types.
+macro_rules! impl_fake_integer {
+    ($($ty:ty),*) => {$(
+        impl Fake for $ty {
+            fn fake(_: &str, seq: u64) -> Self {
+                <$ty>::try_from(seq).unwrap_or_else(|_| <$ty>::max_value())
+            }
+        }
+    )*}
+}
+
+impl_fake_integer! { i8, i16, i32, i64, u8, u16, u32, isize, usize }
+
+/// Implements `Fake` for floating-point types, returning the sequence number.
+macro_rules! impl_fake_float {
+    ($($ty:ty),*) => {$(
+        impl Fake for $ty {
+            #[allow(clippy::cast_precision_loss)]
+            fn fake(_: &str, seq: u64) -> Self {
+                seq as $ty
+            }
+        }
+    )*}
+}
+
+impl_fake_float! { f32, f64 }
+
+impl Fake for u64 {
+    fn fake(_: &str, seq: u64) -> Self {
+        seq
+    }
+}
+
+impl<T> Fake for Option<T> {
+    fn fake(_: &str, _: u64) -> Self {
+        None
+    }
+}
+
+impl<T> Fake for Vec<T> {
+    fn fake(_: &str, _: u64) -> Self {
+        Vec::new()
+    }
+}
+
+impl<K: Eq + Hash, V> Fake for HashMap<K, V> {
+    fn fake(_: &str, _: u64) -> Self {
+        HashMap::new()
+    }
+}
+
+impl<K: Ord, V> Fake for BTreeMap<K, V> {
+    fn fake(_: &str, _: u64) -> Self {
+        BTreeMap::new()
+    }
+}
+
+impl<T: Eq + Hash> Fake for HashSet<T> {
+    fn fake(_: &str, _: u64) -> Self {
+        HashSet::new()
+    }
+}
+
+impl<T: Ord> Fake for BTreeSet<T> {
+    fn fake(_: &str, _: u64) -> Self {
+        BTreeSet::new()
+    }
+}
+
+impl<T: ?Sized> Fake for PhantomData<T> {
+    fn fake(_: &str, _: u64) -> Self {
+        PhantomData
+    }
+}
+
+impl Fake for ObjectId {
+    /// A freshly generated `ObjectId`. If that fails, one made up of the
+    /// sequence number.
+    fn fake(_: &str, seq: u64) -> Self {
+        ObjectId::new().unwrap_or_else(|_| {
+            let mut bytes = [0; 12];
+            bytes[4..].copy_from_slice(&seq.to_be_bytes());
+            ObjectId::with_bytes(bytes)
+        })
+    }
+}
+
+#[cfg(feature = "raw_uuid")]
+impl Fake for Uuid {
+    fn fake(_: &str, _: u64) -> Self {
+        Uuid::new_v4()
+    }
+}
+
+impl<T> Fake for Uid<T> where T: Doc, T::Id: Fake {
+    fn fake(field: &str, seq: u64) -> Self {
+        Uid::from_raw(T::Id::fake(field, seq))
+    }
+}
+
+impl Fake for DateTime<Utc> {
+    fn fake(_: &str, _: u64) -> Self {
+        Utc::now()
+    }
+}
+
+/// Extracts the winning plan from an `explain` reply.
+fn winning_plan(explain: &Document) -> Option<&Document> {
+    explain
+        .get_document("queryPlanner")
+        .and_then(|planner| planner.get_document("winningPlan"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Fake, plan_stages, used_indexes };
+
+    #[test]
+    fn index_scan_plan() {
+        let explain = doc!{
+            "queryPlanner": {
+                "winningPlan": {
+                    "stage": "FETCH",
+                    "inputStage": {
+                        "stage": "OR",
+                        "inputStages": [
+                            { "stage": "IXSCAN", "indexName": "name_1" },
+                            { "stage": "IXSCAN", "indexName": "email_1" },
+                        ],
+                    },
+                },
+            },
+            "ok": 1.0,
+        };
+
+        assert_eq!(plan_stages(&explain), ["FETCH", "OR", "IXSCAN", "IXSCAN"]);
+        assert_eq!(used_indexes(&explain), ["name_1", "email_1"]);
+    }
+
+    #[test]
+    fn collection_scan_plan() {
+        let explain = doc!{
+            "queryPlanner": {
+                "winningPlan": { "stage": "COLLSCAN", "direction": "forward" },
+            },
+            "ok": 1.0,
+        };
+
+        assert_eq!(plan_stages(&explain), ["COLLSCAN"]);
+        assert!(used_indexes(&explain).is_empty());
+        assert!(plan_stages(&doc!{ "ok": 0.0 }).is_empty());
+    }
+
+    #[test]
+    fn fake_values() {
+        assert_eq!(String::fake("email", 42), "email-42");
+        assert_eq!(i32::fake("count", 7), 7);
+        assert_eq!(Option::<String>::fake("nickname", 7), None);
+        assert!(Vec::<u8>::fake("tags", 7).is_empty());
+        assert!(!bool::fake("admin", 7));
+    }
+}
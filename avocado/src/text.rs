@@ -0,0 +1,309 @@
+//! Full-text search with relevance filtering.
+//!
+//! A [`Search`](struct.Search.html) runs a `$text` query against the
+//! text index of a collection, and returns the matching documents ordered by
+//! decreasing relevance. If a minimal score is specified, documents scoring
+//! below it are dropped by the server, so low-relevance noise never reaches
+//! the application. The relevance of matches in each field can be tuned by
+//! the `weights(...)` of the text index, e.g.
+//! `#[index(keys(title = "text", body = "text"), weights(title = 10, body = 2))]`.
+//!
+//! Since a `find` can't filter by the score, the search is executed as an
+//! aggregation pipeline, i.e. using `Collection::aggregate()`. The score of
+//! each document is returned as well by a [`ScoredTextSearch`](struct.ScoredTextSearch.html),
+//! usually run via `Collection::text_search()`.
+//!
+//! The text index can also be declared on the indexed fields themselves,
+//! by `#[avocado(text_index)]`, or `#[avocado(text_index = 10)]` for a
+//! weight other than the default, 1.
+
+use std::marker::PhantomData;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use bson::{ Bson, Document };
+use crate::{
+    doc::Doc,
+    ops::Pipeline,
+    error::{ Error, ErrorKind, Result },
+};
+
+/// The field the text score is stored in temporarily.
+const SCORE_FIELD: &str = "avocadoTextScore";
+
+/// Options of `Collection::text_search()`, the same as the optional
+/// settings of a `Search`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchOptions {
+    /// The language determining stop words and stemming. Defaults to the
+    /// `default_language` of the index if `None`.
+    pub language: Option<String>,
+    /// Documents with a text score lower than this are not returned.
+    pub min_score: Option<f64>,
+    /// Additional conditions the returned documents must satisfy.
+    pub filter: Document,
+    /// The maximal number of documents to return.
+    pub limit: Option<usize>,
+}
+
+/// A full-text search for documents of type `T`.
+pub struct Search<T: Doc> {
+    /// The terms and phrases to search for, in `$text` syntax, e.g.
+    /// `coffee "fair trade" -decaf`.
+    pub search: String,
+    /// The language determining stop words and stemming. Defaults to the
+    /// `default_language` of the index if `None`.
+    pub language: Option<String>,
+    /// Documents with a text score lower than this are not returned.
+    pub min_score: Option<f64>,
+    /// Additional conditions the returned documents must satisfy.
+    pub filter: Document,
+    /// The maximal number of documents to return.
+    pub limit: Option<usize>,
+    /// Only here so that `T` is used.
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: Doc> Search<T> {
+    /// Searches for `search`, without a minimal score or any other condition.
+    pub fn new<S: Into<String>>(search: S) -> Self {
+        Search {
+            search: search.into(),
+            language: None,
+            min_score: None,
+            filter: Document::new(),
+            limit: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Searches for `search`, with the given minimal score and conditions.
+    pub fn with_options<S: Into<String>>(search: S, options: SearchOptions) -> Self {
+        Search {
+            search: search.into(),
+            language: options.language,
+            min_score: options.min_score,
+            filter: options.filter,
+            limit: options.limit,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the text score of each document along with the document.
+    pub fn scored(self) -> ScoredTextSearch<T> {
+        ScoredTextSearch { search: self }
+    }
+
+    /// The stages of the search, which leave the text score of each
+    /// document in `SCORE_FIELD`.
+    #[allow(clippy::cast_possible_wrap)]
+    fn scored_stages(&self) -> Vec<Document> {
+        let mut text = doc!{ "$search": self.search.as_str() };
+
+        if let Some(ref language) = self.language {
+            text.insert("$language", language.as_str());
+        }
+
+        let mut filter = self.filter.clone();
+        filter.insert("$text", text);
+
+        let mut stages = vec![
+            doc!{ "$match": filter },
+            doc!{ "$addFields": { SCORE_FIELD: { "$meta": "textScore" } } },
+        ];
+
+        if let Some(min_score) = self.min_score {
+            stages.push(doc!{ "$match": { SCORE_FIELD: { "$gte": min_score } } });
+        }
+
+        stages.push(doc!{ "$sort": { SCORE_FIELD: -1 } });
+
+        if let Some(limit) = self.limit {
+            stages.push(doc!{ "$limit": limit as i64 });
+        }
+
+        stages
+    }
+
+    /// Sets the minimal text score of returned documents.
+    pub fn min_score(self, min_score: f64) -> Self {
+        Search { min_score: Some(min_score), ..self }
+    }
+
+    /// Sets the language of the search.
+    pub fn language<S: Into<String>>(self, language: S) -> Self {
+        Search { language: Some(language.into()), ..self }
+    }
+
+    /// Sets additional conditions for the returned documents.
+    pub fn filter(self, filter: Document) -> Self {
+        Search { filter, ..self }
+    }
+
+    /// Sets the maximal number of returned documents.
+    pub fn limit(self, limit: usize) -> Self {
+        Search { limit: Some(limit), ..self }
+    }
+}
+
+impl<T: Doc> Clone for Search<T> {
+    fn clone(&self) -> Self {
+        Search {
+            search: self.search.clone(),
+            language: self.language.clone(),
+            min_score: self.min_score,
+            filter: self.filter.clone(),
+            limit: self.limit,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Doc> Debug for Search<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Search")
+            .field("collection", &T::NAME)
+            .field("search", &self.search)
+            .field("language", &self.language)
+            .field("min_score", &self.min_score)
+            .field("filter", &self.filter)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+impl<T: Doc> Pipeline<T> for Search<T> {
+    type Output = T;
+
+    fn stages(&self) -> Vec<Document> {
+        let mut stages = self.scored_stages();
+
+        // The score is only needed for filtering and sorting.
+        stages.push(doc!{ "$project": { SCORE_FIELD: 0 } });
+        stages
+    }
+}
+
+/// A full-text search for documents of type `T`, yielding each document
+/// along with its text score. Created by `Search::scored()`.
+pub struct ScoredTextSearch<T: Doc> {
+    /// The underlying search.
+    search: Search<T>,
+}
+
+impl<T: Doc> Clone for ScoredTextSearch<T> {
+    fn clone(&self) -> Self {
+        ScoredTextSearch { search: self.search.clone() }
+    }
+}
+
+impl<T: Doc> Debug for ScoredTextSearch<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("ScoredTextSearch")
+            .field("search", &self.search)
+            .finish()
+    }
+}
+
+impl<T: Doc> Pipeline<T> for ScoredTextSearch<T> {
+    type Output = (T, f64);
+
+    fn stages(&self) -> Vec<Document> {
+        self.search.scored_stages()
+    }
+
+    fn transform(mut raw: Document) -> Result<Bson> {
+        let score = raw.remove(SCORE_FIELD).ok_or_else(|| Error::new(
+            ErrorKind::MissingDocumentField,
+            format!("missing `{}` field in text search result", SCORE_FIELD)
+        ))?;
+
+        Ok(Bson::Array(vec![Bson::Document(raw), score]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId };
+    use crate::{ doc::Doc, uid::Uid, ops::Pipeline };
+    use super::{ Search, SearchOptions, ScoredTextSearch };
+
+    /// A searchable document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Article {
+        /// The unique ID of the article.
+        _id: Uid<Article>,
+    }
+
+    impl Doc for Article {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Article";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self._id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self._id = id;
+        }
+    }
+
+    #[test]
+    fn stages() {
+        let search = Search::<Article>::new("coffee -decaf")
+            .language("english")
+            .min_score(1.5)
+            .filter(doc!{ "published": true })
+            .limit(10);
+
+        assert_eq!(search.stages(), vec![
+            doc!{
+                "$match": {
+                    "published": true,
+                    "$text": { "$search": "coffee -decaf", "$language": "english" },
+                }
+            },
+            doc!{ "$addFields": { "avocadoTextScore": { "$meta": "textScore" } } },
+            doc!{ "$match": { "avocadoTextScore": { "$gte": 1.5 } } },
+            doc!{ "$sort": { "avocadoTextScore": -1 } },
+            doc!{ "$limit": 10_i64 },
+            doc!{ "$project": { "avocadoTextScore": 0 } },
+        ]);
+    }
+
+    #[test]
+    fn stages_without_threshold() {
+        let search = Search::<Article>::new("coffee");
+
+        assert_eq!(search.stages(), vec![
+            doc!{ "$match": { "$text": { "$search": "coffee" } } },
+            doc!{ "$addFields": { "avocadoTextScore": { "$meta": "textScore" } } },
+            doc!{ "$sort": { "avocadoTextScore": -1 } },
+            doc!{ "$project": { "avocadoTextScore": 0 } },
+        ]);
+    }
+
+    #[test]
+    fn scored_stages_and_transform() {
+        let options = SearchOptions {
+            min_score: Some(0.5),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let search = Search::<Article>::with_options("coffee", options).scored();
+
+        assert_eq!(search.stages(), vec![
+            doc!{ "$match": { "$text": { "$search": "coffee" } } },
+            doc!{ "$addFields": { "avocadoTextScore": { "$meta": "textScore" } } },
+            doc!{ "$match": { "avocadoTextScore": { "$gte": 0.5 } } },
+            doc!{ "$sort": { "avocadoTextScore": -1 } },
+            doc!{ "$limit": 3_i64 },
+        ]);
+
+        let raw = doc!{ "_id": 1, "avocadoTextScore": 1.25 };
+
+        assert_eq!(
+            <ScoredTextSearch<Article> as Pipeline<Article>>::transform(raw).unwrap(),
+            Bson::Array(vec![Bson::from(doc!{ "_id": 1 }), Bson::from(1.25)])
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! A process-wide default time limit for read operations.
+//!
+//! Without a time limit, a single misbehaving query (e.g. one that can't use
+//! an index on a huge collection) can keep a connection and server resources
+//! busy indefinitely. Once a default is configured with
+//! [`set_default_max_time()`](fn.set_default_max_time.html), it is sent as
+//! `maxTimeMS` with every count, distinct, aggregation, query and
+//! find-and-modify operation whose options don't specify a limit themselves.
+//!
+//! ```
+//! # extern crate avocado;
+//! #
+//! # use std::time::Duration;
+//! # use avocado::timeout::{ set_default_max_time, default_max_time };
+//! #
+//! # fn main() {
+//! set_default_max_time(Some(Duration::from_secs(5)));
+//! assert_eq!(default_max_time(), Some(Duration::from_secs(5)));
+//!
+//! set_default_max_time(None);
+//! assert_eq!(default_max_time(), None);
+//! # }
+//! ```
+
+use std::time::Duration;
+use std::convert::TryFrom;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+/// The default limit in milliseconds, or 0 if there is none.
+static DEFAULT_MAX_TIME_MS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets or clears the default time limit. Limits are rounded down to whole
+/// milliseconds; a limit shorter than a millisecond clears the default.
+pub fn set_default_max_time(limit: Option<Duration>) {
+    let millis = limit.map_or(0, |max_time| {
+        let millis = max_time.as_secs()
+            .saturating_mul(1000)
+            .saturating_add(u64::from(max_time.subsec_millis()));
+
+        usize::try_from(millis).unwrap_or_else(|_| usize::max_value())
+    });
+
+    DEFAULT_MAX_TIME_MS.store(millis, Ordering::SeqCst);
+}
+
+/// Returns the default time limit, if any.
+pub fn default_max_time() -> Option<Duration> {
+    match DEFAULT_MAX_TIME_MS.load(Ordering::SeqCst) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis as u64)),
+    }
+}
+
+/// Returns the explicitly specified limit if any, otherwise the default,
+/// in milliseconds, as expected by the `max_time_ms` driver options.
+#[allow(clippy::cast_possible_wrap)]
+pub fn max_time_ms_or_default(explicit: Option<i64>) -> Option<i64> {
+    explicit.or_else(|| match DEFAULT_MAX_TIME_MS.load(Ordering::SeqCst) {
+        0 => None,
+        millis => Some((millis as u64).min(i64::max_value() as u64) as i64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::max_time_ms_or_default;
+
+    #[test]
+    fn explicit_limit_takes_precedence() {
+        assert_eq!(max_time_ms_or_default(Some(250)), Some(250));
+        assert_eq!(max_time_ms_or_default(Some(0)), Some(0));
+    }
+}
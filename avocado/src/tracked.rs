@@ -0,0 +1,194 @@
+//! Dirty tracking of entities, for writing only the fields that changed.
+//!
+//! A [`Tracked`](struct.Tracked.html) wraps an entity and remembers its
+//! serialized form as it was loaded (or last saved). The entity can then be
+//! modified freely through `DerefMut`, and `Tracked::save()` writes only the
+//! fields which differ from the remembered state, using `$set` and `$unset`.
+//! Concurrent updates of other fields of the same document are preserved,
+//! unlike with `Collection::replace_entity()`.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::tracked::Tracked;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! struct User {
+//!     _id: Uid<User>,
+//!     name: String,
+//!     email: String,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let users: Collection<User> = client.db("avocado_example_db").existing_collection();
+//! # let id: Uid<User> = Uid::new_oid()?;
+//! let user = users.find_one(doc!{ "_id": &id })?.expect("no such user");
+//! let mut user = Tracked::new(user)?;
+//!
+//! user.email = String::from("alice@example.com");
+//!
+//! assert_eq!(user.changes()?.paths(), vec!["email"]);
+//! user.save(&users)?; // { "$set": { "email": "alice@example.com" } }
+//! assert!(!user.is_dirty()?);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::{ Deref, DerefMut };
+use std::fmt::Debug;
+use bson::Document;
+use crate::{
+    coll::{ Collection, UpdateOneResult },
+    diff::{ self, ChangeSet },
+    doc::Doc,
+    error::{ Result, ResultExt },
+};
+
+/// An entity which remembers its state as it was loaded or last saved, so
+/// that only the fields modified since then are written back.
+#[derive(Debug, Clone)]
+pub struct Tracked<T> {
+    /// The current value of the entity.
+    value: T,
+    /// The serialized form of the entity when it was loaded or last saved.
+    original: Document,
+}
+
+impl<T: Doc> Tracked<T> {
+    /// Starts tracking the changes of `value`, which should be in the state
+    /// currently stored in the database.
+    pub fn new(value: T) -> Result<Self> {
+        let original = T::SERDE_PROFILE.serialize(&value)?;
+        Ok(Tracked { value, original })
+    }
+
+    /// Returns the changes of the entity since it was loaded or last saved.
+    pub fn changes(&self) -> Result<ChangeSet> {
+        let current = T::SERDE_PROFILE.serialize(&self.value)?;
+        let opaque: Vec<_> = T::encrypted_fields().iter().map(|field| field.name).collect();
+
+        Ok(diff::documents_opaque(&self.original, &current, &opaque))
+    }
+
+    /// Returns `true` if the entity was modified since it was loaded or last
+    /// saved.
+    pub fn is_dirty(&self) -> Result<bool> {
+        self.changes().map(|changes| !changes.is_empty())
+    }
+
+    /// Returns the update document writing the changes of the entity, i.e.
+    /// `$set` and `$unset` operators, or an empty document if the entity
+    /// wasn't modified.
+    pub fn update(&self) -> Result<Document> {
+        self.changes().map(|changes| changes.to_update())
+    }
+
+    /// Writes the changes of the entity to the document with its `_id`, then
+    /// considers the current state as saved. Nothing is written if the
+    /// entity wasn't modified. See `Collection::save_changes()`.
+    pub fn save(&mut self, collection: &Collection<T>) -> Result<UpdateOneResult> where T: Debug {
+        let message = || format!("error in {}::save({:#?})", T::NAME, self.value);
+        let current = T::SERDE_PROFILE.serialize(&self.value).chain(&message)?;
+        let result = collection.save_document_changes(
+            self.original.clone(), current.clone(), &message
+        )?;
+
+        self.original = current;
+
+        Ok(result)
+    }
+
+    /// Discards the record of the changes, considering the current state of
+    /// the entity as saved, e.g. after it was written by other means.
+    pub fn mark_clean(&mut self) -> Result<()> {
+        self.original = T::SERDE_PROFILE.serialize(&self.value)?;
+        Ok(())
+    }
+
+    /// Returns the entity, ending the tracking of its changes.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> AsRef<T> for Tracked<T> {
+    fn as_ref(&self) -> &T {
+        &self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uid::Uid;
+    use crate::doc::Doc;
+    use super::Tracked;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct User {
+        #[serde(rename = "_id")]
+        id: Uid<User>,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        email: Option<String>,
+    }
+
+    impl Doc for User {
+        type Id = String;
+
+        const NAME: &'static str = "User";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn tracks_changes() {
+        let user = User {
+            id: Uid::from_raw(String::from("alice")),
+            name: String::from("Alice"),
+            email: Some(String::from("alice@example.com")),
+        };
+        let mut tracked = Tracked::new(user).unwrap();
+
+        assert!(!tracked.is_dirty().unwrap());
+        assert!(tracked.update().unwrap().is_empty());
+
+        tracked.name = String::from("Alice B.");
+        tracked.email = None;
+
+        assert!(tracked.is_dirty().unwrap());
+        assert_eq!(tracked.update().unwrap(), doc!{
+            "$set": { "name": "Alice B." },
+            "$unset": { "email": "" },
+        });
+
+        tracked.mark_clean().unwrap();
+        assert!(!tracked.is_dirty().unwrap());
+        assert_eq!(tracked.into_inner().name, "Alice B.");
+    }
+}
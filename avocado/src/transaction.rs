@@ -0,0 +1,204 @@
+//! Multi-document transactions.
+//!
+//! A [`Session`](struct.Session.html) is a server-side logical session. Its
+//! `with_transaction()` method runs a closure in a
+//! [`Transaction`](struct.Transaction.html): the reads and writes performed
+//! through the transaction, i.e. by the `..._in()` methods of `Collection`,
+//! are committed atomically if the closure succeeds, and aborted if it
+//! fails. The collections may be of different document types, and even in
+//! different databases of the same client.
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! # #[derive(Debug, Serialize, Deserialize, Doc)]
+//! # struct Order { _id: Uid<Order>, customer: String }
+//! #
+//! # #[derive(Debug, Serialize, Deserialize, Doc)]
+//! # struct CartItem { _id: Uid<CartItem>, customer: String }
+//! #
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let orders: Collection<Order> = db.existing_collection();
+//! let cart: Collection<CartItem> = db.existing_collection();
+//! let order = Order { _id: Uid::new_oid()?, customer: String::from("alice") };
+//!
+//! // Either the order is placed and the cart is emptied, or neither happens
+//! db.transaction(|txn| {
+//!     orders.insert_one_in(txn, &order)?;
+//!     cart.delete_many_in(txn, doc!{ "customer": order.customer.as_str() })?;
+//!     Ok(())
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Transactions require a replica set or a sharded cluster running MongoDB
+//! 4.0 (4.2 for sharded clusters) or newer. Since the driver has no session
+//! support, the session fields are added to the commands by Avocado, and
+//! reads in a transaction are performed eagerly, like snapshot reads.
+//! Failed transactions aren't retried automatically: if the error has the
+//! `TransientTransactionError` label, the whole transaction may be retried
+//! by calling `with_transaction()` again.
+
+use std::cell::Cell;
+use std::fmt::Debug;
+use bson::{ Bson, Document };
+use mongodb::{ CommandType, ThreadedClient };
+use mongodb::db::{ Database, ThreadedDatabase };
+use crate::{
+    snapshot,
+    error::{ Error, ErrorKind::MissingDocumentField, Result, ResultExt },
+};
+
+/// A context in which reads can be performed: a snapshot session or a
+/// transaction.
+pub trait ReadSession: Debug {
+    /// Adds the fields attaching a cursor-returning read command (`find` or
+    /// `aggregate`) to the context.
+    fn attach(&self, command: &mut Document);
+
+    /// Adds the fields attaching a `getMore` command to the context.
+    /// Does nothing by default.
+    fn attach_get_more(&self, _command: &mut Document) {}
+}
+
+impl ReadSession for snapshot::Session {
+    fn attach(&self, command: &mut Document) {
+        command.insert("readConcern", self.read_concern());
+    }
+}
+
+/// A server-side logical session, which transactions can be run in.
+/// The session is ended when it is dropped.
+#[derive(Debug)]
+pub struct Session {
+    /// Any database of the client, used for running the session commands.
+    db: Database,
+    /// The session ID, `lsid`, assigned by the server.
+    id: Document,
+    /// The number of the last transaction started in this session.
+    txn_number: i64,
+}
+
+impl Session {
+    /// Starts a new session using the `startSession` command.
+    pub fn start(db: &Database) -> Result<Self> {
+        let mut reply = db
+            .command(doc!{ "startSession": 1 }, CommandType::Suppressed, None)
+            .chain("can't start session")?;
+        let id = match reply.remove("id") {
+            Some(Bson::Document(id)) => id,
+            _ => return Err(Error::new(MissingDocumentField, "no `id` in reply of `startSession`")),
+        };
+
+        Ok(Session { db: db.clone(), id, txn_number: 0 })
+    }
+
+    /// Returns the session ID assigned by the server.
+    pub fn id(&self) -> &Document {
+        &self.id
+    }
+
+    /// Runs `f` in a new transaction. If it returns `Ok`, the transaction is
+    /// committed; otherwise, it is aborted and the error of `f` is returned.
+    pub fn with_transaction<F, R>(&mut self, f: F) -> Result<R>
+        where F: FnOnce(&Transaction) -> Result<R>
+    {
+        self.txn_number += 1;
+
+        let txn = Transaction {
+            session: &*self,
+            txn_number: self.txn_number,
+            started: Cell::new(false),
+        };
+
+        match f(&txn) {
+            Ok(value) => {
+                txn.finish("commitTransaction").chain("can't commit transaction")?;
+                Ok(value)
+            }
+            Err(error) => {
+                // The original error is more informative than a failure to
+                // abort, and the server aborts the transaction eventually.
+                txn.finish("abortTransaction").ok();
+                Err(error)
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Sessions expire on the server anyway, so failure is harmless.
+        self.db.client
+            .db("admin")
+            .command(doc!{ "endSessions": [self.id.clone()] }, CommandType::Suppressed, None)
+            .ok();
+    }
+}
+
+/// A transaction in progress, passed to the closure run by
+/// `Session::with_transaction()`.
+#[derive(Debug)]
+pub struct Transaction<'s> {
+    /// The session the transaction belongs to.
+    session: &'s Session,
+    /// The number of the transaction within the session.
+    txn_number: i64,
+    /// Whether a command has been sent as part of the transaction, i.e.
+    /// whether the server knows about the transaction.
+    started: Cell<bool>,
+}
+
+impl<'s> Transaction<'s> {
+    /// Adds the session and transaction fields to a command, so that it is
+    /// executed as part of the transaction. The first command also starts
+    /// the transaction on the server.
+    pub fn attach_command(&self, command: &mut Document) {
+        command.insert("lsid", self.session.id.clone());
+        command.insert("txnNumber", self.txn_number);
+        command.insert("autocommit", false);
+
+        if !self.started.replace(true) {
+            command.insert("startTransaction", true);
+        }
+    }
+
+    /// Commits or aborts the transaction, unless it hasn't even started.
+    fn finish(&self, command_name: &str) -> Result<()> {
+        if !self.started.get() {
+            return Ok(());
+        }
+
+        let mut command = Document::new();
+        command.insert(command_name, 1);
+        command.insert("lsid", self.session.id.clone());
+        command.insert("txnNumber", self.txn_number);
+        command.insert("autocommit", false);
+
+        self.session.db.client
+            .db("admin")
+            .command(command, CommandType::Suppressed, None)
+            .map(drop)
+            .map_err(From::from)
+    }
+}
+
+impl<'s> ReadSession for Transaction<'s> {
+    fn attach(&self, command: &mut Document) {
+        self.attach_command(command);
+    }
+
+    fn attach_get_more(&self, command: &mut Document) {
+        self.attach_command(command);
+    }
+}
+
@@ -14,7 +14,7 @@ use serde::{
 use bson::{ Bson, oid::ObjectId };
 use crate::{
     doc::Doc,
-    error::Error,
+    error::{ Error, ErrorKind },
 };
 
 #[cfg(feature = "schema_validation")]
@@ -39,6 +39,66 @@ impl<T: Doc> Uid<T> {
     pub fn into_raw(self) -> T::Id {
         self.0
     }
+
+    /// Reinterprets the ID as the ID of a document of type `U`. This is only
+    /// possible if `T` declares that it shares its IDs with `U`.
+    pub fn cast<U: Doc>(self) -> Uid<U> where T: SharesId<U> {
+        Uid(self.0)
+    }
+}
+
+/// A marker trait declaring that documents of type `Self` are stored with
+/// the same `_id` as a related document of type `U`, e.g. the settings of a
+/// user with the ID of the user, so that their IDs can be converted into
+/// each other using `Uid::cast()`. Without it, `Uid<T>` and `Uid<U>` can't
+/// be mixed up accidentally.
+pub trait SharesId<U: Doc>: Doc<Id = <U as Doc>::Id> {}
+
+/// Raw ID types which have a canonical textual representation, from which
+/// they can be parsed by `Uid::from_str()`.
+pub trait ParseId: Sized {
+    /// Parses the textual representation of the ID.
+    fn parse_id(string: &str) -> Result<Self, Error>;
+}
+
+/// An `ObjectId` is parsed from its 24-digit hexadecimal representation,
+/// which is also what it is `Display`ed as.
+impl ParseId for ObjectId {
+    fn parse_id(string: &str) -> Result<Self, Error> {
+        ObjectId::with_string(string).map_err(Into::into)
+    }
+}
+
+impl ParseId for String {
+    fn parse_id(string: &str) -> Result<Self, Error> {
+        Ok(string.into())
+    }
+}
+
+/// Implements `ParseId` for integer types using their `FromStr` impl.
+macro_rules! impl_parse_id_int {
+    ($($ty:ty),*) => {$(
+        impl ParseId for $ty {
+            fn parse_id(string: &str) -> Result<Self, Error> {
+                string.parse().map_err(|_| Error::new(
+                    ErrorKind::BsonDecoding,
+                    format!("invalid {} ID: {:?}", stringify!($ty), string)
+                ))
+            }
+        }
+    )*}
+}
+
+impl_parse_id_int!{ i32, i64, u32, u64 }
+
+#[cfg(feature = "raw_uuid")]
+impl ParseId for Uuid {
+    fn parse_id(string: &str) -> Result<Self, Error> {
+        Uuid::parse_str(string).map_err(|error| Error::new(
+            ErrorKind::BsonDecoding,
+            format!("invalid UUID {:?}: {}", string, error)
+        ))
+    }
 }
 
 /// Convenience methods for `ObjectId`-valued `Uid`s.
@@ -157,11 +217,11 @@ impl<T: Doc> Display for Uid<T> where T::Id: Display {
     }
 }
 
-impl<T: Doc> FromStr for Uid<T> where T::Id: FromStr {
-    type Err = <T::Id as FromStr>::Err;
+impl<T: Doc> FromStr for Uid<T> where T::Id: ParseId {
+    type Err = Error;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        string.parse().map(Uid::from_raw)
+        T::Id::parse_id(string).map(Uid::from_raw)
     }
 }
 
@@ -198,3 +258,68 @@ impl<T: Doc> BsonSchema for Uid<T> where T::Id: BsonSchema {
         T::Id::bson_schema()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId };
+    use crate::doc::Doc;
+    use super::{ Uid, SharesId };
+
+    /// A document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct User {
+        /// The unique ID of the user.
+        #[serde(rename = "_id")]
+        id: Uid<User>,
+    }
+
+    /// A document type sharing the IDs of `User`.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Settings {
+        /// The ID of the user these are the settings of.
+        #[serde(rename = "_id")]
+        id: Uid<Settings>,
+    }
+
+    impl Doc for User {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "User";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    impl Doc for Settings {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Settings";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    impl SharesId<User> for Settings {}
+
+    #[test]
+    fn cast_and_convert() {
+        let hex = "0102030405060708090a0b0c";
+        let user_id: Uid<User> = hex.parse().unwrap();
+        let settings_id = Uid::<Settings>::from_oid_str(hex).unwrap();
+
+        assert_eq!(user_id.to_string(), hex);
+        assert_eq!(settings_id.clone().cast::<User>(), user_id);
+        assert_eq!(Bson::from(settings_id), Bson::ObjectId(ObjectId::with_string(hex).unwrap()));
+        assert!("not an ObjectId".parse::<Uid<User>>().is_err());
+    }
+}
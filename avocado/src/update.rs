@@ -0,0 +1,529 @@
+//! Typed update operators, for building update documents.
+//!
+//! Each [`Change`](enum.Change.html) is a single update operator applied to
+//! a single field. A list of changes is collected into an update document,
+//! grouping the fields under their operators:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate bson;
+//! # extern crate avocado;
+//! #
+//! # use avocado::update::{ Change, Push, positional };
+//! # use bson::Document;
+//! #
+//! # fn main() {
+//! let update: Document = vec![
+//!     Change::set("status", "shipped"),
+//!     Change::inc("revision", 1),
+//!     Change::set(positional("items", "state"), "packed"),
+//!     Change::push("history", Push::each(vec!["shipped".into()]).slice(-10)),
+//!     Change::current_date("updated_at"),
+//! ].into_iter().collect();
+//!
+//! assert_eq!(update, doc!{
+//!     "$set": { "status": "shipped", "items.$.state": "packed" },
+//!     "$inc": { "revision": 1 },
+//!     "$push": { "history": { "$each": ["shipped"], "$slice": -10_i64 } },
+//!     "$currentDate": { "updated_at": true },
+//! });
+//! # }
+//! ```
+
+use std::iter::FromIterator;
+use bson::{ Bson, Document };
+use serde_json::Value;
+use crate::{
+    bsn::{ BsonExt, from_extended_json },
+    error::{ Error, ErrorKind, Result },
+};
+
+/// A single update operator applied to a single field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// `$set`: sets the field to the value.
+    Set(String, Bson),
+    /// `$setOnInsert`: sets the field to the value if an upsert inserts
+    /// a new document.
+    SetOnInsert(String, Bson),
+    /// `$unset`: removes the field.
+    Unset(String),
+    /// `$inc`: increments the field by the value.
+    Inc(String, Bson),
+    /// `$mul`: multiplies the field by the value.
+    Mul(String, Bson),
+    /// `$min`: sets the field to the value if the value is smaller.
+    Min(String, Bson),
+    /// `$max`: sets the field to the value if the value is greater.
+    Max(String, Bson),
+    /// `$rename`: renames the field to the second name.
+    Rename(String, String),
+    /// `$currentDate`: sets the field to the current date, or to the
+    /// current timestamp if the flag is `true`.
+    CurrentDate(String, bool),
+    /// `$push`: appends the values to the array.
+    Push(String, Push),
+    /// `$addToSet`: appends the values to the array unless already present.
+    AddToSet(String, Vec<Bson>),
+    /// `$pull`: removes the array elements equal to, or matching, the value.
+    Pull(String, Bson),
+    /// `$pullAll`: removes all array elements equal to any of the values.
+    PullAll(String, Vec<Bson>),
+    /// `$pop`: removes the first or the last element of the array.
+    Pop(String, ArrayEnd),
+}
+
+impl Change {
+    /// Returns a `$set` of the field.
+    pub fn set<S: Into<String>, V: Into<Bson>>(field: S, value: V) -> Self {
+        Change::Set(field.into(), value.into())
+    }
+
+    /// Returns a `$setOnInsert` of the field.
+    pub fn set_on_insert<S: Into<String>, V: Into<Bson>>(field: S, value: V) -> Self {
+        Change::SetOnInsert(field.into(), value.into())
+    }
+
+    /// Returns an `$unset` of the field.
+    pub fn unset<S: Into<String>>(field: S) -> Self {
+        Change::Unset(field.into())
+    }
+
+    /// Returns an `$inc` of the field.
+    pub fn inc<S: Into<String>, V: Into<Bson>>(field: S, amount: V) -> Self {
+        Change::Inc(field.into(), amount.into())
+    }
+
+    /// Returns a `$mul` of the field.
+    pub fn mul<S: Into<String>, V: Into<Bson>>(field: S, factor: V) -> Self {
+        Change::Mul(field.into(), factor.into())
+    }
+
+    /// Returns a `$min` of the field.
+    pub fn min<S: Into<String>, V: Into<Bson>>(field: S, value: V) -> Self {
+        Change::Min(field.into(), value.into())
+    }
+
+    /// Returns a `$max` of the field.
+    pub fn max<S: Into<String>, V: Into<Bson>>(field: S, value: V) -> Self {
+        Change::Max(field.into(), value.into())
+    }
+
+    /// Returns a `$rename` of the field `from` to `to`.
+    pub fn rename<S: Into<String>, T: Into<String>>(from: S, to: T) -> Self {
+        Change::Rename(from.into(), to.into())
+    }
+
+    /// Returns a `$currentDate` setting the field to the current date.
+    pub fn current_date<S: Into<String>>(field: S) -> Self {
+        Change::CurrentDate(field.into(), false)
+    }
+
+    /// Returns a `$currentDate` setting the field to the current timestamp.
+    pub fn current_timestamp<S: Into<String>>(field: S) -> Self {
+        Change::CurrentDate(field.into(), true)
+    }
+
+    /// Returns a `$push` to the array field.
+    pub fn push<S: Into<String>, P: Into<Push>>(field: S, push: P) -> Self {
+        Change::Push(field.into(), push.into())
+    }
+
+    /// Returns an `$addToSet` of the values to the array field.
+    pub fn add_to_set<S: Into<String>>(field: S, values: Vec<Bson>) -> Self {
+        Change::AddToSet(field.into(), values)
+    }
+
+    /// Returns a `$pull` from the array field. The condition is either a
+    /// value or a filter on the elements, e.g. `doc!{ "$lt": 0 }`.
+    pub fn pull<S: Into<String>, V: Into<Bson>>(field: S, condition: V) -> Self {
+        Change::Pull(field.into(), condition.into())
+    }
+
+    /// Returns a `$pullAll` of the values from the array field.
+    pub fn pull_all<S: Into<String>>(field: S, values: Vec<Bson>) -> Self {
+        Change::PullAll(field.into(), values)
+    }
+
+    /// Returns a `$pop` from the array field.
+    pub fn pop<S: Into<String>>(field: S, end: ArrayEnd) -> Self {
+        Change::Pop(field.into(), end)
+    }
+
+    /// Returns the operator, the field and the operand of the change.
+    fn into_parts(self) -> (&'static str, String, Bson) {
+        match self {
+            Change::Set(field, value) => ("$set", field, value),
+            Change::SetOnInsert(field, value) => ("$setOnInsert", field, value),
+            Change::Unset(field) => ("$unset", field, Bson::String(String::new())),
+            Change::Inc(field, amount) => ("$inc", field, amount),
+            Change::Mul(field, factor) => ("$mul", field, factor),
+            Change::Min(field, value) => ("$min", field, value),
+            Change::Max(field, value) => ("$max", field, value),
+            Change::Rename(from, to) => ("$rename", from, Bson::String(to)),
+            Change::CurrentDate(field, false) => ("$currentDate", field, Bson::Boolean(true)),
+            Change::CurrentDate(field, true) => {
+                ("$currentDate", field, bson!({ "$type": "timestamp" }))
+            }
+            Change::Push(field, push) => ("$push", field, push.into()),
+            Change::AddToSet(field, mut values) => {
+                let operand = if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    bson!({ "$each": values })
+                };
+                ("$addToSet", field, operand)
+            }
+            Change::Pull(field, condition) => ("$pull", field, condition),
+            Change::PullAll(field, values) => ("$pullAll", field, Bson::Array(values)),
+            Change::Pop(field, end) => ("$pop", field, end.into()),
+        }
+    }
+}
+
+/// The operand of a `$push`: the pushed values, and how the resulting
+/// array is sorted and truncated.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Push {
+    /// The values to append.
+    pub each: Vec<Bson>,
+    /// The number of elements to keep: the first ones if positive, the
+    /// last ones if negative.
+    pub slice: Option<i64>,
+    /// The sort order of the array: `1` or `-1` for the elements
+    /// themselves, or a sort document for embedded documents.
+    pub sort: Option<Bson>,
+    /// The index at which the values are inserted. Negative indexes count
+    /// from the end of the array.
+    pub position: Option<i64>,
+}
+
+impl Push {
+    /// Pushes the values, without sorting or truncating the array.
+    pub fn each(values: Vec<Bson>) -> Self {
+        Push { each: values, ..Push::default() }
+    }
+
+    /// Keeps only the first (or, if negative, the last) `slice` elements.
+    pub fn slice(self, slice: i64) -> Self {
+        Push { slice: Some(slice), ..self }
+    }
+
+    /// Sorts the array after pushing.
+    pub fn sort<B: Into<Bson>>(self, sort: B) -> Self {
+        Push { sort: Some(sort.into()), ..self }
+    }
+
+    /// Inserts the values at the given index instead of appending them.
+    pub fn position(self, position: i64) -> Self {
+        Push { position: Some(position), ..self }
+    }
+}
+
+impl From<Bson> for Push {
+    fn from(value: Bson) -> Self {
+        Push::each(vec![value])
+    }
+}
+
+impl From<Push> for Bson {
+    /// A single value without modifiers is pushed as-is; otherwise, the
+    /// `$each` form is used.
+    fn from(push: Push) -> Self {
+        let Push { mut each, slice, sort, position } = push;
+
+        if each.len() == 1 && slice.is_none() && sort.is_none() && position.is_none() {
+            return each.remove(0);
+        }
+
+        let mut doc = doc!{ "$each": each };
+
+        if let Some(n) = slice {
+            doc.insert("$slice", n);
+        }
+        if let Some(order) = sort {
+            doc.insert("$sort", order);
+        }
+        if let Some(index) = position {
+            doc.insert("$position", index);
+        }
+
+        Bson::Document(doc)
+    }
+}
+
+/// The end of an array, from which `$pop` removes an element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArrayEnd {
+    /// The first element.
+    First,
+    /// The last element.
+    Last,
+}
+
+impl From<ArrayEnd> for Bson {
+    fn from(end: ArrayEnd) -> Self {
+        match end {
+            ArrayEnd::First => Bson::I32(-1),
+            ArrayEnd::Last => Bson::I32(1),
+        }
+    }
+}
+
+impl From<Change> for Document {
+    fn from(change: Change) -> Self {
+        Some(change).into_iter().collect()
+    }
+}
+
+impl FromIterator<Change> for Document {
+    /// Groups the fields of the changes under their operators. If a field
+    /// is changed more than once by the same operator, the last one wins.
+    fn from_iter<I: IntoIterator<Item = Change>>(changes: I) -> Self {
+        let mut update = Document::new();
+
+        for change in changes {
+            let (operator, field, operand) = change.into_parts();
+
+            if let Some(&mut Bson::Document(ref mut fields)) = update.get_mut(operator) {
+                fields.insert(field, operand);
+                continue;
+            }
+
+            let mut fields = Document::new();
+            fields.insert(field, operand);
+            update.insert(operator, fields);
+        }
+
+        update
+    }
+}
+
+/// The conditions selecting the array elements updated through the
+/// `$[<identifier>]` positional operator, i.e. the `arrayFilters` of an
+/// update. An update or upsert operation provides them by overriding
+/// `Update::array_filters()` or `Upsert::array_filters()`.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate bson;
+/// # extern crate avocado;
+/// #
+/// # use avocado::update::{ ArrayFilters, Change, filtered_positional };
+/// # use bson::{ Bson, Document };
+/// #
+/// # fn main() {
+/// // Increment the scores below 80 in the `grades` array
+/// let update = Document::from(Change::inc(filtered_positional("grades", "elem", "score"), 5));
+/// let filters = ArrayFilters::new().with("elem", doc!{ "score": { "$lt": 80 } });
+///
+/// assert_eq!(update, doc!{ "$inc": { "grades.$[elem].score": 5 } });
+/// assert_eq!(Bson::from(filters), bson!([{ "elem.score": { "$lt": 80 } }]));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArrayFilters {
+    /// One filter document per identifier.
+    filters: Vec<Document>,
+}
+
+impl ArrayFilters {
+    /// Returns an empty list of array filters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the condition for the elements bound to `identifier`. Field
+    /// names in `condition` are relative to the element, e.g. `score` means
+    /// `<identifier>.score`; operators, such as `$gte`, apply to the element
+    /// itself.
+    pub fn with(mut self, identifier: &str, condition: Document) -> Self {
+        let mut filter = Document::new();
+        let mut operators = Document::new();
+
+        for (name, value) in condition {
+            if name.starts_with('$') {
+                operators.insert(name, value);
+            } else {
+                filter.insert(format!("{}.{}", identifier, name), value);
+            }
+        }
+
+        if !operators.is_empty() {
+            filter.insert(identifier, operators);
+        }
+
+        self.filters.push(filter);
+        self
+    }
+
+    /// Returns `true` if there are no array filters.
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Returns the filter documents.
+    pub fn filters(&self) -> &[Document] {
+        &self.filters
+    }
+}
+
+impl From<Vec<Document>> for ArrayFilters {
+    /// Uses the filter documents verbatim.
+    fn from(filters: Vec<Document>) -> Self {
+        ArrayFilters { filters }
+    }
+}
+
+impl From<ArrayFilters> for Bson {
+    fn from(array_filters: ArrayFilters) -> Self {
+        Bson::Array(array_filters.filters.into_iter().map(Bson::Document).collect())
+    }
+}
+
+/// Returns the path of `field` in the first array element matched by the
+/// filter, e.g. `items.$.state`.
+pub fn positional(array: &str, field: &str) -> String {
+    format!("{}.$.{}", array, field)
+}
+
+/// Returns the path of `field` in all elements of the array, e.g.
+/// `items.$[].state`.
+pub fn all_positional(array: &str, field: &str) -> String {
+    format!("{}.$[].{}", array, field)
+}
+
+/// Returns the path of `field` in the elements of the array matched by the
+/// array filter named `identifier`, e.g. `items.$[late].state`.
+pub fn filtered_positional(array: &str, identifier: &str, field: &str) -> String {
+    format!("{}.$[{}].{}", array, identifier, field)
+}
+
+/// Builds an update document from JSON, as sent by e.g. an HTTP client.
+/// Extended JSON values are converted like in
+/// [`filter::from_json()`](../filter/fn.from_json.html). Every top-level key
+/// must be an update operator whose operand is a document; replacement
+/// documents are rejected, since they would silently overwrite the whole
+/// entity.
+pub fn from_json(update: Value) -> Result<Document> {
+    let document = from_extended_json(update)?.try_into_doc()?;
+
+    if document.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidUpdate, "update document is empty"));
+    }
+
+    for (key, value) in &document {
+        if !key.starts_with('$') {
+            return Err(Error::new(
+                ErrorKind::InvalidUpdate,
+                format!("`{}` is not an update operator", key)
+            ));
+        }
+        if value.as_document().is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidUpdate,
+                format!("the operand of `{}` must be a document", key)
+            ));
+        }
+    }
+
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, Document };
+    use super::{ Change, Push, ArrayEnd, ArrayFilters, all_positional, filtered_positional };
+
+    #[test]
+    fn field_operators() {
+        let update: Document = vec![
+            Change::set_on_insert("created_by", "admin"),
+            Change::unset("legacy"),
+            Change::mul("price", 1.1),
+            Change::min("low", 3),
+            Change::max("high", 7),
+            Change::rename("nick", "nickname"),
+            Change::current_timestamp("touched"),
+            Change::set(all_positional("items", "seen"), true),
+            Change::set(filtered_positional("items", "late", "state"), "overdue"),
+        ].into_iter().collect();
+
+        assert_eq!(update, doc!{
+            "$setOnInsert": { "created_by": "admin" },
+            "$unset": { "legacy": "" },
+            "$mul": { "price": 1.1 },
+            "$min": { "low": 3 },
+            "$max": { "high": 7 },
+            "$rename": { "nick": "nickname" },
+            "$currentDate": { "touched": { "$type": "timestamp" } },
+            "$set": { "items.$[].seen": true, "items.$[late].state": "overdue" },
+        });
+    }
+
+    #[test]
+    fn array_operators() {
+        let update: Document = vec![
+            Change::push("scores", Bson::I32(89)),
+            Change::push(
+                "top",
+                Push::each(vec![Bson::I32(1), Bson::I32(2)]).sort(-1).slice(3).position(0),
+            ),
+            Change::add_to_set("tags", vec![bson!("a")]),
+            Change::add_to_set("colors", vec![bson!("red"), bson!("blue")]),
+            Change::pull("readings", doc!{ "$lt": 0 }),
+            Change::pull_all("ids", vec![Bson::I32(1), Bson::I32(2)]),
+            Change::pop("queue", ArrayEnd::First),
+        ].into_iter().collect();
+
+        assert_eq!(update, doc!{
+            "$push": {
+                "scores": 89,
+                "top": { "$each": [1, 2], "$slice": 3_i64, "$sort": -1, "$position": 0_i64 },
+            },
+            "$addToSet": { "tags": "a", "colors": { "$each": ["red", "blue"] } },
+            "$pull": { "readings": { "$lt": 0 } },
+            "$pullAll": { "ids": [1, 2] },
+            "$pop": { "queue": -1 },
+        });
+    }
+
+    #[test]
+    fn single_change() {
+        assert_eq!(Document::from(Change::inc("n", 1)), doc!{ "$inc": { "n": 1 } });
+    }
+
+    #[test]
+    fn array_filters() {
+        let filters = ArrayFilters::new()
+            .with("elem", doc!{ "score": { "$lt": 80 }, "graded": true })
+            .with("n", doc!{ "$gte": 100 });
+
+        assert_eq!(filters.filters(), &[
+            doc!{ "elem.score": { "$lt": 80 }, "elem.graded": true },
+            doc!{ "n": { "$gte": 100 } },
+        ][..]);
+        assert!(ArrayFilters::new().is_empty());
+    }
+
+    #[test]
+    fn json_updates() {
+        use chrono::{ TimeZone, Utc };
+        use crate::error::{ ErrorExt, ErrorKind };
+        use super::from_json;
+
+        let update = json!({
+            "$set": { "shipped_at": { "$date": { "$numberLong": "1551398400000" } } },
+            "$inc": { "revision": 1 },
+        });
+        assert_eq!(from_json(update).unwrap(), doc!{
+            "$set": { "shipped_at": Utc.ymd(2019, 3, 1).and_hms(0, 0, 0) },
+            "$inc": { "revision": 1_i64 },
+        });
+
+        let kind = |update| from_json(update).unwrap_err().kind();
+        assert_eq!(kind(json!({})), ErrorKind::InvalidUpdate);
+        assert_eq!(kind(json!({ "$set": { "a": 1 }, "b": 2 })), ErrorKind::InvalidUpdate);
+        assert_eq!(kind(json!({ "$set": 1 })), ErrorKind::InvalidUpdate);
+    }
+}
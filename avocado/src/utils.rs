@@ -1,6 +1,17 @@
 //! Common utility functions and types.
 
-use crate::error::{ Error, ErrorKind, Result };
+use mongodb::coll::options::{
+    CountOptions,
+    DistinctOptions,
+    AggregateOptions,
+    FindOptions,
+    FindOneAndDeleteOptions,
+    FindOneAndUpdateOptions,
+};
+use crate::{
+    timeout::max_time_ms_or_default,
+    error::{ Error, ErrorKind, Result },
+};
 
 /// Converts an `i8`, `i16`, `i32` or `i64` to a `usize` if the range and
 /// the value permits. Constructs an error message based on `msg` otherwise.
@@ -31,10 +42,54 @@ pub fn int_to_usize_with_msg<T: Into<i64>>(x: T, msg: &str) -> Result<usize> {
     }
 }
 
+/// Converts a float to an `i64`, rounding towards zero, if the value is
+/// finite and the result is in the range of `i64`.
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+pub fn float_to_i64(x: f64) -> Option<i64> {
+    // `i64::MIN` is exactly representable as an `f64`, and `-i64::MIN` is
+    // the smallest float which doesn't fit, so the cast is exact in between.
+    let min = i64::min_value() as f64;
+
+    if x.is_finite() && x.trunc() >= min && x.trunc() < -min {
+        Some(x.trunc() as i64)
+    } else {
+        None
+    }
+}
+
+/// Driver options carrying a server-side time limit (`maxTimeMS`).
+pub trait MaxTime: Sized {
+    /// Fills in the process-wide default time limit, unless a limit
+    /// has already been specified.
+    fn with_default_max_time(self) -> Self;
+}
+
+/// Implements `MaxTime` for options types with a `max_time_ms` field.
+macro_rules! impl_max_time {
+    ($($ty:ty,)*) => {$(
+        impl MaxTime for $ty {
+            fn with_default_max_time(mut self) -> Self {
+                self.max_time_ms = max_time_ms_or_default(self.max_time_ms);
+                self
+            }
+        }
+    )*}
+}
+
+impl_max_time! {
+    CountOptions,
+    DistinctOptions,
+    AggregateOptions,
+    FindOptions,
+    FindOneAndDeleteOptions,
+    FindOneAndUpdateOptions,
+}
+
 #[cfg(test)]
 mod tests {
     use std::i64;
-    use super::int_to_usize_with_msg;
+    use std::f64;
+    use super::{ int_to_usize_with_msg, float_to_i64 };
     use crate::error::{ Error, ErrorKind, Result };
 
     #[test]
@@ -72,4 +127,14 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn float_to_i64_works() {
+        assert_eq!(float_to_i64(42.0), Some(42));
+        assert_eq!(float_to_i64(-2.9), Some(-2));
+        assert_eq!(float_to_i64(-9.223_372_036_854_775_808e18), Some(i64::MIN));
+        assert_eq!(float_to_i64(9.223_372_036_854_775_808e18), None);
+        assert_eq!(float_to_i64(f64::NAN), None);
+        assert_eq!(float_to_i64(f64::INFINITY), None);
+    }
 }
@@ -0,0 +1,519 @@
+//! Single-collection polymorphism: enums stored as internally tagged
+//! documents.
+//!
+//! `#[derive(Doc)]` accepts an enum annotated with `#[serde(tag = "...")]`
+//! whose variants all have named fields, including the same `_id` field.
+//! Every variant is stored in the same collection, and the tag field tells
+//! them apart. `Doc::tag_field()`, `Doc::variant_tags()` and
+//! `Doc::variant_tag()` describe the tagging, and
+//! [`Only`](struct.Only.html) restricts an operation to the
+//! documents of a single variant:
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! # use avocado::variant::Only;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! #[serde(tag = "kind", rename_all = "lowercase")]
+//! enum Shape {
+//!     Circle { _id: Uid<Shape>, radius: f64 },
+//!     Rectangle { _id: Uid<Shape>, width: f64, height: f64 },
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let shapes: Collection<Shape> = db.existing_collection();
+//! let large = doc!{ "radius": { "$gt": 10.0 } };
+//!
+//! // Only matches documents with `kind: "circle"`.
+//! let circles = shapes.find_many(Only::new::<Shape>(large, "circle")?)?;
+//! let count = shapes.count(Only::new::<Shape>(doc!{}, "rectangle")?)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A variant can also have its own struct type, with the same fields, which
+//! implements [`Subtype`](trait.Subtype.html). Annotating the variant with
+//! `#[avocado(subtype = "Type")]` derives the impl, along with a conversion
+//! of the struct into the enum. `Collection::of_variant()` then returns a
+//! [`View`](struct.View.html), which only reads
+//! and writes the documents of that variant, deserialized as the struct:
+//!
+//! ```no_run
+//! # #[macro_use]
+//! # extern crate avocado_derive;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde;
+//! # extern crate avocado;
+//! #
+//! # use avocado::prelude::*;
+//! #
+//! #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+//! #[serde(tag = "role")]
+//! enum User {
+//!     #[avocado(subtype = "Admin")]
+//!     Admin { _id: Uid<User>, name: String, permissions: Vec<String> },
+//!     Member { _id: Uid<User>, name: String },
+//! }
+//!
+//! #[derive(Debug, Clone, Serialize, Deserialize)]
+//! struct Admin {
+//!     _id: Uid<User>,
+//!     name: String,
+//!     permissions: Vec<String>,
+//! }
+//!
+//! # fn main() -> AvocadoResult<()> {
+//! # let client = Client::with_uri("mongodb://localhost:27017/")?;
+//! # let db = client.db("avocado_example_db");
+//! let users: Collection<User> = db.existing_collection();
+//! let admins = users.of_variant::<Admin>();
+//!
+//! admins.insert_one(Admin {
+//!     _id: Uid::new_oid()?,
+//!     name: "root".into(),
+//!     permissions: vec!["*".into()],
+//! })?;
+//!
+//! // Filters on `role: "Admin"` automatically.
+//! let root: Option<Admin> = admins.find_one(doc!{ "name": "root" })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Adjacently tagged enums aren't supported, because they nest the fields
+//! of the variant, including `_id`, under a separate content field, whereas
+//! MongoDB requires a top-level `_id`.
+
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::collections::BTreeMap;
+use serde::Deserialize;
+use bson::{ Bson, Document };
+use mongodb::common::WriteConcern;
+use mongodb::coll::options::{ CountOptions, FindOptions, FindOneAndUpdateOptions };
+use crate::{
+    update::ArrayFilters,
+    options,
+    projection::Projection,
+    sort::Sort,
+    coll::{ Collection, UpdateOneResult, UpdateManyResult },
+    cursor::{ self, Cursor },
+    doc::Doc,
+    uid::Uid,
+    ops::*,
+    error::{ Error, ErrorKind, Result },
+};
+
+/// A type holding the fields of a single variant of `T`, an enum stored as
+/// internally tagged documents.
+///
+/// When deriving `Doc` for `T`, this is implemented for the type named by
+/// `#[avocado(subtype = "...")]` on the variant, and so is `From<Self> for T`.
+pub trait Subtype<T: Doc>: Into<T> + for<'a> Deserialize<'a> + Debug {
+    /// The tag of the variant, as in `T::variant_tags()`.
+    const TAG: &'static str;
+}
+
+/// Returns the filter matching the documents of the variant of `T` tagged
+/// `tag`. Returns an error if `T` isn't a tagged enum or it has no such
+/// variant.
+pub fn filter<T: Doc>(tag: &str) -> Result<Document> {
+    let (field, known) = lookup::<T>(tag)?;
+    Ok(doc!{ field: known })
+}
+
+/// Wraps an operation so that it only applies to the documents of a
+/// single variant of a tagged enum.
+///
+/// The tag is added to the filter of the operation. If the filter already
+/// constrains the tag field, both conditions must hold.
+#[derive(Debug, Clone)]
+pub struct Only<O> {
+    /// The wrapped operation.
+    operation: O,
+    /// The tag of the variant.
+    tag: &'static str,
+}
+
+impl<O> Only<O> {
+    /// Restricts `operation` to the variant of `T` tagged `tag`. Returns an
+    /// error if `T` isn't a tagged enum or it has no such variant.
+    pub fn new<T: Doc>(operation: O, tag: &str) -> Result<Self> {
+        lookup::<T>(tag).map(|(_, known)| Only { operation, tag: known })
+    }
+
+    /// The tag of the variant.
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    /// Returns the wrapped operation.
+    pub fn into_inner(self) -> O {
+        self.operation
+    }
+}
+
+impl<T: Doc, Q: Count<T>> Count<T> for Only<Q> {
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn options(&self) -> CountOptions {
+        self.operation.options()
+    }
+}
+
+impl<T: Doc, Q: Query<T>> Query<T> for Only<Q> {
+    type Output = Q::Output;
+
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.operation.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.operation.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.operation.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.operation.command_options()
+    }
+}
+
+impl<T: Doc, U: Update<T>> Update<T> for Only<U> {
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn update(&self) -> Document {
+        self.operation.update()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.operation.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.operation.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.operation.command_options()
+    }
+}
+
+impl<T: Doc, U: Upsert<T>> Upsert<T> for Only<U> {
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn upsert(&self) -> Document {
+        self.operation.upsert()
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.operation.options()
+    }
+
+    fn array_filters(&self) -> ArrayFilters {
+        self.operation.array_filters()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.operation.command_options()
+    }
+}
+
+impl<T: Doc, Q: Delete<T>> Delete<T> for Only<Q> {
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn options(&self) -> WriteConcern {
+        self.operation.options()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.operation.command_options()
+    }
+}
+
+impl<T: Doc, U: FindAndUpdate<T>> FindAndUpdate<T> for Only<U> {
+    type Output = U::Output;
+
+    fn filter(&self) -> Document {
+        tagged::<T>(self.operation.filter(), self.tag)
+    }
+
+    fn update(&self) -> Document {
+        self.operation.update()
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        U::transform(raw)
+    }
+
+    fn options(&self) -> FindOneAndUpdateOptions {
+        self.operation.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.operation.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.operation.sort()
+    }
+}
+
+/// Wraps a query so that it only matches the documents of the variant of
+/// `V`, and deserializes them as `V`.
+#[derive(Debug)]
+pub struct AsSubtype<V, Q> {
+    /// The wrapped query.
+    query: Q,
+    /// Marks the type of the results.
+    marker: PhantomData<V>,
+}
+
+impl<V, Q> AsSubtype<V, Q> {
+    /// Restricts `query` to the variant of `V`.
+    pub fn new(query: Q) -> Self {
+        AsSubtype { query, marker: PhantomData }
+    }
+
+    /// Returns the wrapped query.
+    pub fn into_inner(self) -> Q {
+        self.query
+    }
+}
+
+impl<T: Doc, V: Subtype<T>, Q: Query<T>> Query<T> for AsSubtype<V, Q> {
+    type Output = V;
+
+    fn filter(&self) -> Document {
+        tagged::<T>(self.query.filter(), V::TAG)
+    }
+
+    fn transform(raw: Document) -> Result<Bson> {
+        Q::transform(raw)
+    }
+
+    fn options(&self) -> FindOptions {
+        self.query.options()
+    }
+
+    fn projection(&self) -> Projection {
+        self.query.projection()
+    }
+
+    fn sort(&self) -> Sort {
+        self.query.sort()
+    }
+
+    fn command_options(&self) -> options::Extra {
+        self.query.command_options()
+    }
+}
+
+/// A view of a collection of a tagged enum `T`, in which every operation is
+/// restricted to the documents of the variant of `V`, and documents are
+/// read and inserted as `V`.
+#[derive(Debug)]
+pub struct View<'a, T: Doc, V> {
+    /// The underlying collection.
+    collection: &'a Collection<T>,
+    /// Marks the type of the variant.
+    marker: PhantomData<V>,
+}
+
+impl<'a, T: Doc, V: Subtype<T>> View<'a, T, V> {
+    /// Restricts the collection to the variant of `V`. This is usually
+    /// called through `Collection::of_variant()`.
+    pub fn new(collection: &'a Collection<T>) -> Self {
+        View { collection, marker: PhantomData }
+    }
+
+    /// Returns the underlying collection, with documents of every variant.
+    pub fn collection(&self) -> &'a Collection<T> {
+        self.collection
+    }
+
+    /// Returns the number of documents of the variant matching the query.
+    pub fn count<Q: Count<T>>(&self, query: Q) -> Result<usize> {
+        self.collection.count(self.wrap(query))
+    }
+
+    /// Retrieves a single document of the variant satisfying the query.
+    pub fn find_one<Q: Query<T>>(&self, query: Q) -> Result<Option<V>> {
+        self.collection.find_one(AsSubtype::new(query))
+    }
+
+    /// Retrieves all documents of the variant satisfying the query.
+    pub fn find_many<Q: Query<T>>(&self, query: Q) -> Result<Cursor<V>>
+        where V: cursor::Item
+    {
+        self.collection.find_many(AsSubtype::new(query))
+    }
+
+    /// Inserts a single document, converted to `T`.
+    pub fn insert_one(&self, entity: V) -> Result<Uid<T>> {
+        self.collection.insert_one(&entity.into())
+    }
+
+    /// Inserts many documents, converted to `T`.
+    pub fn insert_many<I>(&self, entities: I) -> Result<BTreeMap<u64, Uid<T>>>
+        where I: IntoIterator<Item = V>,
+              T::Id: Clone + Debug,
+              T: 'static,
+    {
+        let converted: Vec<T> = entities.into_iter().map(Into::into).collect();
+        self.collection.insert_many(&converted)
+    }
+
+    /// Updates a single document of the variant.
+    pub fn update_one<U: Update<T>>(&self, update: U) -> Result<UpdateOneResult> {
+        self.collection.update_one(self.wrap(update))
+    }
+
+    /// Updates all matching documents of the variant.
+    pub fn update_many<U: Update<T>>(&self, update: U) -> Result<UpdateManyResult> {
+        self.collection.update_many(self.wrap(update))
+    }
+
+    /// Deletes one document of the variant.
+    pub fn delete_one<Q: Delete<T>>(&self, query: Q) -> Result<bool> {
+        self.collection.delete_one(self.wrap(query))
+    }
+
+    /// Deletes all matching documents of the variant.
+    pub fn delete_many<Q: Delete<T>>(&self, query: Q) -> Result<usize> {
+        self.collection.delete_many(self.wrap(query))
+    }
+
+    /// Restricts an operation to the variant.
+    fn wrap<O>(&self, operation: O) -> Only<O> {
+        Only { operation, tag: V::TAG }
+    }
+}
+
+/// Returns the tag field of `T` and its static tag equal to `tag`.
+fn lookup<T: Doc>(tag: &str) -> Result<(&'static str, &'static str)> {
+    let field = T::tag_field().ok_or_else(|| Error::new(
+        ErrorKind::InvalidFilter,
+        format!("{} isn't an enum stored as internally tagged documents", T::NAME)
+    ))?;
+    let known = T::variant_tags().iter().cloned().find(|&variant| variant == tag).ok_or_else(|| Error::new(
+        ErrorKind::InvalidFilter,
+        format!("{} has no variant tagged {:?}", T::NAME, tag)
+    ))?;
+
+    Ok((field, known))
+}
+
+/// Adds the condition `{ <tag field>: tag }` to a filter.
+fn tagged<T: Doc>(mut filter: Document, tag: &str) -> Document {
+    let field = match T::tag_field() {
+        Some(field) => field,
+        None => return filter,
+    };
+
+    if filter.contains_key(field) {
+        doc!{ "$and": [filter, { field: tag }] }
+    } else {
+        filter.insert(field, tag);
+        filter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::oid::ObjectId;
+    use crate::{ doc::Doc, uid::Uid, ops::*, error::{ ErrorExt, ErrorKind } };
+    use super::{ Only, filter };
+
+    /// A tagged enum document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    enum Payment {
+        /// A payment by card.
+        Card {
+            /// The unique ID of the payment.
+            _id: Uid<Payment>,
+        },
+        /// A bank transfer.
+        Transfer {
+            /// The unique ID of the payment.
+            _id: Uid<Payment>,
+        },
+    }
+
+    impl Doc for Payment {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Payment";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            match *self {
+                Payment::Card { _id: ref uid } | Payment::Transfer { _id: ref uid } => Some(uid),
+            }
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            match *self {
+                Payment::Card { _id: ref mut uid } | Payment::Transfer { _id: ref mut uid } => *uid = id,
+            }
+        }
+
+        fn tag_field() -> Option<&'static str> {
+            Some("type")
+        }
+
+        fn variant_tags() -> &'static [&'static str] {
+            &["Card", "Transfer"]
+        }
+    }
+
+    #[test]
+    fn variant_filters() {
+        assert_eq!(filter::<Payment>("Card").unwrap(), doc!{ "type": "Card" });
+        assert_eq!(
+            filter::<Payment>("Cash").unwrap_err().kind(),
+            ErrorKind::InvalidFilter
+        );
+
+        let query = Only::new::<Payment>(doc!{ "amount": { "$gt": 10 } }, "Transfer").unwrap();
+        assert_eq!(query.tag(), "Transfer");
+        assert_eq!(
+            Query::<Payment>::filter(&query),
+            doc!{ "amount": { "$gt": 10 }, "type": "Transfer" }
+        );
+
+        let delete = Only::new::<Payment>(doc!{ "type": { "$ne": "Card" } }, "Card").unwrap();
+        assert_eq!(
+            Delete::<Payment>::filter(&delete),
+            doc!{ "$and": [{ "type": { "$ne": "Card" } }, { "type": "Card" }] }
+        );
+    }
+}
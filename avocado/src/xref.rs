@@ -0,0 +1,198 @@
+//! References to documents, possibly living in another database.
+//!
+//! In multi-tenant deployments, each tenant's data often lives in a database
+//! of its own, while some documents refer to documents of another tenant or
+//! of a shared database. An [`XRef`](struct.XRef.html) stores the ID of the
+//! referenced document along with an optional database qualifier, and it is
+//! resolved using the database of the referring document: unqualified
+//! references are looked up in that database, qualified ones in the database
+//! of the same name on the same client. Therefore, no separate client or
+//! database handle needs to be passed around for resolving references.
+//!
+//! An `XRef` is serialized as a standard `DBRef`, i.e. as a document of the
+//! form `{ $ref: <collection>, $id: <ID>, $db: <database> }`, where `$db` is
+//! omitted for unqualified references. Only the name of the database is
+//! stored, not the connection or its credentials, so references stay valid
+//! when credentials are rotated or data is moved to another cluster.
+
+use std::result;
+use std::fmt::{ Debug, Formatter, Result as FmtResult };
+use serde::{
+    ser::{ Serialize, Serializer },
+    de::{ Deserialize, Deserializer, Error as DeError },
+};
+use mongodb::ThreadedClient;
+use mongodb::db::Database;
+use crate::{
+    db::DatabaseExt,
+    doc::Doc,
+    uid::Uid,
+    error::{ Result, ResultExt },
+};
+
+/// A reference to a document of type `T`, optionally qualified with the
+/// name of the database containing it.
+pub struct XRef<T: Doc> {
+    /// The ID of the referenced document.
+    pub id: Uid<T>,
+    /// The name of the database containing the referenced document, or
+    /// `None` if it is the database of the referring document.
+    pub db: Option<String>,
+}
+
+impl<T: Doc> XRef<T> {
+    /// Creates a reference to a document in the same database.
+    pub fn local(id: Uid<T>) -> Self {
+        XRef { id, db: None }
+    }
+
+    /// Creates a reference to a document in the database named `db`.
+    pub fn in_db<S: Into<String>>(db: S, id: Uid<T>) -> Self {
+        XRef { id, db: Some(db.into()) }
+    }
+
+    /// Returns the database containing the referenced document, given the
+    /// database `origin` of the referring document.
+    pub fn database(&self, origin: &Database) -> Database {
+        match self.db {
+            Some(ref name) => origin.client.db(name),
+            None => origin.clone(),
+        }
+    }
+
+    /// Loads the referenced document, given the database `origin` of the
+    /// referring document. Returns `Ok(None)` if the reference is dangling.
+    pub fn resolve(&self, origin: &Database) -> Result<Option<T>> {
+        let filter = doc!{ "_id": bson::to_bson(&self.id)? };
+
+        self.database(origin)
+            .existing_collection::<T>()
+            .find_one(filter)
+            .chain(|| format!("can't resolve reference to {} in {:?}", T::NAME, self.db))
+    }
+}
+
+// The following traits are implemented manually in order to relax trait
+// bounds, just like for `Uid<T>`.
+
+impl<T: Doc> Clone for XRef<T> where T::Id: Clone {
+    fn clone(&self) -> Self {
+        XRef { id: self.id.clone(), db: self.db.clone() }
+    }
+}
+
+impl<T: Doc> PartialEq for XRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id && self.db == other.db
+    }
+}
+
+impl<T: Doc> Eq for XRef<T> {}
+
+impl<T: Doc> Debug for XRef<T> where T::Id: Debug {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("XRef")
+            .field("collection", &T::NAME)
+            .field("id", &self.id)
+            .field("db", &self.db)
+            .finish()
+    }
+}
+
+/// The serialized, `DBRef`-compatible form of an `XRef`.
+#[derive(Serialize, Deserialize)]
+struct RawXRef<C, I, D> {
+    /// The name of the collection containing the referenced document.
+    #[serde(rename = "$ref")]
+    collection: C,
+    /// The ID of the referenced document.
+    #[serde(rename = "$id")]
+    id: I,
+    /// The name of the database containing the referenced document.
+    #[serde(rename = "$db", default, skip_serializing_if = "Option::is_none")]
+    db: Option<D>,
+}
+
+impl<T: Doc> Serialize for XRef<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        RawXRef {
+            collection: T::NAME,
+            id: &self.id,
+            db: self.db.as_ref(),
+        }.serialize(serializer)
+    }
+}
+
+impl<'a, T: Doc> Deserialize<'a> for XRef<T> {
+    fn deserialize<D: Deserializer<'a>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let raw = RawXRef::<String, Uid<T>, String>::deserialize(deserializer)?;
+
+        if raw.collection == T::NAME {
+            Ok(XRef { id: raw.id, db: raw.db })
+        } else {
+            Err(D::Error::custom(format!(
+                "reference to collection `{}` where `{}` was expected",
+                raw.collection, T::NAME
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{ Bson, oid::ObjectId };
+    use bson::{ from_bson, to_bson };
+    use crate::{ doc::Doc, uid::Uid };
+    use super::XRef;
+
+    /// A referenced document type.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Tenant {
+        /// The unique ID of the tenant.
+        #[serde(rename = "_id")]
+        id: Uid<Tenant>,
+    }
+
+    impl Doc for Tenant {
+        type Id = ObjectId;
+
+        const NAME: &'static str = "Tenant";
+
+        fn id(&self) -> Option<&Uid<Self>> {
+            Some(&self.id)
+        }
+
+        fn set_id(&mut self, id: Uid<Self>) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn serialize_as_dbref() {
+        let id = Uid::<Tenant>::from_oid_bytes([7; 12]);
+        let local = XRef::local(id.clone());
+        let remote = XRef::in_db("acme", id);
+
+        assert_eq!(to_bson(&local).unwrap(), Bson::from(doc!{
+            "$ref": "Tenant",
+            "$id": ObjectId::with_bytes([7; 12]),
+        }));
+        assert_eq!(to_bson(&remote).unwrap(), Bson::from(doc!{
+            "$ref": "Tenant",
+            "$id": ObjectId::with_bytes([7; 12]),
+            "$db": "acme",
+        }));
+        assert_eq!(from_bson::<XRef<Tenant>>(to_bson(&local).unwrap()).unwrap(), local);
+        assert_eq!(from_bson::<XRef<Tenant>>(to_bson(&remote).unwrap()).unwrap(), remote);
+    }
+
+    #[test]
+    fn reject_other_collection() {
+        let raw = Bson::from(doc!{
+            "$ref": "User",
+            "$id": ObjectId::with_bytes([7; 12]),
+        });
+
+        assert!(from_bson::<XRef<Tenant>>(raw).is_err());
+    }
+}
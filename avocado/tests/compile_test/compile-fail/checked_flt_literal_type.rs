@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct Person {
+    _id: Uid<Person>,
+    age: Option<u32>,
+}
+
+fn main() {
+    let _ = checked_flt!(Person, { "age": { "$gt": "eighteen" } }); //~ ERROR the trait bound
+}
@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+struct Address {
+    city: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct Person {
+    _id: Uid<Person>,
+    #[avocado(subdoc)]
+    address: Address,
+}
+
+fn main() {
+    let _ = checked_flt!(Person, { "address.ctiy": "Budapest" }); //~ ERROR no method named `ctiy` found
+    //~| ERROR no method named `__avocado_kind_of_ctiy` found
+}
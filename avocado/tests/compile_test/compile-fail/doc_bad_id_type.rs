@@ -0,0 +1,16 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = 42] //~ ERROR value for key `id_type` must be a valid UTF-8 string
+struct MyDoc {
+    _id: Uid<MyDoc>,
+}
+
+fn main() {}
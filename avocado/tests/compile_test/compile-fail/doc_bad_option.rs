@@ -7,8 +7,8 @@ extern crate serde;
 
 use avocado::prelude::*;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Doc)] //~ ERROR proc-macro derive panicked
-#[options(nonexistent_options = "my_options_fn")] //~| no option method named `Doc::nonexistent_options()`
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[options(nonexistent_options = "my_options_fn")] //~ ERROR no option method named `Doc::nonexistent_options()`
 struct MyDoc {
     _id: String,
 }
@@ -0,0 +1,16 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[doc_collation(strength = 2)] //~ ERROR `#[doc_collation(...)]` requires a `locale`
+struct MyDoc {
+    _id: Uid<MyDoc>,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[collection_name = "documents"]
+#[collection_naming = "snake_case_plural"] //~ ERROR `#[collection_name]` and `#[collection_naming]` can't be used together
+struct MyDoc {
+    _id: Uid<MyDoc>,
+}
+
+fn main() {}
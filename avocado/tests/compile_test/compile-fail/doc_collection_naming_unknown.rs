@@ -0,0 +1,16 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[collection_naming = "plural_snake_case"] //~ ERROR unknown `collection_naming` strategy: plural_snake_case
+struct MyDoc {
+    _id: Uid<MyDoc>,
+}
+
+fn main() {}
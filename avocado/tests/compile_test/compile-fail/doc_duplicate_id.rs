@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    #[serde(rename = "_id")]
+    other_id: Uid<MyDoc>, //~ ERROR more than one fields serialize as `_id`
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    #[avocado(version)]
+    version: i64,
+    #[avocado(version)]
+    revision: i64, //~ ERROR more than one field is `#[avocado(version)]`
+}
+
+fn main() {}
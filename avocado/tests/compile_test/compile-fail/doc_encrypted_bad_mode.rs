@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    #[avocado(encrypted = "reversible")]
+    secret: String, //~ ERROR encryption mode must be "randomized" or "deterministic", not "reversible"
+}
+
+fn main() {}
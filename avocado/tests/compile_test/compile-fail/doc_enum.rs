@@ -5,8 +5,8 @@ extern crate avocado;
 extern crate serde_derive;
 extern crate serde;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Doc)] //~ ERROR proc-macro derive panicked
-enum Stuff { //~| only a `struct` can be a top-level `Doc`; consider wrapping this type in a struct
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+enum Stuff { //~ ERROR an enum `Doc` must be internally tagged, using `#[serde(tag = "...")]`
     Foo {
         _id: Uid<Stuff>
     },
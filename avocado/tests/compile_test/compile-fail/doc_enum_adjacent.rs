@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[serde(tag = "type", content = "data")] //~ ERROR an adjacently tagged enum can't be a `Doc`
+enum Stuff {
+    Foo {
+        _id: Uid<Stuff>
+    },
+    Bar {
+        _id: Uid<Stuff>
+    },
+}
+
+fn main() {}
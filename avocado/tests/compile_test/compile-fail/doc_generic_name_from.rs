@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use std::marker::PhantomData;
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[avocado(name_from = "U")] //~ ERROR `#[avocado(name_from = "U")]` must name a type parameter
+struct GenericType<T> {
+    _id: Uid<GenericType<T>>,
+    dummy: PhantomData<T>,
+}
+
+fn main() {}
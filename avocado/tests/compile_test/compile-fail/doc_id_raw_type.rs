@@ -0,0 +1,17 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = "u64"]
+struct MyDoc {
+    #[serde(rename = "_id")]
+    key: Option<u64>, //~ ERROR the `_id` field `key` must be a `Uid<MyDoc>`, not the raw `#[id_type]` `u64`
+}
+
+fn main() {}
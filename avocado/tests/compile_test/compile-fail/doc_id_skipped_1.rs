@@ -5,9 +5,9 @@ extern crate avocado;
 extern crate serde_derive;
 extern crate serde;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Doc)] //~ ERROR proc-macro derive panicked
-#[id_type = "i64"] //~| a `Doc` must contain a field serialized as `_id`
-struct SkippyOne {
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = "i64"]
+struct SkippyOne { //~ ERROR a `Doc` must contain a field serialized as `_id`
     #[serde(skip_serializing, skip_deserializing)]
     _id: Uid<SkippyOne>,
     #[serde(rename = "_id", skip)]
@@ -0,0 +1,16 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = "i64"]
+struct MyDoc {
+    _id: String, //~ ERROR the type of the `_id` field `_id`, `String`, doesn't match `#[id_type]`, `i64`
+}
+
+fn main() {}
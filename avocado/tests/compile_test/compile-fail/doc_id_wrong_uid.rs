@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = "String"]
+struct Other {
+    _id: Uid<Other>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct MyDoc {
+    _id: Uid<Other>, //~ ERROR the `_id` field `_id` must be a `Uid<MyDoc>`, not a `Uid<Other>`
+}
+
+fn main() {}
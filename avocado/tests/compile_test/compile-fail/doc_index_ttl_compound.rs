@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[index(keys(owner = "ascending", expires_at = "ascending"), expire_after_secs = 60)] //~ ERROR TTL indexes must have a single ascending or descending field
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    owner: String,
+    expires_at: i64,
+}
+
+fn main() {}
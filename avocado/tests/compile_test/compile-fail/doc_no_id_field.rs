@@ -5,10 +5,10 @@ extern crate avocado;
 extern crate serde_derive;
 extern crate serde;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Doc)] //~ ERROR proc-macro derive panicked
-#[id_type = "String"] //~| a `Doc` must contain a field serialized as `_id`
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[id_type = "String"]
 #[serde(rename_all = "UPPERCASE")]
-struct Bar {
+struct Bar { //~ ERROR a `Doc` must contain a field serialized as `_id`
     _id: Uid<Bar>,
 }
 
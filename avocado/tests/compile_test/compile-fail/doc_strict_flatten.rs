@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use std::collections::HashMap;
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+#[avocado(strict)]
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    #[serde(flatten)]
+    extra: HashMap<String, Bson>, //~ ERROR `#[avocado(strict)]` can't be used with `#[serde(flatten)]` fields
+}
+
+fn main() {}
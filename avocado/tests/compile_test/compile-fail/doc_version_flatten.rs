@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate avocado_derive;
+extern crate avocado;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+use avocado::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+struct MyDoc {
+    _id: Uid<MyDoc>,
+    #[avocado(version)]
+    #[serde(flatten)]
+    version: i64, //~ ERROR a `#[serde(flatten)]` field can't be `#[avocado(version)]`
+}
+
+fn main() {}
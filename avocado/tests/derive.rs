@@ -209,6 +209,735 @@ fn doc_optional_id() -> avocado::error::Result<()> {
     Ok(())
 }
 
+#[test]
+fn doc_strict_fields() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Lenient {
+        _id: Uid<Lenient>,
+        name: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(strict)]
+    #[serde(rename_all = "camelCase")]
+    struct Strict {
+        #[serde(rename = "_id")]
+        id: Uid<Strict>,
+        legal_name: String,
+        #[serde(skip)]
+        cache: Option<String>,
+        #[serde(skip_serializing)]
+        legacy: bool,
+    }
+
+    assert_eq!(Lenient::strict_fields(), None);
+    assert_eq!(Strict::strict_fields(), Some(&["_id", "legalName", "legacy"][..]));
+}
+
+#[test]
+fn doc_defaulted_fields() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        #[serde(rename = "_id")]
+        _id: Uid<Account>,
+        #[serde(default)]
+        display_name: String,
+        #[serde(default = "default_locale")]
+        locale: String,
+        email: String,
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize, Doc)]
+    #[serde(default)]
+    #[id_type = "u64"]
+    struct Settings {
+        _id: Uid<Settings>,
+        theme: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Rigid {
+        _id: Uid<Rigid>,
+        name: String,
+    }
+
+    fn default_locale() -> String {
+        String::from("en")
+    }
+
+    assert_eq!(Account::defaulted_fields(), &["displayName", "locale"]);
+    assert_eq!(Settings::defaulted_fields(), &["_id", "theme"]);
+    assert!(Rigid::defaulted_fields().is_empty());
+}
+
+#[test]
+fn doc_version_field() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    struct Versioned {
+        #[serde(rename = "_id")]
+        _id: Uid<Versioned>,
+        #[avocado(version)]
+        row_version: i64,
+    }
+
+    let mut entity = Versioned {
+        _id: Uid::from_raw(ObjectId::new().expect("can't generate ObjectId")),
+        row_version: 3,
+    };
+
+    assert_eq!(Versioned::version_field(), Some("rowVersion"));
+    assert_eq!(entity.version(), Some(3));
+
+    entity.set_version(4);
+    assert_eq!(entity.row_version, 4);
+}
+
+#[test]
+fn doc_timestamp_fields() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Uid<Plain>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    struct Stamped {
+        #[serde(rename = "_id")]
+        _id: Uid<Stamped>,
+        #[avocado(created_at)]
+        created_at: Option<bson::UtcDateTime>,
+        #[avocado(updated_at)]
+        #[serde(rename = "modified")]
+        updated_at: Option<bson::UtcDateTime>,
+    }
+
+    assert_eq!(Plain::created_at_field(), None);
+    assert_eq!(Plain::updated_at_field(), None);
+    assert_eq!(Stamped::created_at_field(), Some("createdAt"));
+    assert_eq!(Stamped::updated_at_field(), Some("modified"));
+}
+
+#[test]
+fn subdoc_field_paths() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+    struct Geo {
+        lat: f64,
+        lng: f64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+    struct Extra {
+        note: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+    #[serde(rename_all = "camelCase")]
+    struct Address {
+        city: String,
+        #[serde(rename = "zip")]
+        postal_code: String,
+        #[avocado(subdoc)]
+        geo: Option<Geo>,
+        #[avocado(subdoc)]
+        previous_geos: Vec<Geo>,
+        #[avocado(subdoc)]
+        #[serde(flatten)]
+        extra: Extra,
+        #[serde(skip)]
+        cached: bool,
+    }
+
+    assert_eq!(Address::fields().city(), "city");
+    assert_eq!(Address::fields().postal_code(), "zip");
+    assert_eq!(Address::fields().geo().lng(), "geo.lng");
+    assert_eq!(Address::fields().extra().note(), "note");
+    assert_eq!(Address::fields_at("home").previous_geos().lat(), "home.previousGeos.lat");
+    assert_eq!(Address::fields_at("home").extra().note(), "home.note");
+    assert_eq!(Address::fields_at("home").geo().as_str(), "home.geo");
+    assert_eq!(doc!{ (Address::fields().city()): "Budapest" }, doc!{ "city": "Budapest" });
+}
+
+#[test]
+fn doc_field_paths() {
+    use avocado::update::Change;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+    struct Profile {
+        display_name: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    struct Account {
+        #[serde(rename = "_id")]
+        _id: Uid<Account>,
+        #[serde(rename = "mail")]
+        email: String,
+        login_count: u32,
+        #[avocado(subdoc)]
+        profile: Profile,
+    }
+
+    assert_eq!(Account::fields()._id(), "_id");
+    assert_eq!(Account::fields().email(), "mail");
+    assert_eq!(Account::fields().login_count(), "loginCount");
+    assert_eq!(Account::fields().profile().display_name(), "profile.display_name");
+    assert_eq!(
+        Change::inc(Account::fields().login_count(), 1),
+        Change::inc("loginCount", 1)
+    );
+}
+
+#[test]
+fn checked_filter() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Subdoc)]
+    #[serde(rename_all = "camelCase")]
+    struct Address {
+        city: String,
+        postal_code: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Person {
+        _id: Uid<Person>,
+        #[serde(rename = "fullName")]
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+        #[avocado(subdoc)]
+        address: Address,
+    }
+
+    let min_age = 21;
+    let filter = checked_flt!(Person, {
+        "name": "Alice",
+        "age": { "$gte": 18, "$lt": min_age },
+        "tags": { "$in": ["rust", "mongodb"] },
+        "address.postal_code": null,
+        "$or": [
+            { "address.city": "Budapest" },
+            { "tags": "vienna" },
+        ],
+    });
+
+    assert_eq!(filter, doc!{
+        "fullName": "Alice",
+        "age": { "$gte": 18, "$lt": 21 },
+        "tags": { "$in": ["rust", "mongodb"] },
+        "address.postalCode": Bson::Null,
+        "$or": [
+            { "address.city": "Budapest" },
+            { "tags": "vienna" },
+        ],
+    });
+    assert_eq!(checked_flt!(Person, {}), doc!{});
+}
+
+#[test]
+fn doc_id_generator() {
+    fn next_ticket_number() -> i64 {
+        42
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[id_type = "i64"]
+    #[id_generator = "next_ticket_number"]
+    struct Ticket {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        _id: Option<Uid<Ticket>>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Option<Uid<Plain>>,
+    }
+
+    assert_eq!(Ticket::generate_id(), Some(42));
+    assert_eq!(Plain::generate_id(), None);
+}
+
+#[test]
+fn doc_deleted_at_field() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Permanent {
+        _id: Uid<Permanent>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Trashable {
+        _id: Uid<Trashable>,
+        #[avocado(deleted_at)]
+        #[serde(rename = "trashedAt")]
+        deleted_at: Option<bson::UtcDateTime>,
+    }
+
+    assert_eq!(Permanent::deleted_at_field(), None);
+    assert_eq!(Trashable::deleted_at_field(), Some("trashedAt"));
+}
+
+#[test]
+fn doc_collation() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Binary {
+        _id: Uid<Binary>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[doc_collation(locale = "en", strength = 2, case_first = "upper", numeric_ordering = true)]
+    struct CaseInsensitive {
+        _id: Uid<CaseInsensitive>,
+    }
+
+    assert_eq!(Binary::collation(), None);
+    assert_eq!(CaseInsensitive::collation(), Some(doc!{
+        "locale": "en",
+        "strength": 2,
+        "caseFirst": "upper",
+        "numericOrdering": true,
+    }));
+}
+
+#[test]
+fn doc_capped() {
+    use avocado::doc::Capped;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Uncapped {
+        _id: Uid<Uncapped>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[capped(size = 1048576)]
+    struct SizeLimited {
+        _id: Uid<SizeLimited>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[capped(size = 65536, max = 100)]
+    struct CountLimited {
+        _id: Uid<CountLimited>,
+    }
+
+    assert_eq!(Uncapped::capped(), None);
+    assert_eq!(SizeLimited::capped(), Some(Capped { size: 1048576, max: None }));
+    assert_eq!(CountLimited::capped(), Some(Capped { size: 65536, max: Some(100) }));
+}
+
+#[test]
+fn doc_shard_key() {
+    use avocado::shard;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Unsharded {
+        _id: Uid<Unsharded>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[shard_key(fields(tenant = "ascending", _id = "hashed"), strict)]
+    struct Sharded {
+        _id: Uid<Sharded>,
+        tenant: String,
+    }
+
+    assert_eq!(Unsharded::shard_key(), None);
+    assert_eq!(Sharded::shard_key(), Some(shard::Key {
+        keys: doc!{ "tenant": 1, "_id": "hashed" },
+        strict: true,
+    }));
+}
+
+#[test]
+fn doc_text_index_weights() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[index(keys(title = "text", body = "text"), weights(title = 10, body = 2))]
+    struct Article {
+        _id: Uid<Article>,
+        title: String,
+        body: String,
+    }
+
+    assert_eq!(Article::indexes(), [
+        IndexModel {
+            keys: doc!{
+                "title": IndexType::Text,
+                "body": IndexType::Text,
+            },
+            options: IndexOptions {
+                weights: Some(doc!{ "title": 10, "body": 2 }),
+                ..Default::default()
+            },
+        }
+    ]);
+}
+
+#[test]
+fn doc_field_geo_indexes() {
+    use avocado::geo::Point;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    #[index(keys(name = "ascending"))]
+    struct Store {
+        #[serde(rename = "_id")]
+        _id: Uid<Store>,
+        name: String,
+        #[avocado(geo_index)]
+        shop_location: Point,
+        #[avocado(geo_index = "2d")]
+        grid_cell: [f64; 2],
+    }
+
+    assert_eq!(Store::indexes(), [
+        IndexModel {
+            keys: doc!{ "name": IndexType::Ordered(Order::Ascending) },
+            options: Default::default(),
+        },
+        IndexModel {
+            keys: doc!{ "shopLocation": IndexType::Geo2DSphere },
+            options: Default::default(),
+        },
+        IndexModel {
+            keys: doc!{ "gridCell": IndexType::Geo2D },
+            options: Default::default(),
+        },
+    ]);
+}
+
+#[test]
+fn doc_field_text_index() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Post {
+        _id: Uid<Post>,
+        #[avocado(text_index = 10)]
+        title: String,
+        #[avocado(text_index)]
+        body: String,
+        author: String,
+    }
+
+    assert_eq!(Post::indexes(), [
+        IndexModel {
+            keys: doc!{
+                "title": IndexType::Text,
+                "body": IndexType::Text,
+            },
+            options: IndexOptions {
+                weights: Some(doc!{ "title": 10 }),
+                ..Default::default()
+            },
+        }
+    ]);
+}
+
+#[test]
+fn doc_camel_case_field_naming() {
+    use avocado::doc::FieldNaming;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    #[index(keys(last_login = "descending", home_address::zip_code = "ascending"))]
+    struct Customer {
+        #[serde(rename = "_id")]
+        id: Uid<Customer>,
+        last_login: i64,
+        home_address: Document,
+    }
+
+    assert_eq!(Customer::indexes(), [
+        IndexModel {
+            keys: doc!{
+                "lastLogin": IndexType::Ordered(Order::Descending),
+                "homeAddress.zipCode": IndexType::Ordered(Order::Ascending),
+            },
+            options: Default::default(),
+        }
+    ]);
+
+    match Customer::field_naming() {
+        FieldNaming::CamelCase => {}
+        other => panic!("expected camelCase field naming, found {:?}", other),
+    }
+
+    assert_eq!(
+        Customer::field_naming().rename_document(doc!{ "last_login": { "$gt": 0 } }),
+        doc!{ "lastLogin": { "$gt": 0 } }
+    );
+}
+
+#[test]
+fn doc_factory() {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+    #[avocado(factory)]
+    struct Account {
+        _id: Uid<Account>,
+        email: String,
+        logins: u32,
+        nickname: Option<String>,
+    }
+
+    let first = AccountFactory::new().build();
+    let second = AccountFactory::new()
+        .email("x@y.z")
+        .with(|account| account.logins = 3)
+        .build();
+
+    assert!(first.email.starts_with("email-"));
+    assert_eq!(first.nickname, None);
+    assert_ne!(first._id, second._id);
+    assert_eq!(second.email, "x@y.z");
+    assert_eq!(second.logins, 3);
+}
+
+#[test]
+fn doc_lifecycle_hooks() {
+    use avocado::error::{ ErrorExt, Result };
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(before_insert = "normalize_email", before_update = "forbid_email_change")]
+    #[avocado(before_delete = "require_filter", after_load = "default_role")]
+    struct Member {
+        _id: Uid<Member>,
+        email: String,
+        role: String,
+    }
+
+    fn normalize_email(member: &Member, doc: &mut Document) -> Result<()> {
+        doc.insert("email", member.email.to_lowercase());
+        Ok(())
+    }
+
+    fn forbid_email_change(update: &mut Document) -> Result<()> {
+        match update.get_document("$set") {
+            Ok(set) if set.contains_key("email") => {
+                Err(AvocadoError::new(AvocadoErrorKind::InvalidUpdate, "email is immutable"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn require_filter(filter: &Document) -> Result<()> {
+        if filter.is_empty() {
+            Err(AvocadoError::new(AvocadoErrorKind::InvalidFilter, "refusing to delete all members"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn default_role(doc: &mut Document) -> Result<()> {
+        if !doc.contains_key("role") {
+            doc.insert("role", "guest");
+        }
+        Ok(())
+    }
+
+    let member = Member {
+        _id: Uid::new_oid().unwrap(),
+        email: String::from("Alice@Example.COM"),
+        role: String::from("admin"),
+    };
+    let mut doc = doc!{ "email": "Alice@Example.COM" };
+    member.before_insert(&mut doc).unwrap();
+    assert_eq!(doc.get_str("email").unwrap(), "alice@example.com");
+
+    assert!(Member::before_update(&mut doc!{ "$set": { "role": "owner" } }).is_ok());
+    let error = Member::before_update(&mut doc!{ "$set": { "email": "x@y.z" } }).unwrap_err();
+    assert_eq!(error.kind(), AvocadoErrorKind::InvalidUpdate);
+
+    assert!(Member::before_delete(&doc!{ "role": "guest" }).is_ok());
+    assert!(Member::before_delete(&doc!{}).is_err());
+
+    let mut loaded = doc!{ "email": "bob@example.com" };
+    Member::after_load(&mut loaded).unwrap();
+    assert_eq!(loaded.get_str("role").unwrap(), "guest");
+}
+
+#[test]
+fn doc_encrypted_fields() {
+    use avocado::encrypt::{ EncryptedField, EncryptionMode };
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename_all = "camelCase")]
+    struct Patient {
+        #[serde(rename = "_id")]
+        _id: Uid<Patient>,
+        name: String,
+        #[avocado(encrypted = "deterministic")]
+        social_security_number: String,
+        #[avocado(encrypted)]
+        diagnosis: Option<String>,
+        #[avocado(encrypted = "randomized")]
+        notes: Vec<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Uid<Plain>,
+        name: String,
+    }
+
+    assert_eq!(Patient::encrypted_fields(), &[
+        EncryptedField { name: "socialSecurityNumber", mode: EncryptionMode::Deterministic },
+        EncryptedField { name: "diagnosis", mode: EncryptionMode::Randomized },
+        EncryptedField { name: "notes", mode: EncryptionMode::Randomized },
+    ]);
+    assert!(Plain::encrypted_fields().is_empty());
+}
+
+#[test]
+fn doc_audit_collection() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(rename = "accounts")]
+    #[avocado(audited)]
+    struct Account {
+        _id: Uid<Account>,
+        balance: i64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(audited = "payment_history")]
+    struct Payment {
+        _id: Uid<Payment>,
+        amount: i64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Uid<Plain>,
+    }
+
+    assert_eq!(Account::audit_collection(), Some("accounts_audit"));
+    assert_eq!(Payment::audit_collection(), Some("payment_history"));
+    assert_eq!(Plain::audit_collection(), None);
+}
+
+#[test]
+fn doc_check_field_names() {
+    use std::collections::HashMap;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(check_field_names)]
+    struct Settings {
+        _id: Uid<Settings>,
+        values: HashMap<String, i32>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Uid<Plain>,
+    }
+
+    assert!(Settings::check_field_names());
+    assert!(!Plain::check_field_names());
+}
+
+#[test]
+fn doc_serde_profile() {
+    use avocado::profile::{ self, NoneFields, UnsignedRepr };
+
+    const SKIP_NONE: profile::Profile = profile::Profile {
+        none_fields: NoneFields::Skip,
+        unsigned: UnsignedRepr::Int64,
+    };
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(serde_profile = "SKIP_NONE")]
+    struct Sparse {
+        _id: Uid<Sparse>,
+        comment: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    struct Plain {
+        _id: Uid<Plain>,
+    }
+
+    assert_eq!(Sparse::SERDE_PROFILE, SKIP_NONE);
+    assert_eq!(Plain::SERDE_PROFILE, profile::Profile::DEFAULT);
+}
+
+#[test]
+fn doc_tagged_enum() {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    #[avocado(strict)]
+    #[index(keys(kind = "ascending"))]
+    enum Vehicle {
+        Car {
+            _id: Uid<Vehicle>,
+            seats: u32,
+        },
+        CargoTruck {
+            #[serde(rename = "_id")]
+            id: Option<Uid<Vehicle>>,
+            capacity: f64,
+        },
+        #[serde(rename = "bike")]
+        Bicycle {
+            _id: Uid<Vehicle>,
+            seats: u32,
+        },
+    }
+
+    let id = Uid::new_oid().expect("can't generate ObjectId");
+    let mut car = Vehicle::Car { _id: id.clone(), seats: 4 };
+    let mut truck = Vehicle::CargoTruck { id: None, capacity: 12.5 };
+
+    assert_eq!(Vehicle::NAME, "Vehicle");
+    assert_eq!(Vehicle::tag_field(), Some("kind"));
+    assert_eq!(Vehicle::variant_tags(), &["car", "cargo_truck", "bike"]);
+    assert_eq!(Vehicle::strict_fields(), Some(&["kind", "_id", "seats", "capacity"][..]));
+    assert_eq!(Vehicle::indexes().len(), 1);
+
+    assert_eq!(car.id(), Some(&id));
+    assert_eq!(car.variant_tag(), Some("car"));
+    assert_eq!(truck.id(), None);
+    assert_eq!(truck.variant_tag(), Some("cargo_truck"));
+
+    let other_id = Uid::new_oid().expect("can't generate ObjectId");
+    car.set_id(other_id.clone());
+    truck.set_id(other_id.clone());
+    assert_eq!(car.id(), Some(&other_id));
+    assert_eq!(truck.id(), Some(&other_id));
+}
+
+#[test]
+fn doc_enum_subtype() {
+    use avocado::variant::Subtype;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum Account {
+        #[avocado(subtype = "Savings")]
+        Savings {
+            _id: Uid<Account>,
+            rate: f64,
+            #[serde(skip)]
+            cached_interest: Option<f64>,
+        },
+        Checking {
+            _id: Uid<Account>,
+        },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Savings {
+        _id: Uid<Account>,
+        rate: f64,
+        #[serde(skip)]
+        cached_interest: Option<f64>,
+    }
+
+    let id = Uid::new_oid().expect("can't generate ObjectId");
+    let savings = Savings { _id: id.clone(), rate: 0.02, cached_interest: None };
+    let account: Account = savings.into();
+
+    assert_eq!(<Savings as Subtype<Account>>::TAG, "savings");
+    assert_eq!(account, Account::Savings { _id: id, rate: 0.02, cached_interest: None });
+    assert_eq!(account.variant_tag(), Some("savings"));
+}
+
 #[test]
 fn doc_generic_lifetime_only() {
     #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
@@ -221,6 +950,88 @@ fn doc_generic_lifetime_only() {
     assert_doc_impl!(Doc: GenericLifetime, Id: u32, name: GenericLifetime, index: &[]);
 }
 
+#[test]
+fn doc_generic_type_params() {
+    use serde::{ Serialize, de::DeserializeOwned };
+    use avocado::doc::CollectionName;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[serde(bound = "P: Serialize + DeserializeOwned")]
+    struct Envelope<P: Serialize + DeserializeOwned> {
+        _id: Uid<Envelope<P>>,
+        payload: P,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct OrderPlaced {
+        total: f64,
+    }
+
+    impl CollectionName for OrderPlaced {
+        const COLLECTION_NAME: &'static str = "order_events";
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[avocado(name_from = "P")]
+    #[id_type = "i64"]
+    #[serde(bound = "P: Serialize + DeserializeOwned + CollectionName")]
+    struct Event<'a, P: Serialize + DeserializeOwned + CollectionName> {
+        _id: Uid<Event<'a, P>>,
+        source: PhantomData<&'a ()>,
+        payload: P,
+    }
+
+    assert_doc_impl!(Doc: Envelope<String>, Id: ObjectId, name: Envelope, index: &[]);
+    assert_doc_impl!(Doc: Envelope<OrderPlaced>, Id: ObjectId, name: Envelope, index: &[]);
+    assert_doc_impl!(Doc: Event<OrderPlaced>, Id: i64, name: order_events, index: &[]);
+
+    let mut event = Event { _id: Uid::from_raw(1), source: PhantomData, payload: OrderPlaced { total: 9.5 } };
+    assert_eq!(event.id(), Some(&Uid::from_raw(1)));
+    event.set_id(Uid::from_raw(2));
+    assert_eq!(event._id, Uid::from_raw(2));
+}
+
+#[test]
+fn doc_collection_naming() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[collection_name = "ledger"]
+    #[avocado(audited)]
+    struct LedgerEntry {
+        _id: Uid<LedgerEntry>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[collection_naming = "snake_case_plural"]
+    struct OrderEntry {
+        _id: Uid<OrderEntry>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[collection_naming = "camelCase_plural"]
+    struct ShippingAddress {
+        _id: Uid<ShippingAddress>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[collection_naming = "plural"]
+    struct Holiday {
+        _id: Uid<Holiday>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[collection_naming = "lowercase"]
+    struct UserProfile {
+        _id: Uid<UserProfile>,
+    }
+
+    assert_eq!(LedgerEntry::NAME, "ledger");
+    assert_eq!(LedgerEntry::audit_collection(), Some("ledger_audit"));
+    assert_eq!(OrderEntry::NAME, "order_entries");
+    assert_eq!(ShippingAddress::NAME, "shippingAddresses");
+    assert_eq!(Holiday::NAME, "Holidays");
+    assert_eq!(UserProfile::NAME, "userprofile");
+}
+
 #[test]
 fn doc_index() {
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -314,6 +1125,50 @@ fn doc_index_options() {
     ]);
 }
 
+#[test]
+fn doc_index_ttl() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[index(keys(created = "ascending"), expire_after_seconds = 3600)]
+    struct Session {
+        _id: Uid<Session>,
+        created: i64,
+    }
+
+    assert_eq!(Session::indexes(), [
+        IndexModel {
+            keys: doc!{
+                "created": IndexType::Ordered(Order::Ascending)
+            },
+            options: IndexOptions {
+                expire_after_seconds: Some(3600),
+                ..Default::default()
+            },
+        }
+    ]);
+}
+
+#[test]
+fn doc_index_ttl_secs() {
+    #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+    #[index(keys(expires_at = "ascending"), expire_after_secs = 0)]
+    struct CacheEntry {
+        _id: Uid<CacheEntry>,
+        expires_at: i64,
+    }
+
+    assert_eq!(CacheEntry::indexes(), [
+        IndexModel {
+            keys: doc!{
+                "expires_at": IndexType::Ordered(Order::Ascending)
+            },
+            options: IndexOptions {
+                expire_after_seconds: Some(0),
+                ..Default::default()
+            },
+        }
+    ]);
+}
+
 #[test]
 fn doc_index_embedded_paths() {
     #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
@@ -28,8 +28,9 @@ use std::sync::Mutex;
 use std::iter::FromIterator;
 use std::collections::{ HashSet, BTreeSet, BTreeMap };
 use std::process::{ Command, Child, Stdio };
-use avocado::error::Result;
+use avocado::error::{ ErrorExt, Result };
 use avocado::prelude::*;
+use avocado::testing::{ assert_uses_index, assert_no_collection_scan, used_indexes, plan_stages };
 
 /// Used for killing the MongoDB server process once all tests have run.
 struct ProcessGuard {
@@ -189,109 +190,2271 @@ struct PullRequest {
     lines_changed: usize,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, BsonSchema, Doc)]
+struct Ticket {
+    _id: Uid<Ticket>,
+    status: String,
+    #[avocado(version)]
+    version: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+struct Note {
+    _id: Uid<Note>,
+    text: String,
+    #[avocado(created_at)]
+    created_at: Option<bson::UtcDateTime>,
+    #[avocado(updated_at)]
+    updated_at: Option<bson::UtcDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+struct Memo {
+    _id: Uid<Memo>,
+    subject: String,
+    #[avocado(deleted_at)]
+    deleted_at: Option<bson::UtcDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[id_type = "String"]
+#[id_generator = "new_label_id"]
+struct Label {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<Uid<Label>>,
+    color: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+struct LogEntry {
+    _id: Uid<LogEntry>,
+    message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Doc)]
+#[id_type = "String"]
+struct LinesPerTitle {
+    _id: Uid<LinesPerTitle>,
+    lines: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[capped(size = 4096, max = 3)]
+struct Job {
+    _id: Uid<Job>,
+    seq: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[avocado(before_insert = "slugify_title", before_delete = "require_filter")]
+#[avocado(after_load = "count_load")]
+struct Article {
+    _id: Uid<Article>,
+    title: String,
+    #[serde(default)]
+    slug: String,
+    #[serde(default)]
+    loads: i32,
+}
+
+fn slugify_title(article: &Article, doc: &mut Document) -> Result<()> {
+    doc.insert("slug", article.title.to_lowercase().replace(' ', "-"));
+    Ok(())
+}
+
+fn require_filter(filter: &Document) -> Result<()> {
+    if filter.is_empty() {
+        Err(AvocadoError::new(AvocadoErrorKind::InvalidFilter, "unfiltered deletion"))
+    } else {
+        Ok(())
+    }
+}
+
+fn count_load(doc: &mut Document) -> Result<()> {
+    let loads = doc.get_i32("loads").unwrap_or(0);
+    doc.insert("loads", loads + 1);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[id_type = "i64"]
+struct Receipt {
+    _id: Uid<Receipt>,
+    title: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[serde(tag = "species", rename_all = "lowercase")]
+enum Pet {
+    #[avocado(subtype = "Cat")]
+    Cat {
+        _id: Uid<Pet>,
+        name: String,
+        lives: i32,
+    },
+    Dog {
+        _id: Uid<Pet>,
+        name: String,
+        good: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cat {
+    _id: Uid<Pet>,
+    name: String,
+    lives: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[avocado(audited)]
+struct Ledger {
+    _id: Uid<Ledger>,
+    owner: String,
+    balance: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+#[id_type = "String"]
+#[index(keys(expires_at = "ascending"), expire_after_secs = 0)]
+struct Rate {
+    _id: Uid<Rate>,
+    value: f64,
+    expires_at: Option<bson::UtcDateTime>,
+}
+
+fn new_label_id() -> String {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    static NEXT_LABEL: AtomicUsize = AtomicUsize::new(1);
+
+    format!("label-{}", NEXT_LABEL.fetch_add(1, Ordering::SeqCst))
+}
+
 // Finally, the actual tests.
 
-implement_tests!{
+implement_tests!{
+    #[test]
+    fn basic_insertion_deletion_raw_doc() -> Result<()> {
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+
+        let group_1 = Group {
+            _id: Uid::new_oid()?,
+            name: String::from("Fancy FinTech, Inc."),
+            description: String::from("IoT AI on the quantum blockchain"),
+        };
+        let group_2 = Group {
+            _id: Uid::new_oid()?,
+            name: String::from("PHP Shop, Ltd."),
+            description: String::from("It's Shit But They Pay For It [TM]"),
+        };
+
+        // No documents before insertion
+        assert!(coll.find_one(doc!{})?.is_none());
+        assert!(!coll.find_many(doc!{})?.has_next()?);
+        assert_eq!(coll.count(doc!{})?, 0);
+
+        // Can insert 0 documents
+        let no_ids = coll.insert_many(&[])?;
+        assert!(no_ids.is_empty());
+
+        // Can insert but don't allow duplicates
+        let id_1 = coll.insert_one(&group_1)?;
+        assert!(coll.insert_many(vec![&group_1]).is_err());
+        assert_eq!(id_1, group_1._id);
+
+        let ids_2 = coll.insert_many(vec![&group_2])?;
+        assert!(coll.insert_one(&group_2).is_err());
+        assert_eq!(
+            ids_2,
+            BTreeMap::from_iter(vec![(0, group_2._id.clone())]),
+        );
+
+        // Can retrieve documents after insertion
+        assert_eq!(
+            coll.find_one(doc!{ "name": "PHP Shop, Ltd." })?.as_ref(),
+            Some(&group_2)
+        );
+        assert_eq!(
+            coll.find_many(doc!{ "_id": &group_1._id })?.collect::<Result<Vec<_>>>()?,
+            vec![group_1.clone()]
+        );
+
+        // Can delete after insertion, too
+        assert!(coll.delete_entity(&group_2)?);
+        assert_eq!(coll.delete_entities(vec![&group_1])?, 1);
+
+        assert!(!coll.delete_entity(&group_1)?);
+        assert_eq!(coll.delete_entities(vec![&group_2])?, 0);
+
+        // No documents after deletion
+        assert!(coll.find_one(doc!{})?.is_none());
+        assert!(!coll.find_many(doc!{})?.has_next()?);
+        assert_eq!(coll.count(doc!{})?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn autogen_optional_id_consistent() -> Result<()> {
+        // Automatically-generated `_id` must be consistent
+        let coll: Collection<Commit> = DB_HANDLE.empty_collection()?;
+        let commit = Commit {
+            id: None,
+            hash: String::from("789abcd"),
+        };
+        let generated_commit_id = coll.insert_one(&commit)?;
+        let found_commit = coll.find_one(doc!{ "hash": &commit.hash })?;
+
+        assert_eq!(found_commit, Some(
+            Commit { id: Some(generated_commit_id), ..commit }
+        ));
+
+        // Try more than one at once
+        let more_commits = vec![
+            Commit {
+                id: Some(Uid::new_oid()?),
+                hash: String::from("0123456"),
+            },
+            Commit {
+                id: None,
+                hash: String::from("ef01234"),
+            },
+            Commit {
+                id: Some(Uid::new_oid()?),
+                hash: String::from("cadbfe8"),
+            },
+        ];
+        let ids = coll.insert_many(&more_commits)?;
+
+        assert_eq!(more_commits.len(), ids.len());
+        assert_eq!(Some(&ids[&0]), more_commits[0].id());
+        assert_eq!(Some(&ids[&2]), more_commits[2].id());
+
+        assert_ne!(ids[&0], ids[&1]);
+        assert_ne!(ids[&0], ids[&2]);
+        assert_ne!(ids[&1], ids[&2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn idempotent_insertion() -> Result<()> {
+        let coll: Collection<Commit> = DB_HANDLE.empty_collection()?;
+        coll.create_idempotency_index()?;
+
+        let commit = Commit {
+            id: None,
+            hash: String::from("1234567"),
+        };
+
+        // The first attempt actually inserts the document
+        let first = coll.insert_one_idempotent(&commit, "request-1")?;
+        assert!(!first.already_applied);
+
+        // A retry with the same key doesn't create a duplicate
+        let retry = coll.insert_one_idempotent(&commit, "request-1")?;
+        assert!(retry.already_applied);
+        assert_eq!(retry.id, first.id);
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        // A different key means a different operation
+        let other = coll.insert_one_idempotent(&commit, "request-2")?;
+        assert!(!other.already_applied);
+        assert_ne!(other.id, first.id);
+        assert_eq!(coll.count(doc!{})?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_counts() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let titles = ["fix", "feature", "fix", "docs", "fix", "feature"];
+        let prs: Vec<_> = titles
+            .iter()
+            .enumerate()
+            .map(|(i, title)| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: title.to_string(),
+                lines_changed: i * 10,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        // Groups are ordered by descending count
+        let counts: Vec<(String, i64)> = coll.count_by("title", doc!{})?;
+        assert_eq!(counts, [
+            (String::from("fix"), 3),
+            (String::from("feature"), 2),
+            (String::from("docs"), 1),
+        ]);
+
+        // The filter is applied before grouping
+        let counts: Vec<(String, i64)> = coll.count_by(
+            "$title",
+            doc!{ "lines_changed": { "$gte": 20 } },
+        )?;
+        assert_eq!(counts, [
+            (String::from("fix"), 2),
+            (String::from("docs"), 1),
+            (String::from("feature"), 1),
+        ]);
+
+        // Arbitrary expressions can be used as the key
+        let counts: Vec<(bool, i64)> = coll.count_by(
+            doc!{ "$gte": ["$lines_changed", 30] },
+            doc!{},
+        )?;
+        assert_eq!(counts, [(false, 3), (true, 3)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_collection() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+        use avocado::coll::CopyOptions;
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = (0..25)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let target_name = "PullRequestCopy";
+        DB_HANDLE.drop_collection(target_name)?;
+
+        let options = CopyOptions {
+            filter: doc!{ "lines_changed": { "$lt": 20 } },
+            batch_size: 8,
+            target_name: Some(target_name.into()),
+            ..Default::default()
+        };
+        let mut reports = Vec::new();
+        let result = coll.copy_to(&DB_HANDLE, options, |progress| reports.push(*progress))?;
+
+        assert_eq!(result.documents, 20);
+        assert_eq!(result.batches, 3);
+        assert_eq!(
+            reports.iter().map(|p| p.documents).collect::<Vec<_>>(),
+            [8, 16, 20]
+        );
+
+        let copy: Collection<PullRequest> = DB_HANDLE.collection(target_name).into();
+        let copied: BTreeSet<_> = copy
+            .find_many(doc!{})?
+            .map(|pr| pr.map(|pr| pr.lines_changed))
+            .collect::<Result<_>>()?;
+
+        assert_eq!(copied, (0..20).collect());
+        assert_eq!(coll.count(doc!{})?, 25);
+
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_insert() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs = (0..250).map(|i| PullRequest {
+            id: Uid::new_oid().expect("can't generate ObjectId"),
+            title: format!("PR #{}", i),
+            lines_changed: i,
+        });
+        let mut n_reports = 0;
+        let result = coll.insert_stream(prs, |_| n_reports += 1)?;
+
+        assert_eq!(result.documents, 250);
+        assert_eq!(result.batches, 1);
+        assert_eq!(n_reports, 1);
+        assert!(result.errors.is_empty());
+        assert_eq!(coll.count(doc!{})?, 250);
+
+        Ok(())
+    }
+
+    #[test]
+    fn optimistic_concurrency() -> Result<()> {
+        let coll: Collection<Ticket> = DB_HANDLE.empty_collection()?;
+        let mut ticket = Ticket {
+            _id: Uid::new_oid()?,
+            status: String::from("open"),
+            version: 0,
+        };
+        coll.insert_one(&ticket)?;
+
+        let mut concurrent = ticket.clone();
+
+        ticket.status = String::from("in progress");
+        coll.replace_entity_versioned(&mut ticket)?;
+        assert_eq!(ticket.version, 1);
+
+        concurrent.status = String::from("closed");
+        let error = coll.replace_entity_versioned(&mut concurrent).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::StaleVersion);
+        assert_eq!(concurrent.version, 0);
+
+        #[derive(Debug)]
+        struct Close<'a>(&'a Uid<Ticket>);
+
+        impl<'a> Update<Ticket> for Close<'a> {
+            fn filter(&self) -> Document {
+                doc!{ "_id": self.0 }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "status": "closed" } }
+            }
+        }
+
+        assert!(coll.update_one_versioned(0, Close(&ticket._id)).is_err());
+        assert_eq!(coll.update_one_versioned(1, Close(&ticket._id))?, 2);
+
+        let stored = coll.find_one(doc!{ "_id": &ticket._id })?.expect("ticket not found");
+        assert_eq!(stored.status, "closed");
+        assert_eq!(stored.version, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn automatic_timestamps() -> Result<()> {
+        #[derive(Debug)]
+        struct Edit<'a>(&'a Uid<Note>);
+
+        impl<'a> Update<Note> for Edit<'a> {
+            fn filter(&self) -> Document {
+                doc!{ "_id": self.0 }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "text": "edited" } }
+            }
+        }
+
+        let coll: Collection<Note> = DB_HANDLE.empty_collection_novalidate()?;
+        let note = Note {
+            _id: Uid::new_oid()?,
+            text: String::from("draft"),
+            created_at: None,
+            updated_at: None,
+        };
+        coll.insert_one(&note)?;
+
+        let inserted = coll.find_one(doc!{ "_id": &note._id })?.expect("note not found");
+        assert!(inserted.created_at.is_some());
+        assert_eq!(inserted.created_at, inserted.updated_at);
+
+        coll.update_one(Edit(&note._id))?;
+
+        let updated = coll.find_one(doc!{ "_id": &note._id })?.expect("note not found");
+        assert_eq!(updated.text, "edited");
+        assert_eq!(updated.created_at, inserted.created_at);
+        assert!(updated.updated_at >= inserted.updated_at);
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_documents() -> Result<()> {
+        use mongodb::coll::options::CountOptions;
+
+        #[derive(Debug)]
+        struct Paged(CountOptions);
+
+        impl Count<Group> for Paged {
+            fn options(&self) -> CountOptions {
+                self.0.clone()
+            }
+        }
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = (0..5)
+            .map(|i| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: format!("group {}", i),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        assert_eq!(coll.count(doc!{})?, 0);
+        assert_eq!(coll.estimated_count()?, 0);
+
+        coll.insert_many(&groups)?;
+
+        assert_eq!(coll.count(doc!{})?, 5);
+        assert_eq!(coll.count(doc!{ "name": "group 3" })?, 1);
+        assert_eq!(coll.estimated_count()?, 5);
+
+        let options = CountOptions {
+            skip: Some(1),
+            limit: Some(3),
+            hint_doc: Some(doc!{ "_id": 1 }),
+            ..CountOptions::default()
+        };
+        assert_eq!(coll.count(Paged(options))?, 3);
+
+        let options = CountOptions {
+            skip: Some(4),
+            limit: Some(0),
+            ..CountOptions::default()
+        };
+        assert_eq!(coll.count(Paged(options))?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collated_operations() -> Result<()> {
+        #[derive(Debug)]
+        struct ByName(&'static str);
+
+        impl Query<Group> for ByName {
+            type Output = Group;
+
+            fn filter(&self) -> Document {
+                doc!{ "name": self.0 }
+            }
+
+            fn command_options(&self) -> CommandOptions {
+                CommandOptions::new().collation(doc!{ "locale": "en", "strength": 2 })
+            }
+        }
+
+        impl Update<Group> for ByName {
+            fn filter(&self) -> Document {
+                doc!{ "name": self.0 }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "description": "renamed" } }
+            }
+
+            fn command_options(&self) -> CommandOptions {
+                CommandOptions::new()
+                    .collation(doc!{ "locale": "en", "strength": 2 })
+                    .max_time_ms(10_000)
+            }
+        }
+
+        impl Delete<Group> for ByName {
+            fn filter(&self) -> Document {
+                doc!{ "name": self.0 }
+            }
+
+            fn command_options(&self) -> CommandOptions {
+                CommandOptions::new()
+                    .hint(doc!{ "_id": 1 })
+                    .collation(doc!{ "locale": "en", "strength": 2 })
+            }
+        }
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["Alpha", "alpha", "Beta"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        assert_eq!(coll.find_many(ByName("ALPHA"))?.count(), 2);
+        assert!(coll.find_one(ByName("beta"))?.is_some());
+        assert!(coll.find_one(doc!{ "name": "beta" })?.is_none());
+
+        let result = coll.update_many(ByName("ALPHA"))?;
+        assert_eq!(result.num_matched, 2);
+        assert_eq!(coll.count(doc!{ "description": "renamed" })?, 2);
+
+        assert_eq!(coll.delete_many(ByName("aLpHa"))?, 2);
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collection_administration() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+
+        DB_HANDLE.drop_collection_of::<LogEntry>()?;
+        DB_HANDLE.drop_collection("LogEntryArchive")?;
+
+        let coll: Collection<LogEntry> = DB_HANDLE.create_capped(4096, Some(2))?;
+        let entries: Vec<_> = (0..3)
+            .map(|i| Ok(LogEntry {
+                _id: Uid::new_oid()?,
+                message: format!("entry {}", i),
+            }))
+            .collect::<Result<_>>()?;
+
+        for entry in &entries {
+            coll.insert_one(entry)?;
+        }
+
+        let stats = DB_HANDLE.collection_stats::<LogEntry>()?;
+        assert_eq!(stats.count, 2);
+        assert!(stats.capped);
+        assert_eq!(stats.max, Some(2));
+        assert!(stats.index_sizes.contains_key("_id_"));
+
+        let info = DB_HANDLE
+            .list_collections_typed()?
+            .into_iter()
+            .find(|info| info.name == "LogEntry")
+            .expect("capped collection not listed");
+        assert_eq!(info.kind, "collection");
+        assert!(info.capped);
+        assert!(!info.read_only);
+
+        DB_HANDLE.rename_collection::<LogEntry>("LogEntryArchive", false)?;
+
+        let names: Vec<_> = DB_HANDLE
+            .list_collections_typed()?
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        assert!(names.iter().any(|name| name == "LogEntryArchive"));
+        assert!(!names.iter().any(|name| name == "LogEntry"));
+
+        DB_HANDLE.drop_collection("LogEntryArchive")?;
+        DB_HANDLE.drop_collection_of::<LogEntry>()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_collections() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+
+        let first = format!("tenant_a_{}", LogEntry::NAME);
+        let second = format!("tenant_b_{}", LogEntry::NAME);
+        let coll_a: Collection<LogEntry> = DB_HANDLE.empty_collection_novalidate_named(&first)?;
+        let coll_b: Collection<LogEntry> = DB_HANDLE.empty_collection_novalidate_named(&second)?;
+
+        coll_a.insert_one(&LogEntry { _id: Uid::new_oid()?, message: String::from("a") })?;
+
+        assert_eq!(coll_a.count(doc!{})?, 1);
+        assert_eq!(coll_b.count(doc!{})?, 0);
+        assert_eq!(DB_HANDLE.existing_collection_named::<LogEntry>(&first).count(doc!{})?, 1);
+
+        DB_HANDLE.drop_collection(&first)?;
+        DB_HANDLE.drop_collection(&second)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_scoped_databases() -> Result<()> {
+        use avocado::tenant::{ Tenant, Isolation, ScopedDatabase };
+
+        let acme = ScopedDatabase::new(&DB_HANDLE, Tenant::new("acme")?, Isolation::CollectionPrefix);
+        let globex = ScopedDatabase::new(&DB_HANDLE, Tenant::new("globex")?, Isolation::Database);
+
+        assert_eq!(acme.collection_name::<LogEntry>(), "acme_LogEntry");
+        assert_eq!(globex.collection_name::<LogEntry>(), "LogEntry");
+        assert_eq!(globex.database().name, format!("{}_globex", DB_HANDLE.name));
+
+        let acme_coll: Collection<LogEntry> = acme.empty_collection_novalidate()?;
+        let globex_coll: Collection<LogEntry> = globex.empty_collection_novalidate()?;
+
+        acme_coll.insert_one(&LogEntry { _id: Uid::new_oid()?, message: String::from("acme") })?;
+
+        assert_eq!(acme.existing_collection::<LogEntry>().count(doc!{})?, 1);
+        assert_eq!(globex_coll.count(doc!{})?, 0);
+        assert_eq!(acme.collection_names()?, vec![String::from("acme_LogEntry")]);
+
+        acme.drop_tenant()?;
+        globex.drop_tenant()?;
+
+        assert!(acme.collection_names()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tail_capped_collection() -> Result<()> {
+        let coll: Collection<Job> = DB_HANDLE.empty_collection_novalidate()?;
+        let info = DB_HANDLE
+            .list_collections_typed()?
+            .into_iter()
+            .find(|info| info.name == "Job")
+            .expect("capped collection not created");
+
+        assert!(info.capped);
+
+        for seq in 0..5 {
+            coll.insert_one(&Job { _id: Uid::new_oid()?, seq })?;
+        }
+
+        let seqs: Vec<_> = coll
+            .tail(doc!{ "seq": { "$gte": 1 } })?
+            .map(|job| job.map(|job| job.seq))
+            .collect::<Result<_>>()?;
+
+        // Only the last 3 jobs fit in the collection, in insertion order.
+        assert_eq!(seqs, [2, 3, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn retrying_writes() -> Result<()> {
+        use avocado::retry;
+
+        #[derive(Debug, Clone)]
+        struct SetMessage(Uid<LogEntry>, &'static str);
+
+        impl Update<LogEntry> for SetMessage {
+            fn filter(&self) -> Document {
+                doc!{ "_id": &self.0 }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "message": self.1 } }
+            }
+        }
+
+        let coll: Collection<LogEntry> = DB_HANDLE.empty_collection_novalidate()?;
+        let retrying = coll.retrying(retry::Policy::default());
+        let entry = LogEntry { _id: Uid::new_oid()?, message: String::from("started") };
+
+        retrying.insert_one(&entry)?;
+
+        // A duplicate key isn't transient, so it fails immediately.
+        let error = retrying.insert_one(&entry).unwrap_err();
+        assert!(error.is_duplicate_key());
+        assert!(!retrying.policy().is_retryable(&error));
+
+        let updated = retrying.update_one(SetMessage(entry._id.clone(), "stopped"))?;
+        assert!(updated.modified);
+        assert!(retrying.delete_entity(&entry)?);
+        assert_eq!(coll.count(doc!{})?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_or_fetch() -> Result<()> {
+        use avocado::coll::InsertOrFetch;
+
+        let coll: Collection<User> = DB_HANDLE.empty_collection()?;
+        let user = User {
+            _id: Uid::new_oid()?,
+            legal_name: String::from("Jane Doe"),
+            username: String::from("jdoe"),
+            repos: HashSet::new(),
+            groups: HashSet::new(),
+        };
+        let impostor = User {
+            _id: Uid::new_oid()?,
+            legal_name: String::from("John Doe"),
+            ..user.clone()
+        };
+
+        match coll.insert_or_fetch(&user, doc!{ "username": "jdoe" })? {
+            InsertOrFetch::Inserted(id) => assert_eq!(id, user._id),
+            InsertOrFetch::Existing(existing) => panic!("unexpected conflict: {:?}", existing),
+        }
+
+        let fetched = coll.insert_or_fetch(&impostor, doc!{ "username": "jdoe" })?;
+        assert!(fetched.is_existing());
+        assert_eq!(fetched.existing().map(|u| u.legal_name), Some(String::from("Jane Doe")));
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        // A conflict on another index isn't resolved by the filter.
+        let error = coll.insert_or_fetch(&user, doc!{ "username": "nobody" }).unwrap_err();
+        assert!(error.is_duplicate_key());
+
+        Ok(())
+    }
+
+    #[test]
+    fn command_listeners() -> Result<()> {
+        use std::sync::{ Arc, Mutex };
+        use avocado::monitor::{ self, CommandListener, CommandStarted, CommandSucceeded };
+
+        #[derive(Debug, Clone, Default)]
+        struct Recorder {
+            started: Arc<Mutex<Vec<CommandStarted>>>,
+            succeeded: Arc<Mutex<Vec<CommandSucceeded>>>,
+        }
+
+        impl CommandListener for Recorder {
+            fn started(&self, event: &CommandStarted) {
+                self.started.lock().unwrap().push(event.clone());
+            }
+
+            fn succeeded(&self, event: &CommandSucceeded) {
+                self.succeeded.lock().unwrap().push(event.clone());
+            }
+        }
+
+        let coll: Collection<LogEntry> = DB_HANDLE.empty_collection_novalidate()?;
+        let recorder = Recorder::default();
+        let handle = coll.add_command_listener(recorder.clone())?;
+
+        coll.insert_one(&LogEntry { _id: Uid::new_oid()?, message: String::from("secret") })?;
+        coll.find_one(doc!{ "message": "secret" })?;
+
+        assert!(monitor::remove_listener(handle));
+        coll.count(doc!{})?;
+
+        let started = recorder.started.lock().unwrap();
+        let find = started
+            .iter()
+            .find(|event| event.command_name == "find")
+            .expect("find not recorded");
+
+        assert_eq!(find.collection.as_ref().map(String::as_str), Some("LogEntry"));
+        assert_eq!(find.filter, Some(doc!{ "message": "?" }));
+        assert!(started.iter().all(|event| event.collection.as_ref().map(String::as_str) == Some("LogEntry")));
+        assert!(started.iter().all(|event| event.command_name != "count"));
+        assert_eq!(recorder.succeeded.lock().unwrap().len(), started.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn generated_ids() -> Result<()> {
+        let coll: Collection<Label> = DB_HANDLE.empty_collection_novalidate()?;
+        let generated = coll.insert_one(&Label {
+            _id: None,
+            color: String::from("red"),
+        })?;
+        let explicit = coll.insert_one(&Label {
+            _id: Some(Uid::from_raw(String::from("urgent"))),
+            color: String::from("orange"),
+        })?;
+
+        assert!(generated.as_ref().starts_with("label-"));
+        assert_eq!(explicit.as_ref(), "urgent");
+
+        let stored = coll.find_one(doc!{ "_id": &generated })?.expect("label not found");
+        assert_eq!(stored.color, "red");
+
+        let ids = coll.insert_many(vec![
+            Label { _id: None, color: String::from("green") },
+            Label { _id: None, color: String::from("blue") },
+        ])?;
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[&0], ids[&1]);
+        assert_eq!(coll.count(doc!{})?, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn soft_delete() -> Result<()> {
+        let coll: Collection<Memo> = DB_HANDLE.empty_collection_novalidate()?;
+        let memos: Vec<_> = ["lunch", "meeting", "deadline"]
+            .iter()
+            .map(|subject| Ok(Memo {
+                _id: Uid::new_oid()?,
+                subject: subject.to_string(),
+                deleted_at: None,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&memos)?;
+
+        // Deleted documents are marked, not removed...
+        assert!(coll.delete_one(doc!{ "_id": &memos[0]._id })?);
+        assert!(!coll.delete_one(doc!{ "_id": &memos[0]._id })?);
+        assert_eq!(coll.count(doc!{})?, 2);
+        assert!(coll.find_one(doc!{ "_id": &memos[0]._id })?.is_none());
+
+        let deleted: Vec<_> = coll.find_deleted(doc!{})?.collect::<Result<_>>()?;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].subject, "lunch");
+        assert!(deleted[0].deleted_at.is_some());
+
+        // ...so they can be restored...
+        assert_eq!(coll.undelete(doc!{ "_id": &memos[0]._id })?, 1);
+        assert_eq!(coll.count(doc!{})?, 3);
+
+        // ...or removed for good.
+        assert_eq!(coll.delete_many(doc!{ "subject": { "$ne": "deadline" } })?, 2);
+        assert_eq!(coll.count(doc!{})?, 1);
+        assert_eq!(coll.purge(doc!{ "subject": { "$ne": "deadline" } })?, 2);
+        assert_eq!(coll.find_deleted(doc!{})?.count(), 0);
+        assert_eq!(coll.purge(doc!{})?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn soft_delete_find_and_modify() -> Result<()> {
+        #[derive(Debug, Clone, Copy)]
+        struct Subjects;
+
+        impl Pipeline<Memo> for Subjects {
+            type Output = Document;
+
+            fn stages(&self) -> Vec<Document> {
+                vec![
+                    doc!{ "$sort": { "subject": 1 } },
+                    doc!{ "$project": { "_id": 0, "subject": 1 } },
+                ]
+            }
+        }
+
+        #[derive(Debug, Clone, Copy)]
+        struct Rename(&'static str, &'static str);
+
+        impl Upsert<Memo> for Rename {
+            fn filter(&self) -> Document {
+                doc!{ "subject": self.0 }
+            }
+
+            fn upsert(&self) -> Document {
+                doc!{ "$set": { "subject": self.1 } }
+            }
+        }
+
+        let coll: Collection<Memo> = DB_HANDLE.empty_collection_novalidate()?;
+        let memos: Vec<_> = ["lunch", "meeting"]
+            .iter()
+            .map(|subject| Ok(Memo {
+                _id: Uid::new_oid()?,
+                subject: subject.to_string(),
+                deleted_at: None,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&memos)?;
+
+        // Find-and-delete marks the document, and returns it as it was.
+        let deleted = coll.find_one_and_delete(doc!{ "subject": "lunch" })?;
+        assert_eq!(deleted.as_ref(), Some(&memos[0]));
+        assert!(coll.find_one_and_delete(doc!{ "subject": "lunch" })?.is_none());
+        assert_eq!(coll.find_deleted(doc!{})?.count(), 1);
+
+        // Soft-deleted documents are neither replaced nor upserted into...
+        let replacement = Memo { subject: String::from("brunch"), ..memos[0].clone() };
+        assert!(coll.find_one_and_replace(doc!{ "_id": &memos[0]._id }, &replacement)?.is_none());
+
+        let result = coll.upsert_one(Rename("lunch", "dinner"))?;
+        assert!(result.upserted_id.is_some());
+        assert_eq!(coll.upsert_many(Rename("lunch", "dinner"))?.num_matched, 0);
+
+        // ...nor aggregated.
+        let subjects: Vec<_> = coll.aggregate(Subjects)?.collect::<Result<_>>()?;
+        assert_eq!(subjects, vec![
+            doc!{ "subject": "dinner" },
+            doc!{ "subject": "dinner" },
+            doc!{ "subject": "meeting" },
+        ]);
+
+        let deleted: Vec<_> = coll.find_deleted(doc!{})?.collect::<Result<_>>()?;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].subject, "lunch");
+
+        Ok(())
+    }
+
+    #[test]
+    fn populate_references() -> Result<()> {
+        use avocado::relation::Ref;
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["admins", "guests"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let dangling = Ref::new(Uid::new_oid()?);
+        let refs = vec![
+            Ref::new(groups[1]._id.clone()),
+            dangling.clone(),
+            Ref::new(groups[0]._id.clone()),
+            Ref::new(groups[1]._id.clone()),
+        ];
+        let populated = coll.populate(&refs)?;
+
+        assert_eq!(populated, vec![
+            Some(groups[1].clone()),
+            None,
+            Some(groups[0].clone()),
+            Some(groups[1].clone()),
+        ]);
+
+        assert_eq!(refs[0].fetch(&DB_HANDLE)?, Some(groups[1].clone()));
+        assert_eq!(dangling.fetch(&DB_HANDLE)?, None);
+        assert!(coll.populate(&[])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_ids_and_load_many() -> Result<()> {
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["admins", "guests", "owners"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let missing = Uid::new_oid()?;
+        let ids = vec![
+            groups[2]._id.clone(),
+            missing.clone(),
+            groups[0]._id.clone(),
+        ];
+
+        let by_id = coll.find_by_ids(&ids)?;
+        assert_eq!(by_id.len(), 2);
+        assert_eq!(by_id.get(&groups[0]._id), Some(&groups[0]));
+        assert_eq!(by_id.get(&groups[2]._id), Some(&groups[2]));
+        assert!(!by_id.contains_key(&missing));
+        assert!(coll.find_by_ids(&[])?.is_empty());
+
+        let loaded = coll.load_many(&ids)?;
+        assert!(!loaded.is_complete());
+        assert_eq!(loaded.found, vec![groups[2].clone(), groups[0].clone()]);
+        assert_eq!(loaded.missing, vec![missing]);
+        assert!(loaded.into_complete().is_err());
+
+        let complete = coll.load_many(&[groups[1]._id.clone()])?.into_complete()?;
+        assert_eq!(complete, vec![groups[1].clone()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn loader_batches_lookups() -> Result<()> {
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["admins", "guests"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let missing = Uid::new_oid()?;
+        let loader = coll.loader();
+
+        loader.request(groups[0]._id.clone());
+        loader.request(groups[1]._id.clone());
+        loader.request(groups[0]._id.clone());
+        loader.request(missing.clone());
+
+        // The duplicate is only fetched once, and everything in one batch.
+        assert_eq!(loader.dispatch()?, 3);
+        assert_eq!(loader.dispatch()?, 0);
+
+        // Cached results, including the missing document, aren't fetched again.
+        coll.delete_many(doc!{})?;
+        assert_eq!(loader.load(&groups[1]._id)?, Some(groups[1].clone()));
+        assert_eq!(loader.load(&missing)?, None);
+        assert_eq!(
+            loader.load_many(&[groups[1]._id.clone(), groups[0]._id.clone()])?,
+            vec![Some(groups[1].clone()), Some(groups[0].clone())]
+        );
+
+        loader.clear(&groups[0]._id);
+        assert_eq!(loader.load(&groups[0]._id)?, None);
+
+        loader.prime(groups[0].clone());
+        assert_eq!(loader.load(&groups[0]._id)?, Some(groups[0].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_and_restore() -> Result<()> {
+        use avocado::coll::{ RestorePolicy, RestoreResult };
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["red", "green", "blue"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        // Only the filtered documents end up in the archive
+        let mut archive = Vec::new();
+        assert_eq!(coll.dump(&mut archive, doc!{ "name": { "$ne": "blue" } })?, 2);
+
+        // Restoring into an empty collection inserts everything
+        coll.delete_many(doc!{})?;
+        let result = coll.restore(archive.as_slice(), RestorePolicy::Insert)?;
+        assert_eq!(result, RestoreResult { inserted: 2, replaced: 0 });
+        assert_eq!(coll.count(doc!{})?, 2);
+
+        // Inserting existing documents again fails...
+        assert!(coll.restore(archive.as_slice(), RestorePolicy::Insert).is_err());
+
+        // ...but replacing them succeeds and reverts modifications
+        for group in &groups[..2] {
+            coll.replace_entity(&Group {
+                description: String::from("changed"),
+                ..group.clone()
+            })?;
+        }
+        coll.insert_one(&groups[2])?;
+
+        let result = coll.restore(archive.as_slice(), RestorePolicy::Replace)?;
+        assert_eq!(result, RestoreResult { inserted: 0, replaced: 2 });
+
+        let restored: BTreeSet<_> = coll
+            .find_many(doc!{ "description": "" })?
+            .map(|group| group.map(|group| group.name))
+            .collect::<Result<_>>()?;
+        assert_eq!(restored, BTreeSet::from_iter(vec![
+            String::from("red"),
+            String::from("green"),
+        ]));
+        assert_eq!(coll.count(doc!{})?, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_and_import_json() -> Result<()> {
+        use avocado::doc::ExtendedJsonMode;
+        use avocado::coll::{ RestorePolicy, RestoreResult };
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["red", "green"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let mut export = Vec::new();
+        assert_eq!(coll.export_json(&mut export, doc!{}, ExtendedJsonMode::Canonical)?, 2);
+        assert_eq!(export.iter().filter(|&&byte| byte == b'\n').count(), 2);
+
+        let exported = String::from_utf8(export.clone()).unwrap();
+        let first = Group::from_extended_json(serde_json::from_str(exported.lines().next().unwrap())?)?;
+        assert!(groups.contains(&first));
+        assert!(exported.contains("\"$oid\""));
+
+        coll.delete_many(doc!{})?;
+        let result = coll.import_json(export.as_slice(), RestorePolicy::Insert)?;
+        assert_eq!(result, RestoreResult { inserted: 2, replaced: 0 });
+
+        let result = coll.import_json(export.as_slice(), RestorePolicy::Replace)?;
+        assert_eq!(result, RestoreResult { inserted: 0, replaced: 2 });
+        assert_eq!(coll.count(doc!{})?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn paginate() -> Result<()> {
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|name| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: name.to_string(),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let paginator = coll.paginate(doc!{}, 2);
+
+        let page = paginator.page(1)?;
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.total_items, 5);
+        assert_eq!(page.total_pages(), 3);
+        assert!(page.has_next());
+        assert_eq!(paginator.page(2)?.items.len(), 1);
+        assert!(paginator.page(3)?.items.is_empty());
+
+        let mut names = Vec::new();
+        let mut token = None;
+
+        loop {
+            let page = paginator.after(token.as_ref().map(String::as_str))?;
+            assert!(page.items.len() <= 2);
+            names.extend(page.items.into_iter().map(|group| group.name));
+
+            match page.next_token {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c", "d", "e"]);
+        assert!(paginator.after(Some("garbage")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_cache() -> Result<()> {
+        use std::cell::Cell;
+        use std::time::Duration;
+        use avocado::cache::Cache;
+
+        let coll: Collection<Rate> = DB_HANDLE.empty_collection_novalidate()?;
+        let cache = Cache::new(coll, "expires_at");
+        let key = || Uid::from_raw(String::from("EUR/HUF"));
+        let computations = Cell::new(0);
+        let compute = || {
+            computations.set(computations.get() + 1);
+            Ok(Rate { _id: Uid::from_raw(String::new()), value: 322.5, expires_at: None })
+        };
+
+        let first = cache.get_or_compute(key(), Duration::from_secs(60), compute)?;
+        assert_eq!(first._id, key());
+        assert_eq!(first.value, 322.5);
+        assert_eq!(computations.get(), 1);
+
+        let cached = cache.get_or_compute(key(), Duration::from_secs(60), compute)?;
+        assert_eq!(cached.value, 322.5);
+        assert!(cached.expires_at.is_some());
+        assert_eq!(computations.get(), 1);
+
+        // Entries past their expiration date are ignored even if the server
+        // hasn't deleted them yet.
+        cache.put(key(), first.clone(), Duration::from_secs(0))?;
+        assert!(cache.get(&key())?.is_none());
+        cache.get_or_compute(key(), Duration::from_secs(60), compute)?;
+        assert_eq!(computations.get(), 2);
+
+        assert!(cache.invalidate(&key())?);
+        assert!(cache.get(&key())?.is_none());
+        let offline = || Err(AvocadoError::new(AvocadoErrorKind::MongoDbError, "offline"));
+        assert!(cache.get_or_compute(key(), Duration::from_secs(60), offline).is_err());
+        assert!(cache.get(&key())?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn lifecycle_hooks() -> Result<()> {
+        let coll: Collection<Article> = DB_HANDLE.empty_collection_novalidate()?;
+        let article = Article {
+            _id: Uid::new_oid()?,
+            title: String::from("Hello Hooks"),
+            slug: String::new(),
+            loads: 0,
+        };
+
+        coll.insert_one(&article)?;
+
+        let loaded = coll.find_one(doc!{ "_id": article._id.clone() })?.expect("article not found");
+        assert_eq!(loaded.slug, "hello-hooks");
+        assert_eq!(loaded.loads, 1);
+
+        let error = coll.delete_many(doc!{}).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::InvalidFilter);
+        assert_eq!(coll.count(doc!{})?, 1);
+        assert!(coll.delete_entity(&article)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequences() -> Result<()> {
+        use std::thread;
+        use mongodb::db::ThreadedDatabase;
+        use avocado::seq::Sequence;
+
+        let name = "SequenceTest";
+        DB_HANDLE.collection(name).drop()?;
+
+        let sequence = Sequence::with_collection_name(&DB_HANDLE, name);
+        assert_eq!(sequence.current("invoice_id")?, 0);
+        assert_eq!(sequence.next("invoice_id")?, 1);
+        assert_eq!(sequence.next("invoice_id")?, 2);
+        assert_eq!(sequence.next("ticket_id")?, 1);
+        assert_eq!(sequence.reserve("invoice_id", 10)?, 3..13);
+        assert_eq!(sequence.current("invoice_id")?, 12);
+
+        let uid: Uid<Receipt> = sequence.next_uid()?;
+        assert_eq!(uid, Uid::from_raw(1));
+        assert_eq!(sequence.current(Receipt::NAME)?, 1);
+
+        // Concurrent increments never hand out the same value twice.
+        let handles: Vec<_> = (0..4).map(|_| thread::spawn(move || {
+            let sequence = Sequence::with_collection_name(&DB_HANDLE, name);
+            (0..25).map(|_| sequence.next("order_id")).collect::<Result<Vec<_>>>()
+        })).collect();
+        let mut values = BTreeSet::new();
+
+        for handle in handles {
+            values.extend(handle.join().expect("thread panicked")?);
+        }
+
+        assert_eq!(values, (1..=100).collect());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tagged_enum() -> Result<()> {
+        use avocado::variant;
+
+        let coll: Collection<Pet> = DB_HANDLE.empty_collection_novalidate()?;
+        let pets = vec![
+            Pet::Cat { _id: Uid::new_oid()?, name: String::from("Tom"), lives: 9 },
+            Pet::Dog { _id: Uid::new_oid()?, name: String::from("Rex"), good: true },
+            Pet::Dog { _id: Uid::new_oid()?, name: String::from("Fido"), good: true },
+        ];
+
+        coll.insert_many(&pets)?;
+
+        assert_eq!(coll.count(doc!{})?, 3);
+        assert_eq!(coll.count(variant::Only::new::<Pet>(doc!{}, "dog")?)?, 2);
+        assert_eq!(coll.find_one(variant::filter::<Pet>("cat")?)?, Some(pets[0].clone()));
+
+        let cats: Vec<Pet> = coll
+            .find_many(variant::Only::new::<Pet>(doc!{ "name": "Tom" }, "cat")?)?
+            .collect::<Result<_>>()?;
+        assert_eq!(cats, &pets[..1]);
+
+        let error = variant::Only::new::<Pet>(doc!{}, "fish").unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::InvalidFilter);
+
+        // The tag field takes part in filters, so `Tom` isn't a dog.
+        assert_eq!(coll.delete_many(variant::Only::new::<Pet>(doc!{ "name": "Tom" }, "dog")?)?, 0);
+        assert_eq!(coll.delete_many(variant::Only::new::<Pet>(doc!{ "name": "Rex" }, "dog")?)?, 1);
+        assert_eq!(coll.count(doc!{})?, 2);
+
+        // A view of the cats only, read and written as `Cat`.
+        let cats = coll.of_variant::<Cat>();
+        cats.insert_one(Cat { _id: Uid::new_oid()?, name: String::from("Felix"), lives: 7 })?;
+
+        assert_eq!(cats.count(doc!{})?, 2);
+        assert_eq!(cats.find_one(doc!{ "name": "Fido" })?, None);
+
+        let felix = cats.find_one(doc!{ "name": "Felix" })?.expect("Felix not found");
+        assert_eq!(felix.lives, 7);
+
+        let names: BTreeSet<String> = cats
+            .find_many(doc!{})?
+            .map(|result| result.map(|cat| cat.name))
+            .collect::<Result<_>>()?;
+        assert_eq!(names, BTreeSet::from_iter(vec![String::from("Felix"), String::from("Tom")]));
+
+        // Deletions through the view never touch the other variants.
+        assert_eq!(cats.delete_many(doc!{ "name": "Fido" })?, 0);
+        assert_eq!(cats.delete_many(doc!{})?, 2);
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn audit_log() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+        use avocado::audit::{ self, Audited };
+
+        #[derive(Debug, Clone, Copy)]
+        struct Deposit(i64);
+
+        impl Update<Ledger> for Deposit {
+            fn filter(&self) -> Document {
+                doc!{ "owner": "bob" }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$inc": { "balance": self.0 } }
+            }
+        }
+
+        let name = Ledger::audit_collection().expect("`Ledger` is audited");
+        assert_eq!(name, "Ledger_audit");
+        DB_HANDLE.collection(name).drop()?;
+
+        let coll: Collection<Ledger> = DB_HANDLE.empty_collection_novalidate()?;
+        let log = audit::Log::for_doc::<Ledger, _>(&*DB_HANDLE).expect("no audit log");
+        let context = audit::Context::new("alice").with("request_id", "4f1e");
+        let audited = Audited::with_context(&coll, &log, context);
+        let ledgers = vec![
+            Ledger { _id: Uid::new_oid()?, owner: String::from("bob"), balance: 10 },
+            Ledger { _id: Uid::new_oid()?, owner: String::from("bob"), balance: 20 },
+            Ledger { _id: Uid::new_oid()?, owner: String::from("eve"), balance: 30 },
+        ];
+
+        audited.insert_many(&ledgers)?;
+        assert_eq!(audited.update_many(Deposit(5))?.num_modified, 2);
+        assert_eq!(audited.delete_many(doc!{ "owner": "eve" })?, 1);
+
+        let entries: Vec<audit::Entry> = DB_HANDLE
+            .collection(name)
+            .find(None, None)?
+            .map(|doc| Ok(bson::from_bson(Bson::Document(doc?))?))
+            .collect::<Result<_>>()?;
+        let count = |op| entries.iter().filter(|entry| entry.operation == op).count();
+
+        assert_eq!(entries.len(), 6);
+        assert_eq!(count(audit::Operation::Insert), 3);
+        assert_eq!(count(audit::Operation::Update), 2);
+        assert_eq!(count(audit::Operation::Delete), 1);
+
+        for entry in &entries {
+            assert_eq!(entry.principal, "alice");
+            assert_eq!(entry.collection, "Ledger");
+            assert_eq!(entry.context, doc!{ "request_id": "4f1e" });
+        }
+
+        let update = entries.iter().find(|entry| entry.operation == audit::Operation::Update).unwrap();
+        assert_eq!(update.changes.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_your_writes() -> Result<()> {
+        use avocado::coll::UpdateOneResult;
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let pr = PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Fix typo"),
+            lines_changed: 1,
+        };
+
+        coll.insert_one(&pr)?;
+
+        #[derive(Debug, Clone)]
+        struct Retitle<'a> {
+            id: &'a Uid<PullRequest>,
+            title: &'a str,
+        }
+
+        impl<'a> Update<PullRequest> for Retitle<'a> {
+            fn filter(&self) -> Document {
+                doc!{ "_id": self.id }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "title": self.title } }
+            }
+        }
+
+        let (result, read) = coll.update_then_find_one(
+            Retitle { id: &pr.id, title: "Fix typos" },
+            doc!{ "_id": &pr.id },
+        )?;
+
+        assert_eq!(result, UpdateOneResult { matched: true, modified: true });
+        assert_eq!(read.map(|pr| pr.title), Some(String::from("Fix typos")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn upsert_and_fetch() -> Result<()> {
+        use avocado::coll::Upserted;
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let id = Uid::new_oid()?;
+
+        #[derive(Debug, Clone)]
+        struct Touch<'a> {
+            id: &'a Uid<PullRequest>,
+        }
+
+        impl<'a> Upsert<PullRequest> for Touch<'a> {
+            fn filter(&self) -> Document {
+                doc!{ "_id": self.id }
+            }
+
+            fn upsert(&self) -> Document {
+                doc!{
+                    "$setOnInsert": { "title": "Draft" },
+                    "$inc": { "lines_changed": 1 },
+                }
+            }
+        }
+
+        let expected = |lines_changed| PullRequest {
+            id: id.clone(),
+            title: String::from("Draft"),
+            lines_changed,
+        };
+
+        let first = coll.upsert_and_fetch(Touch { id: &id })?;
+        assert!(first.is_inserted());
+        assert_eq!(first.into_inner(), expected(1));
+
+        let second = coll.upsert_and_fetch(Touch { id: &id })?;
+        assert_eq!(second, Upserted::Updated(expected(2)));
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_map_adapters() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = ["small", "large", "small"]
+            .iter()
+            .enumerate()
+            .map(|(i, title)| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: title.to_string(),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let by_id = coll.find_many(doc!{})?.collect_map_by_id()?;
+        assert_eq!(by_id.len(), 3);
+        for pr in &prs {
+            assert_eq!(by_id.get(&pr.id), Some(pr));
+        }
+
+        let by_lines = coll.find_many(doc!{})?.collect_map_by(|pr| pr.lines_changed)?;
+        assert_eq!(by_lines.len(), 3);
+        assert_eq!(by_lines[&1].title, "large");
+
+        let by_title = coll.find_many(doc!{})?.group_by(|pr| pr.title.clone())?;
+        let mut lines: Vec<_> = by_title["small"].iter().map(|pr| pr.lines_changed).collect();
+        lines.sort();
+        assert_eq!(by_title.len(), 2);
+        assert_eq!(lines, [0, 2]);
+        assert_eq!(by_title["large"].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batched_writes() -> Result<()> {
+        use std::time::Duration;
+        use avocado::batch::{ self, FlushResult };
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let options = batch::Options {
+            max_size: 3,
+            max_delay: Duration::from_secs(3600),
+        };
+        let mut flushes = Vec::new();
+
+        {
+            let mut writer = coll.batched_writer(options);
+
+            for i in 0..7 {
+                let pr = PullRequest {
+                    id: Uid::new_oid()?,
+                    title: format!("PR #{}", i),
+                    lines_changed: i,
+                };
+
+                flushes.extend(writer.insert(&pr)?);
+            }
+
+            // Two full batches have been sent, one document is pending
+            assert_eq!(flushes.len(), 2);
+            assert_eq!(flushes[0].num_inserted, 3);
+            assert_eq!(writer.len(), 1);
+            assert_eq!(coll.count(doc!{})?, 6);
+            assert_eq!(writer.flush_if_due()?, None);
+
+            let explicit = writer.flush()?;
+            assert_eq!(explicit, FlushResult { num_writes: 1, num_inserted: 1, ..Default::default() });
+            assert!(writer.is_empty());
+            assert_eq!(coll.count(doc!{})?, 7);
+
+            // Pending writes are flushed when the writer is dropped
+            writer.insert(&PullRequest {
+                id: Uid::new_oid()?,
+                title: String::from("Last one"),
+                lines_changed: 0,
+            })?;
+        }
+
+        assert_eq!(coll.count(doc!{})?, 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resumable_scan() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let mut prs: Vec<_> = (0..5)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+        prs.sort_by_key(|pr| pr.id.as_ref().bytes());
+
+        let mut scan = coll.find_many_resumable(doc!{ "lines_changed": { "$gte": 1 } });
+        let found: Vec<PullRequest> = scan.by_ref().collect::<Result<_>>()?;
+        let expected: Vec<_> = prs.iter().filter(|pr| pr.lines_changed >= 1).cloned().collect();
+
+        assert_eq!(found, expected);
+        assert_eq!(scan.restarts(), 0);
+        assert_eq!(scan.last_seen(), Some(&bson::to_bson(&expected[3].id)?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn paged_scan() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let mut prs: Vec<_> = (0..7)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+        prs.sort_by_key(|pr| pr.id.as_ref().bytes());
+
+        let all: Vec<PullRequest> = coll.iter_all_sorted_by_id(3).collect::<Result<_>>()?;
+        assert_eq!(all, prs);
+
+        let mut scan = coll.iter_all_sorted_by_id(3);
+        let head: Vec<PullRequest> = scan.by_ref().take(4).collect::<Result<_>>()?;
+        let checkpoint = scan.last_seen().cloned().expect("no last seen _id");
+
+        assert_eq!(head, &prs[..4]);
+        assert_eq!(checkpoint, bson::to_bson(&prs[3].id)?);
+
+        let rest: Vec<PullRequest> = coll
+            .iter_all_sorted_by_id(3)
+            .resume_after(checkpoint)
+            .collect::<Result<_>>()?;
+
+        assert_eq!(rest, &prs[4..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn save_changes() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let old = PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Fix typo"),
+            lines_changed: 1,
+        };
+        coll.insert_one(&old)?;
+
+        #[derive(Debug, Clone)]
+        struct Resize<'a> {
+            id: &'a Uid<PullRequest>,
+            lines_changed: i64,
+        }
+
+        impl<'a> Update<PullRequest> for Resize<'a> {
+            fn filter(&self) -> Document {
+                doc!{ "_id": self.id }
+            }
+
+            fn update(&self) -> Document {
+                doc!{ "$set": { "lines_changed": self.lines_changed } }
+            }
+        }
+
+        // A concurrent writer changes a field which `save_changes()` leaves alone.
+        coll.update_one(Resize { id: &old.id, lines_changed: 3 })?;
+
+        let new = PullRequest {
+            title: String::from("Fix typos"),
+            ..old.clone()
+        };
+        let result = coll.save_changes(&old, &new)?;
+        assert!(result.matched && result.modified);
+
+        let stored = coll.find_one(doc!{ "_id": &old.id })?.expect("document not found");
+        assert_eq!(stored, PullRequest {
+            title: String::from("Fix typos"),
+            lines_changed: 3,
+            ..old.clone()
+        });
+
+        let result = coll.save_changes(&new, &new)?;
+        assert!(result.matched && !result.modified);
+
+        let other = PullRequest { id: Uid::new_oid()?, ..new.clone() };
+        let error = coll.save_changes(&new, &other).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::InvalidUpdate);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_as_view() -> Result<()> {
+        #[derive(Debug, Deserialize)]
+        struct PullRequestTitle {
+            title: String,
+        }
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = (0..5)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i * 10,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let mut titles: Vec<String> = coll
+            .find_as::<PullRequestTitle, _>(doc!{ "lines_changed": { "$gte": 20 } })?
+            .map(|view| view.map(|view| view.title))
+            .collect::<Result<_>>()?;
+        titles.sort();
+
+        assert_eq!(titles, ["PR #2", "PR #3", "PR #4"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_collection() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = (0..4)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        for &full in &[false, true] {
+            let result = coll.validate(full)?;
+
+            assert!(result.valid, "{:#?}", result);
+            assert!(result.errors.is_empty());
+            assert!(result.ns.ends_with(".PullRequest"));
+            assert_eq!(result.num_records, 4);
+            assert_eq!(result.num_indexes, 1);
+            assert_eq!(result.keys_per_index.values().collect::<Vec<_>>(), [&4]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_with_array_filters() -> Result<()> {
+        use avocado::update::{ ArrayFilters, Change };
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, BsonSchema, Doc)]
+        struct Student {
+            _id: Uid<Student>,
+            grades: Vec<i32>,
+        }
+
+        #[derive(Debug, Clone, Copy)]
+        struct Curve;
+
+        impl Update<Student> for Curve {
+            fn filter(&self) -> Document {
+                Document::new()
+            }
+
+            fn update(&self) -> Document {
+                Change::inc("grades.$[low]", 10).into()
+            }
+
+            fn array_filters(&self) -> ArrayFilters {
+                ArrayFilters::new().with("low", doc!{ "$lt": 80 })
+            }
+        }
+
+        let coll: Collection<Student> = DB_HANDLE.empty_collection()?;
+        let student = Student {
+            _id: Uid::new_oid()?,
+            grades: vec![95, 70, 79, 80],
+        };
+        coll.insert_one(&student)?;
+
+        let result = coll.update_many(Curve)?;
+        assert_eq!(result.num_matched, 1);
+        assert_eq!(result.num_modified, 1);
+
+        let curved = coll.find_one(doc!{ "_id": student._id })?.expect("student not found");
+        assert_eq!(curved.grades, [95, 80, 89, 80]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn typed_pipeline() -> Result<()> {
+        use avocado::pipeline::Pipeline;
+
+        #[derive(Debug, Clone, PartialEq, Deserialize)]
+        struct TotalLines {
+            _id: Bson,
+            total: i64,
+        }
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = [10, 20, 3000]
+            .iter()
+            .enumerate()
+            .map(|(i, &lines_changed)| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let pipeline = Pipeline::<PullRequest>::new()
+            .filter(doc!{ "lines_changed": { "$lt": 1000 } })
+            .group(Bson::Null, doc!{ "total": { "$sum": "$lines_changed" } })
+            .output::<TotalLines>();
+        let totals: Vec<_> = coll.aggregate_pipeline(pipeline)?.collect::<Result<_>>()?;
+
+        assert_eq!(totals, vec![TotalLines { _id: Bson::Null, total: 30 }]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_into_collection() -> Result<()> {
+        use avocado::pipeline::{ Pipeline, Stage };
+        use avocado::literal::{ WhenMatched, WhenNotMatched };
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = [("fix", 10), ("fix", 20), ("feature", 300)]
+            .iter()
+            .map(|&(title, lines_changed)| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: title.into(),
+                lines_changed,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+        DB_HANDLE.drop_collection_of::<LinesPerTitle>()?;
+
+        let pipeline = Pipeline::<PullRequest>::new()
+            .group("$title", doc!{ "lines": { "$sum": "$lines_changed" } })
+            .output::<LinesPerTitle>();
+        let out = coll.aggregate_into(pipeline.clone())?;
+        let mut totals: Vec<_> = out.find_many(doc!{})?.collect::<Result<_>>()?;
+        totals.sort();
+
+        assert_eq!(totals, vec![
+            LinesPerTitle { _id: Uid::from_raw(String::from("feature")), lines: 300 },
+            LinesPerTitle { _id: Uid::from_raw(String::from("fix")), lines: 30 },
+        ]);
+
+        // `$merge` keeps the documents which aren't in the output.
+        out.delete_entity(&totals[0])?;
+        out.insert_one(&LinesPerTitle { _id: Uid::from_raw(String::from("docs")), lines: 1 })?;
+
+        let merge = pipeline.clone().merge_into::<LinesPerTitle>(
+            WhenMatched::Replace,
+            WhenNotMatched::Insert,
+        );
+        let merged = coll.aggregate_into(merge)?;
+
+        assert_eq!(merged.count(doc!{})?, 3);
+
+        // The output must be written to the collection of the output type.
+        let wrong = pipeline.stage(Stage::Out(String::from("Elsewhere")));
+        let error = coll.aggregate_into(wrong).unwrap_err();
+
+        assert_eq!(error.kind(), AvocadoErrorKind::InvalidPipeline);
+
+        DB_HANDLE.drop_collection_of::<LinesPerTitle>()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn projected_query() -> Result<()> {
+        use avocado::projection::Projection;
+
+        #[derive(Debug, Clone, PartialEq, Deserialize)]
+        struct Title {
+            title: String,
+        }
+
+        #[derive(Debug, Clone, Copy)]
+        struct Titles;
+
+        impl Query<PullRequest> for Titles {
+            type Output = Title;
+
+            fn projection(&self) -> Projection {
+                Projection::including(vec!["title"]).exclude("_id")
+            }
+        }
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        coll.insert_one(&PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Fix typo"),
+            lines_changed: 1,
+        })?;
+
+        let title = coll.find_one(Titles)?;
+        assert_eq!(title, Some(Title { title: String::from("Fix typo") }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_query() -> Result<()> {
+        #[derive(Debug, Clone, Copy)]
+        struct LargestFirst;
+
+        impl Query<PullRequest> for LargestFirst {
+            type Output = PullRequest;
+
+            fn sort(&self) -> Sort {
+                Sort::new().descending("lines_changed").ascending("title")
+            }
+        }
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = [(5, "b"), (700, "c"), (5, "a")]
+            .iter()
+            .map(|&(lines_changed, title)| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: title.into(),
+                lines_changed,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let titles: Vec<_> = coll
+            .find_many(LargestFirst)?
+            .map(|pr| pr.map(|pr| pr.title))
+            .collect::<Result<_>>()?;
+
+        assert_eq!(titles, ["c", "a", "b"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_warmup() -> Result<()> {
+        use avocado::plan;
+
+        #[derive(Debug, Clone, Copy)]
+        struct LargePullRequests;
+
+        impl Query<PullRequest> for LargePullRequests {
+            type Output = PullRequest;
+
+            fn filter(&self) -> Document {
+                doc!{ "lines_changed": { "$gt": 1000 } }
+            }
+
+            fn options(&self) -> FindOptions {
+                FindOptions {
+                    sort: Some(doc!{ "_id": 1 }),
+                    ..Default::default()
+                }
+            }
+        }
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        coll.insert_one(&PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Rewrite everything"),
+            lines_changed: 4096,
+        })?;
+
+        let warmup = plan::Warmup::new()
+            .query::<PullRequest, _>(&LargePullRequests)
+            .pinned::<PullRequest, _>(&LargePullRequests, &["_id_"]);
+
+        warmup.run(&DB_HANDLE)?;
+        warmup.unpin(&DB_HANDLE)?;
+
+        assert_eq!(coll.find_many(LargePullRequests)?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_stats() -> Result<()> {
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let prs: Vec<_> = (0..4)
+            .map(|i| Ok(PullRequest {
+                id: Uid::new_oid()?,
+                title: format!("PR #{}", i),
+                lines_changed: i,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&prs)?;
+
+        let stats = DB_HANDLE.stats()?;
+
+        assert!(stats.collections >= 1, "{:#?}", stats);
+        assert!(stats.objects >= 4);
+        assert!(stats.indexes >= 1);
+        assert!(stats.data_size > 0);
+        assert!(stats.avg_obj_size > 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_command_as() -> Result<()> {
+        #[derive(Debug, Deserialize)]
+        struct BuildInfo {
+            version: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct CollStats {
+            ns: String,
+        }
+
+        let info: BuildInfo = DB_HANDLE.run_command_as(doc!{ "buildInfo": 1 })?;
+        assert!(!info.version.is_empty());
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let stats: CollStats = coll.run_command_as("collStats", doc!{ "scale": 1024 })?;
+        assert!(stats.ns.ends_with(".PullRequest"), "{:#?}", stats);
+
+        let error = DB_HANDLE
+            .run_command_as::<Document>(doc!{ "avocadoNoSuchCommand": 1 })
+            .unwrap_err();
+        let server_error = error.server_error().expect("no server error");
+        assert!(server_error.code.is_some(), "{:#?}", server_error);
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_info_and_health() -> Result<()> {
+        use avocado::server::HealthCheck;
+
+        let build_info = DB_HANDLE.build_info()?;
+        assert!(!build_info.version.is_empty());
+        assert!(!build_info.version_array.is_empty());
+
+        let hello = DB_HANDLE.hello()?;
+        assert!(hello.max_wire_version >= hello.min_wire_version);
+
+        let status = DB_HANDLE.server_status()?;
+        assert_eq!(status.version, build_info.version);
+        assert!(status.uptime >= 0.0);
+
+        DB_HANDLE.ping()?;
+
+        let health = HealthCheck::default().run(&*DB_HANDLE)?;
+        assert!(health.is_healthy(), "{:#?}", health);
+        assert_eq!(health.role, hello.role());
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_existing_indexes() -> Result<()> {
+        let coll: Collection<User> = DB_HANDLE.empty_collection()?;
+        let mut indexes = coll.list_indexes_typed()?;
+        indexes.sort_by_key(|index| index.model.options.name.clone());
+
+        assert_eq!(indexes.len(), 2);
+        assert_eq!(indexes[0].model.options.name.as_ref().map(String::as_str), Some("_id_"));
+        assert_eq!(indexes[0].model.keys, doc!{ "_id": 1 });
+
+        let username = &indexes[1].model;
+        assert_eq!(username.keys, User::indexes()[0].keys);
+        assert_eq!(username.options.name.as_ref().map(String::as_str), Some("username"));
+        assert_eq!(username.options.unique, Some(true));
+        assert_eq!(username.options.expire_after_seconds, None);
+        assert_eq!(indexes[1].partial_filter_expression, None);
+
+        Ok(())
+    }
+
     #[test]
-    fn basic_insertion_deletion_raw_doc() -> Result<()> {
-        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+    fn validated_collection() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+        use avocado::literal::{ ValidationLevel, ValidationAction };
 
-        let group_1 = Group {
-            _id: Uid::new_oid()?,
-            name: String::from("Fancy FinTech, Inc."),
-            description: String::from("IoT AI on the quantum blockchain"),
-        };
-        let group_2 = Group {
-            _id: Uid::new_oid()?,
-            name: String::from("PHP Shop, Ltd."),
-            description: String::from("It's Shit But They Pay For It [TM]"),
-        };
+        DB_HANDLE.drop_collection(Group::NAME)?;
 
-        // No documents before insertion
-        assert!(coll.find_one(doc!{})?.is_none());
-        assert!(!coll.find_many(doc!{})?.has_next()?);
-        assert_eq!(coll.count(doc!{})?, 0);
+        let invalid = doc!{ "name": 42 };
+        let raw = DB_HANDLE.collection(Group::NAME);
 
-        // Can insert 0 documents
-        let no_ids = coll.insert_many(&[])?;
-        assert!(no_ids.is_empty());
+        DB_HANDLE.create_collection_validated::<Group>(ValidationLevel::Strict, ValidationAction::Warn)?;
+        assert!(raw.insert_one(invalid.clone(), None)?.write_exception.is_none());
 
-        // Can insert but don't allow duplicates
-        let id_1 = coll.insert_one(&group_1)?;
-        assert!(coll.insert_many(vec![&group_1]).is_err());
-        assert_eq!(id_1, group_1._id);
+        // Replaces the validator of the existing collection
+        let coll: Collection<Group> = DB_HANDLE.create_collection_validated(
+            ValidationLevel::Strict,
+            ValidationAction::Error,
+        )?;
+        assert!(raw.insert_one(invalid, None)?.write_exception.is_some());
+        assert_eq!(coll.count(doc!{})?, 1);
 
-        let ids_2 = coll.insert_many(vec![&group_2])?;
-        assert!(coll.insert_one(&group_2).is_err());
-        assert_eq!(
-            ids_2,
-            BTreeMap::from_iter(vec![(0, group_2._id.clone())]),
-        );
+        Ok(())
+    }
 
-        // Can retrieve documents after insertion
-        assert_eq!(
-            coll.find_one(doc!{ "name": "PHP Shop, Ltd." })?.as_ref(),
-            Some(&group_2)
-        );
-        assert_eq!(
-            coll.find_many(doc!{ "_id": &group_1._id })?.collect::<Result<Vec<_>>>()?,
-            vec![group_1.clone()]
-        );
+    #[test]
+    fn synchronize_indexes() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+        use avocado::index_sync;
 
-        // Can delete after insertion, too
-        assert!(coll.delete_entity(&group_2)?);
-        assert_eq!(coll.delete_entities(vec![&group_1])?, 1);
+        let coll: Collection<User> = DB_HANDLE.empty_collection()?;
+        assert!(coll.sync_indexes(index_sync::Options::default())?.is_empty());
 
-        assert!(!coll.delete_entity(&group_1)?);
-        assert_eq!(coll.delete_entities(vec![&group_2])?, 0);
+        DB_HANDLE.collection(User::NAME).create_index(doc!{ "legacy": 1 }, None)?;
 
-        // No documents after deletion
-        assert!(coll.find_one(doc!{})?.is_none());
-        assert!(!coll.find_many(doc!{})?.has_next()?);
+        let dry_run = index_sync::Options { drop_stale: true, dry_run: true };
+        assert_eq!(coll.sync_indexes(dry_run)?.stale, ["legacy_1"]);
+        assert_eq!(coll.list_indexes_typed()?.len(), 3);
+
+        let drop_stale = index_sync::Options { drop_stale: true, dry_run: false };
+        assert_eq!(coll.sync_indexes(drop_stale)?.stale, ["legacy_1"]);
+        assert_eq!(coll.list_indexes_typed()?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn untargeted_writes() -> Result<()> {
+        use avocado::shard::untargeted_write_count;
+
+        #[derive(Debug, Clone, Serialize, Deserialize, BsonSchema, Doc)]
+        #[shard_key(fields(tenant = "ascending"), strict)]
+        struct Tenanted {
+            _id: Uid<Tenanted>,
+            tenant: String,
+            value: i32,
+        }
+
+        let coll: Collection<Tenanted> = DB_HANDLE.empty_collection()?;
+        let id = Uid::new_oid()?;
+
+        coll.insert_one(&Tenanted { _id: id.clone(), tenant: "acme".into(), value: 1 })?;
+
+        let before = untargeted_write_count();
+        let error = coll.delete_many(doc!{ "value": 1 }).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::UntargetedWrite);
+        assert_eq!(untargeted_write_count(), before + 1);
+        assert_eq!(coll.count(doc!{})?, 1);
+
+        assert!(coll.delete_one(doc!{ "_id": &id })?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn attributed_operations() -> Result<()> {
+        use avocado::attribution::Attributed;
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let id = Uid::new_oid()?;
+
+        coll.insert_one(&PullRequest { id: id.clone(), title: "Tagged".into(), lines_changed: 3 })?;
+
+        let filter = doc!{ "_id": &id };
+        assert_eq!(coll.count(Attributed::new("tests/count", filter.clone()))?, 1);
+
+        let found = coll.find_one(Attributed::new("tests/find", filter.clone()))?;
+        assert_eq!(found.map(|pr| pr.lines_changed), Some(3));
+
+        assert!(coll.delete_one(Attributed::new("tests/delete", filter))?);
         assert_eq!(coll.count(doc!{})?, 0);
 
         Ok(())
     }
 
     #[test]
-    fn autogen_optional_id_consistent() -> Result<()> {
-        // Automatically-generated `_id` must be consistent
-        let coll: Collection<Commit> = DB_HANDLE.empty_collection()?;
-        let commit = Commit {
-            id: None,
-            hash: String::from("789abcd"),
-        };
-        let generated_commit_id = coll.insert_one(&commit)?;
-        let found_commit = coll.find_one(doc!{ "hash": &commit.hash })?;
+    fn snapshot_requires_replica_set() -> Result<()> {
+        use avocado::snapshot;
 
-        assert_eq!(found_commit, Some(
-            Commit { id: Some(generated_commit_id), ..commit }
-        ));
+        // The test server is a standalone `mongod`, which has no cluster time.
+        let error = snapshot::Session::start(&DB_HANDLE).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::MissingDocumentField);
 
-        // Try more than one at once
-        let more_commits = vec![
-            Commit {
-                id: Some(Uid::new_oid()?),
-                hash: String::from("0123456"),
-            },
-            Commit {
-                id: None,
-                hash: String::from("ef01234"),
-            },
-            Commit {
-                id: Some(Uid::new_oid()?),
-                hash: String::from("cadbfe8"),
-            },
-        ];
-        let ids = coll.insert_many(&more_commits)?;
+        Ok(())
+    }
 
-        assert_eq!(more_commits.len(), ids.len());
-        assert_eq!(Some(&ids[&0]), more_commits[0].id());
-        assert_eq!(Some(&ids[&2]), more_commits[2].id());
+    #[test]
+    fn query_index_usage() -> Result<()> {
+        let coll: Collection<User> = DB_HANDLE.empty_collection()?;
 
-        assert_ne!(ids[&0], ids[&1]);
-        assert_ne!(ids[&0], ids[&2]);
-        assert_ne!(ids[&1], ids[&2]);
+        assert_uses_index(&coll, doc!{ "username": "h2co3" }, "username");
+        assert_no_collection_scan(&coll, doc!{ "username": { "$in": ["a", "b"] } });
+
+        let explain = coll.explain(doc!{ "legal_name": "Nobody" })?;
+        assert!(used_indexes(&explain).is_empty());
+        assert_eq!(plan_stages(&explain), ["COLLSCAN"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn structured_explain() -> Result<()> {
+        use avocado::literal::ExplainVerbosity;
+        use avocado::pipeline::Pipeline as TypedPipeline;
+
+        let coll: Collection<User> = DB_HANDLE.empty_collection()?;
+        coll.insert_one(&User {
+            _id: Uid::new_oid()?,
+            legal_name: String::from("Jane Doe"),
+            username: String::from("jane"),
+            repos: HashSet::new(),
+            groups: HashSet::new(),
+        })?;
+
+        let planned = coll.explain_with(doc!{ "username": "jane" }, ExplainVerbosity::QueryPlanner)?;
+        assert_eq!(planned.used_indexes(), ["username"]);
+        assert!(planned.execution_stats.is_none());
+
+        let executed = coll.explain_with(doc!{ "username": "jane" }, ExplainVerbosity::ExecutionStats)?;
+        let stats = executed.execution_stats.expect("no execution stats");
+        assert!(stats.execution_success);
+        assert_eq!(stats.n_returned, 1);
+        assert_eq!(stats.total_docs_examined, 1);
+
+        let pipeline = TypedPipeline::<User>::new()
+            .filter(doc!{ "legal_name": "Jane Doe" })
+            .limit(1);
+        let aggregated = coll.explain_pipeline(pipeline, ExplainVerbosity::QueryPlanner)?;
+        assert!(aggregated.is_collection_scan());
 
         Ok(())
     }
@@ -331,8 +2494,25 @@ implement_tests!{
             ])
         );
 
-        // unique index should be enforced
-        assert!(users.insert_one(&impostor).is_err());
+        // unique index should be enforced, and reported by name
+        {
+            use avocado::error::UniqueViolation;
+
+            let error = users.insert_one(&impostor).unwrap_err();
+            assert_eq!(error.kind(), AvocadoErrorKind::UniqueViolation);
+            assert_eq!(
+                error.context::<UniqueViolation>().map(|v| v.constraint.as_str()),
+                Some("username")
+            );
+
+            let error = users.insert_many(vec![&impostor]).unwrap_err();
+            assert_eq!(error.kind(), AvocadoErrorKind::UniqueViolation);
+            assert!(error.is_duplicate_key());
+            assert_eq!(
+                error.server_error().map(|e| e.codes().collect::<Vec<_>>()),
+                Some(vec![11000])
+            );
+        }
 
         let mut repo_1 = Repo {
             _id: Uid::new_oid()?,
@@ -663,6 +2843,21 @@ implement_tests!{
         );
         assert_eq!(previous_pr.lines_changed, 42);
 
+        // Upsert a third one, returning it as inserted
+        let third_pr = PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Brand New"),
+            lines_changed: 7,
+        };
+        let upserted_pr = c.find_one_and_replace_returning(
+            doc!{ "_id": &third_pr.id },
+            &third_pr,
+            ReturnDocument::After,
+            true,
+        )?;
+        assert_eq!(upserted_pr, Some(third_pr.clone()));
+        assert!(c.delete_one(doc!{ "_id": &third_pr.id })?);
+
         // Finally, find and delete them in reverse order of the `_id` field.
         #[derive(Debug, Clone, Copy)]
         struct PullRequestsInReverse;
@@ -692,6 +2887,472 @@ implement_tests!{
         Ok(())
     }
 
+    #[test]
+    fn load_fixtures() -> Result<()> {
+        use std::fs::write;
+        use avocado::fixtures::{ self, FixtureSet };
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+        struct FixtureAuthor {
+            _id: Uid<FixtureAuthor>,
+            name: String,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+        struct FixtureBook {
+            _id: Uid<FixtureBook>,
+            author: Uid<FixtureAuthor>,
+            title: String,
+        }
+
+        let mut dir = temp_dir();
+        dir.push("avocado_test_fixtures");
+        create_dir_all(&dir)?;
+
+        write(dir.join("FixtureAuthor.json"), r#"{
+            "ada": { "name": "Ada" },
+            "alan": { "name": "Alan" }
+        }"#)?;
+        write(dir.join("FixtureBook.json"), r#"[
+            { "title": "Notes", "author": { "$fixture": "FixtureAuthor/ada" } }
+        ]"#)?;
+
+        let authors: Collection<FixtureAuthor> = DB_HANDLE.empty_collection_novalidate()?;
+        let books: Collection<FixtureBook> = DB_HANDLE.empty_collection_novalidate()?;
+        let set = FixtureSet::new(&dir)
+            .with::<FixtureBook>()
+            .with::<FixtureAuthor>()
+            .truncate(true);
+
+        // Loading twice works thanks to truncation, and yields the same IDs.
+        assert_eq!(set.load(&DB_HANDLE)?, vec![("FixtureAuthor", 2), ("FixtureBook", 1)]);
+        assert_eq!(set.load(&DB_HANDLE)?, vec![("FixtureAuthor", 2), ("FixtureBook", 1)]);
+        assert_eq!(authors.count(doc!{})?, 2);
+
+        let book = books.find_one(doc!{})?.expect("no book loaded");
+        assert_eq!(book._id, fixtures::uid::<FixtureBook>("0"));
+        assert_eq!(book.author, fixtures::uid::<FixtureAuthor>("ada"));
+
+        // A single file is loaded without truncation, so the IDs clash.
+        assert!(fixtures::load::<FixtureBook, _>(&DB_HANDLE, dir.join("FixtureBook.json")).is_err());
+        assert_eq!(books.count(doc!{})?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn throwaway_test_db() -> Result<()> {
+        use avocado::testing::TestDb;
+
+        let client = Client::with_uri(&format!("mongodb://localhost:{}/", DB_PORT))?;
+        let first = TestDb::new(&client);
+        let second = TestDb::new(&client);
+        let name = first.name().to_owned();
+
+        assert_ne!(first.name(), second.name());
+
+        // Collections of the same type in different test databases are independent.
+        let prs: Collection<PullRequest> = first.collection()?;
+        let other_prs: Collection<PullRequest> = second.collection()?;
+
+        prs.insert_one(&PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Isolate tests"),
+            lines_changed: 12,
+        })?;
+
+        assert_eq!(prs.count(doc!{})?, 1);
+        assert_eq!(other_prs.count(doc!{})?, 0);
+        assert!(client.database_names()?.contains(&name));
+
+        drop(first);
+        assert!(!client.database_names()?.contains(&name));
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_cache() -> Result<()> {
+        use std::time::Duration;
+        use mongodb::db::ThreadedDatabase;
+        use avocado::cache;
+
+        let coll: Collection<PullRequest> = DB_HANDLE.empty_collection()?;
+        let pr = PullRequest {
+            id: Uid::new_oid()?,
+            title: String::from("Cache queries"),
+            lines_changed: 100,
+        };
+        coll.insert_one(&pr)?;
+
+        let cache = cache::Queries::new(coll, Duration::from_secs(3600))?;
+        let filter = doc!{ "_id": &pr.id };
+        assert_eq!(cache.find_one(filter.clone())?, Some(pr.clone()));
+
+        // A write by another client isn't noticed, so the stale result is served.
+        let other_client = Client::with_uri(&format!("mongodb://localhost:{}/", DB_PORT))?;
+        other_client.db(DB_NAME).collection(PullRequest::NAME).update_one(
+            filter.clone(), doc!{ "$set": { "title": "Elsewhere" } }, None
+        )?;
+        assert_eq!(cache.find_one(filter.clone())?, Some(pr.clone()));
+        assert_eq!(cache.find_many(doc!{})?, vec![PullRequest { title: String::from("Elsewhere"), ..pr.clone() }]);
+
+        // A write through the same client invalidates every cached result.
+        cache.collection().delete_one(filter.clone())?;
+        assert_eq!(cache.find_one(filter)?, None);
+        assert!(cache.find_many(doc!{})?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn geo_near_with_distances() -> Result<()> {
+        use avocado::geo::{ self, Point };
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+        struct Store {
+            #[serde(rename = "_id")]
+            id: Uid<Store>,
+            name: String,
+            #[avocado(geo_index)]
+            location: Point,
+        }
+
+        let coll: Collection<Store> = DB_HANDLE.empty_collection_novalidate()?;
+
+        let stores: Vec<_> = [("far", 0.1), ("near", 0.001), ("middle", 0.01)]
+            .iter()
+            .map(|&(name, offset)| Ok(Store {
+                id: Uid::new_oid()?,
+                name: name.to_string(),
+                location: Point::new(19.04 + offset, 47.5),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&stores)?;
+
+        let options = geo::NearOptions {
+            max_distance: Some(2000.0),
+            ..Default::default()
+        };
+        let results = coll
+            .geo_near(Point::new(19.04, 47.5), options)?
+            .collect::<Result<Vec<_>>>()?;
+        let names: Vec<_> = results.iter().map(|result| result.doc.name.as_str()).collect();
+
+        assert_eq!(names, ["near", "middle"]);
+        assert!(results[0].distance > 50.0 && results[0].distance < 100.0);
+        assert!(results[0].distance < results[1].distance);
+
+        let limited = geo::NearOptions {
+            limit: Some(1),
+            distance_multiplier: Some(0.001),
+            filter: doc!{ "name": { "$ne": "near" } },
+            ..Default::default()
+        };
+        let nearest = coll
+            .geo_near(Point::new(19.04, 47.5), limited)?
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].doc, stores[2]);
+        assert!(nearest[0].distance < 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn text_search_with_scores() -> Result<()> {
+        use avocado::text;
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+        struct Post {
+            #[serde(rename = "_id")]
+            id: Uid<Post>,
+            #[avocado(text_index = 10)]
+            title: String,
+            #[avocado(text_index)]
+            body: String,
+        }
+
+        let coll: Collection<Post> = DB_HANDLE.empty_collection_novalidate()?;
+        let posts: Vec<_> = [
+            ("Brewing coffee", "A guide to grinding beans."),
+            ("Morning routine", "Tea, then coffee, then more coffee."),
+            ("Gardening", "Tomatoes need plenty of sun."),
+        ]
+            .iter()
+            .map(|&(title, body)| Ok(Post {
+                id: Uid::new_oid()?,
+                title: title.to_string(),
+                body: body.to_string(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&posts)?;
+
+        let results = coll
+            .text_search("coffee", text::SearchOptions::default())?
+            .collect::<Result<Vec<_>>>()?;
+
+        // The weight of the title outranks the repetitions in the body.
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, posts[0]);
+        assert_eq!(results[1].0, posts[1]);
+        assert!(results[0].1 > results[1].1);
+
+        let options = text::SearchOptions {
+            min_score: Some(results[0].1),
+            ..Default::default()
+        };
+        let best = coll.text_search("coffee", options)?.collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(best.len(), 1);
+        assert_eq!(best[0].0, posts[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_by_reports() -> Result<()> {
+        use avocado::pipeline::Accumulator;
+
+        #[derive(Debug, Clone, Serialize, Deserialize, Doc)]
+        struct Order {
+            #[serde(rename = "_id")]
+            id: Uid<Order>,
+            customer: String,
+            amount: i64,
+        }
+
+        let coll: Collection<Order> = DB_HANDLE.empty_collection_novalidate()?;
+        let orders: Vec<_> = [("bob", 30), ("alice", 10), ("bob", 5), ("alice", 20), ("carol", 7)]
+            .iter()
+            .map(|&(customer, amount)| Ok(Order {
+                id: Uid::new_oid()?,
+                customer: customer.to_string(),
+                amount,
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&orders)?;
+
+        let counts: Vec<(String, i32)> = coll.group_by("$customer", Accumulator::Count)?;
+        assert_eq!(counts, [
+            ("alice".to_string(), 2),
+            ("bob".to_string(), 2),
+            ("carol".to_string(), 1),
+        ]);
+
+        let totals: Vec<(String, i64)> = coll.group_by("$customer", Accumulator::Sum("$amount".into()))?;
+        assert_eq!(totals, [
+            ("alice".to_string(), 30),
+            ("bob".to_string(), 35),
+            ("carol".to_string(), 7),
+        ]);
+
+        let largest: Vec<(Option<String>, i64)> = coll.group_by(Bson::Null, Accumulator::Max("$amount".into()))?;
+        assert_eq!(largest, [(None, 30)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_and_restore_progress() -> Result<()> {
+        use avocado::coll::{ RestorePolicy, RestoreResult };
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = (0..2500)
+            .map(|i| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: format!("group #{}", i),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let mut archive = Vec::new();
+        let mut dumped = Vec::new();
+        let n_docs = coll.dump_with_progress(&mut archive, doc!{}, |n| dumped.push(n))?;
+
+        assert_eq!(n_docs, 2500);
+        assert_eq!(dumped, [1000, 2000, 2500]);
+
+        coll.delete_many(doc!{})?;
+
+        let mut restored = Vec::new();
+        let result = coll.restore_with_progress(
+            archive.as_slice(),
+            RestorePolicy::Insert,
+            |result| restored.push(result.inserted),
+        )?;
+
+        assert_eq!(result, RestoreResult { inserted: 2500, replaced: 0 });
+        assert_eq!(restored, [1000, 2000, 2500]);
+
+        let mut replaced = Vec::new();
+        coll.restore_with_progress(
+            archive.as_slice(),
+            RestorePolicy::Replace,
+            |result| replaced.push(result.replaced),
+        )?;
+
+        assert_eq!(replaced, [1000, 2000, 2500]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn serde_profile_storage() -> Result<()> {
+        use mongodb::db::ThreadedDatabase;
+        use avocado::profile::{ self, NoneFields, UnsignedRepr };
+
+        const COMPACT: profile::Profile = profile::Profile {
+            none_fields: NoneFields::Skip,
+            unsigned: UnsignedRepr::String,
+        };
+
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Doc)]
+        #[avocado(serde_profile = "COMPACT")]
+        struct Counter {
+            _id: Uid<Counter>,
+            hits: u64,
+            label: Option<String>,
+        }
+
+        let coll: Collection<Counter> = DB_HANDLE.empty_collection_novalidate()?;
+        let raw = DB_HANDLE.collection(Counter::NAME);
+        let counter = Counter {
+            _id: Uid::new_oid()?,
+            hits: u64::max_value(),
+            label: None,
+        };
+        coll.insert_one(&counter)?;
+
+        let stored = raw.find_one(None, None)?.expect("counter not found");
+        assert_eq!(stored.get_str("hits")?, "18446744073709551615");
+        assert!(!stored.contains_key("label"));
+
+        assert_eq!(coll.find_one(doc!{ "_id": &counter._id })?, Some(counter.clone()));
+        assert_eq!(coll.count(doc!{ "label": { "$exists": false } })?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn par_scan_partitions() -> Result<()> {
+        use std::thread;
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = (0..500)
+            .map(|i| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: format!("group {}", i),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let partitions = coll.scan_partitions(4)?;
+        assert!(partitions.len() >= 2 && partitions.len() <= 4);
+        assert_eq!(partitions.first().and_then(|p| p.min.clone()), None);
+        assert_eq!(partitions.last().and_then(|p| p.max.clone()), None);
+
+        let workers: Vec<_> = coll
+            .par_scan(4)?
+            .into_iter()
+            .map(|cursor| thread::spawn(move || {
+                cursor.map(|group| group.map(|g| g._id)).collect::<Result<Vec<_>>>()
+            }))
+            .collect();
+
+        let mut scanned = Vec::new();
+        for worker in workers {
+            scanned.extend(worker.join().expect("scan thread panicked")?);
+        }
+
+        let expected: BTreeSet<_> = groups.iter().map(|g| g._id.clone()).collect();
+        let unique: BTreeSet<_> = scanned.iter().cloned().collect();
+
+        assert_eq!(scanned.len(), groups.len());
+        assert_eq!(unique, expected);
+
+        assert_eq!(coll.scan_partitions(1)?.len(), 1);
+        assert_eq!(coll.par_scan(0)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancellable_operations() -> Result<()> {
+        use std::time::{ Duration, Instant };
+        use avocado::cancel::CancellationToken;
+        use avocado::batch;
+
+        #[derive(Debug, Clone, Copy)]
+        struct Names;
+
+        impl Pipeline<Group> for Names {
+            type Output = Document;
+
+            fn stages(&self) -> Vec<Document> {
+                vec![
+                    doc!{ "$sort": { "name": 1 } },
+                    doc!{ "$project": { "_id": 0, "name": 1 } },
+                ]
+            }
+        }
+
+        let coll: Collection<Group> = DB_HANDLE.empty_collection()?;
+        let groups: Vec<_> = (0..250)
+            .map(|i| Ok(Group {
+                _id: Uid::new_oid()?,
+                name: format!("group {:03}", i),
+                description: String::new(),
+            }))
+            .collect::<Result<_>>()?;
+
+        coll.insert_many(&groups)?;
+
+        let token = CancellationToken::with_timeout(Duration::from_secs(60));
+        let found = coll.find_many_cancellable(doc!{}, &token)?;
+        let names = coll.aggregate_cancellable(Names, &token)?;
+
+        assert_eq!(found.len(), groups.len());
+        assert_eq!(names.len(), groups.len());
+        assert_eq!(names[0], doc!{ "name": "group 000" });
+
+        let canceled = CancellationToken::new();
+        canceled.cancel()?;
+
+        let error = coll.find_many_cancellable(doc!{}, &canceled).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::Canceled);
+
+        let expired = CancellationToken::with_deadline(Instant::now());
+        let error = coll.aggregate_cancellable(Names, &expired).unwrap_err();
+        assert_eq!(error.kind(), AvocadoErrorKind::Canceled);
+
+        let stopped = CancellationToken::new();
+        let mut writer = coll
+            .batched_writer(batch::Options::default())
+            .with_cancellation(stopped.clone());
+
+        writer.insert(&Group {
+            _id: Uid::new_oid()?,
+            name: String::from("late"),
+            description: String::new(),
+        })?;
+        stopped.cancel()?;
+
+        assert_eq!(writer.flush().unwrap_err().kind(), AvocadoErrorKind::Canceled);
+        assert!(writer.is_empty());
+        assert_eq!(coll.count(doc!{ "name": "late" })?, 0);
+
+        Ok(())
+    }
+
     #[test]
     fn keep_server_alive() {}
 }
@@ -0,0 +1,116 @@
+//! The size limits of a capped collection, specified by an attribute.
+
+use proc_macro2::TokenStream;
+use syn::{ Attribute, Lit };
+use quote::{ ToTokens, TokenStreamExt };
+use crate::{
+    error::{ Result, err_msg },
+    attr::*,
+};
+
+/// Describes the `#[capped(size = ..., max = ...)]` attribute.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capped {
+    /// The maximal total size of the documents, in bytes. Required.
+    size: i64,
+    /// The maximal number of documents, if limited.
+    max: Option<i64>,
+}
+
+impl Capped {
+    /// Parses the `#[capped(...)]` attribute, if any.
+    ///
+    /// ### Return value:
+    /// * `Ok(None)` if there is no `#[capped(...)]` attribute
+    /// * `Ok(Some(Capped))` if the attribute is well-formed
+    /// * `Err(Error)` if the attribute is ill-formed or it occurs twice.
+    pub fn from_attributes(attrs: &[Attribute]) -> Result<Option<Self>> {
+        let mut capped = None;
+
+        for attr in attrs {
+            let nested = match attr.parse_ext_meta() {
+                Some(ExtMeta::List(path, _, nested)) => {
+                    if path.colon_sep_str() == "capped" {
+                        nested
+                    } else {
+                        continue
+                    }
+                }
+                Some(ExtMeta::Path(path)) | Some(ExtMeta::KeyValue(path, ..)) => {
+                    if path.colon_sep_str() == "capped" {
+                        err_msg("attribute must have form `#[capped(size = ...)]`")?
+                    } else {
+                        continue
+                    }
+                }
+                None => continue,
+            };
+
+            if capped.is_some() {
+                return err_msg("at most one `#[capped(...)]` attribute is allowed");
+            }
+
+            capped = Some(Self::from_nested(nested)?);
+        }
+
+        Ok(capped)
+    }
+
+    /// Parses the items inside `#[capped(...)]`.
+    fn from_nested<I>(nested: I) -> Result<Self>
+        where I: IntoIterator<Item = NestedExtMeta>
+    {
+        let mut size = None;
+        let mut max = None;
+
+        for item in nested {
+            let (path_str, lit) = match item {
+                NestedExtMeta::Meta(ExtMeta::KeyValue(path, _, lit)) => {
+                    (path.colon_sep_str(), lit)
+                }
+                _ => err_msg("capped collection options must have form `name = value`")?
+            };
+
+            match path_str.as_str() {
+                "size" => size = Some(value_as_positive_i64(&path_str, &lit)?),
+                "max"  => max = Some(value_as_positive_i64(&path_str, &lit)?),
+                _ => err_fmt!("unknown capped collection option: {}", path_str)?
+            }
+        }
+
+        match size {
+            Some(bytes) => Ok(Capped { size: bytes, max }),
+            None => err_msg("`#[capped(...)]` requires a `size`"),
+        }
+    }
+}
+
+/// Extracts a positive `i64` from an integer literal.
+#[allow(clippy::cast_possible_wrap)]
+fn value_as_positive_i64(key: &str, lit: &Lit) -> Result<i64> {
+    match *lit {
+        Lit::Int(ref int) if int.value() > 0 && int.value() <= i64::max_value() as u64 => {
+            Ok(int.value() as i64)
+        }
+        _ => err_fmt!("value for key `{}` must be a positive i64", key)
+    }
+}
+
+impl ToTokens for Capped {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let size = self.size;
+        let max = match self.max {
+            Some(max) => quote!(::std::option::Option::Some(#max)),
+            None => quote!(::std::option::Option::None),
+        };
+
+        tokens.append_all(quote! {
+            fn capped() -> ::std::option::Option<::avocado::doc::Capped> {
+                ::std::option::Option::Some(::avocado::doc::Capped {
+                    size: #size,
+                    max: #max,
+                })
+            }
+        });
+    }
+}
@@ -71,6 +71,51 @@ impl RenameRule {
             ScreamingKebabCase => ScreamingSnakeCase.apply_to_field(field).replace('_', "-"),
         }
     }
+
+    /// Returns a string which is the given variant name, renamed according
+    /// to the rule that is `self`. Variant names are assumed to be PascalCase.
+    pub fn apply_to_variant(self, variant: String) -> String {
+        match self {
+            PascalCase => variant,
+            LowerCase => variant.to_ascii_lowercase(),
+            Uppercase => variant.to_ascii_uppercase(),
+            CamelCase => variant[..1].to_ascii_lowercase() + &variant[1..],
+            SnakeCase => {
+                let mut snake = String::new();
+                for (i, ch) in variant.char_indices() {
+                    if i > 0 && ch.is_uppercase() {
+                        snake.push('_');
+                    }
+                    snake.push(ch.to_ascii_lowercase());
+                }
+                snake
+            }
+            ScreamingSnakeCase => SnakeCase.apply_to_variant(variant).to_ascii_uppercase(),
+            KebabCase => SnakeCase.apply_to_variant(variant).replace('_', "-"),
+            ScreamingKebabCase => ScreamingSnakeCase.apply_to_variant(variant).replace('_', "-"),
+        }
+    }
+
+    /// Returns the given dotted field path with each component renamed,
+    /// except for array indexes, positional operators and names starting
+    /// with an underscore, such as `_id`.
+    pub fn apply_to_path(self, path: &str) -> String {
+        path.split('.')
+            .map(|component| {
+                let kept = component.is_empty()
+                    || component.starts_with('$')
+                    || component.starts_with('_')
+                    || component.bytes().all(|b| b.is_ascii_digit());
+
+                if kept {
+                    component.to_owned()
+                } else {
+                    self.apply_to_field(component.to_owned())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
 }
 
 impl FromStr for RenameRule {
@@ -90,3 +135,68 @@ impl FromStr for RenameRule {
         }
     }
 }
+
+/// A strategy for deriving the name of a collection from the name of the
+/// type, as given by `#[collection_naming = "..."]`: a `rename_all` rule,
+/// optionally followed by `_plural`, or just `plural`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CollectionNaming {
+    /// The case of the collection name. `None` keeps the case of the type.
+    rule: Option<RenameRule>,
+    /// Whether the (last word of the) name is pluralized.
+    plural: bool,
+}
+
+impl CollectionNaming {
+    /// Returns the name of the collection of the type named `ty`, which is
+    /// assumed to be PascalCase. The name is pluralized before converting
+    /// its case, so that e.g. `OrderEntry` becomes `order_entries`.
+    pub fn apply(self, ty: &str) -> String {
+        let name = if self.plural {
+            pluralize(ty)
+        } else {
+            ty.to_owned()
+        };
+
+        match self.rule {
+            Some(rule) => rule.apply_to_variant(name),
+            None => name,
+        }
+    }
+}
+
+impl FromStr for CollectionNaming {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let suffix = "_plural";
+        let (rule_name, plural) = match s.rfind(suffix) {
+            _ if s == "plural" => (None, true),
+            Some(end) if end + suffix.len() == s.len() => (Some(&s[..end]), true),
+            _ => (Some(s), false),
+        };
+        let rule = match rule_name {
+            Some(name) => Some(name.parse::<RenameRule>().map_err(|_| Error::new(format!(
+                "unknown `collection_naming` strategy: {}; expected a `rename_all` \
+                 rule, optionally followed by `_plural`, or `plural`", s
+            )))?),
+            None => None,
+        };
+
+        Ok(CollectionNaming { rule, plural })
+    }
+}
+
+/// Returns the English plural of a word, using the regular rules only.
+fn pluralize(word: &str) -> String {
+    let lower = word.to_lowercase();
+    let vowel_y = ["ay", "ey", "iy", "oy", "uy"].iter().any(|suffix| lower.ends_with(suffix));
+
+    if ["s", "x", "z", "ch", "sh"].iter().any(|suffix| lower.ends_with(suffix)) {
+        format!("{}es", word)
+    } else if lower.ends_with('y') && lower.len() > 1 && !vowel_y {
+        format!("{}ies", &word[..word.len() - 1])
+    } else {
+        format!("{}s", word)
+    }
+}
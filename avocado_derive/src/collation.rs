@@ -0,0 +1,170 @@
+//! The default collation of a collection, specified by an attribute.
+
+use proc_macro2::TokenStream;
+use syn::Attribute;
+use quote::{ ToTokens, TokenStreamExt };
+use crate::{
+    error::{ Result, err_msg },
+    attr::*,
+    meta::*,
+};
+
+/// Describes the `#[doc_collation(...)]` attribute. The value of each option
+/// is stored together with the corresponding (camel case) key of MongoDB's
+/// collation document, in the order they were specified.
+#[derive(Debug, Clone, Default)]
+pub struct Collation {
+    /// The ICU locale, e.g. `"en"` or `"fr_CA"`. Required.
+    locale: Option<String>,
+    /// The rest of the collation options, as key-value pairs.
+    options: Vec<(&'static str, Value)>,
+}
+
+/// The value of a single collation option.
+#[derive(Debug, Clone)]
+enum Value {
+    /// A boolean switch, e.g. `numeric_ordering`.
+    Bool(bool),
+    /// An integer, i.e. `strength`.
+    Int(i32),
+    /// An enumerated string, e.g. `case_first`.
+    Str(String),
+}
+
+impl Collation {
+    /// Parses the `#[doc_collation(...)]` attribute, if any.
+    ///
+    /// ### Return value:
+    /// * `Ok(None)` if there is no `#[doc_collation(...)]` attribute
+    /// * `Ok(Some(Collation))` if the attribute is well-formed
+    /// * `Err(Error)` if the attribute is ill-formed or it occurs twice.
+    pub fn from_attributes(attrs: &[Attribute]) -> Result<Option<Self>> {
+        let mut collation = None;
+
+        for attr in attrs {
+            let nested = match attr.parse_ext_meta() {
+                Some(ExtMeta::List(path, _, nested)) => {
+                    if path.colon_sep_str() == "doc_collation" {
+                        nested
+                    } else {
+                        continue
+                    }
+                }
+                Some(ExtMeta::Path(path)) | Some(ExtMeta::KeyValue(path, ..)) => {
+                    if path.colon_sep_str() == "doc_collation" {
+                        err_msg("attribute must have form `#[doc_collation(...)]`")?
+                    } else {
+                        continue
+                    }
+                }
+                None => continue,
+            };
+
+            if collation.is_some() {
+                return err_msg("at most one `#[doc_collation(...)]` attribute is allowed");
+            }
+
+            collation = Some(Self::from_nested(nested)?);
+        }
+
+        Ok(collation)
+    }
+
+    /// Parses the items inside `#[doc_collation(...)]`.
+    fn from_nested<I>(nested: I) -> Result<Self>
+        where I: IntoIterator<Item = NestedExtMeta>
+    {
+        let mut collation = Collation::default();
+
+        for item in nested {
+            let (path_str, lit) = match item {
+                NestedExtMeta::Meta(ExtMeta::KeyValue(path, _, lit)) => {
+                    (path.colon_sep_str(), lit)
+                }
+                _ => err_msg("collation options must have form `name = value`")?
+            };
+
+            let (key, value) = match path_str.as_str() {
+                "locale" => {
+                    collation.locale = Some(lit_value_as_str(&path_str, &lit)?);
+                    continue
+                }
+                "strength" => {
+                    ("strength", Value::Int(value_as_i32(&path_str, &lit, 1..=5)?))
+                }
+                "case_level" => {
+                    ("caseLevel", Value::Bool(value_as_bool(&path_str, &lit)?))
+                }
+                "numeric_ordering" => {
+                    ("numericOrdering", Value::Bool(value_as_bool(&path_str, &lit)?))
+                }
+                "backwards" => {
+                    ("backwards", Value::Bool(value_as_bool(&path_str, &lit)?))
+                }
+                "normalization" => {
+                    ("normalization", Value::Bool(value_as_bool(&path_str, &lit)?))
+                }
+                "case_first" => {
+                    ("caseFirst", one_of(&path_str, &lit, &["upper", "lower", "off"])?)
+                }
+                "alternate" => {
+                    ("alternate", one_of(&path_str, &lit, &["non-ignorable", "shifted"])?)
+                }
+                "max_variable" => {
+                    ("maxVariable", one_of(&path_str, &lit, &["punct", "space"])?)
+                }
+                _ => err_fmt!("unknown collation option: {}", path_str)?
+            };
+
+            if collation.options.iter().any(|&(k, _)| k == key) {
+                err_fmt!("duplicate collation option: {}", path_str)?
+            }
+
+            collation.options.push((key, value));
+        }
+
+        if collation.locale.is_none() {
+            err_msg("`#[doc_collation(...)]` requires a `locale`")?
+        }
+
+        Ok(collation)
+    }
+}
+
+/// Extracts a string value which must be one of the `allowed` strings.
+fn one_of(key: &str, lit: &syn::Lit, allowed: &[&str]) -> Result<Value> {
+    let value = lit_value_as_str(key, lit)?;
+
+    if allowed.contains(&value.as_str()) {
+        Ok(Value::Str(value))
+    } else {
+        err_fmt!("value for key `{}` must be one of {:?}", key, allowed)
+    }
+}
+
+impl ToTokens for Value {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match *self {
+            Value::Bool(b) => b.to_tokens(tokens),
+            Value::Int(n) => n.to_tokens(tokens),
+            Value::Str(ref s) => s.to_tokens(tokens),
+        }
+    }
+}
+
+impl ToTokens for Collation {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let locale = &self.locale;
+        let keys = self.options.iter().map(|&(key, _)| key);
+        let values = self.options.iter().map(|&(_, ref value)| value);
+
+        tokens.append_all(quote! {
+            fn collation() -> ::std::option::Option<::avocado::prelude::Document> {
+                let mut avocado_collation = ::avocado::prelude::Document::new();
+                avocado_collation.insert("locale", #locale);
+                #(avocado_collation.insert(#keys, #values);)*
+                ::std::option::Option::Some(avocado_collation)
+            }
+        });
+    }
+}
@@ -7,7 +7,11 @@ use std::ops::Deref;
 use std::num::{ ParseIntError, ParseFloatError };
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
+use proc_macro2::{ Span, TokenStream };
+use syn::Attribute;
+use syn::spanned::Spanned;
 use syn::synom::ParseError;
+use crate::attr::PathExt;
 
 /// Returns an `Err(Error::new(...))` with the given formatted error message.
 macro_rules! err_fmt {
@@ -29,6 +33,8 @@ pub struct Error {
     message: String,
     /// The underlying error, if any.
     cause: Option<Box<dyn error::Error + 'static>>,
+    /// The location in the input which the error points to, if known.
+    span: Option<Span>,
 }
 
 impl Error {
@@ -37,8 +43,54 @@ impl Error {
         Error {
             message: message.into(),
             cause: None,
+            span: None,
         }
     }
+
+    /// Makes the error point to the given syntax node, unless it already
+    /// points to a more specific location.
+    pub fn at<T: Spanned>(mut self, node: &T) -> Self {
+        if self.span.is_none() {
+            self.span = Some(node.span());
+        }
+        self
+    }
+
+    /// Converts the error into a `compile_error!()` invocation, spanned so
+    /// that the compiler reports it at the offending field or attribute.
+    /// Errors without a known location are reported at the derive itself.
+    pub fn to_compile_error(&self) -> TokenStream {
+        let span = self.span.unwrap_or_else(Span::call_site);
+        let message = self.to_string();
+
+        quote_spanned!(span=> compile_error!(#message);)
+    }
+}
+
+/// Attaches locations to the errors of a `Result`.
+pub trait ResultExt<T> {
+    /// Makes the error, if any, point to the given syntax node, unless it
+    /// already points to a more specific location.
+    fn at<S: Spanned>(self, node: &S) -> Result<T>;
+
+    /// Makes the error, if any, point to the first attribute named `name`,
+    /// unless it already points to a more specific location.
+    fn at_attr(self, attrs: &[Attribute], name: &str) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn at<S: Spanned>(self, node: &S) -> Result<T> {
+        self.map_err(|error| error.at(node))
+    }
+
+    fn at_attr(self, attrs: &[Attribute], name: &str) -> Result<T> {
+        self.map_err(|error| {
+            match attrs.iter().find(|attr| attr.path.colon_sep_str() == name) {
+                Some(attr) => error.at(attr),
+                None => error,
+            }
+        })
+    }
 }
 
 impl fmt::Display for Error {
@@ -68,6 +120,7 @@ macro_rules! impl_error {
                 Error {
                     message: String::from($message),
                     cause: Some(Box::new(error)),
+                    span: None,
                 }
             }
         }
@@ -0,0 +1,121 @@
+//! Generates fake-data builders ("factories") for `Doc` types, for use in
+//! tests. Only available with the `testing` feature.
+
+use proc_macro2::{ Span, TokenStream };
+use syn::{ Fields, Generics, Ident, Visibility };
+use crate::error::{ Result, err_msg };
+
+/// The names of the methods of the factory. Fields with these names would
+/// clash with the generated setters.
+const RESERVED_NAMES: &[&str] = &["new", "with", "build", "insert"];
+
+/// Implements `{Type}Factory`, which builds values of the type with fake
+/// field values, individual fields of which can be overridden.
+pub fn impl_factory(
+    vis: &Visibility,
+    ty: &Ident,
+    generics: &Generics,
+    fields: &Fields,
+) -> Result<TokenStream> {
+    let named = match *fields {
+        Fields::Named(ref named_fields) => &named_fields.named,
+        _ => return err_msg("a `Doc` must be a struct with named fields"),
+    };
+    let idents: Vec<_> = named.iter().filter_map(|field| field.ident.as_ref()).collect();
+    let types: Vec<_> = named.iter().map(|field| &field.ty).collect();
+
+    let reserved = idents.iter().find(
+        |ident| RESERVED_NAMES.contains(&ident.to_string().as_str())
+    );
+
+    if let Some(ident) = reserved {
+        return err_fmt!("field `{}` would clash with a method of the generated factory", ident);
+    }
+
+    let field_names: Vec<_> = idents.iter().map(ToString::to_string).collect();
+    let setter_docs: Vec<_> = field_names
+        .iter()
+        .map(|name| format!("Overrides the value of `{}`.", name))
+        .collect();
+    let factory = Ident::new(&format!("{}Factory", ty), Span::call_site());
+    let factory_doc = format!(
+        "Builds `{}` values with fake data, for use in tests.",
+        ty
+    );
+    let (impl_gen, ty_gen, where_cls) = generics.split_for_impl();
+    let idents_1 = &idents;
+    let idents_2 = &idents;
+    let setter_vis = idents.iter().map(|_| vis);
+
+    Ok(quote! {
+        #[doc = #factory_doc]
+        #vis struct #factory #impl_gen #where_cls {
+            /// The value being built.
+            entity: #ty #ty_gen,
+        }
+
+        impl #impl_gen #factory #ty_gen #where_cls {
+            /// Creates a factory in which every field has a fake value.
+            #vis fn new() -> Self {
+                let avocado_seq = ::avocado::testing::next_sequence();
+
+                #factory {
+                    entity: #ty {
+                        #(#idents_1: ::avocado::testing::Fake::fake(#field_names, avocado_seq),)*
+                    }
+                }
+            }
+
+            #(
+                #[doc = #setter_docs]
+                #setter_vis fn #idents_1<V>(mut self, value: V) -> Self
+                    where V: ::std::convert::Into<#types>
+                {
+                    self.entity.#idents_2 = ::std::convert::Into::into(value);
+                    self
+                }
+            )*
+
+            /// Modifies the value being built by calling `f` on it.
+            #vis fn with<F>(mut self, f: F) -> Self
+                where F: ::std::ops::FnOnce(&mut #ty #ty_gen)
+            {
+                f(&mut self.entity);
+                self
+            }
+
+            /// Returns the built value without inserting it.
+            #vis fn build(self) -> #ty #ty_gen {
+                self.entity
+            }
+
+            /// Inserts the built value into `collection`, and returns it
+            /// with the ID assigned by the server.
+            #vis fn insert(
+                self,
+                collection: &::avocado::coll::Collection<#ty #ty_gen>,
+            ) -> ::avocado::error::Result<#ty #ty_gen> {
+                let mut entity = self.entity;
+                let id = collection.insert_one(&entity)?;
+                ::avocado::doc::Doc::set_id(&mut entity, id);
+                ::std::result::Result::Ok(entity)
+            }
+        }
+
+        impl #impl_gen ::std::default::Default for #factory #ty_gen #where_cls {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl #impl_gen ::std::fmt::Debug for #factory #ty_gen
+            where #ty #ty_gen: ::std::fmt::Debug
+        {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                f.debug_struct(stringify!(#factory))
+                    .field("entity", &self.entity)
+                    .finish()
+            }
+        }
+    })
+}
@@ -0,0 +1,374 @@
+//! Implements the `checked_flt!` macro, which builds a filter document and
+//! checks its field paths and literals against the fields of a type.
+
+use std::iter::once;
+use proc_macro2::{ TokenStream, TokenTree, Delimiter, Group, Span, Ident };
+use syn::{ Type, Expr, ExprLit, ExprUnary, Lit, LitStr, UnOp };
+use syn::spanned::Spanned;
+use crate::error::{ Result, ResultExt, err_msg };
+
+/// The operators whose operand is an array of filters.
+const LOGICAL_OPERATORS: &[&str] = &["$and", "$or", "$nor"];
+
+/// The operators whose operand is compared to the value of the field.
+const COMPARISON_OPERATORS: &[&str] = &["$eq", "$ne", "$gt", "$gte", "$lt", "$lte", "$regex"];
+
+/// The operators whose operand is an array of values compared to the value
+/// of the field.
+const LIST_OPERATORS: &[&str] = &["$in", "$nin", "$all"];
+
+/// A value in the filter, as written in the invocation of the macro.
+enum Value {
+    /// A document: `{ "key": value, ... }`.
+    Object(Vec<(LitStr, Value)>, Span),
+    /// An array: `[value, ...]`.
+    Array(Vec<Value>, Span),
+    /// The `null` keyword.
+    Null(Span),
+    /// Any other Rust expression, converted into `Bson`.
+    Expr(Box<Expr>),
+}
+
+/// Parses `Type, { "path": value, ... }` and builds the filter document,
+/// along with the compile-time checks of its paths and literals.
+pub fn impl_checked_flt(input: TokenStream) -> Result<TokenStream> {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let comma = match top_level_comma(&tokens) {
+        Some(index) => index,
+        None => return err_msg("expected `checked_flt!(Type, { \"path\": value, ... })`"),
+    };
+    let ty_tokens: TokenStream = tokens[..comma].iter().cloned().collect();
+    let ty: Type = syn::parse2(ty_tokens.clone()).map_err(Into::into).at(&ty_tokens)?;
+    let filter = match parse_value(&tokens[comma + 1..])? {
+        Value::Object(entries, _) => entries,
+        _ => return err_msg("expected the filter document after the type").at(&ty),
+    };
+    let fields = quote!(<#ty as ::avocado::path::Subdoc>::fields());
+    let mut checks = Vec::new();
+    let doc = filter_doc(&fields, &filter, &mut checks)?;
+
+    Ok(quote!({
+        #(#checks)*
+        #doc
+    }))
+}
+
+/// Returns the index of the first comma which isn't between angle brackets.
+fn top_level_comma(tokens: &[TokenTree]) -> Option<usize> {
+    let mut depth = 0_usize;
+
+    tokens.iter().position(|token| match *token {
+        TokenTree::Punct(ref punct) => match punct.as_char() {
+            '<' => { depth += 1; false }
+            '>' => { depth = depth.saturating_sub(1); false }
+            ',' => depth == 0,
+            _ => false,
+        },
+        _ => false,
+    })
+}
+
+/// Splits the tokens at the commas, dropping a trailing comma.
+fn split_at_commas(tokens: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut items = vec![Vec::new()];
+
+    for token in tokens {
+        match token {
+            TokenTree::Punct(ref punct) if punct.as_char() == ',' => items.push(Vec::new()),
+            _ => if let Some(item) = items.last_mut() {
+                item.push(token);
+            },
+        }
+    }
+
+    if items.last().map_or(false, Vec::is_empty) {
+        items.pop();
+    }
+
+    items
+}
+
+/// Parses a document, an array, `null`, or an arbitrary expression.
+fn parse_value(tokens: &[TokenTree]) -> Result<Value> {
+    let value_tokens = match tokens.split_last() {
+        Some((&TokenTree::Punct(ref punct), rest)) if punct.as_char() == ',' => rest,
+        _ => tokens,
+    };
+
+    if value_tokens.len() == 1 {
+        match value_tokens[0] {
+            TokenTree::Group(ref group) if group.delimiter() == Delimiter::Brace => {
+                let entries = split_at_commas(group.stream())
+                    .iter()
+                    .map(|entry| parse_entry(entry, group))
+                    .collect::<Result<_>>()?;
+                return Ok(Value::Object(entries, group.span()));
+            }
+            TokenTree::Group(ref group) if group.delimiter() == Delimiter::Bracket => {
+                let items = split_at_commas(group.stream())
+                    .iter()
+                    .map(|item| parse_value(item))
+                    .collect::<Result<_>>()?;
+                return Ok(Value::Array(items, group.span()));
+            }
+            TokenTree::Ident(ref ident) if ident == "null" => {
+                return Ok(Value::Null(ident.span()));
+            }
+            _ => {}
+        }
+    }
+
+    let stream: TokenStream = value_tokens.iter().cloned().collect();
+
+    if stream.is_empty() {
+        return err_msg("expected a value");
+    }
+
+    syn::parse2(stream.clone())
+        .map(|expr| Value::Expr(Box::new(expr)))
+        .map_err(Into::into)
+        .at(&stream)
+}
+
+/// Parses a `"key": value` entry of a document.
+fn parse_entry(tokens: &[TokenTree], group: &Group) -> Result<(LitStr, Value)> {
+    let key = match tokens.first() {
+        Some(&TokenTree::Literal(ref literal)) => {
+            syn::parse2(once(TokenTree::Literal(literal.clone())).collect())
+                .map_err(Into::into)
+                .at(literal)?
+        }
+        Some(token) => return err_msg("expected a string literal key").at(token),
+        None => return err_msg("expected a `\"key\": value` entry").at(group),
+    };
+
+    match tokens.get(1) {
+        Some(&TokenTree::Punct(ref punct)) if punct.as_char() == ':' => {}
+        _ => return err_msg("expected `:` after the key").at(&key),
+    }
+
+    let value = parse_value(&tokens[2..]).at(&key)?;
+
+    Ok((key, value))
+}
+
+/// Builds a filter document, checking the paths of its fields.
+fn filter_doc(
+    fields: &TokenStream,
+    entries: &[(LitStr, Value)],
+    checks: &mut Vec<TokenStream>,
+) -> Result<TokenStream> {
+    let mut inserts = Vec::with_capacity(entries.len());
+
+    for &(ref key, ref value) in entries {
+        let name = key.value();
+
+        let insert = if LOGICAL_OPERATORS.contains(&name.as_str()) {
+            let filters = match *value {
+                Value::Array(ref items, _) => items,
+                _ => return err_fmt!("the operand of `{}` must be an array of filters", name).at(key),
+            };
+            let docs = filters
+                .iter()
+                .map(|filter| match *filter {
+                    Value::Object(ref subentries, _) => filter_doc(fields, subentries, checks),
+                    _ => err_fmt!("the operand of `{}` must be an array of filters", name).at(key),
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            quote! {
+                __doc.insert(#key, ::avocado::prelude::Bson::Array(vec![
+                    #(::avocado::prelude::Bson::Document(#docs),)*
+                ]));
+            }
+        } else if name.starts_with('$') {
+            let raw = raw_value(value);
+            quote!(__doc.insert(#key, #raw);)
+        } else {
+            let path = FieldRef::new(fields, key)?;
+            let path_expr = &path.path;
+            let bson = field_value(&path, value, checks)?;
+
+            quote! {
+                __doc.insert(
+                    ::std::string::String::from(::avocado::path::Path::clone(&#path_expr)),
+                    #bson
+                );
+            }
+        };
+
+        inserts.push(insert);
+    }
+
+    if inserts.is_empty() {
+        Ok(quote!(::avocado::prelude::Document::new()))
+    } else {
+        Ok(quote!({
+            let mut __doc = ::avocado::prelude::Document::new();
+            #(#inserts)*
+            __doc
+        }))
+    }
+}
+
+/// A field referred to by a dotted path in the filter.
+struct FieldRef {
+    /// The expression evaluating to the `path::Path` of the field.
+    path: TokenStream,
+    /// The expression evaluating to the `avocado::path::kind` marker of the
+    /// field.
+    kind: TokenStream,
+}
+
+impl FieldRef {
+    /// Resolves the path `key`, made up of Rust field names, to the accessor
+    /// methods generated by `#[derive(Subdoc)]`, so that a misspelled field
+    /// is reported as a missing method, pointing to the key.
+    fn new(fields: &TokenStream, key: &LitStr) -> Result<Self> {
+        let name = key.value();
+        let span = key.span();
+        let mut segments = Vec::new();
+
+        for segment in name.split('.') {
+            let is_ident = segment.chars().next().map_or(false, |c| c == '_' || c.is_ascii_alphabetic())
+                && segment.chars().all(|c| c == '_' || c.is_ascii_alphanumeric());
+
+            if !is_ident {
+                return err_fmt!(
+                    "`{}` in the path `{}` is not a field name; only paths made up of \
+                     field names can be checked",
+                    segment, name
+                ).at(key);
+            }
+
+            segments.push(Ident::new(segment, span));
+        }
+
+        let (last, parents) = match segments.split_last() {
+            Some(split) => split,
+            None => return err_msg("empty field path").at(key),
+        };
+        let kind_method = Ident::new(&format!("__avocado_kind_of_{}", last), span);
+
+        Ok(FieldRef {
+            path: quote_spanned!(span=> #fields #(.#parents())* .#last()),
+            kind: quote_spanned!(span=> #fields #(.#parents())* .#kind_method()),
+        })
+    }
+
+    /// Records a check that the literal `value`, if it is one, can be
+    /// compared to the field.
+    fn check_literal(&self, value: &Value, checks: &mut Vec<TokenStream>) {
+        let (kind_name, span) = match *value {
+            Value::Object(_, span) => ("Object", span),
+            Value::Array(_, span) => ("List", span),
+            Value::Null(span) => ("Null", span),
+            Value::Expr(ref expr) => match literal_kind(expr) {
+                Some(name) => (name, expr.span()),
+                None => return,
+            },
+        };
+        let kind = &self.kind;
+        let literal_kind = Ident::new(kind_name, span);
+
+        checks.push(quote_spanned! {span=>
+            ::avocado::path::kind::check(#kind, ::avocado::path::kind::#literal_kind);
+        });
+    }
+}
+
+/// Returns the name of the `avocado::path::kind` marker of a literal, or
+/// `None` if the expression isn't a literal.
+fn literal_kind(expr: &Expr) -> Option<&'static str> {
+    match *expr {
+        Expr::Lit(ExprLit { ref lit, .. }) => match *lit {
+            Lit::Str(_) | Lit::Char(_) => Some("Str"),
+            Lit::Int(_) | Lit::Float(_) => Some("Number"),
+            Lit::Bool(_) => Some("Bool"),
+            _ => None,
+        },
+        Expr::Unary(ExprUnary { op: UnOp::Neg(_), expr: ref operand, .. }) => {
+            literal_kind(operand).filter(|&kind| kind == "Number")
+        }
+        _ => None,
+    }
+}
+
+/// Builds the value a field is matched against, checking its literals.
+fn field_value(
+    field: &FieldRef,
+    value: &Value,
+    checks: &mut Vec<TokenStream>,
+) -> Result<TokenStream> {
+    let entries = match *value {
+        Value::Object(ref entries, _) => entries,
+        _ => {
+            field.check_literal(value, checks);
+            return Ok(raw_value(value));
+        }
+    };
+    let operators = entries.iter().filter(|&&(ref key, _)| key.value().starts_with('$')).count();
+
+    if operators == 0 {
+        field.check_literal(value, checks);
+        return Ok(raw_value(value));
+    }
+
+    if operators < entries.len() {
+        return err_msg("a document can't contain both operators and fields").at(&entries[0].0);
+    }
+
+    let mut inserts = Vec::with_capacity(entries.len());
+
+    for &(ref key, ref operand) in entries {
+        let name = key.value();
+
+        if COMPARISON_OPERATORS.contains(&name.as_str()) {
+            field.check_literal(operand, checks);
+        } else if LIST_OPERATORS.contains(&name.as_str()) {
+            if let Value::Array(ref items, _) = *operand {
+                for item in items {
+                    field.check_literal(item, checks);
+                }
+            }
+        } else if name == "$not" {
+            if let Value::Object(..) = *operand {
+                let negated = field_value(field, operand, checks)?;
+                inserts.push(quote!(__doc.insert(#key, #negated);));
+                continue;
+            }
+        }
+
+        let raw = raw_value(operand);
+        inserts.push(quote!(__doc.insert(#key, #raw);));
+    }
+
+    Ok(quote!(::avocado::prelude::Bson::Document({
+        let mut __doc = ::avocado::prelude::Document::new();
+        #(#inserts)*
+        __doc
+    })))
+}
+
+/// Converts a value into `Bson` as-is, without checking it.
+fn raw_value(value: &Value) -> TokenStream {
+    match *value {
+        Value::Object(ref entries, _) => {
+            let keys = entries.iter().map(|&(ref key, _)| key);
+            let values = entries.iter().map(|&(_, ref entry)| raw_value(entry));
+
+            quote!(::avocado::prelude::Bson::Document({
+                #[allow(unused_mut)]
+                let mut __doc = ::avocado::prelude::Document::new();
+                #(__doc.insert(#keys, #values);)*
+                __doc
+            }))
+        }
+        Value::Array(ref items, _) => {
+            let values = items.iter().map(raw_value);
+            quote!(::avocado::prelude::Bson::Array(vec![#(#values),*]))
+        }
+        Value::Null(_) => quote!(::avocado::prelude::Bson::Null),
+        Value::Expr(ref expr) => quote!(::avocado::prelude::Bson::from(#expr)),
+    }
+}
@@ -0,0 +1,96 @@
+//! Checks the type of the `_id` field against the `Id` type of a `Doc`.
+
+use syn::{ Ident, Type, TypePath, PathSegment, PathArguments, GenericArgument };
+use quote::ToTokens;
+use crate::{
+    SerializedField,
+    error::{ Result, ResultExt },
+};
+
+/// The raw types which are commonly used as the `Id` of a `Doc`. A field of
+/// one of these types can't be a well-typed `_id`, since that must be a
+/// `Uid<T>`, which in turn has the raw type given by `#[id_type = "..."]`.
+const RAW_ID_TYPES: &[&str] = &[
+    "ObjectId", "String", "Uuid", "Bson",
+    "i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "isize", "usize",
+];
+
+/// Returns `Err` if the type of the field serialized as `_id` doesn't agree
+/// with the `Id` type of the `Doc` named `ty`. The field must be a
+/// `Uid<ty>` or an `Option<Uid<ty>>`. Fields of other, unrecognized types
+/// (e.g. type aliases) are left to the type checker.
+pub fn check_id_field(ty: &Ident, fields: &[SerializedField], id_ty: &Type) -> Result<()> {
+    let field = match fields.iter().find(|field| field.name == "_id") {
+        Some(field) => field,
+        None => return Ok(()),
+    };
+    let uid = generic_argument_of(&field.ty, "Option").unwrap_or(&field.ty);
+
+    if let Some(doc_ty) = generic_argument_of(uid, "Uid") {
+        return match last_segment(doc_ty) {
+            Some(segment) if segment.ident == "Self" || segment.ident == *ty => Ok(()),
+            _ => err_fmt!(
+                "the `_id` field `{}` must be a `Uid<{}>`, not a `Uid<{}>`",
+                field.ident, ty, type_str(doc_ty)
+            ).at(doc_ty),
+        };
+    }
+
+    let raw_ident = match last_segment(uid) {
+        Some(segment) => &segment.ident,
+        None => return Ok(()),
+    };
+    let id_ident = last_segment(id_ty).map(|segment| &segment.ident);
+
+    if id_ident == Some(raw_ident) {
+        err_fmt!(
+            "the `_id` field `{}` must be a `Uid<{}>`, not the raw `#[id_type]` `{}`",
+            field.ident, ty, type_str(uid)
+        ).at(uid)
+    } else if RAW_ID_TYPES.iter().any(|name| raw_ident == name) {
+        err_fmt!(
+            "the type of the `_id` field `{}`, `{}`, doesn't match `#[id_type]`, `{}`; \
+             declare the field as a `Uid<{}>`",
+            field.ident, type_str(uid), type_str(id_ty), ty
+        ).at(uid)
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the last segment of the path, if `ty` is a path type.
+pub fn last_segment(ty: &Type) -> Option<&PathSegment> {
+    match *ty {
+        Type::Path(TypePath { qself: None, ref path }) => {
+            path.segments.last().map(|pair| pair.into_value())
+        }
+        _ => None,
+    }
+}
+
+/// If `ty` is the generic type named `name` applied to a single type
+/// argument, like `Option<T>`, returns that type argument.
+pub fn generic_argument_of<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = last_segment(ty)?;
+
+    if segment.ident != name {
+        return None;
+    }
+
+    let argument = match segment.arguments {
+        PathArguments::AngleBracketed(ref arguments) if arguments.args.len() == 1 => {
+            arguments.args.first()?.into_value()
+        }
+        _ => return None,
+    };
+
+    match *argument {
+        GenericArgument::Type(ref inner) => Some(inner),
+        _ => None,
+    }
+}
+
+/// Returns the source code of the type, without the spaces between tokens.
+fn type_str(ty: &Type) -> String {
+    ty.into_token_stream().to_string().replace(' ', "")
+}
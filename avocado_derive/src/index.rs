@@ -5,9 +5,10 @@ use proc_macro2::TokenStream;
 use syn::Attribute;
 use quote::{ ToTokens, TokenStreamExt };
 use crate::{
-    error::{ Error, Result, err_msg },
+    error::{ Error, Result, ResultExt, err_msg },
     attr::*,
     meta::*,
+    case::RenameRule,
 };
 
 /// Describes the parts of an index that can be derived using attributes.
@@ -19,6 +20,8 @@ pub struct Spec {
     unique: Option<bool>,
     /// Whether this is a sparse index.
     sparse: Option<bool>,
+    /// The number of seconds after which documents expire, for TTL indexes.
+    expire_after_seconds: Option<i32>,
     /// The name of the default language for a text index.
     default_language: Option<String>,
     /// The name of the field specifying the language of the document.
@@ -32,6 +35,8 @@ pub struct Spec {
     min: Option<f64>,
     /// Cluster size in units of distance, for geoHaystack. Must be positive.
     bucket_size: Option<i32>,
+    /// The relative weights of the fields of a text index.
+    weights: Vec<(String, i32)>,
     /// The actual indexed field names and their type.
     keys: Vec<(String, Type)>,
 }
@@ -114,6 +119,11 @@ impl Spec {
                     "bits" => spec.bits = value_as_i32(&path_str,
                                                        &lit,
                                                        1..=32)?.into(),
+                    "expire_after_seconds" | "expire_after_secs" => spec.expire_after_seconds = value_as_i32(
+                        &path_str,
+                        &lit,
+                        0..
+                    )?.into(),
                     "bucket_size" => spec.bucket_size = value_as_i32(
                         &path_str,
                         &lit,
@@ -137,6 +147,9 @@ impl Spec {
                     "keys" => {
                         spec.keys = list_into_names_and_values(&path_str, list)?
                     }
+                    "weights" => {
+                        spec.weights = list_into_weights(&path_str, list)?
+                    }
                     _ => err_fmt!("bad list attribute: {}", path_str)?
                 }
             }
@@ -144,11 +157,71 @@ impl Spec {
 
         if spec.keys.is_empty() {
             err_msg("at least one field must be specified for indexing")
+        } else if
+            !spec.weights.is_empty()
+            &&
+            !spec.keys.iter().any(|&(_, ty)| ty == Type::Text)
+        {
+            err_msg("`weights(...)` can only be specified for text indexes")
+        } else if
+            spec.expire_after_seconds.is_some()
+            &&
+            (spec.keys.len() != 1 || !spec.keys[0].1.is_ordered())
+        {
+            err_msg("TTL indexes must have a single ascending or descending field")
         } else {
             Ok(Some(spec))
         }
     }
 
+    /// Creates the spec of a single-field geospatial index, as declared by
+    /// `#[avocado(geo_index)]` (`2dsphere`) or `#[avocado(geo_index = "2d")]`
+    /// on a field.
+    pub fn geo(field: String, kind: &str) -> Result<Self> {
+        let ty = match kind {
+            "2dsphere" => Type::Geo2DSphere,
+            "2d" => Type::Geo2D,
+            _ => err_fmt!("geospatial index type must be \"2dsphere\" or \"2d\", not {:?}", kind)?
+        };
+
+        Ok(Spec {
+            keys: vec![(field, ty)],
+            ..Spec::default()
+        })
+    }
+
+    /// Creates the spec of the text index declared by `#[avocado(text_index)]`
+    /// or `#[avocado(text_index = weight)]` on each of `fields`. Fields
+    /// without an explicit weight have the default weight, 1.
+    pub fn text(fields: Vec<(String, Option<i32>)>) -> Self {
+        let weights = fields
+            .iter()
+            .filter_map(|&(ref field, weight)| weight.map(|value| (field.clone(), value)))
+            .collect();
+        let keys = fields
+            .into_iter()
+            .map(|(field, _)| (field, Type::Text))
+            .collect();
+
+        Spec { keys, weights, ..Spec::default() }
+    }
+
+    /// Returns `true` if any of the indexed fields is a text field.
+    pub fn is_text(&self) -> bool {
+        self.keys.iter().any(|&(_, ty)| ty == Type::Text)
+    }
+
+    /// Renames the indexed fields and the weighted fields according to
+    /// the `rename_all` rule of the type.
+    pub fn rename_fields(&mut self, rule: RenameRule) {
+        for &mut (ref mut field, _) in &mut self.keys {
+            *field = rule.apply_to_path(field);
+        }
+        for &mut (ref mut field, _) in &mut self.weights {
+            *field = rule.apply_to_path(field);
+        }
+    }
+
     /// Attempts to create an array of `Spec`s from several attributes.
     ///
     /// The implementation could have been simpler:
@@ -165,7 +238,7 @@ impl Spec {
         attrs
             .into_iter()
             .filter_map(|attr| {
-                match Spec::from_attribute(attr) {
+                match Spec::from_attribute(attr).at(attr) {
                     Ok(Some(spec)) => Some(Ok(spec)),
                     Ok(None) => None,
                     Err(error) => Some(Err(error)),
@@ -188,12 +261,27 @@ impl ToTokens for Spec {
         let language_override = self.language_override.as_ref().map(
             |s| quote!(language_override: Some(String::from(#s)),)
         );
+        let expire_after_seconds = self.expire_after_seconds.as_ref().map(
+            |n| quote!(expire_after_seconds: Some(#n),)
+        );
         let bucket_size = self.bucket_size.as_ref().map(
             |n| quote!(bucket_size: Some(#n),)
         );
         let bits = self.bits.as_ref().map(|n| quote!(bits: Some(#n),));
         let min = self.min.as_ref().map(|x| quote!(min: Some(#x),));
         let max = self.max.as_ref().map(|x| quote!(max: Some(#x),));
+        let weights = if self.weights.is_empty() {
+            None
+        } else {
+            let fields = self.weights.iter().map(|&(ref field, _)| field);
+            let values = self.weights.iter().map(|&(_, weight)| weight);
+
+            Some(quote!(weights: Some({
+                let mut avocado_weights = ::avocado::prelude::Document::new();
+                #(avocado_weights.insert(#fields, #values);)*
+                avocado_weights
+            }),))
+        };
         let fields = self.keys.iter().map(|&(ref field, _)| field);
         let types  = self.keys.iter().map(|&(_, ty)| ty);
 
@@ -208,12 +296,14 @@ impl ToTokens for Spec {
                     #name
                     #unique
                     #sparse
+                    #expire_after_seconds
                     #min
                     #max
                     #bits
                     #bucket_size
                     #default_language
                     #language_override
+                    #weights
                     ..Default::default()
                 },
             }
@@ -221,8 +311,28 @@ impl ToTokens for Spec {
     }
 }
 
+/// Parses the `weights(field = 10, ...)` list of a text index. Weights
+/// must be integers in the range `[1, 99999]`, as required by MongoDB.
+fn list_into_weights<I>(outer_name: &str, list: I) -> Result<Vec<(String, i32)>>
+    where I: IntoIterator<Item = NestedExtMeta>
+{
+    list.into_iter()
+        .map(|nested| match nested {
+            NestedExtMeta::Meta(ExtMeta::KeyValue(path, _, literal)) => {
+                let weight = value_as_i32(&path.colon_sep_str(), &literal, 1..=99_999)?;
+                Ok((path.dot_sep_str(), weight))
+            }
+            _ => err_fmt!(
+                "attribute `{}` must contain key-value pairs only, not {:#?}",
+                outer_name,
+                nested
+            )
+        })
+        .collect()
+}
+
 /// An index type, applied to a single indexed field.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Type {
     /// An ordered, ascending index field.
     Ascending,
@@ -240,6 +350,13 @@ enum Type {
     GeoHaystack,
 }
 
+impl Type {
+    /// Returns `true` for ascending and descending index fields.
+    fn is_ordered(self) -> bool {
+        self == Type::Ascending || self == Type::Descending
+    }
+}
+
 impl FromStr for Type {
     type Err = Error;
 
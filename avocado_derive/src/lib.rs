@@ -1,4 +1,5 @@
-//! This crate only contains the `#[derive(Doc)]` proc-macro for Avocado.
+//! This crate contains the `#[derive(Doc)]` and `#[derive(Subdoc)]`
+//! proc-macros and the `checked_flt!` macro for Avocado.
 //! For documentation, please see the main [`avocado`][1] crate.
 //!
 //! [1]: https://docs.rs/avocado
@@ -44,48 +45,119 @@ mod attr;
 mod case;
 mod index;
 mod option;
+mod collation;
+mod capped;
+mod shard;
+mod id;
+mod subdoc;
+mod variant;
+mod filter;
+#[cfg(feature = "testing")]
+mod factory;
 
 use proc_macro::TokenStream;
-use proc_macro2::Span;
+use proc_macro2::{ Span, TokenStream as TokenStream2 };
 use syn::{
     DeriveInput, Data, Generics, Fields, Ident,
-    Type, Attribute, TypePath, Path, PathSegment,
+    Type, Attribute, TypePath, Path, PathSegment, Visibility, WherePredicate,
 };
 use self::{
     meta::*,
-    case::RenameRule,
+    case::{ RenameRule, CollectionNaming },
     index::Spec,
     option::DocOptions,
-    error::{ Error, Result, err_msg },
+    collation::Collation,
+    capped::Capped,
+    shard::ShardKey,
+    variant::TaggedEnum,
+    error::{ Result, ResultExt, err_msg },
 };
 
 /// The top-level entry point of this proc-macro. Only here to be exported
-/// and to handle `Result::Err` return values by `panic!()`ing.
-#[proc_macro_derive(Doc, attributes(avocado, index, id_type, options))]
+/// and to turn `Result::Err` return values into spanned compile errors.
+#[proc_macro_derive(Doc, attributes(
+    avocado, index, id_type, id_generator, options, doc_collation, capped, shard_key,
+    collection_name, collection_naming
+))]
 pub fn derive_avocado_doc(input: TokenStream) -> TokenStream {
-    impl_avocado_doc(input).unwrap_or_else(|error| panic!("{}", error))
+    impl_avocado_doc(input).unwrap_or_else(|error| error.to_compile_error().into())
 }
 
 /// Implements `Doc` for the specified type.
 fn impl_avocado_doc(input: TokenStream) -> Result<TokenStream> {
     let parsed_ast: DeriveInput = syn::parse(input)?;
+    let vis = parsed_ast.vis;
     let ty = parsed_ast.ident;
     let generics = parsed_ast.generics;
-    let ty_name = serde_renamed_ident(&parsed_ast.attrs, ty.to_string())?;
-    let (impl_gen, ty_gen, where_cls) = generics.split_for_impl();
-    let id_ty = raw_id_type(&parsed_ast.attrs)?;
-    let indexes = Spec::from_attributes(&parsed_ast.attrs)?;
-    let options = DocOptions::from_attributes(&parsed_ast.attrs)?;
+    let ty_name = collection_name(&parsed_ast.attrs, &ty)?;
+    let name_param = collection_name_param(&parsed_ast.attrs, &generics)?;
+    let bounded = doc_generics(&ty, &generics, name_param.as_ref());
+    let (impl_gen, ty_gen, where_cls) = bounded.split_for_impl();
+    let name = match name_param {
+        Some(ref param) => quote!(<#param as ::avocado::doc::CollectionName>::COLLECTION_NAME),
+        None => quote!(#ty_name),
+    };
+    let id_ty = raw_id_type(&parsed_ast.attrs).at_attr(&parsed_ast.attrs, "id_type")?;
+    let id_generator = impl_id_generator(&parsed_ast.attrs)?;
+    let hooks = impl_hooks(&parsed_ast.attrs)?;
+    let audit_collection = impl_audit_collection(&parsed_ast.attrs, &ty_name)?;
+    let check_field_names = impl_check_field_names(&parsed_ast.attrs)?;
+    let serde_profile = impl_serde_profile(&parsed_ast.attrs)?;
+    let mut indexes = Spec::from_attributes(&parsed_ast.attrs)?;
+    let options = DocOptions::from_attributes(&parsed_ast.attrs)
+        .at_attr(&parsed_ast.attrs, "options")?;
+    let collation = Collation::from_attributes(&parsed_ast.attrs)
+        .at_attr(&parsed_ast.attrs, "doc_collation")?;
+    let capped = Capped::from_attributes(&parsed_ast.attrs)
+        .at_attr(&parsed_ast.attrs, "capped")?;
+    let mut shard_key = ShardKey::from_attributes(&parsed_ast.attrs)
+        .at_attr(&parsed_ast.attrs, "shard_key")?;
+    // The `rename_all` attribute of an enum applies to its variants, not
+    // to the fields, so the field naming of an enum isn't known.
+    let field_naming = match parsed_ast.data {
+        Data::Enum(_) => TokenStream2::new(),
+        _ => impl_field_naming(&parsed_ast.attrs, &mut indexes, shard_key.as_mut())?,
+    };
     let index_count = indexes.len();
 
-    ensure_only_lifetime_params(&generics)?;
+    ensure_no_const_params(&generics)?;
 
     match parsed_ast.data {
         Data::Struct(s) => {
-            let id_name = name_of_id_field(s.fields, &parsed_ast.attrs)?;
+            if let Some(param) = generics.type_params().next() {
+                if has_avocado_word(&parsed_ast.attrs, "factory")? {
+                    return err_msg("`#[avocado(factory)]` can't be used on a type with type parameters")
+                        .at(param);
+                }
+            }
+
+            let factory = impl_factory(&vis, &ty, &generics, &s.fields, &parsed_ast.attrs)?;
+            let fields = serialized_fields(s.fields, &parsed_ast.attrs).at(&ty)?;
+            let id_name = name_of_id_field(&fields).at(&ty)?;
+
+            id::check_id_field(&ty, &fields, &id_ty)?;
+
+            if generics.type_params().next().is_some() {
+                if let Some(field) = fields.iter().find(|field| field.subdoc) {
+                    return err_msg("`#[avocado(subdoc)]` fields can't be used in a type with type parameters")
+                        .at(&field.ident);
+                }
+            }
+
+            let strict_fields = impl_strict_fields(&fields, &parsed_ast.attrs)?;
+            let defaulted_fields = impl_defaulted_fields(&fields);
+            let version = impl_version(&fields)?;
+            let created_at = impl_timestamp_field(&fields, "created_at", |field| field.created_at)?;
+            let updated_at = impl_timestamp_field(&fields, "updated_at", |field| field.updated_at)?;
+            let deleted_at = impl_timestamp_field(&fields, "deleted_at", |field| field.deleted_at)?;
+            let encrypted_fields = impl_encrypted_fields(&fields)?;
+            let field_indexes = impl_field_indexes(&fields, &indexes)?;
+            let field_index_count = field_indexes.len();
+            let registration = register_metadata(&ty, &generics, &ty_name, &id_ty, &fields);
+            let field_paths = subdoc::impl_fields(&vis, &ty, &generics, &fields);
             let ast = quote! {
                 impl #impl_gen ::avocado::doc::Doc for #ty #ty_gen #where_cls {
-                    const NAME: &'static str = #ty_name;
+                    const NAME: &'static str = #name;
 
                     type Id = #id_ty;
 
@@ -97,24 +169,294 @@ fn impl_avocado_doc(input: TokenStream) -> Result<TokenStream> {
                         self.#id_name = ::std::convert::From::from(id);
                     }
 
+                    fn indexes() -> ::std::vec::Vec<::avocado::prelude::IndexModel> {
+                        let mut index_vector = ::std::vec::Vec::with_capacity(
+                            #index_count + #field_index_count
+                        );
+                        #(index_vector.push(#indexes);)*
+                        #(index_vector.push(#field_indexes);)*
+                        index_vector
+                    }
+
+                    #id_generator
+
+                    #hooks
+
+                    #strict_fields
+
+                    #defaulted_fields
+
+                    #version
+
+                    #created_at
+
+                    #updated_at
+
+                    #deleted_at
+
+                    #encrypted_fields
+
+                    #audit_collection
+
+                    #check_field_names
+
+                    #serde_profile
+
+                    #collation
+
+                    #capped
+
+                    #shard_key
+
+                    #field_naming
+
+                    #options
+                }
+
+                #registration
+
+                #field_paths
+
+                #factory
+            };
+            Ok(ast.into())
+        },
+        Data::Enum(e) => {
+            if has_avocado_word(&parsed_ast.attrs, "factory")? {
+                return err_msg("`#[avocado(factory)]` can't be used on an enum").at(&ty);
+            }
+
+            let tagged = TaggedEnum::from_data(e, &parsed_ast.attrs).at(&ty)?;
+            tagged.check_id_fields(&ty, &id_ty)?;
+            let methods = tagged.impl_methods(&ty);
+            let strict_fields = tagged.impl_strict_fields(&parsed_ast.attrs)?;
+            let subtypes = tagged.impl_subtypes(&ty, &generics)?;
+            let registration = register_metadata(&ty, &generics, &ty_name, &id_ty, &tagged.fields());
+            let ast = quote! {
+                impl #impl_gen ::avocado::doc::Doc for #ty #ty_gen #where_cls {
+                    const NAME: &'static str = #name;
+
+                    type Id = #id_ty;
+
+                    #methods
+
                     fn indexes() -> ::std::vec::Vec<::avocado::prelude::IndexModel> {
                         let mut index_vector = ::std::vec::Vec::with_capacity(#index_count);
                         #(index_vector.push(#indexes);)*
                         index_vector
                     }
 
+                    #id_generator
+
+                    #hooks
+
+                    #strict_fields
+
+                    #audit_collection
+
+                    #check_field_names
+
+                    #serde_profile
+
+                    #collation
+
+                    #capped
+
+                    #shard_key
+
                     #options
                 }
+
+                #registration
+
+                #subtypes
             };
             Ok(ast.into())
         },
         _ => err_msg(
-            "only a `struct` can be a top-level `Doc`; consider wrapping this type in a struct"
-        ),
+            "only a `struct` or an internally tagged `enum` can be a top-level `Doc`; \
+             consider wrapping this type in a struct"
+        ).at(&ty),
     }
 }
 
-/// Returns the collection name based on the the type name,
+/// The entry point of `#[derive(Subdoc)]`.
+#[proc_macro_derive(Subdoc, attributes(avocado))]
+pub fn derive_avocado_subdoc(input: TokenStream) -> TokenStream {
+    impl_avocado_subdoc(input).unwrap_or_else(|error| error.to_compile_error().into())
+}
+
+/// Implements `Subdoc` for the specified type.
+fn impl_avocado_subdoc(input: TokenStream) -> Result<TokenStream> {
+    let parsed_ast: DeriveInput = syn::parse(input)?;
+
+    match parsed_ast.data {
+        Data::Struct(s) => {
+            let fields = serialized_fields(s.fields, &parsed_ast.attrs).at(&parsed_ast.ident)?;
+            let ast = subdoc::impl_fields(
+                &parsed_ast.vis,
+                &parsed_ast.ident,
+                &parsed_ast.generics,
+                &fields,
+            );
+            Ok(ast.into())
+        },
+        _ => err_msg("only a `struct` can be a `Subdoc`").at(&parsed_ast.ident),
+    }
+}
+
+/// The entry point of `checked_flt!`, building a filter document whose field
+/// paths and literals are checked against the fields of a `Subdoc` type.
+#[proc_macro]
+pub fn checked_flt(input: TokenStream) -> TokenStream {
+    let expanded = filter::impl_checked_flt(input.into()).unwrap_or_else(|error| {
+        let compile_error = error.to_compile_error();
+        quote!({ #compile_error })
+    });
+
+    expanded.into()
+}
+
+/// If the type is annotated with `#[id_generator = "path::to::fn"]`,
+/// implements `Doc::generate_id()` by calling that function.
+fn impl_id_generator(attrs: &[Attribute]) -> Result<TokenStream2> {
+    let generator: Path = match literal_value_for_name(attrs, "id_generator")? {
+        Some(path) => path,
+        None => return Ok(TokenStream2::new()),
+    };
+
+    Ok(quote! {
+        fn generate_id() -> ::std::option::Option<Self::Id> {
+            ::std::option::Option::Some(#generator())
+        }
+    })
+}
+
+/// Implements the lifecycle hooks of `Doc` named by the type-level
+/// `#[avocado(before_insert = "path", before_update = "path",
+/// before_delete = "path", after_load = "path")]` attributes, by calling
+/// the functions at the given paths.
+fn impl_hooks(attrs: &[Attribute]) -> Result<TokenStream2> {
+    let hook_path = |key: &str| -> Result<Option<Path>> {
+        match avocado_name_value(attrs, key)? {
+            Some(nv) => syn::parse_str(&value_as_str(&nv)?).map(Some).map_err(Into::into).at(&nv.lit),
+            None => Ok(None),
+        }
+    };
+    let before_insert = hook_path("before_insert")?.map(|path| quote! {
+        fn before_insert(
+            &self,
+            doc: &mut ::avocado::prelude::Document,
+        ) -> ::avocado::error::Result<()> {
+            #path(self, doc)
+        }
+    });
+    let before_update = hook_path("before_update")?.map(|path| quote! {
+        fn before_update(
+            update: &mut ::avocado::prelude::Document,
+        ) -> ::avocado::error::Result<()> {
+            #path(update)
+        }
+    });
+    let before_delete = hook_path("before_delete")?.map(|path| quote! {
+        fn before_delete(
+            filter: &::avocado::prelude::Document,
+        ) -> ::avocado::error::Result<()> {
+            #path(filter)
+        }
+    });
+    let after_load = hook_path("after_load")?.map(|path| quote! {
+        fn after_load(
+            doc: &mut ::avocado::prelude::Document,
+        ) -> ::avocado::error::Result<()> {
+            #path(doc)
+        }
+    });
+
+    Ok(quote! {
+        #before_insert
+        #before_update
+        #before_delete
+        #after_load
+    })
+}
+
+/// Implements `Doc::audit_collection()` if the type is `#[avocado(audited)]`.
+fn impl_audit_collection(attrs: &[Attribute], ty_name: &str) -> Result<TokenStream2> {
+    let name = match avocado_word_or_value(attrs, "audited")? {
+        Some(Some(name)) => name,
+        Some(None) => format!("{}_audit", ty_name),
+        None => return Ok(quote!{}),
+    };
+
+    if name.is_empty() {
+        return err_msg("the name of the audit collection must not be empty");
+    }
+
+    Ok(quote! {
+        fn audit_collection() -> ::std::option::Option<&'static str> {
+            ::std::option::Option::Some(#name)
+        }
+    })
+}
+
+/// Implements `Doc::check_field_names()` if the type is
+/// `#[avocado(check_field_names)]`.
+fn impl_check_field_names(attrs: &[Attribute]) -> Result<TokenStream2> {
+    if !has_avocado_word(attrs, "check_field_names")? {
+        return Ok(TokenStream2::new());
+    }
+
+    Ok(quote! {
+        fn check_field_names() -> bool {
+            true
+        }
+    })
+}
+
+/// Implements `Doc::SERDE_PROFILE` as the constant at the path given by
+/// `#[avocado(serde_profile = "path::to::CONST")]`.
+fn impl_serde_profile(attrs: &[Attribute]) -> Result<TokenStream2> {
+    let nv = match avocado_name_value(attrs, "serde_profile")? {
+        Some(nv) => nv,
+        None => return Ok(TokenStream2::new()),
+    };
+    let path: Path = syn::parse_str(&value_as_str(&nv)?).map_err(Into::into).at(&nv.lit)?;
+
+    Ok(quote! {
+        const SERDE_PROFILE: ::avocado::profile::Profile = #path;
+    })
+}
+
+/// Returns the collection name: either the one given by
+/// `#[collection_name = "..."]`, or the type name converted according to
+/// `#[collection_naming = "..."]`, or the type name, taking Serde renaming
+/// into account.
+fn collection_name(attrs: &[Attribute], ty: &Ident) -> Result<String> {
+    let explicit = str_value_for_name(attrs, "collection_name")?;
+    let naming: Option<CollectionNaming> = match str_value_for_name(attrs, "collection_naming")? {
+        Some(strategy) => Some(strategy.parse().at_attr(attrs, "collection_naming")?),
+        None => None,
+    };
+
+    match (explicit, naming) {
+        (Some(_), Some(_)) => {
+            err_msg("`#[collection_name]` and `#[collection_naming]` can't be used together")
+                .at_attr(attrs, "collection_naming")
+        }
+        (Some(name), None) => {
+            if name.is_empty() || name.contains('$') || name.contains('\0') {
+                err_fmt!("invalid collection name: {:?}", name).at_attr(attrs, "collection_name")
+            } else {
+                Ok(name)
+            }
+        }
+        (None, Some(strategy)) => Ok(strategy.apply(&ty.to_string())),
+        (None, None) => serde_renamed_ident(attrs, ty.to_string()),
+    }
+}
+
+/// Returns the name of a field or a variant based on its identifier,
 /// taking Serde renaming into account as well.
 fn serde_renamed_ident(attrs: &[Attribute], ident: String) -> Result<String> {
     serde_name_value(attrs, "rename")?
@@ -156,9 +498,44 @@ fn raw_id_type(attrs: &[Attribute]) -> Result<Type> {
         }))
 }
 
-/// Returns an error if there is no field serializing as `_id` or if there
-/// are more than 1 of them. (The `_id` field must be unambiguous and unique.)
-fn name_of_id_field(fields: Fields, attrs: &[Attribute]) -> Result<Ident> {
+/// A field of the struct, as it appears in the serialized document.
+#[derive(Debug, Clone)]
+struct SerializedField {
+    /// The original identifier of the field in the struct.
+    ident: Ident,
+    /// The key of the field in the serialized document.
+    name: String,
+    /// The type of the field.
+    ty: Type,
+    /// Whether the field is annotated with `#[serde(flatten)]`.
+    flattened: bool,
+    /// Whether the field is annotated with `#[avocado(version)]`.
+    versioned: bool,
+    /// Whether the field is annotated with `#[avocado(created_at)]`.
+    created_at: bool,
+    /// Whether the field is annotated with `#[avocado(updated_at)]`.
+    updated_at: bool,
+    /// Whether the field is annotated with `#[avocado(deleted_at)]`.
+    deleted_at: bool,
+    /// Whether the field is annotated with `#[avocado(subdoc)]`.
+    subdoc: bool,
+    /// Whether the field has a default value when missing from the
+    /// document, by `#[serde(default)]` on either the field or the type.
+    defaulted: bool,
+    /// The mode given by `#[avocado(encrypted = "...")]`, or `None` if the
+    /// field isn't encrypted. A bare `#[avocado(encrypted)]` is randomized.
+    encrypted: Option<String>,
+    /// The index type given by `#[avocado(geo_index = "...")]`, or `None`
+    /// if the field isn't indexed. A bare `#[avocado(geo_index)]` is `2dsphere`.
+    geo_index: Option<String>,
+    /// `Some` if the field is annotated with `#[avocado(text_index)]`, with
+    /// the weight given by `#[avocado(text_index = weight)]`, if any.
+    text_index: Option<Option<i32>>,
+}
+
+/// Returns the fields which are serialized or deserialized, along with their
+/// names after applying Serde's renaming rules.
+fn serialized_fields(fields: Fields, attrs: &[Attribute]) -> Result<Vec<SerializedField>> {
     let named = match fields {
         Fields::Named(fields) => fields.named,
         _ => return err_msg("a `Doc` must be a struct with named fields"),
@@ -166,9 +543,10 @@ fn name_of_id_field(fields: Fields, attrs: &[Attribute]) -> Result<Ident> {
     let rename_attr = serde_name_value(attrs, "rename_all")?;
     let rename_rule: Option<RenameRule> = match rename_attr {
         None => None,
-        Some(kv) => Some(value_as_str(&kv)?.parse()?)
+        Some(kv) => Some(value_as_str(&kv)?.parse().at(&kv.lit)?)
     };
-    let mut id_name = None;
+    let all_defaulted = has_serde_word_or_value(attrs, "default")?;
+    let mut serialized = Vec::with_capacity(named.len());
 
     for field in named {
         // The field isn't inspected if it's never serialized or deserialized.
@@ -192,36 +570,401 @@ fn name_of_id_field(fields: Fields, attrs: &[Attribute]) -> Result<Ident> {
         // The final field name is the exact name specified in the immediate
         // `#[serde(rename = "...")]` attribute applied directly to the field,
         // or the potentially-`rename_all`'d name, if the former doesn't exist.
-        let field_name = serde_renamed_ident(&field.attrs, rename_all_ident)?;
+        let name = serde_renamed_ident(&field.attrs, rename_all_ident)?;
+        let flattened = has_serde_word(&field.attrs, "flatten")?;
+        let versioned = has_avocado_word(&field.attrs, "version")?;
+        let created_at = has_avocado_word(&field.attrs, "created_at")?;
+        let updated_at = has_avocado_word(&field.attrs, "updated_at")?;
+        let deleted_at = has_avocado_word(&field.attrs, "deleted_at")?;
+        let subdoc = has_avocado_word(&field.attrs, "subdoc")?;
+        let defaulted = all_defaulted || has_serde_word_or_value(&field.attrs, "default")?;
+        let encrypted = avocado_word_or_value(&field.attrs, "encrypted")?.map(
+            |mode| mode.unwrap_or_else(|| String::from("randomized"))
+        );
+        let geo_index = avocado_word_or_value(&field.attrs, "geo_index")?.map(
+            |kind| kind.unwrap_or_else(|| String::from("2dsphere"))
+        );
+        let text_index = avocado_word_or_int(&field.attrs, "text_index", 1..=99_999)?;
+        let ty = field.ty;
 
-        if field_name == "_id" {
-            if id_name.is_some() {
-                return err_msg("more than one fields serialize as `_id`");
-            } else {
-                id_name = Some(ident);
+        serialized.push(SerializedField {
+            ident, name, ty, flattened, versioned, created_at, updated_at, deleted_at, subdoc,
+            defaulted, encrypted, geo_index, text_index,
+        });
+    }
+
+    Ok(serialized)
+}
+
+/// Returns an error if there is no field serializing as `_id` or if there
+/// are more than 1 of them. (The `_id` field must be unambiguous and unique.)
+fn name_of_id_field(fields: &[SerializedField]) -> Result<Ident> {
+    let mut id_fields = fields.iter().filter(|field| field.name == "_id");
+
+    match (id_fields.next(), id_fields.next()) {
+        (Some(field), None) => Ok(field.ident.clone()),
+        (Some(_), Some(field)) => err_msg("more than one fields serialize as `_id`").at(&field.ident),
+        (None, _) => err_msg("a `Doc` must contain a field serialized as `_id`"),
+    }
+}
+
+/// If the type is annotated with `#[avocado(strict)]`, implements the
+/// `Doc::strict_fields()` method, returning the names of all serialized
+/// fields. Otherwise, the default (lenient) implementation is used.
+fn impl_strict_fields(fields: &[SerializedField], attrs: &[Attribute]) -> Result<TokenStream2> {
+    if !has_avocado_word(attrs, "strict")? {
+        return Ok(TokenStream2::new());
+    }
+
+    if let Some(field) = fields.iter().find(|field| field.flattened) {
+        return err_msg("`#[avocado(strict)]` can't be used with `#[serde(flatten)]` fields")
+            .at(&field.ident);
+    }
+
+    let names = fields.iter().map(|field| &field.name);
+
+    Ok(quote! {
+        fn strict_fields() -> ::std::option::Option<&'static [&'static str]> {
+            ::std::option::Option::Some(&[#(#names),*])
+        }
+    })
+}
+
+/// Implements `Doc::defaulted_fields()`, returning the names of the fields
+/// which deserialize to a default value when missing, unless there are none.
+/// The keys of a `#[serde(flatten)]` field aren't known, so it's never listed.
+fn impl_defaulted_fields(fields: &[SerializedField]) -> TokenStream2 {
+    let names: Vec<_> = fields
+        .iter()
+        .filter(|field| field.defaulted && !field.flattened)
+        .map(|field| &field.name)
+        .collect();
+
+    if names.is_empty() {
+        return TokenStream2::new();
+    }
+
+    quote! {
+        fn defaulted_fields() -> &'static [&'static str] {
+            &[#(#names),*]
+        }
+    }
+}
+
+/// Implements `Doc::encrypted_fields()` based on the fields annotated with
+/// `#[avocado(encrypted)]` or `#[avocado(encrypted = "randomized")]`, and
+/// `#[avocado(encrypted = "deterministic")]`.
+fn impl_encrypted_fields(fields: &[SerializedField]) -> Result<TokenStream2> {
+    let mut encrypted = Vec::new();
+
+    for field in fields {
+        let mode = match field.encrypted {
+            Some(ref mode) => mode,
+            None => continue,
+        };
+        let variant = match mode.as_str() {
+            "randomized" => quote!(Randomized),
+            "deterministic" => quote!(Deterministic),
+            _ => return err_fmt!(
+                "encryption mode must be \"randomized\" or \"deterministic\", not {:?}", mode
+            ).at(&field.ident),
+        };
+
+        if field.flattened {
+            return err_msg("a `#[serde(flatten)]` field can't be `#[avocado(encrypted)]`")
+                .at(&field.ident);
+        }
+        if field.name == "_id" {
+            return err_msg("the `_id` field can't be `#[avocado(encrypted)]`").at(&field.ident);
+        }
+
+        let name = &field.name;
+
+        encrypted.push(quote! {
+            ::avocado::encrypt::EncryptedField {
+                name: #name,
+                mode: ::avocado::encrypt::EncryptionMode::#variant,
             }
+        });
+    }
+
+    if encrypted.is_empty() {
+        return Ok(TokenStream2::new());
+    }
+
+    Ok(quote! {
+        fn encrypted_fields() -> &'static [::avocado::encrypt::EncryptedField] {
+            &[#(#encrypted),*]
+        }
+    })
+}
+
+/// Returns the indexes declared on fields: a single-field geospatial index
+/// for each field annotated with `#[avocado(geo_index)]` or
+/// `#[avocado(geo_index = "2d")]`, and one text index of all the fields
+/// annotated with `#[avocado(text_index)]`. A collection can only have a
+/// single text index, so the `indexes` declared on the type can't have one.
+fn impl_field_indexes(fields: &[SerializedField], indexes: &[Spec]) -> Result<Vec<Spec>> {
+    let mut specs = Vec::new();
+    let mut text_fields = Vec::new();
+
+    for field in fields {
+        if field.flattened && (field.geo_index.is_some() || field.text_index.is_some()) {
+            return err_msg("a `#[serde(flatten)]` field can't be indexed").at(&field.ident);
+        }
+        if let Some(ref kind) = field.geo_index {
+            specs.push(Spec::geo(field.name.clone(), kind).at(&field.ident)?);
+        }
+        if let Some(weight) = field.text_index {
+            if indexes.iter().any(Spec::is_text) {
+                return err_msg("`#[avocado(text_index)]` can't be used with a text `#[index]`")
+                    .at(&field.ident);
+            }
+
+            text_fields.push((field.name.clone(), weight));
         }
     }
 
-    id_name.ok_or_else(
-        || Error::new("a `Doc` must contain a field serialized as `_id`")
-    )
+    if !text_fields.is_empty() {
+        specs.push(Spec::text(text_fields));
+    }
+
+    Ok(specs)
 }
 
-/// Returns `Ok` if the generics only contain lifetime parameters.
-/// Returns `Err` if there are also type and/or const parameters.
-fn ensure_only_lifetime_params(generics: &Generics) -> Result<()> {
-    let make_error = |param_type| err_fmt!(
-        "`Doc` can't be derived for a type that is generic over {} parameters",
-        param_type
-    );
+/// If a field is annotated with `#[avocado(version)]`, implements
+/// `Doc::version_field()`, `Doc::version()` and `Doc::set_version()` based
+/// on it. The field must be an `i64`.
+fn impl_version(fields: &[SerializedField]) -> Result<TokenStream2> {
+    let mut versioned = fields.iter().filter(|field| field.versioned);
+
+    let field = match (versioned.next(), versioned.next()) {
+        (None, _) => return Ok(TokenStream2::new()),
+        (Some(field), None) => field,
+        (Some(_), Some(second)) => {
+            return err_msg("more than one field is `#[avocado(version)]`").at(&second.ident)
+        }
+    };
+
+    if field.flattened {
+        return err_msg("a `#[serde(flatten)]` field can't be `#[avocado(version)]`").at(&field.ident);
+    }
+
+    let ident = &field.ident;
+    let name = &field.name;
+
+    Ok(quote! {
+        fn version_field() -> ::std::option::Option<&'static str> {
+            ::std::option::Option::Some(#name)
+        }
+
+        fn version(&self) -> ::std::option::Option<i64> {
+            ::std::option::Option::Some(self.#ident)
+        }
+
+        fn set_version(&mut self, version: i64) {
+            self.#ident = version;
+        }
+    })
+}
+
+/// If a field is annotated with `#[avocado(created_at)]`,
+/// `#[avocado(updated_at)]` or `#[avocado(deleted_at)]`, as selected by
+/// `kind` and `is_marked`, implements the corresponding `Doc::*_field()`.
+fn impl_timestamp_field<F>(fields: &[SerializedField], kind: &str, is_marked: F) -> Result<TokenStream2>
+    where F: Fn(&SerializedField) -> bool
+{
+    let mut marked = fields.iter().filter(|field| is_marked(field));
+
+    let field = match (marked.next(), marked.next()) {
+        (None, _) => return Ok(TokenStream2::new()),
+        (Some(field), None) => field,
+        (Some(_), Some(second)) => {
+            return err_fmt!("more than one field is `#[avocado({})]`", kind).at(&second.ident)
+        }
+    };
+
+    if field.flattened {
+        return err_fmt!("a `#[serde(flatten)]` field can't be `#[avocado({})]`", kind).at(&field.ident);
+    }
+
+    let method = Ident::new(&format!("{}_field", kind), Span::call_site());
+    let name = &field.name;
+
+    Ok(quote! {
+        fn #method() -> ::std::option::Option<&'static str> {
+            ::std::option::Option::Some(#name)
+        }
+    })
+}
+
+/// If the type is annotated with `#[serde(rename_all = "camelCase")]`,
+/// implements `Doc::field_naming()` accordingly, and renames the fields of
+/// the indexes and of the shard key, which are written using the names of
+/// the Rust fields.
+fn impl_field_naming(
+    attrs: &[Attribute],
+    indexes: &mut [Spec],
+    shard_key: Option<&mut ShardKey>,
+) -> Result<TokenStream2> {
+    let rule: RenameRule = match serde_name_value(attrs, "rename_all")? {
+        Some(kv) => value_as_str(&kv)?.parse().at(&kv.lit)?,
+        None => return Ok(TokenStream2::new()),
+    };
+
+    if rule != RenameRule::CamelCase {
+        return Ok(TokenStream2::new());
+    }
+
+    for spec in indexes {
+        spec.rename_fields(rule);
+    }
+    if let Some(key) = shard_key {
+        key.rename_fields(rule);
+    }
+
+    Ok(quote! {
+        fn field_naming() -> ::avocado::doc::FieldNaming {
+            ::avocado::doc::FieldNaming::CamelCase
+        }
+    })
+}
+
+/// If the type is annotated with `#[avocado(factory)]`, generates a
+/// `{Type}Factory` builder of fake values. Requires the `testing` feature.
+#[cfg(feature = "testing")]
+fn impl_factory(
+    vis: &Visibility,
+    ty: &Ident,
+    generics: &Generics,
+    fields: &Fields,
+    attrs: &[Attribute],
+) -> Result<TokenStream2> {
+    if has_avocado_word(attrs, "factory")? {
+        factory::impl_factory(vis, ty, generics, fields)
+    } else {
+        Ok(TokenStream2::new())
+    }
+}
+
+/// Without the `testing` feature, `#[avocado(factory)]` is an error.
+#[cfg(not(feature = "testing"))]
+fn impl_factory(
+    _vis: &Visibility,
+    _ty: &Ident,
+    _generics: &Generics,
+    _fields: &Fields,
+    attrs: &[Attribute],
+) -> Result<TokenStream2> {
+    if has_avocado_word(attrs, "factory")? {
+        err_msg("`#[avocado(factory)]` requires the `testing` feature of `avocado_derive`")
+    } else {
+        Ok(TokenStream2::new())
+    }
+}
+
+/// Submits the metadata of the type to the registry in `avocado::schema`.
+/// Lifetime parameters, if any, are instantiated with `'static`. Types with
+/// type parameters aren't registered, since they have no single instance.
+fn register_metadata(
+    ty: &Ident,
+    generics: &Generics,
+    ty_name: &str,
+    id_ty: &Type,
+    fields: &[SerializedField],
+) -> TokenStream2 {
+    if generics.type_params().next().is_some() {
+        return TokenStream2::new();
+    }
+
+    let type_name = ty.to_string();
+    let lifetimes = generics.lifetimes().map(|_| quote!('static));
+    let static_ty = if generics.lifetimes().next().is_some() {
+        quote!(#ty<#(#lifetimes),*>)
+    } else {
+        quote!(#ty)
+    };
+    let field_names = fields.iter().map(|field| &field.name);
+    let field_types = fields.iter().map(|field| &field.ty);
+
+    quote! {
+        ::avocado::inventory::submit! {
+            #![crate = avocado]
+            ::avocado::schema::DocMetadata {
+                type_name: #type_name,
+                name: #ty_name,
+                id_type: stringify!(#id_ty),
+                fields: &[
+                    #(
+                        ::avocado::schema::FieldMetadata {
+                            name: #field_names,
+                            ty: stringify!(#field_types),
+                        }
+                    ),*
+                ],
+                indexes: <#static_ty as ::avocado::doc::Doc>::indexes,
+            }
+        }
+    }
+}
+
+/// Returns `Err` if the generics contain const parameters.
+fn ensure_no_const_params(generics: &Generics) -> Result<()> {
+    match generics.const_params().next() {
+        Some(param) => {
+            err_msg("`Doc` can't be derived for a type that is generic over const parameters").at(param)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns the type parameter named by `#[avocado(name_from = "...")]`, if
+/// any, which determines the collection name through `CollectionName`.
+fn collection_name_param(attrs: &[Attribute], generics: &Generics) -> Result<Option<Ident>> {
+    let nv = match avocado_name_value(attrs, "name_from")? {
+        Some(nv) => nv,
+        None => return Ok(None),
+    };
+    let param = value_as_str(&nv)?;
+
+    if serde_name_value(attrs, "rename")?.is_some() {
+        return err_msg("`#[avocado(name_from)]` can't be used with `#[serde(rename)]`").at(&nv);
+    }
+    if
+        str_value_for_name(attrs, "collection_name")?.is_some()
+        ||
+        str_value_for_name(attrs, "collection_naming")?.is_some()
+    {
+        return err_msg(
+            "`#[avocado(name_from)]` can't be used with `#[collection_name]` or `#[collection_naming]`"
+        ).at(&nv);
+    }
+
+    match generics.type_params().find(|type_param| type_param.ident == param) {
+        Some(type_param) => Ok(Some(type_param.ident.clone())),
+        None => err_fmt!("`#[avocado(name_from = \"{}\")]` must name a type parameter", param).at(&nv.lit),
+    }
+}
+
+/// Returns the generics of the `Doc` impl. If the type has type parameters,
+/// these are bounded so that the supertraits of `Doc` are implemented, and
+/// so that the parameter naming the collection, if any, implements
+/// `CollectionName`.
+fn doc_generics(ty: &Ident, generics: &Generics, name_param: Option<&Ident>) -> Generics {
+    let mut bounded = generics.clone();
 
     if generics.type_params().next().is_some() {
-        return make_error("type");
+        let (_, ty_gen, _) = generics.split_for_impl();
+        let predicate: WherePredicate = parse_quote! {
+            #ty #ty_gen: ::avocado::serde::Serialize
+                + for<'avocado_de> ::avocado::serde::Deserialize<'avocado_de>
+        };
+        bounded.make_where_clause().predicates.push(predicate);
     }
-    if generics.const_params().next().is_some() {
-        return make_error("const");
+    if let Some(param) = name_param {
+        let predicate: WherePredicate = parse_quote!(#param: ::avocado::doc::CollectionName);
+        bounded.make_where_clause().predicates.push(predicate);
     }
 
-    Ok(())
+    bounded
 }
@@ -9,7 +9,7 @@ use syn::{ Attribute, Meta, MetaList, NestedMeta, MetaNameValue, Lit };
 use syn::synom::Synom;
 use crate::{
     attr::{ ExtMeta, NestedExtMeta, PathExt },
-    error::{ Error, Result },
+    error::{ Error, Result, ResultExt },
 };
 
 /// Utilities for working with ranges.
@@ -78,8 +78,8 @@ fn meta(attrs: &[Attribute], name: &str, key: &str) -> Option<Meta> {
 fn name_value(attrs: &[Attribute], name: &str, key: &str) -> Result<Option<MetaNameValue>> {
     match meta(attrs, name, key) {
         Some(Meta::NameValue(name_value)) => Ok(Some(name_value)),
-        Some(_) => {
-            err_fmt!("attribute must have form `#[{}({} = \"...\")]`", name, key)
+        Some(meta) => {
+            err_fmt!("attribute must have form `#[{}({} = \"...\")]`", name, key).at(&meta)
         }
         None => Ok(None),
     }
@@ -89,8 +89,8 @@ fn name_value(attrs: &[Attribute], name: &str, key: &str) -> Result<Option<MetaN
 fn has_meta_word(attrs: &[Attribute], name: &str, key: &str) -> Result<bool> {
     match meta(attrs, name, key) {
         Some(Meta::Word(_)) => Ok(true),
-        Some(_) => {
-            err_fmt!("attribute must have form `#[{}({})]`", name, key)
+        Some(meta) => {
+            err_fmt!("attribute must have form `#[{}({})]`", name, key).at(&meta)
         }
         None => Ok(false),
     }
@@ -106,12 +106,69 @@ pub fn has_serde_word(attrs: &[Attribute], key: &str) -> Result<bool> {
     has_meta_word(attrs, "serde", key)
 }
 
+/// Search for a `Serde` attribute which is either a single word or a
+/// name-value pair, like `#[serde(default)]` and `#[serde(default = "...")]`.
+pub fn has_serde_word_or_value(attrs: &[Attribute], key: &str) -> Result<bool> {
+    match meta(attrs, "serde", key) {
+        Some(Meta::Word(_)) | Some(Meta::NameValue(_)) => Ok(true),
+        Some(meta) => {
+            err_fmt!("attribute must have form `#[serde({})]` or `#[serde({} = \"...\")]`", key, key)
+                .at(&meta)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Search for an `#[avocado(...)]` attribute, provided that it's a single word.
+pub fn has_avocado_word(attrs: &[Attribute], key: &str) -> Result<bool> {
+    has_meta_word(attrs, "avocado", key)
+}
+
+/// Search for an `#[avocado(...)]` attribute, provided that it's a name-value pair.
+pub fn avocado_name_value(attrs: &[Attribute], key: &str) -> Result<Option<MetaNameValue>> {
+    name_value(attrs, "avocado", key)
+}
+
+/// Search for an `#[avocado(...)]` attribute which is either a single word
+/// or a name-value pair. Returns `Some(None)` for the word, and the value
+/// for the pair.
+pub fn avocado_word_or_value(attrs: &[Attribute], key: &str) -> Result<Option<Option<String>>> {
+    match meta(attrs, "avocado", key) {
+        Some(Meta::Word(_)) => Ok(Some(None)),
+        Some(Meta::NameValue(name_value)) => value_as_str(&name_value).map(|value| Some(Some(value))),
+        Some(Meta::List(list)) => {
+            err_fmt!("attribute must have form `#[avocado({})]` or `#[avocado({} = \"...\")]`", key, key)
+                .at(&list)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Search for an `#[avocado(...)]` attribute which is either a single word
+/// or a name-value pair with an integer value in `range`. Returns `Some(None)`
+/// for the word, and the value for the pair.
+pub fn avocado_word_or_int<R>(attrs: &[Attribute], key: &str, range: R) -> Result<Option<Option<i32>>>
+    where R: Debug + RangeBoundsExt<i32>
+{
+    match meta(attrs, "avocado", key) {
+        Some(Meta::Word(_)) => Ok(Some(None)),
+        Some(Meta::NameValue(name_value)) => {
+            value_as_i32(key, &name_value.lit, range).map(|value| Some(Some(value)))
+        }
+        Some(Meta::List(list)) => {
+            err_fmt!("attribute must have form `#[avocado({})]` or `#[avocado({} = ...)]`", key, key)
+                .at(&list)
+        }
+        None => Ok(None),
+    }
+}
+
 /// Extracts a boolean value from an attribute value.
 /// Returns `Err` if the value is not a `LitBool`.
 pub fn value_as_bool(key: &str, lit: &Lit) -> Result<bool> {
     match *lit {
         Lit::Bool(ref lit) => Ok(lit.value),
-        _ => err_fmt!("value for key `{}` must be a bool", key)
+        _ => err_fmt!("value for key `{}` must be a bool", key).at(lit)
     }
 }
 
@@ -125,7 +182,7 @@ pub fn value_as_str(nv: &MetaNameValue) -> Result<String> {
         }
         _ => err_fmt!("value for key `{}` must be a valid UTF-8 string",
                       nv.ident.to_string())
-    }
+    }.at(&nv.lit)
 }
 
 /// Similar to `value_as_str()`, but for `ExtMeta`-related usage.
@@ -136,7 +193,7 @@ pub fn lit_value_as_str(key: &str, lit: &Lit) -> Result<String> {
             String::from_utf8(string.value()).map_err(Into::into)
         }
         _ => err_fmt!("value for key `{}` must be a valid UTF-8 string", key)
-    }
+    }.at(lit)
 }
 
 /// Extracts an `i32` value from an attribute value.
@@ -144,9 +201,16 @@ pub fn lit_value_as_str(key: &str, lit: &Lit) -> Result<String> {
 ///
 /// Accepts string-valued attributes as well because that is currently the
 /// only way to specify a negative number.
-#[allow(clippy::cast_possible_truncation)]
 pub fn value_as_i32<R>(key: &str, lit: &Lit, range: R) -> Result<i32>
     where R: Debug + RangeBoundsExt<i32>
+{
+    lit_to_i32(key, lit, range).at(lit)
+}
+
+/// The implementation of `value_as_i32()`, without the location of errors.
+#[allow(clippy::cast_possible_truncation)]
+fn lit_to_i32<R>(key: &str, lit: &Lit, range: R) -> Result<i32>
+    where R: Debug + RangeBoundsExt<i32>
 {
     let value = match *lit {
         Lit::Int(ref lit) => {
@@ -175,9 +239,16 @@ pub fn value_as_i32<R>(key: &str, lit: &Lit, range: R) -> Result<i32>
 ///
 /// Accepts string-valued attributes as well because that is currently the
 /// only way to specify a negative number.
-#[allow(clippy::cast_precision_loss)]
 pub fn value_as_f64<R>(key: &str, lit: &Lit, range: R) -> Result<f64>
     where R: Debug + RangeBoundsExt<f64>
+{
+    lit_to_f64(key, lit, range).at(lit)
+}
+
+/// The implementation of `value_as_f64()`, without the location of errors.
+#[allow(clippy::cast_precision_loss)]
+fn lit_to_f64<R>(key: &str, lit: &Lit, range: R) -> Result<f64>
+    where R: Debug + RangeBoundsExt<f64>
 {
     let value = match *lit {
         Lit::Float(ref lit) => lit.value(),
@@ -213,11 +284,12 @@ pub fn list_into_names_and_values<T, I>(outer_name: &str, list: I) -> Result<Vec
                     _ => return err_fmt!(
                         "value for key `{}` must be a valid UTF-8 string",
                         path.colon_sep_str()
-                    )
+                    ).at(&literal)
                 };
                 val_str
                     .parse()
                     .map_err(Into::into)
+                    .at(&literal)
                     .map(|value| (path.dot_sep_str(), value))
             }
             _ => err_fmt!(
@@ -229,16 +301,15 @@ pub fn list_into_names_and_values<T, I>(outer_name: &str, list: I) -> Result<Vec
         .collect()
 }
 
-/// Extracts the literal value of a top-level name-value pair of the given name.
-pub fn literal_value_for_name<T: Synom>(attrs: &[Attribute], name: &str) -> Result<Option<T>> {
+/// Returns the top-level name-value pair of the given name, like
+/// `#[id_type = "..."]`, if any.
+fn top_level_name_value(attrs: &[Attribute], name: &str) -> Result<Option<MetaNameValue>> {
     attrs
         .iter()
         .find_map(|attr| match attr.interpret_meta()? {
             Meta::NameValue(nv) => {
                 if nv.ident == name {
-                    value_as_str(&nv)
-                        .and_then(|s| syn::parse_str(&s).map_err(Into::into))
-                        .into()
+                    Some(Ok(nv))
                 } else {
                     None
                 }
@@ -246,7 +317,7 @@ pub fn literal_value_for_name<T: Synom>(attrs: &[Attribute], name: &str) -> Resu
             Meta::Word(ident) | Meta::List(MetaList { ident, .. }) => {
                 if ident == name {
                     Some(
-                        err_fmt!("attribute must have form `#[{} = ...]`", name)
+                        err_fmt!("attribute must have form `#[{} = ...]`", name).at(&ident)
                     )
                 } else {
                     None
@@ -255,3 +326,22 @@ pub fn literal_value_for_name<T: Synom>(attrs: &[Attribute], name: &str) -> Resu
         })
         .map_or(Ok(None), |result| result.map(Some))
 }
+
+/// Extracts the string value of a top-level name-value pair of the given name.
+pub fn str_value_for_name(attrs: &[Attribute], name: &str) -> Result<Option<String>> {
+    match top_level_name_value(attrs, name)? {
+        Some(nv) => value_as_str(&nv).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Extracts the literal value of a top-level name-value pair of the given name.
+pub fn literal_value_for_name<T: Synom>(attrs: &[Attribute], name: &str) -> Result<Option<T>> {
+    match top_level_name_value(attrs, name)? {
+        Some(nv) => value_as_str(&nv)
+            .and_then(|s| syn::parse_str(&s).map_err(Into::into))
+            .at(&nv.lit)
+            .map(Some),
+        None => Ok(None),
+    }
+}
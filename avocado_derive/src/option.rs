@@ -6,7 +6,7 @@ use proc_macro2::{ TokenStream, Span };
 use syn::{ Attribute, Ident, Path, PathSegment };
 use syn::{ Meta, NestedMeta, MetaNameValue, Lit };
 use quote::{ ToTokens, TokenStreamExt };
-use crate::error::{ Result, err_msg };
+use crate::error::{ Error, Result, ResultExt, err_msg };
 
 /// This type can tokenize itself in a way that, when quoted inside
 /// an `impl Doc for T`, will expand to a bunch of option functions
@@ -97,7 +97,7 @@ impl DocOptions {
                         lit: Lit::Str(path_str),
                         ..
                     })) => {
-                        let path: Path = path_str.parse()?;
+                        let path: Path = path_str.parse().map_err(Error::from).at(&path_str)?;
                         let fn_name = ident.to_string();
 
                         match options.0.get_mut(&fn_name) {
@@ -106,12 +106,12 @@ impl DocOptions {
                             }
                             None => return err_fmt!(
                                 "no option method named `Doc::{}()`", fn_name
-                            )
+                            ).at(&ident)
                         }
                     },
-                    _ => return err_msg(
+                    other => return err_msg(
                         "attribute must have form `#[options(fn_name = \"path\", ...)]`"
-                    )
+                    ).at(&other)
                 }
             }
         }
@@ -0,0 +1,171 @@
+//! The shard key of a collection, specified by an attribute.
+
+use std::str::FromStr;
+use proc_macro2::TokenStream;
+use syn::Attribute;
+use quote::{ ToTokens, TokenStreamExt };
+use crate::{
+    error::{ Error, Result, err_msg },
+    attr::*,
+    meta::*,
+    case::RenameRule,
+};
+
+/// Describes the `#[shard_key(fields(...), strict)]` attribute.
+#[derive(Debug, Clone, Default)]
+pub struct ShardKey {
+    /// The fields of the shard key and their type, in order.
+    fields: Vec<(String, Type)>,
+    /// Whether untargeted writes should be rejected.
+    strict: bool,
+}
+
+impl ShardKey {
+    /// Parses the `#[shard_key(...)]` attribute, if any.
+    ///
+    /// ### Return value:
+    /// * `Ok(None)` if there is no `#[shard_key(...)]` attribute
+    /// * `Ok(Some(ShardKey))` if the attribute is well-formed
+    /// * `Err(Error)` if the attribute is ill-formed or it occurs twice.
+    pub fn from_attributes(attrs: &[Attribute]) -> Result<Option<Self>> {
+        let mut shard_key = None;
+
+        for attr in attrs {
+            let nested = match attr.parse_ext_meta() {
+                Some(ExtMeta::List(path, _, nested)) => {
+                    if path.colon_sep_str() == "shard_key" {
+                        nested
+                    } else {
+                        continue
+                    }
+                }
+                Some(ExtMeta::Path(path)) | Some(ExtMeta::KeyValue(path, ..)) => {
+                    if path.colon_sep_str() == "shard_key" {
+                        err_msg("attribute must have form `#[shard_key(...)]`")?
+                    } else {
+                        continue
+                    }
+                }
+                None => continue,
+            };
+
+            if shard_key.is_some() {
+                return err_msg("at most one `#[shard_key(...)]` attribute is allowed");
+            }
+
+            shard_key = Some(Self::from_nested(nested)?);
+        }
+
+        Ok(shard_key)
+    }
+
+    /// Parses the items inside `#[shard_key(...)]`.
+    fn from_nested<I>(nested: I) -> Result<Self>
+        where I: IntoIterator<Item = NestedExtMeta>
+    {
+        let mut shard_key = ShardKey::default();
+
+        for item in nested {
+            match item {
+                NestedExtMeta::Meta(ExtMeta::List(path, _, list)) => {
+                    let path_str = path.colon_sep_str();
+
+                    if path_str != "fields" {
+                        err_fmt!("bad list attribute: {}", path_str)?
+                    }
+                    if !shard_key.fields.is_empty() {
+                        err_msg("`fields(...)` may only be specified once")?
+                    }
+
+                    shard_key.fields = list_into_names_and_values(&path_str, list)?;
+                }
+                NestedExtMeta::Meta(ExtMeta::Path(path)) => {
+                    match path.colon_sep_str().as_str() {
+                        "strict" => shard_key.strict = true,
+                        path_str => err_fmt!("bad path attribute: {}", path_str)?
+                    }
+                }
+                NestedExtMeta::Meta(ExtMeta::KeyValue(path, _, lit)) => {
+                    let path_str = path.colon_sep_str();
+
+                    match path_str.as_str() {
+                        "strict" => shard_key.strict = value_as_bool(&path_str, &lit)?,
+                        _ => err_fmt!("bad name-value attribute: {}", path_str)?
+                    }
+                }
+                NestedExtMeta::Literal(lit) => {
+                    err_fmt!("expected a meta item, found literal: {:#?}", lit)?
+                }
+            }
+        }
+
+        if shard_key.fields.is_empty() {
+            err_msg("`#[shard_key(...)]` requires at least one field in `fields(...)`")?
+        }
+
+        let num_hashed = shard_key.fields.iter().filter(|&&(_, ty)| ty == Type::Hashed).count();
+
+        if num_hashed > 1 {
+            err_msg("a shard key may contain at most one hashed field")?
+        }
+
+        Ok(shard_key)
+    }
+
+    /// Renames the fields of the shard key according to the `rename_all`
+    /// rule of the type.
+    pub fn rename_fields(&mut self, rule: RenameRule) {
+        for &mut (ref mut field, _) in &mut self.fields {
+            *field = rule.apply_to_path(field);
+        }
+    }
+}
+
+impl ToTokens for ShardKey {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let strict = self.strict;
+        let fields = self.fields.iter().map(|&(ref field, _)| field);
+        let types = self.fields.iter().map(|&(_, ty)| ty);
+
+        tokens.append_all(quote! {
+            fn shard_key() -> ::std::option::Option<::avocado::shard::Key> {
+                let mut avocado_keys = ::avocado::prelude::Document::new();
+                #(avocado_keys.insert(#fields, #types);)*
+                ::std::option::Option::Some(::avocado::shard::Key {
+                    keys: avocado_keys,
+                    strict: #strict,
+                })
+            }
+        });
+    }
+}
+
+/// The type of a single shard key field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    /// Ranged sharding on the values of the field.
+    Ascending,
+    /// Hashed sharding on the values of the field.
+    Hashed,
+}
+
+impl FromStr for Type {
+    type Err = Error;
+
+    fn from_str(string: &str) -> Result<Self> {
+        Ok(match string {
+            "ascending" => Type::Ascending,
+            "hashed"    => Type::Hashed,
+            _ => err_fmt!("unknown shard key type '{}'", string)?
+        })
+    }
+}
+
+impl ToTokens for Type {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match *self {
+            Type::Ascending => 1.to_tokens(tokens),
+            Type::Hashed    => "hashed".to_tokens(tokens),
+        }
+    }
+}
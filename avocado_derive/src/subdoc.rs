@@ -0,0 +1,169 @@
+//! Generates the field path accessors of `Subdoc` types.
+
+use proc_macro2::{ Span, TokenStream };
+use syn::{ Generics, Ident, Visibility, Type };
+use crate::{
+    SerializedField,
+    id::{ last_segment, generic_argument_of },
+};
+
+/// The names of the types which are serialized as a BSON number.
+const NUMBER_TYPES: &[&str] = &[
+    "i8", "i16", "i32", "i64", "i128", "isize",
+    "u8", "u16", "u32", "u64", "u128", "usize",
+    "f32", "f64",
+];
+
+/// The names of the generic collection types which are serialized as a
+/// BSON array of their type argument.
+const ARRAY_TYPES: &[&str] = &[
+    "Vec", "VecDeque", "LinkedList", "HashSet", "BTreeSet", "BinaryHeap",
+];
+
+/// The names of the generic smart pointers which are serialized as their
+/// type argument.
+const POINTER_TYPES: &[&str] = &["Box", "Rc", "Arc"];
+
+/// Implements `{Type}Fields`, with a method returning the `path::Path` of
+/// each serialized field, and implements `Subdoc` for the type with it.
+pub fn impl_fields(
+    vis: &Visibility,
+    ty: &Ident,
+    generics: &Generics,
+    fields: &[SerializedField],
+) -> TokenStream {
+    let fields_ty = Ident::new(&format!("{}Fields", ty), Span::call_site());
+    let fields_doc = format!("The paths of the fields of `{}`.", ty);
+    let (impl_gen, ty_gen, where_cls) = generics.split_for_impl();
+    let mut methods = Vec::with_capacity(fields.len());
+
+    for field in fields {
+        let ident = &field.ident;
+        let name = &field.name;
+        let declared_ty = &field.ty;
+        let kind_method = Ident::new(
+            &format!("__avocado_kind_of_{}", ident.to_string().trim_start_matches("r#")),
+            Span::call_site(),
+        );
+        let kind = if field.subdoc {
+            quote!(::avocado::path::kind::Any)
+        } else {
+            field_kind(declared_ty)
+        };
+
+        let method = match (field.subdoc, field.flattened) {
+            // The fields of a flattened document are at the same level as
+            // the other fields, but their names aren't known statically.
+            (false, true) => continue,
+            (false, false) => {
+                let doc = format!("The path of `{}`.", name);
+                quote! {
+                    #[doc = #doc]
+                    #vis fn #ident(&self) -> ::avocado::path::Path {
+                        self.path.field(#name)
+                    }
+                }
+            }
+            (true, flattened) => {
+                let doc = format!("The paths of the fields of `{}`.", name);
+                let path = if flattened {
+                    quote!(self.path.clone())
+                } else {
+                    quote!(self.path.field(#name))
+                };
+                quote! {
+                    #[doc = #doc]
+                    #vis fn #ident(&self) -> <#declared_ty as ::avocado::path::Subdoc>::Fields {
+                        <#declared_ty as ::avocado::path::Subdoc>::fields_at(#path)
+                    }
+                }
+            }
+        };
+
+        methods.push(method);
+
+        // Used by `checked_flt!` for checking the literals compared to the field.
+        methods.push(quote! {
+            #[doc(hidden)]
+            #vis fn #kind_method(&self) -> #kind {
+                ::std::default::Default::default()
+            }
+        });
+    }
+
+    quote! {
+        #[doc = #fields_doc]
+        #[derive(Debug, Clone)]
+        #vis struct #fields_ty {
+            /// The path of the document containing the fields.
+            path: ::avocado::path::Path,
+        }
+
+        impl #fields_ty {
+            #(#methods)*
+        }
+
+        impl ::std::ops::Deref for #fields_ty {
+            type Target = ::avocado::path::Path;
+
+            fn deref(&self) -> &Self::Target {
+                &self.path
+            }
+        }
+
+        impl #impl_gen ::avocado::path::Subdoc for #ty #ty_gen #where_cls {
+            type Fields = #fields_ty;
+
+            fn fields_at<AvocadoPrefix>(prefix: AvocadoPrefix) -> Self::Fields
+                where AvocadoPrefix: ::std::convert::Into<::avocado::path::Path>
+            {
+                #fields_ty {
+                    path: ::std::convert::Into::into(prefix),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the `avocado::path::kind` marker of the BSON type a field of type
+/// `ty` is serialized as, based on the name of the type. Types which can't
+/// be recognized by their name are classified as `Any`.
+fn field_kind(ty: &Type) -> TokenStream {
+    match *ty {
+        Type::Reference(ref reference) => return field_kind(&reference.elem),
+        Type::Paren(ref paren) => return field_kind(&paren.elem),
+        Type::Group(ref group) => return field_kind(&group.elem),
+        Type::Slice(ref slice) => {
+            let element = field_kind(&slice.elem);
+            return quote!(::avocado::path::kind::Array<#element>);
+        }
+        Type::Array(ref array) => {
+            let element = field_kind(&array.elem);
+            return quote!(::avocado::path::kind::Array<#element>);
+        }
+        _ => {}
+    }
+
+    let name = match last_segment(ty) {
+        Some(segment) => segment.ident.to_string(),
+        None => return quote!(::avocado::path::kind::Any),
+    };
+
+    if name == "String" || name == "str" || name == "char" {
+        quote!(::avocado::path::kind::Str)
+    } else if name == "bool" {
+        quote!(::avocado::path::kind::Bool)
+    } else if NUMBER_TYPES.contains(&name.as_str()) {
+        quote!(::avocado::path::kind::Number)
+    } else if let Some(inner) = generic_argument_of(ty, "Option") {
+        let inner_kind = field_kind(inner);
+        quote!(::avocado::path::kind::Nullable<#inner_kind>)
+    } else if let Some(inner) = ARRAY_TYPES.iter().find_map(|&array| generic_argument_of(ty, array)) {
+        let element = field_kind(inner);
+        quote!(::avocado::path::kind::Array<#element>)
+    } else if let Some(inner) = POINTER_TYPES.iter().find_map(|&ptr| generic_argument_of(ty, ptr)) {
+        field_kind(inner)
+    } else {
+        quote!(::avocado::path::kind::Any)
+    }
+}
@@ -0,0 +1,273 @@
+//! Implements `Doc` for enums stored as internally tagged documents.
+
+use std::collections::HashSet;
+use proc_macro2::TokenStream;
+use syn::{ Attribute, DataEnum, Fields, Generics, Ident, Path, Type };
+use crate::{
+    SerializedField,
+    serialized_fields,
+    name_of_id_field,
+    id,
+    serde_renamed_ident,
+    meta::*,
+    case::RenameRule,
+    error::{ Result, ResultExt, err_msg },
+};
+
+/// An enum annotated with `#[serde(tag = "...")]`.
+#[derive(Debug, Clone)]
+pub struct TaggedEnum {
+    /// The name of the field holding the tag of the variant.
+    tag_field: String,
+    /// The variants, in declaration order.
+    variants: Vec<TaggedVariant>,
+}
+
+/// A variant of a tagged enum.
+#[derive(Debug, Clone)]
+struct TaggedVariant {
+    /// The identifier of the variant.
+    ident: Ident,
+    /// The tag of the variant, after applying Serde's renaming rules.
+    tag: String,
+    /// The identifier of the field serialized as `_id`.
+    id_ident: Ident,
+    /// The serialized fields of the variant.
+    fields: Vec<SerializedField>,
+    /// The identifiers of all fields, including the skipped ones.
+    all_idents: Vec<Ident>,
+    /// The type named by `#[avocado(subtype = "...")]`, if any.
+    subtype: Option<Path>,
+}
+
+impl TaggedEnum {
+    /// Parses the variants of an enum, given the attributes of the enum.
+    pub fn from_data(data: DataEnum, attrs: &[Attribute]) -> Result<Self> {
+        if has_serde_word(attrs, "untagged")? {
+            return err_msg("an untagged enum can't be a `Doc`; use `#[serde(tag = \"...\")]`");
+        }
+        if let Some(nv) = serde_name_value(attrs, "content")? {
+            return err_msg(
+                "an adjacently tagged enum can't be a `Doc`, because its `_id` wouldn't be \
+                 a top-level field; remove `#[serde(content = \"...\")]`"
+            ).at(&nv);
+        }
+
+        let tag_field = match serde_name_value(attrs, "tag")? {
+            Some(nv) => value_as_str(&nv)?,
+            None => return err_msg("an enum `Doc` must be internally tagged, using `#[serde(tag = \"...\")]`"),
+        };
+        let rename_rule: Option<RenameRule> = match serde_name_value(attrs, "rename_all")? {
+            None => None,
+            Some(kv) => Some(value_as_str(&kv)?.parse().at(&kv.lit)?)
+        };
+        let mut variants = Vec::with_capacity(data.variants.len());
+        let mut tags = HashSet::new();
+
+        for variant in data.variants {
+            let all_idents: Vec<Ident> = match variant.fields {
+                Fields::Named(ref named) => named.named
+                    .iter()
+                    .flat_map(|field| field.ident.clone())
+                    .collect(),
+                _ => return err_fmt!(
+                    "variant `{}` of an enum `Doc` must have named fields, including `_id`",
+                    variant.ident
+                ).at(&variant.ident),
+            };
+            let subtype: Option<Path> = match avocado_name_value(&variant.attrs, "subtype")? {
+                Some(nv) => Some(syn::parse_str(&value_as_str(&nv)?).map_err(Into::into).at(&nv.lit)?),
+                None => None,
+            };
+
+            let renamed = rename_rule.map_or_else(
+                || variant.ident.to_string(),
+                |rule| rule.apply_to_variant(variant.ident.to_string()),
+            );
+            let tag = serde_renamed_ident(&variant.attrs, renamed)?;
+            let fields = serialized_fields(variant.fields, &variant.attrs).at(&variant.ident)?;
+            let id_ident = name_of_id_field(&fields).at(&variant.ident)?;
+
+            if !tags.insert(tag.clone()) {
+                return err_fmt!("more than one variant is tagged `{}`", tag).at(&variant.ident);
+            }
+
+            for field in &fields {
+                if field.name == tag_field {
+                    return err_fmt!(
+                        "field `{}` of variant `{}` conflicts with the tag field",
+                        field.ident, variant.ident
+                    ).at(&field.ident);
+                }
+                if field.versioned || field.created_at || field.updated_at || field.deleted_at {
+                    return err_msg("version and timestamp fields aren't supported in enum variants")
+                        .at(&field.ident);
+                }
+                if field.encrypted.is_some() {
+                    return err_msg("`#[avocado(encrypted)]` isn't supported in enum variants")
+                        .at(&field.ident);
+                }
+            }
+
+            variants.push(TaggedVariant {
+                ident: variant.ident,
+                tag,
+                id_ident,
+                fields,
+                all_idents,
+                subtype,
+            });
+        }
+
+        if variants.is_empty() {
+            return err_msg("an enum `Doc` must have at least one variant");
+        }
+
+        Ok(TaggedEnum { tag_field, variants })
+    }
+
+    /// Checks the type of the `_id` field of each variant against the `Id`
+    /// type of the enum.
+    pub fn check_id_fields(&self, ty: &Ident, id_ty: &Type) -> Result<()> {
+        for variant in &self.variants {
+            id::check_id_field(ty, &variant.fields, id_ty)?;
+        }
+
+        Ok(())
+    }
+
+    /// Implements `Doc::id()`, `Doc::set_id()`, `Doc::tag_field()`,
+    /// `Doc::variant_tags()` and `Doc::variant_tag()`.
+    pub fn impl_methods(&self, ty: &Ident) -> TokenStream {
+        let tag_field = &self.tag_field;
+        let tags: Vec<_> = self.variants.iter().map(|variant| &variant.tag).collect();
+        let id_arms = self.variants.iter().map(|variant| {
+            let ident = &variant.ident;
+            let id_ident = &variant.id_ident;
+            quote! {
+                #ty::#ident { #id_ident: ref id, .. } => ::std::convert::From::from(id),
+            }
+        });
+        let set_id_arms = self.variants.iter().map(|variant| {
+            let ident = &variant.ident;
+            let id_ident = &variant.id_ident;
+            quote! {
+                #ty::#ident { #id_ident: ref mut field, .. } => {
+                    *field = ::std::convert::From::from(id);
+                }
+            }
+        });
+        let tag_arms = self.variants.iter().map(|variant| {
+            let ident = &variant.ident;
+            let tag = &variant.tag;
+            quote! {
+                #ty::#ident { .. } => ::std::option::Option::Some(#tag),
+            }
+        });
+
+        quote! {
+            fn id(&self) -> ::std::option::Option<&::avocado::uid::Uid<Self>> {
+                match *self {
+                    #(#id_arms)*
+                }
+            }
+
+            fn set_id(&mut self, id: ::avocado::uid::Uid<Self>) {
+                match *self {
+                    #(#set_id_arms)*
+                }
+            }
+
+            fn tag_field() -> ::std::option::Option<&'static str> {
+                ::std::option::Option::Some(#tag_field)
+            }
+
+            fn variant_tags() -> &'static [&'static str] {
+                &[#(#tags),*]
+            }
+
+            fn variant_tag(&self) -> ::std::option::Option<&'static str> {
+                match *self {
+                    #(#tag_arms)*
+                }
+            }
+        }
+    }
+
+    /// Implements `Subtype` for the types named by `#[avocado(subtype = "...")]`,
+    /// and converts them into the enum, field by field.
+    pub fn impl_subtypes(&self, ty: &Ident, generics: &Generics) -> Result<TokenStream> {
+        let mut impls = Vec::new();
+
+        for variant in &self.variants {
+            let subtype = match variant.subtype {
+                Some(ref path) => path,
+                None => continue,
+            };
+
+            if !generics.params.is_empty() {
+                return err_msg("`#[avocado(subtype)]` can't be used on a generic enum")
+                    .at(&variant.ident);
+            }
+
+            let ident = &variant.ident;
+            let tag = &variant.tag;
+            let fields = &variant.all_idents;
+            let values = &variant.all_idents;
+
+            impls.push(quote! {
+                impl ::avocado::variant::Subtype<#ty> for #subtype {
+                    const TAG: &'static str = #tag;
+                }
+
+                impl ::std::convert::From<#subtype> for #ty {
+                    fn from(subtype: #subtype) -> Self {
+                        #ty::#ident {
+                            #(#fields: subtype.#values,)*
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(quote!(#(#impls)*))
+    }
+
+    /// If the enum is annotated with `#[avocado(strict)]`, implements
+    /// `Doc::strict_fields()`, returning the tag field and the names of the
+    /// serialized fields of all variants.
+    pub fn impl_strict_fields(&self, attrs: &[Attribute]) -> Result<TokenStream> {
+        if !has_avocado_word(attrs, "strict")? {
+            return Ok(TokenStream::new());
+        }
+
+        let fields = self.fields();
+
+        if let Some(field) = fields.iter().find(|field| field.flattened) {
+            return err_msg("`#[avocado(strict)]` can't be used with `#[serde(flatten)]` fields")
+                .at(&field.ident);
+        }
+
+        let tag_field = &self.tag_field;
+        let names = fields.iter().map(|field| &field.name);
+
+        Ok(quote! {
+            fn strict_fields() -> ::std::option::Option<&'static [&'static str]> {
+                ::std::option::Option::Some(&[#tag_field, #(#names),*])
+            }
+        })
+    }
+
+    /// The distinct serialized fields of all variants, by name, in order of
+    /// first appearance.
+    pub fn fields(&self) -> Vec<SerializedField> {
+        let mut names = HashSet::new();
+
+        self.variants
+            .iter()
+            .flat_map(|variant| &variant.fields)
+            .filter(|field| names.insert(field.name.clone()))
+            .cloned()
+            .collect()
+    }
+}